@@ -0,0 +1,222 @@
+//! Proof that the other end of a connection inside a deployment is a member of it, and that
+//! clients connecting to tables and views may use them.
+//!
+//! Workers, domains and the controller share a secret. When one of them connects to another, the
+//! accepting end sends a random challenge, and the connecting end answers with an HMAC of it
+//! under the secret, so the secret itself never crosses the network. If the deployment uses TLS,
+//! this happens once the TLS handshake is done. Connections are not checked if no secret is set.
+//!
+//! Clients instead present their access token once the (TLS) connection to a table or view is up,
+//! and the accepting end answers with whether the token's roles allow that connection.
+
+use crate::internal::{hmac_sha256, secrets_match};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const CHALLENGE_LEN: usize = 32;
+
+fn answer(secret: &str, challenge: &[u8]) -> [u8; 32] {
+    hmac_sha256(secret.as_bytes(), challenge)
+}
+
+/// Answer the challenge of the accepting end of `stream` with `secret`.
+pub fn prove<S: Read + Write>(stream: &mut S, secret: Option<&str>) -> io::Result<()> {
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+
+    let mut f = move || {
+        let mut challenge = [0; CHALLENGE_LEN];
+        stream.read_exact(&mut challenge)?;
+        stream.write_all(&answer(secret, &challenge))?;
+        stream.flush()
+    };
+
+    if tokio::runtime::Handle::try_current().is_ok() {
+        tokio::task::block_in_place(f)
+    } else {
+        f()
+    }
+}
+
+/// Answer the challenge of the accepting end of `stream` with `secret`, without blocking.
+pub async fn prove_async<S>(stream: &mut S, secret: Option<&str>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(secret) = secret {
+        let mut challenge = [0; CHALLENGE_LEN];
+        stream.read_exact(&mut challenge).await?;
+        stream.write_all(&answer(secret, &challenge)).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+/// Check that the connecting end of `stream` knows `secret`.
+///
+/// Fails with `PermissionDenied` if it does not.
+pub async fn challenge<S>(stream: &mut S, secret: Option<&str>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(secret) = secret {
+        let challenge: [u8; CHALLENGE_LEN] = rand::random();
+        stream.write_all(&challenge).await?;
+        stream.flush().await?;
+
+        let mut given = [0; 32];
+        stream.read_exact(&mut given).await?;
+        if !secrets_match(&given, &answer(secret, &challenge)) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "peer does not know the worker secret",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Sent by the accepting end of a client connection once it has checked the client's token.
+const ADMITTED: u8 = 1;
+/// Sent by the accepting end of a client connection if the client's token does not let it in.
+const REFUSED: u8 = 0;
+
+/// Present the access token `token` over a client connection to a table or view, and wait for the
+/// accepting end to let the connection through.
+///
+/// Fails with `PermissionDenied` if the token does not allow the connection.
+pub async fn present<S>(stream: &mut S, token: Option<&str>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let token = token.unwrap_or("").as_bytes();
+    if token.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "access token is too long",
+        ));
+    }
+    stream
+        .write_all(&(token.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(token).await?;
+    stream.flush().await?;
+
+    let mut verdict = [0; 1];
+    stream.read_exact(&mut verdict).await?;
+    if verdict[0] != ADMITTED {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "access token does not allow this connection",
+        ));
+    }
+    Ok(())
+}
+
+/// Read the access token presented by the connecting end of a client connection, and let it
+/// through if `check` allows the token, or the lack of one.
+///
+/// Fails with `PermissionDenied` if it does not.
+pub async fn admit<S, F>(stream: &mut S, check: F) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(Option<&str>) -> bool,
+{
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut token = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut token).await?;
+    let token = match std::str::from_utf8(&token) {
+        Ok("") => None,
+        Ok(token) => Some(token),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "access token is not valid UTF-8",
+            ))
+        }
+    };
+
+    let admitted = check(token);
+    stream
+        .write_all(&[if admitted { ADMITTED } else { REFUSED }])
+        .await?;
+    stream.flush().await?;
+    if !admitted {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "client's access token does not allow this connection",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(expected: Option<&str>, given: Option<&str>) -> io::Result<()> {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let given = given.map(String::from);
+        let client = tokio::spawn(async move {
+            let mut s = tokio::net::TcpStream::connect(addr).await.unwrap();
+            // the accepting end hangs up on wrong answers, which may race with the answer itself
+            let _ = prove_async(&mut s, given.as_ref().map(String::as_str)).await;
+        });
+        let (mut s, _) = listener.accept().await.unwrap();
+        let res = challenge(&mut s, expected).await;
+        drop(s);
+        client.await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn it_checks_the_secret() {
+        assert!(handshake(None, None).await.is_ok());
+        assert!(handshake(Some("hunter2"), Some("hunter2")).await.is_ok());
+
+        let denied = handshake(Some("hunter2"), Some("hunter3"))
+            .await
+            .unwrap_err();
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+        // a peer without a secret doesn't answer at all
+        assert!(handshake(Some("hunter2"), None).await.is_err());
+    }
+
+    async fn present_token(token: Option<&str>) -> (io::Result<()>, io::Result<Option<String>>) {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = token.map(String::from);
+        let client = tokio::spawn(async move {
+            let mut s = tokio::net::TcpStream::connect(addr).await.unwrap();
+            present(&mut s, token.as_ref().map(String::as_str)).await
+        });
+        let (mut s, _) = listener.accept().await.unwrap();
+        let mut seen = None;
+        let res = admit(&mut s, |token| {
+            seen = token.map(String::from);
+            token == Some("hunter2")
+        })
+        .await;
+        drop(s);
+        (client.await.unwrap(), res.map(|_| seen))
+    }
+
+    #[tokio::test]
+    async fn it_admits_by_token() {
+        let (client, server) = present_token(Some("hunter2")).await;
+        assert!(client.is_ok());
+        assert_eq!(server.unwrap(), Some("hunter2".to_owned()));
+
+        let (client, server) = present_token(Some("hunter3")).await;
+        assert_eq!(client.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(server.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let (client, server) = present_token(None).await;
+        assert_eq!(client.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(server.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -7,19 +7,28 @@ use std::collections::HashMap;
 use std::hash::Hash;
 use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::RwLock;
 use std::{
     pin::Pin,
     task::{Context, Poll},
 };
 
-use async_bincode::{AsyncBincodeWriter, AsyncDestination};
+use crate::{Tagged, WriteAck};
+use async_bincode::{AsyncBincodeStream, AsyncBincodeWriter, AsyncDestination};
 use futures_util::sink::{Sink, SinkExt};
 use tokio::io::BufWriter;
 
+pub mod auth;
 pub mod tcp;
+pub mod tls;
 
 pub use self::tcp::{DualTcpStream, TcpSender};
+use self::tls::{MaybeTlsStream, TlsConfig};
+
+/// A connection to a domain on which it acknowledges the writes it is sent.
+pub type AckedConnection<T> =
+    AsyncBincodeStream<MaybeTlsStream, Tagged<WriteAck>, T, AsyncDestination>;
 
 pub const CONNECTION_FROM_BASE: u8 = 1;
 pub const CONNECTION_FROM_DOMAIN: u8 = 2;
@@ -32,6 +41,8 @@ pub struct DomainConnectionBuilder<D, T> {
     addr: SocketAddr,
     chan: Option<tokio::sync::mpsc::UnboundedSender<T>>,
     is_for_base: bool,
+    secret: Option<String>,
+    tls: Option<TlsConfig>,
     _marker: D,
 }
 
@@ -64,6 +75,8 @@ impl<T> DomainConnectionBuilder<Remote, T> {
             chan: None,
             addr,
             is_for_base: true,
+            secret: None,
+            tls: None,
             _marker: Remote,
         }
     }
@@ -85,32 +98,55 @@ impl<T> DomainConnectionBuilder<Remote, T>
 where
     T: serde::Serialize,
 {
+    /// Connect, and tell the other end what kind of connection this is.
+    fn connect(&self) -> io::Result<std::net::TcpStream> {
+        let mut s = tcp::connect_raw(self.sport, &self.addr)?;
+        s.write_all(&[if self.is_for_base {
+            CONNECTION_FROM_BASE
+        } else {
+            CONNECTION_FROM_DOMAIN
+        }])?;
+        s.flush()?;
+        Ok(s)
+    }
+
     pub fn build_async(
         self,
-    ) -> io::Result<AsyncBincodeWriter<BufWriter<tokio::net::TcpStream>, T, AsyncDestination>> {
-        // TODO: async
-        // we must currently write and call flush, because the remote end (currently) does a
-        // synchronous read upon accepting a connection.
-        let s = self.build_sync()?.into_inner().into_inner()?;
-
-        tokio::net::TcpStream::from_std(s)
+    ) -> io::Result<AsyncBincodeWriter<BufWriter<MaybeTlsStream>, T, AsyncDestination>> {
+        self.build_stream()
             .map(BufWriter::new)
             .map(AsyncBincodeWriter::from)
             .map(AsyncBincodeWriter::for_async)
     }
 
+    fn build_stream(self) -> io::Result<MaybeTlsStream> {
+        let s = tokio::net::TcpStream::from_std(self.connect()?)?;
+        // clients connect to bases with their own TLS settings and tokens instead
+        if self.is_for_base || (self.tls.is_none() && self.secret.is_none()) {
+            return Ok(MaybeTlsStream::Plain(s));
+        }
+
+        // TODO: async
+        // the handshake has to finish before the connection is handed out, since building
+        // connections is synchronous.
+        let (tls, secret) = (self.tls, self.secret);
+        tokio::task::block_in_place(move || {
+            futures_executor::block_on(async move {
+                let mut s = tls::connect(tls.as_ref(), s).await?;
+                auth::prove_async(&mut s, secret.as_ref().map(String::as_str)).await?;
+                Ok(s)
+            })
+        })
+    }
+
     pub fn build_sync(self) -> io::Result<TcpSender<T>> {
-        let mut s = TcpSender::connect_from(self.sport, &self.addr)?;
-        {
-            let s = s.get_mut();
-            s.write_all(&[if self.is_for_base {
-                CONNECTION_FROM_BASE
-            } else {
-                CONNECTION_FROM_DOMAIN
-            }])?;
-            s.flush()?;
+        let s = self.connect()?;
+        if self.is_for_base {
+            return TcpSender::new(s);
         }
 
+        let mut s = TcpSender::with_tls(s, self.tls.as_ref())?;
+        auth::prove(s.get_mut(), self.secret.as_ref().map(String::as_str))?;
         Ok(s)
     }
 }
@@ -152,6 +188,8 @@ where
                 chan: None,
                 addr: self.addr,
                 is_for_base: false,
+                secret: self.secret,
+                tls: self.tls,
                 _marker: Remote,
             }
             .build_async()
@@ -159,6 +197,23 @@ where
         }
     }
 
+    /// Connect to the domain over TCP even if it is local, on a connection that also carries
+    /// acknowledgements of the writes sent on it back.
+    pub fn build_acked(self) -> io::Result<AckedConnection<T>> {
+        let s = DomainConnectionBuilder {
+            sport: self.sport,
+            chan: None,
+            addr: self.addr,
+            is_for_base: false,
+            secret: self.secret,
+            tls: self.tls,
+            _marker: Remote,
+        }
+        .build_stream()?;
+
+        Ok(AsyncBincodeStream::from(s).for_async())
+    }
+
     pub fn build_sync(self) -> io::Result<Box<dyn Sender<Item = T> + Send>> {
         if let Some(chan) = self.chan {
             Ok(Box::new(chan))
@@ -168,6 +223,8 @@ where
                 chan: None,
                 addr: self.addr,
                 is_for_base: false,
+                secret: self.secret,
+                tls: self.tls,
                 _marker: Remote,
             }
             .build_sync()
@@ -185,6 +242,12 @@ struct ChannelCoordinatorInner<K: Eq + Hash + Clone, T> {
 
 pub struct ChannelCoordinator<K: Eq + Hash + Clone, T> {
     inner: RwLock<ChannelCoordinatorInner<K, T>>,
+    /// The secret that connections to domains prove knowledge of.
+    secret: Option<String>,
+    /// The TLS settings that connections to domains are made with.
+    tls: Option<TlsConfig>,
+    /// Bumped whenever a domain shows up at a different address than before.
+    moves: AtomicUsize,
 }
 
 impl<K: Eq + Hash + Clone, T> Default for ChannelCoordinator<K, T> {
@@ -195,17 +258,46 @@ impl<K: Eq + Hash + Clone, T> Default for ChannelCoordinator<K, T> {
 
 impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
     pub fn new() -> Self {
+        Self::with_access(None, None)
+    }
+
+    /// Make connections to domains over TLS if `tls` is set, and have them prove that they know
+    /// `secret` (see [`auth`]).
+    pub fn with_access(secret: Option<String>, tls: Option<TlsConfig>) -> Self {
         Self {
             inner: RwLock::new(ChannelCoordinatorInner {
                 addrs: Default::default(),
                 locals: Default::default(),
             }),
+            secret,
+            tls,
+            moves: AtomicUsize::new(0),
         }
     }
 
+    /// The secret that connections inside the deployment prove knowledge of, if any.
+    pub fn secret(&self) -> Option<&str> {
+        self.secret.as_ref().map(String::as_str)
+    }
+
+    /// The TLS settings that connections inside the deployment are made with, if any.
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
     pub fn insert_remote(&self, key: K, addr: SocketAddr) {
         let mut inner = self.inner.write().unwrap();
-        inner.addrs.insert(key, addr);
+        if let Some(old) = inner.addrs.insert(key, addr) {
+            if old != addr {
+                self.moves.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// How many times a domain has moved to a different address. Connections made before this
+    /// last changed may point to where a domain used to be.
+    pub fn moves(&self) -> usize {
+        self.moves.load(Ordering::SeqCst)
     }
 
     pub fn insert_local(&self, key: K, chan: tokio::sync::mpsc::UnboundedSender<T>) {
@@ -213,6 +305,16 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
         inner.locals.insert(key, chan);
     }
 
+    /// Forget about a local channel, and return it so that any final messages can be sent on it.
+    pub fn remove_local<Q>(&self, key: &Q) -> Option<tokio::sync::mpsc::UnboundedSender<T>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut inner = self.inner.write().unwrap();
+        inner.locals.remove(key)
+    }
+
     pub fn has<Q>(&self, key: &Q) -> bool
     where
         K: Borrow<Q>,
@@ -248,6 +350,8 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
             addr: *inner.addrs.get(key)?,
             chan: inner.locals.get(key).cloned(),
             is_for_base: false,
+            secret: self.secret.clone(),
+            tls: self.tls.clone(),
             _marker: MaybeLocal,
         })
     }
@@ -3,7 +3,8 @@ use std::io::{self, Write};
 use std::marker::PhantomData;
 use std::net::{Ipv4Addr, SocketAddr};
 
-use crate::Tagged;
+use super::tls::{self, MaybeTlsSyncStream, TlsConfig};
+use crate::{Tagged, WriteAck};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use bufstream::BufStream;
 use byteorder::{NetworkEndian, WriteBytesExt};
@@ -51,8 +52,29 @@ macro_rules! poisoning_try {
     };
 }
 
+/// Open a blocking TCP connection to `addr`, from local port `sport` if given.
+pub(crate) fn connect_raw(
+    sport: Option<u16>,
+    addr: &SocketAddr,
+) -> Result<std::net::TcpStream, io::Error> {
+    let f = move || {
+        let s = net2::TcpBuilder::new_v4()?
+            .reuse_address(true)?
+            .bind((Ipv4Addr::UNSPECIFIED, sport.unwrap_or(0)))?
+            .connect(addr)?;
+        s.set_nodelay(true)?;
+        Ok(s)
+    };
+
+    if tokio::runtime::Handle::try_current().is_ok() {
+        tokio::task::block_in_place(f)
+    } else {
+        f()
+    }
+}
+
 pub struct TcpSender<T> {
-    stream: BufStream<std::net::TcpStream>,
+    stream: BufStream<MaybeTlsSyncStream>,
     poisoned: bool,
 
     phantom: PhantomData<T>,
@@ -62,39 +84,43 @@ impl<T: Serialize> TcpSender<T> {
     pub fn new(stream: std::net::TcpStream) -> Result<Self, io::Error> {
         stream.set_nodelay(true).unwrap();
         Ok(Self {
-            stream: BufStream::new(stream),
+            stream: BufStream::new(MaybeTlsSyncStream::Plain(stream)),
             poisoned: false,
             phantom: PhantomData,
         })
     }
 
-    pub(crate) fn connect_from(sport: Option<u16>, addr: &SocketAddr) -> Result<Self, io::Error> {
-        let f = move || {
-            let s = net2::TcpBuilder::new_v4()?
-                .reuse_address(true)?
-                .bind((Ipv4Addr::UNSPECIFIED, sport.unwrap_or(0)))?
-                .connect(addr)?;
-            s.set_nodelay(true)?;
-            Self::new(s)
-        };
-
-        if tokio::runtime::Handle::try_current().is_ok() {
+    /// Send on `stream`, wrapped in TLS if `tls` is set.
+    pub fn with_tls(
+        stream: std::net::TcpStream,
+        tls: Option<&TlsConfig>,
+    ) -> Result<Self, io::Error> {
+        stream.set_nodelay(true)?;
+        let f = move || tls::connect_sync(tls, stream);
+        let stream = if tokio::runtime::Handle::try_current().is_ok() {
             tokio::task::block_in_place(f)
         } else {
             f()
-        }
+        }?;
+
+        Ok(Self {
+            stream: BufStream::new(stream),
+            poisoned: false,
+            phantom: PhantomData,
+        })
     }
 
     pub fn connect(addr: &SocketAddr) -> Result<Self, io::Error> {
-        Self::connect_from(None, addr)
+        Self::new(connect_raw(None, addr)?)
     }
 
-    pub fn get_mut(&mut self) -> &mut BufStream<std::net::TcpStream> {
-        &mut self.stream
+    /// Connect to `addr`, over TLS if `tls` is set.
+    pub fn connect_tls(addr: &SocketAddr, tls: Option<&TlsConfig>) -> Result<Self, io::Error> {
+        Self::with_tls(connect_raw(None, addr)?, tls)
     }
 
-    pub(crate) fn into_inner(self) -> BufStream<std::net::TcpStream> {
-        self.stream
+    pub fn get_mut(&mut self) -> &mut BufStream<MaybeTlsSyncStream> {
+        &mut self.stream
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -158,9 +184,9 @@ pub enum RecvError {
 
 #[pin_project(project = DualTcpStreamProj)]
 pub enum DualTcpStream<S, T, T2, D> {
-    Passthrough(#[pin] AsyncBincodeStream<S, T, Tagged<()>, D>),
+    Passthrough(#[pin] AsyncBincodeStream<S, T, Tagged<WriteAck>, D>),
     Upgrade(
-        #[pin] AsyncBincodeStream<S, T2, Tagged<()>, D>,
+        #[pin] AsyncBincodeStream<S, T2, Tagged<WriteAck>, D>,
         Box<dyn FnMut(T2) -> T + Send + Sync>,
     ),
 }
@@ -173,7 +199,7 @@ impl<S, T, T2> From<S> for DualTcpStream<S, T, T2, AsyncDestination> {
 
 impl<S, T, T2> DualTcpStream<S, T, T2, AsyncDestination> {
     pub fn upgrade<F: 'static + FnMut(T2) -> T + Send + Sync>(stream: S, f: F) -> Self {
-        let s: AsyncBincodeStream<S, T2, Tagged<()>, AsyncDestination> =
+        let s: AsyncBincodeStream<S, T2, Tagged<WriteAck>, AsyncDestination> =
             AsyncBincodeStream::from(stream).for_async();
         DualTcpStream::Upgrade(s, Box::new(f))
     }
@@ -186,11 +212,11 @@ impl<S, T, T2> DualTcpStream<S, T, T2, AsyncDestination> {
     }
 }
 
-impl<S, T, T2, D> Sink<Tagged<()>> for DualTcpStream<S, T, T2, D>
+impl<S, T, T2, D> Sink<Tagged<WriteAck>> for DualTcpStream<S, T, T2, D>
 where
     S: AsyncWrite,
-    AsyncBincodeStream<S, T, Tagged<()>, D>: Sink<Tagged<()>, Error = bincode::Error>,
-    AsyncBincodeStream<S, T2, Tagged<()>, D>: Sink<Tagged<()>, Error = bincode::Error>,
+    AsyncBincodeStream<S, T, Tagged<WriteAck>, D>: Sink<Tagged<WriteAck>, Error = bincode::Error>,
+    AsyncBincodeStream<S, T2, Tagged<WriteAck>, D>: Sink<Tagged<WriteAck>, Error = bincode::Error>,
 {
     type Error = bincode::Error;
 
@@ -201,7 +227,7 @@ where
         }
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Tagged<()>) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, item: Tagged<WriteAck>) -> Result<(), Self::Error> {
         match self.project() {
             DualTcpStreamProj::Passthrough(abs) => abs.start_send(item),
             DualTcpStreamProj::Upgrade(abs, _) => abs.start_send(item),
@@ -228,8 +254,8 @@ where
     for<'a> T: Deserialize<'a>,
     for<'a> T2: Deserialize<'a>,
     S: AsyncRead,
-    AsyncBincodeStream<S, T, Tagged<()>, D>: Stream<Item = Result<T, bincode::Error>>,
-    AsyncBincodeStream<S, T2, Tagged<()>, D>: Stream<Item = Result<T2, bincode::Error>>,
+    AsyncBincodeStream<S, T, Tagged<WriteAck>, D>: Stream<Item = Result<T, bincode::Error>>,
+    AsyncBincodeStream<S, T2, Tagged<WriteAck>, D>: Stream<Item = Result<T2, bincode::Error>>,
 {
     type Item = Result<T, bincode::Error>;
 
@@ -0,0 +1,406 @@
+//! Optional TLS for the connections that clients make to Noria, and that Noria servers make to
+//! one another.
+//!
+//! All members of a deployment share a single [`TlsConfig`]: the certificate authority that
+//! signed the servers' certificates, the name those certificates were issued for, and (on
+//! servers, or on clients when mutual TLS is in use) a certificate and private key to present to
+//! the other side. Certificates are checked against the configured name rather than the peer's
+//! address, since Noria addresses its workers by IP.
+
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader, Read, Write};
+use std::mem::MaybeUninit;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientSession, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig, Session, StreamOwned,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tower_service::Service;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// TLS settings for connections to and between Noria servers.
+///
+/// A `TlsConfig` that only has a certificate authority can be used by clients. Servers also need
+/// an identity, set with [`TlsConfig::with_identity`].
+#[derive(Clone)]
+pub struct TlsConfig {
+    server_name: String,
+    roots: RootCertStore,
+    identity: Option<(Vec<Certificate>, PrivateKey)>,
+    mutual: bool,
+
+    client: Arc<ClientConfig>,
+    server: Option<Arc<ServerConfig>>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("server_name", &self.server_name)
+            .field("has_identity", &self.identity.is_some())
+            .field("mutual", &self.mutual)
+            .finish()
+    }
+}
+
+impl TlsConfig {
+    /// Trust server certificates that are signed by the PEM-encoded certificate authority at
+    /// `ca`, and that were issued for `server_name`.
+    pub fn new<P: AsRef<Path>>(ca: P, server_name: &str) -> io::Result<Self> {
+        DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| invalid(format!("{} is not a valid server name", server_name)))?;
+
+        let ca = ca.as_ref();
+        let mut roots = RootCertStore::empty();
+        match roots.add_pem_file(&mut open(ca)?) {
+            Ok((valid, _)) if valid > 0 => {}
+            _ => {
+                return Err(invalid(format!(
+                    "{} does not contain a valid certificate",
+                    ca.display()
+                )))
+            }
+        }
+
+        TlsConfig {
+            server_name: server_name.to_owned(),
+            roots,
+            identity: None,
+            mutual: false,
+            client: Arc::new(ClientConfig::new()),
+            server: None,
+        }
+        .finish()
+    }
+
+    /// Present the PEM-encoded certificate chain at `cert` and the PEM-encoded (PKCS#8 or RSA)
+    /// private key at `key` to peers.
+    ///
+    /// This is required for servers, and for clients that connect to servers that
+    /// [require client certificates](TlsConfig::require_client_certs).
+    pub fn with_identity<P1, P2>(mut self, cert: P1, key: P2) -> io::Result<Self>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let (cert, key) = (cert.as_ref(), key.as_ref());
+        let certs = pemfile::certs(&mut open(cert)?)
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or_else(|| invalid(format!("{} contains no certificates", cert.display())))?;
+
+        let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?).unwrap_or_default();
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut open(key)?).unwrap_or_default();
+        }
+        if keys.is_empty() {
+            return Err(invalid(format!(
+                "{} contains no private key",
+                key.display()
+            )));
+        }
+
+        self.identity = Some((certs, keys.swap_remove(0)));
+        self.finish()
+    }
+
+    /// Only accept connections from peers that present a certificate signed by the certificate
+    /// authority.
+    ///
+    /// With this set, workers can only join the deployment if they hold a valid certificate, and
+    /// every client must be configured [with an identity](TlsConfig::with_identity) too.
+    pub fn require_client_certs(mut self) -> io::Result<Self> {
+        self.mutual = true;
+        self.finish()
+    }
+
+    /// The name that server certificates must be issued for.
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    fn finish(mut self) -> io::Result<Self> {
+        let mut client = ClientConfig::new();
+        client.root_store = self.roots.clone();
+
+        self.server = if let Some((ref certs, ref key)) = self.identity {
+            client
+                .set_single_client_cert(certs.clone(), key.clone())
+                .map_err(|e| invalid(format!("bad client identity: {}", e)))?;
+
+            let verifier = if self.mutual {
+                AllowAnyAuthenticatedClient::new(self.roots.clone())
+            } else {
+                NoClientAuth::new()
+            };
+            let mut server = ServerConfig::new(verifier);
+            server
+                .set_single_cert(certs.clone(), key.clone())
+                .map_err(|e| invalid(format!("bad server identity: {}", e)))?;
+            Some(Arc::new(server))
+        } else {
+            None
+        };
+        self.client = Arc::new(client);
+        Ok(self)
+    }
+
+    /// Perform the client side of the TLS handshake over `stream`.
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<MaybeTlsStream> {
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .expect("server name is checked on construction");
+        TlsConnector::from(self.client.clone())
+            .connect(name, stream)
+            .await
+            .map(MaybeTlsStream::Client)
+    }
+
+    /// Perform the client side of the TLS handshake over the blocking `stream`.
+    pub fn connect_sync(&self, mut stream: std::net::TcpStream) -> io::Result<MaybeTlsSyncStream> {
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .expect("server name is checked on construction");
+        let mut session = ClientSession::new(&self.client, name);
+        while session.is_handshaking() {
+            session.complete_io(&mut stream)?;
+        }
+        Ok(MaybeTlsSyncStream::Client(Box::new(StreamOwned::new(
+            session, stream,
+        ))))
+    }
+
+    /// Perform the server side of the TLS handshake over `stream`.
+    ///
+    /// Fails if this configuration does not have an identity.
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<MaybeTlsStream> {
+        let server = self.server.clone().ok_or_else(|| {
+            invalid("accepting TLS connections requires a server identity".to_owned())
+        })?;
+        TlsAcceptor::from(server)
+            .accept(stream)
+            .await
+            .map(MaybeTlsStream::Server)
+    }
+}
+
+/// Perform the client side of a TLS handshake over `stream` if `tls` is set.
+pub async fn connect(tls: Option<&TlsConfig>, stream: TcpStream) -> io::Result<MaybeTlsStream> {
+    match tls {
+        Some(tls) => tls.connect(stream).await,
+        None => Ok(MaybeTlsStream::Plain(stream)),
+    }
+}
+
+/// Perform the client side of a TLS handshake over the blocking `stream` if `tls` is set.
+pub fn connect_sync(
+    tls: Option<&TlsConfig>,
+    stream: std::net::TcpStream,
+) -> io::Result<MaybeTlsSyncStream> {
+    match tls {
+        Some(tls) => tls.connect_sync(stream),
+        None => Ok(MaybeTlsSyncStream::Plain(stream)),
+    }
+}
+
+/// Perform the server side of a TLS handshake over `stream` if `tls` is set.
+pub async fn accept(tls: Option<&TlsConfig>, stream: TcpStream) -> io::Result<MaybeTlsStream> {
+    match tls {
+        Some(tls) => tls.accept(stream).await,
+        None => Ok(MaybeTlsStream::Plain(stream)),
+    }
+}
+
+/// A TCP connection that may or may not be wrapped in TLS.
+#[derive(Debug)]
+pub enum MaybeTlsStream {
+    /// An unencrypted connection.
+    Plain(TcpStream),
+    /// The client end of a TLS connection.
+    Client(tokio_rustls::client::TlsStream<TcpStream>),
+    /// The server end of a TLS connection.
+    Server(tokio_rustls::server::TlsStream<TcpStream>),
+}
+
+impl MaybeTlsStream {
+    /// The underlying TCP connection.
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self {
+            MaybeTlsStream::Plain(ref s) => s,
+            MaybeTlsStream::Client(ref s) => s.get_ref().0,
+            MaybeTlsStream::Server(ref s) => s.get_ref().0,
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        match *self {
+            MaybeTlsStream::Plain(ref s) => s.prepare_uninitialized_buffer(buf),
+            MaybeTlsStream::Client(ref s) => s.prepare_uninitialized_buffer(buf),
+            MaybeTlsStream::Server(ref s) => s.prepare_uninitialized_buffer(buf),
+        }
+    }
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Client(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Server(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Client(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Server(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Client(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Server(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Client(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Server(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A blocking TCP connection that may or may not be wrapped in TLS.
+pub enum MaybeTlsSyncStream {
+    /// An unencrypted connection.
+    Plain(std::net::TcpStream),
+    /// The client end of a TLS connection.
+    Client(Box<StreamOwned<ClientSession, std::net::TcpStream>>),
+}
+
+impl MaybeTlsSyncStream {
+    /// The underlying TCP connection.
+    pub fn get_ref(&self) -> &std::net::TcpStream {
+        match *self {
+            MaybeTlsSyncStream::Plain(ref s) => s,
+            MaybeTlsSyncStream::Client(ref s) => &s.sock,
+        }
+    }
+}
+
+impl Read for MaybeTlsSyncStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTlsSyncStream::Plain(ref mut s) => s.read(buf),
+            MaybeTlsSyncStream::Client(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MaybeTlsSyncStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTlsSyncStream::Plain(ref mut s) => s.write(buf),
+            MaybeTlsSyncStream::Client(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeTlsSyncStream::Plain(ref mut s) => s.flush(),
+            MaybeTlsSyncStream::Client(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl hyper::client::connect::Connection for MaybeTlsStream {
+    fn connected(&self) -> hyper::client::connect::Connected {
+        hyper::client::connect::Connected::new()
+    }
+}
+
+/// A `hyper` connector that speaks TLS to the controller if configured to.
+#[derive(Clone)]
+pub(crate) struct HttpsConnector {
+    http: hyper::client::HttpConnector,
+    tls: Option<TlsConfig>,
+}
+
+impl HttpsConnector {
+    pub(crate) fn new(tls: Option<TlsConfig>) -> Self {
+        let mut http = hyper::client::HttpConnector::new();
+        http.enforce_http(false);
+        HttpsConnector { http, tls }
+    }
+}
+
+impl Service<hyper::Uri> for HttpsConnector {
+    type Response = MaybeTlsStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let connecting = self.http.call(uri);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            Ok(connect(tls.as_ref(), stream).await?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_rejects_bad_configs() {
+        let dir = std::env::temp_dir().join(format!("noria-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bogus = dir.join("bogus.pem");
+        std::fs::write(&bogus, "not a certificate").unwrap();
+
+        assert!(TlsConfig::new(dir.join("missing.pem"), "noria").is_err());
+        assert!(TlsConfig::new(&bogus, "noria").is_err());
+        assert!(TlsConfig::new(&bogus, "not a name!").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -12,6 +12,7 @@ pub use self::zk::ZookeeperAuthority;
 
 pub const CONTROLLER_KEY: &str = "/controller";
 pub const STATE_KEY: &str = "/state";
+pub const AUDIT_LOG_KEY: &str = "/audit_log";
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Epoch(i64);
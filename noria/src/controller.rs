@@ -1,8 +1,13 @@
+use crate::channel::tls::{HttpsConnector, TlsConfig};
 use crate::consensus::{self, Authority};
-use crate::debug::stats;
+use crate::debug::{replays, stats};
+use crate::internal::DomainIndex;
 use crate::table::{Table, TableBuilder, TableRpc};
 use crate::view::{View, ViewBuilder, ViewRpc};
-use crate::ActivationResult;
+use crate::{
+    ActivationResult, AuditEntry, DataType, MaterializationReport, RecipeExplanation,
+    RecipeVersion, Role,
+};
 use failure::{self, ResultExt};
 use futures_util::future;
 use petgraph::graph::NodeIndex;
@@ -31,9 +36,37 @@ pub struct ControllerDescriptor {
     pub nonce: u64,
 }
 
+/// How to reach the servers of a Noria deployment.
+///
+/// By default, connections are unencrypted and unauthenticated. If the deployment was started
+/// with TLS or with an access token, the same settings must be given here.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    tls: Option<TlsConfig>,
+    auth_token: Option<String>,
+}
+
+impl ConnectOptions {
+    /// Connect to the controller, the base tables and the views over TLS.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Present `token` to the controller, the base tables and the views. When the deployment was
+    /// started with an access token, the token's roles decide what the connection may do (for
+    /// example, whether it may write to tables or extend the recipe).
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+}
+
 struct Controller<A> {
     authority: Arc<A>,
-    client: hyper::Client<hyper::client::HttpConnector>,
+    client: hyper::Client<HttpsConnector>,
+    scheme: &'static str,
+    auth_token: Option<String>,
 }
 
 #[derive(Debug)]
@@ -70,6 +103,8 @@ where
     fn call(&mut self, req: ControllerRequest) -> Self::Future {
         let client = self.client.clone();
         let auth = self.authority.clone();
+        let scheme = self.scheme;
+        let token = self.auth_token.clone();
         let path = req.path;
         let body = req.request;
 
@@ -85,12 +120,17 @@ where
                     )
                     .context("failed to deserialize authority reply")?;
 
-                    url = Some(format!("http://{}/{}", descriptor.external_addr, path));
+                    url = Some(format!(
+                        "{}://{}/{}",
+                        scheme, descriptor.external_addr, path
+                    ));
                 }
 
-                let r = hyper::Request::post(url.as_ref().unwrap())
-                    .body(hyper::Body::from(body.clone()))
-                    .unwrap();
+                let mut r = hyper::Request::post(url.as_ref().unwrap());
+                if let Some(ref token) = token {
+                    r = r.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token));
+                }
+                let r = r.body(hyper::Body::from(body.clone())).unwrap();
 
                 let res = client
                     .request(r)
@@ -104,6 +144,12 @@ where
 
                 match status {
                     hyper::StatusCode::OK => return Ok(body),
+                    hyper::StatusCode::UNAUTHORIZED => {
+                        bail!("rpc call to {} was not authorized", path)
+                    }
+                    hyper::StatusCode::FORBIDDEN => {
+                        bail!("rpc call to {} is not allowed for this principal", path)
+                    }
                     hyper::StatusCode::INTERNAL_SERVER_ERROR => bail!(
                         "rpc call to {} failed: {}",
                         path,
@@ -147,6 +193,8 @@ where
     handle: Buffer<Controller<A>, ControllerRequest>,
     domains: Arc<Mutex<HashMap<(SocketAddr, usize), TableRpc>>>,
     views: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+    tls: Option<TlsConfig>,
+    auth_token: Option<String>,
     tracer: tracing::Dispatch,
 }
 
@@ -159,6 +207,8 @@ where
             handle: self.handle.clone(),
             domains: self.domains.clone(),
             views: self.views.clone(),
+            tls: self.tls.clone(),
+            auth_token: self.auth_token.clone(),
             tracer: self.tracer.clone(),
         }
     }
@@ -173,6 +223,42 @@ impl ControllerHandle<consensus::ZookeeperAuthority> {
     }
 }
 
+/// A recipe change that Noria rejected.
+///
+/// When a recipe is rejected, none of it is applied, and the previous recipe stays in place.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+pub enum RecipeError {
+    /// The recipe text could not be parsed.
+    #[fail(display = "failed to parse recipe: {}", _0)]
+    Parse(String),
+
+    /// A query uses a SQL construct that Noria does not support.
+    #[fail(
+        display = "query \"{}\" uses an unsupported construct: {}",
+        query, construct
+    )]
+    Unsupported {
+        /// The name of the offending query.
+        query: String,
+        /// The construct that isn't supported.
+        construct: String,
+    },
+
+    /// A query is supported in principle, but can't be added as written (e.g., because it refers
+    /// to a table that does not exist).
+    #[fail(display = "query \"{}\" is invalid: {}", query, reason)]
+    Invalid {
+        /// The name of the offending query.
+        query: String,
+        /// Why the query was rejected.
+        reason: String,
+    },
+
+    /// The recipe change failed for some other reason.
+    #[fail(display = "{}", _0)]
+    Other(String),
+}
+
 // this alias is needed to work around -> impl Trait capturing _all_ lifetimes by default
 // the A parameter is needed so it gets captured into the impl Trait
 #[cfg(not(doc))]
@@ -200,18 +286,36 @@ where
 impl<A: Authority + 'static> ControllerHandle<A> {
     #[doc(hidden)]
     pub async fn make(authority: Arc<A>) -> Result<Self, failure::Error> {
+        Self::make_with(authority, ConnectOptions::default()).await
+    }
+
+    #[doc(hidden)]
+    pub async fn make_with(
+        authority: Arc<A>,
+        options: ConnectOptions,
+    ) -> Result<Self, failure::Error> {
         // need to use lazy otherwise current executor won't be known
         let tracer = tracing::dispatcher::get_default(|d| d.clone());
+        let scheme = if options.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
         Ok(ControllerHandle {
             views: Default::default(),
             domains: Default::default(),
             handle: Buffer::new(
                 Controller {
                     authority,
-                    client: hyper::Client::new(),
+                    client: hyper::Client::builder()
+                        .build(HttpsConnector::new(options.tls.clone())),
+                    scheme,
+                    auth_token: options.auth_token.clone(),
                 },
                 1,
             ),
+            tls: options.tls,
+            auth_token: options.auth_token,
             tracer,
         })
     }
@@ -245,6 +349,15 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         Self::make(Arc::new(authority)).await
     }
 
+    /// Like `ControllerHandle::new`, but connects using the given TLS and authentication
+    /// settings.
+    pub async fn new_with(authority: A, options: ConnectOptions) -> Result<Self, failure::Error>
+    where
+        A: Send + 'static,
+    {
+        Self::make_with(Arc::new(authority), options).await
+    }
+
     /// Enumerate all known base tables.
     ///
     /// These have all been created in response to a `CREATE TABLE` statement in a recipe.
@@ -304,6 +417,8 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         assert_infrequent::at_most(200);
 
         let views = self.views.clone();
+        let tls = self.tls.clone();
+        let token = self.auth_token.clone();
         let name = name.to_string();
         let fut = self
             .handle
@@ -315,7 +430,9 @@ impl<A: Authority + 'static> ControllerHandle<A> {
                 .context("failed to fetch view builder")?;
 
             match serde_json::from_slice::<Option<ViewBuilder>>(&body) {
-                Ok(Some(vb)) => Ok(vb.build(views)?),
+                Ok(Some(vb)) => {
+                    Ok(vb.build(views, tls.as_ref(), token.as_ref().map(String::as_str))?)
+                }
                 Ok(None) => Err(failure::err_msg("view does not exist")),
                 Err(e) => Err(failure::Error::from(e)),
             }
@@ -334,6 +451,8 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         assert_infrequent::at_most(200);
 
         let domains = self.domains.clone();
+        let tls = self.tls.clone();
+        let token = self.auth_token.clone();
         let name = name.to_string();
         let fut = self
             .handle
@@ -346,7 +465,9 @@ impl<A: Authority + 'static> ControllerHandle<A> {
                 .context("failed to fetch table builder")?;
 
             match serde_json::from_slice::<Option<TableBuilder>>(&body) {
-                Ok(Some(tb)) => Ok(tb.build(domains)?),
+                Ok(Some(tb)) => {
+                    Ok(tb.build(domains, tls.as_ref(), token.as_ref().map(String::as_str))?)
+                }
                 Ok(None) => Err(failure::err_msg("view table not exist")),
                 Err(e) => Err(failure::Error::from(e)),
             }
@@ -354,6 +475,56 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         }
     }
 
+    /// Obtain a `Table` for the given base table whose writes must satisfy the write policies of
+    /// the user universe `universe`.
+    ///
+    /// Operations that the universe's policies don't allow fail with
+    /// [`TableError::PolicyViolation`](crate::error::TableError::PolicyViolation). Tables with
+    /// write policies can only be written to through such handles. When access control is
+    /// enforced, only the principal named after the universe and security administrators may
+    /// obtain one.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn universe_table(
+        &mut self,
+        name: &str,
+        universe: DataType,
+    ) -> impl Future<Output = Result<Table, failure::Error>> {
+        #[cfg(debug_assertions)]
+        assert_infrequent::at_most(200);
+
+        let domains = self.domains.clone();
+        let tls = self.tls.clone();
+        let token = self.auth_token.clone();
+        let name = name.to_string();
+        let fut = self
+            .handle
+            .call(ControllerRequest::new("universe_table_builder", (&name, &universe)).unwrap());
+
+        async move {
+            let body: hyper::body::Bytes = fut
+                .await
+                .map_err(failure::Context::new)
+                .context("failed to fetch table builder")?;
+
+            match serde_json::from_slice::<Option<TableBuilder>>(&body) {
+                Ok(Some(tb)) => {
+                    Ok(tb.build(domains, tls.as_ref(), token.as_ref().map(String::as_str))?)
+                }
+                Ok(None) => Err(failure::err_msg("table or universe does not exist")),
+                Err(e) => Err(failure::Error::from(e)),
+            }
+            .map_err(move |e| {
+                e.context(format!(
+                    "building table for {} in universe {}",
+                    name,
+                    universe.to_string()
+                ))
+                .into()
+            })
+        }
+    }
+
     #[doc(hidden)]
     pub fn rpc<Q: Serialize, R: 'static>(
         &mut self,
@@ -379,6 +550,48 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         self.rpc("get_statistics", (), "failed to get stats")
     }
 
+    /// Get the partial replays that most recently filled holes in each domain, oldest first.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn replay_log(
+        &mut self,
+    ) -> impl Future<Output = Result<Vec<replays::ReplayLogEntry>, failure::Error>> {
+        self.rpc("replay_log", (), "failed to get replay log")
+    }
+
+    /// Get cardinality statistics for the base tables, along with advice on which nodes' state
+    /// would be better off fully or partially materialized given how it has been read so far.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn materialization_report(
+        &mut self,
+    ) -> impl Future<Output = Result<MaterializationReport, failure::Error>> {
+        self.rpc(
+            "materialization_report",
+            (),
+            "failed to get materialization report",
+        )
+    }
+
+    /// Follow the current advice from `Self::materialization_report` in future migrations, and
+    /// return the names of the nodes it was applied to.
+    ///
+    /// Existing state is not rebuilt. Instead, nodes that are advised to be fully materialized are
+    /// fully materialized whenever they are next added to the graph, such as when their query is
+    /// re-added after having been removed. Advice to partially materialize a node is only a hint to
+    /// restructure the query or enable partial materialization, and is not applied.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn apply_materialization_advice(
+        &mut self,
+    ) -> impl Future<Output = Result<Vec<String>, failure::Error>> {
+        self.rpc(
+            "apply_materialization_advice",
+            (),
+            "failed to apply materialization advice",
+        )
+    }
+
     /// Flush all partial state, evicting all rows present.
     ///
     /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
@@ -388,22 +601,86 @@ impl<A: Authority + 'static> ControllerHandle<A> {
 
     /// Extend the existing recipe with the given set of queries.
     ///
+    /// If the recipe is rejected, the error is a [`RecipeError`], and the existing recipe is left
+    /// unchanged.
+    ///
     /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
     pub fn extend_recipe(
         &mut self,
         recipe_addition: &str,
     ) -> impl Future<Output = Result<ActivationResult, failure::Error>> {
-        self.rpc("extend_recipe", recipe_addition, "failed to extend recipe")
+        let fut = self.rpc::<_, Result<ActivationResult, RecipeError>>(
+            "extend_recipe",
+            recipe_addition,
+            "failed to extend recipe",
+        );
+        async move { Ok(fut.await??) }
+    }
+
+    /// Find out what extending the existing recipe with the given set of queries would do,
+    /// without changing anything.
+    ///
+    /// Queries that Noria can't run are reported as a [`RecipeError`].
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn explain_recipe(
+        &mut self,
+        recipe_addition: &str,
+    ) -> impl Future<Output = Result<RecipeExplanation, failure::Error>> {
+        let fut = self.rpc::<_, Result<RecipeExplanation, RecipeError>>(
+            "explain_recipe",
+            recipe_addition,
+            "failed to explain recipe",
+        );
+        async move { Ok(fut.await??) }
     }
 
     /// Replace the existing recipe with this one.
     ///
+    /// If the recipe is rejected, the error is a [`RecipeError`], and the existing recipe is left
+    /// unchanged.
+    ///
     /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
     pub fn install_recipe(
         &mut self,
         new_recipe: &str,
     ) -> impl Future<Output = Result<ActivationResult, failure::Error>> {
-        self.rpc("install_recipe", new_recipe, "failed to install recipe")
+        let fut = self.rpc::<_, Result<ActivationResult, RecipeError>>(
+            "install_recipe",
+            new_recipe,
+            "failed to install recipe",
+        );
+        async move { Ok(fut.await??) }
+    }
+
+    /// List the stored versions of the recipe, oldest first.
+    ///
+    /// Only the most recent versions are kept, and older ones can no longer be rolled back to.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn recipe_history(
+        &mut self,
+    ) -> impl Future<Output = Result<Vec<RecipeVersion>, failure::Error>> {
+        self.rpc("recipe_history", (), "failed to get recipe history")
+    }
+
+    /// Return the recipe to how it was at an earlier `version`, removing queries added since and
+    /// re-adding ones that have been removed.
+    ///
+    /// The rollback is recorded as a new version of the recipe. If it is rejected, the error is a
+    /// [`RecipeError`], and the existing recipe is left unchanged.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn rollback_to(
+        &mut self,
+        version: usize,
+    ) -> impl Future<Output = Result<ActivationResult, failure::Error>> {
+        let fut = self.rpc::<_, Result<ActivationResult, RecipeError>>(
+            "rollback_to",
+            version,
+            "failed to roll back recipe",
+        );
+        async move { Ok(fut.await??) }
     }
 
     /// Fetch a graphviz description of the dataflow graph.
@@ -434,4 +711,112 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         // TODO: this should likely take a view name, and we should verify that it's a Reader.
         self.rpc("remove_node", view, "failed to remove node")
     }
+
+    /// Remove the security universe identified by `context`, which must have the same `id` (and
+    /// `group`, for group universes) that it was created with.
+    ///
+    /// Every query and policy node that was added for the universe is removed, and domains that
+    /// are left without nodes are shut down. View handles for the universe's views stop working.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn destroy_universe(
+        &mut self,
+        context: HashMap<String, DataType>,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc(
+            "destroy_universe",
+            context,
+            "failed to destroy security universe",
+        )
+    }
+
+    /// Give the principal `name` exactly the given roles, creating it if it doesn't exist yet.
+    ///
+    /// When a new principal is created, the access token it has to present is returned. The token
+    /// can't be retrieved later. Requires the `SecurityAdmin` role.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn set_principal(
+        &mut self,
+        name: &str,
+        roles: Vec<Role>,
+    ) -> impl Future<Output = Result<Option<String>, failure::Error>> {
+        self.rpc("set_principal", (name, roles), "failed to set principal")
+    }
+
+    /// Remove the principal `name`, so that its access token no longer works. Requires the
+    /// `SecurityAdmin` role.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn remove_principal(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("remove_principal", name, "failed to remove principal")
+    }
+
+    /// List the principals and the roles they hold. Requires the `SecurityAdmin` role.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn principals(
+        &mut self,
+    ) -> impl Future<Output = Result<BTreeMap<String, Vec<Role>>, failure::Error>> {
+        self.rpc("principals", (), "failed to list principals")
+    }
+
+    /// Fetch the most recent entries of the access control audit log, oldest first. Requires the
+    /// `SecurityAdmin` role.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn audit_log(&mut self) -> impl Future<Output = Result<Vec<AuditEntry>, failure::Error>> {
+        self.rpc("audit_log", (), "failed to fetch audit log")
+    }
+
+    /// Move a shard of a running domain to the given worker.
+    ///
+    /// Existing table handles keep working, but view handles for views in the moved domain must
+    /// be fetched again.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn move_domain(
+        &mut self,
+        domain: DomainIndex,
+        shard: usize,
+        worker: SocketAddr,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc(
+            "move_domain",
+            (domain, shard, worker),
+            "failed to move domain",
+        )
+    }
+
+    /// Move domain shards from the busiest workers to the least busy ones, based on how much time
+    /// each domain has spent processing since the last time they were rebalanced. Returns the
+    /// shards that were moved, and where to.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn rebalance(
+        &mut self,
+    ) -> impl Future<Output = Result<Vec<(DomainIndex, usize, SocketAddr)>, failure::Error>> {
+        self.rpc("rebalance", (), "failed to rebalance")
+    }
+
+    /// Change how many shards the table or view called `name` is split into, without
+    /// interrupting reads or writes.
+    ///
+    /// View handles for the view must be fetched again once this completes. A table's rows are
+    /// moved to a new base, and the queries that read from it are rebuilt on top of that, so view
+    /// handles for those queries must be fetched again too. Table handles fetched before the move
+    /// keep working, but their writes go through the old base, which acknowledges them as soon as
+    /// it has passed them on. Fetch new ones to write to the new base directly.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn reshard(
+        &mut self,
+        name: &str,
+        shards: usize,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("reshard", (name, shards), "failed to reshard")
+    }
 }
@@ -2,7 +2,7 @@ use arccstr::ArcCStr;
 
 use chrono::{self, NaiveDate, NaiveDateTime};
 
-use nom_sql::Literal;
+use nom_sql::{Literal, SqlType};
 
 use std::convert::TryFrom;
 use std::fmt;
@@ -144,6 +144,76 @@ impl DataType {
             _ => false,
         }
     }
+
+    /// Converts this value into the representation used for columns of SQL type `ty`, as needed
+    /// when the type of an existing column changes.
+    ///
+    /// Returns `None` if the value has no representation in the new type, such as text that
+    /// doesn't parse as a number. `NULL` stays `NULL`, and values are left unchanged for types
+    /// that aren't distinguished by `DataType`.
+    pub fn coerce_to(&self, ty: &SqlType) -> Option<DataType> {
+        match (ty, self) {
+            (_, DataType::None) => Some(DataType::None),
+            (SqlType::Bool, _)
+            | (SqlType::Tinyint(_), _)
+            | (SqlType::Int(_), _)
+            | (SqlType::UnsignedInt(_), _)
+            | (SqlType::Bigint(_), _)
+            | (SqlType::UnsignedBigint(_), _) => match *self {
+                DataType::Real(i, _) => Some(i.into()),
+                DataType::Text(..) | DataType::TinyText(..) => {
+                    let s: &str = self.into();
+                    s.trim().parse::<i64>().ok().map(DataType::from)
+                }
+                DataType::Timestamp(ts) => Some(ts.timestamp().into()),
+                _ => Some(self.clone()),
+            },
+            (SqlType::Float, _)
+            | (SqlType::Double, _)
+            | (SqlType::Real, _)
+            | (SqlType::Decimal(..), _) => match *self {
+                DataType::Int(_)
+                | DataType::UnsignedInt(_)
+                | DataType::BigInt(_)
+                | DataType::UnsignedBigInt(_) => {
+                    let i: i128 = self.into();
+                    Some((i as f64).into())
+                }
+                DataType::Text(..) | DataType::TinyText(..) => {
+                    let s: &str = self.into();
+                    s.trim().parse::<f64>().ok().map(DataType::from)
+                }
+                DataType::Timestamp(_) => None,
+                _ => Some(self.clone()),
+            },
+            (SqlType::Char(_), _)
+            | (SqlType::Varchar(_), _)
+            | (SqlType::Tinytext, _)
+            | (SqlType::Mediumtext, _)
+            | (SqlType::Longtext, _)
+            | (SqlType::Text, _) => match *self {
+                DataType::Text(..) | DataType::TinyText(..) => Some(self.clone()),
+                DataType::Timestamp(ts) => Some(ts.format("%Y-%m-%d %H:%M:%S").to_string().into()),
+                _ => Some(self.to_string().into()),
+            },
+            (SqlType::Date, _) | (SqlType::DateTime(_), _) | (SqlType::Timestamp, _) => match *self
+            {
+                DataType::Timestamp(_) => Some(self.clone()),
+                DataType::Text(..) | DataType::TinyText(..) => {
+                    let s: &str = self.into();
+                    let s = s.trim();
+                    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+                        .or_else(|_| {
+                            NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms(0, 0, 0))
+                        })
+                        .ok()
+                        .map(DataType::Timestamp)
+                }
+                _ => None,
+            },
+            _ => Some(self.clone()),
+        }
+    }
 }
 
 impl PartialEq for DataType {
@@ -728,6 +798,20 @@ impl TableOperation {
             _ => None,
         }
     }
+
+    /// The values of the columns `key` that pick the shard of a table that this operation goes
+    /// to. Deletes and updates carry those values as the first part of their key.
+    #[doc(hidden)]
+    pub fn shard_key(&self, key: &[usize]) -> Vec<DataType> {
+        match *self {
+            TableOperation::Insert(ref row) | TableOperation::InsertOrUpdate { ref row, .. } => {
+                key.iter().map(|&c| row[c].clone()).collect()
+            }
+            TableOperation::Delete { key: ref k } | TableOperation::Update { key: ref k, .. } => {
+                k.iter().take(key.len()).cloned().collect()
+            }
+        }
+    }
 }
 
 impl From<Vec<DataType>> for TableOperation {
@@ -1192,4 +1276,35 @@ mod tests {
         assert_ne!(hash(&long), hash(&time));
         assert_ne!(hash(&long), hash(&shrt6));
     }
+
+    #[test]
+    fn data_type_coercion() {
+        assert_eq!(
+            DataType::from("42").coerce_to(&SqlType::Int(32)),
+            Some(42.into())
+        );
+        assert_eq!(DataType::from("x").coerce_to(&SqlType::Int(32)), None);
+        assert_eq!(
+            DataType::from(2.5).coerce_to(&SqlType::Bigint(64)),
+            Some(2.into())
+        );
+        assert_eq!(
+            DataType::from(7).coerce_to(&SqlType::Real),
+            Some(7.0.into())
+        );
+        assert_eq!(
+            DataType::from(7).coerce_to(&SqlType::Varchar(10)),
+            Some("7".into())
+        );
+        assert_eq!(
+            DataType::from("2020-01-02").coerce_to(&SqlType::Timestamp),
+            Some(DataType::Timestamp(
+                NaiveDate::from_ymd(2020, 1, 2).and_hms(0, 0, 0)
+            ))
+        );
+        assert_eq!(
+            DataType::None.coerce_to(&SqlType::Text),
+            Some(DataType::None)
+        );
+    }
 }
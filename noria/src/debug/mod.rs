@@ -1,2 +1,4 @@
+/// Types related to partial replays.
+pub mod replays;
 /// Types related to graph statistics.
 pub mod stats;
@@ -0,0 +1,36 @@
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// A node that a partial replay went through.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayHop {
+    /// The node.
+    pub node: NodeIndex,
+    /// The columns of the node that the replayed keys are in, if the node is partially
+    /// materialized along this path.
+    pub partial_key: Option<Vec<usize>>,
+}
+
+/// A partial replay that recently filled a hole, as reported by `ControllerHandle::replay_log`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayLogEntry {
+    /// The domain the replay ended in.
+    pub domain: usize,
+    /// The shard of that domain.
+    pub shard: usize,
+    /// The tag of the replay path.
+    pub tag: u32,
+    /// The node that missed, and that the replay filled.
+    pub node: NodeIndex,
+    /// The number of keys that were replayed.
+    pub keys: usize,
+    /// The part of the replay path within the domain the replay ended in, ending with `node`.
+    pub path: Vec<ReplayHop>,
+    /// The number of rows the replay brought into `node`.
+    pub rows: usize,
+    /// When the replay finished.
+    pub finished: SystemTime,
+    /// How long it took from when the keys were first found missing until they were filled.
+    pub duration: Duration,
+}
@@ -40,6 +40,66 @@ pub struct NodeStats {
     pub materialized: MaterializationStatus,
     /// The value returned from Ingredient::probe.
     pub probe_result: HashMap<String, String>,
+    /// Lookups into and evictions from this node's state, if it is partially materialized.
+    #[serde(default)]
+    pub cache: CacheStats,
+    /// Number of rows in this node's state. The indices of partial state can each hold different
+    /// rows, in which case this is the number of rows in the largest one.
+    #[serde(default)]
+    pub rows: u64,
+    /// The key distribution of each index on this node's state.
+    #[serde(default)]
+    pub indices: Vec<IndexStats>,
+    /// Number of records this node has processed, not counting replays.
+    #[serde(default)]
+    pub records: u64,
+}
+
+/// Statistics about the keys of one index on a node's state.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexStats {
+    /// The columns the index is keyed on.
+    pub columns: Vec<usize>,
+    /// Number of rows in the index, if the state can tell cheaply.
+    pub rows: Option<u64>,
+    /// Number of distinct keys in the index, if the state can tell cheaply.
+    pub distinct_keys: Option<u64>,
+    /// Number of rows stored under the most common key, if the state can tell cheaply.
+    pub max_rows_per_key: Option<u64>,
+}
+
+/// Statistics about lookups into, and evictions from, partially materialized state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Number of lookups that found their key.
+    pub hits: u64,
+    /// Number of lookups that hit a hole, and had to wait for a replay.
+    pub misses: u64,
+    /// Number of keys evicted.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// The fraction of lookups that found their key, or `None` if there were no lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
+
+impl std::ops::Add for CacheStats {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        CacheStats {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            evictions: self.evictions + other.evictions,
+        }
+    }
 }
 
 /// Statistics about the Soup data-flow.
@@ -4,7 +4,9 @@
 mod addressing;
 mod external;
 mod proto;
+mod secret;
 
 pub use self::addressing::{DomainIndex, LocalNodeIndex};
 pub use self::external::MaterializationStatus;
 pub use self::proto::LocalOrNot;
+pub use self::secret::{hmac_sha256, secrets_match};
@@ -0,0 +1,60 @@
+//! Keyed hashes and comparisons for secrets that are shared across a deployment.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// Compute the HMAC-SHA256 (RFC 2104) of `msg` under `key`.
+pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    let mut hmac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    hmac.update(msg);
+
+    let mut mac = [0; 32];
+    mac.copy_from_slice(&hmac.finalize().into_bytes());
+    mac
+}
+
+/// Compare two secrets in time that does not depend on where they first differ.
+pub fn secrets_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn it_computes_rfc4231_macs() {
+        assert_eq!(
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // keys longer than a block are hashed first
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn it_compares_secrets() {
+        assert!(secrets_match(b"hunter2", b"hunter2"));
+        assert!(!secrets_match(b"hunter3", b"hunter2"));
+        assert!(!secrets_match(b"hunter", b"hunter2"));
+    }
+}
@@ -200,8 +200,10 @@ pub(crate) const VIEW_POOL_SIZE: usize = 16;
 /// batch less work, which means lower overall efficiency.
 pub(crate) const PENDING_LIMIT: usize = 8192;
 
+use nom_sql::SqlType;
 use petgraph::graph::NodeIndex;
 use std::collections::HashMap;
+use std::time::SystemTime;
 use tokio_tower::multiplex;
 
 mod controller;
@@ -245,6 +247,7 @@ pub mod results {
 
 /// Noria errors.
 pub mod error {
+    pub use crate::controller::RecipeError;
     pub use crate::table::TableError;
     pub use crate::view::ViewError;
 }
@@ -295,13 +298,14 @@ impl<T> From<T> for Tagged<T> {
     }
 }
 
-pub use crate::controller::{ControllerDescriptor, ControllerHandle};
+pub use crate::channel::tls::TlsConfig;
+pub use crate::controller::{ConnectOptions, ControllerDescriptor, ControllerHandle};
 pub use crate::data::{DataType, Modification, Operation, TableOperation};
 pub use crate::table::Table;
 pub use crate::view::View;
 
 #[doc(hidden)]
-pub use crate::table::Input;
+pub use crate::table::{Input, WriteAck, WriteGrant};
 
 #[doc(hidden)]
 pub use crate::view::{ReadQuery, ReadReply, ReadReplyBatch};
@@ -328,6 +332,166 @@ pub struct ActivationResult {
     pub expressions_removed: usize,
 }
 
+/// What extending the recipe would do, as reported by `ControllerHandle::explain_recipe`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RecipeExplanation {
+    /// Names of the queries and tables the extended recipe would expose.
+    pub queries: Vec<String>,
+    /// Nodes that would be added to the graph.
+    pub new_nodes: Vec<ExplainedNode>,
+    /// Names of existing nodes that the new nodes would build on.
+    pub reused_nodes: Vec<String>,
+    /// Roughly how many bytes of existing state would be replayed to fill the new fully
+    /// materialized nodes. Partial state is filled on demand, so it doesn't count.
+    pub estimated_replay_bytes: u64,
+}
+
+/// A node that extending the recipe would add.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExplainedNode {
+    /// The node's name.
+    pub name: String,
+    /// What the node does.
+    pub description: String,
+    /// Whether the node's state would be kept, and if so, whether fully or partially.
+    pub materialized: MaterializationStatus,
+}
+
+/// A stored version of the recipe, as reported by `ControllerHandle::recipe_history`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RecipeVersion {
+    /// The recipe's version number.
+    pub version: usize,
+    /// How this version was produced from the one before it.
+    pub change: RecipeChange,
+    /// The recipe text that produced this version. For a rollback, this is the full text of the
+    /// recipe that was restored.
+    pub text: String,
+    /// When this version was applied.
+    pub applied_at: SystemTime,
+}
+
+/// How a recipe version was produced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RecipeChange {
+    /// The recipe was replaced using `ControllerHandle::install_recipe`.
+    Install,
+    /// The recipe was extended using `ControllerHandle::extend_recipe`.
+    Extend,
+    /// The recipe was rolled back to the given earlier version using
+    /// `ControllerHandle::rollback_to`.
+    Rollback(usize),
+}
+
+/// What the controller knows about the data in the graph and how it is read, as reported by
+/// `ControllerHandle::materialization_report`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MaterializationReport {
+    /// Cardinality statistics for each base table, by table name.
+    pub tables: HashMap<String, TableStatistics>,
+    /// Nodes whose state would likely be better off materialized differently.
+    pub advice: Vec<MaterializationAdvice>,
+}
+
+/// Cardinality statistics for a base table, summed across its shards.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TableStatistics {
+    /// Number of rows in the table.
+    pub rows: u64,
+    /// Key distribution of each column the table is indexed on, by column name.
+    pub columns: HashMap<String, ColumnStatistics>,
+}
+
+/// The distribution of values in an indexed column.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ColumnStatistics {
+    /// Number of distinct values in the column.
+    pub distinct_values: u64,
+    /// Number of rows that share the most common value.
+    pub max_rows_per_value: u64,
+}
+
+/// A recommendation to materialize a node's state differently.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MaterializationAdvice {
+    /// The node the advice is for.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// How the node's state is materialized now.
+    pub current: MaterializationStatus,
+    /// How the node's state should be materialized instead.
+    pub recommended: Recommendation,
+    /// Why the change is recommended.
+    pub reason: String,
+    /// Number of rows in the node's state, summed across its shards.
+    pub rows: u64,
+    /// Total memory size of the node's state, summed across its shards.
+    pub mem_size: u64,
+    /// Lookups into and evictions from the node's state, summed across its shards.
+    pub cache: debug::stats::CacheStats,
+}
+
+/// How a node's state should be materialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Recommendation {
+    /// Keep all of the node's state, so that lookups never have to wait for a replay.
+    Full,
+    /// Only keep the state that is read, and fill in the rest on demand.
+    Partial,
+}
+
+/// What a principal may do with a deployment that has access control enabled.
+///
+/// A principal can hold several roles. The administrative roles also let their holders read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Role {
+    /// List tables and queries, read from views, and inspect the graph.
+    Reader,
+    /// Write to base tables.
+    Writer,
+    /// Change the recipe, and manage where and how the data-flow runs.
+    SchemaAdmin,
+    /// Change security policies, manage universes, and manage principals and their roles.
+    SecurityAdmin,
+}
+
+impl Role {
+    /// Does holding this role allow what `required` allows?
+    pub fn grants(self, required: Role) -> bool {
+        self == required || (required == Role::Reader && self != Role::Writer)
+    }
+}
+
+/// Who made a request, as recorded in the audit log.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Principal {
+    /// A client that presented the deployment's access token, which holds every role.
+    Root,
+    /// A client that presented the access token of the named principal.
+    Named(String),
+    /// A client that presented no access token.
+    Anonymous,
+    /// A client that presented an access token that doesn't belong to anyone.
+    Unknown,
+}
+
+/// A request that access control acted on, as reported by `ControllerHandle::audit_log`.
+///
+/// Every denied request is recorded, as is every allowed request to an endpoint that needs an
+/// administrative role.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// When the request was made.
+    pub at: SystemTime,
+    /// Who made the request.
+    pub principal: Principal,
+    /// The controller endpoint that was requested.
+    pub endpoint: String,
+    /// Whether the request was allowed.
+    pub allowed: bool,
+}
+
 #[doc(hidden)]
 #[inline]
 pub fn shard_by(dt: &DataType, shards: usize) -> usize {
@@ -343,10 +507,119 @@ pub fn shard_by(dt: &DataType, shards: usize) -> usize {
             hasher.write(s.as_bytes());
             hasher.finish() as usize % shards
         }
+        DataType::Real(i, f) => {
+            use std::hash::Hasher;
+            let mut hasher = ahash::AHasher::new_with_keys(0x3306, 0x6033);
+            hasher.write_i64(i);
+            hasher.write_i32(f);
+            hasher.finish() as usize % shards
+        }
+        DataType::Timestamp(ts) => {
+            use std::hash::Hasher;
+            let mut hasher = ahash::AHasher::new_with_keys(0x3306, 0x6033);
+            hasher.write_i64(ts.timestamp());
+            hasher.write_u32(ts.timestamp_subsec_nanos());
+            hasher.finish() as usize % shards
+        }
         // a bit hacky: send all NULL values to the first shard
         DataType::None => 0,
-        ref x => {
-            unimplemented!("asked to shard on value {:?}", x);
+    }
+}
+
+/// Pick the shard for a compound key by hashing all of its columns together.
+///
+/// A key with a single column goes to the same shard as it would with `shard_by`.
+#[doc(hidden)]
+#[inline]
+pub fn shard_by_key(key: &[DataType], shards: usize) -> usize {
+    if let [dt] = key {
+        return shard_by(dt, shards);
+    }
+
+    use std::hash::Hasher;
+    let mut hasher = ahash::AHasher::new_with_keys(0x3306, 0x6033);
+    for dt in key {
+        hasher.write_usize(shard_by(dt, usize::MAX));
+    }
+    hasher.finish() as usize % shards
+}
+
+/// Pick the shard for a value of a column of type `ty` that is split into ranges at `bounds`.
+///
+/// Shard `i` holds the values that are at least `bounds[i - 1]` and below `bounds[i]`, so there is
+/// one more shard than there are bounds. The value is coerced to `ty` before it is compared to the
+/// bounds, since values of different `DataType` variants don't order by what they represent.
+/// `NULL`, and values that have no representation in `ty`, go to the first shard.
+#[doc(hidden)]
+#[inline]
+pub fn shard_by_range(dt: &DataType, ty: &SqlType, bounds: &[DataType]) -> usize {
+    match dt.coerce_to(ty) {
+        None | Some(DataType::None) => 0,
+        Some(dt) => bounds.iter().take_while(|&b| dt >= *b).count(),
+    }
+}
+
+/// How the keys of a sharded table or view are spread across its shards.
+#[doc(hidden)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyPartitioning {
+    /// By a hash of the whole key.
+    Hash,
+    /// By ranges of the key's single column, which has the given type, split at the given bounds.
+    Range(SqlType, Vec<DataType>),
+}
+
+impl Default for KeyPartitioning {
+    fn default() -> Self {
+        KeyPartitioning::Hash
+    }
+}
+
+impl KeyPartitioning {
+    /// Pick which of `shards` shards holds `key`.
+    pub fn shard(&self, key: &[DataType], shards: usize) -> usize {
+        match *self {
+            KeyPartitioning::Hash => shard_by_key(key, shards),
+            KeyPartitioning::Range(ref ty, ref bounds) => {
+                debug_assert_eq!(bounds.len() + 1, shards);
+                shard_by_range(&key[0], ty, bounds)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_shards_every_type() {
+        let ts = chrono::NaiveDate::from_ymd(2020, 1, 1).and_hms(12, 0, 0);
+        for dt in &[
+            DataType::from(1.5),
+            DataType::Timestamp(ts),
+            DataType::None,
+            "a".into(),
+            7.into(),
+        ] {
+            let shard = shard_by(dt, 16);
+            assert!(shard < 16);
+            assert!(shard_by_key(&[dt.clone(), dt.clone()], 16) < 16);
+        }
+    }
+
+    #[test]
+    fn it_routes_ranges_by_column_type() {
+        let ty = SqlType::Int(32);
+        let bounds = [100.into(), 200.into()];
+        assert_eq!(shard_by_range(&50.into(), &ty, &bounds), 0);
+        assert_eq!(shard_by_range(&100.into(), &ty, &bounds), 1);
+        assert_eq!(shard_by_range(&"150".into(), &ty, &bounds), 1);
+        assert_eq!(shard_by_range(&DataType::from(250.5), &ty, &bounds), 2);
+        assert_eq!(shard_by_range(&DataType::BigInt(200), &ty, &bounds), 2);
+
+        // NULL, and values that aren't numbers at all, go to the first shard
+        assert_eq!(shard_by_range(&DataType::None, &ty, &bounds), 0);
+        assert_eq!(shard_by_range(&"many".into(), &ty, &bounds), 0);
+    }
+}
@@ -1,7 +1,9 @@
+use crate::channel::auth;
+use crate::channel::tls::{self, MaybeTlsStream, TlsConfig};
 use crate::channel::CONNECTION_FROM_BASE;
 use crate::data::*;
 use crate::internal::*;
-use crate::LocalOrNot;
+use crate::{KeyPartitioning, LocalOrNot};
 use crate::{Tagged, Tagger};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use futures_util::{
@@ -26,8 +28,8 @@ use tower_service::Service;
 use vec_map::VecMap;
 
 type Transport = AsyncBincodeStream<
-    tokio::net::TcpStream,
-    Tagged<()>,
+    MaybeTlsStream,
+    Tagged<WriteAck>,
     Tagged<LocalOrNot<Input>>,
     AsyncDestination,
 >;
@@ -202,7 +204,7 @@ async fn update_user(users: &mut Table) -> Result<(), TableError> {
 }
 
 #[derive(Debug)]
-struct Endpoint(SocketAddr);
+struct Endpoint(SocketAddr, Option<TlsConfig>, Option<String>);
 
 type InnerService = multiplex::Client<
     multiplex::MultiplexTransport<Transport, Tagger>,
@@ -225,11 +227,17 @@ impl Service<()> for Endpoint {
 
     fn call(&mut self, _: ()) -> Self::Future {
         let f = tokio::net::TcpStream::connect(self.0);
+        let tls = self.1.clone();
+        let token = self.2.clone();
         async move {
             let mut s = f.await?;
             s.set_nodelay(true)?;
+            // the connection type is sent before the TLS handshake, since the replica has to
+            // know how to deserialize the stream before it gets to the handshake.
             s.write_all(&[CONNECTION_FROM_BASE]).await.unwrap();
             s.flush().await.unwrap();
+            let mut s = tls::connect(tls.as_ref(), s).await?;
+            auth::present(&mut s, token.as_ref().map(String::as_str)).await?;
             let s = AsyncBincodeStream::from(s).for_async();
             let t = multiplex::MultiplexTransport::new(s, Tagger::default());
             Ok(multiplex::Client::with_error_handler(t, |e| {
@@ -241,6 +249,8 @@ impl Service<()> for Endpoint {
 
 fn make_table_stream(
     addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    token: Option<String>,
 ) -> impl futures_util::stream::TryStream<
     Ok = tower_discover::Change<usize, InnerService>,
     Error = tokio::io::Error,
@@ -248,15 +258,22 @@ fn make_table_stream(
     // TODO: use whatever comes out of https://github.com/tower-rs/tower/issues/456 instead of
     // creating _all_ the connections every time.
     (0..crate::TABLE_POOL_SIZE)
-        .map(|i| async move {
-            let svc = Endpoint(addr).call(()).await?;
-            Ok(tower_discover::Change::Insert(i, svc))
+        .map(|i| {
+            let mut endpoint = Endpoint(addr, tls.clone(), token.clone());
+            async move {
+                let svc = endpoint.call(()).await?;
+                Ok(tower_discover::Change::Insert(i, svc))
+            }
         })
         .collect::<futures_util::stream::FuturesUnordered<_>>()
 }
 
-fn make_table_discover(addr: SocketAddr) -> Discover {
-    ServiceStream::new(make_table_stream(addr))
+fn make_table_discover(
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    token: Option<String>,
+) -> Discover {
+    ServiceStream::new(make_table_stream(addr, tls, token))
 }
 
 // Unpin + Send bounds are needed due to https://github.com/rust-lang/rust/issues/55997
@@ -289,6 +306,10 @@ pub enum TableError {
     )]
     WrongKeyColumnCount(usize, usize),
 
+    /// The table's write policies do not allow the operation, or the handle may not write to it.
+    #[fail(display = "write rejected by policy: {}", _0)]
+    PolicyViolation(String),
+
     /// The underlying connection to Noria produced an error.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] failure::Error),
@@ -300,11 +321,41 @@ impl From<Box<dyn std::error::Error + Send + Sync>> for TableError {
     }
 }
 
+/// How a domain answers a write: with `Ok` once it has applied it, or with the reason it refused
+/// to.
+#[doc(hidden)]
+pub type WriteAck = Result<(), String>;
+
+fn check_ack(ack: Tagged<WriteAck>) -> Result<Tagged<()>, TableError> {
+    match ack.v {
+        Ok(()) => Ok(Tagged {
+            tag: ack.tag,
+            v: (),
+        }),
+        Err(reason) => Err(TableError::PolicyViolation(reason)),
+    }
+}
+
+/// The controller's permission for a table handle to write on behalf of a user universe.
+///
+/// Bases with write policies only accept writes that carry a grant they were told about, so a
+/// client can't claim a universe that the controller didn't hand it.
+#[doc(hidden)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteGrant {
+    /// The universe whose write policies the writes must satisfy.
+    pub universe: DataType,
+    /// The secret the controller gave the universe's bases along with its policies.
+    pub token: String,
+}
+
 #[doc(hidden)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Input {
     pub dst: LocalNodeIndex,
     pub data: Vec<TableOperation>,
+    /// The universe whose write policies the operations must satisfy, if any.
+    pub grant: Option<WriteGrant>,
 }
 
 impl fmt::Debug for Input {
@@ -312,6 +363,7 @@ impl fmt::Debug for Input {
         fmt.debug_struct("Input")
             .field("dst", &self.dst)
             .field("data", &self.data)
+            .field("universe", &self.grant.as_ref().map(|g| &g.universe))
             .finish()
     }
 }
@@ -324,17 +376,23 @@ pub struct TableBuilder {
     pub addr: LocalNodeIndex,
     pub key_is_primary: bool,
     pub key: Vec<usize>,
+    #[serde(default)]
+    pub partitioning: KeyPartitioning,
     pub dropped: VecMap<DataType>,
 
     pub table_name: String,
     pub columns: Vec<String>,
     pub schema: Option<CreateTableStatement>,
+    #[serde(default)]
+    pub grant: Option<WriteGrant>,
 }
 
 impl TableBuilder {
     pub(crate) fn build(
         self,
         rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), TableRpc>>>,
+        tls: Option<&TlsConfig>,
+        token: Option<&str>,
     ) -> Result<Table, io::Error> {
         let mut addrs = Vec::with_capacity(self.txs.len());
         let mut conns = Vec::with_capacity(self.txs.len());
@@ -352,7 +410,11 @@ impl TableBuilder {
                     // TODO: maybe always use the same local port?
                     let (c, w) = Buffer::pair(
                         ConcurrencyLimit::new(
-                            Balance::from_entropy(make_table_discover(addr)),
+                            Balance::from_entropy(make_table_discover(
+                                addr,
+                                tls.cloned(),
+                                token.map(String::from),
+                            )),
                             crate::PENDING_LIMIT,
                         ),
                         crate::BUFFER_TO_POOL,
@@ -376,10 +438,12 @@ impl TableBuilder {
             node: self.addr,
             key: self.key,
             key_is_primary: self.key_is_primary,
+            partitioning: self.partitioning,
             columns: self.columns,
             dropped: self.dropped,
             table_name: self.table_name,
             schema: self.schema,
+            grant: self.grant,
             dst_is_local: false,
 
             shard_addrs: addrs,
@@ -402,10 +466,12 @@ pub struct Table {
     node: LocalNodeIndex,
     key_is_primary: bool,
     key: Vec<usize>,
+    partitioning: KeyPartitioning,
     columns: Vec<String>,
     dropped: VecMap<DataType>,
     table_name: String,
     schema: Option<CreateTableStatement>,
+    grant: Option<WriteGrant>,
     dst_is_local: bool,
 
     shards: Vec<TableRpc>,
@@ -425,6 +491,7 @@ impl fmt::Debug for Table {
             .field("dropped", &self.dropped)
             .field("table_name", &self.table_name)
             .field("schema", &self.schema)
+            .field("universe", &self.universe())
             .field("dst_is_local", &self.dst_is_local)
             .field("shard_addrs", &self.shard_addrs)
             .finish()
@@ -507,31 +574,23 @@ impl Table {
             let _guard = span.as_ref().map(tracing::Span::enter);
             tracing::trace!("submit request");
             future::Either::Right(future::Either::Left(
-                self.shards[0].call(request).map_err(TableError::from),
+                self.shards[0]
+                    .call(request)
+                    .map_err(TableError::from)
+                    .and_then(|ack| async move { check_ack(ack) }),
             ))
         } else {
             if self.key.is_empty() {
                 unreachable!("sharded base without a key?");
             }
-            if self.key.len() != 1 {
-                // base sharded by complex key
-                unimplemented!();
-            }
-            let key_col = self.key[0];
 
             let _guard = span.as_ref().map(tracing::Span::enter);
             tracing::trace!("shard request");
             let mut shard_writes = vec![Vec::new(); self.shards.len()];
             for r in i.data.drain(..) {
-                let shard = {
-                    let key = match r {
-                        TableOperation::Insert(ref r) => &r[key_col],
-                        TableOperation::Delete { ref key } => &key[0],
-                        TableOperation::Update { ref key, .. } => &key[0],
-                        TableOperation::InsertOrUpdate { ref row, .. } => &row[key_col],
-                    };
-                    crate::shard_by(key, self.shards.len())
-                };
+                let shard = self
+                    .partitioning
+                    .shard(&r.shard_key(&self.key), self.shards.len());
                 shard_writes[shard].push(r);
             }
 
@@ -543,12 +602,14 @@ impl Table {
                             LocalOrNot::for_local_transfer(Input {
                                 dst: i.dst,
                                 data: rs,
+                                grant: i.grant.clone(),
                             })
                         }
                     } else {
                         LocalOrNot::new(Input {
                             dst: i.dst,
                             data: rs,
+                            grant: i.grant.clone(),
                         })
                     };
                     let request = Tagged::from(p);
@@ -573,8 +634,8 @@ impl Table {
 
             future::Either::Right(future::Either::Right(
                 wait_for
-                    .try_for_each(|_| async { Ok(()) })
                     .map_err(TableError::from)
+                    .try_for_each(|ack| async move { check_ack(ack).map(|_| ()) })
                     .map_ok(Tagged::from),
             ))
         }
@@ -583,7 +644,7 @@ impl Table {
 
 impl Service<Vec<TableOperation>> for Table {
     type Error = TableError;
-    type Response = <TableRpc as Service<Tagged<LocalOrNot<Input>>>>::Response;
+    type Response = Tagged<()>;
 
     #[cfg(not(doc))]
     type Future = impl Future<Output = Result<Tagged<()>, TableError>> + Send;
@@ -614,6 +675,11 @@ impl Table {
         self.dst_is_local = true;
     }
 
+    /// Get the universe whose write policies this handle's writes must satisfy, if any.
+    pub fn universe(&self) -> Option<&DataType> {
+        self.grant.as_ref().map(|g| &g.universe)
+    }
+
     /// Get the list of columns in this base table.
     ///
     /// Note that this will *not* be updated if the underlying recipe changes and adds or removes
@@ -724,6 +790,7 @@ impl Table {
         Input {
             dst: self.node,
             data: ops,
+            grant: self.grant.clone(),
         }
     }
 
@@ -1,5 +1,7 @@
+use crate::channel::auth;
+use crate::channel::tls::{self, MaybeTlsStream, TlsConfig};
 use crate::data::*;
-use crate::{Tagged, Tagger};
+use crate::{KeyPartitioning, Tagged, Tagger};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use futures_util::{
     future, future::TryFutureExt, ready, stream::futures_unordered::FuturesUnordered,
@@ -21,15 +23,11 @@ use tower_discover::ServiceStream;
 use tower_limit::concurrency::ConcurrencyLimit;
 use tower_service::Service;
 
-type Transport = AsyncBincodeStream<
-    tokio::net::TcpStream,
-    Tagged<ReadReply>,
-    Tagged<ReadQuery>,
-    AsyncDestination,
->;
+type Transport =
+    AsyncBincodeStream<MaybeTlsStream, Tagged<ReadReply>, Tagged<ReadQuery>, AsyncDestination>;
 
 #[derive(Debug)]
-struct Endpoint(SocketAddr);
+struct Endpoint(SocketAddr, Option<TlsConfig>, Option<String>);
 
 type InnerService = multiplex::Client<
     multiplex::MultiplexTransport<Transport, Tagger>,
@@ -52,9 +50,13 @@ impl Service<()> for Endpoint {
 
     fn call(&mut self, _: ()) -> Self::Future {
         let f = tokio::net::TcpStream::connect(self.0);
+        let tls = self.1.clone();
+        let token = self.2.clone();
         async move {
             let s = f.await?;
             s.set_nodelay(true)?;
+            let mut s = tls::connect(tls.as_ref(), s).await?;
+            auth::present(&mut s, token.as_ref().map(String::as_str)).await?;
             let s = AsyncBincodeStream::from(s).for_async();
             let t = multiplex::MultiplexTransport::new(s, Tagger::default());
             Ok(multiplex::Client::with_error_handler(t, |e| {
@@ -66,6 +68,8 @@ impl Service<()> for Endpoint {
 
 fn make_views_stream(
     addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    token: Option<String>,
 ) -> impl futures_util::stream::TryStream<
     Ok = tower_discover::Change<usize, InnerService>,
     Error = tokio::io::Error,
@@ -73,15 +77,22 @@ fn make_views_stream(
     // TODO: use whatever comes out of https://github.com/tower-rs/tower/issues/456 instead of
     // creating _all_ the connections every time.
     (0..crate::VIEW_POOL_SIZE)
-        .map(|i| async move {
-            let svc = Endpoint(addr).call(()).await?;
-            Ok(tower_discover::Change::Insert(i, svc))
+        .map(|i| {
+            let mut endpoint = Endpoint(addr, tls.clone(), token.clone());
+            async move {
+                let svc = endpoint.call(()).await?;
+                Ok(tower_discover::Change::Insert(i, svc))
+            }
         })
         .collect::<futures_util::stream::FuturesUnordered<_>>()
 }
 
-fn make_views_discover(addr: SocketAddr) -> Discover {
-    ServiceStream::new(make_views_stream(addr))
+fn make_views_discover(
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    token: Option<String>,
+) -> Discover {
+    ServiceStream::new(make_views_stream(addr, tls, token))
 }
 
 // Unpin + Send bounds are needed due to https://github.com/rust-lang/rust/issues/55997
@@ -147,6 +158,8 @@ pub struct ViewBuilder {
     pub columns: Vec<String>,
     pub schema: Option<Vec<ColumnSpecification>>,
     pub shards: Vec<SocketAddr>,
+    #[serde(default)]
+    pub partitioning: KeyPartitioning,
 }
 
 impl ViewBuilder {
@@ -155,11 +168,14 @@ impl ViewBuilder {
     pub fn build(
         &self,
         rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+        tls: Option<&TlsConfig>,
+        token: Option<&str>,
     ) -> Result<View, io::Error> {
         let node = self.node;
         let columns = self.columns.clone();
         let shards = self.shards.clone();
         let schema = self.schema.clone();
+        let partitioning = self.partitioning.clone();
 
         let mut addrs = Vec::with_capacity(shards.len());
         let mut conns = Vec::with_capacity(shards.len());
@@ -178,7 +194,11 @@ impl ViewBuilder {
                     // TODO: maybe always use the same local port?
                     let (c, w) = Buffer::pair(
                         ConcurrencyLimit::new(
-                            Balance::from_entropy(make_views_discover(addr)),
+                            Balance::from_entropy(make_views_discover(
+                                addr,
+                                tls.cloned(),
+                                token.map(String::from),
+                            )),
                             crate::PENDING_LIMIT,
                         ),
                         crate::BUFFER_TO_POOL,
@@ -201,6 +221,7 @@ impl ViewBuilder {
             node,
             schema,
             columns,
+            partitioning,
             shard_addrs: addrs,
             shards: conns,
             tracer,
@@ -217,6 +238,7 @@ pub struct View {
     node: NodeIndex,
     columns: Vec<String>,
     schema: Option<Vec<ColumnSpecification>>,
+    partitioning: KeyPartitioning,
 
     shards: Vec<ViewRpc>,
     shard_addrs: Vec<SocketAddr>,
@@ -295,10 +317,9 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
         if let Some(ref span) = span {
             span.in_scope(|| tracing::trace!("shard request"));
         }
-        assert!(keys.iter().all(|k| k.len() == 1));
         let mut shard_queries = vec![Vec::new(); self.shards.len()];
         for key in keys {
-            let shard = crate::shard_by(&key[0], self.shards.len());
+            let shard = self.partitioning.shard(&key, self.shards.len());
             shard_queries[shard].push(key);
         }
 
@@ -117,6 +117,10 @@ impl Tag {
     pub fn new(upquery: u32) -> Tag {
         Tag(upquery)
     }
+
+    pub fn id(self) -> u32 {
+        self.0
+    }
 }
 
 impl slog::Value for Tag {
@@ -184,4 +188,26 @@ impl<'a> KeyType<'a> {
             _ => unimplemented!(),
         }
     }
+    /// Copy the values of this key into a `Vec`, in column order.
+    pub fn to_vec(&self) -> Vec<DataType> {
+        match *self {
+            KeyType::Single(k) => vec![k.clone()],
+            KeyType::Double((ref a, ref b)) => vec![a.clone(), b.clone()],
+            KeyType::Tri((ref a, ref b, ref c)) => vec![a.clone(), b.clone(), c.clone()],
+            KeyType::Quad((ref a, ref b, ref c, ref d)) => {
+                vec![a.clone(), b.clone(), c.clone(), d.clone()]
+            }
+            KeyType::Quin((ref a, ref b, ref c, ref d, ref e)) => {
+                vec![a.clone(), b.clone(), c.clone(), d.clone(), e.clone()]
+            }
+            KeyType::Sex((ref a, ref b, ref c, ref d, ref e, ref f)) => vec![
+                a.clone(),
+                b.clone(),
+                c.clone(),
+                d.clone(),
+                e.clone(),
+                f.clone(),
+            ],
+        }
+    }
 }
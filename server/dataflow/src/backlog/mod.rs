@@ -1,8 +1,12 @@
+use crate::eviction::{key_hash, AccessTracker, EvictionPolicy};
 use crate::prelude::*;
 use ahash::RandomState;
 use common::SizeOf;
+use indexmap::IndexSet;
+use noria::debug::stats::CacheStats;
 use rand::prelude::*;
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 /// Allocate a new end-user facing result table.
@@ -63,18 +67,32 @@ fn new_inner(
         _ => make!(Many),
     };
 
+    // only partial readers are evicted from, so there's no point in tracking accesses otherwise
+    let access = trigger.as_ref().map(|_| Arc::new(AccessTracker::default()));
+    // full readers never miss, but whether they are read from at all is still worth knowing
+    let lookups = if trigger.is_none() {
+        Some(Arc::new(AtomicU64::new(0)))
+    } else {
+        None
+    };
+
     let w = WriteHandle {
         partial: trigger.is_some(),
+        filled: trigger.as_ref().map(|_| IndexSet::default()),
         handle: w,
         key: Vec::from(key),
         cols,
         contiguous,
         mem_size: 0,
+        access: access.clone(),
+        lookups: lookups.clone(),
     };
     let r = SingleReadHandle {
         handle: r,
         trigger,
         key: Vec::from(key),
+        access,
+        lookups,
     };
 
     (r, w)
@@ -107,10 +125,15 @@ fn key_to_double(k: Key) -> Cow<(DataType, DataType)> {
 pub(crate) struct WriteHandle {
     handle: multiw::Handle,
     partial: bool,
+    // The filled keys of a partial reader, so that eviction can pick keys at random without
+    // walking the map.
+    filled: Option<IndexSet<Vec<DataType>, RandomState>>,
     cols: usize,
     key: Vec<usize>,
     contiguous: bool,
     mem_size: usize,
+    access: Option<Arc<AccessTracker>>,
+    lookups: Option<Arc<AtomicU64>>,
 }
 
 type Key<'a> = Cow<'a, [DataType]>;
@@ -130,6 +153,12 @@ impl<'a> MutWriteHandleEntry<'a> {
             .handle
             .meta_get_and(Cow::Borrowed(&*self.key), |rs| rs.is_empty())
         {
+            if let Some(ref mut filled) = self.handle.filled {
+                filled.insert(self.key.to_vec());
+                if let Some(ref access) = self.handle.access {
+                    access.reserve(filled.len());
+                }
+            }
             self.handle.handle.clear(self.key)
         } else {
             unreachable!("attempted to fill already-filled key");
@@ -146,6 +175,12 @@ impl<'a> MutWriteHandleEntry<'a> {
             .map(|r| r.0.unwrap_or(0))
             .unwrap_or(0);
         self.handle.mem_size = self.handle.mem_size.checked_sub(size as usize).unwrap();
+        if let Some(ref access) = self.handle.access {
+            access.evicted(1);
+        }
+        if let Some(ref mut filled) = self.handle.filled {
+            filled.swap_remove(&*self.key);
+        }
         self.handle.handle.empty(self.key)
     }
 }
@@ -254,20 +289,61 @@ impl WriteHandle {
         self.partial
     }
 
-    /// Evict `count` randomly selected keys from state and return them along with the number of
-    /// bytes that will be freed once the underlying `evmap` applies the operation.
-    pub(crate) fn evict_random_keys(&mut self, rng: &mut ThreadRng, mut n: usize) -> u64 {
+    /// Clone every row that has been made visible to readers.
+    pub(crate) fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        self.handle.cloned_records()
+    }
+
+    /// Evict `n` keys chosen by `policy` from state and return the number of bytes that will be
+    /// freed once the underlying `evmap` applies the operation.
+    pub(crate) fn evict_cold_keys(
+        &mut self,
+        rng: &mut ThreadRng,
+        n: usize,
+        policy: &dyn EvictionPolicy,
+    ) -> u64 {
         let mut bytes_to_be_freed = 0;
         if self.mem_size > 0 {
             if self.handle.is_empty() {
                 unreachable!("mem size is {}, but map is empty", self.mem_size);
             }
 
-            self.handle.empty_random_for_each(rng, n, |vs| {
-                let size: u64 = vs.iter().map(|r| r.deep_size_of() as u64).sum();
-                bytes_to_be_freed += size;
-                n -= 1;
-            });
+            let mut evicted = 0;
+            match (&mut self.filled, &self.access) {
+                (Some(filled), Some(access)) => {
+                    access.tick();
+                    for _ in 0..n {
+                        if filled.is_empty() {
+                            break;
+                        }
+                        let victim = access.choose_victim(policy, rng, filled.len(), |i| {
+                            key_hash(&filled.get_index(i).unwrap()[..])
+                        });
+                        let key = filled.swap_remove_index(victim).unwrap();
+                        let size = self
+                            .handle
+                            .meta_get_and(Cow::Borrowed(&key[..]), |vs| {
+                                vs.iter().map(|r| r.deep_size_of() as u64).sum::<u64>()
+                            })
+                            .and_then(|(size, _)| size)
+                            .unwrap_or(0);
+                        self.handle.empty(Cow::Owned(key));
+                        bytes_to_be_freed += size;
+                        evicted += 1;
+                    }
+                }
+                _ => {
+                    self.handle.empty_random_for_each(rng, n, |vs| {
+                        let size: u64 = vs.iter().map(|r| r.deep_size_of() as u64).sum();
+                        bytes_to_be_freed += size;
+                        evicted += 1;
+                    });
+                }
+            }
+
+            if let Some(ref access) = self.access {
+                access.evicted(evicted);
+            }
         }
 
         self.mem_size = self
@@ -276,6 +352,20 @@ impl WriteHandle {
             .unwrap();
         bytes_to_be_freed
     }
+
+    /// Hits, misses and evictions for this reader.
+    ///
+    /// Every lookup into a fully materialized reader is a hit.
+    pub(crate) fn cache_stats(&self) -> CacheStats {
+        match (&self.access, &self.lookups) {
+            (Some(access), _) => access.stats(),
+            (None, Some(lookups)) => CacheStats {
+                hits: lookups.load(Ordering::Relaxed),
+                ..Default::default()
+            },
+            (None, None) => CacheStats::default(),
+        }
+    }
 }
 
 impl SizeOf for WriteHandle {
@@ -300,6 +390,8 @@ pub struct SingleReadHandle {
     handle: multir::Handle,
     trigger: Option<Arc<dyn Fn(&mut dyn Iterator<Item = &[DataType]>) -> bool + Send + Sync>>,
     key: Vec<usize>,
+    access: Option<Arc<AccessTracker>>,
+    lookups: Option<Arc<AtomicU64>>,
 }
 
 impl std::fmt::Debug for SingleReadHandle {
@@ -348,6 +440,15 @@ impl SingleReadHandle {
                 if records.is_none() && self.trigger.is_none() {
                     records = Some(then(&evmap::Values::default()));
                 }
+                if let Some(ref access) = self.access {
+                    if records.is_some() {
+                        access.hit(key_hash(key));
+                    } else {
+                        access.miss();
+                    }
+                } else if let Some(ref lookups) = self.lookups {
+                    lookups.fetch_add(1, Ordering::Relaxed);
+                }
                 (records, meta)
             })
     }
@@ -395,6 +496,9 @@ mod tests {
             .unwrap()
             .0
             .unwrap());
+
+        // lookups into full state always hit, except before the first swap
+        assert_eq!(w.cache_stats().hits, 4);
     }
 
     #[test]
@@ -564,4 +668,50 @@ mod tests {
             .0
             .unwrap());
     }
+
+    #[test]
+    fn it_evicts_cold_keys() {
+        use crate::eviction::SampledLru;
+
+        let (r, mut w) = new_partial(1, &[0], |_: &mut dyn Iterator<Item = &[DataType]>| true);
+        w.swap();
+        for i in 0..100 {
+            w.mut_with_key(vec![i.into()]).mark_filled();
+            w.add(vec![Record::Positive(vec![i.into()])]);
+        }
+        w.swap();
+
+        let hot: Vec<DataType> = (0..10).map(DataType::from).collect();
+        for key in &hot {
+            assert_eq!(
+                r.try_find_and(&[key.clone()], |rs| rs.len()).unwrap().0,
+                Some(1)
+            );
+        }
+        assert_eq!(
+            r.try_find_and(&[100.into()], |rs| rs.len()).unwrap().0,
+            None
+        );
+
+        let bytes = w.evict_cold_keys(&mut rand::thread_rng(), 10, &SampledLru::new(10));
+        assert!(bytes > 0);
+        assert_eq!(
+            w.cache_stats(),
+            CacheStats {
+                hits: 10,
+                misses: 1,
+                evictions: 10,
+            }
+        );
+
+        w.swap();
+        assert_eq!(r.len(), 90);
+        for key in &hot {
+            assert!(r
+                .try_find_and(&[key.clone()], |rs| rs.len())
+                .unwrap()
+                .0
+                .is_some());
+        }
+    }
 }
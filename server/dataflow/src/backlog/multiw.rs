@@ -18,6 +18,14 @@ impl Handle {
         }
     }
 
+    pub fn len(&self) -> usize {
+        match *self {
+            Handle::Single(ref h) => h.len(),
+            Handle::Double(ref h) => h.len(),
+            Handle::Many(ref h) => h.len(),
+        }
+    }
+
     pub fn clear(&mut self, k: Key) {
         match *self {
             Handle::Single(ref mut h) => {
@@ -61,6 +69,24 @@ impl Handle {
         }
     }
 
+    /// Clone every row in the map, as of the last refresh.
+    pub fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        macro_rules! cloned_records {
+            ($h:ident) => {{
+                match $h.read() {
+                    Some(map) => map.iter().flat_map(|(_, vs)| vs.iter().cloned()).collect(),
+                    None => Vec::new(),
+                }
+            }};
+        }
+
+        match *self {
+            Handle::Single(ref h) => cloned_records!(h),
+            Handle::Double(ref h) => cloned_records!(h),
+            Handle::Many(ref h) => cloned_records!(h),
+        }
+    }
+
     pub fn refresh(&mut self) {
         match *self {
             Handle::Single(ref mut h) => {
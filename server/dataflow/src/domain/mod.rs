@@ -5,21 +5,27 @@ use std::cmp;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time;
 
 use crate::group_commit::GroupCommitQueueSet;
+use crate::node::special::BaseForward;
 use crate::payload::{ControlReplyPacket, ReplayPieceContext, SourceSelection};
 use crate::prelude::*;
 use ahash::RandomState;
 use futures_util::{future::FutureExt, stream::StreamExt};
 use noria::channel::{self, TcpSender};
 pub use noria::internal::DomainIndex as Index;
+use noria::TableOperation;
 use slog::Logger;
 use stream_cancel::Valve;
 
+use crate::eviction::{EvictionPolicy, EvictionPriority, StateSizes};
+use crate::metrics::{DomainMetrics, NodeMetrics};
+use crate::tracing::{self, TraceContext, Tracer};
 use crate::Readers;
+use crate::StorageBackends;
 use timekeeper::{RealTime, SimpleTracker, ThreadTime, Timer, TimerSet};
 use tokio;
 
@@ -41,10 +47,17 @@ pub enum ProcessResult {
 pub struct Config {
     pub concurrent_replays: usize,
     pub replay_batch_timeout: time::Duration,
+    /// How often base tables with a retention period are checked for expired rows.
+    pub retention_interval: time::Duration,
+    /// Partial replays that take longer than this to fill a hole are logged as slow.
+    pub slow_replay_threshold: time::Duration,
 }
 
 const BATCH_SIZE: usize = 256;
 
+/// How many of the partial replays that most recently filled holes a domain keeps around.
+const REPLAY_LOG_LEN: usize = 256;
+
 #[derive(Debug)]
 enum DomainMode {
     Forwarding,
@@ -133,12 +146,61 @@ pub struct DomainBuilder {
     pub persistence_parameters: PersistenceParameters,
     /// Configuration parameters for the domain.
     pub config: Config,
+    /// State to restore on boot, if this domain was moved here from another worker.
+    pub snapshot: Option<Box<DomainSnapshot>>,
+}
+
+/// The state a domain carries with it when it is moved to another worker.
+///
+/// Nodes travel in the `DomainBuilder` itself. Materializations are rebuilt by replaying the
+/// control packets that originally set them up, after which the rows of fully materialized state
+/// are put back in. Partial state is not carried over; it is evicted before the move, and refilled
+/// on demand afterwards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DomainSnapshot {
+    setup: Vec<Packet>,
+    rows: Vec<(LocalNodeIndex, Vec<Vec<DataType>>)>,
+    ingress_inject: Map<(usize, Vec<DataType>)>,
+}
+
+/// An executor for packets that are not expected to produce any output, such as the setup packets
+/// replayed when restoring a relocated domain.
+struct NoOutput;
+
+impl Executor for NoOutput {
+    fn ack(&mut self, _: SourceChannelIdentifier) {
+        unreachable!("restoring domain tried to ack a write");
+    }
+    fn reject(&mut self, _: SourceChannelIdentifier, _: String) {
+        unreachable!("restoring domain tried to reject a write");
+    }
+    fn create_universe(&mut self, _: HashMap<String, DataType>) {
+        unreachable!("restoring domain tried to create a universe");
+    }
+    fn send(&mut self, _: ReplicaAddr, _: Box<Packet>) {
+        unreachable!("restoring domain tried to send a packet");
+    }
+    fn forward(&mut self, _: Box<Packet>) {
+        unreachable!("restoring domain tried to forward a packet");
+    }
+}
+
+/// Where a domain stands in being moved to another worker.
+enum Relocation {
+    /// The domain is running here.
+    None,
+    /// The domain's state has been shipped off, and incoming packets are held until its
+    /// replacement is up.
+    Buffering(VecDeque<Box<Packet>>),
+    /// The domain's replacement is up, and everything that still arrives here is passed on to it.
+    Forwarding,
 }
 
 unsafe impl Send for DomainBuilder {}
 
 impl DomainBuilder {
     /// Starts up the domain represented by this `DomainBuilder`.
+    #[allow(clippy::too_many_arguments)]
     pub fn build(
         self,
         log: Logger,
@@ -146,7 +208,11 @@ impl DomainBuilder {
         channel_coordinator: Arc<ChannelCoordinator>,
         control_addr: SocketAddr,
         shutdown_valve: &Valve,
-        state_size: Arc<AtomicUsize>,
+        state_size: Arc<StateSizes>,
+        metrics: Arc<DomainMetrics>,
+        tracer: Arc<Tracer>,
+        storage_backends: StorageBackends,
+        eviction_policy: Arc<dyn EvictionPolicy>,
     ) -> Domain {
         // initially, all nodes are not ready
         let not_ready = self
@@ -156,15 +222,21 @@ impl DomainBuilder {
             .collect();
 
         let log = log.new(o!("domain" => self.index.index(), "shard" => self.shard.unwrap_or(0)));
-        let control_reply_tx = TcpSender::connect(&control_addr).unwrap();
-        let group_commit_queues = GroupCommitQueueSet::new(&self.persistence_parameters);
+        let mut control_reply_tx =
+            TcpSender::connect_tls(&control_addr, channel_coordinator.tls()).unwrap();
+        noria::channel::auth::prove(control_reply_tx.get_mut(), channel_coordinator.secret())
+            .unwrap();
+        let group_commit_queues =
+            GroupCommitQueueSet::new(&self.persistence_parameters, metrics.clone());
 
         Domain {
             index: self.index,
             shard: self.shard,
-            _nshards: self.nshards,
+            nshards: self.nshards,
 
             persistence_parameters: self.persistence_parameters,
+            storage_backends,
+            eviction_policy,
             nodes: self.nodes,
             state: StateMap::default(),
             log,
@@ -176,6 +248,10 @@ impl DomainBuilder {
             replay_paths_by_dst: Default::default(),
 
             ingress_inject: Default::default(),
+            setup_log: Vec::new(),
+            snapshot: self.snapshot,
+            restoring: false,
+            relocation: Relocation::None,
 
             shutdown_valve: shutdown_valve.clone(),
             readers,
@@ -185,6 +261,11 @@ impl DomainBuilder {
             buffered_replay_requests: Default::default(),
             replay_batch_timeout: self.config.replay_batch_timeout,
             timed_purges: Default::default(),
+            retention_interval: self.config.retention_interval,
+            next_expiry: None,
+            retyping: Default::default(),
+            replay_log: VecDeque::with_capacity(REPLAY_LOG_LEN),
+            slow_replay_threshold: self.config.slow_replay_threshold,
 
             concurrent_replays: 0,
             max_concurrent_replays: self.config.concurrent_replays,
@@ -194,11 +275,16 @@ impl DomainBuilder {
             group_commit_queues,
 
             state_size,
+            metrics,
+            holes_since: Default::default(),
+            tracer,
+            traced: None,
             total_time: Timer::new(),
             total_ptime: Timer::new(),
             wait_time: Timer::new(),
             process_times: TimerSet::new(),
             process_ptimes: TimerSet::new(),
+            processed_records: Map::new(),
 
             total_replay_time: Timer::new(),
             total_forward_time: Timer::new(),
@@ -217,7 +303,7 @@ struct TimedPurge {
 pub struct Domain {
     index: Index,
     shard: Option<usize>,
-    _nshards: usize,
+    nshards: usize,
 
     nodes: DomainNodes,
     state: StateMap,
@@ -227,7 +313,17 @@ pub struct Domain {
 
     ingress_inject: Map<(usize, Vec<DataType>)>,
 
+    /// The packets that set up this domain's materializations and replay paths, in the order they
+    /// were received, so that they can be replayed if the domain is moved to another worker.
+    setup_log: Vec<Packet>,
+    snapshot: Option<Box<DomainSnapshot>>,
+    /// Set while replaying `setup_log` on a new worker, where the controller expects no replies.
+    restoring: bool,
+    relocation: Relocation,
+
     persistence_parameters: PersistenceParameters,
+    storage_backends: StorageBackends,
+    eviction_policy: Arc<dyn EvictionPolicy>,
 
     mode: DomainMode,
     waiting: Map<Waiting>,
@@ -250,13 +346,32 @@ pub struct Domain {
     replay_batch_timeout: time::Duration,
     delayed_for_self: VecDeque<Box<Packet>>,
 
+    retention_interval: time::Duration,
+    /// When to next look for expired rows in bases with a retention period, if there are any.
+    next_expiry: Option<time::Instant>,
+    /// The keys of the rows of each base that still have to be converted to the base's current
+    /// column types, along with the columns of the index that the keys are for.
+    retyping: Map<(Vec<usize>, Vec<Vec<DataType>>)>,
+
+    /// The partial replays that most recently filled holes in this domain, oldest first.
+    replay_log: VecDeque<noria::debug::replays::ReplayLogEntry>,
+    slow_replay_threshold: time::Duration,
+
     group_commit_queues: GroupCommitQueueSet,
 
-    state_size: Arc<AtomicUsize>,
+    state_size: Arc<StateSizes>,
+    metrics: Arc<DomainMetrics>,
+    /// When a replay was first requested for each key that is missing from a node's state.
+    holes_since: HashMap<(LocalNodeIndex, Vec<DataType>), time::Instant>,
+    tracer: Arc<Tracer>,
+    /// The trace and span of the sampled write this domain is currently handling, if any.
+    traced: Option<([u8; 16], u64)>,
     total_time: Timer<SimpleTracker, RealTime>,
     total_ptime: Timer<SimpleTracker, ThreadTime>,
     wait_time: Timer<SimpleTracker, RealTime>,
     process_times: TimerSet<LocalNodeIndex, SimpleTracker, RealTime>,
+    /// How many records each node has processed outside of replays.
+    processed_records: Map<u64>,
     process_ptimes: TimerSet<LocalNodeIndex, SimpleTracker, ThreadTime>,
 
     /// time spent processing replays
@@ -281,6 +396,14 @@ impl Domain {
             }
         }
 
+        let now = time::Instant::now();
+        for key in &miss_keys {
+            self.holes_since
+                .entry((miss_in, key.clone()))
+                .or_insert(now);
+        }
+        self.metrics.requested_partial_replays(miss_keys.len());
+
         for &tag in &tags {
             // send a message to the source domain(s) responsible
             // for the chosen tag so they'll start replay.
@@ -579,10 +702,12 @@ impl Domain {
             return;
         }
 
-        let (mut m, evictions) = {
+        let (m, evictions) = {
             let mut n = self.nodes[me].borrow_mut();
             self.process_times.start(me);
             self.process_ptimes.start(me);
+            let traced_since = self.traced.map(|_| time::SystemTime::now());
+            *self.processed_records.entry(me).or_default() += m.len() as u64;
             let mut m = Some(m);
             let (misses, _, captured) = n.process(
                 &mut m,
@@ -598,6 +723,9 @@ impl Domain {
             assert_eq!(captured.len(), 0);
             self.process_ptimes.stop();
             self.process_times.stop();
+            if let Some(since) = traced_since {
+                self.trace_node(&n, since);
+            }
 
             if m.is_none() {
                 // no need to deal with our children if we're not sending them anything
@@ -722,6 +850,17 @@ impl Domain {
             m => unreachable!("dispatch process got {:?}", m),
         }
 
+        self.dispatch_to_children(me, m.unwrap(), executor);
+    }
+
+    /// Send the output `m` of node `me` on to each of its children.
+    fn dispatch_to_children(
+        &mut self,
+        me: LocalNodeIndex,
+        m: Box<Packet>,
+        executor: &mut dyn Executor,
+    ) {
+        let mut m = Some(m);
         // NOTE: we can't directly iterate over .children due to self.dispatch in the loop
         let nchildren = self.nodes[me].borrow().children().len();
         for i in 0..nchildren {
@@ -750,18 +889,295 @@ impl Domain {
         }
     }
 
+    /// Apply records that originate in a base node itself, rather than in a client write, to the
+    /// base's state, and send them downstream like the output of any other write.
+    fn emit_base_records(
+        &mut self,
+        base: LocalNodeIndex,
+        mut rs: Records,
+        executor: &mut dyn Executor,
+    ) {
+        if let Some(state) = self.state.get_mut(base) {
+            state.process_records(&mut rs, None);
+        }
+
+        let m = Box::new(Packet::Message {
+            link: Link::new(base, base),
+            data: rs,
+            trace: None,
+        });
+        self.dispatch_to_children(base, m, executor);
+    }
+
+    /// Give `node` the state it needs to be indexed by `index`, and start delivering updates to it.
+    ///
+    /// Fails if a base asks for a storage backend that this worker does not have, rather than
+    /// storing the base some other way than the operator configured.
+    fn ready(
+        &mut self,
+        node: LocalNodeIndex,
+        purge: bool,
+        index: HashSet<Vec<usize>>,
+    ) -> Result<(), String> {
+        assert_eq!(self.mode, DomainMode::Forwarding);
+
+        self.nodes[node].borrow_mut().purge = purge;
+
+        if !index.is_empty() {
+            let mut s: Box<dyn State> = {
+                let n = self.nodes[node].borrow();
+                let params = &self.persistence_parameters;
+                match n.get_base() {
+                    Some(base) => {
+                        let base_name = format!(
+                            "{}-{}-{}",
+                            params.log_prefix,
+                            n.name(),
+                            self.shard.unwrap_or(0),
+                        );
+
+                        // the controller only places bases on workers that have their backend
+                        let backends = &self.storage_backends;
+                        let key = base.key();
+                        backends.open(base.storage(), base_name, key, params)?
+                    }
+                    None => Box::new(MemoryState::default()),
+                }
+            };
+            for idx in index {
+                s.add_key(&idx[..], None);
+            }
+            let retention = self.nodes[node]
+                .borrow()
+                .get_base()
+                .and_then(|b| b.retention());
+            if let Some((column, _)) = retention {
+                // so that expiry can look up old rows without a full scan
+                s.add_key(&[column], None);
+            }
+            assert!(self.state.insert(node, s).is_none());
+        } else {
+            // NOTE: just because index_on is None does *not* mean we're not
+            // materialized
+        }
+
+        if self.not_ready.remove(&node) {
+            trace!(self.log, "readying empty node"; "local" => node.id());
+        }
+
+        let has_retention = self.nodes[node]
+            .borrow()
+            .get_base()
+            .and_then(|b| b.retention())
+            .is_some();
+        if has_retention && self.next_expiry.is_none() {
+            self.next_expiry = Some(time::Instant::now() + self.retention_interval);
+        }
+
+        // swap replayed reader nodes to expose new state
+        {
+            let mut n = self.nodes[node].borrow_mut();
+            if n.is_reader() {
+                n.with_reader_mut(|r| {
+                    if let Some(ref mut state) = r.writer_mut() {
+                        trace!(self.log, "swapping state"; "local" => node.id());
+                        state.swap();
+                        trace!(self.log, "state swapped"; "local" => node.id());
+                    }
+                })
+                .unwrap();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete rows that have outlived their base's retention period, if it is time to look for
+    /// them. The deletions flow downstream like deletions issued by clients.
+    fn expire_base_rows(&mut self, executor: &mut dyn Executor) {
+        match self.next_expiry {
+            Some(at) if at <= time::Instant::now() => {}
+            _ => return,
+        }
+        self.next_expiry = Some(time::Instant::now() + self.retention_interval);
+
+        let bases: Vec<_> = self
+            .nodes
+            .values()
+            .filter_map(|n| {
+                let n = n.borrow();
+                n.get_base()
+                    .and_then(|b| b.retention())
+                    .map(|_| n.local_addr())
+            })
+            .collect();
+
+        let now = time::SystemTime::now();
+        for base in bases {
+            let expired = {
+                let state = match self.state.get(base) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let n = self.nodes[base].borrow();
+                let b = n.get_base().unwrap();
+                let (column, ranges) = match b.expired_ranges(now) {
+                    Some(expired) => expired,
+                    None => continue,
+                };
+
+                // the base is indexed by its retention column, so we only need to look at the
+                // rows that may have expired rather than at all of them
+                let mut rows = Vec::new();
+                for range in &ranges {
+                    match state.lookup_range(&[column], range) {
+                        LookupResult::Some(rs) => rows.extend(rs.into_iter().map(Cow::into_owned)),
+                        LookupResult::Missing => unreachable!("base state is never partial"),
+                    }
+                }
+                b.expired_rows(rows, now)
+            };
+            if !expired.is_empty() {
+                debug!(self.log, "expiring base rows"; "node" => base.id(), "rows" => expired.len());
+                self.emit_base_records(base, expired, executor);
+            }
+        }
+    }
+
+    /// Convert the next few rows of each base whose column types changed. The changes flow
+    /// downstream like writes issued by clients.
+    fn retype_base_rows(&mut self, executor: &mut dyn Executor) {
+        let bases: Vec<_> = self.retyping.iter().map(|(base, _)| base).collect();
+        for base in bases {
+            let (changes, done) = {
+                let (ref columns, ref mut keys) = *self.retyping.get_mut(base).unwrap();
+                let state = self
+                    .state
+                    .get(base)
+                    .expect("base with rows to convert has no state");
+                let n = self.nodes[base].borrow();
+                let b = n.get_base().unwrap();
+
+                // keys whose rows have since been deleted are simply not found, and rows written
+                // since already have the new types
+                let mut rows = Vec::new();
+                for key in keys.drain(keys.len().saturating_sub(BATCH_SIZE)..) {
+                    match state.lookup(columns, &KeyType::from(&key[..])) {
+                        LookupResult::Some(rs) => rows.extend(rs),
+                        LookupResult::Missing => unreachable!("base state is never partial"),
+                    }
+                }
+                (b.retype_rows(rows), keys.is_empty())
+            };
+            if done {
+                self.retyping.remove(base);
+            }
+            if !changes.is_empty() {
+                self.emit_base_records(base, changes, executor);
+            }
+        }
+    }
+
+    /// If `m` is part of a sampled write, start this domain's span for handling it, and make the
+    /// domains it is sent on to record their spans below that one.
+    fn start_trace(&mut self, m: &mut Packet) -> Option<(TraceContext, time::SystemTime)> {
+        let trace = m.trace_mut()?;
+        let from = *trace;
+        let span_id = tracing::new_span_id();
+        trace.parent = Some(span_id);
+        self.traced = Some((from.trace_id, span_id));
+        Some((from, time::SystemTime::now()))
+    }
+
+    /// Record this domain's span for the sampled write it just finished handling.
+    fn finish_trace(&mut self, from: TraceContext, started: time::SystemTime) {
+        let (trace_id, span_id) = self.traced.take().unwrap();
+        let end = time::SystemTime::now();
+        self.tracer.record(tracing::Span {
+            trace_id,
+            span_id,
+            parent: from.parent,
+            name: format!("domain {}.{}", self.index.index(), self.shard.unwrap_or(0)),
+            // the sender's clock may be ahead of ours
+            start: cmp::min(from.since, started),
+            end,
+            attributes: vec![
+                ("noria.domain", self.index.index().to_string()),
+                ("noria.shard", self.shard.unwrap_or(0).to_string()),
+                (
+                    "noria.queued_us",
+                    started
+                        .duration_since(from.since)
+                        .map(|d| d.as_micros())
+                        .unwrap_or(0)
+                        .to_string(),
+                ),
+            ],
+        });
+    }
+
+    /// Record a span for `n` processing its part of the sampled write being handled.
+    fn trace_node(&self, n: &Node, since: time::SystemTime) {
+        let (trace_id, parent) = self.traced.unwrap();
+        let kind = if n.is_base() {
+            "base"
+        } else if n.is_ingress() {
+            "ingress"
+        } else if n.is_egress() {
+            "egress"
+        } else if n.is_sharder() {
+            "sharder"
+        } else if n.is_reader() {
+            // this includes making the write visible to readers
+            "reader"
+        } else {
+            "operator"
+        };
+        self.tracer.record(tracing::Span {
+            trace_id,
+            span_id: tracing::new_span_id(),
+            parent: Some(parent),
+            name: format!("{} {}", kind, n.name()),
+            start: since,
+            end: time::SystemTime::now(),
+            attributes: vec![
+                ("noria.node", n.global_addr().index().to_string()),
+                ("noria.node_type", kind.to_owned()),
+            ],
+        });
+    }
+
     #[allow(clippy::cognitive_complexity)]
-    fn handle(&mut self, m: Box<Packet>, executor: &mut dyn Executor, top: bool) {
+    fn handle(&mut self, mut m: Box<Packet>, executor: &mut dyn Executor, top: bool) {
         if self.wait_time.is_running() {
             self.wait_time.stop();
         }
 
+        if let Packet::PrepareState { .. } | Packet::SetupReplayPath { .. } | Packet::Ready { .. } =
+            *m
+        {
+            self.setup_log.push((*m).clone());
+        }
+
+        let replay_started = match *m {
+            Packet::ReplayPiece { .. }
+            | Packet::RequestReaderReplay { .. }
+            | Packet::RequestPartialReplay { .. }
+            | Packet::StartReplay { .. }
+            | Packet::Finish(..) => Some(time::Instant::now()),
+            _ => None,
+        };
+
         match *m {
             Packet::Message { .. } | Packet::Input { .. } => {
+                let traced = self.start_trace(&mut m);
                 // WO for https://github.com/rust-lang/rfcs/issues/1403
                 self.total_forward_time.start();
                 self.dispatch(m, executor);
                 self.total_forward_time.stop();
+                if let Some((from, started)) = traced {
+                    self.finish_trace(from, started);
+                }
             }
             Packet::ReplayPiece { .. } => {
                 self.total_replay_time.start();
@@ -789,11 +1205,27 @@ impl Domain {
                         trace!(self.log, "new node incorporated"; "local" => addr.id());
                     }
                     Packet::RemoveNodes { nodes } => {
+                        self.setup_log.retain(|m| match *m {
+                            Packet::PrepareState { node, .. } | Packet::Ready { node, .. } => {
+                                !nodes.contains(&node)
+                            }
+                            _ => true,
+                        });
+
                         for &node in &nodes {
                             self.nodes[node].borrow_mut().remove();
-                            self.state.remove(node);
+                            self.retyping.remove(node);
+                            if let Some(mut state) = self.state.remove(node) {
+                                // the node is gone for good, so its state on disk should go too
+                                if let Err(e) = state.destroy() {
+                                    warn!(self.log, "failed to destroy state of removed node";
+                                          "local" => node.id(), "error" => e);
+                                }
+                            }
                             trace!(self.log, "node removed"; "local" => node.id());
                         }
+                        // no replays are coming for the holes in removed nodes anymore
+                        self.holes_since.retain(|&(n, _), _| !nodes.contains(&n));
 
                         for node in nodes {
                             for cn in self.nodes.iter_mut() {
@@ -834,6 +1266,80 @@ impl Domain {
                             .send(ControlReplyPacket::ack())
                             .unwrap();
                     }
+                    Packet::SetWritePolicies {
+                        node,
+                        universe,
+                        policies,
+                    } => {
+                        let mut n = self.nodes[node].borrow_mut();
+                        n.get_base_mut()
+                            .expect("told to set write policies on non-base node")
+                            .set_write_policies(universe, policies);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::RestrictWrites { node } => {
+                        let mut n = self.nodes[node].borrow_mut();
+                        n.get_base_mut()
+                            .expect("told to restrict writes to non-base node")
+                            .restrict_writes();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::StopForwardingBase { node } => {
+                        let mut n = self.nodes[node].borrow_mut();
+                        n.get_base_mut()
+                            .expect("told to stop forwarding writes of non-base node")
+                            .stop_forwarding();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::RetireBase { node } => {
+                        self.nodes[node]
+                            .borrow_mut()
+                            .get_base_mut()
+                            .expect("told to retire non-base node")
+                            .retire();
+                        // the base that replaced this one has all the rows now
+                        self.retyping.remove(node);
+                        if let Some(mut state) = self.state.remove(node) {
+                            if let Err(e) = state.destroy() {
+                                warn!(self.log, "failed to destroy state of retired base";
+                                      "local" => node.id(), "error" => e);
+                            }
+                        }
+                        self.update_state_sizes();
+                        info!(self.log, "retired base"; "node" => node.id());
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::ModifyBaseColumn {
+                        node,
+                        column,
+                        default,
+                        ty,
+                    } => {
+                        self.nodes[node]
+                            .borrow_mut()
+                            .get_base_mut()
+                            .expect("told to modify base column of non-base node")
+                            .modify_column(column, default, ty);
+
+                        // rows we hold are converted a few keys at a time in between handling
+                        // other packets (see `retype_base_rows`). bases without state can only
+                        // convert rows as they are written.
+                        if let Some(state) = self.state.get(node) {
+                            let columns = state.keys()[0].clone();
+                            self.retyping.insert(node, (columns, state.cloned_keys()));
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
                     Packet::UpdateEgress {
                         node,
                         new_tx,
@@ -849,6 +1355,14 @@ impl Domain {
                             }
                         });
                     }
+                    Packet::RemoveEgressTx { node, to } => {
+                        self.nodes[node]
+                            .borrow_mut()
+                            .with_egress_mut(move |e| e.remove_tx(to));
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
                     Packet::UpdateSharder { node, new_txs } => {
                         let mut n = self.nodes[node].borrow_mut();
                         n.with_sharder_mut(move |s| {
@@ -865,9 +1379,25 @@ impl Domain {
                     Packet::PrepareState { node, state } => {
                         use crate::payload::InitialState;
                         match state {
-                            InitialState::PartialLocal(index) => {
+                            InitialState::PartialLocal { index, persistent } => {
                                 if !self.state.contains_key(node) {
-                                    self.state.insert(node, Box::new(MemoryState::default()));
+                                    let s: Box<dyn State> = if persistent {
+                                        let n = self.nodes[node].borrow();
+                                        let mut params = self.persistence_parameters.clone();
+                                        // partial state can't be recovered after a restart, since
+                                        // we wouldn't know which keys are holes.
+                                        params.mode = DurabilityMode::DeleteOnExit;
+                                        let name = format!(
+                                            "{}-{}-{}-partial",
+                                            params.log_prefix,
+                                            n.name(),
+                                            self.shard.unwrap_or(0),
+                                        );
+                                        Box::new(PersistentState::new(name, None, &params))
+                                    } else {
+                                        Box::new(MemoryState::default())
+                                    };
+                                    self.state.insert(node, s);
                                 }
                                 let state = self.state.get_mut(node).unwrap();
                                 for (key, tags) in index {
@@ -896,6 +1426,10 @@ impl Domain {
                             } => {
                                 use crate::backlog;
                                 let k = key.clone(); // ugh
+
+                                // the reader's shards line up with those of the trigger domain,
+                                // so misses go to the shard that the key is sharded to here.
+                                let sharding = self.nodes[node].borrow().sharded_by();
                                 let txs = (0..shards)
                                     .map(|shard| {
                                         let key = key.clone();
@@ -945,11 +1479,14 @@ impl Domain {
                                             }
                                             txs[0].send(misses).is_ok()
                                         } else {
-                                            // TODO: compound reader
                                             let mut per_shard = HashMap::new();
                                             for miss in misses {
-                                                assert_eq!(miss.len(), 1);
-                                                let shard = crate::shard_by(&miss[0], n);
+                                                let shard = if sharding.shards() == Some(n) {
+                                                    sharding.shard_of(miss)
+                                                } else {
+                                                    assert_eq!(miss.len(), 1);
+                                                    crate::shard_by(&miss[0], n)
+                                                };
                                                 per_shard
                                                     .entry(shard)
                                                     .or_insert_with(Vec::new)
@@ -1018,9 +1555,11 @@ impl Domain {
                         trigger,
                     } => {
                         // let coordinator know that we've registered the tagged path
-                        self.control_reply_tx
-                            .send(ControlReplyPacket::ack())
-                            .unwrap();
+                        if !self.restoring {
+                            self.control_reply_tx
+                                .send(ControlReplyPacket::ack())
+                                .unwrap();
+                        }
 
                         if notify_done {
                             info!(self.log,
@@ -1303,64 +1842,17 @@ impl Domain {
                         self.total_replay_time.stop();
                     }
                     Packet::Ready { node, purge, index } => {
-                        assert_eq!(self.mode, DomainMode::Forwarding);
-
-                        self.nodes[node].borrow_mut().purge = purge;
-
-                        if !index.is_empty() {
-                            let mut s: Box<dyn State> = {
-                                let n = self.nodes[node].borrow();
-                                let params = &self.persistence_parameters;
-                                match (n.get_base(), &params.mode) {
-                                    (Some(base), &DurabilityMode::DeleteOnExit)
-                                    | (Some(base), &DurabilityMode::Permanent) => {
-                                        let base_name = format!(
-                                            "{}-{}-{}",
-                                            params.log_prefix,
-                                            n.name(),
-                                            self.shard.unwrap_or(0),
-                                        );
-
-                                        Box::new(PersistentState::new(
-                                            base_name,
-                                            base.key(),
-                                            &params,
-                                        ))
-                                    }
-                                    _ => Box::new(MemoryState::default()),
-                                }
-                            };
-                            for idx in index {
-                                s.add_key(&idx[..], None);
-                            }
-                            assert!(self.state.insert(node, s).is_none());
-                        } else {
-                            // NOTE: just because index_on is None does *not* mean we're not
-                            // materialized
-                        }
-
-                        if self.not_ready.remove(&node) {
-                            trace!(self.log, "readying empty node"; "local" => node.id());
+                        let ready = self.ready(node, purge, index);
+                        if let Err(ref e) = ready {
+                            crit!(self.log, "failed to ready node: {}", e; "local" => node.id());
                         }
-
-                        // swap replayed reader nodes to expose new state
-                        {
-                            let mut n = self.nodes[node].borrow_mut();
-                            if n.is_reader() {
-                                n.with_reader_mut(|r| {
-                                    if let Some(ref mut state) = r.writer_mut() {
-                                        trace!(self.log, "swapping state"; "local" => node.id());
-                                        state.swap();
-                                        trace!(self.log, "state swapped"; "local" => node.id());
-                                    }
-                                })
-                                .unwrap();
-                            }
+                        if !self.restoring {
+                            let reply = match ready {
+                                Ok(()) => ControlReplyPacket::ack(),
+                                Err(e) => ControlReplyPacket::Failed(e),
+                            };
+                            self.control_reply_tx.send(reply).unwrap();
                         }
-
-                        self.control_reply_tx
-                            .send(ControlReplyPacket::ack())
-                            .unwrap();
                     }
                     Packet::GetStatistics => {
                         let domain_stats = noria::debug::stats::DomainStats {
@@ -1381,43 +1873,7 @@ impl Domain {
 
                                 let time = self.process_times.num_nanoseconds(local_index);
                                 let ptime = self.process_ptimes.num_nanoseconds(local_index);
-                                let mem_size = if n.is_reader() {
-                                    let mut size = 0;
-                                    n.with_reader(|r| size = r.state_size().unwrap_or(0))
-                                        .unwrap();
-                                    size
-                                } else {
-                                    self.state
-                                        .get(local_index)
-                                        .map(|s| s.deep_size_of())
-                                        .unwrap_or(0)
-                                };
-
-                                let mat_state = if !n.is_reader() {
-                                    match self.state.get(local_index) {
-                                        Some(ref s) => {
-                                            if s.is_partial() {
-                                                MaterializationStatus::Partial {
-                                                    beyond_materialization_frontier: n.purge,
-                                                }
-                                            } else {
-                                                MaterializationStatus::Full
-                                            }
-                                        }
-                                        None => MaterializationStatus::Not,
-                                    }
-                                } else {
-                                    n.with_reader(|r| {
-                                        if r.is_partial() {
-                                            MaterializationStatus::Partial {
-                                                beyond_materialization_frontier: n.purge,
-                                            }
-                                        } else {
-                                            MaterializationStatus::Full
-                                        }
-                                    })
-                                    .unwrap()
-                                };
+                                let (mem_size, mat_state, cache) = self.node_state(n);
 
                                 let probe_result = if n.is_internal() {
                                     n.probe()
@@ -1425,6 +1881,20 @@ impl Domain {
                                     Default::default()
                                 };
 
+                                // readers don't keep a row count, so only report internal state
+                                let (rows, indices) = match self.state.get(local_index) {
+                                    Some(s) if !n.is_reader() => {
+                                        let indices = s.index_stats();
+                                        let rows = indices
+                                            .iter()
+                                            .filter_map(|i| i.rows)
+                                            .max()
+                                            .unwrap_or_else(|| s.rows() as u64);
+                                        (rows, indices)
+                                    }
+                                    _ => (0, Vec::new()),
+                                };
+
                                 if time.is_some() && ptime.is_some() {
                                     Some((
                                         node_index,
@@ -1435,6 +1905,14 @@ impl Domain {
                                             mem_size,
                                             materialized: mat_state,
                                             probe_result,
+                                            cache,
+                                            rows,
+                                            indices,
+                                            records: self
+                                                .processed_records
+                                                .get(local_index)
+                                                .copied()
+                                                .unwrap_or(0),
                                         },
                                     ))
                                 } else {
@@ -1447,10 +1925,25 @@ impl Domain {
                             .send(ControlReplyPacket::Statistics(domain_stats, node_stats))
                             .unwrap();
                     }
+                    Packet::GetReplayLog => {
+                        let log = self.replay_log.iter().cloned().collect();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ReplayLog(log))
+                            .unwrap();
+                    }
                     Packet::UpdateStateSize => {
                         self.update_state_sizes();
                     }
                     Packet::Quit => unreachable!("Quit messages are handled by event loop"),
+                    Packet::Relocate
+                    | Packet::Handoff
+                    | Packet::Rerouting { .. }
+                    | Packet::Rerouted { .. } => {
+                        unreachable!("relocation messages are handled by event loop")
+                    }
+                    Packet::ForwardBase { .. } | Packet::BaseCopied { .. } => {
+                        unreachable!("base copying messages are handled by event loop")
+                    }
                     Packet::Spin => {
                         // spinning as instructed
                     }
@@ -1459,6 +1952,10 @@ impl Domain {
             }
         }
 
+        if let Some(started) = replay_started {
+            self.metrics.replayed_for(started.elapsed());
+        }
+
         if top {
             let mut elapsed_replays = Vec::new();
             loop {
@@ -1501,6 +1998,7 @@ impl Domain {
                     for (tag, requesting_shard, keys, single_shard) in elapsed_replays.drain(..) {
                         self.seed_all(tag, requesting_shard, keys, single_shard, executor);
                     }
+                    self.metrics.replayed_for(now.elapsed());
                     self.total_replay_time.stop();
                 }
 
@@ -1779,6 +2277,53 @@ impl Domain {
         }
     }
 
+    /// Remember that a partial replay along `tag` filled `keys` holes in `node`, and complain if
+    /// that took a while.
+    fn log_replay(
+        &mut self,
+        tag: Tag,
+        node: LocalNodeIndex,
+        keys: usize,
+        rows: usize,
+        requested: time::Instant,
+    ) {
+        let duration = requested.elapsed();
+        let node = self.nodes[node].borrow().global_addr();
+        if duration >= self.slow_replay_threshold {
+            warn!(self.log, "slow partial replay";
+                  "tag" => tag,
+                  "node" => node.index(),
+                  "keys" => keys,
+                  "rows" => rows,
+                  "took" => ?duration);
+        }
+
+        let path = self.replay_paths[&tag]
+            .path
+            .iter()
+            .map(|segment| noria::debug::replays::ReplayHop {
+                node: self.nodes[segment.node].borrow().global_addr(),
+                partial_key: segment.partial_key.clone(),
+            })
+            .collect();
+
+        if self.replay_log.len() == REPLAY_LOG_LEN {
+            self.replay_log.pop_front();
+        }
+        self.replay_log
+            .push_back(noria::debug::replays::ReplayLogEntry {
+                domain: self.index.index(),
+                shard: self.shard.unwrap_or(0),
+                tag: tag.id(),
+                node,
+                keys,
+                path,
+                rows,
+                finished: time::SystemTime::now(),
+                duration,
+            });
+    }
+
     #[allow(clippy::cognitive_complexity)]
     fn handle_replay(&mut self, m: Box<Packet>, ex: &mut dyn Executor) {
         let tag = m.tag().unwrap();
@@ -1792,6 +2337,9 @@ impl Domain {
         let mut finished = None;
         let mut need_replay = Vec::new();
         let mut finished_partial = 0;
+        // the node, key count, row count and request time of a hole this replay filled, if any
+        let mut filled = None;
+        let started = time::Instant::now();
 
         // this loop is just here so we have a way of giving up the borrow of self.replay_paths
         #[allow(clippy::never_loop)]
@@ -1963,8 +2511,15 @@ impl Domain {
                         assert!(!target || i == path.len() - 1);
 
                         // are we about to fill a hole?
+                        let mut requested = None;
                         if target {
                             let backfill_keys = backfill_keys.as_ref().unwrap();
+                            requested = backfill_keys
+                                .iter()
+                                .filter_map(|key| {
+                                    self.holes_since.get(&(segment.node, key.clone())).copied()
+                                })
+                                .min();
                             // mark the state for the key being replayed as *not* a hole otherwise
                             // we'll just end up with the same "need replay" response that
                             // triggered this replay initially.
@@ -2022,6 +2577,19 @@ impl Domain {
                             HashSet::new()
                         };
 
+                        if target && misses.is_empty() {
+                            let rows = match m.as_deref() {
+                                Some(Packet::ReplayPiece { data, .. }) => data.len(),
+                                _ => 0,
+                            };
+                            let keys = backfill_keys
+                                .as_ref()
+                                .unwrap()
+                                .len()
+                                .saturating_sub(captured.len());
+                            filled = Some((segment.node, keys, rows, requested));
+                        }
+
                         if target {
                             if !misses.is_empty() {
                                 // we missed while processing
@@ -2054,6 +2622,11 @@ impl Domain {
                                 {
                                     for key in backfill_keys.as_ref().unwrap().iter() {
                                         prev.remove(&key[..]);
+                                        if let Some(since) =
+                                            self.holes_since.remove(&(segment.node, key.clone()))
+                                        {
+                                            self.metrics.filled_hole_after(since.elapsed());
+                                        }
                                     }
                                 }
                             }
@@ -2432,6 +3005,12 @@ impl Domain {
             break;
         }
 
+        if let Some((node, keys, rows, requested)) = filled {
+            if keys != 0 {
+                self.log_replay(tag, node, keys, rows, requested.unwrap_or(started));
+            }
+        }
+
         if finished_partial != 0 {
             self.finished_partial_replay(tag, finished_partial);
         }
@@ -2481,6 +3060,9 @@ impl Domain {
                 // downstream nodes that missed in us on that key know that they can (probably)
                 // continue with their replays.
                 for key in for_keys.unwrap() {
+                    if let Some(since) = self.holes_since.remove(&(ni, key.clone())) {
+                        self.metrics.filled_hole_after(since.elapsed());
+                    }
                     let hole = (key_cols.clone(), key);
                     let replay = waiting.redos.remove(&hole).unwrap_or_else(|| {
                         panic!(
@@ -2652,6 +3234,7 @@ impl Domain {
             shard: Option<usize>,
             state: &mut StateMap,
             nodes: &DomainNodes,
+            holes_since: &mut HashMap<(LocalNodeIndex, Vec<DataType>), time::Instant>,
         ) {
             // TODO: this is a linear walk of replay paths -- we should make that not linear
             for (tag, ref path) in replay_paths {
@@ -2669,6 +3252,7 @@ impl Domain {
 
                     let mut keys = Vec::from(keys);
                     walk_path(&path.path[..], &mut keys, *tag, shard, nodes, ex);
+                    forget_holes(holes_since, &path.path[..], &keys);
 
                     if let TriggerEndpoint::Local(_) = path.trigger {
                         let target = replay_paths[&tag].path.last().unwrap();
@@ -2697,12 +3281,30 @@ impl Domain {
                             shard,
                             state,
                             nodes,
+                            holes_since,
                         );
                     }
                 }
             }
         }
 
+        /// Stop waiting for replays to fill the given keys in the nodes along `path`, which they
+        /// were just evicted from.
+        fn forget_holes(
+            holes_since: &mut HashMap<(LocalNodeIndex, Vec<DataType>), time::Instant>,
+            path: &[ReplayPathSegment],
+            keys: &[Vec<DataType>],
+        ) {
+            if holes_since.is_empty() {
+                return;
+            }
+            for segment in path {
+                for key in keys {
+                    holes_since.remove(&(segment.node, key.clone()));
+                }
+            }
+        }
+
         fn walk_path(
             path: &[ReplayPathSegment],
             keys: &mut Vec<Vec<DataType>>,
@@ -2734,31 +3336,55 @@ impl Domain {
                     vec![(node, num_bytes)]
                 } else {
                     let mut candidates: Vec<_> = self
-                        .nodes
-                        .values()
-                        .filter_map(|nd| {
-                            let n = &*nd.borrow();
-                            let local_index = n.local_addr();
-
-                            if n.is_reader() {
-                                let mut size = None;
-                                n.with_reader(|r| {
-                                    if r.is_partial() {
-                                        size = r.state_size();
-                                    }
-                                })
-                                .unwrap();
-                                size.map(|s| (local_index, s))
-                            } else {
-                                self.state
-                                    .get(local_index)
-                                    .filter(|state| state.is_partial())
-                                    .map(|state| (local_index, state.deep_size_of()))
+                        .evictable_nodes()
+                        .into_iter()
+                        .filter(|&(_, s, _, _)| s > 0)
+                        .collect();
+                    let mut chosen = Vec::new();
+
+                    // first, bring nodes that are over their own memory limit back under it
+                    for (node, size, _, limit) in &mut candidates {
+                        if let Some(limit) = *limit {
+                            if *size > limit {
+                                let evict = *size - limit;
+                                trace!(self.log, "node {:?} is {}b over its limit", node, evict);
+                                chosen.push((*node, evict));
+                                num_bytes = num_bytes.saturating_sub(evict);
+                                *size = limit;
                             }
+                        }
+                    }
+
+                    // then evict from low-priority nodes, emptying them if need be
+                    candidates.sort_unstable_by_key(|&(_, s, _, _)| -1 * (s as i64));
+                    for (node, size, priority, _) in &mut candidates {
+                        if num_bytes == 0 {
+                            break;
+                        }
+                        if *priority == EvictionPriority::Low {
+                            let evict = cmp::min(*size, num_bytes);
+                            chosen.push((*node, evict));
+                            num_bytes -= evict;
+                            *size -= evict;
+                        }
+                    }
+
+                    // and only then from everything else, sparing high-priority nodes if we can
+                    let spare_high = candidates
+                        .iter()
+                        .any(|&(_, s, p, _)| s > 0 && p == EvictionPriority::Normal);
+                    let mut candidates: Vec<_> = candidates
+                        .into_iter()
+                        .filter(|&(_, s, p, _)| {
+                            s > 0
+                                && p != EvictionPriority::Low
+                                && (p == EvictionPriority::Normal || !spare_high)
                         })
-                        .filter(|&(_, s)| s > 0)
-                        .map(|(x, s)| (x, s as usize))
+                        .map(|(x, s, _, _)| (x, s))
                         .collect();
+                    if num_bytes == 0 {
+                        candidates.clear();
+                    }
 
                     // we want to spread the eviction across the nodes,
                     // rather than emptying out one node completely.
@@ -2794,7 +3420,8 @@ impl Domain {
                         n -= 1;
                     }
 
-                    candidates
+                    chosen.extend(candidates);
+                    chosen
                 };
 
                 for (node, num_bytes) in nodes {
@@ -2805,7 +3432,10 @@ impl Domain {
                         if n.is_dropped() {
                             break; // Node was dropped. Give up.
                         } else if n.is_reader() {
-                            let freed_now = n.with_reader_mut(|r| r.evict_random_keys(16)).unwrap();
+                            let policy = &*self.eviction_policy;
+                            let freed_now = n
+                                .with_reader_mut(|r| r.evict_cold_keys(16, policy))
+                                .unwrap();
 
                             freed += freed_now;
                             if n.with_reader(|r| r.is_empty()).unwrap() {
@@ -2818,12 +3448,16 @@ impl Domain {
                             }
                         } else {
                             let (key_columns, keys, bytes) = {
-                                let k = self.state[node].evict_random_keys(16);
+                                let k =
+                                    self.state[node].evict_cold_keys(16, &*self.eviction_policy);
                                 (k.0.to_vec(), k.1, k.2)
                             };
                             freed += bytes;
 
                             if !keys.is_empty() {
+                                for key in &keys {
+                                    self.holes_since.remove(&(node, key.clone()));
+                                }
                                 trigger_downstream_evictions(
                                     &self.log,
                                     &key_columns[..],
@@ -2835,6 +3469,7 @@ impl Domain {
                                     self.shard,
                                     &mut self.state,
                                     &self.nodes,
+                                    &mut self.holes_since,
                                 );
                             }
                             if self.state[node].is_empty() {
@@ -2844,7 +3479,10 @@ impl Domain {
                         }
                     }
                     debug!(self.log, "evicted {} from node {:?}", freed, n);
-                    self.state_size.fetch_sub(freed as usize, Ordering::AcqRel);
+                    self.metrics.evicted(freed);
+                    self.state_size
+                        .total
+                        .fetch_sub(freed as usize, Ordering::AcqRel);
                 }
             }
             (Packet::EvictKeys {
@@ -2865,6 +3503,7 @@ impl Domain {
                     .position(|ps| ps.node == dst)
                     .expect("got eviction for non-local node");
                 walk_path(&path[i..], &mut keys, tag, self.shard, &mut self.nodes, ex);
+                forget_holes(&mut self.holes_since, &path[i..], &keys);
 
                 match trigger {
                     TriggerEndpoint::End { .. } | TriggerEndpoint::Local(..) => {
@@ -2881,6 +3520,7 @@ impl Domain {
                         }
                         if let Some(evicted) = self.state[target].evict_keys(tag, &keys) {
                             let key_columns = evicted.0.to_vec();
+                            self.metrics.evicted(evicted.1);
                             trigger_downstream_evictions(
                                 &self.log,
                                 &key_columns[..],
@@ -2892,6 +3532,7 @@ impl Domain {
                                 self.shard,
                                 &mut self.state,
                                 &mut self.nodes,
+                                &mut self.holes_since,
                             );
                         }
                     }
@@ -2902,31 +3543,260 @@ impl Domain {
         };
     }
 
+    /// Ship this domain's nodes and state to the controller so that the domain can be started on
+    /// another worker, and hold on to everything that arrives afterwards until `Handoff`.
+    ///
+    /// A domain that is in the middle of a replay, or of converting base rows to new column types,
+    /// can't be moved, since that progress is not part of the snapshot. The controller is told as
+    /// much, and may try again later.
+    fn relocate(&mut self, executor: &mut dyn Executor) {
+        // every write we have accepted must be reflected in the state we ship
+        for m in self.group_commit_queues.flush_all() {
+            self.handle(m, executor, true);
+        }
+
+        let busy = self.mode != DomainMode::Forwarding
+            || !self.waiting.is_empty()
+            || self.concurrent_replays != 0
+            || !self.replay_request_queue.is_empty()
+            || !self.delayed_for_self.is_empty()
+            || !self.retyping.is_empty()
+            || self
+                .buffered_replay_requests
+                .values()
+                .any(|&(_, ref keys, _)| !keys.is_empty())
+            || self.reader_triggered.values().any(|keys| !keys.is_empty());
+        if busy {
+            warn!(
+                self.log,
+                "refusing to relocate domain with replays in flight"
+            );
+            self.control_reply_tx
+                .send(ControlReplyPacket::Snapshot(None))
+                .unwrap();
+            return;
+        }
+
+        // the replacement starts out with nothing but holes, so anything that was filled in here
+        // must also be evicted downstream. otherwise, updates to those keys would be dropped at the
+        // replacement while other domains still hold on to the keys.
+        let partial: Vec<_> = self
+            .state
+            .iter()
+            .filter(|&(_, s)| s.is_partial())
+            .map(|(node, _)| node)
+            .collect();
+        for node in partial {
+            self.handle_eviction(
+                Box::new(Packet::Evict {
+                    node: Some(node),
+                    num_bytes: usize::MAX,
+                }),
+                executor,
+            );
+        }
+
+        let mut rows: Vec<_> = self
+            .state
+            .iter()
+            .filter(|&(_, s)| !s.is_partial())
+            .map(|(node, s)| (node, s.cloned_records()))
+            .collect();
+        let shard = self.shard.unwrap_or(0);
+        for n in self.nodes.values() {
+            let n = n.borrow();
+            if let Ok(reader_rows) = n.with_reader(|r| r.cloned_records()) {
+                if let Some(reader_rows) = reader_rows {
+                    rows.push((n.local_addr(), reader_rows));
+                }
+
+                // reads for this view will have to go to the new worker
+                tokio::task::block_in_place(|| {
+                    self.readers
+                        .lock()
+                        .unwrap()
+                        .remove(&(n.global_addr(), shard))
+                });
+            }
+        }
+
+        // persistent state must be closed before the replacement tries to open it
+        self.state = StateMap::default();
+        self.replay_paths.clear();
+        self.replay_paths_by_dst = Default::default();
+        self.buffered_replay_requests.clear();
+        self.timed_purges.clear();
+        self.next_expiry = None;
+        self.not_ready.clear();
+
+        let snapshot = DomainSnapshot {
+            setup: mem::replace(&mut self.setup_log, Vec::new()),
+            rows,
+            ingress_inject: mem::replace(&mut self.ingress_inject, Default::default()),
+        };
+        let builder = DomainBuilder {
+            index: self.index,
+            shard: self.shard,
+            nshards: self.nshards,
+            nodes: mem::replace(&mut self.nodes, Default::default()),
+            persistence_parameters: self.persistence_parameters.clone(),
+            config: Config {
+                concurrent_replays: self.max_concurrent_replays,
+                replay_batch_timeout: self.replay_batch_timeout,
+                retention_interval: self.retention_interval,
+                slow_replay_threshold: self.slow_replay_threshold,
+            },
+            snapshot: Some(Box::new(snapshot)),
+        };
+
+        info!(self.log, "relocating domain");
+        self.relocation = Relocation::Buffering(VecDeque::new());
+        self.control_reply_tx
+            .send(ControlReplyPacket::Snapshot(Some(Box::new(builder))))
+            .unwrap();
+        self.update_state_sizes();
+    }
+
+    /// Send the rows of a base, a batch at a time, to the shards of the base that its table is
+    /// moving to, and pass on every write the base takes from then on.
+    fn forward_base(&mut self, node: LocalNodeIndex, to: BaseForward, executor: &mut dyn Executor) {
+        use itertools::Itertools;
+
+        // every write we have accepted must be reflected in the rows we send
+        if let Some(m) = self.group_commit_queues.flush(node) {
+            self.handle(m, executor, true);
+        }
+        // the rows sent must have the base's current column types
+        while self.retyping.contains_key(node) {
+            self.retype_base_rows(executor);
+        }
+
+        let (domain, shards, copy_to) = (to.domain, to.shards, to.node);
+        self.nodes[node]
+            .borrow_mut()
+            .get_base_mut()
+            .expect("told to forward writes of non-base node")
+            .forward_to(to);
+        let rows = self
+            .state
+            .get(node)
+            .expect("base has no state")
+            .cloned_records();
+        info!(self.log, "copying base rows"; "node" => node.id(), "rows" => rows.len());
+
+        let n = self.nodes[node].borrow();
+        let base = n.get_base().unwrap();
+        for batch in &rows.into_iter().chunks(BATCH_SIZE) {
+            for (to, m) in base.forwarded(batch.map(TableOperation::Insert).collect(), None) {
+                executor.send(to, m);
+            }
+        }
+        // writes forwarded from now on arrive after all the rows
+        for shard in 0..shards {
+            executor.send(
+                (domain, shard),
+                Box::new(Packet::BaseCopied { node: copy_to }),
+            );
+        }
+    }
+
+    /// Acknowledge that a shard of the base that a table is moving from has sent all its rows
+    /// here, once they are all in the new base `node`.
+    fn base_copied(&mut self, node: LocalNodeIndex, executor: &mut dyn Executor) {
+        if let Some(m) = self.group_commit_queues.flush(node) {
+            self.handle(m, executor, true);
+        }
+        self.control_reply_tx
+            .send(ControlReplyPacket::ack())
+            .unwrap();
+    }
+
+    /// Rebuild the materializations of a domain that was moved here from another worker.
+    fn restore(&mut self, snapshot: DomainSnapshot) {
+        let DomainSnapshot {
+            setup,
+            rows,
+            ingress_inject,
+        } = snapshot;
+
+        info!(self.log, "restoring relocated domain"; "setup" => setup.len(), "states" => rows.len());
+        self.ingress_inject = ingress_inject;
+        self.restoring = true;
+        for m in setup {
+            self.handle(Box::new(m), &mut NoOutput, true);
+        }
+        self.restoring = false;
+
+        for (node, rows) in rows {
+            let mut n = self.nodes[node].borrow_mut();
+            if n.is_reader() {
+                n.with_reader_mut(|r| {
+                    let w = r
+                        .writer_mut()
+                        .expect("relocated reader state was not prepared");
+                    w.add(rows.into_iter().map(Record::Positive));
+                    w.swap();
+                })
+                .unwrap();
+            } else {
+                let state = self
+                    .state
+                    .get_mut(node)
+                    .expect("relocated state was not prepared");
+                // a persistent base may have been reopened with its rows intact
+                if state.is_empty() {
+                    let mut rs: Records = rows.into_iter().collect();
+                    state.process_records(&mut rs, None);
+                }
+            }
+        }
+    }
+
+    /// Whether this domain has been moved to another worker, and now only forwards packets to it.
+    pub fn is_relocated(&self) -> bool {
+        match self.relocation {
+            Relocation::None => false,
+            Relocation::Buffering(_) | Relocation::Forwarding => true,
+        }
+    }
+
+    /// Whether this domain has been moved to another worker, and its replacement is up.
+    pub fn has_handed_off(&self) -> bool {
+        match self.relocation {
+            Relocation::Forwarding => true,
+            Relocation::None | Relocation::Buffering(_) => false,
+        }
+    }
+
     pub fn id(&self) -> (Index, usize) {
         (self.index, self.shard.unwrap_or(0))
     }
 
     pub fn booted(&mut self, addr: SocketAddr) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.restore(*snapshot);
+        }
         info!(self.log, "booted domain"; "nodes" => self.nodes.len());
         self.control_reply_tx
             .send(ControlReplyPacket::Booted(self.shard.unwrap_or(0), addr))
             .unwrap();
     }
 
-    pub fn update_state_sizes(&mut self) {
-        let total: u64 = self
-            .nodes
+    /// The nodes of this domain whose state can be evicted, along with the size of that state,
+    /// the node's eviction priority, and its memory limit.
+    fn evictable_nodes(&self) -> Vec<(LocalNodeIndex, usize, EvictionPriority, Option<usize>)> {
+        self.nodes
             .values()
-            .map(|nd| {
+            .filter_map(|nd| {
                 let n = &*nd.borrow();
                 let local_index = n.local_addr();
 
-                if n.is_reader() {
+                let size = if n.is_reader() {
                     // We are a reader, which has its own kind of state
-                    let mut size = 0;
+                    let mut size = None;
                     n.with_reader(|r| {
                         if r.is_partial() {
-                            size = r.state_size().unwrap_or(0)
+                            size = Some(r.state_size().unwrap_or(0));
                         }
                     })
                     .unwrap();
@@ -2935,15 +3805,111 @@ impl Domain {
                     // Not a reader, state is with domain
                     self.state
                         .get(local_index)
-                        .filter(|state| state.is_partial())
+                        .filter(|state| state.is_partial() && !state.is_persistent())
                         .map(|s| s.deep_size_of())
-                        .unwrap_or(0)
+                };
+                size.map(|s| (local_index, s as usize, n.eviction_priority, n.memory_limit))
+            })
+            .collect()
+    }
+
+    /// The size of `n`'s state, how it is materialized, and how lookups into it have fared.
+    fn node_state(
+        &self,
+        n: &Node,
+    ) -> (u64, MaterializationStatus, noria::debug::stats::CacheStats) {
+        let local_index = n.local_addr();
+        let mem_size = if n.is_reader() {
+            let mut size = 0;
+            n.with_reader(|r| size = r.state_size().unwrap_or(0))
+                .unwrap();
+            size
+        } else {
+            self.state
+                .get(local_index)
+                .map(|s| s.deep_size_of())
+                .unwrap_or(0)
+        };
+
+        let mat_state = if !n.is_reader() {
+            match self.state.get(local_index) {
+                Some(ref s) => {
+                    if s.is_partial() {
+                        MaterializationStatus::Partial {
+                            beyond_materialization_frontier: n.purge,
+                        }
+                    } else {
+                        MaterializationStatus::Full
+                    }
+                }
+                None => MaterializationStatus::Not,
+            }
+        } else {
+            n.with_reader(|r| {
+                if r.is_partial() {
+                    MaterializationStatus::Partial {
+                        beyond_materialization_frontier: n.purge,
+                    }
+                } else {
+                    MaterializationStatus::Full
                 }
             })
+            .unwrap()
+        };
+
+        let cache = if n.is_reader() {
+            n.with_reader(|r| r.cache_stats()).unwrap()
+        } else {
+            self.state
+                .get(local_index)
+                .map(|s| s.cache_stats())
+                .unwrap_or_default()
+        };
+
+        (mem_size, mat_state, cache)
+    }
+
+    pub fn update_state_sizes(&mut self) {
+        let nodes = self.evictable_nodes();
+        let total: usize = nodes.iter().map(|&(_, s, _, _)| s).sum();
+        let low_priority: usize = nodes
+            .iter()
+            .filter(|&&(_, _, p, _)| p == EvictionPriority::Low)
+            .map(|&(_, s, _, _)| s)
+            .sum();
+        let over_budget: usize = nodes
+            .iter()
+            .filter_map(|&(_, s, _, limit)| limit.map(|l| s.saturating_sub(l)))
             .sum();
 
-        self.state_size.store(total as usize, Ordering::Release);
+        self.state_size.total.store(total, Ordering::Release);
+        self.state_size
+            .low_priority
+            .store(low_priority, Ordering::Release);
+        self.state_size
+            .over_budget
+            .store(over_budget, Ordering::Release);
         // no response sent, as worker will read the atomic
+
+        // the worker also reports on our nodes whenever its metrics are scraped
+        let nodes = self
+            .nodes
+            .values()
+            .filter(|nd| !nd.borrow().is_dropped())
+            .map(|nd| {
+                let n = &*nd.borrow();
+                let (state_bytes, materialized, cache) = self.node_state(n);
+                NodeMetrics {
+                    node: n.global_addr(),
+                    name: n.name().to_owned(),
+                    state_bytes,
+                    materialized,
+                    reader: n.is_reader(),
+                    cache,
+                }
+            })
+            .collect();
+        self.metrics.set_nodes(nodes);
     }
 
     pub fn on_event(&mut self, executor: &mut dyn Executor, event: PollEvent) -> ProcessResult {
@@ -2952,6 +3918,7 @@ impl Domain {
         }
         //self.total_time.start();
         //self.total_ptime.start();
+        let started = time::Instant::now();
         let res = match event {
             PollEvent::ResumePolling => {
                 // when do we need to be woken up again?
@@ -2975,33 +3942,100 @@ impl Domain {
                     }
                 });
 
-                let mut timeout = opt1.or(opt2).or(opt3);
+                let opt4 = self.next_expiry.map(|at| {
+                    if at > now {
+                        at - now
+                    } else {
+                        time::Duration::from_millis(0)
+                    }
+                });
+
+                // bases with rows left to convert want to get back to them right away
+                let opt5 = if self.retyping.is_empty() {
+                    None
+                } else {
+                    Some(time::Duration::from_millis(0))
+                };
+
+                let mut timeout = opt1.or(opt2).or(opt3).or(opt4).or(opt5);
                 if let Some(opt2) = opt2 {
                     timeout = Some(std::cmp::min(timeout.unwrap(), opt2));
                 }
                 if let Some(opt3) = opt3 {
                     timeout = Some(std::cmp::min(timeout.unwrap(), opt3));
                 }
+                if let Some(opt4) = opt4 {
+                    timeout = Some(std::cmp::min(timeout.unwrap(), opt4));
+                }
+                if let Some(opt5) = opt5 {
+                    timeout = Some(std::cmp::min(timeout.unwrap(), opt5));
+                }
                 ProcessResult::KeepPolling(timeout)
             }
-            PollEvent::Process(packet) => {
+            PollEvent::Process(mut packet) => {
                 if let Packet::Quit = *packet {
                     return ProcessResult::StopPolling;
                 }
 
-                // TODO: Initialize tracer here, and when flushing group commit
-                // queue.
+                match self.relocation {
+                    Relocation::None => {}
+                    Relocation::Buffering(ref mut buffered) => {
+                        if let Packet::Handoff = *packet {
+                            let buffered = mem::replace(buffered, VecDeque::new());
+                            self.relocation = Relocation::Forwarding;
+                            for m in buffered {
+                                executor.forward(m);
+                            }
+                        } else {
+                            buffered.push_back(packet);
+                        }
+                        return ProcessResult::Processed;
+                    }
+                    Relocation::Forwarding => {
+                        executor.forward(packet);
+                        return ProcessResult::Processed;
+                    }
+                }
+                if let Packet::Relocate = *packet {
+                    self.relocate(executor);
+                    return ProcessResult::Processed;
+                }
+                if let Packet::ForwardBase { node, ref to } = *packet {
+                    let to = to.clone();
+                    self.forward_base(node, to, executor);
+                    return ProcessResult::Processed;
+                }
+                if let Packet::BaseCopied { node } = *packet {
+                    self.base_copied(node, executor);
+                    return ProcessResult::Processed;
+                }
+
+                // writes are sampled as they arrive, so that their traces include the time spent
+                // waiting for group commit
+                if let Packet::Input { ref mut trace, .. } = *packet {
+                    if trace.is_none() {
+                        *trace = self.tracer.sample();
+                    }
+                }
                 if self.group_commit_queues.should_append(&packet, &self.nodes) {
                     if let Some(packet) = self.group_commit_queues.append(packet) {
                         self.handle(packet, executor, true);
                     }
                 } else {
+                    if let Packet::Input { .. } = *packet {
+                        // earlier writes to the same base must not be overtaken
+                        if let Some(queued) = self.group_commit_queues.flush(packet.dst()) {
+                            self.handle(queued, executor, true);
+                        }
+                    }
                     self.handle(packet, executor, true);
                 }
 
                 while let Some(m) = self.group_commit_queues.flush_if_necessary() {
                     self.handle(m, executor, true);
                 }
+                self.expire_base_rows(executor);
+                self.retype_base_rows(executor);
 
                 ProcessResult::Processed
             }
@@ -3009,6 +4043,8 @@ impl Domain {
                 while let Some(m) = self.group_commit_queues.flush_if_necessary() {
                     self.handle(m, executor, true);
                 }
+                self.expire_base_rows(executor);
+                self.retype_base_rows(executor);
 
                 if !self.buffered_replay_requests.is_empty() || !self.timed_purges.is_empty() {
                     self.handle(Box::new(Packet::Spin), executor, true);
@@ -3017,6 +4053,7 @@ impl Domain {
                 ProcessResult::Processed
             }
         };
+        self.metrics.processed_for(started.elapsed());
         if !self.wait_time.is_running() {
             self.wait_time.start();
         }
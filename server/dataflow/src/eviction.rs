@@ -0,0 +1,333 @@
+//! Policies for choosing which keys to evict from partially materialized state.
+//!
+//! Partial state (and partial readers) keep an `AccessTracker` that records, approximately, when
+//! each key was last looked up. When memory runs low, a number of keys are sampled at random, and
+//! an `EvictionPolicy` picks which of them to evict based on those access times. This is the
+//! approach Redis takes for its approximated LRU, and avoids having to maintain a full recency
+//! list on the lookup path.
+
+use noria::debug::stats::CacheStats;
+use rand::Rng;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+/// Decides which keys are evicted when a worker exceeds its memory limit.
+pub trait EvictionPolicy: Send + Sync + fmt::Debug {
+    /// The number of keys to sample for each key that is evicted.
+    fn samples(&self) -> usize;
+
+    /// Choose which of the sampled keys to evict, given when each of them was last accessed.
+    ///
+    /// Access times are logical timestamps where larger is more recent, and `0` means that the
+    /// key has not been accessed since it was filled. `last_access` is never empty, and the
+    /// returned index must be less than its length.
+    fn choose(&self, last_access: &[u64]) -> usize;
+}
+
+/// Evicts keys uniformly at random, regardless of how often they are accessed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomEviction;
+
+impl EvictionPolicy for RandomEviction {
+    fn samples(&self) -> usize {
+        1
+    }
+
+    fn choose(&self, _: &[u64]) -> usize {
+        0
+    }
+}
+
+/// Approximates least-recently-used eviction by evicting the least recently accessed of a few
+/// randomly sampled keys.
+///
+/// More samples give a closer approximation of true LRU, at the cost of more work per evicted key.
+#[derive(Clone, Copy, Debug)]
+pub struct SampledLru {
+    samples: usize,
+}
+
+impl SampledLru {
+    /// Evict the least recently used of `samples` randomly chosen keys.
+    pub fn new(samples: usize) -> Self {
+        assert_ne!(samples, 0);
+        SampledLru { samples }
+    }
+}
+
+impl Default for SampledLru {
+    fn default() -> Self {
+        SampledLru::new(5)
+    }
+}
+
+impl EvictionPolicy for SampledLru {
+    fn samples(&self) -> usize {
+        self.samples
+    }
+
+    fn choose(&self, last_access: &[u64]) -> usize {
+        last_access
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &t)| t)
+            .map(|(i, _)| i)
+            .unwrap()
+    }
+}
+
+/// How eagerly a view's state is evicted when a worker exceeds its memory limit.
+///
+/// State with `Low` priority is evicted before any other state, and state with `High` priority is
+/// only evicted once there is no `Normal` state left to evict from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum EvictionPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for EvictionPriority {
+    fn default() -> Self {
+        EvictionPriority::Normal
+    }
+}
+
+/// The size of a domain's evictable state, as last measured by the domain.
+///
+/// The domain updates these periodically, and the worker reads them to decide where to evict.
+#[derive(Debug, Default)]
+pub struct StateSizes {
+    /// Bytes of partially materialized state held by the domain.
+    pub total: AtomicUsize,
+    /// Bytes of that state held by nodes with `EvictionPriority::Low`.
+    pub low_priority: AtomicUsize,
+    /// Bytes by which the domain's nodes exceed their own memory limits.
+    pub over_budget: AtomicUsize,
+}
+
+/// Hash a key for use with an `AccessTracker`.
+///
+/// Callers must hash a given key the same way on lookup and on eviction.
+pub(crate) fn key_hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Number of access time slots a tracker starts out with, and the most it grows to. Keys that hash
+// to the same slot share an access time, which can only make a cold key look hotter than it is.
+const MIN_SLOTS: usize = 1 << 6;
+const MAX_SLOTS: usize = 1 << 20;
+
+/// Tracks approximately when each key of some partial state was last accessed, along with hit,
+/// miss and eviction counts.
+///
+/// Access times are kept in a table indexed by key hash, which grows along with the number of keys
+/// in the state rather than with the number of keys ever accessed. All methods take `&self` so
+/// that readers can record accesses concurrently.
+pub(crate) struct AccessTracker {
+    // A logical clock that advances on every miss and every eviction round.
+    clock: AtomicU64,
+    // Always a power of two in length.
+    last_access: RwLock<Box<[AtomicU64]>>,
+    slots: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl fmt::Debug for AccessTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessTracker")
+            .field("clock", &self.clock)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Default for AccessTracker {
+    fn default() -> Self {
+        AccessTracker {
+            clock: AtomicU64::new(1),
+            last_access: RwLock::new((0..MIN_SLOTS).map(|_| AtomicU64::new(0)).collect()),
+            slots: AtomicUsize::new(MIN_SLOTS),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AccessTracker {
+    fn with_slot<F, T>(&self, hash: u64, f: F) -> T
+    where
+        F: FnOnce(&AtomicU64) -> T,
+    {
+        let last_access = self.last_access.read().unwrap();
+        f(&last_access[hash as usize & (last_access.len() - 1)])
+    }
+
+    /// Make room for the access times of `keys` keys, so that they share slots no more than they
+    /// have to.
+    pub(crate) fn reserve(&self, keys: usize) {
+        let slots = self.slots.load(Ordering::Relaxed);
+        if keys <= slots || slots == MAX_SLOTS {
+            return;
+        }
+
+        let mut last_access = self.last_access.write().unwrap();
+        let old = last_access.len();
+        let new = cmp::min(keys, MAX_SLOTS).next_power_of_two();
+        if new <= old {
+            return;
+        }
+        // a hash's slot in the larger table is its old slot plus a multiple of the old size, so
+        // every key keeps its access time
+        *last_access = (0..new)
+            .map(|i| AtomicU64::new(last_access[i & (old - 1)].load(Ordering::Relaxed)))
+            .collect();
+        self.slots.store(new, Ordering::Relaxed);
+    }
+
+    /// Record a lookup that found the key with the given hash.
+    pub(crate) fn hit(&self, hash: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let now = self.clock.load(Ordering::Relaxed);
+        self.with_slot(hash, |slot| {
+            // avoid dirtying the cache line for keys that are read over and over
+            if slot.load(Ordering::Relaxed) != now {
+                slot.store(now, Ordering::Relaxed);
+            }
+        })
+    }
+
+    /// Record a lookup that hit a hole.
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.clock.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that `n` keys were evicted.
+    pub(crate) fn evicted(&self, n: usize) {
+        self.evictions.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// When the key with the given hash was last accessed.
+    pub(crate) fn last_access(&self, hash: u64) -> u64 {
+        self.with_slot(hash, |slot| slot.load(Ordering::Relaxed))
+    }
+
+    /// Pick a key to evict among `len` keys, where `hash_at(i)` returns the hash of the `i`th key.
+    ///
+    /// This samples as many keys as `policy` asks for and lets it choose among them.
+    pub(crate) fn choose_victim<R, F>(
+        &self,
+        policy: &dyn EvictionPolicy,
+        rng: &mut R,
+        len: usize,
+        hash_at: F,
+    ) -> usize
+    where
+        R: Rng,
+        F: Fn(usize) -> u64,
+    {
+        debug_assert_ne!(len, 0);
+        let candidates: Vec<usize> = (0..policy.samples().max(1))
+            .map(|_| rng.gen_range(0, len))
+            .collect();
+        let last_access: Vec<u64> = candidates
+            .iter()
+            .map(|&i| self.last_access(hash_at(i)))
+            .collect();
+        candidates[policy.choose(&last_access)]
+    }
+
+    /// Start a new round of evictions, so that keys accessed from now on are considered more
+    /// recently used than those accessed before.
+    pub(crate) fn tick(&self) {
+        self.clock.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampled_lru_chooses_least_recent() {
+        let lru = SampledLru::new(3);
+        assert_eq!(lru.choose(&[4, 2, 7]), 1);
+        assert_eq!(lru.choose(&[4, 0, 0]), 1);
+        assert_eq!(RandomEviction.choose(&[4, 2, 7]), 0);
+    }
+
+    #[test]
+    fn it_tracks_accesses() {
+        let t = AccessTracker::default();
+        let (hot, cold) = (key_hash(&1), key_hash(&2));
+        t.hit(cold);
+        t.miss();
+        t.hit(hot);
+        assert!(t.last_access(hot) > t.last_access(cold));
+        assert_eq!(t.last_access(key_hash(&3)), 0);
+
+        t.evicted(2);
+        let stats = t.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 2));
+    }
+
+    #[test]
+    fn it_evicts_cold_keys() {
+        let t = AccessTracker::default();
+        let keys: Vec<u64> = (0..100).map(|k| key_hash(&k)).collect();
+        t.reserve(keys.len());
+        for &k in &keys[..50] {
+            t.hit(k);
+        }
+
+        // with every key sampled, the policy should always find a cold one
+        let policy = SampledLru::new(100);
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let victim = t.choose_victim(&policy, &mut rng, keys.len(), |i| keys[i]);
+            assert!(victim >= 50);
+        }
+    }
+
+    #[test]
+    fn it_grows_with_the_state() {
+        let t = AccessTracker::default();
+        let keys: Vec<u64> = (0..1000).map(|k| key_hash(&k)).collect();
+        for &k in &keys[..500] {
+            t.hit(k);
+        }
+        t.miss();
+        let before: Vec<u64> = keys.iter().map(|&k| t.last_access(k)).collect();
+
+        t.reserve(keys.len());
+        assert_eq!(t.last_access.read().unwrap().len(), 1024);
+        // every key keeps its access time
+        for (&k, &was) in keys.iter().zip(&before) {
+            assert_eq!(t.last_access(k), was);
+        }
+
+        // it doesn't shrink, and stops growing eventually
+        t.reserve(10);
+        assert_eq!(t.last_access.read().unwrap().len(), 1024);
+        t.reserve(usize::max_value());
+        assert_eq!(t.last_access.read().unwrap().len(), MAX_SLOTS);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::metrics::DomainMetrics;
 use crate::prelude::*;
 use noria::internal::LocalOrNot;
+use std::sync::Arc;
 use std::time;
 
 pub struct GroupCommitQueueSet {
@@ -7,22 +9,26 @@ pub struct GroupCommitQueueSet {
     #[allow(clippy::vec_box)]
     pending_packets: Map<(time::Instant, Vec<Box<Packet>>)>,
     params: PersistenceParameters,
+    metrics: Arc<DomainMetrics>,
 }
 
 impl GroupCommitQueueSet {
     /// Create a new `GroupCommitQueue`.
-    pub fn new(params: &PersistenceParameters) -> Self {
+    pub fn new(params: &PersistenceParameters, metrics: Arc<DomainMetrics>) -> Self {
         Self {
             pending_packets: Map::default(),
             params: params.clone(),
+            metrics,
         }
     }
 
     /// Returns whether the given packet should be persisted.
     pub fn should_append(&self, p: &Packet, nodes: &DomainNodes) -> bool {
-        if let Packet::Input { .. } = *p {
+        if let Packet::Input { ref inner, .. } = *p {
             assert!(nodes[p.dst()].borrow().is_base());
-            true
+            // writes made on behalf of a universe are checked against its write policies, so they
+            // can't be merged with writes that aren't
+            unsafe { inner.deref() }.grant.is_none()
         } else {
             false
         }
@@ -45,9 +51,38 @@ impl GroupCommitQueueSet {
         }
     }
 
+    /// Flush the queue of `node` if it has packets in it, whether or not it has timed out.
+    pub fn flush(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
+        if self.pending_packets.contains_key(node) {
+            self.flush_internal(node)
+        } else {
+            None
+        }
+    }
+
+    /// Flush every queue that has packets in it, whether or not it has timed out.
+    #[allow(clippy::vec_box)]
+    pub fn flush_all(&mut self) -> Vec<Box<Packet>> {
+        let nodes: Vec<_> = self
+            .pending_packets
+            .iter()
+            .filter(|(_, &(_, ref ps))| !ps.is_empty())
+            .map(|(n, _)| n)
+            .collect();
+
+        nodes
+            .into_iter()
+            .filter_map(|node| self.flush_internal(node))
+            .collect()
+    }
+
     /// Merge any pending packets.
     fn flush_internal(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
-        Self::merge_packets(&mut self.pending_packets[node].1)
+        let packets = &mut self.pending_packets[node].1;
+        if !packets.is_empty() {
+            self.metrics.group_committed(packets.len());
+        }
+        Self::merge_packets(packets)
     }
 
     /// Add a new packet to be persisted, and if this triggered a flush return an iterator over the
@@ -93,14 +128,17 @@ impl GroupCommitQueueSet {
         let merged_dst = packets.peek().as_mut().unwrap().dst();
 
         let mut all_senders = vec![];
+        // if several of the writes were sampled, only the first one's trace carries on
+        let mut merged_trace = None;
         let merged_data = packets.fold(Vec::new(), |mut acc, p| {
             match *p {
                 Packet::Input {
                     inner,
                     src,
                     senders,
+                    trace,
                 } => {
-                    let Input { dst, data } = unsafe { inner.take() };
+                    let Input { dst, data, .. } = unsafe { inner.take() };
 
                     assert_eq!(senders.len(), 0);
                     assert_eq!(merged_dst, dst);
@@ -109,6 +147,7 @@ impl GroupCommitQueueSet {
                     if let Some(src) = src {
                         all_senders.push(src);
                     }
+                    merged_trace = merged_trace.or(trace);
                 }
                 _ => unreachable!(),
             }
@@ -119,9 +158,11 @@ impl GroupCommitQueueSet {
             inner: LocalOrNot::new(Input {
                 dst: merged_dst,
                 data: merged_data,
+                grant: None,
             }),
             src: None,
             senders: all_senders,
+            trace: merged_trace,
         }))
     }
 
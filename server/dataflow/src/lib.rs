@@ -14,13 +14,16 @@ extern crate serde_derive;
 extern crate slog;
 
 pub(crate) mod backlog;
+pub mod metrics;
 pub mod node;
 pub mod ops;
 pub mod payload; // it makes me _really_ sad that this has to be pub
 pub mod prelude;
 pub(crate) mod state;
+pub mod tracing;
 
 mod domain;
+mod eviction;
 mod group_commit;
 mod processing;
 
@@ -34,15 +37,26 @@ pub type Readers =
     Arc<Mutex<HashMap<(petgraph::graph::NodeIndex, usize), backlog::SingleReadHandle>>>;
 pub type DomainConfig = domain::Config;
 
-pub use crate::domain::{Domain, DomainBuilder, Index, PollEvent, ProcessResult};
+pub use crate::domain::{Domain, DomainBuilder, DomainSnapshot, Index, PollEvent, ProcessResult};
+pub use crate::eviction::{
+    EvictionPolicy, EvictionPriority, RandomEviction, SampledLru, StateSizes,
+};
 pub use crate::payload::Packet;
+#[cfg(feature = "test-support")]
+pub use crate::state::backend::{LogBackend, LogStorage};
+pub use crate::state::backend::{MEMORY_BACKEND, ROCKSDB_BACKEND};
+pub use crate::state::{BaseStorage, KeyRange, StorageBackend, StorageBackends};
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Sharding {
     None,
     ForcedNone,
     Random(usize),
     ByColumn(usize, usize),
+    /// Hash partitioned on a combination of columns.
+    ByColumns(Vec<usize>, usize),
+    /// Range partitioned on a single ordered column of the given type, split at the given bounds.
+    ByRange(usize, nom_sql::SqlType, Vec<noria::DataType>),
 }
 
 impl Sharding {
@@ -56,7 +70,72 @@ impl Sharding {
     pub fn shards(&self) -> Option<usize> {
         match *self {
             Sharding::None | Sharding::ForcedNone => None,
-            Sharding::Random(shards) | Sharding::ByColumn(_, shards) => Some(shards),
+            Sharding::Random(shards)
+            | Sharding::ByColumn(_, shards)
+            | Sharding::ByColumns(_, shards) => Some(shards),
+            Sharding::ByRange(_, _, ref bounds) => Some(bounds.len() + 1),
+        }
+    }
+
+    /// Hash partitioning on `columns`, which uses `ByColumn` if there is only one column.
+    pub fn by_columns(columns: Vec<usize>, shards: usize) -> Sharding {
+        if columns.len() == 1 {
+            Sharding::ByColumn(columns[0], shards)
+        } else {
+            Sharding::ByColumns(columns, shards)
+        }
+    }
+
+    /// The columns that decide which shard a row belongs to, if it is sharded by key.
+    pub fn columns(&self) -> Option<Vec<usize>> {
+        match *self {
+            Sharding::ByColumn(c, _) | Sharding::ByRange(c, ..) => Some(vec![c]),
+            Sharding::ByColumns(ref cs, _) => Some(cs.clone()),
+            Sharding::None | Sharding::ForcedNone | Sharding::Random(_) => None,
+        }
+    }
+
+    /// The same sharding, but on different columns, as given by `remap` for each column.
+    ///
+    /// If any of the columns doesn't map to a new column, rows end up effectively randomly
+    /// sharded.
+    pub fn remap(&self, mut remap: impl FnMut(usize) -> Option<usize>) -> Sharding {
+        match *self {
+            Sharding::ByColumn(c, shards) => match remap(c) {
+                Some(c) => Sharding::ByColumn(c, shards),
+                None => Sharding::Random(shards),
+            },
+            Sharding::ByColumns(ref cs, shards) => {
+                match cs.iter().map(|&c| remap(c)).collect::<Option<Vec<_>>>() {
+                    Some(cs) => Sharding::ByColumns(cs, shards),
+                    None => Sharding::Random(shards),
+                }
+            }
+            Sharding::ByRange(c, ref ty, ref bounds) => match remap(c) {
+                Some(c) => Sharding::ByRange(c, ty.clone(), bounds.clone()),
+                None => Sharding::Random(bounds.len() + 1),
+            },
+            ref s => s.clone(),
+        }
+    }
+
+    /// How clients should spread keys across the shards.
+    pub fn partitioning(&self) -> noria::KeyPartitioning {
+        match *self {
+            Sharding::ByRange(_, ref ty, ref bounds) => {
+                noria::KeyPartitioning::Range(ty.clone(), bounds.clone())
+            }
+            _ => noria::KeyPartitioning::Hash,
+        }
+    }
+
+    /// Pick the shard that holds `key`, which has the values of this sharding's columns.
+    pub fn shard_of(&self, key: &[noria::DataType]) -> usize {
+        match *self {
+            Sharding::ByColumn(_, shards) => shard_by(&key[0], shards),
+            Sharding::ByColumns(_, shards) => noria::shard_by_key(key, shards),
+            Sharding::ByRange(_, ref ty, ref bounds) => noria::shard_by_range(&key[0], ty, bounds),
+            Sharding::None | Sharding::ForcedNone | Sharding::Random(_) => 0,
         }
     }
 }
@@ -72,6 +151,18 @@ pub enum DurabilityMode {
     Permanent,
 }
 
+/// The on-disk table format used for persistent base table state.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PersistentTableFormat {
+    /// RocksDB's plain table format. Point lookups go through a hashed key prefix index, which is
+    /// fast, but the keys can't be iterated in order.
+    Plain,
+    /// RocksDB's block-based table format, with keys encoded so that they sort in `DataType`
+    /// order. This supports range scans over an index at the cost of somewhat slower point
+    /// lookups.
+    BlockBased,
+}
+
 /// Parameters to control the operation of GroupCommitQueue.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct PersistenceParameters {
@@ -85,6 +176,9 @@ pub struct PersistenceParameters {
     pub log_dir: Option<PathBuf>,
     /// Number of background threads PersistentState can use (shared acrosss all worker threads).
     pub persistence_threads: i32,
+    /// The table format used by PersistentState. Existing databases must be re-opened with the
+    /// format they were created with.
+    pub table_format: PersistentTableFormat,
 }
 
 impl Default for PersistenceParameters {
@@ -95,6 +189,7 @@ impl Default for PersistenceParameters {
             log_prefix: String::from("soup"),
             log_dir: None,
             persistence_threads: 1,
+            table_format: PersistentTableFormat::Plain,
         }
     }
 }
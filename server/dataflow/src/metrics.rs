@@ -0,0 +1,450 @@
+//! Metrics that Noria instances export in the Prometheus text format.
+//!
+//! Domains keep their `DomainMetrics` up to date as they go, and render nothing themselves; the
+//! instance's HTTP endpoint renders every local domain's metrics whenever it is scraped. All
+//! counters are cumulative since the domain started, so scrapers compute rates themselves.
+
+use crate::prelude::*;
+use noria::debug::stats::CacheStats;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Bucket bounds, in seconds, for latency histograms.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+/// Bucket bounds for histograms of batch sizes.
+pub const SIZE_BUCKETS: &[f64] = &[
+    1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0, 1024.0,
+];
+
+/// A histogram with fixed bucket bounds that can be updated concurrently.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, with one more bucket for those above the largest bound.
+    buckets: Box<[AtomicU64]>,
+    /// The bits of the `f64` sum of all observations.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Make an empty histogram with the given (ascending) bucket bounds.
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let i = self
+            .bounds
+            .iter()
+            .position(|&b| v <= b)
+            .unwrap_or_else(|| self.bounds.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+
+        let mut sum = self.sum.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(sum) + v).to_bits();
+            match self
+                .sum
+                .compare_exchange_weak(sum, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => sum = current,
+            }
+        }
+    }
+
+    /// Observe a duration, in seconds.
+    pub fn observe_duration(&self, d: time::Duration) {
+        self.observe(d.as_secs_f64());
+    }
+
+    /// The total number of observations.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+}
+
+/// Accumulates metrics in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    /// Start a new metric family. All of its samples must be added before the next family starts.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        self.labels(labels, None);
+        writeln!(self.out, " {}", value).unwrap();
+    }
+
+    /// Add the `_bucket`, `_sum` and `_count` samples of a histogram.
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], h: &Histogram) {
+        let mut cumulative = 0;
+        for (i, bucket) in h.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = h
+                .bounds
+                .get(i)
+                .map(|b| b.to_string())
+                .unwrap_or_else(|| "+Inf".to_owned());
+            write!(self.out, "{}_bucket", name).unwrap();
+            self.labels(labels, Some(&le));
+            writeln!(self.out, " {}", cumulative).unwrap();
+        }
+        write!(self.out, "{}_sum", name).unwrap();
+        self.labels(labels, None);
+        writeln!(
+            self.out,
+            " {}",
+            f64::from_bits(h.sum.load(Ordering::Relaxed))
+        )
+        .unwrap();
+        write!(self.out, "{}_count", name).unwrap();
+        self.labels(labels, None);
+        writeln!(self.out, " {}", cumulative).unwrap();
+    }
+
+    fn labels(&mut self, labels: &[(&str, &str)], le: Option<&str>) {
+        if labels.is_empty() && le.is_none() {
+            return;
+        }
+        self.out.push('{');
+        let le = le.map(|le| ("le", le));
+        for (i, (k, v)) in labels.iter().cloned().chain(le).enumerate() {
+            if i != 0 {
+                self.out.push(',');
+            }
+            write!(self.out, "{}=\"", k).unwrap();
+            for c in v.chars() {
+                match c {
+                    '\\' => self.out.push_str("\\\\"),
+                    '"' => self.out.push_str("\\\""),
+                    '\n' => self.out.push_str("\\n"),
+                    c => self.out.push(c),
+                }
+            }
+            self.out.push('"');
+        }
+        self.out.push('}');
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// The state of one node in a domain, as of the domain's last periodic size check.
+#[derive(Clone, Debug)]
+pub struct NodeMetrics {
+    pub node: NodeIndex,
+    pub name: String,
+    /// Bytes of state held by the node.
+    pub state_bytes: u64,
+    pub materialized: MaterializationStatus,
+    pub reader: bool,
+    /// Lookups into and evictions from the node's state, if it is partially materialized.
+    pub cache: CacheStats,
+}
+
+/// What a domain has been up to.
+#[derive(Debug)]
+pub struct DomainMetrics {
+    processing_nanos: AtomicU64,
+    replay_nanos: AtomicU64,
+    partial_replays: AtomicU64,
+    replay_latency: Histogram,
+    evicted_bytes: AtomicU64,
+    group_commit_size: Histogram,
+    nodes: Mutex<Vec<NodeMetrics>>,
+}
+
+impl Default for DomainMetrics {
+    fn default() -> Self {
+        DomainMetrics {
+            processing_nanos: AtomicU64::new(0),
+            replay_nanos: AtomicU64::new(0),
+            partial_replays: AtomicU64::new(0),
+            replay_latency: Histogram::new(LATENCY_BUCKETS),
+            evicted_bytes: AtomicU64::new(0),
+            group_commit_size: Histogram::new(SIZE_BUCKETS),
+            nodes: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl DomainMetrics {
+    pub(crate) fn processed_for(&self, d: time::Duration) {
+        self.processing_nanos
+            .fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn replayed_for(&self, d: time::Duration) {
+        self.replay_nanos
+            .fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn requested_partial_replays(&self, keys: usize) {
+        self.partial_replays
+            .fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    /// A hole that was requested `d` ago has been filled.
+    pub(crate) fn filled_hole_after(&self, d: time::Duration) {
+        self.replay_latency.observe_duration(d);
+    }
+
+    pub(crate) fn evicted(&self, bytes: u64) {
+        self.evicted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A group commit wrote `packets` packets at once.
+    pub(crate) fn group_committed(&self, packets: usize) {
+        self.group_commit_size.observe(packets as f64);
+    }
+
+    pub(crate) fn set_nodes(&self, nodes: Vec<NodeMetrics>) {
+        *self.nodes.lock().unwrap() = nodes;
+    }
+}
+
+/// Render the metrics of the given domain shards.
+pub fn render_domains(
+    domains: &[((DomainIndex, usize), Arc<DomainMetrics>)],
+    out: &mut Exposition,
+) {
+    let labels: Vec<_> = domains
+        .iter()
+        .map(|&((di, shard), _)| (di.index().to_string(), shard.to_string()))
+        .collect();
+
+    fn seconds(nanos: &AtomicU64) -> f64 {
+        nanos.load(Ordering::Relaxed) as f64 / 1e9
+    }
+    let counters: [(&str, &str, fn(&DomainMetrics) -> f64); 4] = [
+        (
+            "noria_domain_processing_seconds_total",
+            "Time spent handling packets.",
+            |m| seconds(&m.processing_nanos),
+        ),
+        (
+            "noria_domain_replay_seconds_total",
+            "Time spent handling replays and replay requests, as part of the processing time.",
+            |m| seconds(&m.replay_nanos),
+        ),
+        (
+            "noria_domain_partial_replays_total",
+            "Keys for which a partial replay was requested.",
+            |m| m.partial_replays.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "noria_domain_evicted_bytes_total",
+            "Bytes of state evicted.",
+            |m| m.evicted_bytes.load(Ordering::Relaxed) as f64,
+        ),
+    ];
+    for &(name, help, value) in &counters {
+        out.family(name, "counter", help);
+        for ((domain, shard), (_, m)) in labels.iter().zip(domains) {
+            out.sample(
+                name,
+                &[("domain", &**domain), ("shard", &**shard)],
+                value(m),
+            );
+        }
+    }
+
+    let name = "noria_domain_partial_replay_latency_seconds";
+    out.family(
+        name,
+        "histogram",
+        "Time from requesting a partial replay of a missing key until the key is filled.",
+    );
+    for ((domain, shard), (_, m)) in labels.iter().zip(domains) {
+        out.histogram(
+            name,
+            &[("domain", &**domain), ("shard", &**shard)],
+            &m.replay_latency,
+        );
+    }
+    let name = "noria_domain_group_commit_packets";
+    out.family(
+        name,
+        "histogram",
+        "Number of writes to a base table that were committed together.",
+    );
+    for ((domain, shard), (_, m)) in labels.iter().zip(domains) {
+        out.histogram(
+            name,
+            &[("domain", &**domain), ("shard", &**shard)],
+            &m.group_commit_size,
+        );
+    }
+
+    // nodes are only reported as of each domain's last size check
+    let nodes: Vec<_> = domains
+        .iter()
+        .map(|(_, m)| m.nodes.lock().unwrap().clone())
+        .collect();
+    type Samples = Vec<(Option<(&'static str, &'static str)>, f64)>;
+    let node_families: [(&str, &str, &str, fn(&NodeMetrics) -> Samples); 5] = [
+        (
+            "noria_node_state_bytes",
+            "gauge",
+            "Bytes of state held by a node.",
+            |n| vec![(None, n.state_bytes as f64)],
+        ),
+        (
+            "noria_node_materialized",
+            "gauge",
+            "How a node's state is materialized.",
+            |n| {
+                let how = match n.materialized {
+                    MaterializationStatus::Not => "none",
+                    MaterializationStatus::Full => "full",
+                    MaterializationStatus::Partial { .. } => "partial",
+                };
+                vec![(Some(("materialization", how)), 1.0)]
+            },
+        ),
+        (
+            "noria_node_lookups_total",
+            "counter",
+            "Lookups into a node's partial state, by whether they found their key.",
+            |n| {
+                if n.reader {
+                    return Vec::new();
+                }
+                vec![
+                    (Some(("result", "hit")), n.cache.hits as f64),
+                    (Some(("result", "miss")), n.cache.misses as f64),
+                ]
+            },
+        ),
+        (
+            "noria_reader_lookups_total",
+            "counter",
+            "Lookups into a reader, by whether they found their key.",
+            |n| {
+                if !n.reader {
+                    return Vec::new();
+                }
+                vec![
+                    (Some(("result", "hit")), n.cache.hits as f64),
+                    (Some(("result", "miss")), n.cache.misses as f64),
+                ]
+            },
+        ),
+        (
+            "noria_node_evicted_keys_total",
+            "counter",
+            "Keys evicted from a node's partial state.",
+            |n| vec![(None, n.cache.evictions as f64)],
+        ),
+    ];
+    for &(name, kind, help, samples) in &node_families {
+        out.family(name, kind, help);
+        for ((domain, shard), ns) in labels.iter().zip(&nodes) {
+            for n in ns {
+                let node = n.node.index().to_string();
+                for (extra, v) in samples(n) {
+                    let mut labels = vec![
+                        ("domain", &**domain),
+                        ("shard", &**shard),
+                        ("node", &*node),
+                        ("name", &*n.name),
+                    ];
+                    labels.extend(extra);
+                    out.sample(name, &labels, v);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_histograms() {
+        let h = Histogram::new(&[1.0, 10.0]);
+        h.observe(0.5);
+        h.observe(5.0);
+        h.observe(5.0);
+        h.observe(50.0);
+        assert_eq!(h.count(), 4);
+
+        let mut out = Exposition::default();
+        out.family("x", "histogram", "Some \"x\".");
+        out.histogram("x", &[("view", "a\"b")], &h);
+        assert_eq!(
+            out.finish(),
+            "# HELP x Some \"x\".\n\
+             # TYPE x histogram\n\
+             x_bucket{view=\"a\\\"b\",le=\"1\"} 1\n\
+             x_bucket{view=\"a\\\"b\",le=\"10\"} 3\n\
+             x_bucket{view=\"a\\\"b\",le=\"+Inf\"} 4\n\
+             x_sum{view=\"a\\\"b\"} 60.5\n\
+             x_count{view=\"a\\\"b\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn it_renders_domains() {
+        let m = Arc::new(DomainMetrics::default());
+        m.processed_for(time::Duration::from_millis(1500));
+        m.evicted(42);
+        m.group_committed(3);
+        m.set_nodes(vec![NodeMetrics {
+            node: NodeIndex::new(7),
+            name: "q".to_owned(),
+            state_bytes: 100,
+            materialized: MaterializationStatus::Full,
+            reader: true,
+            cache: CacheStats {
+                hits: 2,
+                misses: 1,
+                evictions: 0,
+            },
+        }]);
+
+        let mut out = Exposition::default();
+        render_domains(&[((DomainIndex::from(3), 1), m)], &mut out);
+        let out = out.finish();
+        let has = |line: &str| out.lines().any(|l| l == line);
+        assert!(has(
+            "noria_domain_processing_seconds_total{domain=\"3\",shard=\"1\"} 1.5"
+        ));
+        assert!(has(
+            "noria_domain_evicted_bytes_total{domain=\"3\",shard=\"1\"} 42"
+        ));
+        assert!(has(
+            "noria_domain_group_commit_packets_bucket{domain=\"3\",shard=\"1\",le=\"4\"} 1"
+        ));
+        assert!(has(
+            "noria_node_state_bytes{domain=\"3\",shard=\"1\",node=\"7\",name=\"q\"} 100"
+        ));
+        assert!(has(
+            "noria_reader_lookups_total{domain=\"3\",shard=\"1\",node=\"7\",name=\"q\",result=\"miss\"} 1"
+        ));
+        assert!(has(
+            "noria_node_materialized{domain=\"3\",shard=\"1\",node=\"7\",name=\"q\",materialization=\"full\"} 1"
+        ));
+    }
+}
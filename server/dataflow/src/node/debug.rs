@@ -9,7 +9,7 @@ impl fmt::Debug for Node {
             NodeType::Source => write!(f, "source node"),
             NodeType::Ingress => write!(f, "ingress node"),
             NodeType::Egress { .. } => write!(f, "egress node"),
-            NodeType::Sharder(ref s) => write!(f, "sharder [{}] node", s.sharded_by()),
+            NodeType::Sharder(ref s) => write!(f, "sharder {:?} node", s.sharded_by()),
             NodeType::Reader(..) => write!(f, "reader node"),
             NodeType::Base(..) => write!(f, "B"),
             NodeType::Internal(ref i) => write!(f, "internal {} node", i.description(true)),
@@ -26,7 +26,10 @@ impl Node {
     ) -> String {
         let mut s = String::new();
         let border = match self.sharded_by {
-            Sharding::ByColumn(_, _) | Sharding::Random(_) => "filled,dashed",
+            Sharding::ByColumn(..)
+            | Sharding::ByColumns(..)
+            | Sharding::ByRange(..)
+            | Sharding::Random(_) => "filled,dashed",
             _ => {
                 if Self::is_security(self.name()) {
                     "filled,rounded"
@@ -53,7 +56,7 @@ impl Node {
                 NodeType::Sharder(ref sharder) => {
                     s.push_str(&format!(
                         "[style=bold, shape=Msquare, label=\"shard by {}\"]\n",
-                        Self::escape(&self.fields[sharder.sharded_by()]),
+                        Self::escape(&self.column_names(sharder.sharded_by())),
                     ));
                 }
                 NodeType::Reader(_) => {
@@ -135,6 +138,12 @@ impl Node {
 
             let sharding = match self.sharded_by {
                 Sharding::ByColumn(k, w) => format!("shard ⚷: {} / {}-way", self.fields[k], w),
+                Sharding::ByColumns(ref ks, w) => {
+                    format!("shard ⚷: {} / {}-way", self.column_names(ks), w)
+                }
+                Sharding::ByRange(k, _, ref bounds) => {
+                    format!("shard ⚷: {} / {} ranges", self.fields[k], bounds.len() + 1)
+                }
                 Sharding::Random(_) => "shard randomly".to_owned(),
                 Sharding::None => "unsharded".to_owned(),
                 Sharding::ForcedNone => "desharded to avoid SS".to_owned(),
@@ -175,7 +184,7 @@ impl Node {
                 NodeType::Sharder(ref sharder) => s.push_str(&format!(
                     "{{ {} | shard by {} | {} }}",
                     addr,
-                    self.fields[sharder.sharded_by()],
+                    self.column_names(sharder.sharded_by()),
                     sharding
                 )),
                 NodeType::Reader(ref r) => {
@@ -220,6 +229,14 @@ impl Node {
         name.starts_with("sp_")
     }
 
+    fn column_names(&self, columns: &[usize]) -> String {
+        columns
+            .iter()
+            .map(|&c| &*self.fields[c])
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     fn escape(s: &str) -> String {
         use regex::Regex;
 
@@ -1,4 +1,5 @@
 use crate::domain;
+use crate::eviction::EvictionPriority;
 use crate::ops;
 use crate::prelude::*;
 use petgraph;
@@ -32,6 +33,10 @@ pub struct Node {
     taken: bool,
 
     pub purge: bool,
+    /// How eagerly this node's partial state is evicted.
+    pub eviction_priority: EvictionPriority,
+    /// The most bytes of partial state that each shard of this node should hold.
+    pub memory_limit: Option<usize>,
 
     sharded_by: Sharding,
 }
@@ -57,6 +62,8 @@ impl Node {
             taken: false,
 
             purge: false,
+            eviction_priority: EvictionPriority::default(),
+            memory_limit: None,
 
             sharded_by: Sharding::None,
         }
@@ -179,7 +186,7 @@ impl Node {
     }
 
     pub fn sharded_by(&self) -> Sharding {
-        self.sharded_by
+        self.sharded_by.clone()
     }
 
     /// Set this node's sharding property.
@@ -202,6 +209,8 @@ impl Node {
         n.index = self.index;
         n.domain = self.domain;
         n.purge = self.purge;
+        n.eviction_priority = self.eviction_priority;
+        n.memory_limit = self.memory_limit;
         self.taken = true;
 
         DanglingDomainNode(n)
@@ -340,6 +349,10 @@ impl Node {
         self.fields.len() - 1
     }
 
+    pub fn rename_column(&mut self, column: usize, field: &str) {
+        self.fields[column] = field.to_string();
+    }
+
     pub fn has_domain(&self) -> bool {
         self.domain.is_some()
     }
@@ -33,11 +33,44 @@ impl Node {
                 // NOTE: bases only accept BaseOperations
                 match m.take().map(|p| *p) {
                     Some(Packet::Input {
-                        inner, mut senders, ..
+                        inner,
+                        src,
+                        mut senders,
+                        trace,
                     }) => {
-                        let Input { dst, data } = unsafe { inner.take() };
+                        let Input { dst, data, grant } = unsafe { inner.take() };
+                        senders.extend(src);
+
+                        // a retired base has handed its rows over to the base that replaced it,
+                        // and only passes on writes made with table handles fetched before that
+                        if b.is_retired() {
+                            for (to, m) in b.forwarded(data, grant.as_ref()) {
+                                ex.send(to, m);
+                            }
+                            senders.drain(..).for_each(|src| ex.ack(src));
+                            return (vec![], vec![], HashSet::new());
+                        }
+
+                        let forwarded = b.forwards_to().map(|_| data.clone());
                         let mut rs = b.process(addr, data, &*state);
 
+                        // writes that the base's write policies don't allow are dropped before
+                        // they change anything
+                        if let Err(reason) = b.check_write(grant.as_ref(), &rs) {
+                            debug!(log, "rejecting write"; "node" => addr.id(), "reason" => &reason);
+                            senders
+                                .drain(..)
+                                .for_each(|src| ex.reject(src, reason.clone()));
+                            return (vec![], vec![], HashSet::new());
+                        }
+
+                        // the base that the table is moving to must see the same writes
+                        if let Some(ops) = forwarded {
+                            for (to, m) in b.forwarded(ops, grant.as_ref()) {
+                                ex.send(to, m);
+                            }
+                        }
+
                         // When a replay originates at a base node, we replay the data *through* that
                         // same base node because its column set may have changed. However, this replay
                         // through the base node itself should *NOT* update the materialization,
@@ -56,6 +89,7 @@ impl Node {
                         *m = Some(Box::new(Packet::Message {
                             link: Link::new(dst, dst),
                             data: rs,
+                            trace,
                         }));
                     }
                     Some(ref p) => {
@@ -1,8 +1,13 @@
+use crate::ops::filter::{self, FilterCondition};
 use crate::prelude::*;
-use noria::{Modification, Operation, TableOperation};
-use std::borrow::Cow;
+use nom_sql::SqlType;
+use noria::internal::secrets_match;
+use noria::{KeyPartitioning, Modification, Operation, TableOperation, WriteGrant};
+use std::borrow::{Borrow, Cow};
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::ops::Bound;
+use std::time;
 use vec_map::VecMap;
 
 /// Base is used to represent the root nodes of the Noria data flow graph.
@@ -13,10 +18,39 @@ use vec_map::VecMap;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Base {
     primary_key: Option<Vec<usize>>,
+    storage: Option<String>,
+    retention: Option<(usize, time::Duration)>,
 
     defaults: Vec<DataType>,
     dropped: Vec<usize>,
+    retyped: Vec<(usize, SqlType)>,
     unmodified: bool,
+
+    /// Whether writes must be made on behalf of a universe whose write policies admit them.
+    restricted: bool,
+    /// For each universe that may write to this base, the token its table handles present, and the
+    /// alternative sets of conditions that every row it writes must satisfy one of.
+    write_policies: HashMap<DataType, (String, Vec<Vec<(usize, FilterCondition)>>)>,
+    /// The base that this base's table is moving to, which every write is passed on to as well.
+    forward: Option<BaseForward>,
+    /// Whether writes are only passed on to the replacement, which has all of this base's rows.
+    retired: bool,
+}
+
+/// Where a base passes on the writes it takes once its table is moving to a base that is split
+/// into a different number of shards, and how to lay them out for that base.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaseForward {
+    /// The replacement base.
+    pub node: LocalNodeIndex,
+    /// The domain of the replacement base, and how many shards it has.
+    pub domain: DomainIndex,
+    pub shards: usize,
+    /// The columns of the replacement whose values pick the shard that a write goes to, and how.
+    pub key: Vec<usize>,
+    pub partitioning: KeyPartitioning,
+    /// For each column of the replacement, the column of this base that holds its values.
+    pub columns: Vec<usize>,
 }
 
 impl Base {
@@ -33,10 +67,33 @@ impl Base {
         self
     }
 
+    /// Builder with a named storage backend (see `StorageBackends`).
+    pub fn with_storage(mut self, storage: String) -> Base {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Builder with a retention period: rows whose `column` holds a time (a timestamp, or seconds
+    /// since the UNIX epoch) more than `ttl` in the past are deleted.
+    pub fn with_retention(mut self, column: usize, ttl: time::Duration) -> Base {
+        self.retention = Some((column, ttl));
+        self
+    }
+
     pub fn key(&self) -> Option<&[usize]> {
         self.primary_key.as_ref().map(|cols| &cols[..])
     }
 
+    /// The storage backend this base's state is kept in, if one was chosen explicitly.
+    pub fn storage(&self) -> Option<&str> {
+        self.storage.as_ref().map(String::as_str)
+    }
+
+    /// The column that determines the age of each row, and how old rows may get.
+    pub fn retention(&self) -> Option<(usize, time::Duration)> {
+        self.retention
+    }
+
     /// Add a new column to this base node.
     pub fn add_column(&mut self, default: DataType) -> usize {
         assert!(
@@ -65,6 +122,258 @@ impl Base {
         self.dropped.push(column);
     }
 
+    /// Change the type and default value of an existing column.
+    ///
+    /// Values written to the column from now on are converted to the new type; values that can't
+    /// be converted become `NULL`. Existing rows are converted with `retype_rows`.
+    pub fn modify_column(&mut self, column: usize, default: DataType, ty: SqlType) {
+        assert!(
+            !self.defaults.is_empty(),
+            "cannot modify columns of base nodes without\
+             setting default values for initial columns"
+        );
+        assert!(column < self.defaults.len());
+        self.unmodified = false;
+        self.defaults[column] = default;
+        self.retyped.retain(|&(c, _)| c != column);
+        self.retyped.push((column, ty));
+    }
+
+    /// Convert the given rows to the current column types, returning the changes needed to
+    /// replace the rows that changed.
+    pub(crate) fn retype_rows<I, R>(&self, rows: I) -> Records
+    where
+        I: IntoIterator<Item = R>,
+        R: Borrow<[DataType]>,
+    {
+        let mut changes = Vec::new();
+        for row in rows {
+            let row = row.borrow();
+            let mut new = row.to_vec();
+            self.fix(&mut new);
+            if new[..] != *row {
+                changes.push(Record::Negative(row.to_vec()));
+                changes.push(Record::Positive(new));
+            }
+        }
+        changes.into()
+    }
+
+    /// Returns deletions for those of the given rows that have outlived the base's retention
+    /// period as of `now`.
+    pub(crate) fn expired_rows(&self, rows: Vec<Vec<DataType>>, now: time::SystemTime) -> Records {
+        let (column, cutoff) = match self.expiry_cutoff(now) {
+            Some(cutoff) => cutoff,
+            None => return Records::default(),
+        };
+
+        rows.into_iter()
+            .filter(|row| match unix_seconds(&row[column]) {
+                Some(t) => t < cutoff,
+                // rows without a time never expire
+                None => false,
+            })
+            .map(Record::Negative)
+            .collect()
+    }
+
+    /// The ranges of values in the retention column that hold expired rows as of `now`, one for
+    /// each type of value that `expired_rows` reads a time from. A lookup of these ranges may
+    /// return rows without a time too, so its results should still go through `expired_rows`.
+    pub(crate) fn expired_ranges(&self, now: time::SystemTime) -> Option<(usize, Vec<KeyRange>)> {
+        let (column, cutoff) = self.expiry_cutoff(now)?;
+        let mut ranges = vec![(
+            Bound::Included(vec![DataType::BigInt(i64::min_value())]),
+            Bound::Excluded(vec![DataType::BigInt(cutoff)]),
+        )];
+        if let Some(ts) = chrono::NaiveDateTime::from_timestamp_opt(cutoff, 0) {
+            ranges.push((Bound::Unbounded, Bound::Excluded(vec![ts.into()])));
+        }
+        Some((column, ranges))
+    }
+
+    /// The retention column, and the time in seconds since the epoch before which rows expire.
+    fn expiry_cutoff(&self, now: time::SystemTime) -> Option<(usize, i64)> {
+        let (column, ttl) = self.retention?;
+        let cutoff = match now.checked_sub(ttl)?.duration_since(time::UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        Some((column, cutoff))
+    }
+
+    /// Only accept writes that are made on behalf of a universe with write policies on this base.
+    pub fn restrict_writes(&mut self) {
+        self.restricted = true;
+    }
+
+    /// Pass every write this base takes from now on to the base described by `to` as well.
+    pub fn forward_to(&mut self, to: BaseForward) {
+        self.forward = Some(to);
+    }
+
+    /// Stop passing writes on to the base that this base's table was moving to.
+    pub fn stop_forwarding(&mut self) {
+        self.forward = None;
+    }
+
+    /// The base that writes are passed on to, if any.
+    pub fn forwards_to(&self) -> Option<&BaseForward> {
+        self.forward.as_ref()
+    }
+
+    /// Only pass writes on from now on, without applying them here, since the replacement has
+    /// taken over the base's table.
+    pub fn retire(&mut self) {
+        assert!(
+            self.forward.is_some(),
+            "retired base must forward its writes"
+        );
+        self.retired = true;
+    }
+
+    /// Whether writes are only passed on to the replacement.
+    pub fn is_retired(&self) -> bool {
+        self.retired
+    }
+
+    /// The writes that pass `ops`, made with `grant`, on to each shard of the base that writes are
+    /// forwarded to, with their rows laid out like that base's.
+    pub(crate) fn forwarded(
+        &self,
+        ops: Vec<TableOperation>,
+        grant: Option<&WriteGrant>,
+    ) -> Vec<(ReplicaAddr, Box<Packet>)> {
+        let to = match self.forward {
+            Some(ref to) => to,
+            None => return Vec::new(),
+        };
+
+        let relayout = |mut row: Vec<DataType>| -> Vec<DataType> {
+            self.fix(&mut row);
+            to.columns.iter().map(|&c| row[c].clone()).collect()
+        };
+        let mut shards = vec![Vec::new(); to.shards];
+        for op in ops {
+            let op = match op {
+                TableOperation::Insert(row) => TableOperation::Insert(relayout(row)),
+                TableOperation::InsertOrUpdate { row, update } => TableOperation::InsertOrUpdate {
+                    row: relayout(row),
+                    update: to
+                        .columns
+                        .iter()
+                        .map(|&c| update.get(c).cloned().unwrap_or(Modification::None))
+                        .collect(),
+                },
+                TableOperation::Update { set, key } => TableOperation::Update {
+                    set: to
+                        .columns
+                        .iter()
+                        .map(|&c| set.get(c).cloned().unwrap_or(Modification::None))
+                        .collect(),
+                    key,
+                },
+                delete @ TableOperation::Delete { .. } => delete,
+            };
+            let shard = if to.shards == 1 {
+                0
+            } else {
+                to.partitioning.shard(&op.shard_key(&to.key), to.shards)
+            };
+            shards[shard].push(op);
+        }
+
+        shards
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, ref data)| !data.is_empty())
+            .map(|(shard, data)| {
+                let m = Packet::Input {
+                    inner: LocalOrNot::new(Input {
+                        dst: to.node,
+                        data,
+                        grant: grant.cloned(),
+                    }),
+                    src: None,
+                    senders: Vec::new(),
+                    trace: None,
+                };
+                ((to.domain, shard), Box::new(m))
+            })
+            .collect()
+    }
+
+    /// Restrict the writes made with `universe`'s grant to rows that satisfy at least one of the
+    /// given conjunctions of conditions, or revoke the universe's right to write if `policies` is
+    /// `None`. No alternatives at all rejects every write.
+    ///
+    /// This also restricts writes that aren't made on behalf of any universe.
+    pub fn set_write_policies(
+        &mut self,
+        universe: DataType,
+        policies: Option<(String, Vec<Vec<(usize, FilterCondition)>>)>,
+    ) {
+        self.restricted = true;
+        match policies {
+            Some(policies) => {
+                self.write_policies.insert(universe, policies);
+            }
+            None => {
+                self.write_policies.remove(&universe);
+            }
+        }
+    }
+
+    /// Check that the records produced by a write made with `grant` only remove and add rows
+    /// that the grant's write policies allow it to write.
+    pub(crate) fn check_write(
+        &self,
+        grant: Option<&WriteGrant>,
+        rs: &Records,
+    ) -> Result<(), String> {
+        if !self.restricted {
+            return Ok(());
+        }
+
+        let grant = grant.ok_or_else(|| {
+            "writes to this table must be made on behalf of a user universe".to_owned()
+        })?;
+        let policies = match self.write_policies.get(&grant.universe) {
+            Some((token, policies)) if secrets_match(grant.token.as_bytes(), token.as_bytes()) => {
+                policies
+            }
+            _ => {
+                return Err(format!(
+                    "universe {} may not write to this table",
+                    grant.universe.to_string()
+                ))
+            }
+        };
+
+        // the records capture both the rows as they were before the write and as they are now
+        for r in rs.iter() {
+            let mut admitted = false;
+            for p in policies {
+                match filter::matches(p, r) {
+                    Ok(true) => {
+                        admitted = true;
+                        break;
+                    }
+                    Ok(false) => {}
+                    Err(op) => return Err(format!("write policy uses unsupported {:?}", op)),
+                }
+            }
+            if !admitted {
+                return Err(format!(
+                    "universe {} may not write row {:?}",
+                    grant.universe.to_string(),
+                    &r[..]
+                ));
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_dropped(&self) -> VecMap<DataType> {
         self.dropped
             .iter()
@@ -81,6 +390,12 @@ impl Base {
             let rlen = row.len();
             row.extend(self.defaults.iter().skip(rlen).cloned());
         }
+
+        for &(column, ref ty) in &self.retyped {
+            if let Some(v) = row.get_mut(column) {
+                *v = v.coerce_to(ty).unwrap_or(DataType::None);
+            }
+        }
     }
 }
 
@@ -91,10 +406,18 @@ impl Clone for Base {
     fn clone(&self) -> Base {
         Base {
             primary_key: self.primary_key.clone(),
+            storage: self.storage.clone(),
+            retention: self.retention,
 
             defaults: self.defaults.clone(),
             dropped: self.dropped.clone(),
+            retyped: self.retyped.clone(),
             unmodified: self.unmodified,
+
+            restricted: self.restricted,
+            write_policies: self.write_policies.clone(),
+            forward: self.forward.clone(),
+            retired: self.retired,
         }
     }
 }
@@ -103,14 +426,34 @@ impl Default for Base {
     fn default() -> Self {
         Base {
             primary_key: None,
+            storage: None,
+            retention: None,
 
             defaults: Vec::new(),
             dropped: Vec::new(),
+            retyped: Vec::new(),
             unmodified: true,
+
+            restricted: false,
+            write_policies: HashMap::new(),
+            forward: None,
+            retired: false,
         }
     }
 }
 
+/// Interpret `v` as a point in time, in seconds since the UNIX epoch.
+fn unix_seconds(v: &DataType) -> Option<i64> {
+    match *v {
+        DataType::Timestamp(ts) => Some(ts.timestamp()),
+        DataType::Int(n) => Some(i64::from(n)),
+        DataType::UnsignedInt(n) => Some(i64::from(n)),
+        DataType::BigInt(n) => Some(n),
+        DataType::UnsignedBigInt(n) => Some(n as i64),
+        _ => None,
+    }
+}
+
 fn key_val(i: usize, col: usize, r: &TableOperation) -> &DataType {
     match *r {
         TableOperation::Insert(ref row) => &row[col],
@@ -284,6 +627,8 @@ impl Base {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ops::filter::{Operator, Value};
+    use std::ops::RangeBounds;
 
     #[test]
     fn it_works_default() {
@@ -307,6 +652,223 @@ mod tests {
         assert_eq!(b.unmodified, true);
     }
 
+    #[test]
+    fn it_retypes_columns() {
+        let mut b = Base::new(vec![DataType::None, DataType::None]);
+        b.modify_column(1, 0.into(), SqlType::Int(32));
+
+        let mut row = vec![1.into(), "42".into()];
+        b.fix(&mut row);
+        assert_eq!(row, vec![1.into(), 42.into()]);
+
+        let changes = b.retype_rows(vec![
+            vec![1.into(), 42.into()],
+            vec![2.into(), "7".into()],
+            vec![3.into(), "seven".into()],
+        ]);
+        let expected: Records = vec![
+            (vec![2.into(), "7".into()], false),
+            (vec![2.into(), 7.into()], true),
+            (vec![3.into(), "seven".into()], false),
+            (vec![3.into(), DataType::None], true),
+        ]
+        .into();
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn it_forwards_writes() {
+        let mut b =
+            Base::new(vec![DataType::None, DataType::None, DataType::None]).with_key(vec![0]);
+        b.drop_column(1);
+        b.add_column(5.into());
+        b.forward_to(BaseForward {
+            node: unsafe { LocalNodeIndex::make(1 as u32) },
+            domain: DomainIndex::from(2),
+            shards: 2,
+            key: vec![0],
+            partitioning: KeyPartitioning::Hash,
+            columns: vec![0, 2, 3],
+        });
+
+        // rows lose the dropped column, and gain the default of the added one
+        let forwarded = b.forwarded(
+            vec![
+                TableOperation::Insert(vec![1.into(), "x".into(), 3.into()]),
+                TableOperation::Update {
+                    set: vec![
+                        Modification::None,
+                        Modification::Set("y".into()),
+                        Modification::Set(4.into()),
+                    ],
+                    key: vec![2.into()],
+                },
+                TableOperation::Delete {
+                    key: vec![1.into()],
+                },
+            ],
+            None,
+        );
+        let mut ops = HashMap::new();
+        for ((domain, shard), m) in forwarded {
+            assert_eq!(domain, DomainIndex::from(2));
+            match *m {
+                Packet::Input {
+                    ref inner,
+                    src: None,
+                    ..
+                } => {
+                    let input = unsafe { inner.deref() };
+                    assert_eq!(input.dst, unsafe { LocalNodeIndex::make(1 as u32) });
+                    assert!(ops.insert(shard, input.data.clone()).is_none());
+                }
+                ref m => panic!("forwarded {:?}", m),
+            }
+        }
+
+        // each write goes to the shard that holds its key
+        let shard_of = |k: i32| KeyPartitioning::Hash.shard(&[k.into()], 2);
+        let mut expected: HashMap<usize, Vec<TableOperation>> = HashMap::new();
+        expected
+            .entry(shard_of(1))
+            .or_default()
+            .push(TableOperation::Insert(vec![1.into(), 3.into(), 5.into()]));
+        expected
+            .entry(shard_of(2))
+            .or_default()
+            .push(TableOperation::Update {
+                set: vec![
+                    Modification::None,
+                    Modification::Set(4.into()),
+                    Modification::None,
+                ],
+                key: vec![2.into()],
+            });
+        expected
+            .entry(shard_of(1))
+            .or_default()
+            .push(TableOperation::Delete {
+                key: vec![1.into()],
+            });
+        assert_eq!(ops, expected);
+
+        // once the base stops forwarding, nothing is passed on
+        b.stop_forwarding();
+        assert!(b
+            .forwarded(
+                vec![TableOperation::Insert(vec![1.into(), 2.into(), 3.into()])],
+                None
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn it_expires_rows() {
+        let b = Base::new(vec![DataType::None, DataType::None])
+            .with_retention(1, time::Duration::from_secs(60));
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1_000_000);
+
+        let expired = b.expired_rows(
+            vec![
+                vec![1.into(), 999_000.into()],
+                vec![2.into(), 999_990.into()],
+                vec![3.into(), DataType::None],
+            ],
+            now,
+        );
+        let expected: Records = vec![(vec![1.into(), 999_000.into()], false)].into();
+        assert_eq!(expired, expected);
+
+        // the ranges to look up cover expired integer and timestamp times, but no current ones
+        let (column, ranges) = b.expired_ranges(now).unwrap();
+        assert_eq!(column, 1);
+        let covered = |v: DataType| {
+            ranges
+                .iter()
+                .any(|r| RangeBounds::contains(r, &vec![v.clone()]))
+        };
+        let ts = |secs| DataType::from(chrono::NaiveDateTime::from_timestamp(secs, 0));
+        assert!(covered(999_000.into()));
+        assert!(!covered(999_990.into()));
+        assert!(covered(ts(999_000)));
+        assert!(!covered(ts(999_990)));
+        assert!(!covered(DataType::None));
+
+        // bases without a retention period never expire anything
+        let b = Base::new(vec![DataType::None, DataType::None]);
+        assert!(b
+            .expired_rows(vec![vec![1.into(), 0.into()]], now)
+            .is_empty());
+        assert!(b.expired_ranges(now).is_none());
+    }
+
+    #[test]
+    fn it_checks_universe_writes() {
+        let mut b = Base::new(vec![DataType::None, DataType::None]);
+        let us = unsafe { LocalNodeIndex::make(0 as u32) };
+        let states = StateMap::new();
+        let mut write = |a: i32, c: i32| {
+            b.process(
+                us,
+                vec![TableOperation::Insert(vec![a.into(), c.into()])],
+                &states,
+            )
+        };
+        let (mine, theirs) = (write(1, 3), write(2, 7));
+        let forbidden = write(2, 3);
+        let grant = |universe: i32, token: &str| WriteGrant {
+            universe: universe.into(),
+            token: token.to_owned(),
+        };
+
+        // anyone may write until the base gets write policies
+        assert!(b.check_write(None, &forbidden).is_ok());
+
+        // universe 1 may write the rows it authored, and any row in class 7
+        b.set_write_policies(
+            1.into(),
+            Some((
+                "t1".to_owned(),
+                vec![
+                    vec![(
+                        0,
+                        FilterCondition::Comparison(Operator::Equal, Value::Constant(1.into())),
+                    )],
+                    vec![(1, FilterCondition::In(vec![7.into()]))],
+                ],
+            )),
+        );
+        let g1 = grant(1, "t1");
+        assert!(b.check_write(Some(&g1), &mine).is_ok());
+        assert!(b.check_write(Some(&g1), &theirs).is_ok());
+        assert!(b.check_write(Some(&g1), &forbidden).is_err());
+
+        // writes without a grant, with a made-up grant, or with another universe's token are
+        // all refused
+        assert!(b.check_write(None, &mine).is_err());
+        assert!(b.check_write(Some(&grant(2, "t1")), &mine).is_err());
+        assert!(b.check_write(Some(&grant(1, "t2")), &mine).is_err());
+
+        // operators that write policies can't evaluate reject the write rather than panic
+        b.set_write_policies(
+            2.into(),
+            Some((
+                "t2".to_owned(),
+                vec![vec![(
+                    0,
+                    FilterCondition::Comparison(Operator::Like, Value::Constant("%".into())),
+                )]],
+            )),
+        );
+        assert!(b.check_write(Some(&grant(2, "t2")), &mine).is_err());
+
+        // a universe without any allowed rows can't write at all, nor can a revoked one
+        b.set_write_policies(1.into(), Some(("t1".to_owned(), vec![])));
+        assert!(b.check_write(Some(&g1), &theirs).is_err());
+        b.set_write_policies(1.into(), None);
+        assert!(b.check_write(Some(&g1), &mine).is_err());
+    }
+
     fn test_lots_of_changes_in_same_batch(mut state: Box<dyn State>) {
         use crate::node;
         use crate::prelude::*;
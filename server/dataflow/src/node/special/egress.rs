@@ -47,6 +47,12 @@ impl Egress {
         self.tags.insert(tag, dst);
     }
 
+    /// Stop sending to `dst`, and forget about any replay paths that lead to it.
+    pub fn remove_tx(&mut self, dst: NodeIndex) {
+        self.txs.retain(|tx| tx.node != dst);
+        self.tags.retain(|_, &mut to| to != dst);
+    }
+
     pub fn process(
         &mut self,
         m: &mut Option<Box<Packet>>,
@@ -95,6 +101,7 @@ impl Egress {
             // in which case it wants to know about the shard
             m.link_mut().src = unsafe { LocalNodeIndex::make(shard as u32) };
             m.link_mut().dst = tx.local;
+            m.trace_sent();
 
             output.send(tx.dest, m);
             if take {
@@ -6,7 +6,7 @@ mod sharder;
 pub struct Ingress;
 pub struct Source;
 
-pub use self::base::Base;
+pub use self::base::{Base, BaseForward};
 pub use self::egress::Egress;
 pub use self::reader::Reader;
 pub use self::sharder::Sharder;
@@ -1,5 +1,7 @@
 use crate::backlog;
+use crate::eviction::EvictionPolicy;
 use crate::prelude::*;
+use noria::debug::stats::CacheStats;
 
 #[derive(Serialize, Deserialize)]
 pub struct Reader {
@@ -85,23 +87,39 @@ impl Reader {
         self.writer.as_ref().map(|w| w.is_empty()).unwrap_or(true)
     }
 
+    /// Clone the rows of a fully materialized reader, or `None` if the reader is partial or has no
+    /// state.
+    pub(crate) fn cloned_records(&self) -> Option<Vec<Vec<DataType>>> {
+        match self.writer {
+            Some(ref w) if !w.is_partial() => Some(w.cloned_records()),
+            _ => None,
+        }
+    }
+
     pub(crate) fn state_size(&self) -> Option<u64> {
         self.writer.as_ref().map(SizeOf::deep_size_of)
     }
 
-    /// Evict a randomly selected key, returning the number of bytes evicted.
+    /// Evict `n` keys chosen by `policy`, returning the number of bytes evicted.
     /// Note that due to how `evmap` applies the evictions asynchronously, we can only evict a
-    /// single key at a time here.
-    pub(crate) fn evict_random_keys(&mut self, n: usize) -> u64 {
+    /// single batch of keys at a time here.
+    pub(crate) fn evict_cold_keys(&mut self, n: usize, policy: &dyn EvictionPolicy) -> u64 {
         let mut bytes_freed = 0;
         if let Some(ref mut handle) = self.writer {
             let mut rng = rand::thread_rng();
-            bytes_freed = handle.evict_random_keys(&mut rng, n);
+            bytes_freed = handle.evict_cold_keys(&mut rng, n, policy);
             handle.swap();
         }
         bytes_freed
     }
 
+    pub(crate) fn cache_stats(&self) -> CacheStats {
+        self.writer
+            .as_ref()
+            .map(backlog::WriteHandle::cache_stats)
+            .unwrap_or_default()
+    }
+
     pub(in crate::node) fn on_eviction(&mut self, keys: &[Vec<DataType>]) {
         // NOTE: *could* be None if reader has been created but its state hasn't been built yet
         if let Some(w) = self.writer.as_mut() {
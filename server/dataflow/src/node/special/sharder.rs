@@ -1,12 +1,14 @@
 use crate::payload;
 use crate::prelude::*;
+use noria::KeyPartitioning;
 use vec_map::VecMap;
 
 #[derive(Serialize, Deserialize)]
 pub struct Sharder {
     txs: Vec<(LocalNodeIndex, ReplicaAddr)>,
     sharded: VecMap<Box<Packet>>,
-    shard_by: usize,
+    shard_by: Vec<usize>,
+    partitioning: KeyPartitioning,
 }
 
 impl Clone for Sharder {
@@ -16,16 +18,30 @@ impl Clone for Sharder {
         Sharder {
             txs: Vec::new(),
             sharded: Default::default(),
-            shard_by: self.shard_by,
+            shard_by: self.shard_by.clone(),
+            partitioning: self.partitioning.clone(),
         }
     }
 }
 
 impl Sharder {
     pub fn new(by: usize) -> Self {
+        Self::partitioned(vec![by], KeyPartitioning::Hash)
+    }
+
+    /// A sharder that spreads rows across its children the way `sharding` says they should be.
+    pub fn for_sharding(sharding: &Sharding) -> Self {
+        let by = sharding
+            .columns()
+            .expect("can only shard by a sharding that has key columns");
+        Self::partitioned(by, sharding.partitioning())
+    }
+
+    fn partitioned(by: Vec<usize>, partitioning: KeyPartitioning) -> Self {
         Self {
             txs: Default::default(),
             shard_by: by,
+            partitioning,
             sharded: VecMap::default(),
         }
     }
@@ -36,7 +52,8 @@ impl Sharder {
         Self {
             txs,
             sharded: VecMap::default(),
-            shard_by: self.shard_by,
+            shard_by: self.shard_by.clone(),
+            partitioning: self.partitioning.clone(),
         }
     }
 
@@ -48,18 +65,38 @@ impl Sharder {
         }
     }
 
-    pub fn sharded_by(&self) -> usize {
-        self.shard_by
+    /// The columns that decide which shard a row goes to.
+    pub fn sharded_by(&self) -> &[usize] {
+        &self.shard_by[..]
+    }
+
+    /// The sharding of this sharder's output when it has `shards` children.
+    pub fn sharding(&self, shards: usize) -> Sharding {
+        match self.partitioning {
+            KeyPartitioning::Hash => Sharding::by_columns(self.shard_by.clone(), shards),
+            KeyPartitioning::Range(ref ty, ref bounds) => {
+                Sharding::ByRange(self.shard_by[0], ty.clone(), bounds.clone())
+            }
+        }
     }
 
     #[inline]
     fn to_shard(&self, r: &Record) -> usize {
-        self.shard(&r[self.shard_by])
+        match (&self.partitioning, &self.shard_by[..]) {
+            (KeyPartitioning::Hash, &[c]) => crate::shard_by(&r[c], self.txs.len()),
+            (KeyPartitioning::Range(ref ty, ref bounds), &[c]) => {
+                noria::shard_by_range(&r[c], ty, bounds)
+            }
+            _ => {
+                let key: Vec<_> = self.shard_by.iter().map(|&c| r[c].clone()).collect();
+                self.shard(&key)
+            }
+        }
     }
 
     #[inline]
-    fn shard(&self, dt: &DataType) -> usize {
-        crate::shard_by(dt, self.txs.len())
+    fn shard(&self, key: &[DataType]) -> usize {
+        self.partitioning.shard(key, self.txs.len())
     }
 
     pub fn process(
@@ -155,6 +192,7 @@ impl Sharder {
             if let Some(mut shard) = self.sharded.remove(i) {
                 shard.link_mut().src = index;
                 shard.link_mut().dst = dst;
+                shard.trace_sent();
                 output.send(addr, shard);
             }
         }
@@ -171,10 +209,10 @@ impl Sharder {
     ) {
         assert!(!is_sharded);
 
-        if key_columns.len() == 1 && key_columns[0] == self.shard_by {
+        if key_columns == &self.shard_by[..] {
             // Send only to the shards that must evict something.
             for key in keys {
-                let shard = self.shard(&key[0]);
+                let shard = self.shard(key);
                 let dst = self.txs[shard].0;
                 let p = self.sharded.entry(shard).or_insert_with(|| {
                     Box::new(Packet::EvictKeys {
@@ -196,7 +234,6 @@ impl Sharder {
             }
         } else {
             assert_eq!(!key_columns.len(), 0);
-            assert!(!key_columns.contains(&self.shard_by));
 
             // send to all shards
             for &mut (dst, addr) in self.txs.iter_mut() {
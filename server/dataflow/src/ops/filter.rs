@@ -40,6 +40,38 @@ pub enum FilterCondition {
     In(Vec<DataType>),
 }
 
+/// Whether a row satisfies every one of the given conditions on its columns.
+///
+/// Fails with the operator of the first condition that filters can't evaluate.
+pub fn matches(filter: &[(usize, FilterCondition)], r: &[DataType]) -> Result<bool, Operator> {
+    for (i, cond) in filter {
+        // check if this filter matches
+        let d = &r[*i];
+        let matched = match cond {
+            FilterCondition::Comparison(ref op, ref f) => {
+                let v = match *f {
+                    Value::Constant(ref dt) => dt,
+                    Value::Column(c) => &r[c],
+                };
+                match *op {
+                    Operator::Equal => d == v,
+                    Operator::NotEqual => d != v,
+                    Operator::Greater => d > v,
+                    Operator::GreaterOrEqual => d >= v,
+                    Operator::Less => d < v,
+                    Operator::LessOrEqual => d <= v,
+                    ref op => return Err(op.clone()),
+                }
+            }
+            FilterCondition::In(ref fs) => fs.contains(d),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
 impl Filter {
     /// Construct a new filter operator. The `filter` vector must have as many elements as the
     /// `src` node has columns. Each column that is set to `None` matches any value, while columns
@@ -82,29 +114,7 @@ impl Ingredient for Filter {
         _: &StateMap,
     ) -> ProcessingResult {
         rs.retain(|r| {
-            self.filter.iter().all(|(i, cond)| {
-                // check if this filter matches
-                let d = &r[*i];
-                match cond {
-                    FilterCondition::Comparison(ref op, ref f) => {
-                        let v = match *f {
-                            Value::Constant(ref dt) => dt,
-                            Value::Column(c) => &r[c],
-                        };
-                        match *op {
-                            Operator::Equal => d == v,
-                            Operator::NotEqual => d != v,
-                            Operator::Greater => d > v,
-                            Operator::GreaterOrEqual => d >= v,
-                            Operator::Less => d < v,
-                            Operator::LessOrEqual => d <= v,
-                            Operator::In => unreachable!(),
-                            _ => unimplemented!(),
-                        }
-                    }
-                    FilterCondition::In(ref fs) => fs.contains(d),
-                }
-            })
+            matches(&self.filter, r).unwrap_or_else(|op| unimplemented!("filtering on {:?}", op))
         });
 
         ProcessingResult {
@@ -420,8 +420,10 @@ pub mod test {
 
             impl Executor for Ex {
                 fn ack(&mut self, _: SourceChannelIdentifier) {}
+                fn reject(&mut self, _: SourceChannelIdentifier, _: String) {}
                 fn create_universe(&mut self, _: HashMap<String, DataType>) {}
                 fn send(&mut self, _: ReplicaAddr, _: Box<Packet>) {}
+                fn forward(&mut self, _: Box<Packet>) {}
             }
 
             let mut u = {
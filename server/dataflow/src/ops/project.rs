@@ -4,6 +4,8 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt;
 
+use noria::internal::hmac_sha256;
+
 use crate::prelude::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,6 +53,62 @@ impl fmt::Display for ProjectExpression {
     }
 }
 
+/// Obscures the value of an emitted column.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ColumnMask {
+    /// Replace the value with `NULL`.
+    Null,
+    /// Replace the value with a hex digest of it, keyed with the deployment's mask key so that
+    /// values can't be recovered by hashing guesses.
+    Hash,
+    /// Keep only the first `n` characters of the value.
+    KeepFirst(usize),
+    /// Keep only the last `n` characters of the value.
+    KeepLast(usize),
+}
+
+impl ColumnMask {
+    fn apply(&self, value: &DataType, key: &[u8]) -> DataType {
+        if value.is_none() {
+            return DataType::None;
+        }
+
+        let text = || match *value {
+            DataType::Text(..) | DataType::TinyText(..) => {
+                let s: &str = value.into();
+                s.to_owned()
+            }
+            _ => value.to_string(),
+        };
+
+        match *self {
+            ColumnMask::Null => DataType::None,
+            ColumnMask::Hash => hmac_sha256(key, text().as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+                .into(),
+            ColumnMask::KeepFirst(n) => text().chars().take(n).collect::<String>().into(),
+            ColumnMask::KeepLast(n) => {
+                let s = text();
+                let skip = s.chars().count().saturating_sub(n);
+                s.chars().skip(skip).collect::<String>().into()
+            }
+        }
+    }
+}
+
+impl fmt::Display for ColumnMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ColumnMask::Null => write!(f, "null"),
+            ColumnMask::Hash => write!(f, "hash"),
+            ColumnMask::KeepFirst(n) => write!(f, "first {}", n),
+            ColumnMask::KeepLast(n) => write!(f, "last {}", n),
+        }
+    }
+}
+
 /// Permutes or omits columns from its source node, or adds additional literal value columns.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
@@ -58,6 +116,9 @@ pub struct Project {
     emit: Option<Vec<usize>>,
     additional: Option<Vec<DataType>>,
     expressions: Option<Vec<ProjectExpression>>,
+    masks: Option<Vec<(usize, ColumnMask)>>,
+    /// The key that `ColumnMask::Hash` digests are made with.
+    mask_key: Vec<u8>,
     src: IndexPair,
     cols: usize,
 }
@@ -74,12 +135,21 @@ impl Project {
             emit: Some(emit.into()),
             additional,
             expressions,
+            masks: None,
+            mask_key: Vec::new(),
             src: src.into(),
             cols: 0,
             us: None,
         }
     }
 
+    /// Mask the given output columns of this projection, keying hashes with `key`.
+    pub fn with_masks(mut self, masks: Vec<(usize, ColumnMask)>, key: &[u8]) -> Project {
+        self.masks = if masks.is_empty() { None } else { Some(masks) };
+        self.mask_key = key.to_vec();
+        self
+    }
+
     fn resolve_col(&self, col: usize) -> usize {
         if self.emit.is_some() && col >= self.emit.as_ref().unwrap().len() {
             panic!(
@@ -98,6 +168,20 @@ impl Project {
             self.expressions.as_ref().map(Vec::as_slice).unwrap_or(&[]),
         )
     }
+
+    pub fn masks(&self) -> &[(usize, ColumnMask)] {
+        self.masks.as_ref().map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    fn is_masked(&self, col: usize) -> bool {
+        self.masks().iter().any(|&(i, _)| i == col)
+    }
+}
+
+fn apply_masks(masks: &[(usize, ColumnMask)], key: &[u8], record: &mut [DataType]) {
+    for &(i, ref mask) in masks {
+        record[i] = mask.apply(&record[i], key);
+    }
 }
 
 fn eval_expression(expression: &ProjectExpression, record: &[DataType]) -> DataType {
@@ -143,6 +227,8 @@ impl Ingredient for Project {
         let emit = self.emit.clone();
         let additional = self.additional.clone();
         let expressions = self.expressions.clone();
+        let masks = self.masks.clone();
+        let mask_key = self.mask_key.clone();
 
         // translate output columns to input columns
         let mut in_cols = Cow::Borrowed(columns);
@@ -155,6 +241,10 @@ impl Ingredient for Project {
                             outi <= emit.len(),
                             "should never be queried for generated columns"
                         );
+                        assert!(
+                            !self.is_masked(outi),
+                            "should never be queried for masked columns"
+                        );
                         emit[outi]
                     })
                     .collect(),
@@ -186,6 +276,10 @@ impl Ingredient for Project {
                                 new_r.append(&mut a.clone());
                             }
 
+                            if let Some(ref m) = masks {
+                                apply_masks(m, &mask_key, &mut new_r);
+                            }
+
                             Cow::from(new_r)
                         })) as Box<_>,
                         None => Box::new(rs) as Box<_>,
@@ -209,8 +303,10 @@ impl Ingredient for Project {
         // the inputs, so we don't needlessly perform extra work on each
         // update.
         self.emit = self.emit.take().and_then(|emit| {
-            let complete =
-                emit.len() == self.cols && self.additional.is_none() && self.expressions.is_none();
+            let complete = emit.len() == self.cols
+                && self.additional.is_none()
+                && self.expressions.is_none()
+                && self.masks.is_none();
             let sequential = emit.iter().enumerate().all(|(i, &j)| i == j);
             if complete && sequential {
                 None
@@ -246,6 +342,10 @@ impl Ingredient for Project {
                     new_r.append(&mut a.clone());
                 }
 
+                if let Some(ref m) = self.masks {
+                    apply_masks(m, &self.mask_key, &mut new_r);
+                }
+
                 **r = new_r;
             }
         }
@@ -261,6 +361,10 @@ impl Ingredient for Project {
     }
 
     fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        // masked values can't be looked up in the parent
+        if self.is_masked(col) {
+            return None;
+        }
         Some(vec![(self.src.as_global(), self.resolve_col(col))])
     }
 
@@ -291,6 +395,15 @@ impl Ingredient for Project {
                             .collect::<Vec<_>>(),
                     );
                 }
+
+                if let Some(ref masks) = self.masks {
+                    emit_cols.extend(
+                        masks
+                            .iter()
+                            .map(|&(i, ref m)| format!("mask {}: {}", i, m))
+                            .collect::<Vec<_>>(),
+                    );
+                }
             }
         };
         format!("π[{}]", emit_cols.join(", "))
@@ -299,6 +412,8 @@ impl Ingredient for Project {
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         let result = if self.emit.is_some() && column >= self.emit.as_ref().unwrap().len() {
             None
+        } else if self.is_masked(column) {
+            None
         } else {
             Some(self.resolve_col(column))
         };
@@ -430,6 +545,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_masks_columns() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y", "z", "w"]);
+        g.set_op(
+            "mask",
+            &["x", "y", "z", "w"],
+            Project::new(s.as_global(), &[0, 1, 2, 3], None, None).with_masks(
+                vec![
+                    (0, ColumnMask::Null),
+                    (1, ColumnMask::KeepLast(4)),
+                    (2, ColumnMask::KeepFirst(3)),
+                    (3, ColumnMask::Hash),
+                ],
+                b"k1",
+            ),
+            false,
+        );
+        assert_eq!(
+            g.node().description(true),
+            "π[0, 1, 2, 3, mask 0: null, mask 1: last 4, mask 2: first 3, mask 3: hash]"
+        );
+
+        let rec = vec![
+            "secret".into(),
+            "123-45-6789".into(),
+            123_456.into(),
+            "x".into(),
+        ];
+        let out = g.narrow_one_row(rec, false);
+        let row = match out[0] {
+            Record::Positive(ref r) => r.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(row[0], DataType::None);
+        assert_eq!(row[1], "6789".into());
+        assert_eq!(row[2], "123".into());
+        assert_ne!(row[3], "x".into());
+
+        // hashing is deterministic for a given key, and leaves NULLs alone
+        assert_eq!(ColumnMask::Hash.apply(&"x".into(), b"k1"), row[3]);
+        assert_ne!(ColumnMask::Hash.apply(&"x".into(), b"k2"), row[3]);
+        let hashed: &str = (&row[3]).into();
+        assert_eq!(hashed.len(), 64);
+        assert_eq!(
+            ColumnMask::Hash.apply(&DataType::None, b"k1"),
+            DataType::None
+        );
+        assert_eq!(
+            ColumnMask::KeepLast(4).apply(&"12".into(), b"k1"),
+            "12".into()
+        );
+
+        // masked columns don't resolve to the parent's
+        assert_eq!(g.node().resolve(0), None);
+        assert_eq!(g.node().parent_columns(1), vec![(s.as_global(), None)]);
+    }
+
     #[test]
     fn it_forwards_addition_arithmetic() {
         let mut p = setup_column_arithmetic(ArithmeticOperator::Add);
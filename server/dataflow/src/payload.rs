@@ -2,7 +2,10 @@ use petgraph;
 use serde::{Deserialize, Serialize};
 
 use crate::domain;
+use crate::node::special::BaseForward;
 use crate::prelude::*;
+use crate::tracing::TraceContext;
+use nom_sql::SqlType;
 use noria;
 use noria::internal::LocalOrNot;
 
@@ -42,7 +45,11 @@ pub enum TriggerEndpoint {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum InitialState {
-    PartialLocal(Vec<(Vec<usize>, Vec<Tag>)>),
+    PartialLocal {
+        index: Vec<(Vec<usize>, Vec<Tag>)>,
+        /// Keep the state on disk (in RocksDB) rather than in memory.
+        persistent: bool,
+    },
     IndexedLocal(HashSet<Vec<usize>>),
     PartialGlobal {
         gid: petgraph::graph::NodeIndex,
@@ -86,12 +93,14 @@ pub enum Packet {
         inner: LocalOrNot<Input>,
         src: Option<SourceChannelIdentifier>,
         senders: Vec<SourceChannelIdentifier>,
+        trace: Option<TraceContext>,
     },
 
     /// Regular data-flow update.
     Message {
         link: Link,
         data: Records,
+        trace: Option<TraceContext>,
     },
 
     /// Update that is part of a tagged data-flow replay path.
@@ -147,6 +156,42 @@ pub enum Packet {
         column: usize,
     },
 
+    /// Changes the type of an existing column of a `Base` node, and converts its rows.
+    ModifyBaseColumn {
+        node: LocalNodeIndex,
+        column: usize,
+        default: DataType,
+        ty: SqlType,
+    },
+
+    /// Sets the rows a universe may write through a `Base` node, and the token its writes must
+    /// carry. Each inner list is a conjunction of column conditions; a row is admitted if any of
+    /// them holds. `None` revokes the universe's right to write.
+    SetWritePolicies {
+        node: LocalNodeIndex,
+        universe: DataType,
+        policies: Option<(
+            String,
+            Vec<Vec<(usize, crate::ops::filter::FilterCondition)>>,
+        )>,
+    },
+
+    /// Only accept writes to a `Base` node that are made on behalf of a universe.
+    RestrictWrites {
+        node: LocalNodeIndex,
+    },
+
+    /// Stop a `Base` node from passing writes on to a base that its table is no longer moving to.
+    StopForwardingBase {
+        node: LocalNodeIndex,
+    },
+
+    /// Have a `Base` node that passes its writes on to its replacement drop its rows, and only
+    /// pass writes on from now on.
+    RetireBase {
+        node: LocalNodeIndex,
+    },
+
     /// Update Egress node.
     UpdateEgress {
         node: LocalNodeIndex,
@@ -154,6 +199,12 @@ pub enum Packet {
         new_tag: Option<(Tag, NodeIndex)>,
     },
 
+    /// Stop an Egress node from sending to a removed node in another domain.
+    RemoveEgressTx {
+        node: LocalNodeIndex,
+        to: NodeIndex,
+    },
+
     /// Add a shard to a Sharder node.
     ///
     /// Note that this *must* be done *before* the sharder starts being used!
@@ -224,8 +275,48 @@ pub enum Packet {
     /// Argument specifies if we wish to get the full state size or just the partial nodes.
     GetStatistics,
 
+    /// Request that a domain send the partial replays that most recently filled holes in it on
+    /// the control reply channel.
+    GetReplayLog,
+
     /// Ask domain to log its state size
     UpdateStateSize,
+
+    /// Ask domain to ship its nodes and state to the controller so that it can be started on
+    /// another worker. The domain holds on to all packets it receives from then on until it is
+    /// told where its replacement lives.
+    Relocate,
+
+    /// Ask domain to send the rows of a `Base` node, in batches, to the shards of the base that
+    /// its table is moving to, and to pass every write it takes from then on to that base too.
+    /// Each shard of the new base is sent a `BaseCopied` once the rows have all been sent.
+    ForwardBase {
+        node: LocalNodeIndex,
+        to: BaseForward,
+    },
+
+    /// Tell a shard of the base that a table is moving to that one shard of the old base has sent
+    /// it all its rows. The domain acknowledges this once the rows are in the new base.
+    BaseCopied {
+        node: LocalNodeIndex,
+    },
+
+    /// Tell a relocated domain that its replacement is up, and that all held and future packets
+    /// should be passed on to it.
+    Handoff,
+
+    /// The last packet a domain sends on a connection to a relocated domain once it has learned
+    /// where the replacement lives. Whatever it sends to the replacement directly is held back
+    /// until this has been passed on to it.
+    Rerouting {
+        from: ReplicaAddr,
+    },
+
+    /// The first packet a domain sends on a connection to the replacement of a relocated domain
+    /// that it used to send to.
+    Rerouted {
+        from: ReplicaAddr,
+    },
 }
 
 impl Packet {
@@ -267,6 +358,16 @@ impl Packet {
         }
     }
 
+    /// The number of records or operations the packet carries.
+    pub(crate) fn len(&self) -> usize {
+        match *self {
+            Packet::Input { ref inner, .. } => unsafe { inner.deref() }.data.len(),
+            Packet::Message { ref data, .. } => data.len(),
+            Packet::ReplayPiece { ref data, .. } => data.len(),
+            _ => unreachable!(),
+        }
+    }
+
     pub(crate) fn map_data<F>(&mut self, map: F)
     where
         F: FnOnce(&mut Records),
@@ -288,6 +389,22 @@ impl Packet {
         }
     }
 
+    pub(crate) fn trace_mut(&mut self) -> Option<&mut TraceContext> {
+        match *self {
+            Packet::Input { ref mut trace, .. } | Packet::Message { ref mut trace, .. } => {
+                trace.as_mut()
+            }
+            _ => None,
+        }
+    }
+
+    /// Note that this packet, if it is part of a traced write, is being sent to another domain.
+    pub(crate) fn trace_sent(&mut self) {
+        if let Some(trace) = self.trace_mut() {
+            trace.since = std::time::SystemTime::now();
+        }
+    }
+
     pub(crate) fn tag(&self) -> Option<Tag> {
         match *self {
             Packet::ReplayPiece { tag, .. } => Some(tag),
@@ -308,9 +425,14 @@ impl Packet {
 
     pub(crate) fn clone_data(&self) -> Self {
         match *self {
-            Packet::Message { link, ref data } => Packet::Message {
+            Packet::Message {
+                link,
+                ref data,
+                trace,
+            } => Packet::Message {
                 link,
                 data: data.clone(),
+                trace,
             },
             Packet::ReplayPiece {
                 link,
@@ -368,7 +490,12 @@ pub enum ControlReplyPacket {
         noria::debug::stats::DomainStats,
         HashMap<petgraph::graph::NodeIndex, noria::debug::stats::NodeStats>,
     ),
+    ReplayLog(Vec<noria::debug::replays::ReplayLogEntry>),
     Booted(usize, SocketAddr),
+    /// The state of a relocated domain, or `None` if it was too busy to be moved.
+    Snapshot(Option<Box<domain::DomainBuilder>>),
+    /// A request the domain could not carry out, and why.
+    Failed(String),
 }
 
 impl ControlReplyPacket {
@@ -35,13 +35,19 @@ pub use common::*;
 pub use noria::internal::*;
 pub use petgraph::graph::NodeIndex;
 pub type Graph = petgraph::Graph<Node, Edge>;
+pub use crate::state::KeyRange;
 pub use crate::DurabilityMode;
 pub use crate::PersistenceParameters;
+pub use crate::PersistentTableFormat;
 
 /// Channel coordinator type specialized for domains
 pub type ChannelCoordinator = noria::channel::ChannelCoordinator<(DomainIndex, usize), Box<Packet>>;
 pub trait Executor {
     fn ack(&mut self, tag: SourceChannelIdentifier);
+    fn reject(&mut self, tag: SourceChannelIdentifier, reason: String);
     fn create_universe(&mut self, req: HashMap<String, DataType>);
     fn send(&mut self, dest: ReplicaAddr, m: Box<Packet>);
+    /// Pass a packet on to the replacement of this relocated domain. Writes are acknowledged once
+    /// the replacement has applied them.
+    fn forward(&mut self, m: Box<Packet>);
 }
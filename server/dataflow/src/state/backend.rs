@@ -0,0 +1,443 @@
+//! Pluggable storage for base tables.
+//!
+//! By default, base tables are stored in a `MemoryState` or a RocksDB-backed `PersistentState`
+//! depending on the configured `DurabilityMode`. A table can instead name a storage backend
+//! explicitly (e.g., `CREATE TABLE ... WITH (storage = 'rocksdb')`). Apart from the built-in
+//! `memory` and `rocksdb` backends, any type implementing `StorageBackend` can be registered under
+//! a name of its choosing, and is then used for every table that asks for it.
+
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(any(test, feature = "test-support"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::eviction::EvictionPolicy;
+use crate::prelude::*;
+use common::SizeOf;
+use noria::debug::stats::CacheStats;
+
+/// Name of the built-in backend that keeps base tables in memory.
+pub const MEMORY_BACKEND: &str = "memory";
+/// Name of the built-in backend that keeps base tables in RocksDB.
+pub const ROCKSDB_BACKEND: &str = "rocksdb";
+
+/// The state of a single shard of a base table.
+///
+/// Base tables are always fully materialized, so unlike the domain-internal state
+/// implementations, a `BaseStorage` never has to deal with holes, replays, or eviction.
+pub trait BaseStorage: SizeOf + Send {
+    /// Add an index keyed by the given columns. Any rows already stored must be indexed.
+    fn add_key(&mut self, columns: &[usize]);
+
+    /// Returns the columns of each index, in the order they were added.
+    fn keys(&self) -> Vec<Vec<usize>>;
+
+    /// Returns whether the data is kept outside of the process' memory. Persistent storage does
+    /// not count towards a worker's memory use.
+    fn is_persistent(&self) -> bool;
+
+    /// Insert each positive record and remove each negative record.
+    fn process_records(&mut self, records: &Records);
+
+    /// Return all rows whose values in `columns` equal `key`. `columns` is always an index
+    /// previously added with `add_key`.
+    fn lookup(&self, columns: &[usize], key: &[DataType]) -> Vec<Vec<DataType>>;
+
+    /// Return all rows whose values in `columns` fall within `range`.
+    ///
+    /// The default implementation scans every row.
+    fn lookup_range(&self, columns: &[usize], range: &KeyRange) -> Vec<Vec<DataType>> {
+        use std::ops::RangeBounds;
+        self.cloned_records()
+            .into_iter()
+            .filter(|row| {
+                let key: Vec<_> = columns.iter().map(|&c| row[c].clone()).collect();
+                range.contains(&key)
+            })
+            .collect()
+    }
+
+    /// Returns the number of rows stored.
+    fn rows(&self) -> usize;
+
+    /// Return a copy of all rows.
+    fn cloned_records(&self) -> Vec<Vec<DataType>>;
+
+    /// Remove all rows.
+    fn clear(&mut self);
+
+    /// Delete all data kept outside of memory, because the table has been dropped. The storage is
+    /// not used again afterwards.
+    ///
+    /// The default implementation does nothing.
+    fn destroy(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A factory for base table storage.
+pub trait StorageBackend: Send + Sync {
+    /// Open the storage for one shard of a base table.
+    ///
+    /// `name` is unique to the table shard and stable across restarts, and can be used to locate
+    /// on-disk data. `primary_key` is the table's primary key, if it has one.
+    fn open(
+        &self,
+        name: &str,
+        primary_key: Option<&[usize]>,
+        params: &PersistenceParameters,
+    ) -> Box<dyn BaseStorage>;
+}
+
+/// The set of storage backends available to base tables on a worker.
+#[derive(Clone, Default)]
+pub struct StorageBackends {
+    backends: HashMap<String, Arc<dyn StorageBackend>>,
+}
+
+impl fmt::Debug for StorageBackends {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+impl StorageBackends {
+    /// Make `backend` available to tables that ask for storage `name`.
+    ///
+    /// Panics if `name` is already taken, including by one of the built-in backends.
+    pub fn register<S, B>(&mut self, name: S, backend: B)
+    where
+        S: Into<String>,
+        B: StorageBackend + 'static,
+    {
+        let name = name.into();
+        assert!(
+            !self.contains(&name),
+            "storage backend {} is already registered",
+            name
+        );
+        self.backends.insert(name, Arc::new(backend));
+    }
+
+    /// Returns whether there is a backend registered under `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        name == MEMORY_BACKEND || name == ROCKSDB_BACKEND || self.backends.contains_key(name)
+    }
+
+    /// Returns the names of all available backends.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec![MEMORY_BACKEND, ROCKSDB_BACKEND];
+        names.extend(self.backends.keys().map(String::as_str));
+        names
+    }
+
+    /// Create the state for a shard of a base table.
+    ///
+    /// If the table doesn't name a backend, the storage is chosen by the durability mode in
+    /// `params`, as it always has been. Fails if the table names a backend that isn't registered.
+    pub(crate) fn open(
+        &self,
+        storage: Option<&str>,
+        name: String,
+        primary_key: Option<&[usize]>,
+        params: &PersistenceParameters,
+    ) -> Result<Box<dyn State>, String> {
+        let storage = storage.unwrap_or_else(|| match params.mode {
+            DurabilityMode::MemoryOnly => MEMORY_BACKEND,
+            DurabilityMode::DeleteOnExit | DurabilityMode::Permanent => ROCKSDB_BACKEND,
+        });
+
+        Ok(match storage {
+            MEMORY_BACKEND => Box::new(MemoryState::default()),
+            ROCKSDB_BACKEND => Box::new(PersistentState::new(name, primary_key, params)),
+            other => match self.backends.get(other) {
+                Some(backend) => Box::new(BackendState(backend.open(&name, primary_key, params))),
+                None => return Err(format!("no storage backend named {} on this worker", other)),
+            },
+        })
+    }
+}
+
+/// Adapts a `BaseStorage` to the `State` interface used inside domains.
+struct BackendState(Box<dyn BaseStorage>);
+
+impl SizeOf for BackendState {
+    fn size_of(&self) -> u64 {
+        self.0.size_of()
+    }
+
+    fn deep_size_of(&self) -> u64 {
+        self.0.deep_size_of()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl State for BackendState {
+    fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
+        assert!(partial.is_none(), "base storage can't be partial");
+        self.0.add_key(columns);
+    }
+
+    fn is_useful(&self) -> bool {
+        !self.0.keys().is_empty()
+    }
+
+    fn is_partial(&self) -> bool {
+        false
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.0.is_persistent()
+    }
+
+    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
+        assert!(partial_tag.is_none(), "base storage can't be partial");
+        self.0.process_records(records);
+    }
+
+    fn mark_hole(&mut self, _: &[DataType], _: Tag) {
+        unreachable!("base storage can't be partial");
+    }
+
+    fn mark_filled(&mut self, _: Vec<DataType>, _: Tag) {
+        unreachable!("base storage can't be partial");
+    }
+
+    fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a> {
+        LookupResult::Some(RecordResult::Owned(self.0.lookup(columns, &key.to_vec())))
+    }
+
+    fn lookup_range<'a>(&'a self, columns: &[usize], range: &KeyRange) -> LookupResult<'a> {
+        LookupResult::Some(RecordResult::Owned(self.0.lookup_range(columns, range)))
+    }
+
+    fn rows(&self) -> usize {
+        self.0.rows()
+    }
+
+    fn keys(&self) -> Vec<Vec<usize>> {
+        self.0.keys()
+    }
+
+    fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        self.0.cloned_records()
+    }
+
+    fn evict_cold_keys(
+        &mut self,
+        _: usize,
+        _: &dyn EvictionPolicy,
+    ) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        unreachable!("base storage is never evicted from");
+    }
+
+    fn evict_keys(&mut self, _: Tag, _: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
+        unreachable!("base storage is never evicted from");
+    }
+
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    fn destroy(&mut self) -> Result<(), String> {
+        self.0.destroy()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+}
+
+/// An append-only log of writes, as one might keep for audit tables.
+///
+/// Only here so that tests of custom storage backends, in this crate and others, share one.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Default)]
+pub struct LogStorage {
+    keys: Vec<Vec<usize>>,
+    log: Vec<Record>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl SizeOf for LogStorage {
+    fn size_of(&self) -> u64 {
+        std::mem::size_of::<Self>() as u64
+    }
+
+    fn deep_size_of(&self) -> u64 {
+        self.log.iter().map(|r| r.rec().deep_size_of()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl BaseStorage for LogStorage {
+    fn add_key(&mut self, columns: &[usize]) {
+        self.keys.push(Vec::from(columns));
+    }
+
+    fn keys(&self) -> Vec<Vec<usize>> {
+        self.keys.clone()
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    fn process_records(&mut self, records: &Records) {
+        self.log.extend(records.iter().cloned());
+    }
+
+    fn lookup(&self, columns: &[usize], key: &[DataType]) -> Vec<Vec<DataType>> {
+        self.cloned_records()
+            .into_iter()
+            .filter(|row| columns.iter().zip(key).all(|(&c, k)| row[c] == *k))
+            .collect()
+    }
+
+    fn rows(&self) -> usize {
+        self.cloned_records().len()
+    }
+
+    fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        let mut rows: Vec<Vec<DataType>> = Vec::new();
+        for r in &self.log {
+            match *r {
+                Record::Positive(ref row) => rows.push(row.clone()),
+                Record::Negative(ref row) => {
+                    if let Some(i) = rows.iter().position(|r| r == row) {
+                        rows.swap_remove(i);
+                    }
+                }
+            }
+        }
+        rows
+    }
+
+    fn clear(&mut self) {
+        self.log.clear();
+    }
+}
+
+/// Opens a `LogStorage` for every table, and counts how many it has opened.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Default)]
+pub struct LogBackend(pub Arc<AtomicUsize>);
+
+#[cfg(any(test, feature = "test-support"))]
+impl StorageBackend for LogBackend {
+    fn open(
+        &self,
+        _: &str,
+        _: Option<&[usize]>,
+        _: &PersistenceParameters,
+    ) -> Box<dyn BaseStorage> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Box::new(LogStorage::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends() -> StorageBackends {
+        let mut backends = StorageBackends::default();
+        backends.register("log", LogBackend::default());
+        backends
+    }
+
+    #[test]
+    fn storage_backends_names() {
+        let backends = backends();
+        assert!(backends.contains(MEMORY_BACKEND));
+        assert!(backends.contains(ROCKSDB_BACKEND));
+        assert!(backends.contains("log"));
+        assert!(!backends.contains("sled"));
+        assert_eq!(backends.names().len(), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn storage_backends_no_shadowing() {
+        let mut backends = StorageBackends::default();
+        backends.register(MEMORY_BACKEND, LogBackend::default());
+    }
+
+    #[test]
+    fn storage_backends_default_by_durability() {
+        let state = StorageBackends::default()
+            .open(
+                None,
+                String::from("storage_backends_default"),
+                None,
+                &PersistenceParameters::default(),
+            )
+            .unwrap();
+        assert!(!state.is_persistent());
+    }
+
+    #[test]
+    fn storage_backends_unknown() {
+        assert!(backends()
+            .open(
+                Some("sled"),
+                String::from("storage_backends_unknown"),
+                None,
+                &PersistenceParameters::default(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn storage_backends_custom() {
+        let mut state = backends()
+            .open(
+                Some("log"),
+                String::from("storage_backends_custom"),
+                Some(&[0]),
+                &PersistenceParameters::default(),
+            )
+            .unwrap();
+        state.add_key(&[0], None);
+        assert!(state.is_useful());
+
+        let mut records: Records = vec![
+            vec![1.into(), "a".into()],
+            vec![2.into(), "b".into()],
+            vec![3.into(), "c".into()],
+        ]
+        .into();
+        state.process_records(&mut records, None);
+        state.process_records(&mut vec![(vec![2.into(), "b".into()], false)].into(), None);
+        assert_eq!(state.rows(), 2);
+
+        let one = 1.into();
+        match state.lookup(&[0], &KeyType::Single(&one)) {
+            LookupResult::Some(rows) => {
+                let rows: Vec<_> = rows.into_iter().collect();
+                assert_eq!(rows.len(), 1);
+                assert_eq!(&rows[0][..], &[1.into(), "a".into()]);
+            }
+            LookupResult::Missing => unreachable!(),
+        }
+
+        use std::ops::Bound;
+        let range = (Bound::Included(vec![2.into()]), Bound::Unbounded);
+        match state.lookup_range(&[0], &range) {
+            LookupResult::Some(rows) => {
+                let rows: Vec<_> = rows.into_iter().collect();
+                assert_eq!(rows.len(), 1);
+                assert_eq!(&rows[0][..], &[3.into(), "c".into()]);
+            }
+            LookupResult::Missing => unreachable!(),
+        }
+    }
+}
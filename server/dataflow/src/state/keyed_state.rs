@@ -3,6 +3,7 @@ use indexmap::IndexMap;
 use std::rc::Rc;
 
 use super::mk_key::MakeKey;
+use crate::eviction::key_hash;
 use crate::prelude::*;
 use common::SizeOf;
 
@@ -31,55 +32,106 @@ impl KeyedState {
         }
     }
 
-    /// Remove all rows for a randomly chosen key seeded by `seed`, returning that key along with
-    /// the number of bytes freed. Returns `None` if map is empty.
-    pub(super) fn evict_with_seed(&mut self, seed: usize) -> Option<(u64, Vec<DataType>)> {
+    pub(super) fn len(&self) -> usize {
+        match *self {
+            KeyedState::Single(ref m) => m.len(),
+            KeyedState::Double(ref m) => m.len(),
+            KeyedState::Tri(ref m) => m.len(),
+            KeyedState::Quad(ref m) => m.len(),
+            KeyedState::Quin(ref m) => m.len(),
+            KeyedState::Sex(ref m) => m.len(),
+        }
+    }
+
+    /// A copy of every key.
+    pub(super) fn cloned_keys(&self) -> Vec<Vec<DataType>> {
+        match *self {
+            KeyedState::Single(ref m) => m.keys().map(|k| vec![k.clone()]).collect(),
+            KeyedState::Double(ref m) => m.keys().map(|k| vec![k.0.clone(), k.1.clone()]).collect(),
+            KeyedState::Tri(ref m) => m
+                .keys()
+                .map(|k| vec![k.0.clone(), k.1.clone(), k.2.clone()])
+                .collect(),
+            KeyedState::Quad(ref m) => m
+                .keys()
+                .map(|k| vec![k.0.clone(), k.1.clone(), k.2.clone(), k.3.clone()])
+                .collect(),
+            KeyedState::Quin(ref m) => m
+                .keys()
+                .map(|k| {
+                    vec![
+                        k.0.clone(),
+                        k.1.clone(),
+                        k.2.clone(),
+                        k.3.clone(),
+                        k.4.clone(),
+                    ]
+                })
+                .collect(),
+            KeyedState::Sex(ref m) => m
+                .keys()
+                .map(|k| {
+                    vec![
+                        k.0.clone(),
+                        k.1.clone(),
+                        k.2.clone(),
+                        k.3.clone(),
+                        k.4.clone(),
+                        k.5.clone(),
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    /// The access tracking hash (see `eviction::key_hash`) of the key at `index`.
+    pub(super) fn key_hash_at(&self, index: usize) -> u64 {
+        match *self {
+            KeyedState::Single(ref m) => key_hash(m.get_index(index).unwrap().0),
+            KeyedState::Double(ref m) => key_hash(m.get_index(index).unwrap().0),
+            KeyedState::Tri(ref m) => key_hash(m.get_index(index).unwrap().0),
+            KeyedState::Quad(ref m) => key_hash(m.get_index(index).unwrap().0),
+            KeyedState::Quin(ref m) => key_hash(m.get_index(index).unwrap().0),
+            KeyedState::Sex(ref m) => key_hash(m.get_index(index).unwrap().0),
+        }
+    }
+
+    /// Remove all rows for the key at `index`, returning that key along with the number of bytes
+    /// freed and of rows removed. Returns `None` if there is no such key.
+    pub(super) fn evict_at(&mut self, index: usize) -> Option<(u64, usize, Vec<DataType>)> {
         let (rs, key) = match *self {
-            KeyedState::Single(ref mut m) if !m.is_empty() => {
-                let index = seed % m.len();
+            KeyedState::Single(ref mut m) => {
                 m.swap_remove_index(index).map(|(k, rs)| (rs, vec![k]))
             }
-            KeyedState::Double(ref mut m) if !m.is_empty() => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1]))
-            }
-            KeyedState::Tri(ref mut m) if !m.is_empty() => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2]))
-            }
-            KeyedState::Quad(ref mut m) if !m.is_empty() => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3]))
-            }
-            KeyedState::Quin(ref mut m) if !m.is_empty() => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4]))
-            }
-            KeyedState::Sex(ref mut m) if !m.is_empty() => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4, k.5]))
-            }
-            _ => {
-                // map must be empty, so no point in trying to evict from it.
-                return None;
-            }
+            KeyedState::Double(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1])),
+            KeyedState::Tri(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2])),
+            KeyedState::Quad(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3])),
+            KeyedState::Quin(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4])),
+            KeyedState::Sex(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4, k.5])),
         }?;
         Some((
             rs.iter()
                 .filter(|r| Rc::strong_count(&r.0) == 1)
                 .map(SizeOf::deep_size_of)
                 .sum(),
+            rs.len(),
             key,
         ))
     }
 
-    /// Remove all rows for the given key, returning the number of bytes freed.
-    pub(super) fn evict(&mut self, key: &[DataType]) -> u64 {
+    /// Remove all rows for the given key, returning the number of bytes freed and of rows removed.
+    /// Returns `None` if there is no such key.
+    pub(super) fn evict(&mut self, key: &[DataType]) -> Option<(u64, usize)> {
         match *self {
             KeyedState::Single(ref mut m) => m.swap_remove(&(key[0])),
             KeyedState::Double(ref mut m) => {
@@ -99,12 +151,14 @@ impl KeyedState {
             }
         }
         .map(|rows| {
-            rows.iter()
-                .filter(|r| Rc::strong_count(&r.0) == 1)
-                .map(SizeOf::deep_size_of)
-                .sum()
+            (
+                rows.iter()
+                    .filter(|r| Rc::strong_count(&r.0) == 1)
+                    .map(SizeOf::deep_size_of)
+                    .sum(),
+                rows.len(),
+            )
         })
-        .unwrap_or(0)
     }
 }
 
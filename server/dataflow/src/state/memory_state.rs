@@ -3,9 +3,11 @@ use std::rc::Rc;
 
 use rand::{self, Rng};
 
+use crate::eviction::EvictionPolicy;
 use crate::prelude::*;
 use crate::state::single_state::SingleState;
 use common::SizeOf;
+use noria::debug::stats::{CacheStats, IndexStats};
 
 #[derive(Default)]
 pub struct MemoryState {
@@ -76,6 +78,10 @@ impl State for MemoryState {
         self.state.iter().any(SingleState::partial)
     }
 
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
     fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
         if self.is_partial() {
             records.retain(|r| {
@@ -139,6 +145,14 @@ impl State for MemoryState {
         self.state[index].lookup(key)
     }
 
+    fn lookup_range<'a>(&'a self, columns: &[usize], range: &KeyRange) -> LookupResult<'a> {
+        debug_assert!(!self.state.is_empty(), "lookup on uninitialized index");
+        let index = self
+            .state_for(columns)
+            .expect("lookup on non-indexed column set");
+        self.state[index].lookup_range(range)
+    }
+
     fn keys(&self) -> Vec<Vec<usize>> {
         self.state.iter().map(|s| s.key().to_vec()).collect()
     }
@@ -153,10 +167,19 @@ impl State for MemoryState {
         self.state[0].values().flat_map(fix).collect()
     }
 
-    fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+    fn cloned_keys(&self) -> Vec<Vec<DataType>> {
+        assert!(!self.state[0].partial());
+        self.state[0].cloned_keys()
+    }
+
+    fn evict_cold_keys(
+        &mut self,
+        count: usize,
+        policy: &dyn EvictionPolicy,
+    ) -> (&[usize], Vec<Vec<DataType>>, u64) {
         let mut rng = rand::thread_rng();
         let index = rng.gen_range(0, self.state.len());
-        let (bytes_freed, keys) = self.state[index].evict_random_keys(count, &mut rng);
+        let (bytes_freed, keys) = self.state[index].evict_cold_keys(count, policy, &mut rng);
         self.mem_size = self.mem_size.saturating_sub(bytes_freed);
         (self.state[index].key(), keys, bytes_freed)
     }
@@ -178,6 +201,17 @@ impl State for MemoryState {
         }
         self.mem_size = 0;
     }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.state
+            .iter()
+            .map(SingleState::cache_stats)
+            .fold(CacheStats::default(), |a, b| a + b)
+    }
+
+    fn index_stats(&self) -> Vec<IndexStats> {
+        self.state.iter().map(SingleState::index_stats).collect()
+    }
 }
 
 impl MemoryState {
@@ -284,4 +318,139 @@ mod tests {
             _ => unreachable!(),
         };
     }
+
+    #[test]
+    fn memory_state_index_stats() {
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        state.add_key(&[1], None);
+        insert(&mut state, vec![1.into(), "Cat".into()]);
+        insert(&mut state, vec![2.into(), "Cat".into()]);
+        insert(&mut state, vec![3.into(), "Dog".into()]);
+
+        let stats = state.index_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].columns, vec![0]);
+        assert_eq!(stats[0].rows, Some(3));
+        assert_eq!(stats[0].distinct_keys, Some(3));
+        assert_eq!(stats[0].max_rows_per_key, Some(1));
+        assert_eq!(stats[1].columns, vec![1]);
+        assert_eq!(stats[1].rows, Some(3));
+        assert_eq!(stats[1].distinct_keys, Some(2));
+        assert_eq!(stats[1].max_rows_per_key, Some(2));
+
+        // the largest key shrinks as rows are removed
+        let mut records: Records = vec![(vec![2.into(), "Cat".into()], false)].into();
+        state.process_records(&mut records, None);
+        let stats = state.index_stats();
+        assert_eq!(stats[1].rows, Some(2));
+        assert_eq!(stats[1].max_rows_per_key, Some(1));
+
+        state.clear();
+        assert_eq!(state.index_stats()[1].max_rows_per_key, Some(0));
+    }
+
+    #[test]
+    fn memory_state_cloned_keys() {
+        let mut state = MemoryState::default();
+        state.add_key(&[1], None);
+        state.add_key(&[0], None);
+        insert(&mut state, vec![1.into(), "Cat".into()]);
+        insert(&mut state, vec![2.into(), "Cat".into()]);
+        insert(&mut state, vec![3.into(), "Dog".into()]);
+
+        // keys come from the first index
+        let mut keys = state.cloned_keys();
+        keys.sort();
+        assert_eq!(keys, vec![vec!["Cat".into()], vec!["Dog".into()]]);
+    }
+
+    #[test]
+    fn memory_state_lookup_range() {
+        use std::ops::Bound;
+
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        for i in 0..10 {
+            insert(&mut state, vec![i.into(), "Cat".into()]);
+        }
+
+        let range = (
+            Bound::Included(vec![3.into()]),
+            Bound::Excluded(vec![6.into()]),
+        );
+        match state.lookup_range(&[0], &range) {
+            LookupResult::Some(RecordResult::Owned(mut rows)) => {
+                rows.sort();
+                let keys: Vec<DataType> = rows.into_iter().map(|r| r[0].clone()).collect();
+                assert_eq!(keys, vec![3.into(), 4.into(), 5.into()]);
+            }
+            _ => unreachable!(),
+        };
+
+        let range = (Bound::Excluded(vec![8.into()]), Bound::Unbounded);
+        match state.lookup_range(&[0], &range) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows, vec![vec![9.into(), "Cat".into()]]);
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn memory_state_partial_lookup_range() {
+        use std::ops::Bound;
+
+        let mut state = MemoryState::default();
+        let tag = Tag::new(1);
+        state.add_key(&[0], Some(vec![tag]));
+        state.mark_filled(vec![1.into()], tag);
+
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        match state.lookup_range(&[0], &range) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn memory_state_evicts_cold_keys() {
+        use crate::eviction::SampledLru;
+
+        let mut state = MemoryState::default();
+        let tag = Tag::new(1);
+        state.add_key(&[0], Some(vec![tag]));
+        for i in 0..100 {
+            state.mark_filled(vec![i.into()], tag);
+            state.process_records(&mut vec![vec![i.into(), "Cat".into()]].into(), Some(tag));
+        }
+
+        // read the first half of the keys, and miss on one that isn't there
+        let hot: Vec<DataType> = (0..50).map(DataType::from).collect();
+        for key in &hot {
+            match state.lookup(&[0], &KeyType::Single(key)) {
+                LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(rows.len(), 1),
+                _ => unreachable!(),
+            }
+        }
+        match state.lookup(&[0], &KeyType::Single(&100.into())) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        }
+
+        let (columns, keys, bytes) = state.evict_cold_keys(10, &SampledLru::new(100));
+        assert_eq!(columns, &[0]);
+        assert_eq!(keys.len(), 10);
+        assert!(bytes > 0);
+        for key in keys {
+            assert!(
+                !hot.contains(&key[0]),
+                "evicted recently used key {:?}",
+                key
+            );
+        }
+
+        let stats = state.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (50, 1, 10));
+    }
 }
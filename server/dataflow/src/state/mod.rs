@@ -1,22 +1,31 @@
+pub(crate) mod backend;
 mod keyed_state;
 mod memory_state;
 mod mk_key;
+mod ordered_key;
 mod persistent_state;
 mod single_state;
 
 use std::borrow::Cow;
-use std::ops::Deref;
+use std::ops::{Bound, Deref};
 use std::rc::Rc;
 use std::vec;
 
+use crate::eviction::EvictionPolicy;
 use crate::prelude::*;
 use ahash::RandomState;
 use common::SizeOf;
 use hashbag::HashBag;
+use noria::debug::stats::{CacheStats, IndexStats};
 
+pub use self::backend::{BaseStorage, StorageBackend, StorageBackends};
 pub(crate) use self::memory_state::MemoryState;
 pub(crate) use self::persistent_state::PersistentState;
 
+/// A range of index keys, as used by `State::lookup_range`. Both bounds are full keys of the
+/// index being scanned, and are compared lexicographically.
+pub type KeyRange = (Bound<Vec<DataType>>, Bound<Vec<DataType>>);
+
 pub(crate) trait State: SizeOf + Send {
     /// Add an index keyed by the given columns and replayed to by the given partial tags.
     fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>);
@@ -27,6 +36,10 @@ pub(crate) trait State: SizeOf + Send {
 
     fn is_partial(&self) -> bool;
 
+    /// Returns whether this state is kept on disk rather than in memory. Persistent state doesn't
+    /// count towards a domain's memory use, and isn't evicted from to stay within memory limits.
+    fn is_persistent(&self) -> bool;
+
     // Inserts or removes each record into State. Records that miss all indices in partial state
     // are removed from `records` (thus the mutable reference).
     fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>);
@@ -37,6 +50,11 @@ pub(crate) trait State: SizeOf + Send {
 
     fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a>;
 
+    /// Look up all rows whose values in `columns` fall within `range`. Partially materialized
+    /// state can't tell which keys in a range are holes, and so always returns
+    /// `LookupResult::Missing`.
+    fn lookup_range<'a>(&'a self, columns: &[usize], range: &KeyRange) -> LookupResult<'a>;
+
     fn rows(&self) -> usize;
 
     fn keys(&self) -> Vec<Vec<usize>>;
@@ -44,15 +62,55 @@ pub(crate) trait State: SizeOf + Send {
     /// Return a copy of all records. Panics if the state is only partially materialized.
     fn cloned_records(&self) -> Vec<Vec<DataType>>;
 
-    /// Evict `count` randomly selected keys, returning key colunms of the index chosen to evict
+    /// Return a copy of the distinct keys of the first index, so that all rows can be visited a
+    /// few keys at a time. Panics if the state is only partially materialized.
+    ///
+    /// The default implementation copies all records to find their keys.
+    fn cloned_keys(&self) -> Vec<Vec<DataType>> {
+        use itertools::Itertools;
+
+        let columns = &self.keys()[0];
+        self.cloned_records()
+            .into_iter()
+            .map(|row| columns.iter().map(|&c| row[c].clone()).collect())
+            .unique()
+            .collect()
+    }
+
+    /// Evict `count` keys chosen by `policy`, returning key colunms of the index chosen to evict
     /// from along with the keys evicted and the number of bytes evicted.
-    fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64);
+    fn evict_cold_keys(
+        &mut self,
+        count: usize,
+        policy: &dyn EvictionPolicy,
+    ) -> (&[usize], Vec<Vec<DataType>>, u64);
 
     /// Evict the listed keys from the materialization targeted by `tag`, returning the key columns
     /// of the index that was evicted from and the number of bytes evicted.
     fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)>;
 
     fn clear(&mut self);
+
+    /// Delete anything the state keeps outside of memory, such as files on disk, because its node
+    /// has been removed for good. The state is not used again afterwards.
+    fn destroy(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Hits, misses and evictions across all partially materialized indices.
+    fn cache_stats(&self) -> CacheStats;
+
+    /// The key distribution of each index. State that can't count its keys cheaply only reports
+    /// the indexed columns.
+    fn index_stats(&self) -> Vec<IndexStats> {
+        self.keys()
+            .into_iter()
+            .map(|columns| IndexStats {
+                columns,
+                ..IndexStats::default()
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -0,0 +1,171 @@
+//! An order-preserving (memcomparable) byte encoding of `DataType` keys.
+//!
+//! RocksDB compares keys bytewise, so for range scans over a persistent index to visit keys in
+//! the same order as `DataType`'s `Ord` implementation, we can't use bincode (which is
+//! little-endian and length-prefixed). Instead, each value is encoded as a one-byte type tag
+//! followed by a big-endian, sign-flipped representation of the value. Strings are
+//! null-escaped and terminated, which keeps the encoding of a whole key tuple prefix-free: no
+//! encoded key is a strict prefix of another key with the same number of columns.
+//!
+//! Values of different types are ranked as in `DataType::cmp` (integers > reals > text >
+//! timestamps), with `None` sorting first. All integer types share a tag, so that equal integers
+//! of different widths encode identically.
+
+use crate::prelude::*;
+
+const TAG_NONE: u8 = 0x01;
+const TAG_TIMESTAMP: u8 = 0x02;
+const TAG_TEXT: u8 = 0x03;
+const TAG_REAL: u8 = 0x04;
+const TAG_INT: u8 = 0x05;
+
+// Null bytes in text values are escaped as `0x00 0xFF`, and text values end with `0x00 0x01`.
+const TEXT_ESCAPE: u8 = 0xFF;
+const TEXT_TERMINATOR: u8 = 0x01;
+
+/// Append the order-preserving encoding of `value` to `out`.
+pub(super) fn encode_value(value: &DataType, out: &mut Vec<u8>) {
+    match *value {
+        DataType::None => out.push(TAG_NONE),
+        DataType::Int(..)
+        | DataType::UnsignedInt(..)
+        | DataType::BigInt(..)
+        | DataType::UnsignedBigInt(..) => {
+            let n: i128 = value.into();
+            out.push(TAG_INT);
+            out.extend_from_slice(&((n as u128) ^ (1 << 127)).to_be_bytes());
+        }
+        DataType::Real(i, f) => {
+            out.push(TAG_REAL);
+            out.extend_from_slice(&((i as u64) ^ (1 << 63)).to_be_bytes());
+            out.extend_from_slice(&((f as u32) ^ (1 << 31)).to_be_bytes());
+        }
+        DataType::Text(..) | DataType::TinyText(..) => {
+            let text: &str = value.into();
+            out.push(TAG_TEXT);
+            for &b in text.as_bytes() {
+                out.push(b);
+                if b == 0 {
+                    out.push(TEXT_ESCAPE);
+                }
+            }
+            out.push(0);
+            out.push(TEXT_TERMINATOR);
+        }
+        DataType::Timestamp(ts) => {
+            out.push(TAG_TIMESTAMP);
+            out.extend_from_slice(&((ts.timestamp() as u64) ^ (1 << 63)).to_be_bytes());
+            out.extend_from_slice(&ts.timestamp_subsec_nanos().to_be_bytes());
+        }
+    }
+}
+
+/// Encode a (possibly compound) key so that bytewise comparison of two encoded keys matches the
+/// lexicographic `DataType` ordering of the keys.
+pub(super) fn encode<'a, I>(key: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a DataType>,
+{
+    let mut out = Vec::new();
+    for value in key {
+        encode_value(value, &mut out);
+    }
+    out
+}
+
+/// Encode a `KeyType` with `encode`.
+pub(super) fn encode_key_type(key: &KeyType) -> Vec<u8> {
+    match *key {
+        KeyType::Single(k) => encode(Some(k)),
+        KeyType::Double((ref a, ref b)) => encode(vec![a, b]),
+        KeyType::Tri((ref a, ref b, ref c)) => encode(vec![a, b, c]),
+        KeyType::Quad((ref a, ref b, ref c, ref d)) => encode(vec![a, b, c, d]),
+        KeyType::Quin((ref a, ref b, ref c, ref d, ref e)) => encode(vec![a, b, c, d, e]),
+        KeyType::Sex((ref a, ref b, ref c, ref d, ref e, ref f)) => encode(vec![a, b, c, d, e, f]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_order_preserved(mut values: Vec<Vec<DataType>>) {
+        values.sort();
+        let encoded: Vec<_> = values.iter().map(|k| encode(k)).collect();
+        for (i, pair) in encoded.windows(2).enumerate() {
+            assert!(
+                pair[0] <= pair[1],
+                "{:?} encoded greater than {:?}",
+                values[i],
+                values[i + 1]
+            );
+        }
+    }
+
+    #[test]
+    fn ordered_key_integers() {
+        assert_order_preserved(vec![
+            vec![DataType::Int(-10)],
+            vec![DataType::BigInt(std::i64::MIN)],
+            vec![DataType::UnsignedBigInt(std::u64::MAX)],
+            vec![DataType::Int(0)],
+            vec![DataType::UnsignedInt(7)],
+            vec![DataType::BigInt(8)],
+            vec![DataType::Int(std::i32::MAX)],
+        ]);
+
+        // the integer types must encode equal values identically for lookups to work
+        assert_eq!(encode(&[DataType::Int(5)]), encode(&[DataType::BigInt(5)]));
+    }
+
+    #[test]
+    fn ordered_key_reals() {
+        assert_order_preserved(vec![
+            vec![DataType::Real(-1, -500_000_000)],
+            vec![DataType::Real(-1, 0)],
+            vec![DataType::Real(0, 0)],
+            vec![DataType::Real(0, 1)],
+            vec![DataType::Real(3, 141_592_653)],
+        ]);
+    }
+
+    #[test]
+    fn ordered_key_text() {
+        assert_order_preserved(vec![
+            vec!["".into()],
+            vec!["a".into()],
+            vec!["ab".into()],
+            vec!["b".into()],
+            vec!["a much longer text value that is not tiny".into()],
+        ]);
+    }
+
+    #[test]
+    fn ordered_key_compound() {
+        assert_order_preserved(vec![
+            vec!["a".into(), 2.into()],
+            vec!["a".into(), 10.into()],
+            vec!["ab".into(), 1.into()],
+            vec!["b".into(), (-1).into()],
+        ]);
+    }
+
+    #[test]
+    fn ordered_key_prefix_free() {
+        let short = encode(&[DataType::from("a"), DataType::from(1)]);
+        let long = encode(&[DataType::from("ab"), DataType::from(1)]);
+        assert!(!long.starts_with(&short));
+        assert!(!short.starts_with(&long));
+    }
+
+    #[test]
+    fn ordered_key_key_type() {
+        let a = DataType::from(1);
+        let b = DataType::from("b");
+        assert_eq!(encode_key_type(&KeyType::Single(&a)), encode(&[a.clone()]));
+        assert_eq!(
+            encode_key_type(&KeyType::Double((a.clone(), b.clone()))),
+            encode(&[a, b])
+        );
+    }
+}
@@ -1,12 +1,19 @@
+use ahash::RandomState;
 use bincode;
+use indexmap::IndexSet;
 use itertools::Itertools;
+use rand::{self, Rng};
 use rocksdb::{self, PlainTableFactoryOptions, SliceTransform, WriteBatch};
 use serde;
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
 use tempfile::{tempdir, TempDir};
 
+use crate::eviction::{key_hash, AccessTracker, EvictionPolicy};
 use crate::prelude::*;
-use crate::state::{RecordResult, State};
+use crate::state::{ordered_key, KeyRange, RecordResult, State};
 use common::SizeOf;
+use noria::debug::stats::CacheStats;
 
 // Incremented on each PersistentState initialization so that IndexSeq
 // can be used to create unique identifiers for rows.
@@ -32,10 +39,16 @@ struct PersistentMeta {
     epoch: IndexEpoch,
 }
 
-#[derive(Clone)]
+// The keys of a partially materialized index that are not holes.
+type FilledKeys = IndexSet<Vec<DataType>, RandomState>;
+
 struct PersistentIndex {
     column_family: String,
     columns: Vec<usize>,
+    // Only set for partially materialized indices.
+    filled: Option<FilledKeys>,
+    // Only set for partially materialized indices.
+    access: Option<AccessTracker>,
 }
 
 /// PersistentState stores data in RocksDB.
@@ -49,10 +62,19 @@ pub struct PersistentState {
     // Subsequent indices maintain pointers to the data in the first index, and cause an additional
     // read during lookups. When `self.has_unique_index` is true the first index is a primary key,
     // and all its keys are considered unique.
+    //
+    // Partially materialized indices are the exception: each of them may hold a different subset
+    // of the rows, so they all store their rows independently (keyed like a non-unique primary
+    // key), and there is no primary index.
     indices: Vec<PersistentIndex>,
+    // Maps partial replay tags to the index in `self.indices` that they fill.
+    by_tag: HashMap<Tag, usize>,
     seq: IndexSeq,
     epoch: IndexEpoch,
     has_unique_index: bool,
+    // Decides how keys are encoded (see Self::serialize_raw_key), and whether ordered iteration
+    // over an index is possible.
+    table_format: PersistentTableFormat,
     // With DurabilityMode::DeleteOnExit,
     // RocksDB files are stored in a temporary directory.
     _directory: Option<TempDir>,
@@ -60,11 +82,17 @@ pub struct PersistentState {
 
 impl State for PersistentState {
     fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
-        assert!(partial_tag.is_none(), "PersistentState can't be partial");
         if records.len() == 0 {
             return;
         }
 
+        if self.is_partial() {
+            self.process_partial_records(records, partial_tag);
+            return;
+        }
+
+        assert!(partial_tag.is_none(), "got tagged records for full state");
+
         let mut batch = WriteBatch::default();
         for r in records.iter() {
             match *r {
@@ -90,9 +118,21 @@ impl State for PersistentState {
             .iter()
             .position(|index| &index.columns[..] == columns)
             .expect("lookup on non-indexed column set");
+
+        let index = &self.indices[index_id];
+        if let (Some(filled), Some(access)) = (&index.filled, &index.access) {
+            let key = key.to_vec();
+            if !filled.contains(&key) {
+                // partially materialized, so this is a hole
+                access.miss();
+                return LookupResult::Missing;
+            }
+            access.hit(key_hash(&key[..]));
+        }
+
         tokio::task::block_in_place(|| {
             let cf = db.cf_handle(&self.indices[index_id].column_family).unwrap();
-            let prefix = Self::serialize_prefix(&key);
+            let prefix = self.serialize_prefix(&key);
             let data = if index_id == 0 && self.has_unique_index {
                 // This is a primary key, so we know there's only one row to retrieve
                 // (no need to use prefix_iterator).
@@ -105,7 +145,7 @@ impl State for PersistentState {
                 }
             } else {
                 // This could correspond to more than one value, so we'll use a prefix_iterator:
-                db.prefix_iterator_cf(cf, &prefix)
+                self.prefix_iterator(db, cf, &prefix)
                     .map(|(_key, value)| bincode::deserialize(&*value).unwrap())
                     .collect()
             };
@@ -114,28 +154,112 @@ impl State for PersistentState {
         })
     }
 
+    fn lookup_range(&self, columns: &[usize], range: &KeyRange) -> LookupResult {
+        let db = self.db.as_ref().unwrap();
+        let index_id = self
+            .indices
+            .iter()
+            .position(|index| &index.columns[..] == columns)
+            .expect("lookup on non-indexed column set");
+
+        if self.indices[index_id].filled.is_some() {
+            // we can't know which keys in the range are holes
+            return LookupResult::Missing;
+        }
+
+        let data = tokio::task::block_in_place(|| match self.table_format {
+            PersistentTableFormat::Plain => {
+                // Plain tables can't be iterated in key order, so we'll have to scan all the rows
+                // and check each of their keys instead:
+                self.all_rows()
+                    .map(|(_key, value)| bincode::deserialize(&*value).unwrap())
+                    .filter(|row: &Vec<DataType>| {
+                        let key: Vec<DataType> = columns.iter().map(|&c| row[c].clone()).collect();
+                        range.contains(&key)
+                    })
+                    .collect()
+            }
+            PersistentTableFormat::BlockBased => {
+                let cf = db.cf_handle(&self.indices[index_id].column_family).unwrap();
+                let encode = |key: &Vec<DataType>| ordered_key::encode(key);
+                let (start, skip) = match range.0 {
+                    Bound::Included(ref k) => (Some(encode(k)), None),
+                    Bound::Excluded(ref k) => {
+                        let k = encode(k);
+                        (Some(k.clone()), Some(k))
+                    }
+                    Bound::Unbounded => (None, None),
+                };
+                let end = match range.1 {
+                    Bound::Included(ref k) => Bound::Included(encode(k)),
+                    Bound::Excluded(ref k) => Bound::Excluded(encode(k)),
+                    Bound::Unbounded => Bound::Unbounded,
+                };
+
+                let mode = match start {
+                    Some(ref k) => rocksdb::IteratorMode::From(k, rocksdb::Direction::Forward),
+                    None => rocksdb::IteratorMode::Start,
+                };
+
+                // Index keys may be followed by a suffix (a sequence number or a primary key) that
+                // makes them unique, so bounds are compared against the encoded key as a prefix.
+                db.full_iterator_cf(cf, mode)
+                    .skip_while(|(key, _)| match skip {
+                        Some(ref k) => key.starts_with(k),
+                        None => false,
+                    })
+                    .take_while(|(key, _)| match end {
+                        Bound::Included(ref k) => key[..] <= k[..] || key.starts_with(k),
+                        Bound::Excluded(ref k) => key[..] < k[..],
+                        Bound::Unbounded => true,
+                    })
+                    .map(|(_key, value)| bincode::deserialize(&*value).unwrap())
+                    .collect()
+            }
+        });
+
+        LookupResult::Some(RecordResult::Owned(data))
+    }
+
     fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
-        assert!(partial.is_none(), "Bases can't be partial");
         let existing = self
             .indices
             .iter()
-            .any(|index| &index.columns[..] == columns);
+            .position(|index| &index.columns[..] == columns);
+
+        if let Some(ref tags) = partial {
+            let index_id = existing.unwrap_or_else(|| self.indices.len());
+            for &tag in tags {
+                self.by_tag.insert(tag, index_id);
+            }
+        }
 
-        if existing {
+        if existing.is_some() {
             return;
         }
 
+        assert!(
+            self.indices
+                .iter()
+                .all(|index| index.filled.is_some() == partial.is_some()),
+            "PersistentState can't mix full and partial indices"
+        );
+
         let cols = Vec::from(columns);
         // We'll store all the pointers (or values if this is index 0) for
         // this index in its own column family:
         let index_id = self.indices.len().to_string();
 
         tokio::task::block_in_place(|| {
-            let db = self.db.as_mut().unwrap();
-            db.create_cf(&index_id, &self.db_opts).unwrap();
+            self.db
+                .as_mut()
+                .unwrap()
+                .create_cf(&index_id, &self.db_opts)
+                .unwrap();
 
-            // Build the new index for existing values:
-            if !self.indices.is_empty() {
+            // Build the new index for existing values (partial indices start out empty):
+            let db = self.db.as_ref().unwrap();
+            if !self.indices.is_empty() && partial.is_none() {
                 let first_cf = db.cf_handle(&self.indices[0].column_family).unwrap();
                 let iter = db.full_iterator_cf(first_cf, rocksdb::IteratorMode::Start);
                 for chunk in iter.chunks(INDEX_BATCH_SIZE).into_iter() {
@@ -143,7 +267,7 @@ impl State for PersistentState {
                     for (ref pk, ref value) in chunk {
                         let row: Vec<DataType> = bincode::deserialize(&value).unwrap();
                         let index_key = Self::build_key(&row, columns);
-                        let key = Self::serialize_secondary(&index_key, pk);
+                        let key = self.serialize_secondary(&index_key, pk);
                         let cf = db.cf_handle(&index_id).unwrap();
                         batch.put_cf(cf, &key, value);
                     }
@@ -155,6 +279,8 @@ impl State for PersistentState {
             self.indices.push(PersistentIndex {
                 columns: cols,
                 column_family: index_id.to_string(),
+                filled: partial.as_ref().map(|_| FilledKeys::default()),
+                access: partial.map(|_| AccessTracker::default()),
             });
 
             self.persist_meta();
@@ -169,11 +295,27 @@ impl State for PersistentState {
     }
 
     fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        assert!(!self.is_partial());
         self.all_rows()
             .map(|(_, ref value)| bincode::deserialize(&value).unwrap())
             .collect()
     }
 
+    fn cloned_keys(&self) -> Vec<Vec<DataType>> {
+        assert!(!self.is_partial());
+        // rows are ordered by their key in the first index, so equal keys are next to each other
+        let columns = &self.indices[0].columns;
+        let mut keys: Vec<Vec<DataType>> = self
+            .all_rows()
+            .map(|(_, ref value)| {
+                let row: Vec<DataType> = bincode::deserialize(&value).unwrap();
+                columns.iter().map(|&c| row[c].clone()).collect()
+            })
+            .collect();
+        keys.dedup();
+        keys
+    }
+
     // Returns a row count estimate from RocksDB.
     fn rows(&self) -> usize {
         tokio::task::block_in_place(|| {
@@ -193,27 +335,121 @@ impl State for PersistentState {
     }
 
     fn is_partial(&self) -> bool {
-        false
+        self.indices.iter().any(|index| index.filled.is_some())
     }
 
-    fn mark_filled(&mut self, _: Vec<DataType>, _: Tag) {
-        unreachable!("PersistentState can't be partial")
+    fn is_persistent(&self) -> bool {
+        true
     }
 
-    fn mark_hole(&mut self, _: &[DataType], _: Tag) {
-        unreachable!("PersistentState can't be partial")
+    fn mark_filled(&mut self, key: Vec<DataType>, tag: Tag) {
+        let index = &mut self.indices[self.by_tag[&tag]];
+        let filled = index.filled.as_mut().expect("filling full index");
+        let inserted = filled.insert(key);
+        assert!(inserted);
+        if let Some(ref access) = index.access {
+            access.reserve(filled.len());
+        }
+    }
+
+    fn mark_hole(&mut self, key: &[DataType], tag: Tag) {
+        let index_id = self.by_tag[&tag];
+        let freed = self.evict_key(index_id, key);
+        // mark_hole should only be called on keys we called mark_filled on
+        assert!(freed.is_some());
+    }
+
+    fn evict_cold_keys(
+        &mut self,
+        count: usize,
+        policy: &dyn EvictionPolicy,
+    ) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        assert!(
+            self.is_partial(),
+            "can't evict keys from full PersistentState"
+        );
+        let mut rng = rand::thread_rng();
+        let index_id = rng.gen_range(0, self.indices.len());
+        self.indices[index_id].access.as_ref().unwrap().tick();
+
+        let mut bytes_freed = 0;
+        let mut keys = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key = match self.indices[index_id] {
+                PersistentIndex {
+                    filled: Some(ref filled),
+                    access: Some(ref access),
+                    ..
+                } if !filled.is_empty() => {
+                    let victim = access.choose_victim(policy, &mut rng, filled.len(), |i| {
+                        key_hash(&filled.get_index(i).unwrap()[..])
+                    });
+                    filled.get_index(victim).unwrap().clone()
+                }
+                _ => break,
+            };
+
+            bytes_freed += self.evict_key(index_id, &key).unwrap();
+            keys.push(key);
+        }
+
+        let index = &self.indices[index_id];
+        index.access.as_ref().unwrap().evicted(keys.len());
+        (&index.columns[..], keys, bytes_freed)
     }
 
-    fn evict_random_keys(&mut self, _: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
-        unreachable!("can't evict keys from PersistentState")
+    fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
+        // we may be told to evict from a tag that add_key hasn't been called for yet
+        // this can happen if an upstream domain issues an eviction for a replay path that we have
+        // been told about, but that has not yet been finalized.
+        let index_id = *self.by_tag.get(&tag)?;
+        let mut evicted = 0;
+        let mut bytes = 0;
+        for key in keys {
+            if let Some(freed) = self.evict_key(index_id, key) {
+                evicted += 1;
+                bytes += freed;
+            }
+        }
+        let index = &self.indices[index_id];
+        index.access.as_ref().unwrap().evicted(evicted);
+        Some((&index.columns[..], bytes))
     }
 
-    fn evict_keys(&mut self, _: Tag, _: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
-        unreachable!("can't evict keys from PersistentState")
+    fn cache_stats(&self) -> CacheStats {
+        self.indices
+            .iter()
+            .filter_map(|index| index.access.as_ref())
+            .map(AccessTracker::stats)
+            .fold(CacheStats::default(), |a, b| a + b)
     }
 
     fn clear(&mut self) {
-        unreachable!("can't clear PersistentState")
+        assert!(self.is_partial(), "can't clear full PersistentState");
+        for index_id in 0..self.indices.len() {
+            tokio::task::block_in_place(|| {
+                let db = self.db.as_ref().unwrap();
+                let cf = db.cf_handle(&self.indices[index_id].column_family).unwrap();
+                let mut batch = WriteBatch::default();
+                for (key, _) in db.full_iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                    batch.delete_cf(cf, &key);
+                }
+                db.write(batch).unwrap();
+            });
+            self.indices[index_id].filled.as_mut().unwrap().clear();
+        }
+    }
+
+    fn destroy(&mut self) -> Result<(), String> {
+        // RocksDB must be closed before its files can be removed
+        let db = match self.db.take() {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+        let path = db.path().to_path_buf();
+        drop(db);
+        tokio::task::block_in_place(|| rocksdb::DB::destroy(&self.db_opts, &path))
+            .map_err(|e| format!("failed to delete {}: {}", path.display(), e))
     }
 }
 
@@ -270,6 +506,8 @@ impl PersistentState {
                 .map(|(i, columns)| PersistentIndex {
                     column_family: i.to_string(),
                     columns,
+                    filled: None,
+                    access: None,
                 })
                 .collect();
 
@@ -283,7 +521,9 @@ impl PersistentState {
             let mut state = Self {
                 seq: 0,
                 indices,
+                by_tag: HashMap::new(),
                 has_unique_index: primary_key.is_some(),
+                table_format: params.table_format,
                 epoch: meta.epoch,
                 db_opts: opts,
                 db: Some(db),
@@ -303,6 +543,8 @@ impl PersistentState {
                 let persistent_index = PersistentIndex {
                     column_family: "0".to_string(),
                     columns: primary_key.unwrap().to_vec(),
+                    filled: None,
+                    access: None,
                 };
 
                 state.indices.push(persistent_index);
@@ -319,16 +561,25 @@ impl PersistentState {
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
 
-        let user_key_length = 0; // variable key length
         let bloom_bits_per_key = 10;
-        let hash_table_ratio = 0.75;
-        let index_sparseness = 16;
-        opts.set_plain_table_factory(&PlainTableFactoryOptions {
-            user_key_length,
-            bloom_bits_per_key,
-            hash_table_ratio,
-            index_sparseness,
-        });
+        match params.table_format {
+            PersistentTableFormat::Plain => {
+                let user_key_length = 0; // variable key length
+                let hash_table_ratio = 0.75;
+                let index_sparseness = 16;
+                opts.set_plain_table_factory(&PlainTableFactoryOptions {
+                    user_key_length,
+                    bloom_bits_per_key,
+                    hash_table_ratio,
+                    index_sparseness,
+                });
+            }
+            PersistentTableFormat::BlockBased => {
+                let mut block_opts = rocksdb::BlockBasedOptions::default();
+                block_opts.set_bloom_filter(bloom_bits_per_key as i32, false);
+                opts.set_block_based_table_factory(&block_opts);
+            }
+        }
 
         if let Some(ref path) = params.log_dir {
             // Append the db name to the WAL path to ensure
@@ -336,9 +587,11 @@ impl PersistentState {
             opts.set_wal_dir(path.join(&name));
         }
 
-        // Create prefixes using `prefix_transform` on all new inserted keys:
-        let transform = SliceTransform::create("key", prefix_transform, Some(in_domain));
-        opts.set_prefix_extractor(transform);
+        if params.table_format == PersistentTableFormat::Plain {
+            // Create prefixes using `prefix_transform` on all new inserted keys:
+            let transform = SliceTransform::create("key", prefix_transform, Some(in_domain));
+            opts.set_prefix_extractor(transform);
+        }
 
         // Assigns the number of threads for compactions and flushes in RocksDB.
         // Optimally we'd like to use env->SetBackgroundThreads(n, Env::HIGH)
@@ -363,11 +616,13 @@ impl PersistentState {
         // Keep up to 4 parallel memtables:
         opts.set_max_write_buffer_number(4);
 
-        // Use a hash linked list since we're doing prefix seeks.
-        opts.set_allow_concurrent_memtable_write(false);
-        opts.set_memtable_factory(rocksdb::MemtableFactory::HashLinkList {
-            bucket_count: 1_000_000,
-        });
+        if params.table_format == PersistentTableFormat::Plain {
+            // Use a hash linked list since we're doing prefix seeks.
+            opts.set_allow_concurrent_memtable_write(false);
+            opts.set_memtable_factory(rocksdb::MemtableFactory::HashLinkList {
+                bucket_count: 1_000_000,
+            });
+        }
 
         opts
     }
@@ -418,7 +673,17 @@ impl PersistentState {
     //
     // Self::serialize_raw_key is responsible for serializing the underlying KeyType tuple directly
     // (without the enum variant), plus any extra information as described above.
-    fn serialize_raw_key<S: serde::Serialize>(key: &KeyType, extra: S) -> Vec<u8> {
+    //
+    // With PersistentTableFormat::BlockBased, the (size, key) part is instead replaced by the
+    // order-preserving encoding from `ordered_key`, which doesn't need a size since it is
+    // prefix-free. Any extra information is serialized as before.
+    fn serialize_raw_key<S: serde::Serialize>(&self, key: &KeyType, extra: S) -> Vec<u8> {
+        if self.table_format == PersistentTableFormat::BlockBased {
+            let mut bytes = ordered_key::encode_key_type(key);
+            bytes.extend(bincode::serialize(&extra).unwrap());
+            return bytes;
+        }
+
         fn serialize<K: serde::Serialize, E: serde::Serialize>(k: K, extra: E) -> Vec<u8> {
             let size: u64 = bincode::serialized_size(&k).unwrap();
             bincode::serialize(&(size, k, extra)).unwrap()
@@ -434,16 +699,36 @@ impl PersistentState {
         }
     }
 
-    fn serialize_prefix(key: &KeyType) -> Vec<u8> {
-        Self::serialize_raw_key(key, ())
+    fn serialize_prefix(&self, key: &KeyType) -> Vec<u8> {
+        self.serialize_raw_key(key, ())
     }
 
-    fn serialize_secondary(key: &KeyType, raw_primary: &[u8]) -> Vec<u8> {
-        let mut bytes = Self::serialize_raw_key(key, ());
+    fn serialize_secondary(&self, key: &KeyType, raw_primary: &[u8]) -> Vec<u8> {
+        let mut bytes = self.serialize_raw_key(key, ());
         bytes.extend_from_slice(raw_primary);
         bytes
     }
 
+    // Iterates over all the rows in `cf` whose keys start with `prefix`. Block-based tables have
+    // no prefix extractor, so we seek to the prefix and stop once we're past it instead.
+    fn prefix_iterator<'a>(
+        &self,
+        db: &'a rocksdb::DB,
+        cf: &'a rocksdb::ColumnFamily,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        match self.table_format {
+            PersistentTableFormat::Plain => Box::new(db.prefix_iterator_cf(cf, prefix)),
+            PersistentTableFormat::BlockBased => {
+                let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+                Box::new(
+                    db.full_iterator_cf(cf, mode)
+                        .take_while(move |(key, _)| key.starts_with(prefix)),
+                )
+            }
+        }
+    }
+
     // Filters out secondary indices to return an iterator for the actual key-value pairs.
     fn all_rows(&self) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
         let db = self.db.as_ref().unwrap();
@@ -460,13 +745,13 @@ impl PersistentState {
         let serialized_pk = {
             let pk = Self::build_key(r, &self.indices[0].columns);
             if self.has_unique_index {
-                Self::serialize_prefix(&pk)
+                self.serialize_prefix(&pk)
             } else {
                 // For bases without primary keys we store the actual row values keyed by the index
                 // that was added first. This means that we can't consider the keys unique though, so
                 // we'll append a sequence number.
                 self.seq += 1;
-                Self::serialize_raw_key(&pk, (self.epoch, self.seq))
+                self.serialize_raw_key(&pk, (self.epoch, self.seq))
             }
         };
 
@@ -482,13 +767,135 @@ impl PersistentState {
             for index in self.indices[1..].iter() {
                 // Construct a key with the index values, and serialize it with bincode:
                 let key = Self::build_key(&r, &index.columns);
-                let serialized_key = Self::serialize_secondary(&key, &serialized_pk);
+                let serialized_key = self.serialize_secondary(&key, &serialized_pk);
                 let cf = db.cf_handle(&index.column_family).unwrap();
                 batch.put_cf(cf, &serialized_key, &serialized_row);
             }
         })
     }
 
+    // Partially materialized state isn't recovered after a restart (we wouldn't know which keys
+    // are holes), so we don't bother syncing its writes to the WAL.
+    //
+    // Records that miss all indices are removed from `records`, just like in MemoryState.
+    fn process_partial_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
+        let mut batch = WriteBatch::default();
+        records.retain(|r| match *r {
+            Record::Positive(ref r) => self.insert_partial(&mut batch, r, partial_tag),
+            Record::Negative(ref r) => {
+                // The row we're removing may have been inserted earlier in this batch, so we need
+                // to make sure it's visible to the lookups in remove_partial:
+                if !batch.is_empty() {
+                    let pending = std::mem::replace(&mut batch, WriteBatch::default());
+                    tokio::task::block_in_place(|| self.db.as_ref().unwrap().write(pending))
+                        .unwrap();
+                }
+                self.remove_partial(&mut batch, r)
+            }
+        });
+
+        tokio::task::block_in_place(|| self.db.as_ref().unwrap().write(batch)).unwrap();
+    }
+
+    // Inserts `r` into every index where its key isn't a hole, or only into the index targeted by
+    // `partial_tag` for replays. Returns false if the record hit no index.
+    fn insert_partial(
+        &mut self,
+        batch: &mut WriteBatch,
+        r: &[DataType],
+        partial_tag: Option<Tag>,
+    ) -> bool {
+        let targets: Vec<usize> = match partial_tag {
+            Some(tag) => match self.by_tag.get(&tag) {
+                Some(&index_id) => vec![index_id],
+                None => {
+                    // got tagged insert for unknown tag. this will happen if a node on an old
+                    // replay path is now materialized. must return true to avoid any records
+                    // (which are destined for a downstream materialization) from being pruned.
+                    return true;
+                }
+            },
+            None => (0..self.indices.len()).collect(),
+        };
+
+        self.seq += 1;
+        let serialized_row = bincode::serialize(&r).unwrap();
+        let mut hit_any = false;
+        tokio::task::block_in_place(|| {
+            let db = self.db.as_ref().unwrap();
+            for index_id in targets {
+                let index = &self.indices[index_id];
+                let filled = index.filled.as_ref().unwrap();
+                let key: Vec<DataType> = index.columns.iter().map(|&c| r[c].clone()).collect();
+                if !filled.contains(&key) {
+                    continue;
+                }
+
+                let key = Self::build_key(r, &index.columns);
+                let serialized_key = self.serialize_raw_key(&key, (self.epoch, self.seq));
+                let cf = db.cf_handle(&index.column_family).unwrap();
+                batch.put_cf(cf, &serialized_key, &serialized_row);
+                hit_any = true;
+            }
+        });
+        hit_any
+    }
+
+    // Removes one copy of `r` from every index where its key isn't a hole. Returns false if the
+    // record hit no index.
+    fn remove_partial(&self, batch: &mut WriteBatch, r: &[DataType]) -> bool {
+        tokio::task::block_in_place(|| {
+            let db = self.db.as_ref().unwrap();
+            let mut hit = false;
+            for index in &self.indices {
+                let filled = index.filled.as_ref().unwrap();
+                let key: Vec<DataType> = index.columns.iter().map(|&c| r[c].clone()).collect();
+                if !filled.contains(&key) {
+                    continue;
+                }
+
+                let cf = db.cf_handle(&index.column_family).unwrap();
+                let prefix = self.serialize_prefix(&Self::build_key(r, &index.columns));
+                let found = self
+                    .prefix_iterator(db, cf, &prefix)
+                    .find(|(_, raw_value)| {
+                        let value: Vec<DataType> = bincode::deserialize(&*raw_value).unwrap();
+                        r == &value[..]
+                    });
+
+                if let Some((key, _)) = found {
+                    batch.delete_cf(cf, &key);
+                    hit = true;
+                }
+            }
+            hit
+        })
+    }
+
+    // Turns `key` back into a hole in the given partial index, deleting all its rows. Returns the
+    // number of bytes freed, or None if the key was already a hole.
+    fn evict_key(&mut self, index_id: usize, key: &[DataType]) -> Option<u64> {
+        let index = &mut self.indices[index_id];
+        if !index.filled.as_mut().unwrap().swap_remove(key) {
+            return None;
+        }
+
+        let index = &self.indices[index_id];
+        let prefix = self.serialize_prefix(&KeyType::from(key));
+        tokio::task::block_in_place(|| {
+            let db = self.db.as_ref().unwrap();
+            let cf = db.cf_handle(&index.column_family).unwrap();
+            let mut batch = WriteBatch::default();
+            let mut freed = 0;
+            for (key, value) in self.prefix_iterator(db, cf, &prefix) {
+                freed += (key.len() + value.len()) as u64;
+                batch.delete_cf(cf, &key);
+            }
+            db.write(batch).unwrap();
+            Some(freed)
+        })
+    }
+
     fn remove(&self, batch: &mut WriteBatch, r: &[DataType]) {
         tokio::task::block_in_place(|| {
             let db = self.db.as_ref().unwrap();
@@ -501,14 +908,14 @@ impl PersistentState {
                 // Then delete any references that point _exactly_ to that row:
                 for index in self.indices[1..].iter() {
                     let key = Self::build_key(&r, &index.columns);
-                    let serialized_key = Self::serialize_secondary(&key, primary_key);
+                    let serialized_key = self.serialize_secondary(&key, primary_key);
                     let cf = db.cf_handle(&index.column_family).unwrap();
                     batch.delete_cf(cf, &serialized_key);
                 }
             };
 
             let pk = Self::build_key(&r, &pk_index.columns);
-            let prefix = Self::serialize_prefix(&pk);
+            let prefix = self.serialize_prefix(&pk);
             if self.has_unique_index {
                 if cfg!(debug_assertions) {
                     // This would imply that we're trying to delete a different row than the one we
@@ -524,8 +931,8 @@ impl PersistentState {
 
                 do_remove(&prefix[..]);
             } else {
-                let (key, _value) = db
-                    .prefix_iterator_cf(value_cf, &prefix)
+                let (key, _value) = self
+                    .prefix_iterator(db, value_cf, &prefix)
                     .find(|(_, raw_value)| {
                         let value: Vec<DataType> = bincode::deserialize(&*raw_value).unwrap();
                         r == &value[..]
@@ -635,6 +1042,21 @@ mod tests {
         )
     }
 
+    fn setup_block_based(prefix: &str, primary_key: Option<&[usize]>) -> PersistentState {
+        let mut params = PersistenceParameters::default();
+        params.table_format = PersistentTableFormat::BlockBased;
+        PersistentState::new(String::from(prefix), primary_key, &params)
+    }
+
+    fn range_keys(state: &PersistentState, columns: &[usize], range: &KeyRange) -> Vec<DataType> {
+        match state.lookup_range(columns, range) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                rows.into_iter().map(|r| r[columns[0]].clone()).collect()
+            }
+            _ => unreachable!(),
+        }
+    }
+
     #[test]
     fn persistent_state_is_partial() {
         let state = setup_persistent("persistent_state_is_partial");
@@ -1079,6 +1501,26 @@ mod tests {
         assert_eq!(state.cloned_records(), vec![first, second]);
     }
 
+    #[test]
+    fn persistent_state_cloned_keys() {
+        let mut state = setup_persistent("persistent_state_cloned_keys");
+        state.add_key(&[1], None);
+        state.add_key(&[0], None);
+        state.process_records(
+            &mut vec![
+                vec![10.into(), "Cat".into()],
+                vec![20.into(), "Cat".into()],
+                vec![30.into(), "Dog".into()],
+            ]
+            .into(),
+            None,
+        );
+
+        let mut keys = state.cloned_keys();
+        keys.sort();
+        assert_eq!(keys, vec![vec!["Cat".into()], vec!["Dog".into()]]);
+    }
+
     #[test]
     #[cfg(not(windows))]
     fn persistent_state_drop() {
@@ -1097,6 +1539,26 @@ mod tests {
         assert!(!PathBuf::from(path).exists());
     }
 
+    #[test]
+    fn persistent_state_destroy() {
+        let (_dir, name) = get_tmp_path();
+        let mut params = PersistenceParameters::default();
+        params.mode = DurabilityMode::Permanent;
+
+        let mut state = PersistentState::new(name.clone(), Some(&[0]), &params);
+        insert(&mut state, vec![10.into(), "Cat".into()]);
+        let path = PathBuf::from(format!("{}.db", name));
+        assert!(path.exists());
+
+        state.destroy().unwrap();
+        assert!(!path.exists());
+        drop(state);
+
+        // a new table by the same name starts out empty
+        let state = PersistentState::new(name, Some(&[0]), &params);
+        assert_eq!(state.rows(), 0);
+    }
+
     #[test]
     fn persistent_state_old_records_new_index() {
         let mut state = setup_persistent("persistent_state_old_records_new_index");
@@ -1148,7 +1610,7 @@ mod tests {
         state.add_key(&[0], None);
         let data = (DataType::from(1), DataType::from(10));
         let r = KeyType::Double(data.clone());
-        let k = PersistentState::serialize_prefix(&r);
+        let k = state.serialize_prefix(&r);
         let prefix = prefix_transform(&k);
         let size: u64 = bincode::deserialize(&prefix).unwrap();
         assert_eq!(size, bincode::serialized_size(&data).unwrap());
@@ -1161,7 +1623,7 @@ mod tests {
         assert!(prefix <= &k[..]);
 
         // 3) If Compare(k1, k2) <= 0, then Compare(prefix(k1), prefix(k2)) <= 0
-        let other_k = PersistentState::serialize_prefix(&r);
+        let other_k = state.serialize_prefix(&r);
         let other_prefix = prefix_transform(&other_k);
         assert!(k <= other_k);
         assert!(prefix <= other_prefix);
@@ -1169,4 +1631,224 @@ mod tests {
         // 4) prefix(prefix(key)) == prefix(key)
         assert_eq!(prefix, prefix_transform(&prefix));
     }
+
+    #[test]
+    fn persistent_state_block_based_lookup() {
+        let mut state = setup_block_based("persistent_state_block_based_lookup", None);
+        let first: Vec<DataType> = vec![10.into(), "Cat".into()];
+        let duplicate: Vec<DataType> = vec![10.into(), "Other Cat".into()];
+        let second: Vec<DataType> = vec![100.into(), "Cat".into()];
+        state.add_key(&[0], None);
+        state.add_key(&[1], None);
+        state.process_records(
+            &mut vec![first.clone(), duplicate.clone(), second.clone()].into(),
+            None,
+        );
+
+        match state.lookup(&[0], &KeyType::Single(&10.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows, vec![first.clone(), duplicate.clone()]);
+            }
+            _ => unreachable!(),
+        }
+
+        match state.lookup(&[1], &KeyType::Single(&"Cat".into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows, vec![first.clone(), second.clone()]);
+            }
+            _ => unreachable!(),
+        }
+
+        state.process_records(&mut vec![(first.clone(), false)].into(), None);
+        match state.lookup(&[0], &KeyType::Single(&10.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![duplicate]),
+            _ => unreachable!(),
+        }
+
+        match state.lookup(&[1], &KeyType::Single(&"Cat".into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![second]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_block_based_lookup_range() {
+        let pk = &[0];
+        let mut state = setup_block_based("persistent_state_block_based_lookup_range", Some(pk));
+        state.add_key(pk, None);
+        state.add_key(&[1], None);
+        // Insert in an order that differs from both the key order and the bincode byte order:
+        let rows: Vec<Vec<DataType>> = vec![300, -5, 2, 70, 1000, 0]
+            .into_iter()
+            .map(|i| vec![i.into(), (i % 7).into()])
+            .collect();
+        state.process_records(&mut rows.into(), None);
+
+        let range = (
+            Bound::Included(vec![0.into()]),
+            Bound::Excluded(vec![300.into()]),
+        );
+        assert_eq!(
+            range_keys(&state, pk, &range),
+            vec![0.into(), 2.into(), 70.into()]
+        );
+
+        let range = (
+            Bound::Excluded(vec![0.into()]),
+            Bound::Included(vec![300.into()]),
+        );
+        assert_eq!(
+            range_keys(&state, pk, &range),
+            vec![2.into(), 70.into(), 300.into()]
+        );
+
+        let range = (Bound::Unbounded, Bound::Excluded(vec![2.into()]));
+        assert_eq!(range_keys(&state, pk, &range), vec![(-5).into(), 0.into()]);
+
+        // Secondary index keys are followed by the primary key, which we should see past:
+        let range = (
+            Bound::Included(vec![2.into()]),
+            Bound::Included(vec![6.into()]),
+        );
+        assert_eq!(
+            range_keys(&state, &[1], &range),
+            vec![2.into(), 6.into(), 6.into()]
+        );
+    }
+
+    #[test]
+    fn persistent_state_plain_lookup_range() {
+        let mut state = setup_persistent("persistent_state_plain_lookup_range");
+        state.add_key(&[0], None);
+        for i in 0..10 {
+            insert(&mut state, vec![i.into(), "Cat".into()]);
+        }
+
+        let range = (
+            Bound::Included(vec![3.into()]),
+            Bound::Included(vec![5.into()]),
+        );
+        let mut keys = range_keys(&state, &[0], &range);
+        keys.sort();
+        assert_eq!(keys, vec![3.into(), 4.into(), 5.into()]);
+    }
+
+    #[test]
+    fn persistent_state_partial_lookup() {
+        let mut state = setup_persistent("persistent_state_partial_lookup");
+        let tag = Tag::new(1);
+        state.add_key(&[0], Some(vec![tag]));
+        assert!(state.is_partial());
+
+        // Everything is a hole to begin with, so this record should be dropped:
+        let mut records: Records = vec![vec![DataType::from(1), "Cat".into()]].into();
+        state.process_records(&mut records, None);
+        assert!(records.is_empty());
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        }
+
+        // Fill the key through a replay:
+        let row: Vec<DataType> = vec![1.into(), "Dog".into()];
+        state.mark_filled(vec![1.into()], tag);
+        state.process_records(&mut vec![row.clone()].into(), Some(tag));
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![row.clone()]),
+            _ => unreachable!(),
+        }
+
+        // Regular updates to filled keys should now go through:
+        let other: Vec<DataType> = vec![1.into(), "Bird".into()];
+        let mut records: Records = vec![(other.clone(), true), (row.clone(), false)].into();
+        state.process_records(&mut records, None);
+        assert_eq!(records.len(), 2);
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![other]),
+            _ => unreachable!(),
+        }
+
+        state.mark_hole(&[1.into()], tag);
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_partial_insert_remove_same_batch() {
+        let mut state = setup_persistent("persistent_state_partial_insert_remove_same_batch");
+        let tag = Tag::new(1);
+        state.add_key(&[0], Some(vec![tag]));
+        state.mark_filled(vec![1.into()], tag);
+
+        let row: Vec<DataType> = vec![1.into(), "Cat".into()];
+        let mut records: Records = vec![(row.clone(), true), (row.clone(), false)].into();
+        state.process_records(&mut records, None);
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert!(rows.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_partial_multiple_indices() {
+        let mut state = setup_persistent("persistent_state_partial_multiple_indices");
+        let (tag_a, tag_b) = (Tag::new(1), Tag::new(2));
+        state.add_key(&[0], Some(vec![tag_a]));
+        state.add_key(&[1], Some(vec![tag_b]));
+        state.mark_filled(vec!["Cat".into()], tag_b);
+
+        // Only the second index has a filled key for this row:
+        let row: Vec<DataType> = vec![1.into(), "Cat".into()];
+        state.process_records(&mut vec![row.clone()].into(), None);
+        match state.lookup(&[1], &KeyType::Single(&"Cat".into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![row]),
+            _ => unreachable!(),
+        }
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_partial_evict() {
+        let mut state = setup_persistent("persistent_state_partial_evict");
+        let tag = Tag::new(1);
+        state.add_key(&[0], Some(vec![tag]));
+        for i in 0..10 {
+            state.mark_filled(vec![i.into()], tag);
+            state.process_records(&mut vec![vec![i.into(), "Cat".into()]].into(), Some(tag));
+        }
+
+        let (columns, bytes) = state.evict_keys(tag, &[vec![3.into()]]).unwrap();
+        assert_eq!(columns, &[0]);
+        assert!(bytes > 0);
+        match state.lookup(&[0], &KeyType::Single(&3.into())) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        }
+
+        // Unknown tags are ignored:
+        assert!(state.evict_keys(Tag::new(2), &[vec![4.into()]]).is_none());
+
+        let (columns, keys, bytes) =
+            state.evict_cold_keys(4, &crate::eviction::SampledLru::default());
+        assert_eq!(columns, &[0]);
+        assert_eq!(keys.len(), 4);
+        assert!(bytes > 0);
+        for key in keys {
+            match state.lookup(&[0], &KeyType::from(&key[..])) {
+                LookupResult::Missing => {}
+                _ => unreachable!(),
+            }
+        }
+
+        state.clear();
+        match state.lookup(&[0], &KeyType::Single(&0.into())) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        }
+    }
 }
@@ -1,8 +1,12 @@
 use super::mk_key::MakeKey;
+use crate::eviction::{key_hash, AccessTracker, EvictionPolicy};
 use crate::prelude::*;
 use crate::state::keyed_state::KeyedState;
 use common::SizeOf;
+use noria::debug::stats::{CacheStats, IndexStats};
 use rand::prelude::*;
+use std::collections::{btree_map, BTreeMap};
+use std::ops::RangeBounds;
 use std::rc::Rc;
 
 pub(super) struct SingleState {
@@ -10,6 +14,10 @@ pub(super) struct SingleState {
     state: KeyedState,
     partial: bool,
     rows: usize,
+    // The number of keys with each number of rows, so that statistics needn't walk the index.
+    key_sizes: BTreeMap<usize, usize>,
+    // Only kept for partial state, since full state is never evicted from.
+    access: Option<AccessTracker>,
 }
 
 macro_rules! insert_row_match_impl {
@@ -17,11 +25,14 @@ macro_rules! insert_row_match_impl {
         let key = MakeKey::from_row(&$self.key, &*$r);
         match $map.entry(key) {
             Entry::Occupied(mut rs) => {
-                rs.get_mut().insert($r);
+                let rs = rs.get_mut();
+                rs.insert($r);
+                resize_key(&mut $self.key_sizes, Some(rs.len() - 1), Some(rs.len()));
             }
             Entry::Vacant(..) if $self.partial => return false,
             rs @ Entry::Vacant(..) => {
                 rs.or_default().insert($r);
+                resize_key(&mut $self.key_sizes, None, Some(1));
             }
         }
     }};
@@ -32,7 +43,7 @@ macro_rules! remove_row_match_impl {
         // TODO: can we avoid the Clone here?
         let key = MakeKey::from_row(&$self.key, $r);
         if let Some(ref mut rs) = $map.get_mut::<$($hint)*>(&key) {
-            return $do_remove(&mut $self.rows, rs);
+            return $do_remove(&mut $self.rows, &mut $self.key_sizes, rs);
         }
     }};
 }
@@ -44,6 +55,12 @@ impl SingleState {
             state: columns.into(),
             partial,
             rows: 0,
+            key_sizes: BTreeMap::new(),
+            access: if partial {
+                Some(AccessTracker::default())
+            } else {
+                None
+            },
         }
     }
 
@@ -60,12 +77,14 @@ impl SingleState {
                 if let Some(ref mut rs) = map.get_mut(&r[self.key[0]]) {
                     self.rows += 1;
                     rs.insert(r);
+                    resize_key(&mut self.key_sizes, Some(rs.len() - 1), Some(rs.len()));
                     return true;
                 } else if self.partial {
                     // trying to insert a record into partial materialization hole!
                     return false;
                 }
                 map.insert(r[self.key[0]].clone(), std::iter::once(r).collect());
+                resize_key(&mut self.key_sizes, None, Some(1));
             }
             KeyedState::Double(ref mut map) => insert_row_match_impl!(self, r, map),
             KeyedState::Tri(ref mut map) => insert_row_match_impl!(self, r, map),
@@ -80,8 +99,12 @@ impl SingleState {
 
     /// Attempt to remove row `r`.
     pub(super) fn remove_row(&mut self, r: &[DataType], hit: &mut bool) -> Option<Row> {
-        let mut do_remove = |self_rows: &mut usize, rs: &mut Rows| -> Option<Row> {
+        let mut do_remove = |self_rows: &mut usize,
+                             key_sizes: &mut BTreeMap<usize, usize>,
+                             rs: &mut Rows|
+         -> Option<Row> {
             *hit = true;
+            let before = rs.len();
             let rm = if rs.len() == 1 {
                 // it *should* be impossible to get a negative for a record that we don't have,
                 // so let's avoid hashing + eqing if we don't need to
@@ -102,6 +125,7 @@ impl SingleState {
 
             if rm.is_some() {
                 *self_rows = self_rows.checked_sub(1).unwrap();
+                resize_key(key_sizes, Some(before), Some(rs.len()));
             }
             rm
         };
@@ -109,7 +133,7 @@ impl SingleState {
         match self.state {
             KeyedState::Single(ref mut map) => {
                 if let Some(ref mut rs) = map.get_mut(&r[self.key[0]]) {
-                    return do_remove(&mut self.rows, rs);
+                    return do_remove(&mut self.rows, &mut self.key_sizes, rs);
                 }
             }
             KeyedState::Double(ref mut map) => {
@@ -178,6 +202,10 @@ impl SingleState {
             ),
         };
         assert!(replaced.is_none());
+        resize_key(&mut self.key_sizes, None, Some(0));
+        if let Some(ref access) = self.access {
+            access.reserve(self.state.len());
+        }
     }
 
     pub(super) fn mark_hole(&mut self, key: &[DataType]) -> u64 {
@@ -200,8 +228,9 @@ impl SingleState {
             }
         };
         // mark_hole should only be called on keys we called mark_filled on
+        let removed = removed.unwrap();
+        resize_key(&mut self.key_sizes, Some(removed.len()), None);
         removed
-            .unwrap()
             .iter()
             .filter(|r| Rc::strong_count(&r.0) == 1)
             .map(SizeOf::deep_size_of)
@@ -210,6 +239,7 @@ impl SingleState {
 
     pub(super) fn clear(&mut self) {
         self.rows = 0;
+        self.key_sizes.clear();
         match self.state {
             KeyedState::Single(ref mut map) => map.clear(),
             KeyedState::Double(ref mut map) => map.clear(),
@@ -220,29 +250,73 @@ impl SingleState {
         };
     }
 
-    /// Evict `count` randomly selected keys from state and return them along with the number of
+    /// Evict `count` keys chosen by `policy` from state and return them along with the number of
     /// bytes freed.
-    pub(super) fn evict_random_keys(
+    pub(super) fn evict_cold_keys(
         &mut self,
         count: usize,
+        policy: &dyn EvictionPolicy,
         rng: &mut ThreadRng,
     ) -> (u64, Vec<Vec<DataType>>) {
+        if let Some(ref access) = self.access {
+            access.tick();
+        }
+
         let mut bytes_freed = 0;
         let mut keys = Vec::with_capacity(count);
         for _ in 0..count {
-            if let Some((n, key)) = self.state.evict_with_seed(rng.gen()) {
-                bytes_freed += n;
-                keys.push(key);
-            } else {
+            let len = self.state.len();
+            if len == 0 {
                 break;
             }
+            let index = match self.access {
+                Some(ref access) => {
+                    let state = &self.state;
+                    access.choose_victim(policy, rng, len, |i| state.key_hash_at(i))
+                }
+                None => rng.gen_range(0, len),
+            };
+            let (n, rows, key) = self.state.evict_at(index).unwrap();
+            resize_key(&mut self.key_sizes, Some(rows), None);
+            bytes_freed += n;
+            keys.push(key);
+        }
+
+        if let Some(ref access) = self.access {
+            access.evicted(keys.len());
         }
         (bytes_freed, keys)
     }
 
     /// Evicts a specified key from this state, returning the number of bytes freed.
     pub(super) fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
-        keys.iter().map(|k| self.state.evict(k)).sum()
+        if let Some(ref access) = self.access {
+            access.evicted(keys.len());
+        }
+        let mut bytes_freed = 0;
+        for key in keys {
+            if let Some((n, rows)) = self.state.evict(key) {
+                resize_key(&mut self.key_sizes, Some(rows), None);
+                bytes_freed += n;
+            }
+        }
+        bytes_freed
+    }
+
+    pub(super) fn cache_stats(&self) -> CacheStats {
+        self.access
+            .as_ref()
+            .map(AccessTracker::stats)
+            .unwrap_or_default()
+    }
+
+    pub(super) fn index_stats(&self) -> IndexStats {
+        IndexStats {
+            columns: self.key.clone(),
+            rows: Some(self.rows as u64),
+            distinct_keys: Some(self.state.len() as u64),
+            max_rows_per_key: Some(self.key_sizes.keys().next_back().cloned().unwrap_or(0) as u64),
+        }
     }
 
     pub(super) fn values<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Rows> + 'a> {
@@ -255,6 +329,9 @@ impl SingleState {
             KeyedState::Sex(ref map) => Box::new(map.values()),
         }
     }
+    pub(super) fn cloned_keys(&self) -> Vec<Vec<DataType>> {
+        self.state.cloned_keys()
+    }
     pub(super) fn key(&self) -> &[usize] {
         &self.key
     }
@@ -268,7 +345,16 @@ impl SingleState {
         self.rows == 0
     }
     pub(super) fn lookup<'a>(&'a self, key: &KeyType) -> LookupResult<'a> {
-        if let Some(rs) = self.state.lookup(key) {
+        let found = self.state.lookup(key);
+        if let Some(ref access) = self.access {
+            if found.is_some() {
+                access.hit(lookup_hash(key));
+            } else {
+                access.miss();
+            }
+        }
+
+        if let Some(rs) = found {
             LookupResult::Some(RecordResult::Borrowed(rs))
         } else if self.partial() {
             // partially materialized, so this is a hole (empty results would be vec![])
@@ -277,4 +363,52 @@ impl SingleState {
             LookupResult::Some(RecordResult::Owned(vec![]))
         }
     }
+
+    /// Look up all rows with keys in `range`. The in-memory indices are hashed, so this has to
+    /// scan every row in the index.
+    pub(super) fn lookup_range<'a>(&'a self, range: &KeyRange) -> LookupResult<'a> {
+        if self.partial() {
+            // we can't know which keys in the range are holes
+            return LookupResult::Missing;
+        }
+
+        let rows = self
+            .values()
+            .flat_map(|rs| rs.iter())
+            .filter(|r| {
+                let key: Vec<DataType> = self.key.iter().map(|&c| r[c].clone()).collect();
+                range.contains(&key)
+            })
+            .map(|r| Vec::clone(&**r))
+            .collect();
+        LookupResult::Some(RecordResult::Owned(rows))
+    }
+}
+
+/// Record that a key that had `from` rows now has `to` rows in `key_sizes`, where `None` means
+/// that the key isn't in the index.
+fn resize_key(key_sizes: &mut BTreeMap<usize, usize>, from: Option<usize>, to: Option<usize>) {
+    if let Some(from) = from {
+        if let btree_map::Entry::Occupied(mut keys) = key_sizes.entry(from) {
+            *keys.get_mut() -= 1;
+            if *keys.get() == 0 {
+                keys.remove();
+            }
+        }
+    }
+    if let Some(to) = to {
+        *key_sizes.entry(to).or_insert(0) += 1;
+    }
+}
+
+/// Hash a lookup key the same way `KeyedState::key_hash_at` hashes the corresponding map key.
+fn lookup_hash(key: &KeyType) -> u64 {
+    match *key {
+        KeyType::Single(k) => key_hash(k),
+        KeyType::Double(ref k) => key_hash(k),
+        KeyType::Tri(ref k) => key_hash(k),
+        KeyType::Quad(ref k) => key_hash(k),
+        KeyType::Quin(ref k) => key_hash(k),
+        KeyType::Sex(ref k) => key_hash(k),
+    }
 }
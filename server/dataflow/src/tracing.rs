@@ -0,0 +1,200 @@
+//! Sampled tracing of writes through the data-flow graph.
+//!
+//! Base domains pick a random sample of the writes they receive, and give each a `TraceContext`
+//! that then travels with the write (and whatever it turns into) from domain to domain. Every
+//! domain that handles a traced packet records a span for doing so, with a child span for each
+//! node that processed it, into its instance's `Tracer`. The instance exports what the tracer has
+//! collected in the OTLP/JSON format, so that a write's path across workers can be looked at in
+//! Jaeger or anything else that speaks OpenTelemetry.
+
+use rand::Rng;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many finished spans a tracer holds on to before it starts dropping new ones.
+const MAX_BUFFERED_SPANS: usize = 1 << 16;
+
+/// Where a sampled write is in its trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// The span of the domain that sent the write on, if any.
+    pub parent: Option<u64>,
+    /// When the write was sent on, or received from a client. The span of the domain that
+    /// handles it next starts here, so that time spent queued up for that domain counts against
+    /// it.
+    pub since: SystemTime,
+}
+
+/// A finished span of some trace.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: u64,
+    pub parent: Option<u64>,
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+/// Collects the spans recorded by an instance's domains until they are exported.
+#[derive(Debug)]
+pub struct Tracer {
+    sample_rate: f64,
+    spans: Mutex<Vec<Span>>,
+}
+
+impl Default for Tracer {
+    /// A tracer that never samples anything.
+    fn default() -> Self {
+        Tracer::new(0.0)
+    }
+}
+
+impl Tracer {
+    /// Make a tracer that traces roughly a `sample_rate` fraction of writes.
+    pub fn new(sample_rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&sample_rate));
+        Tracer {
+            sample_rate,
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Start a new trace for a write that was just received, if it is picked to be traced.
+    pub(crate) fn sample(&self) -> Option<TraceContext> {
+        if self.sample_rate <= 0.0 || rand::thread_rng().gen::<f64>() >= self.sample_rate {
+            return None;
+        }
+
+        let mut trace_id = rand::random::<[u8; 16]>();
+        // all-zero ids are invalid
+        trace_id[0] |= 1;
+        Some(TraceContext {
+            trace_id,
+            parent: None,
+            since: SystemTime::now(),
+        })
+    }
+
+    pub(crate) fn record(&self, span: Span) {
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() < MAX_BUFFERED_SPANS {
+            spans.push(span);
+        }
+    }
+
+    /// Hand over all the spans recorded since the last call.
+    pub fn take_spans(&self) -> Vec<Span> {
+        std::mem::replace(&mut *self.spans.lock().unwrap(), Vec::new())
+    }
+}
+
+/// Make up an id for a new span.
+pub(crate) fn new_span_id() -> u64 {
+    // zero is not a valid span id
+    rand::thread_rng().gen_range(1, u64::max_value())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+/// Render `spans` as an OTLP/JSON `ExportTraceServiceRequest` from the given service.
+pub fn to_otlp_json(service: &str, spans: &[Span]) -> Value {
+    let spans: Vec<_> = spans
+        .iter()
+        .map(|s| {
+            let mut span = json!({
+                "traceId": hex(&s.trace_id),
+                "spanId": hex(&s.span_id.to_be_bytes()),
+                "name": s.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(s.start),
+                "endTimeUnixNano": unix_nanos(s.end),
+                "attributes": s.attributes.iter().map(|(k, v)| {
+                    json!({ "key": k, "value": { "stringValue": v } })
+                }).collect::<Vec<_>>(),
+            });
+            if let Some(parent) = s.parent {
+                span["parentSpanId"] = json!(hex(&parent.to_be_bytes()));
+            }
+            span
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "noria" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_samples_at_the_given_rate() {
+        assert!((0..1000).all(|_| Tracer::default().sample().is_none()));
+
+        let t = Tracer::new(1.0);
+        let a = t.sample().unwrap();
+        let b = t.sample().unwrap();
+        assert_ne!(a.trace_id, b.trace_id);
+        assert_eq!(a.parent, None);
+    }
+
+    #[test]
+    fn it_renders_otlp_json() {
+        let t = Tracer::new(1.0);
+        let start = UNIX_EPOCH + Duration::from_millis(1500);
+        let span = |span_id, parent| Span {
+            trace_id: [0xab; 16],
+            span_id,
+            parent,
+            name: "domain 0.0".to_owned(),
+            start,
+            end: start + Duration::from_micros(20),
+            attributes: vec![("noria.domain", "0".to_owned())],
+        };
+        t.record(span(1, None));
+        t.record(span(0x0102, Some(1)));
+
+        let spans = t.take_spans();
+        assert!(t.take_spans().is_empty());
+        let out = to_otlp_json("noria", &spans);
+        let rs = &out["resourceSpans"][0];
+        assert_eq!(
+            rs["resource"]["attributes"][0]["value"]["stringValue"],
+            "noria"
+        );
+        let spans = rs["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["traceId"], "abababababababababababababababab");
+        assert_eq!(spans[0]["spanId"], "0000000000000001");
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!(spans[1]["spanId"], "0000000000000102");
+        assert_eq!(spans[1]["parentSpanId"], "0000000000000001");
+        assert_eq!(spans[1]["startTimeUnixNano"], "1500000000");
+        assert_eq!(spans[1]["endTimeUnixNano"], "1500020000");
+        assert_eq!(spans[1]["attributes"][0]["key"], "noria.domain");
+    }
+}
@@ -12,6 +12,7 @@ use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::grouped::aggregate::Aggregation as AggregationKind;
 use dataflow::ops::grouped::extremum::Extremum as ExtremumKind;
 use dataflow::ops::grouped::filteraggregate::FilterAggregation as FilterAggregationKind;
+use dataflow::ops::project::ColumnMask;
 use std::collections::HashMap;
 
 /// Helper enum to avoid having separate `make_aggregation_node` and `make_extremum_node` functions
@@ -61,11 +62,14 @@ impl MirNode {
         rc_mn
     }
 
-    /// Adapts an existing `Base`-type MIR Node with the specified column additions and removals.
+    /// Adapts an existing `Base`-type MIR Node with the specified column additions, removals, and
+    /// modifications. Modified columns are given as `(old, new)` pairs, and keep their position
+    /// and base column ID.
     pub fn adapt_base(
         node: MirNodeRef,
         added_cols: Vec<&ColumnSpecification>,
         removed_cols: Vec<&ColumnSpecification>,
+        modified_cols: Vec<(&ColumnSpecification, &ColumnSpecification)>,
     ) -> MirNodeRef {
         let over_node = node.borrow();
         match over_node.inner {
@@ -78,6 +82,12 @@ impl MirNode {
                     .iter()
                     .cloned()
                     .filter(|&(ref cs, _)| !removed_cols.contains(&cs))
+                    .map(
+                        |(cs, cid)| match modified_cols.iter().find(|&&(old, _)| *old == cs) {
+                            Some(&(_, new)) => (new.clone(), cid),
+                            None => (cs, cid),
+                        },
+                    )
                     .chain(
                         added_cols
                             .iter()
@@ -95,13 +105,31 @@ impl MirNode {
                     over_node.columns.len() + added_cols.len() - removed_cols.len()
                 );
 
+                // keys follow renamed columns
+                let keys = keys
+                    .iter()
+                    .map(|k| {
+                        match modified_cols
+                            .iter()
+                            .find(|&&(old, _)| old.column.name == k.name)
+                        {
+                            Some(&(_, new)) => Column::from(&new.column),
+                            None => k.clone(),
+                        }
+                    })
+                    .collect();
+
                 let new_inner = MirNodeType::Base {
                     column_specs: new_column_specs,
-                    keys: keys.clone(),
+                    keys,
                     adapted_over: Some(BaseNodeAdaptation {
                         over: node.clone(),
                         columns_added: added_cols.into_iter().cloned().collect(),
                         columns_removed: removed_cols.into_iter().cloned().collect(),
+                        columns_modified: modified_cols
+                            .into_iter()
+                            .map(|(old, new)| (old.clone(), new.clone()))
+                            .collect(),
                     }),
                 };
                 MirNode::new(
@@ -388,12 +416,14 @@ impl MirNode {
     }
 }
 
-/// Specifies the adapatation of an existing base node by column addition/removal.
+/// Specifies the adapatation of an existing base node by column addition/removal/modification.
 /// `over` is a `MirNode` of type `Base`.
 pub struct BaseNodeAdaptation {
     pub over: MirNodeRef,
     pub columns_added: Vec<ColumnSpecification>,
     pub columns_removed: Vec<ColumnSpecification>,
+    /// Columns whose type, default, or name changed, as `(old, new)` pairs.
+    pub columns_modified: Vec<(ColumnSpecification, ColumnSpecification)>,
 }
 
 pub enum MirNodeType {
@@ -488,6 +518,10 @@ pub enum MirNodeType {
         column: String,
         key: String,
     },
+    /// masked columns
+    Mask {
+        masks: Vec<(Column, ColumnMask)>,
+    },
 }
 
 impl MirNodeType {
@@ -763,6 +797,12 @@ impl MirNodeType {
                 } => (value == our_value && our_key == key && our_col == column),
                 _ => false,
             },
+            MirNodeType::Mask {
+                masks: ref our_masks,
+            } => match *other {
+                MirNodeType::Mask { ref masks } => masks == our_masks,
+                _ => false,
+            },
             _ => unimplemented!(),
         }
     }
@@ -1031,6 +1071,14 @@ impl Debug for MirNodeType {
                 write!(f, "{}", cols)
             }
             MirNodeType::Rewrite { ref column, .. } => write!(f, "Rw [{}]", column),
+            MirNodeType::Mask { ref masks } => {
+                let cols = masks
+                    .iter()
+                    .map(|&(ref c, ref m)| format!("{}: {}", c.name, m))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "Mask [{}]", cols)
+            }
         }
     }
 }
@@ -333,6 +333,17 @@ impl GraphViz for MirNodeType {
             MirNodeType::Rewrite { ref column, .. } => {
                 write!(out, "Rw | column: {}", column)?;
             }
+            MirNodeType::Mask { ref masks } => {
+                write!(
+                    out,
+                    "Mask | {}",
+                    masks
+                        .iter()
+                        .map(|&(ref c, ref m)| format!("{}: {}", print_col(c), m))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+            }
         }
         Ok(out)
     }
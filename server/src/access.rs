@@ -0,0 +1,485 @@
+//! Who may connect to a Noria deployment, and what they may do once connected.
+//!
+//! These settings are deliberately kept out of `Config`, since `Config` is stored in the
+//! authority in the clear. Principals are stored in the authority too, so only hashes of their
+//! tokens are kept there.
+
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::StatusCode;
+use noria::channel::auth;
+use noria::internal::secrets_match;
+use noria::{Principal, Role, TlsConfig};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// How long instances go on checking clients against the principals they last read.
+const PRINCIPALS_TTL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub(crate) struct AccessConfig {
+    /// Used for the controller's HTTP endpoint, and for client connections to base tables and
+    /// views.
+    pub(crate) tls: Option<TlsConfig>,
+    /// The token that gives clients every role. Access control is only enforced if this is set.
+    pub(crate) auth_token: Option<String>,
+    /// The roles of clients that present no token.
+    pub(crate) anonymous_roles: Vec<Role>,
+    /// The secret that processes must prove they know to connect to internal sockets.
+    pub(crate) worker_secret: Option<String>,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            tls: None,
+            auth_token: None,
+            anonymous_roles: vec![Role::Reader],
+            worker_secret: None,
+        }
+    }
+}
+
+/// Who a request to the controller claims to come from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Caller {
+    /// Presented the deployment's access token, or access control is disabled.
+    Root,
+    /// Presented some other token, identified by its hash.
+    Token(String),
+    /// Presented no token.
+    Anonymous,
+}
+
+/// Where instances look up the principals, for checks they make without asking the controller.
+///
+/// Reading the principals means reading and decoding the controller's state, so they are only read
+/// again once those read last are `PRINCIPALS_TTL` old. The read blocks, so it is done off the
+/// async executor, and callers that need the principals while they are being read wait for that
+/// read to finish instead of starting one of their own.
+#[derive(Clone)]
+pub(crate) struct PrincipalSource {
+    read: Arc<dyn Fn() -> BTreeMap<String, PrincipalEntry> + Send + Sync>,
+    ttl: Duration,
+    last: Arc<tokio::sync::Mutex<Option<(Instant, Arc<BTreeMap<String, PrincipalEntry>>)>>>,
+}
+
+impl PrincipalSource {
+    pub(crate) fn new<F>(read: F) -> Self
+    where
+        F: Fn() -> BTreeMap<String, PrincipalEntry> + Send + Sync + 'static,
+    {
+        PrincipalSource {
+            read: Arc::new(read),
+            ttl: PRINCIPALS_TTL,
+            last: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The principals, as read at most `PRINCIPALS_TTL` ago.
+    pub(crate) async fn get(&self) -> Arc<BTreeMap<String, PrincipalEntry>> {
+        let mut last = self.last.lock().await;
+        if let Some((at, ref principals)) = *last {
+            if at.elapsed() < self.ttl {
+                return principals.clone();
+            }
+        }
+        let read = self.read.clone();
+        let principals = match tokio::task::spawn_blocking(move || read()).await {
+            Ok(principals) => Arc::new(principals),
+            // nobody is let in by a principal we couldn't read, and we try again next time
+            Err(_) => return Default::default(),
+        };
+        *last = Some((Instant::now(), principals.clone()));
+        principals
+    }
+}
+
+/// A principal, as stored in the authority.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PrincipalEntry {
+    pub(crate) token_hash: String,
+    pub(crate) roles: Vec<Role>,
+}
+
+impl AccessConfig {
+    /// Work out who made a request with the given headers.
+    pub(crate) fn identify(&self, headers: &HeaderMap) -> Caller {
+        self.identify_token(
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer ")),
+        )
+    }
+
+    /// Work out who presented `given`, the token sent on a connection to a table or a view.
+    pub(crate) fn identify_token(&self, given: Option<&str>) -> Caller {
+        let root = match self.auth_token {
+            Some(ref token) => token,
+            None => return Caller::Root,
+        };
+
+        match given {
+            Some(given) if secrets_match(given.as_bytes(), root.as_bytes()) => Caller::Root,
+            Some(given) => Caller::Token(hash_token(given)),
+            None => Caller::Anonymous,
+        }
+    }
+
+    /// Check that `caller` has `role`, for requests that every instance serves without asking the
+    /// controller.
+    pub(crate) async fn authorize_role(
+        &self,
+        caller: &Caller,
+        role: Role,
+        principals: &PrincipalSource,
+    ) -> Result<(), StatusCode> {
+        match *caller {
+            Caller::Token(_) => self.authorize_with(caller, role, &principals.get().await),
+            _ => self.authorize_with(caller, role, &BTreeMap::new()),
+        }
+    }
+
+    /// Check that `caller` has `role`, given the principals that were last read.
+    fn authorize_with(
+        &self,
+        caller: &Caller,
+        role: Role,
+        principals: &BTreeMap<String, PrincipalEntry>,
+    ) -> Result<(), StatusCode> {
+        let (principal, roles) = resolve(caller, &self.anonymous_roles, || principals);
+        if roles.iter().any(|r| r.grants(role)) {
+            Ok(())
+        } else {
+            Err(denied(&principal))
+        }
+    }
+
+    /// Read the token that a client presents on a new connection to a table or a view, and only
+    /// let the connection through if the token grants `role`.
+    pub(crate) async fn admit<S>(
+        &self,
+        stream: &mut S,
+        role: Role,
+        principals: &PrincipalSource,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // the token is checked as soon as it has been read, so any principal it may name has to
+        // be known beforehand
+        let known = match self.auth_token {
+            Some(_) => principals.get().await,
+            None => Default::default(),
+        };
+        auth::admit(stream, |token| {
+            let caller = self.identify_token(token);
+            self.authorize_with(&caller, role, &known).is_ok()
+        })
+        .await
+    }
+}
+
+/// Work out which principal `caller` is, and which roles they have.
+///
+/// `principals` is only called for callers that presented a token other than the deployment's.
+pub(crate) fn resolve<F, P>(
+    caller: &Caller,
+    anonymous_roles: &[Role],
+    principals: F,
+) -> (Principal, Vec<Role>)
+where
+    F: FnOnce() -> P,
+    P: std::ops::Deref<Target = BTreeMap<String, PrincipalEntry>>,
+{
+    match *caller {
+        Caller::Root => (
+            Principal::Root,
+            vec![
+                Role::Reader,
+                Role::Writer,
+                Role::SchemaAdmin,
+                Role::SecurityAdmin,
+            ],
+        ),
+        Caller::Anonymous => (Principal::Anonymous, anonymous_roles.to_vec()),
+        Caller::Token(ref hash) => principals()
+            .iter()
+            .find(|(_, p)| p.token_hash == *hash)
+            .map(|(name, p)| (Principal::Named(name.clone()), p.roles.clone()))
+            .unwrap_or((Principal::Unknown, Vec::new())),
+    }
+}
+
+/// The status of the response to a request that `principal` may not make.
+pub(crate) fn denied(principal: &Principal) -> StatusCode {
+    match *principal {
+        // the caller has to authenticate (properly) first
+        Principal::Anonymous | Principal::Unknown => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::FORBIDDEN,
+    }
+}
+
+/// The role needed to use the controller endpoint at `path`, or `None` if there is no such
+/// endpoint.
+pub(crate) fn required_role(path: &str) -> Option<Role> {
+    let role = match path {
+        "/simple_graph"
+        | "/simple_graphviz"
+        | "/graph"
+        | "/graphviz"
+        | "/get_statistics"
+        | "/dashboard_nodes"
+        | "/dashboard_totals"
+        | "/replay_log"
+        | "/inputs"
+        | "/outputs"
+        | "/instances"
+        | "/nodes"
+        | "/view_builder"
+        | "/materialization_report"
+        | "/recipe_history" => Role::Reader,
+        "/table_builder" | "/universe_table_builder" => Role::Writer,
+        "/extend_recipe"
+        | "/explain_recipe"
+        | "/install_recipe"
+        | "/rollback_to"
+        | "/apply_materialization_advice"
+        | "/remove_node"
+        | "/move_domain"
+        | "/rebalance"
+        | "/reshard"
+        | "/flush_partial" => Role::SchemaAdmin,
+        "/set_security_config"
+        | "/create_universe"
+        | "/destroy_universe"
+        | "/set_principal"
+        | "/remove_principal"
+        | "/principals"
+        | "/audit_log" => Role::SecurityAdmin,
+        _ => return None,
+    };
+    Some(role)
+}
+
+/// The hash of `token` that is stored in place of the token itself.
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Make up a fresh access token.
+pub(crate) fn new_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn it_identifies_callers() {
+        let open = AccessConfig::default();
+        assert_eq!(open.identify(&HeaderMap::new()), Caller::Root);
+        assert_eq!(open.identify(&bearer("whatever")), Caller::Root);
+
+        let locked = AccessConfig {
+            auth_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(locked.identify(&HeaderMap::new()), Caller::Anonymous);
+        assert_eq!(locked.identify(&bearer("s3cret")), Caller::Root);
+        assert_eq!(
+            locked.identify(&bearer("s3cre")),
+            Caller::Token(hash_token("s3cre"))
+        );
+        assert_ne!(hash_token("s3cre"), hash_token("s3cret"));
+    }
+
+    #[test]
+    fn it_requires_roles_for_endpoints() {
+        assert_eq!(required_role("/view_builder"), Some(Role::Reader));
+        assert_eq!(required_role("/inputs"), Some(Role::Reader));
+        assert_eq!(required_role("/table_builder"), Some(Role::Writer));
+        assert_eq!(required_role("/extend_recipe"), Some(Role::SchemaAdmin));
+        assert_eq!(required_role("/explain_recipe"), Some(Role::SchemaAdmin));
+        assert_eq!(required_role("/remove_node"), Some(Role::SchemaAdmin));
+        assert_eq!(
+            required_role("/set_security_config"),
+            Some(Role::SecurityAdmin)
+        );
+        assert_eq!(required_role("/set_principal"), Some(Role::SecurityAdmin));
+        // endpoints nobody thought about aren't open to everyone
+        assert_eq!(required_role("/new_endpoint"), None);
+        assert_eq!(required_role("/view_builder/"), None);
+
+        assert!(Role::SchemaAdmin.grants(Role::Reader));
+        assert!(Role::SecurityAdmin.grants(Role::Reader));
+        assert!(!Role::Writer.grants(Role::Reader));
+        assert!(!Role::SchemaAdmin.grants(Role::Writer));
+        assert!(!Role::SchemaAdmin.grants(Role::SecurityAdmin));
+        assert!(!Role::SecurityAdmin.grants(Role::SchemaAdmin));
+    }
+
+    #[tokio::test]
+    async fn it_authorizes_roles_without_the_controller() {
+        let locked = AccessConfig {
+            auth_token: Some("s3cret".to_owned()),
+            anonymous_roles: vec![Role::Writer],
+            ..Default::default()
+        };
+        let principals = PrincipalSource::new(|| {
+            let mut principals = BTreeMap::new();
+            principals.insert(
+                "dashboard".to_owned(),
+                PrincipalEntry {
+                    token_hash: hash_token("dash"),
+                    roles: vec![Role::Reader],
+                },
+            );
+            principals.insert(
+                "ingest".to_owned(),
+                PrincipalEntry {
+                    token_hash: hash_token("ingest"),
+                    roles: vec![Role::Writer],
+                },
+            );
+            principals
+        });
+        let unread = PrincipalSource::new(|| unreachable!());
+
+        assert_eq!(
+            locked
+                .authorize_role(&Caller::Root, Role::Reader, &unread)
+                .await,
+            Ok(())
+        );
+        assert_eq!(
+            locked
+                .authorize_role(&Caller::Anonymous, Role::Reader, &unread)
+                .await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            locked
+                .authorize_role(&Caller::Anonymous, Role::Writer, &unread)
+                .await,
+            Ok(())
+        );
+        let dash = Caller::Token(hash_token("dash"));
+        let ingest = Caller::Token(hash_token("ingest"));
+        assert_eq!(
+            locked
+                .authorize_role(&dash, Role::Reader, &principals)
+                .await,
+            Ok(())
+        );
+        assert_eq!(
+            locked
+                .authorize_role(&dash, Role::Writer, &principals)
+                .await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            locked
+                .authorize_role(&ingest, Role::Reader, &principals)
+                .await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            locked
+                .authorize_role(&ingest, Role::Writer, &principals)
+                .await,
+            Ok(())
+        );
+        assert_eq!(
+            locked
+                .authorize_role(
+                    &Caller::Token(hash_token("guess")),
+                    Role::Reader,
+                    &principals
+                )
+                .await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        // without a token of its own, the deployment doesn't check anything
+        let open = AccessConfig::default();
+        assert_eq!(open.identify_token(None), Caller::Root);
+        assert_eq!(
+            open.authorize_role(&Caller::Anonymous, Role::Reader, &unread)
+                .await,
+            Ok(())
+        );
+        // but with one, anonymous clients may only read by default
+        let locked = AccessConfig {
+            auth_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(locked.identify_token(None), Caller::Anonymous);
+        assert_eq!(locked.identify_token(Some("s3cret")), Caller::Root);
+        assert_eq!(
+            locked
+                .authorize_role(&Caller::Anonymous, Role::Writer, &unread)
+                .await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reads_principals_at_most_once_a_ttl() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let source = |ttl| {
+            let reads = reads.clone();
+            PrincipalSource::new(move || {
+                reads.fetch_add(1, Ordering::SeqCst);
+                BTreeMap::new()
+            })
+            .with_ttl(ttl)
+        };
+
+        // copies share what was read
+        let principals = source(PRINCIPALS_TTL);
+        let copy = principals.clone();
+        assert!(principals.get().await.is_empty());
+        assert!(copy.get().await.is_empty());
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // and read again once that is too old
+        let principals = source(Duration::from_millis(10));
+        assert!(principals.get().await.is_empty());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reads.load(Ordering::SeqCst) < 3 {
+            assert!(
+                Instant::now() < deadline,
+                "principals were never read again"
+            );
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+            assert!(principals.get().await.is_empty());
+        }
+    }
+}
@@ -0,0 +1,105 @@
+extern crate clap;
+extern crate noria;
+
+use noria::{ConnectOptions, ControllerHandle, ZookeeperAuthority};
+use std::process;
+use std::time::Duration;
+
+fn main() {
+    use clap::{App, Arg};
+    let matches = App::new("noria-replays")
+        .version("0.0.1")
+        .about("Lists the partial replays that most recently filled holes in a Noria deployment.")
+        .arg(
+            Arg::with_name("zookeeper")
+                .short("z")
+                .long("zookeeper")
+                .takes_value(true)
+                .default_value("127.0.0.1:2181")
+                .help("Zookeeper connection info."),
+        )
+        .arg(
+            Arg::with_name("deployment")
+                .long("deployment")
+                .short("d")
+                .required(true)
+                .takes_value(true)
+                .help("Noria deployment ID."),
+        )
+        .arg(
+            Arg::with_name("auth-token")
+                .long("auth-token")
+                .takes_value(true)
+                .help("Access token of the deployment, if it has one."),
+        )
+        .arg(
+            Arg::with_name("min-duration")
+                .long("min-duration")
+                .takes_value(true)
+                .value_name("MS")
+                .help("Only list replays that took at least this many milliseconds."),
+        )
+        .get_matches();
+
+    let deployment = matches.value_of("deployment").unwrap();
+    let zookeeper_addr = format!("{}/{}", matches.value_of("zookeeper").unwrap(), deployment);
+    let min_duration = Duration::from_millis(
+        matches
+            .value_of("min-duration")
+            .map(|ms| {
+                ms.parse()
+                    .expect("--min-duration must be a number of milliseconds")
+            })
+            .unwrap_or(0),
+    );
+    let mut options = ConnectOptions::default();
+    if let Some(token) = matches.value_of("auth-token") {
+        options = options.with_auth_token(token);
+    }
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let log = rt.block_on(async move {
+        let authority = ZookeeperAuthority::new(&zookeeper_addr)?;
+        let mut ch = ControllerHandle::new_with(authority, options).await?;
+        ch.ready().await?;
+        ch.replay_log().await
+    });
+    let log = match log {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("failed to get replay log: {}", e);
+            process::exit(1);
+        }
+    };
+
+    println!(
+        "{:>8} {:>6} {:>6} {:>6} {:>6} {:>8} {:>12}  path",
+        "domain", "tag", "node", "keys", "rows", "ms", "finished"
+    );
+    for e in log.into_iter().filter(|e| e.duration >= min_duration) {
+        let finished = e
+            .finished
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path: Vec<_> = e
+            .path
+            .iter()
+            .map(|hop| match hop.partial_key {
+                Some(ref cols) => format!("{}{:?}", hop.node.index(), cols),
+                None => hop.node.index().to_string(),
+            })
+            .collect();
+        println!(
+            "{:>8} {:>6} {:>6} {:>6} {:>6} {:>8.1} {:>12}  {}",
+            format!("{}.{}", e.domain, e.shard),
+            e.tag,
+            e.node.index(),
+            e.keys,
+            e.rows,
+            e.duration.as_secs_f64() * 1000.0,
+            finished,
+            path.join(" -> ")
+        );
+    }
+}
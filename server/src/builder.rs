@@ -1,9 +1,15 @@
+use crate::access::AccessConfig;
 use crate::handle::Handle;
+use crate::tracing::TraceExport;
 use crate::Config;
 use crate::FrontierStrategy;
+use crate::PartialStorageStrategy;
 use crate::ReuseConfigType;
-use dataflow::PersistenceParameters;
+use dataflow::{
+    EvictionPolicy, PersistenceParameters, SampledLru, StorageBackend, StorageBackends,
+};
 use noria::consensus::{Authority, LocalAuthority};
+use noria::{Role, TlsConfig};
 use std::future::Future;
 use std::net::IpAddr;
 use std::sync::Arc;
@@ -14,7 +20,11 @@ pub struct Builder {
     config: Config,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    storage_backends: StorageBackends,
+    eviction_policy: Arc<dyn EvictionPolicy>,
+    tracing: Option<(f64, TraceExport)>,
     listen_addr: IpAddr,
+    access: AccessConfig,
     log: slog::Logger,
 }
 impl Default for Builder {
@@ -22,9 +32,13 @@ impl Default for Builder {
         Self {
             config: Config::default(),
             listen_addr: "127.0.0.1".parse().unwrap(),
+            access: AccessConfig::default(),
             log: slog::Logger::root(slog::Discard, o!()),
             memory_limit: None,
             memory_check_frequency: None,
+            storage_backends: StorageBackends::default(),
+            eviction_policy: Arc::new(SampledLru::default()),
+            tracing: None,
         }
     }
 }
@@ -43,6 +57,17 @@ impl Builder {
         self.config.domain_config.replay_batch_timeout = t;
     }
 
+    /// Set how often base tables with a retention period (`WITH (ttl = ...)`) are checked for
+    /// expired rows.
+    pub fn set_retention_interval(&mut self, t: time::Duration) {
+        self.config.domain_config.retention_interval = t;
+    }
+
+    /// Set how long a partial replay may take to fill a hole before it is logged as slow.
+    pub fn set_slow_replay_threshold(&mut self, t: time::Duration) {
+        self.config.domain_config.slow_replay_threshold = t;
+    }
+
     /// Set the persistence parameters used by the system.
     pub fn set_persistence(&mut self, p: PersistenceParameters) {
         self.config.persistence = p;
@@ -58,6 +83,11 @@ impl Builder {
         self.config.frontier_strategy = f;
     }
 
+    /// Which partially materialized internal nodes should keep their state on disk?
+    pub fn set_partial_storage_strategy(&mut self, s: PartialStorageStrategy) {
+        self.config.partial_storage = s;
+    }
+
     /// Set sharding policy for all subsequent migrations; `None` disables
     pub fn set_sharding(&mut self, shards: Option<usize>) {
         self.config.sharding = shards;
@@ -70,6 +100,21 @@ impl Builder {
         self.config.quorum = quorum;
     }
 
+    /// Periodically move domains from the busiest workers to the least busy ones, based on how
+    /// much time each domain spends processing. Disabled by default.
+    pub fn set_rebalance_interval(&mut self, every: time::Duration) {
+        assert_ne!(every, time::Duration::from_millis(0));
+        self.config.rebalance_every = Some(every);
+    }
+
+    /// Destroy user universes that no view has been read from for at least `timeout`, so that
+    /// users who have stopped using the system don't keep their copies of every query around.
+    /// Disabled by default.
+    pub fn set_universe_idle_timeout(&mut self, timeout: time::Duration) {
+        assert_ne!(timeout, time::Duration::from_millis(0));
+        self.config.universe_idle_timeout = Some(timeout);
+    }
+
     /// Set the memory limit (target) and how often we check it (in millis).
     pub fn set_memory_limit(&mut self, limit: usize, check_freq: time::Duration) {
         assert_ne!(limit, 0);
@@ -78,11 +123,78 @@ impl Builder {
         self.memory_check_frequency = Some(check_freq);
     }
 
+    /// Set how keys are chosen for eviction from partially materialized state when the memory
+    /// limit is exceeded. Defaults to `SampledLru`.
+    pub fn set_eviction_policy<P: EvictionPolicy + 'static>(&mut self, policy: P) {
+        self.eviction_policy = Arc::new(policy);
+    }
+
+    /// Make a storage backend available to base tables under the given name.
+    ///
+    /// Tables select a backend in the recipe, e.g. `CREATE TABLE ... WITH (storage = 'name')`.
+    /// The backend must be registered on every worker that may end up hosting such a table.
+    pub fn add_storage_backend<S, B>(&mut self, name: S, backend: B)
+    where
+        S: Into<String>,
+        B: StorageBackend + 'static,
+    {
+        self.storage_backends.register(name, backend);
+    }
+
+    /// Trace roughly a `sample_rate` fraction of the writes that reach this instance's base tables
+    /// through the data-flow, and export the spans that this instance's domains record for them
+    /// to `export`. Disabled by default.
+    pub fn set_tracing(&mut self, sample_rate: f64, export: TraceExport) {
+        assert!(
+            sample_rate > 0.0 && sample_rate <= 1.0,
+            "sample rate must be in (0, 1]"
+        );
+        self.tracing = Some((sample_rate, export));
+    }
+
     /// Set the IP address that the worker should use for listening.
     pub fn set_listen_addr(&mut self, listen_addr: IpAddr) {
         self.listen_addr = listen_addr;
     }
 
+    /// Serve the controller's HTTP endpoint, base tables and views over TLS, and encrypt all
+    /// traffic between workers, domains and the controller.
+    ///
+    /// `tls` must have an identity (see `TlsConfig::with_identity`), and clients must be given a
+    /// matching configuration through `ConnectOptions::with_tls`. Every server in the deployment
+    /// must use the same authority and server name, since they connect to one another with it.
+    /// If `tls` requires client certificates, only holders of a certificate signed by the same
+    /// authority can connect; otherwise, use `set_worker_secret` to keep processes outside the
+    /// deployment from connecting to the internal sockets.
+    pub fn set_tls(&mut self, tls: TlsConfig) {
+        self.access.tls = Some(tls);
+    }
+
+    /// Enable access control, with `token` as the token that holds every role.
+    ///
+    /// Clients present a token with `ConnectOptions::with_auth_token`, both to the controller and
+    /// when they connect to tables (which needs `Writer`) and views (which needs `Reader`). Other
+    /// principals and their tokens are managed with `ControllerHandle::set_principal`. Clients
+    /// that present no token get the roles set with `set_anonymous_roles`.
+    pub fn set_auth_token<S: Into<String>>(&mut self, token: S) {
+        self.access.auth_token = Some(token.into());
+    }
+
+    /// Set the roles of clients that present no token when access control is enabled. Defaults
+    /// to just `Reader`.
+    pub fn set_anonymous_roles(&mut self, roles: Vec<Role>) {
+        self.access.anonymous_roles = roles;
+    }
+
+    /// Only let processes started with the same `secret` connect to the deployment's internal
+    /// sockets.
+    ///
+    /// Connections between workers, domains and the controller must answer a challenge with an
+    /// HMAC keyed by the secret, so the secret itself is never sent over the network.
+    pub fn set_worker_secret<S: Into<String>>(&mut self, secret: S) {
+        self.access.worker_secret = Some(secret.into());
+    }
+
     /// Set the logger that the derived worker should use. By default, it uses `slog::Discard`.
     pub fn log_with(&mut self, log: slog::Logger) {
         self.log = log;
@@ -113,10 +225,18 @@ impl Builder {
             ref config,
             memory_limit,
             memory_check_frequency,
+            ref storage_backends,
+            ref eviction_policy,
+            ref tracing,
+            ref access,
             ref log,
         } = *self;
 
         let config = config.clone();
+        let storage_backends = storage_backends.clone();
+        let eviction_policy = eviction_policy.clone();
+        let tracing = tracing.clone();
+        let access = access.clone();
         let log = log.clone();
 
         crate::startup::start_instance(
@@ -125,6 +245,10 @@ impl Builder {
             config,
             memory_limit,
             memory_check_frequency,
+            storage_backends,
+            eviction_policy,
+            tracing,
+            access,
             log,
         )
     }
@@ -0,0 +1,314 @@
+//! Statistics about the data in the graph, and what they suggest about how to plan for it.
+//!
+//! Domains report how many rows each node's state holds, how the keys of each of its indices are
+//! distributed, and how lookups into it have fared. The controller sums these across shards, and
+//! uses them to estimate the sizes of joins when adding queries, and to advise on whether a node's
+//! state would be better off fully or partially materialized.
+
+use dataflow::prelude::*;
+use noria::debug::stats::{CacheStats, GraphStats};
+use noria::{MaterializationAdvice, Recommendation, TableStatistics};
+use std::collections::{BTreeMap, HashMap};
+
+/// Partial state needs at least this many lookups before its hit rate is taken into account.
+const MIN_LOOKUPS: u64 = 1_000;
+
+/// Partial state whose hit rate is below this is advised to be fully materialized.
+const MIN_HIT_RATE: f64 = 0.5;
+
+/// Partial state is only advised to be fully materialized if it takes fewer bytes than this.
+const SMALL_STATE: u64 = 64 * 1024 * 1024;
+
+/// Full state that takes more bytes than this is advised to be partially materialized.
+const LARGE_STATE: u64 = 1024 * 1024 * 1024;
+
+/// Sum the statistics that domains reported for each base table across its shards.
+///
+/// Each single-column index on a table tells us how its values are distributed. Shards are counted
+/// as if they held disjoint values, so for tables that aren't sharded by a column, its number of
+/// distinct values is overestimated.
+pub(super) fn table_statistics(
+    graph: &Graph,
+    stats: &GraphStats,
+) -> HashMap<String, TableStatistics> {
+    let mut tables: HashMap<String, TableStatistics> = HashMap::new();
+    for (_, nodes) in stats.values() {
+        for (&ni, ns) in nodes {
+            let n = match graph.node_weight(ni) {
+                Some(n) if n.is_base() && !n.is_dropped() => n,
+                _ => continue,
+            };
+
+            let table = tables.entry(n.name().to_owned()).or_default();
+            table.rows += ns.rows;
+            for index in &ns.indices {
+                let (col, distinct, max) = match (
+                    &index.columns[..],
+                    index.distinct_keys,
+                    index.max_rows_per_key,
+                ) {
+                    ([col], Some(distinct), Some(max)) => (*col, distinct, max),
+                    _ => continue,
+                };
+                let name = match n.fields().get(col) {
+                    Some(name) => name.clone(),
+                    None => continue,
+                };
+
+                let column = table.columns.entry(name).or_default();
+                column.distinct_values += distinct;
+                column.max_rows_per_value = column.max_rows_per_value.max(max);
+            }
+        }
+    }
+    tables
+}
+
+/// Work out which nodes' state would likely be better off materialized differently, given how
+/// large it is and how it has been read so far.
+///
+/// Partial state that most lookups miss, and that isn't being evicted from, is advised to be fully
+/// materialized, so that reads stop waiting for replays. Large full state is advised to be
+/// partially materialized, so that only the keys that are read take up memory.
+pub(super) fn advise(
+    graph: &Graph,
+    stats: &GraphStats,
+    partial_enabled: bool,
+) -> Vec<MaterializationAdvice> {
+    // sum each node's statistics across its shards
+    let mut totals: BTreeMap<NodeIndex, MaterializationAdvice> = BTreeMap::new();
+    for (_, nodes) in stats.values() {
+        for (&ni, ns) in nodes {
+            let n = match graph.node_weight(ni) {
+                Some(n) if !n.is_base() && !n.is_dropped() => n,
+                _ => continue,
+            };
+
+            let total = totals.entry(ni).or_insert_with(|| MaterializationAdvice {
+                node: ni,
+                name: n.name().to_owned(),
+                current: ns.materialized.clone(),
+                recommended: Recommendation::Full,
+                reason: String::new(),
+                rows: 0,
+                mem_size: 0,
+                cache: CacheStats::default(),
+            });
+            total.rows += ns.rows;
+            total.mem_size += ns.mem_size;
+            total.cache = total.cache + ns.cache;
+        }
+    }
+
+    totals
+        .into_iter()
+        .filter_map(|(_, mut advice)| {
+            let (recommended, reason) = match advice.current {
+                MaterializationStatus::Partial { .. } => {
+                    let lookups = advice.cache.hits + advice.cache.misses;
+                    let hit_rate = advice.cache.hit_rate()?;
+                    if lookups < MIN_LOOKUPS
+                        || hit_rate >= MIN_HIT_RATE
+                        || advice.cache.evictions != 0
+                        || advice.mem_size > SMALL_STATE
+                    {
+                        return None;
+                    }
+                    (
+                        Recommendation::Full,
+                        format!(
+                            "{:.0}% of {} lookups had to wait for a replay, and no keys have been \
+                             evicted to save memory",
+                            (1.0 - hit_rate) * 100.0,
+                            lookups
+                        ),
+                    )
+                }
+                MaterializationStatus::Full if advice.mem_size > LARGE_STATE => {
+                    let fix = if partial_enabled {
+                        "restructuring the query so that its keys can be traced back to its \
+                         ancestors' state"
+                    } else {
+                        "enabling partial materialization"
+                    };
+                    (
+                        Recommendation::Partial,
+                        format!(
+                            "the state takes {} bytes; consider {} so that only keys that are \
+                             read are kept",
+                            advice.mem_size, fix
+                        ),
+                    )
+                }
+                _ => return None,
+            };
+            advice.recommended = recommended;
+            advice.reason = reason;
+            Some(advice)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dataflow::node;
+    use dataflow::ops::{self, identity::Identity};
+    use noria::debug::stats::{DomainStats, IndexStats, NodeStats};
+
+    fn node_stats(
+        materialized: MaterializationStatus,
+        mem_size: u64,
+        cache: CacheStats,
+        indices: Vec<IndexStats>,
+    ) -> NodeStats {
+        NodeStats {
+            desc: String::new(),
+            process_time: 0,
+            process_ptime: 0,
+            mem_size,
+            materialized,
+            probe_result: HashMap::new(),
+            cache,
+            rows: indices.iter().filter_map(|i| i.rows).max().unwrap_or(0),
+            indices,
+            records: 0,
+        }
+    }
+
+    fn domain_stats() -> DomainStats {
+        DomainStats {
+            total_time: 0,
+            total_ptime: 0,
+            total_replay_time: 0,
+            total_forward_time: 0,
+            wait_time: 0,
+        }
+    }
+
+    fn graph() -> (Graph, NodeIndex, NodeIndex, NodeIndex) {
+        let mut g = Graph::new();
+        let source = g.add_node(node::Node::new(
+            "source",
+            &["because-type-inference"],
+            node::special::Source,
+        ));
+        let base = g.add_node(node::Node::new(
+            "t",
+            &["id", "category"],
+            node::special::Base::default(),
+        ));
+        g.add_edge(source, base, ());
+        let cached = g.add_node(node::Node::new(
+            "cached",
+            &["id", "category"],
+            ops::NodeOperator::Identity(Identity::new(base)),
+        ));
+        g.add_edge(base, cached, ());
+        let big = g.add_node(node::Node::new(
+            "big",
+            &["id", "category"],
+            ops::NodeOperator::Identity(Identity::new(base)),
+        ));
+        g.add_edge(base, big, ());
+        (g, base, cached, big)
+    }
+
+    #[test]
+    fn it_sums_table_statistics_across_shards() {
+        let (g, base, _, _) = graph();
+        let shard = |rows, distinct, max| {
+            let mut nodes = HashMap::new();
+            nodes.insert(
+                base,
+                node_stats(
+                    MaterializationStatus::Full,
+                    0,
+                    CacheStats::default(),
+                    vec![
+                        IndexStats {
+                            columns: vec![1],
+                            rows: Some(rows),
+                            distinct_keys: Some(distinct),
+                            max_rows_per_key: Some(max),
+                        },
+                        IndexStats {
+                            columns: vec![0, 1],
+                            rows: Some(rows),
+                            distinct_keys: Some(rows),
+                            max_rows_per_key: Some(1),
+                        },
+                    ],
+                ),
+            );
+            (domain_stats(), nodes)
+        };
+
+        let mut domains = HashMap::new();
+        domains.insert((0.into(), 0), shard(10, 2, 8));
+        domains.insert((0.into(), 1), shard(20, 5, 6));
+        let tables = table_statistics(&g, &GraphStats { domains });
+
+        assert_eq!(tables.len(), 1);
+        let t = &tables["t"];
+        assert_eq!(t.rows, 30);
+        // only single-column indices say anything about a column
+        assert_eq!(t.columns.len(), 1);
+        assert_eq!(t.columns["category"].distinct_values, 7);
+        assert_eq!(t.columns["category"].max_rows_per_value, 8);
+    }
+
+    #[test]
+    fn it_advises_on_materialization() {
+        let (g, base, cached, big) = graph();
+        let partial = MaterializationStatus::Partial {
+            beyond_materialization_frontier: false,
+        };
+        let missing = CacheStats {
+            hits: 100,
+            misses: 900,
+            evictions: 0,
+        };
+
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            base,
+            node_stats(
+                MaterializationStatus::Full,
+                2 * LARGE_STATE,
+                missing,
+                vec![],
+            ),
+        );
+        nodes.insert(cached, node_stats(partial.clone(), 1024, missing, vec![]));
+        nodes.insert(
+            big,
+            node_stats(
+                MaterializationStatus::Full,
+                2 * LARGE_STATE,
+                CacheStats::default(),
+                vec![],
+            ),
+        );
+        let mut domains = HashMap::new();
+        domains.insert((0.into(), 0), (domain_stats(), nodes));
+        let stats = GraphStats { domains };
+
+        // bases are always fully materialized, so they get no advice
+        let advice = advise(&g, &stats, true);
+        assert_eq!(advice.len(), 2);
+        assert_eq!(advice[0].name, "cached");
+        assert_eq!(advice[0].current, partial);
+        assert_eq!(advice[0].recommended, Recommendation::Full);
+        assert_eq!(advice[1].name, "big");
+        assert_eq!(advice[1].recommended, Recommendation::Partial);
+
+        // partial state that is being evicted from is short on memory, so shouldn't grow
+        let mut domains = stats.domains;
+        let (_, nodes) = domains.get_mut(&(0.into(), 0)).unwrap();
+        nodes.get_mut(&cached).unwrap().cache.evictions = 10;
+        let advice = advise(&g, &GraphStats { domains }, true);
+        assert_eq!(advice.len(), 1);
+        assert_eq!(advice[0].name, "big");
+    }
+}
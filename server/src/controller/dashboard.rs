@@ -0,0 +1,200 @@
+//! What the dashboard served at `/dashboard.html` shows about each node.
+//!
+//! The page polls the controller for what each node's domains report about it, and works out rates
+//! from how the cumulative counters change between polls. Whenever the graph changes, it fetches a
+//! summary of every node in the graph again, and draws the graph from the nodes' parents.
+
+use dataflow::prelude::*;
+use noria::debug::stats::GraphStats;
+use noria::MaterializationStatus;
+use petgraph::visit::Topo;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The totals that the dashboard polls for are gathered from the domains at most this often,
+/// however many dashboards are open.
+pub(super) const TOTALS_EVERY: Duration = Duration::from_secs(5);
+
+/// Everything the dashboard shows about a node.
+#[derive(Debug, Serialize)]
+pub(super) struct NodeSummary {
+    pub(super) node: usize,
+    pub(super) name: String,
+    pub(super) description: String,
+    pub(super) parents: Vec<usize>,
+    pub(super) domain: usize,
+    /// The worker each shard of the node's domain runs on.
+    pub(super) workers: Vec<String>,
+    pub(super) materialized: MaterializationStatus,
+    /// The SQL of the recipe expressions that the node is part of.
+    pub(super) queries: Vec<String>,
+    /// The MIR nodes the node was made from.
+    pub(super) mir: Vec<String>,
+}
+
+/// What the dashboard polls for.
+#[derive(Debug, Serialize)]
+pub(super) struct DashboardTotals {
+    /// Changes whenever the graph does, so that the dashboard knows to fetch its nodes again.
+    pub(super) generation: u64,
+    /// When the totals were gathered, in milliseconds since the Unix epoch.
+    pub(super) at: u64,
+    pub(super) nodes: HashMap<usize, Totals>,
+}
+
+/// What a node's domains reported about it, summed across shards.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub(super) struct Totals {
+    /// Bytes of state.
+    pub(super) state_bytes: u64,
+    /// Rows of state.
+    pub(super) rows: u64,
+    /// Wall-clock nanoseconds spent processing.
+    pub(super) process_time: u64,
+    /// Records processed, not counting replays.
+    pub(super) records: u64,
+}
+
+/// Sum the statistics that domains reported for each node across its shards.
+pub(super) fn totals(stats: &GraphStats) -> HashMap<NodeIndex, Totals> {
+    let mut totals: HashMap<NodeIndex, Totals> = HashMap::new();
+    for (_, nodes) in stats.values() {
+        for (&ni, ns) in nodes {
+            let t = totals.entry(ni).or_default();
+            t.state_bytes += ns.mem_size;
+            t.rows += ns.rows;
+            t.process_time += ns.process_time;
+            t.records += ns.records;
+        }
+    }
+    totals
+}
+
+/// The names of the queries whose leaves each node feeds into, as `leaf_queries` names the queries
+/// of a single node.
+///
+/// Works up the graph from its leaves, so that each edge is only followed once.
+pub(super) fn queries_below<F>(graph: &Graph, leaf_queries: F) -> HashMap<NodeIndex, Vec<String>>
+where
+    F: Fn(NodeIndex) -> Vec<String>,
+{
+    let mut order = Vec::new();
+    let mut topo = Topo::new(graph);
+    while let Some(ni) = topo.next(graph) {
+        order.push(ni);
+    }
+
+    let mut below: HashMap<NodeIndex, Vec<String>> = HashMap::new();
+    for ni in order.into_iter().rev() {
+        let mut names = leaf_queries(ni);
+        for child in graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing) {
+            names.extend(below[&child].iter().cloned());
+        }
+        names.sort();
+        names.dedup();
+        below.insert(ni, names);
+    }
+    below
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noria::debug::stats::{DomainStats, NodeStats};
+
+    fn node_stats(mem_size: u64, rows: u64, records: u64) -> NodeStats {
+        NodeStats {
+            desc: String::new(),
+            process_time: 10,
+            process_ptime: 0,
+            mem_size,
+            materialized: MaterializationStatus::Full,
+            probe_result: HashMap::new(),
+            cache: Default::default(),
+            rows,
+            indices: Vec::new(),
+            records,
+        }
+    }
+
+    fn domain_stats() -> DomainStats {
+        DomainStats {
+            total_time: 0,
+            total_ptime: 0,
+            total_replay_time: 0,
+            total_forward_time: 0,
+            wait_time: 0,
+        }
+    }
+
+    #[test]
+    fn it_sums_across_shards() {
+        let a = NodeIndex::new(1);
+        let b = NodeIndex::new(2);
+        let mut domains = HashMap::new();
+        for shard in 0..2 {
+            let mut nodes = HashMap::new();
+            nodes.insert(a, node_stats(100, 3, 7));
+            domains.insert((0.into(), shard), (domain_stats(), nodes));
+        }
+        let mut nodes = HashMap::new();
+        nodes.insert(b, node_stats(5, 1, 2));
+        domains.insert((1.into(), 0), (domain_stats(), nodes));
+
+        let totals = totals(&GraphStats { domains });
+        assert_eq!(
+            totals[&a],
+            Totals {
+                state_bytes: 200,
+                rows: 6,
+                process_time: 20,
+                records: 14,
+            }
+        );
+        assert_eq!(totals[&b].records, 2);
+        assert_eq!(totals.len(), 2);
+    }
+
+    #[test]
+    fn it_finds_queries_below_each_node() {
+        use dataflow::node;
+        use dataflow::ops::{self, identity::Identity};
+
+        // a base with two queries that share a node
+        let mut g = Graph::new();
+        let mut add = |name: &str, parents: &[NodeIndex]| {
+            let ni = match parents.first() {
+                None => g.add_node(node::Node::new(
+                    name,
+                    &["x"],
+                    node::special::Base::default(),
+                )),
+                Some(&p) => g.add_node(node::Node::new(
+                    name,
+                    &["x"],
+                    ops::NodeOperator::Identity(Identity::new(p)),
+                )),
+            };
+            for &p in parents {
+                g.add_edge(p, ni, ());
+            }
+            ni
+        };
+        let base = add("t", &[]);
+        let shared = add("shared", &[base]);
+        let q1 = add("q1", &[shared]);
+        let q2 = add("q2", &[shared]);
+        let q3 = add("q3", &[base]);
+
+        let below = queries_below(&g, |ni| {
+            if ni == q1 || ni == q2 || ni == q3 {
+                vec![g[ni].name().to_owned()]
+            } else {
+                vec![]
+            }
+        });
+        assert_eq!(below[&base], vec!["q1", "q2", "q3"]);
+        assert_eq!(below[&shared], vec!["q1", "q2"]);
+        assert_eq!(below[&q3], vec!["q3"]);
+    }
+}
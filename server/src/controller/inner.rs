@@ -1,10 +1,18 @@
+use crate::access::{self, AccessConfig, Caller, PrincipalEntry};
+use crate::controller::cost;
+use crate::controller::dashboard::{self, DashboardTotals, NodeSummary, Totals};
 use crate::controller::domain_handle::{DomainHandle, DomainShardHandle};
 use crate::controller::migrate::materialization::Materializations;
-use crate::controller::recipe::Schema;
+use crate::controller::rbac::{AccessControl, StoredAuditLog};
+use crate::controller::rebalance;
+use crate::controller::recipe::{self, Schema};
 use crate::controller::schema;
+use crate::controller::sql;
 use crate::controller::{ControllerState, Migration, Recipe};
 use crate::controller::{Worker, WorkerIdentifier};
 use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::metrics::ControllerGauges;
+use dataflow::ops::filter::FilterCondition;
 use dataflow::prelude::*;
 use dataflow::{node, payload::ControlReplyPacket, prelude::Packet, DomainBuilder, DomainConfig};
 use futures_util::stream::StreamExt;
@@ -12,17 +20,22 @@ use hyper::{self, Method, StatusCode};
 use nom_sql::ColumnSpecification;
 use noria::builders::*;
 use noria::channel::tcp::{SendError, TcpSender};
-use noria::consensus::{Authority, Epoch, STATE_KEY};
+use noria::consensus::{Authority, Epoch, AUDIT_LOG_KEY, STATE_KEY};
+use noria::debug::replays::ReplayLogEntry;
 use noria::debug::stats::{DomainStats, GraphStats, NodeStats};
-use noria::ActivationResult;
+use noria::error::RecipeError;
+use noria::{
+    ActivationResult, AuditEntry, MaterializationReport, RecipeChange, RecipeExplanation,
+    RecipeVersion, Recommendation, Role, TableStatistics, TlsConfig, WriteGrant,
+};
 use petgraph::visit::Bfs;
 use slog::Logger;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use std::{cell, io, time};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{cell, io, panic, time};
 
 /// `Controller` is the core component of the alternate Soup implementation.
 ///
@@ -44,6 +57,10 @@ pub(super) struct ControllerInner {
 
     /// Current recipe
     recipe: Recipe,
+    /// The most recent versions the recipe has gone through, oldest first, and the texts that
+    /// reproduce the version before them. Mirrors the copy kept in the authority.
+    recipe_history: Vec<RecipeVersion>,
+    recipe_history_base: Vec<String>,
 
     pub(super) domains: HashMap<DomainIndex, DomainHandle>,
     pub(in crate::controller) domain_nodes: HashMap<DomainIndex, Vec<NodeIndex>>,
@@ -64,6 +81,40 @@ pub(super) struct ControllerInner {
     heartbeat_every: Duration,
     healthcheck_every: Duration,
     last_checked_workers: Instant,
+    rebalance_every: Option<Duration>,
+    last_rebalanced: Instant,
+    /// How busy each domain shard was in the last rebalancing round.
+    last_busy: HashMap<(DomainIndex, usize), u64>,
+    universe_idle_timeout: Option<Duration>,
+    last_collected_universes: Instant,
+    /// How many lookups the views of each user universe had served when last checked, and when
+    /// that number last changed.
+    universe_reads: HashMap<DataType, (u64, Instant)>,
+    /// The token that each user universe's table handles present to bases with write policies,
+    /// and the bases that check its writes against them.
+    universe_writes: HashMap<DataType, (String, Vec<NodeIndex>)>,
+    /// The bases that only accept writes made on behalf of a universe.
+    restricted_bases: HashSet<NodeIndex>,
+    /// The bases of resharded tables, which pass the writes made with table handles fetched
+    /// before the table was resharded on to the base that replaced them.
+    retired_bases: HashSet<NodeIndex>,
+
+    /// Used to read from views through the same TLS-protected listeners that clients use.
+    tls: Option<TlsConfig>,
+    /// The token that the controller presents when it reads from views itself.
+    auth_token: Option<String>,
+    /// The key that hashing column masks are made with, so that the same value is masked the same
+    /// way across views and controllers.
+    pub(super) mask_key: String,
+    /// Who may use which endpoint.
+    access_control: AccessControl,
+
+    /// How many migrations and node removals the graph has gone through.
+    pub(super) graph_generation: u64,
+    /// The SQL of the queries that each node is part of, as of a generation of the graph.
+    dashboard_queries: Option<(u64, HashMap<NodeIndex, Vec<String>>)>,
+    /// The totals the dashboard last polled for, and when they were gathered.
+    dashboard_totals: Option<(SystemTime, HashMap<NodeIndex, Totals>)>,
 
     log: slog::Logger,
 
@@ -97,6 +148,22 @@ impl DomainReplies {
         }
     }
 
+    /// Wait for every shard of `d` to ready a node, and return why one of them could not.
+    pub(in crate::controller) async fn wait_for_ready(
+        &mut self,
+        d: &DomainHandle,
+    ) -> Result<(), String> {
+        let mut ready = Ok(());
+        for r in self.read_n_domain_replies(d.shards()).await {
+            match r {
+                ControlReplyPacket::Ack(_) => {}
+                ControlReplyPacket::Failed(e) => ready = Err(e),
+                r => unreachable!("got unexpected non-ack control reply: {:?}", r),
+            }
+        }
+        ready
+    }
+
     async fn wait_for_statistics(
         &mut self,
         d: &DomainHandle,
@@ -110,6 +177,17 @@ impl DomainReplies {
         }
         stats
     }
+
+    async fn wait_for_replay_log(&mut self, d: &DomainHandle) -> Vec<ReplayLogEntry> {
+        let mut log = Vec::new();
+        for r in self.read_n_domain_replies(d.shards()).await {
+            match r {
+                ControlReplyPacket::ReplayLog(entries) => log.extend(entries),
+                r => unreachable!("got unexpected non-replay-log control reply: {:?}", r),
+            }
+        }
+        log
+    }
 }
 
 pub(super) fn graphviz(
@@ -192,10 +270,15 @@ impl ControllerInner {
         path: String,
         query: Option<String>,
         body: hyper::body::Bytes,
+        caller: Caller,
         authority: &Arc<A>,
     ) -> Result<Result<String, String>, StatusCode> {
         use serde_json as json;
 
+        let authorized = self.access_control.authorize(&caller, &path);
+        self.store_audit_log(authority);
+        authorized?;
+
         match (&method, path.as_ref()) {
             (&Method::GET, "/simple_graph") => return Ok(Ok(self.graphviz(false))),
             (&Method::POST, "/simple_graphviz") => {
@@ -211,6 +294,39 @@ impl ControllerInner {
             (&Method::POST, "/get_statistics") => {
                 return Ok(Ok(json::to_string(&self.get_statistics()).unwrap()));
             }
+            (&Method::GET, "/dashboard_nodes") => {
+                return Ok(Ok(json::to_string(&self.dashboard_nodes()).unwrap()));
+            }
+            (&Method::GET, "/dashboard_totals") => {
+                return Ok(Ok(json::to_string(&self.dashboard_totals()).unwrap()));
+            }
+            (&Method::GET, "/replay_log") | (&Method::POST, "/replay_log") => {
+                return Ok(Ok(json::to_string(&self.replay_log()).unwrap()));
+            }
+            (&Method::POST, "/set_principal") => {
+                return json::from_slice(&body)
+                    .map_err(|_| StatusCode::BAD_REQUEST)
+                    .map(|(name, roles)| {
+                        self.set_principal(authority, name, roles)
+                            .map(|r| json::to_string(&r).unwrap())
+                    });
+            }
+            (&Method::POST, "/remove_principal") => {
+                return json::from_slice(&body)
+                    .map_err(|_| StatusCode::BAD_REQUEST)
+                    .map(|name| {
+                        self.remove_principal(authority, name)
+                            .map(|r| json::to_string(&r).unwrap())
+                    });
+            }
+            (&Method::POST, "/principals") => {
+                return Ok(Ok(json::to_string(&self.access_control.roles()).unwrap()));
+            }
+            (&Method::POST, "/audit_log") => {
+                return Ok(Ok(
+                    json::to_string(&self.access_control.audit_log()).unwrap()
+                ));
+            }
             _ => {}
         }
 
@@ -262,21 +378,42 @@ impl ControllerInner {
             (Method::POST, "/table_builder") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| Ok(json::to_string(&self.table_builder(args)).unwrap())),
+            (Method::POST, "/universe_table_builder") => {
+                let (name, universe): (String, DataType) =
+                    json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+                let authorized = self.access_control.authorize_universe(&caller, &universe);
+                self.store_audit_log(authority);
+                authorized?;
+                Ok(Ok(json::to_string(
+                    &self.universe_table_builder(&name, universe),
+                )
+                .unwrap()))
+            }
             (Method::POST, "/view_builder") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| Ok(json::to_string(&self.view_builder(args)).unwrap())),
             (Method::POST, "/extend_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
-                .map(|args| {
-                    self.extend_recipe(authority, args)
-                        .map(|r| json::to_string(&r).unwrap())
-                }),
+                .map(|args| Ok(json::to_string(&self.extend_recipe(authority, args)).unwrap())),
+            (Method::POST, "/explain_recipe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| Ok(json::to_string(&self.explain_recipe(args)).unwrap())),
             (Method::POST, "/install_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
-                .map(|args| {
-                    self.install_recipe(authority, args)
-                        .map(|r| json::to_string(&r).unwrap())
-                }),
+                .map(|args| Ok(json::to_string(&self.install_recipe(authority, args)).unwrap())),
+            (Method::POST, "/materialization_report") => {
+                Ok(Ok(json::to_string(&self.materialization_report()).unwrap()))
+            }
+            (Method::POST, "/apply_materialization_advice") => {
+                let applied = self.apply_materialization_advice();
+                Ok(Ok(json::to_string(&applied).unwrap()))
+            }
+            (Method::POST, "/recipe_history") => {
+                Ok(Ok(json::to_string(&self.recipe_history).unwrap()))
+            }
+            (Method::POST, "/rollback_to") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| Ok(json::to_string(&self.rollback_to(authority, args)).unwrap())),
             (Method::POST, "/set_security_config") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -289,24 +426,44 @@ impl ControllerInner {
                     self.create_universe(args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/destroy_universe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.destroy_universe(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::POST, "/remove_node") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
                     self.remove_nodes(vec![args].as_slice())
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/move_domain") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(domain, shard, worker)| {
+                    self.move_domain(domain, shard, worker)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/rebalance") => Ok(Ok(json::to_string(&self.rebalance()).unwrap())),
+            (Method::POST, "/reshard") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(name, shards): (String, usize)| {
+                    self.reshard(&name, shards)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             _ => Err(StatusCode::NOT_FOUND),
         }
     }
 
     pub(super) fn handle_register(&mut self, msg: CoordinationMessage) -> Result<(), io::Error> {
-        let (remote, read_listen_addr) = if let CoordinationPayload::Register {
+        let (remote, read_listen_addr, storage_backends) = if let CoordinationPayload::Register {
             addr: remote,
             read_listen_addr,
+            storage_backends,
             ..
         } = msg.payload
         {
-            (remote, read_listen_addr)
+            (remote, read_listen_addr, storage_backends)
         } else {
             unreachable!();
         };
@@ -316,11 +473,30 @@ impl ControllerInner {
             "new worker registered from {:?}, which listens on {:?}", msg.source, remote
         );
 
-        let sender = TcpSender::connect(&remote)?;
-        let ws = Worker::new(sender);
+        let mut sender = TcpSender::connect_tls(&remote, self.channel_coordinator.tls())?;
+        noria::channel::auth::prove(sender.get_mut(), self.channel_coordinator.secret())?;
+        let ws = Worker::new(sender, storage_backends);
         self.workers.insert(msg.source, ws);
         self.read_addrs.insert(msg.source, read_listen_addr);
 
+        // tell the new worker where existing domains live, so that domains can be moved to it
+        let w = self.workers.get_mut(&msg.source).unwrap();
+        for (&idx, d) in &self.domains {
+            for shard in 0..d.shards() {
+                if let Some(addr) = self.channel_coordinator.get_addr(&(idx, shard)) {
+                    w.sender
+                        .send(CoordinationMessage {
+                            epoch: self.epoch,
+                            source: w.sender.local_addr().unwrap(),
+                            payload: CoordinationPayload::DomainBooted(DomainDescriptor::new(
+                                idx, shard, addr,
+                            )),
+                        })
+                        .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?;
+                }
+            }
+        }
+
         if self.workers.len() >= self.quorum {
             if let Some((recipes, recipe_version)) = self.pending_recovery.take() {
                 assert_eq!(self.workers.len(), self.quorum);
@@ -413,6 +589,24 @@ impl ControllerInner {
         }
 
         self.check_worker_liveness();
+
+        if let Some(every) = self.rebalance_every {
+            if self.last_rebalanced.elapsed() >= every
+                && self.pending_recovery.is_none()
+                && self.workers.len() >= self.quorum
+            {
+                self.rebalance();
+            }
+        }
+
+        if let Some(timeout) = self.universe_idle_timeout {
+            if self.last_collected_universes.elapsed() >= timeout
+                && self.pending_recovery.is_none()
+                && self.workers.len() >= self.quorum
+            {
+                self.collect_idle_universes(timeout);
+            }
+        }
         Ok(())
     }
 
@@ -420,7 +614,9 @@ impl ControllerInner {
     pub(super) fn new(
         log: slog::Logger,
         state: ControllerState,
+        audit_log: Vec<AuditEntry>,
         drx: tokio::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
+        access: AccessConfig,
     ) -> Self {
         let mut g = petgraph::Graph::new();
         let source = g.add_node(node::Node::new(
@@ -434,8 +630,12 @@ impl ControllerInner {
             materializations.disable_partial()
         }
         materializations.set_frontier_strategy(state.config.frontier_strategy);
+        materializations.set_partial_storage_strategy(state.config.partial_storage);
 
-        let cc = Arc::new(ChannelCoordinator::new());
+        let cc = Arc::new(ChannelCoordinator::with_access(
+            access.worker_secret.clone(),
+            access.tls.clone(),
+        ));
         assert_ne!(state.config.quorum, 0);
 
         let pending_recovery = if !state.recipes.is_empty() {
@@ -447,6 +647,13 @@ impl ControllerInner {
         let mut recipe = Recipe::blank(Some(log.clone()));
         recipe.enable_reuse(state.config.reuse);
 
+        let access_control = AccessControl::new(
+            access.anonymous_roles,
+            state.principals,
+            audit_log,
+            log.clone(),
+        );
+
         ControllerInner {
             ingredients: g,
             source,
@@ -458,7 +665,11 @@ impl ControllerInner {
             persistence: state.config.persistence,
             heartbeat_every: state.config.heartbeat_every,
             healthcheck_every: state.config.healthcheck_every,
+            rebalance_every: state.config.rebalance_every,
+            universe_idle_timeout: state.config.universe_idle_timeout,
             recipe,
+            recipe_history: state.recipe_history,
+            recipe_history_base: state.recipe_history_base,
             quorum: state.config.quorum,
             log,
 
@@ -474,6 +685,21 @@ impl ControllerInner {
 
             pending_recovery,
             last_checked_workers: Instant::now(),
+            last_rebalanced: Instant::now(),
+            last_busy: HashMap::new(),
+            last_collected_universes: Instant::now(),
+            universe_reads: HashMap::default(),
+            universe_writes: HashMap::default(),
+            restricted_bases: HashSet::default(),
+            retired_bases: HashSet::default(),
+            tls: access.tls,
+            auth_token: access.auth_token,
+            mask_key: state.mask_key,
+            access_control,
+
+            graph_generation: 0,
+            dashboard_queries: None,
+            dashboard_totals: None,
 
             replies: DomainReplies(drx),
         }
@@ -538,6 +764,7 @@ impl ControllerInner {
                 config: self.domain_config.clone(),
                 nodes,
                 persistence_parameters: self.persistence.clone(),
+                snapshot: None,
             };
 
             let (identifier, w) = loop {
@@ -648,24 +875,40 @@ impl ControllerInner {
 
     /// Adds a new user universe.
     /// User universes automatically enforce security policies.
-    fn add_universe<F, T>(&mut self, context: HashMap<String, DataType>, f: F) -> T
+    ///
+    /// If `f` fails, the migration is not committed, and any nodes it added are removed again.
+    fn add_universe<F, T>(&mut self, context: HashMap<String, DataType>, f: F) -> Result<T, String>
     where
-        F: FnOnce(&mut Migration) -> T,
+        F: FnOnce(&mut Migration) -> Result<T, String>,
     {
         info!(self.log, "starting migration: new soup universe");
+        let graph = self.ingredients.clone();
         let miglog = self.log.new(o!());
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
             columns: Default::default(),
             readers: Default::default(),
+            sharding_hints: Default::default(),
+            partitions: Default::default(),
             context,
             start: time::Instant::now(),
             log: miglog,
         };
-        let r = f(&mut m);
-        m.commit();
-        r
+        match f(&mut m) {
+            Ok(r) => match m.commit() {
+                Ok(()) => Ok(r),
+                Err(e) => {
+                    crit!(self.log, "failed to commit migration: {}", e);
+                    Err(e)
+                }
+            },
+            Err(e) => {
+                self.ingredients = graph;
+                warn!(self.log, "universe migration aborted");
+                Err(e)
+            }
+        }
     }
 
     /// Perform a new query schema migration.
@@ -681,20 +924,76 @@ impl ControllerInner {
             added: Default::default(),
             columns: Default::default(),
             readers: Default::default(),
+            sharding_hints: Default::default(),
+            partitions: Default::default(),
             context: Default::default(),
             start: time::Instant::now(),
             log: miglog,
         };
         let r = f(&mut m);
-        m.commit();
+        if let Err(e) = m.commit() {
+            crit!(self.log, "failed to commit migration: {}", e);
+        }
         r
     }
 
+    /// Perform a new query schema migration, but only if `f` succeeds.
+    ///
+    /// If `f` fails, the migration is not committed, and any nodes it added are removed again.
+    fn try_migrate<F, T>(&mut self, f: F) -> Result<T, RecipeError>
+    where
+        F: FnOnce(&mut Migration) -> Result<T, RecipeError>,
+    {
+        info!(self.log, "starting migration");
+        let graph = self.ingredients.clone();
+        let miglog = self.log.new(o!());
+        let mut m = Migration {
+            mainline: self,
+            added: Default::default(),
+            columns: Default::default(),
+            readers: Default::default(),
+            sharding_hints: Default::default(),
+            partitions: Default::default(),
+            context: Default::default(),
+            start: time::Instant::now(),
+            log: miglog,
+        };
+        match f(&mut m) {
+            Ok(r) => match m.commit() {
+                Ok(()) => Ok(r),
+                Err(e) => {
+                    crit!(self.log, "failed to commit migration: {}", e);
+                    Err(RecipeError::Other(e))
+                }
+            },
+            Err(e) => {
+                self.ingredients = graph;
+                warn!(self.log, "migration aborted");
+                Err(e)
+            }
+        }
+    }
+
     #[cfg(test)]
     pub(crate) fn graph(&self) -> &Graph {
         &self.ingredients
     }
 
+    /// The size of the deployment, for the controller's metrics.
+    pub(super) fn gauges(&self) -> ControllerGauges {
+        let healthy_workers = self.workers.values().filter(|w| w.healthy).count();
+        ControllerGauges {
+            healthy_workers,
+            unhealthy_workers: self.workers.len() - healthy_workers,
+            domains: self.domains.len(),
+            nodes: self
+                .ingredients
+                .node_indices()
+                .filter(|&ni| ni != self.source && !self.ingredients[ni].is_dropped())
+                .count(),
+        }
+    }
+
     /// Get a Vec of all known input nodes.
     ///
     /// Input nodes are here all nodes of type `Table`. The addresses returned by this function will
@@ -702,6 +1001,7 @@ impl ControllerInner {
     fn inputs(&self) -> BTreeMap<String, NodeIndex> {
         self.ingredients
             .neighbors_directed(self.source, petgraph::EdgeDirection::Outgoing)
+            .filter(|&n| !self.ingredients[n].is_dropped() && !self.retired_bases.contains(&n))
             .map(|n| {
                 let base = &self.ingredients[n];
                 assert!(base.is_base());
@@ -774,12 +1074,14 @@ impl ControllerInner {
             let shards = (0..self.domains[&domain].shards())
                 .map(|i| self.read_addrs[&self.domains[&domain].assignment(i)])
                 .collect();
+            let partitioning = self.ingredients[r].sharded_by().partitioning();
 
             ViewBuilder {
                 node: r,
                 columns,
                 schema,
                 shards,
+                partitioning,
             }
         })
     }
@@ -808,19 +1110,7 @@ impl ControllerInner {
 
         trace!(self.log, "creating table"; "for" => base);
 
-        let mut key = self.ingredients[ni]
-            .suggest_indexes(ni)
-            .remove(&ni)
-            .unwrap_or_else(Vec::new);
-        let mut is_primary = false;
-        if key.is_empty() {
-            if let Sharding::ByColumn(col, _) = self.ingredients[ni].sharded_by() {
-                key = vec![col];
-            }
-        } else {
-            is_primary = true;
-        }
-
+        let (key, is_primary) = self.write_key(ni);
         let txs = (0..self.domains[&node.domain()].shards())
             .map(|i| {
                 self.channel_coordinator
@@ -854,15 +1144,51 @@ impl ControllerInner {
             addr: node.local_addr(),
             key,
             key_is_primary: is_primary,
+            partitioning: node.sharded_by().partitioning(),
             dropped: base_operator.get_dropped(),
             table_name: node.name().to_owned(),
             columns,
             schema,
+            grant: None,
         })
     }
 
+    /// The columns whose values pick the shard of base `ni` that a write goes to, and whether they
+    /// are the base's primary key.
+    fn write_key(&self, ni: NodeIndex) -> (Vec<usize>, bool) {
+        let key = self.ingredients[ni]
+            .suggest_indexes(ni)
+            .remove(&ni)
+            .unwrap_or_else(Vec::new);
+        if !key.is_empty() {
+            return (key, true);
+        }
+        let key = self.ingredients[ni]
+            .sharded_by()
+            .columns()
+            .unwrap_or_else(Vec::new);
+        (key, false)
+    }
+
+    /// Obtain a TableBuilder for writes to the named base on behalf of an existing user universe,
+    /// which the base checks against the universe's write policies.
+    fn universe_table_builder(&self, base: &str, universe: DataType) -> Option<TableBuilder> {
+        let token = self.universe_writes.get(&universe)?.0.clone();
+        let mut tb = self.table_builder(base)?;
+        tb.grant = Some(WriteGrant { universe, token });
+        Some(tb)
+    }
+
     /// Get statistics about the time spent processing different parts of the graph.
     fn get_statistics(&mut self) -> GraphStats {
+        self.domain_statistics(|_| true)
+    }
+
+    /// Get statistics from the domains that `wanted` picks.
+    fn domain_statistics<F>(&mut self, wanted: F) -> GraphStats
+    where
+        F: Fn(DomainIndex) -> bool,
+    {
         trace!(self.log, "asked to get statistics");
         let log = &self.log;
         let workers = &self.workers;
@@ -871,6 +1197,7 @@ impl ControllerInner {
         let domains = self
             .domains
             .iter_mut()
+            .filter(|&(&di, _)| wanted(di))
             .flat_map(|(&di, s)| {
                 trace!(log, "requesting stats from domain"; "di" => di.index());
                 s.send_to_healthy(Box::new(Packet::GetStatistics), workers)
@@ -885,6 +1212,160 @@ impl ControllerInner {
         GraphStats { domains }
     }
 
+    /// Summarize every node in the graph for the dashboard.
+    fn dashboard_nodes(&mut self) -> Vec<NodeSummary> {
+        let generation = self.graph_generation;
+        if self.dashboard_queries.as_ref().map(|&(g, _)| g) != Some(generation) {
+            // a node is part of the queries whose leaves it feeds into, and of its own
+            // expression if it has one (as bases do)
+            let recipe = &self.recipe;
+            let queries = dashboard::queries_below(&self.ingredients, |ni| {
+                recipe.queries_for_nodes(vec![ni])
+            })
+            .into_iter()
+            .map(|(ni, mut names)| {
+                names.push(self.ingredients[ni].name().to_owned());
+                (ni, recipe.sql_for_queries(&names))
+            })
+            .collect();
+            self.dashboard_queries = Some((generation, queries));
+        }
+        let queries = &self.dashboard_queries.as_ref().unwrap().1;
+        let mut mir = self.recipe.mir_by_flow_node();
+
+        self.ingredients
+            .node_indices()
+            .filter(|&ni| ni != self.source)
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .map(|ni| {
+                let n = &self.ingredients[ni];
+
+                let (domain, workers) = if n.has_domain() {
+                    let workers = self
+                        .domains
+                        .get(&n.domain())
+                        .map(|dh| {
+                            (0..dh.shards())
+                                .map(|shard| dh.assignment(shard).to_string())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    (n.domain().index(), workers)
+                } else {
+                    (0, Vec::new())
+                };
+
+                NodeSummary {
+                    node: ni.index(),
+                    name: n.name().to_owned(),
+                    description: n.description(true),
+                    parents: self
+                        .ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                        .filter(|&p| p != self.source)
+                        .map(|p| p.index())
+                        .collect(),
+                    domain,
+                    workers,
+                    materialized: self.materializations.get_status(ni, n),
+                    queries: queries.get(&ni).cloned().unwrap_or_default(),
+                    mir: mir.remove(&ni).unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// What each node's domains report about it, for the dashboard.
+    ///
+    /// The totals are only gathered from the domains again once they are `dashboard::TOTALS_EVERY`
+    /// old, so that open dashboards don't keep every domain busy reporting statistics.
+    fn dashboard_totals(&mut self) -> DashboardTotals {
+        let stale = match self.dashboard_totals {
+            Some((at, _)) => at
+                .elapsed()
+                .map_or(true, |age| age >= dashboard::TOTALS_EVERY),
+            None => true,
+        };
+        if stale {
+            let totals = dashboard::totals(&self.get_statistics());
+            self.dashboard_totals = Some((SystemTime::now(), totals));
+        }
+
+        let (at, ref totals) = *self.dashboard_totals.as_ref().unwrap();
+        DashboardTotals {
+            generation: self.graph_generation,
+            at: at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            nodes: totals.iter().map(|(ni, t)| (ni.index(), *t)).collect(),
+        }
+    }
+
+    /// The partial replays that most recently filled holes in each domain, oldest first.
+    fn replay_log(&mut self) -> Vec<ReplayLogEntry> {
+        let workers = &self.workers;
+        let replies = &mut self.replies;
+        let mut log: Vec<_> = self
+            .domains
+            .values_mut()
+            .flat_map(|s| {
+                s.send_to_healthy(Box::new(Packet::GetReplayLog), workers)
+                    .unwrap();
+                futures_executor::block_on(replies.wait_for_replay_log(&s))
+            })
+            .collect();
+        log.sort_by_key(|e| e.finished);
+        log
+    }
+
+    /// Cardinality statistics for the base tables, or none if some worker can't be reached.
+    fn table_statistics(&mut self) -> HashMap<String, TableStatistics> {
+        if self.workers.values().any(|w| !w.healthy) {
+            return HashMap::new();
+        }
+
+        // only the domains with base tables have anything to say about them
+        let domains: HashSet<_> = self
+            .ingredients
+            .node_indices()
+            .map(|ni| &self.ingredients[ni])
+            .filter(|n| n.is_base() && !n.is_dropped() && n.has_domain())
+            .map(|n| n.domain())
+            .collect();
+        let stats = self.domain_statistics(|di| domains.contains(&di));
+        cost::table_statistics(&self.ingredients, &stats)
+    }
+
+    fn materialization_report(&mut self) -> MaterializationReport {
+        let stats = self.get_statistics();
+        MaterializationReport {
+            tables: cost::table_statistics(&self.ingredients, &stats),
+            advice: cost::advise(
+                &self.ingredients,
+                &stats,
+                self.materializations.partial_enabled(),
+            ),
+        }
+    }
+
+    /// Fully materialize the nodes that are advised to be whenever they are next added, and
+    /// return their names.
+    fn apply_materialization_advice(&mut self) -> Vec<String> {
+        let names: Vec<_> = self
+            .materialization_report()
+            .advice
+            .into_iter()
+            .filter(|a| a.recommended == Recommendation::Full)
+            .map(|a| a.name)
+            .collect();
+        for name in &names {
+            info!(self.log, "will fully materialize \"{}\" from now on", name);
+            self.materializations.force_full(name.clone());
+        }
+        names
+    }
+
     fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
         self.workers
             .iter()
@@ -892,6 +1373,486 @@ impl ControllerInner {
             .collect()
     }
 
+    /// Move a shard of a running domain to another worker.
+    ///
+    /// The domain ships its nodes and fully materialized state to us, and we start it up on `to`.
+    /// The old instance holds on to the updates it receives in the meantime, and once its worker
+    /// learns where the new instance lives, it forwards those and anything that still arrives
+    /// later. Writes it forwards are acknowledged once the new instance has applied them. Other
+    /// domains switch over to the new instance as they learn where it is, builders handed out
+    /// from then on point at the new worker, and the old instance shuts down once nothing is
+    /// connected to it anymore.
+    ///
+    /// If the domain can't be started on `to`, it is started from the same snapshot on the worker
+    /// it came from instead, and the move fails.
+    pub(super) fn move_domain(
+        &mut self,
+        idx: DomainIndex,
+        shard: usize,
+        to: WorkerIdentifier,
+    ) -> Result<(), String> {
+        let from = match self.domains.get(&idx) {
+            Some(d) if shard < d.shards() => d.assignment(shard),
+            _ => return Err(format!("no domain {}.{}", idx.index(), shard)),
+        };
+        if from == to {
+            return Err(format!(
+                "domain {}.{} is already on worker {:?}",
+                idx.index(),
+                shard,
+                to
+            ));
+        }
+        let w = match self.workers.get(&to) {
+            Some(w) if w.healthy => w,
+            _ => return Err(format!("no healthy worker {:?}", to)),
+        };
+        // the bases in the domain have to be stored the same way on the new worker
+        if let Some(storage) = self.domain_nodes[&idx]
+            .iter()
+            .filter_map(|&ni| self.ingredients[ni].get_base())
+            .filter_map(|b| b.storage())
+            .find(|&s| !w.storage_backends.iter().any(|b| b == s))
+        {
+            return Err(format!(
+                "no storage backend named {} on worker {:?}",
+                storage, to
+            ));
+        }
+
+        info!(self.log, "moving domain {}.{}", idx.index(), shard; "from" => ?from, "to" => ?to);
+        // the new instance starts out with no time spent
+        self.last_busy.remove(&(idx, shard));
+        self.domains
+            .get_mut(&idx)
+            .unwrap()
+            .send_to_healthy_shard(shard, Box::new(Packet::Relocate), &self.workers)
+            .map_err(|e| e.to_string())?;
+        let domain = match futures_executor::block_on(self.replies.read_n_domain_replies(1)).pop() {
+            Some(ControlReplyPacket::Snapshot(Some(domain))) => domain,
+            Some(ControlReplyPacket::Snapshot(None)) => {
+                return Err(format!(
+                    "domain {}.{} is busy with a replay",
+                    idx.index(),
+                    shard
+                ));
+            }
+            crp => {
+                return Err(format!(
+                    "domain {}.{} did not send a snapshot, but {:?}",
+                    idx.index(),
+                    shard,
+                    crp
+                ));
+            }
+        };
+
+        let addr = match self.boot_snapshot(to, (*domain).clone()) {
+            Ok(addr) => addr,
+            Err(e) => {
+                // the old instance has already shipped off its state, so the domain is started
+                // again where it was, from the same snapshot. the new instance takes over from
+                // the old one just like a replacement on the other worker would have.
+                warn!(self.log, "could not boot moved domain {}.{}", idx.index(), shard;
+                      "to" => ?to, "error" => &e);
+                let addr = self.boot_snapshot(from, *domain).map_err(|e| {
+                    format!(
+                        "could not boot domain {}.{} on either worker: {}",
+                        idx.index(),
+                        shard,
+                        e
+                    )
+                })?;
+                self.announce_moved_domain(idx, shard, from, addr)?;
+                return Err(format!(
+                    "could not boot domain {}.{} on worker {:?}: {}",
+                    idx.index(),
+                    shard,
+                    to,
+                    e
+                ));
+            }
+        };
+        self.announce_moved_domain(idx, shard, to, addr)
+    }
+
+    /// Start a domain from a snapshot of it on worker `on`, and return where it listens.
+    fn boot_snapshot(
+        &mut self,
+        on: WorkerIdentifier,
+        domain: DomainBuilder,
+    ) -> Result<SocketAddr, String> {
+        let w = self
+            .workers
+            .get_mut(&on)
+            .ok_or_else(|| format!("no worker {:?}", on))?;
+        let src = w.sender.local_addr().map_err(|e| e.to_string())?;
+        w.sender
+            .send(CoordinationMessage {
+                epoch: self.epoch,
+                source: src,
+                payload: CoordinationPayload::AssignDomain(domain),
+            })
+            .map_err(|e| format!("could not reach worker {:?}: {}", on, e))?;
+        match futures_executor::block_on(self.replies.read_n_domain_replies(1)).pop() {
+            Some(ControlReplyPacket::Booted(_, addr)) => Ok(addr),
+            crp => Err(format!("domain did not boot, but sent {:?}", crp)),
+        }
+    }
+
+    /// Tell everyone that domain shard `idx.shard` now runs on worker `on` at `addr`.
+    fn announce_moved_domain(
+        &mut self,
+        idx: DomainIndex,
+        shard: usize,
+        on: WorkerIdentifier,
+        addr: SocketAddr,
+    ) -> Result<(), String> {
+        // announcing the new address also tells the old worker to hand off to it
+        self.channel_coordinator.insert_remote((idx, shard), addr);
+        let dd = DomainDescriptor::new(idx, shard, addr);
+        for (wi, endpoint) in self.workers.iter_mut() {
+            let source = match endpoint.sender.local_addr() {
+                Ok(source) => source,
+                Err(e) => {
+                    warn!(self.log, "could not tell worker where domain moved"; "worker" => ?wi, "error" => %e);
+                    continue;
+                }
+            };
+            // a worker we can't reach is dealt with once its heartbeats stop
+            if let Err(e) = endpoint.sender.send(CoordinationMessage {
+                epoch: self.epoch,
+                source,
+                payload: CoordinationPayload::DomainBooted(dd),
+            }) {
+                warn!(self.log, "could not tell worker where domain moved"; "worker" => ?wi, "error" => %e);
+            }
+        }
+
+        let tx = self
+            .channel_coordinator
+            .builder_for(&(idx, shard))
+            .ok_or_else(|| format!("no address for domain {}.{}", idx.index(), shard))?
+            .build_sync()
+            .map_err(|e| e.to_string())?;
+        self.domains
+            .get_mut(&idx)
+            .ok_or_else(|| format!("no domain {}", idx.index()))?
+            .shards[shard] = DomainShardHandle { worker: on, tx };
+        Ok(())
+    }
+
+    /// Move domain shards from the busiest workers to the least busy ones, and return the shards
+    /// that were moved along with where they went.
+    fn rebalance(&mut self) -> Vec<(DomainIndex, usize, WorkerIdentifier)> {
+        self.last_rebalanced = Instant::now();
+        if self.workers.len() < 2 || self.workers.values().any(|w| !w.healthy) {
+            return Vec::new();
+        }
+
+        let mut loads: HashMap<_, Vec<_>> = self.workers.keys().map(|&w| (w, Vec::new())).collect();
+        for (&(idx, shard), &(ref stats, _)) in &self.get_statistics().domains {
+            let worker = self.domains[&idx].assignment(shard);
+            let busy = rebalance::busy_time(stats);
+            let load = rebalance::load(busy, self.last_busy.insert((idx, shard), busy));
+            loads.get_mut(&worker).unwrap().push(((idx, shard), load));
+        }
+
+        let mut moved = Vec::new();
+        for (idx, shard, to) in rebalance::plan(loads) {
+            match self.move_domain(idx, shard, to) {
+                Ok(()) => moved.push((idx, shard, to)),
+                Err(e) => warn!(
+                    self.log,
+                    "could not move domain {}.{}: {}",
+                    idx.index(),
+                    shard,
+                    e
+                ),
+            }
+        }
+        moved
+    }
+
+    /// Change how many shards the table or view called `name` is split into.
+    ///
+    /// A view gets a new reader with the requested number of shards next to its old one. Reads
+    /// keep going to the old reader until the new one has been filled, at which point the old one
+    /// is removed; view handles fetched after that read from the new reader. A table is moved
+    /// onto a new base, along with the queries that read from it (see `reshard_table`).
+    fn reshard(&mut self, name: &str, shards: usize) -> Result<(), String> {
+        if shards == 0 {
+            return Err("shard count must be at least 1".to_owned());
+        }
+        if self.sharding.is_none() && shards != 1 {
+            return Err(format!(
+                "cannot split {} into {} shards, since sharding is disabled",
+                name, shards
+            ));
+        }
+
+        if let Some(&base) = self.inputs().get(name) {
+            return if self.ingredients[base].sharded_by().shards().unwrap_or(1) == shards {
+                Ok(())
+            } else {
+                self.reshard_table(name, base, shards)
+            };
+        }
+
+        let leaf = match self.recipe.node_addr_for(name) {
+            Ok(ni) => ni,
+            Err(_) => *self
+                .outputs()
+                .get(name)
+                .ok_or_else(|| format!("no view named {}", name))?,
+        };
+        let name = self.recipe.resolve_alias(name).unwrap_or(name).to_owned();
+        let old = self
+            .find_view_for(leaf, &name)
+            .ok_or_else(|| format!("view {} is not maintained", name))?;
+        let domain = self.ingredients[old].domain();
+        if self.domains[&domain].shards() == shards {
+            return Ok(());
+        }
+
+        let key = self.ingredients[old]
+            .with_reader(|r| r.key().map(<[usize]>::to_vec))
+            .unwrap()
+            .ok_or_else(|| format!("view {} has no key", name))?;
+        let memory_limit = self.ingredients[old].memory_limit;
+        let priority = self.ingredients[old].eviction_priority;
+
+        info!(self.log, "resharding view"; "view" => &name, "shards" => shards);
+        self.migrate(|mig| {
+            mig.maintain(name.clone(), leaf, &key);
+            mig.set_view_eviction(leaf, memory_limit, priority)?;
+            mig.set_view_sharding(leaf, shards)
+        })?;
+
+        // the new reader is in place, so take down the old one along with any shufflers that
+        // only fed it
+        let mut removals = vec![old];
+        let mut node = old;
+        loop {
+            let parent = self
+                .ingredients
+                .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
+                .next()
+                .unwrap();
+            let edge = self.ingredients.find_edge(parent, node).unwrap();
+            self.ingredients.remove_edge(edge);
+
+            if parent == leaf
+                || self
+                    .ingredients
+                    .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
+                    .count()
+                    != 0
+            {
+                break;
+            }
+            removals.push(parent);
+            node = parent;
+        }
+        self.remove_nodes(&removals)?;
+
+        self.recipe.set_view_shards(&name, shards);
+        Ok(())
+    }
+
+    /// Move the table `name`, whose base is `old`, onto a new base with `shards` shards.
+    ///
+    /// The new base is added next to the old one, and each shard of the old base sends its rows
+    /// straight to the shards of the new base, a batch at a time. From then on, the old base also
+    /// passes every write it takes on to the new base, so writes keep going through during the
+    /// move. Once the new base has all the rows, the queries that read from the table are planned
+    /// again on top of it, and the nodes of the old queries are removed; until then, reads keep
+    /// going to the old views. The old base then drops its rows, but stays around to pass on the
+    /// writes made with table handles fetched before the move. Handles fetched afterwards use the
+    /// new nodes.
+    ///
+    /// If the move fails part-way, the new base is removed and the old one carries on as before.
+    fn reshard_table(&mut self, name: &str, old: NodeIndex, shards: usize) -> Result<(), String> {
+        if let Sharding::ByRange(..) = self.ingredients[old].sharded_by() {
+            return Err(format!(
+                "cannot reshard table {}, which is split by its partition bounds",
+                name
+            ));
+        }
+        if self
+            .universe_writes
+            .values()
+            .any(|&(_, ref bases)| bases.contains(&old))
+        {
+            return Err(format!(
+                "cannot reshard table {} while user universes write to it",
+                name
+            ));
+        }
+
+        // the queries with a leaf below the base are the ones that read from it
+        let mut below = Vec::new();
+        let mut bfs = Bfs::new(&self.ingredients, old);
+        while let Some(ni) = bfs.next(&self.ingredients) {
+            if ni != old {
+                below.push(ni);
+            }
+        }
+        let dependents = self
+            .recipe
+            .queries_for_nodes(below)
+            .into_iter()
+            .filter(|q| q != name)
+            .collect();
+        let (mut without, mut with_table, mut resharded) =
+            self.recipe.make_reshard(name, shards, dependents)?;
+
+        info!(self.log, "resharding table"; "table" => name, "shards" => shards);
+        let checkpoint = self.recipe.sql_inc().checkpoint();
+        let replaced = self.try_migrate(|mig| -> Result<_, RecipeError> {
+            let replaced = without.activate(mig)?.removed_leaves;
+            with_table.set_prior(without.clone());
+            with_table.set_sql_inc(without.sql_inc().clone());
+            with_table.activate(mig)?;
+            Ok(replaced)
+        });
+        let replaced = match replaced {
+            Ok(replaced) => replaced,
+            Err(e) => {
+                self.recipe.set_sql_inc(checkpoint.restore());
+                return Err(format!("cannot reshard table {}: {}", name, e));
+            }
+        };
+        let new = with_table.node_addr_for(name)?;
+
+        if let Err(e) = self.forward_base(old, new) {
+            self.abandon_reshard(old, new, checkpoint);
+            return Err(format!("cannot move the rows of table {}: {}", name, e));
+        }
+
+        resharded.set_table_statistics(self.table_statistics());
+        resharded.set_prior(with_table.clone());
+        resharded.set_sql_inc(with_table.sql_inc().clone());
+        if let Err(e) = self.try_migrate(|mig| resharded.activate(mig)) {
+            self.abandon_reshard(old, new, checkpoint);
+            return Err(format!(
+                "cannot plan the queries on resharded table {}: {}",
+                name, e
+            ));
+        }
+        self.recipe = resharded;
+        self.restrict_writes();
+
+        // the old base stays behind for the writes of table handles fetched before the move
+        let replaced: Vec<_> = replaced.into_iter().filter(|&ni| ni != old).collect();
+        self.remove_leaves(&replaced)?;
+        let m = Box::new(Packet::RetireBase {
+            node: self.ingredients[old].local_addr(),
+        });
+        let domain = self
+            .domains
+            .get_mut(&self.ingredients[old].domain())
+            .unwrap();
+        match domain.send_to_healthy(m, &self.workers) {
+            Ok(()) => futures_executor::block_on(self.replies.wait_for_acks(&domain)),
+            Err(e) => warn!(self.log, "old base of resharded table kept its rows";
+                            "table" => name, "error" => ?e),
+        }
+        self.retired_bases.insert(old);
+
+        // the old queries' domains may have been left without nodes
+        self.shutdown_empty_domains();
+        Ok(())
+    }
+
+    /// Have each shard of base `old` send its rows to the shards of base `new`, which belongs to
+    /// the same table, and pass on every write it takes from then on. Returns once `new` holds
+    /// all the rows.
+    fn forward_base(&mut self, old: NodeIndex, new: NodeIndex) -> Result<(), String> {
+        let (old_domain, new_domain) = (
+            self.ingredients[old].domain(),
+            self.ingredients[new].domain(),
+        );
+        // the rows of a shard that can't be reached would never arrive
+        let workers = &self.workers;
+        let failed = [old_domain, new_domain].iter().any(|di| {
+            let d = &self.domains[di];
+            (0..d.shards()).any(|shard| !workers[&d.assignment(shard)].healthy)
+        });
+        if failed {
+            return Err("a worker that holds it has failed".to_owned());
+        }
+
+        // the new base has the table's current columns, while the old one still has the ones that
+        // were dropped along the way, and the ones that were added at the end
+        let fields = self.ingredients[old].fields();
+        let dropped = self.ingredients[old]
+            .get_base()
+            .expect("resharded table has non-base node")
+            .get_dropped();
+        let columns = self.ingredients[new]
+            .fields()
+            .iter()
+            .map(|f| {
+                (0..fields.len())
+                    .find(|&c| !dropped.contains_key(c) && fields[c] == *f)
+                    .ok_or_else(|| format!("the old base has no column {}", f))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let (key, _) = self.write_key(new);
+        let to = node::special::BaseForward {
+            node: self.ingredients[new].local_addr(),
+            domain: new_domain,
+            shards: self.domains[&new_domain].shards(),
+            key,
+            partitioning: self.ingredients[new].sharded_by().partitioning(),
+            columns,
+        };
+        // every shard of the new base hears from every shard of the old one
+        let copies = self.domains[&old_domain].shards() * to.shards;
+        let m = Box::new(Packet::ForwardBase {
+            node: self.ingredients[old].local_addr(),
+            to,
+        });
+        self.domains
+            .get_mut(&old_domain)
+            .unwrap()
+            .send_to_healthy(m, &self.workers)
+            .map_err(|e| e.to_string())?;
+        for r in futures_executor::block_on(self.replies.read_n_domain_replies(copies)) {
+            match r {
+                ControlReplyPacket::Ack(_) => {}
+                r => unreachable!("got unexpected non-ack control reply: {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo a reshard that failed after the table's new base `new` was added next to its old base
+    /// `old`: the old base stops passing writes on, the new one is removed, and the SQL
+    /// incorporator goes back to how it was at `checkpoint`.
+    fn abandon_reshard(&mut self, old: NodeIndex, new: NodeIndex, checkpoint: sql::Checkpoint) {
+        let m = Box::new(Packet::StopForwardingBase {
+            node: self.ingredients[old].local_addr(),
+        });
+        let domain = self
+            .domains
+            .get_mut(&self.ingredients[old].domain())
+            .unwrap();
+        match domain.send_to_healthy(m, &self.workers) {
+            Ok(()) => futures_executor::block_on(self.replies.wait_for_acks(&domain)),
+            Err(e) => warn!(self.log, "failed to stop base from forwarding writes"; "error" => ?e),
+        }
+
+        // writes already on their way to the new base are dropped along with it
+        if let Err(e) = self.remove_nodes(&[new]) {
+            warn!(self.log, "failed to remove base of abandoned reshard"; "error" => e);
+        }
+        self.recipe.set_sql_inc(checkpoint.restore());
+        self.shutdown_empty_domains();
+    }
+
     fn flush_partial(&mut self) -> u64 {
         // get statistics for current domain sizes
         // and evict all state from partial nodes
@@ -971,7 +1932,16 @@ impl ControllerInner {
                 let rgb: Option<ViewBuilder> = self.view_builder(&g);
                 // TODO: using block_on here _only_ works because View::lookup just waits on a
                 // channel, which doesn't use anything except the pure executor
-                let mut view = rgb.map(|rgb| rgb.build(x.clone()).unwrap()).unwrap();
+                let mut view = rgb
+                    .map(|rgb| {
+                        rgb.build(
+                            x.clone(),
+                            self.tls.as_ref(),
+                            self.auth_token.as_ref().map(String::as_str),
+                        )
+                        .unwrap()
+                    })
+                    .unwrap();
                 let my_groups: Vec<DataType> = futures_executor::block_on(view.lookup(uid, true))
                     .unwrap()
                     .iter()
@@ -981,86 +1951,245 @@ impl ControllerInner {
             }
         }
 
-        self.add_universe(context.clone(), |mut mig| {
-            r.next();
-            match r.create_universe(&mut mig, universe_groups) {
-                Ok(ar) => {
-                    info!(log, "{} expressions added", ar.expressions_added);
-                    info!(log, "{} expressions removed", ar.expressions_removed);
-                    Ok(())
-                }
-                Err(e) => {
-                    crit!(log, "failed to create universe: {:?}", e);
-                    Err("failed to create universe".to_owned())
-                }
+        // what the universe may write to each table with write policies
+        let mut writes = Vec::new();
+        if context.get("group").is_none() {
+            for table in self.recipe.write_policy_tables() {
+                let ni = self
+                    .recipe
+                    .node_addr_for(&table)
+                    .map_err(|_| format!("write policy on unknown table {}", table))?;
+                let conditions = self.recipe.write_conditions(
+                    &table,
+                    self.ingredients[ni].fields(),
+                    &context,
+                )?;
+                writes.push((ni, conditions));
             }
-            .unwrap();
+        }
+
+        // the universe's policies may fail to plan part-way through, so remember the incorporator
+        // state to put back if they do
+        let checkpoint = self.recipe.sql_inc().checkpoint();
+        let created = self.add_universe(context.clone(), |mut mig| {
+            r.next();
+            r.create_universe(&mut mig, universe_groups)
         });
 
+        match created {
+            Ok(ar) => {
+                info!(log, "{} expressions added", ar.expressions_added);
+                info!(log, "{} expressions removed", ar.expressions_removed);
+            }
+            Err(e) => {
+                crit!(log, "failed to create universe: {}", e);
+                self.recipe.set_sql_inc(checkpoint.restore());
+                return Err(e);
+            }
+        }
+
+        if context.get("group").is_none() {
+            // a universe that is never read from is idle from the moment it is created
+            self.universe_reads
+                .insert(uid[0].clone(), (0, Instant::now()));
+        }
+
+        if context.get("group").is_none() {
+            // the token proves to bases that the controller handed out the universe's grant
+            let token = access::new_token();
+            let bases = writes.iter().map(|&(ni, _)| ni).collect();
+            for (ni, conditions) in writes {
+                self.set_write_policies(ni, uid[0].clone(), Some((token.clone(), conditions)));
+            }
+            self.universe_writes.insert(uid[0].clone(), (token, bases));
+        }
+
         self.recipe = r;
         Ok(())
     }
 
-    fn set_security_config(&mut self, p: String) -> Result<(), String> {
-        self.recipe.set_security_config(&p);
+    /// Remove the universe identified by `context`, along with every node that was added for it,
+    /// and shut down any domains that are left without nodes.
+    pub(super) fn destroy_universe(
+        &mut self,
+        context: HashMap<String, DataType>,
+    ) -> Result<(), String> {
+        let id = context
+            .get("id")
+            .ok_or_else(|| "universe context must have an id".to_owned())?
+            .clone();
+        let group = context.get("group").cloned();
+        if group.is_none() {
+            self.universe_reads.remove(&id);
+            // the universe's table handles may outlive it, so they lose the right to write
+            let (_, bases) = self.universe_writes.remove(&id).unwrap_or_default();
+            for ni in bases {
+                if !self.ingredients[ni].is_dropped() {
+                    self.set_write_policies(ni, id.clone(), None);
+                }
+            }
+        }
+
+        let mut r = self.recipe.clone();
+        let ar = r.destroy_universe(id.clone(), group)?;
+        info!(
+            self.log,
+            "destroying universe {}", id.to_string();
+            "leaves" => ar.removed_leaves.len(),
+        );
+
+        self.remove_leaves(&ar.removed_leaves)?;
+        self.recipe = r;
+
+        // the universe's views tend to have domains of their own, and nodes removed further up
+        // may have emptied others too
+        self.shutdown_empty_domains();
         Ok(())
     }
 
-    fn apply_recipe(&mut self, mut new: Recipe) -> Result<ActivationResult, String> {
-        let r = self.migrate(|mig| {
-            new.activate(mig)
-                .map_err(|e| format!("failed to activate recipe: {}", e))
+    /// Tell the domain of base `ni` which rows `universe` may write to it, and with which token.
+    fn set_write_policies(
+        &mut self,
+        ni: NodeIndex,
+        universe: DataType,
+        policies: Option<(String, Vec<Vec<(usize, FilterCondition)>>)>,
+    ) {
+        self.restricted_bases.insert(ni);
+        let m = Box::new(Packet::SetWritePolicies {
+            node: self.ingredients[ni].local_addr(),
+            universe,
+            policies,
         });
+        let domain = self
+            .domains
+            .get_mut(&self.ingredients[ni].domain())
+            .unwrap();
+        domain.send_to_healthy(m, &self.workers).unwrap();
+        futures_executor::block_on(self.replies.wait_for_acks(&domain));
+    }
 
-        match r {
-            Ok(ref ra) => {
-                let (removed_bases, removed_other): (Vec<_>, Vec<_>) = ra
-                    .removed_leaves
-                    .iter()
-                    .cloned()
-                    .partition(|ni| self.ingredients[*ni].is_base());
-
-                // first remove query nodes in reverse topological order
-                let mut topo_removals = Vec::with_capacity(removed_other.len());
-                let mut topo = petgraph::visit::Topo::new(&self.ingredients);
-                while let Some(node) = topo.next(&self.ingredients) {
-                    if removed_other.contains(&node) {
-                        topo_removals.push(node);
-                    }
-                }
-                topo_removals.reverse();
+    /// Make the bases of tables with write policies refuse writes that aren't made on behalf of a
+    /// universe, even before any universe exists.
+    fn restrict_writes(&mut self) {
+        for table in self.recipe.write_policy_tables() {
+            let ni = match self.recipe.node_addr_for(&table) {
+                Ok(ni) if !self.restricted_bases.contains(&ni) => ni,
+                // the table may not have been created yet
+                _ => continue,
+            };
+            self.restricted_bases.insert(ni);
+            let m = Box::new(Packet::RestrictWrites {
+                node: self.ingredients[ni].local_addr(),
+            });
+            let domain = self
+                .domains
+                .get_mut(&self.ingredients[ni].domain())
+                .unwrap();
+            domain.send_to_healthy(m, &self.workers).unwrap();
+            futures_executor::block_on(self.replies.wait_for_acks(&domain));
+        }
+    }
 
-                for leaf in topo_removals {
-                    self.remove_leaf(leaf)?;
+    /// Destroy every user universe whose views haven't served any lookups for `timeout`.
+    fn collect_idle_universes(&mut self, timeout: Duration) -> Vec<DataType> {
+        self.last_collected_universes = Instant::now();
+
+        // lookups served by the reader of each query leaf
+        let stats = self.get_statistics();
+        let mut lookups: HashMap<NodeIndex, u64> = HashMap::new();
+        for (_, nodes) in stats.domains.values() {
+            for (&ni, ns) in nodes {
+                if let Ok(leaf) = self.ingredients[ni].with_reader(|r| r.is_for()) {
+                    *lookups.entry(leaf).or_default() += ns.cache.hits + ns.cache.misses;
                 }
+            }
+        }
 
-                // now remove bases
-                for base in removed_bases {
-                    // TODO(malte): support removing bases that still have children?
-                    let children: Vec<NodeIndex> = self
-                        .ingredients
-                        .neighbors_directed(base, petgraph::EdgeDirection::Outgoing)
-                        .collect();
-                    // TODO(malte): what about domain crossings? can ingress/egress nodes be left
-                    // behind?
-                    assert_eq!(children.len(), 0);
-                    debug!(
-                        self.log,
-                        "Removing base \"{}\"",
-                        self.ingredients[base].name();
-                        "node" => base.index(),
-                    );
-                    // now drop the (orphaned) base
-                    self.remove_nodes(vec![base].as_slice()).unwrap();
-                }
+        let universes = self.recipe.user_universes();
+        self.universe_reads
+            .retain(|id, _| universes.contains_key(id));
+
+        let now = Instant::now();
+        let mut idle = Vec::new();
+        for (id, leaves) in universes {
+            let reads = leaves.iter().filter_map(|leaf| lookups.get(leaf)).sum();
+            let last = self
+                .universe_reads
+                .entry(id.clone())
+                .or_insert((reads, now));
+            if last.0 != reads {
+                *last = (reads, now);
+            } else if now.duration_since(last.1) >= timeout {
+                idle.push(id);
+            }
+        }
 
+        for id in &idle {
+            info!(self.log, "universe {} is idle", id.to_string());
+            let mut context = HashMap::new();
+            context.insert("id".to_owned(), id.clone());
+            if let Err(e) = self.destroy_universe(context) {
+                crit!(
+                    self.log,
+                    "failed to destroy idle universe {}: {}",
+                    id.to_string(),
+                    e
+                );
+            }
+        }
+        idle
+    }
+
+    /// Shut down every domain whose nodes have all been removed.
+    fn shutdown_empty_domains(&mut self) {
+        let empty: Vec<DomainIndex> = self
+            .domain_nodes
+            .iter()
+            .filter(|(_, nodes)| nodes.iter().all(|&ni| self.ingredients[ni].is_dropped()))
+            .map(|(&di, _)| di)
+            .collect();
+        for di in empty {
+            self.shutdown_domain(di);
+        }
+    }
+
+    /// Stop all shards of a domain that no longer has any nodes, and forget about it.
+    fn shutdown_domain(&mut self, di: DomainIndex) {
+        info!(self.log, "shutting down empty domain {}", di.index());
+        if let Some(mut d) = self.domains.remove(&di) {
+            // don't unwrap, because shards on failed workers are gone already
+            drop(d.send_to_healthy(Box::new(Packet::Quit), &self.workers));
+        }
+        self.domain_nodes.remove(&di);
+        self.remap.remove(&di);
+    }
+
+    fn set_security_config(&mut self, p: String) -> Result<(), String> {
+        self.recipe.set_security_config(&p)?;
+        self.restrict_writes();
+        Ok(())
+    }
+
+    fn apply_recipe(&mut self, mut new: Recipe) -> Result<ActivationResult, RecipeError> {
+        // order the joins of new queries by how large the tables they read from are now
+        new.set_table_statistics(self.table_statistics());
+
+        // activation may fail half-way through adding the new queries, so remember the
+        // incorporator state to put back if it does
+        let checkpoint = new.sql_inc().checkpoint();
+        let r = self.try_migrate(|mig| new.activate(mig));
+
+        match r {
+            Ok(ref ra) => {
+                self.remove_leaves(&ra.removed_leaves)
+                    .map_err(RecipeError::Other)?;
                 self.recipe = new;
+                self.restrict_writes();
             }
             Err(ref e) => {
                 crit!(self.log, "failed to apply recipe: {}", e);
-                // TODO(malte): a little yucky, since we don't really need the blank recipe
-                let recipe = mem::replace(&mut self.recipe, Recipe::blank(None));
-                self.recipe = recipe.revert();
+                self.recipe = new.revert();
+                self.recipe.set_sql_inc(checkpoint.restore());
             }
         }
 
@@ -1071,34 +2200,85 @@ impl ControllerInner {
         &mut self,
         authority: &Arc<A>,
         add_txt: String,
-    ) -> Result<ActivationResult, String> {
+    ) -> Result<ActivationResult, RecipeError> {
         // needed because self.apply_recipe needs to mutate self.recipe, so can't have it borrowed
         let new = mem::replace(&mut self.recipe, Recipe::blank(None));
         match new.extend(&add_txt) {
             Ok(new) => {
-                let activation_result = self.apply_recipe(new);
-                if authority
-                    .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
-                        None => unreachable!(),
-                        Some(ref state) if state.epoch > self.epoch => Err(()),
-                        Some(mut state) => {
-                            state.recipe_version = self.recipe.version();
-                            state.recipes.push(add_txt.clone());
-                            Ok(state)
-                        }
-                    })
-                    .is_err()
-                {
-                    return Err("Failed to persist recipe extension".to_owned());
-                }
+                let activation_result = self.apply_recipe(new)?;
+                self.persist_recipe(
+                    authority,
+                    RecipeChange::Extend,
+                    add_txt.clone(),
+                    |recipes| recipes.push(add_txt.clone()),
+                )
+                .map_err(|_| RecipeError::Other("Failed to persist recipe extension".to_owned()))?;
 
-                activation_result
+                Ok(activation_result)
             }
             Err((old, e)) => {
                 // need to restore the old recipe
                 crit!(self.log, "failed to extend recipe: {:?}", e);
                 self.recipe = old;
-                Err("failed to extend recipe".to_owned())
+                Err(RecipeError::Parse(e))
+            }
+        }
+    }
+
+    /// Work out what extending the recipe with `add_txt` would do, without changing anything.
+    ///
+    /// The new queries are planned against the live graph, and the graph is then put back the way
+    /// it was. Queries that can't be planned, including ones that make the planner panic, are
+    /// reported as errors.
+    fn explain_recipe(&mut self, add_txt: String) -> Result<RecipeExplanation, RecipeError> {
+        let mut new = self
+            .recipe
+            .clone()
+            .extend(&add_txt)
+            .map_err(|(_, e)| RecipeError::Parse(e))?;
+
+        // how much state each existing node holds, across all of its shards
+        let stats = self.get_statistics();
+        let mut sizes = HashMap::new();
+        for (_, nodes) in stats.values() {
+            for (&ni, ns) in nodes {
+                *sizes.entry(ni).or_insert(0) += ns.mem_size;
+            }
+        }
+        new.set_table_statistics(cost::table_statistics(&self.ingredients, &stats));
+
+        info!(self.log, "starting dry-run migration");
+        let graph = self.ingredients.clone();
+        // the copy of the recipe shares MIR nodes with the live one, so those need putting back too
+        let checkpoint = self.recipe.sql_inc().checkpoint();
+        let miglog = self.log.new(o!());
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut m = Migration {
+                mainline: &mut *self,
+                added: Default::default(),
+                columns: Default::default(),
+                readers: Default::default(),
+                sharding_hints: Default::default(),
+                partitions: Default::default(),
+                context: Default::default(),
+                start: time::Instant::now(),
+                log: miglog,
+            };
+            let ra = new.activate(&mut m)?;
+            Ok(m.explain(&ra.new_nodes, &sizes))
+        }));
+        self.ingredients = graph;
+        checkpoint.restore();
+
+        match r {
+            Ok(r) => r,
+            Err(e) => {
+                let e = recipe::panic_message(&*e);
+                crit!(self.log, "recipe cannot be planned: {}", e);
+                Err(RecipeError::Other(format!(
+                    "recipe cannot be planned: {}",
+                    e
+                )))
             }
         }
     }
@@ -1107,31 +2287,196 @@ impl ControllerInner {
         &mut self,
         authority: &Arc<A>,
         r_txt: String,
-    ) -> Result<ActivationResult, String> {
+    ) -> Result<ActivationResult, RecipeError> {
         match Recipe::from_str(&r_txt, Some(self.log.clone())) {
             Ok(r) => {
+                r.check_alterations().map_err(RecipeError::Parse)?;
                 let old = mem::replace(&mut self.recipe, Recipe::blank(None));
                 let new = old.replace(r).unwrap();
-                let activation_result = self.apply_recipe(new);
-                if authority
-                    .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
-                        None => unreachable!(),
-                        Some(ref state) if state.epoch > self.epoch => Err(()),
-                        Some(mut state) => {
-                            state.recipe_version = self.recipe.version();
-                            state.recipes = vec![r_txt.clone()];
-                            Ok(state)
-                        }
-                    })
-                    .is_err()
-                {
-                    return Err("Failed to persist recipe installation".to_owned());
-                }
-                activation_result
+                let activation_result = self.apply_recipe(new)?;
+                self.persist_recipe(authority, RecipeChange::Install, r_txt.clone(), |recipes| {
+                    *recipes = vec![r_txt.clone()]
+                })
+                .map_err(|_| {
+                    RecipeError::Other("Failed to persist recipe installation".to_owned())
+                })?;
+                Ok(activation_result)
             }
             Err(e) => {
                 crit!(self.log, "failed to parse recipe: {:?}", e);
-                Err("failed to parse recipe".to_owned())
+                Err(RecipeError::Parse(e))
+            }
+        }
+    }
+
+    /// Return the recipe to how it was at the earlier `version`.
+    ///
+    /// The target recipe is rebuilt by replaying the stored recipe texts that produced it, and
+    /// then replaces the current recipe, so queries added since are removed and queries removed
+    /// since are added back. The rollback itself becomes a new version of the recipe.
+    fn rollback_to<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        version: usize,
+    ) -> Result<ActivationResult, RecipeError> {
+        if version >= self.recipe.version() {
+            return Err(RecipeError::Other(format!(
+                "cannot roll back to version {}, as the current version is {}",
+                version,
+                self.recipe.version()
+            )));
+        }
+
+        let texts = recipe::replay_texts(&self.recipe_history_base, &self.recipe_history, version)
+            .map_err(RecipeError::Other)?;
+        let target = Recipe::replay(&texts, Some(self.log.clone())).map_err(RecipeError::Parse)?;
+
+        info!(self.log, "rolling back recipe to version {}", version);
+        let old = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let new = old.replace(target).unwrap();
+        let activation_result = self.apply_recipe(new)?;
+        self.persist_recipe(
+            authority,
+            RecipeChange::Rollback(version),
+            texts.join("\n"),
+            |recipes| *recipes = texts.clone(),
+        )
+        .map_err(|_| RecipeError::Other("Failed to persist recipe rollback".to_owned()))?;
+        Ok(activation_result)
+    }
+
+    /// Record the recipe's new version, both locally and in the authority, so that it survives
+    /// controller failover. `update` changes the recipe texts that recovery replays.
+    fn persist_recipe<A, F>(
+        &mut self,
+        authority: &Arc<A>,
+        change: RecipeChange,
+        text: String,
+        update: F,
+    ) -> Result<(), ()>
+    where
+        A: Authority + 'static,
+        F: Fn(&mut Vec<String>),
+    {
+        let version = RecipeVersion {
+            version: self.recipe.version(),
+            change,
+            text,
+            applied_at: SystemTime::now(),
+        };
+        let epoch = self.epoch;
+        match authority.read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+            None => unreachable!(),
+            Some(ref state) if state.epoch > epoch => Err(()),
+            Some(mut state) => {
+                state.recipe_version = version.version;
+                update(&mut state.recipes);
+                state.recipe_history.push(version.clone());
+                recipe::prune_history(
+                    &mut state.recipe_history_base,
+                    &mut state.recipe_history,
+                    recipe::HISTORY_LEN,
+                    recipe::HISTORY_BYTES,
+                );
+                Ok(state)
+            }
+        }) {
+            Ok(Ok(state)) => {
+                self.recipe_history = state.recipe_history;
+                self.recipe_history_base = state.recipe_history_base;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Give the principal `name` exactly `roles`, and return its new token if it didn't exist.
+    fn set_principal<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        name: String,
+        roles: Vec<Role>,
+    ) -> Result<Option<String>, String> {
+        if name.is_empty() {
+            return Err("principals must have a name".to_owned());
+        }
+
+        let mut principals = self.access_control.principals.clone();
+        let token = match principals.get_mut(&name) {
+            Some(p) => {
+                p.roles = roles;
+                None
+            }
+            None => {
+                let token = access::new_token();
+                let token_hash = access::hash_token(&token);
+                principals.insert(name, PrincipalEntry { token_hash, roles });
+                Some(token)
+            }
+        };
+        self.store_principals(authority, principals)?;
+        Ok(token)
+    }
+
+    fn remove_principal<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        name: String,
+    ) -> Result<(), String> {
+        let mut principals = self.access_control.principals.clone();
+        if principals.remove(&name).is_none() {
+            return Err(format!("no principal named {}", name));
+        }
+        self.store_principals(authority, principals)
+    }
+
+    /// Replace the principals, both in the authority and here.
+    fn store_principals<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        principals: BTreeMap<String, PrincipalEntry>,
+    ) -> Result<(), String> {
+        let epoch = self.epoch;
+        match authority.read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+            None => unreachable!(),
+            Some(ref state) if state.epoch > epoch => Err(()),
+            Some(mut state) => {
+                state.principals = principals.clone();
+                Ok(state)
+            }
+        }) {
+            Ok(Ok(_)) => {
+                self.access_control.principals = principals;
+                Ok(())
+            }
+            _ => Err("failed to store principals in the authority".to_owned()),
+        }
+    }
+
+    /// Write the audit log to the authority if it has changed, so that the next controller has it
+    /// too.
+    ///
+    /// The request that was audited is served even if this fails. The entries stay in memory, and
+    /// are written out along with the next change.
+    fn store_audit_log<A: Authority + 'static>(&mut self, authority: &Arc<A>) {
+        let audit_log = match self.access_control.take_audit_log_changes() {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+        let epoch = self.epoch;
+        match authority.read_modify_write(AUDIT_LOG_KEY, |stored: Option<StoredAuditLog>| {
+            match stored {
+                Some(ref stored) if stored.epoch > epoch => Err(()),
+                _ => Ok(StoredAuditLog {
+                    epoch,
+                    entries: audit_log.clone(),
+                }),
+            }
+        }) {
+            Ok(Ok(_)) => {}
+            _ => {
+                error!(self.log, "failed to store the audit log in the authority");
+                self.access_control.audit_log_not_stored();
             }
         }
     }
@@ -1140,6 +2485,50 @@ impl ControllerInner {
         graphviz(&self.ingredients, detailed, &self.materializations)
     }
 
+    /// Remove the given query leaves and bases, along with any nodes that only they used.
+    fn remove_leaves(&mut self, leaves: &[NodeIndex]) -> Result<(), String> {
+        let (removed_bases, removed_other): (Vec<_>, Vec<_>) = leaves
+            .iter()
+            .cloned()
+            .partition(|ni| self.ingredients[*ni].is_base());
+
+        // first remove query nodes in reverse topological order
+        let mut topo_removals = Vec::with_capacity(removed_other.len());
+        let mut topo = petgraph::visit::Topo::new(&self.ingredients);
+        while let Some(node) = topo.next(&self.ingredients) {
+            if removed_other.contains(&node) {
+                topo_removals.push(node);
+            }
+        }
+        topo_removals.reverse();
+
+        for leaf in topo_removals {
+            self.remove_leaf(leaf)?;
+        }
+
+        // now remove bases
+        for base in removed_bases {
+            // TODO(malte): support removing bases that still have children?
+            let children: Vec<NodeIndex> = self
+                .ingredients
+                .neighbors_directed(base, petgraph::EdgeDirection::Outgoing)
+                .collect();
+            // TODO(malte): what about domain crossings? can ingress/egress nodes be left
+            // behind?
+            assert_eq!(children.len(), 0);
+            debug!(
+                self.log,
+                "Removing base \"{}\"",
+                self.ingredients[base].name();
+                "node" => base.index(),
+            );
+            // now drop the (orphaned) base
+            self.remove_nodes(vec![base].as_slice()).unwrap();
+        }
+
+        Ok(())
+    }
+
     fn remove_leaf(&mut self, mut leaf: NodeIndex) -> Result<(), String> {
         let mut removals = vec![];
         let start = leaf;
@@ -1199,6 +2588,7 @@ impl ControllerInner {
         );
 
         let mut nodes = vec![leaf];
+        let mut detached = Vec::new();
         while let Some(node) = nodes.pop() {
             let mut parents = self
                 .ingredients
@@ -1218,16 +2608,42 @@ impl ControllerInner {
                         .count() == 0
                 {
                     nodes.push(parent);
+                } else if self.ingredients[parent].is_egress() {
+                    // the egress stays around for its other children, but must stop sending here
+                    detached.push((parent, node));
                 }
             }
 
             removals.push(node);
         }
 
+        for (egress, ingress) in detached {
+            self.detach_egress(egress, ingress);
+        }
         self.remove_nodes(removals.as_slice())
     }
 
+    /// Tell an egress node that it no longer has `ingress` as a child.
+    fn detach_egress(&mut self, egress: NodeIndex, ingress: NodeIndex) {
+        let m = Box::new(Packet::RemoveEgressTx {
+            node: self.ingredients[egress].local_addr(),
+            to: ingress,
+        });
+        let domain = self
+            .domains
+            .get_mut(&self.ingredients[egress].domain())
+            .unwrap();
+        // a domain on a failed worker has nothing left to send to
+        let workers = &self.workers;
+        if (0..domain.shards()).all(|s| workers[&domain.assignment(s)].healthy) {
+            domain.send_to_healthy(m, workers).unwrap();
+            futures_executor::block_on(self.replies.wait_for_acks(&domain));
+        }
+    }
+
     fn remove_nodes(&mut self, removals: &[NodeIndex]) -> Result<(), String> {
+        self.graph_generation += 1;
+
         // Remove node from controller local state
         let mut domain_removals: HashMap<DomainIndex, Vec<LocalNodeIndex>> = HashMap::default();
         for ni in removals {
@@ -46,6 +46,27 @@ impl Default for FrontierStrategy {
     }
 }
 
+/// Strategy for determining which partially materialized internal nodes should keep their state
+/// on disk (in RocksDB) rather than in memory.
+///
+/// Readers are always kept in memory. Partial state on disk does not count towards the memory
+/// limit, and is never recovered after a restart.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PartialStorageStrategy {
+    /// Keep all partial state in memory (this is the default).
+    Memory,
+    /// Keep the partial state of all internal nodes on disk.
+    AllPersistent,
+    /// Keep the partial state of all internal nodes whose name contains the given string on disk.
+    Match(String),
+}
+
+impl Default for PartialStorageStrategy {
+    fn default() -> Self {
+        PartialStorageStrategy::Memory
+    }
+}
+
 pub(in crate::controller) struct Materializations {
     log: Logger,
 
@@ -55,6 +76,9 @@ pub(in crate::controller) struct Materializations {
     partial: HashSet<NodeIndex>,
     partial_enabled: bool,
     frontier_strategy: FrontierStrategy,
+    partial_storage: PartialStorageStrategy,
+    /// Names of nodes that should be fully materialized even where partial would be possible.
+    force_full: HashSet<String>,
 
     tag_generator: AtomicUsize,
 }
@@ -71,6 +95,8 @@ impl Materializations {
             partial: HashSet::default(),
             partial_enabled: true,
             frontier_strategy: FrontierStrategy::None,
+            partial_storage: PartialStorageStrategy::Memory,
+            force_full: HashSet::default(),
 
             tag_generator: AtomicUsize::default(),
         }
@@ -86,10 +112,28 @@ impl Materializations {
         self.partial_enabled = false;
     }
 
+    /// Is partial materialization enabled for new materializations?
+    pub(in crate::controller) fn partial_enabled(&self) -> bool {
+        self.partial_enabled
+    }
+
     /// Which nodes should be placed beyond the materialization frontier?
     pub(in crate::controller) fn set_frontier_strategy(&mut self, f: FrontierStrategy) {
         self.frontier_strategy = f;
     }
+
+    /// Which partially materialized nodes should keep their state on disk?
+    pub(in crate::controller) fn set_partial_storage_strategy(
+        &mut self,
+        s: PartialStorageStrategy,
+    ) {
+        self.partial_storage = s;
+    }
+
+    /// Fully materialize nodes with the given name whenever they are added in the future.
+    pub(in crate::controller) fn force_full(&mut self, name: String) {
+        self.force_full.insert(name);
+    }
 }
 
 impl Materializations {
@@ -97,6 +141,15 @@ impl Materializations {
         Tag::new(self.tag_generator.fetch_add(1, Ordering::SeqCst) as u32)
     }
 
+    /// Should the partial state of the given (internal) node be kept on disk?
+    fn persist_partial(&self, n: &Node) -> bool {
+        match self.partial_storage {
+            PartialStorageStrategy::Memory => false,
+            PartialStorageStrategy::AllPersistent => true,
+            PartialStorageStrategy::Match(ref m) => n.name().contains(m),
+        }
+    }
+
     /// Extend the current set of materializations with any additional materializations needed to
     /// satisfy indexing obligations in the given set of (new) nodes.
     #[allow(clippy::cognitive_complexity)]
@@ -335,6 +388,11 @@ impl Materializations {
                 able = false;
             }
 
+            if self.force_full.contains(graph[ni].name()) {
+                warn!(self.log, "full because advised"; "node" => ni.index());
+                able = false;
+            }
+
             // we are already fully materialized, so can't be made partial
             if !new.contains(&ni)
                 && self.added.get(&ni).map(|i| i.len()).unwrap_or(0)
@@ -452,6 +510,35 @@ impl Materializations {
         assert!(replay_obligations.is_empty());
     }
 
+    /// Work out which of the nodes in `new` would be materialized, and how, without committing
+    /// to any of it.
+    ///
+    /// The returned materializations reflect the decisions that `commit` would make, but they
+    /// aren't set up, and are only meant to be inspected.
+    pub(in crate::controller) fn plan(&self, graph: &Graph, new: &HashSet<NodeIndex>) -> Self {
+        let mut planned = Materializations {
+            log: self.log.clone(),
+
+            have: self.have.clone(),
+            added: self.added.clone(),
+
+            partial: self.partial.clone(),
+            partial_enabled: self.partial_enabled,
+            frontier_strategy: self.frontier_strategy.clone(),
+            partial_storage: self.partial_storage.clone(),
+            force_full: self.force_full.clone(),
+
+            tag_generator: AtomicUsize::new(self.tag_generator.load(Ordering::SeqCst)),
+        };
+        planned.extend(graph, new);
+        planned
+    }
+
+    /// Is the given node materialized, or would it be once planned materializations are set up?
+    pub(in crate::controller) fn is_materialized(&self, index: NodeIndex) -> bool {
+        self.have.contains_key(&index)
+    }
+
     /// Retrieves the materialization status of a given node, or None
     /// if the node isn't materialized.
     pub(in crate::controller) fn get_status(
@@ -485,7 +572,7 @@ impl Materializations {
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, Worker>,
         replies: &mut DomainReplies,
-    ) {
+    ) -> Result<(), String> {
         self.extend(graph, new);
 
         // check that we don't have fully materialized nodes downstream of partially materialized
@@ -858,7 +945,10 @@ impl Materializations {
                     workers,
                 )
                 .unwrap();
-            futures_executor::block_on(replies.wait_for_acks(&domain));
+            if let Err(e) = futures_executor::block_on(replies.wait_for_ready(&domain)) {
+                self.added.clear();
+                return Err(format!("could not ready node {}: {}", ni.index(), e));
+            }
             trace!(self.log, "node ready"; "node" => ni.index());
 
             if reconstructed {
@@ -870,6 +960,7 @@ impl Materializations {
         }
 
         self.added.clear();
+        Ok(())
     }
 
     /// Perform all operations necessary to bring any materializations for the given node up, and
@@ -381,6 +381,12 @@ impl<'a> Plan<'a> {
                                         lookup_key.iter().position(|&kc| kc == c)
                                     }
                                 }
+                                Sharding::ByColumns(..) | Sharding::ByRange(..) => {
+                                    // replay requests are only routed to a single shard by
+                                    // hashing one key column, so ask all the shards (or the
+                                    // same shard as us; see below).
+                                    None
+                                }
                                 s if s.is_none() => None,
                                 s => unreachable!("unhandled new sharding pattern {:?}", s),
                             };
@@ -542,7 +548,10 @@ impl<'a> Plan<'a> {
                         .drain()
                         .map(|(k, paths)| (k, paths.into_iter().map(|(tag, _)| tag).collect()))
                         .collect();
-                    InitialState::PartialLocal(indices)
+                    InitialState::PartialLocal {
+                        index: indices,
+                        persistent: self.m.persist_partial(&self.graph[self.node]),
+                    }
                 } else {
                     let indices = self.tags.drain().map(|(k, _)| k).collect();
                     InitialState::IndexedLocal(indices)
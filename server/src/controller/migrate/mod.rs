@@ -22,9 +22,11 @@
 
 use crate::controller::ControllerInner;
 use dataflow::prelude::*;
-use dataflow::{node, prelude::Packet};
+use dataflow::{node, prelude::Packet, EvictionPriority};
+use nom_sql::SqlType;
+use noria::{ExplainedNode, RecipeExplanation};
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::time::{self, Instant};
 
 use petgraph;
 use slog;
@@ -39,6 +41,7 @@ mod sharding;
 pub(super) enum ColumnChange {
     Add(String, DataType),
     Drop(usize),
+    Modify(usize, DataType, SqlType),
 }
 
 /// A `Migration` encapsulates a number of changes to the Soup data flow graph.
@@ -51,6 +54,10 @@ pub struct Migration<'a> {
     pub(super) added: HashSet<NodeIndex>,
     pub(super) columns: Vec<(NodeIndex, ColumnChange)>,
     pub(super) readers: HashMap<NodeIndex, NodeIndex>,
+    /// Shard counts for new nodes that shouldn't use the default sharding factor.
+    pub(super) sharding_hints: HashMap<NodeIndex, usize>,
+    /// Range partitioning for new base nodes.
+    pub(super) partitions: HashMap<NodeIndex, Sharding>,
 
     pub(super) start: Instant,
     pub(super) log: slog::Logger,
@@ -225,6 +232,325 @@ impl<'a> Migration<'a> {
         self.columns.push((node, ColumnChange::Drop(column)));
     }
 
+    /// Change the type of a column in a base node.
+    ///
+    /// Existing rows are converted to the new type, and any values that cannot be converted are
+    /// set to `NULL`. The new default is used for writes that don't have the column.
+    // crate viz for tests
+    pub fn modify_column(
+        &mut self,
+        node: NodeIndex,
+        column: usize,
+        default: DataType,
+        ty: SqlType,
+    ) {
+        // not allowed to modify columns of new nodes
+        assert!(!self.added.contains(&node));
+
+        let base = &mut self.mainline.ingredients[node];
+        assert!(base.is_base());
+
+        // we can't rely on DerefMut, since it disallows mutating Taken nodes
+        base.get_base_mut()
+            .unwrap()
+            .modify_column(column, default.clone(), ty.clone());
+
+        // also eventually propagate to domain clone, which converts the rows it holds
+        self.columns
+            .push((node, ColumnChange::Modify(column, default, ty)));
+    }
+
+    /// Rename a column of a base node.
+    ///
+    /// Column names only matter to the controller, so the domains are not told about this.
+    // crate viz for tests
+    pub fn rename_column<S: ToString>(&mut self, node: NodeIndex, column: usize, field: S) {
+        let base = &mut self.mainline.ingredients[node];
+        assert!(base.is_base());
+        base.rename_column(column, &field.to_string());
+    }
+
+    /// Choose the storage backend that holds a base node's state.
+    ///
+    /// The storage can only be chosen for bases added in this migration; for an existing base,
+    /// this only checks that its storage is unchanged. Since the base's domain may end up on any
+    /// worker, every worker must have the backend.
+    pub(in crate::controller) fn set_base_storage(
+        &mut self,
+        node: NodeIndex,
+        storage: &str,
+    ) -> Result<(), String> {
+        if let Some((addr, _)) = self
+            .mainline
+            .workers
+            .iter()
+            .find(|(_, w)| !w.storage_backends.iter().any(|b| b == storage))
+        {
+            return Err(format!(
+                "no storage backend named {} on worker {}",
+                storage, addr
+            ));
+        }
+
+        let added = self.added.contains(&node);
+        let base = &mut self.mainline.ingredients[node];
+        let name = base.name().to_owned();
+        let base = match base.get_base_mut() {
+            Some(base) => base,
+            None => return Err(format!("cannot set storage of non-base node {}", name)),
+        };
+
+        if added {
+            *base = std::mem::take(base).with_storage(storage.to_owned());
+            Ok(())
+        } else if base.storage() == Some(storage) {
+            Ok(())
+        } else {
+            Err(format!("cannot change storage of existing table {}", name))
+        }
+    }
+
+    /// Give a base node a retention period, after which rows are deleted based on the time in
+    /// the column named `column`.
+    ///
+    /// Like its storage, a base's retention period can only be chosen when the base is added.
+    pub(in crate::controller) fn set_base_retention(
+        &mut self,
+        node: NodeIndex,
+        column: &str,
+        ttl: time::Duration,
+    ) -> Result<(), String> {
+        let added = self.added.contains(&node);
+        let base = &mut self.mainline.ingredients[node];
+        let name = base.name().to_owned();
+        let column = match base.fields().iter().position(|f| f == column) {
+            Some(column) => column,
+            None => return Err(format!("table {} has no column named {}", name, column)),
+        };
+        let base = match base.get_base_mut() {
+            Some(base) => base,
+            None => return Err(format!("cannot set retention of non-base node {}", name)),
+        };
+
+        if added {
+            *base = std::mem::take(base).with_retention(column, ttl);
+            Ok(())
+        } else if base.retention() == Some((column, ttl)) {
+            Ok(())
+        } else {
+            Err(format!(
+                "cannot change retention period of existing table {}",
+                name
+            ))
+        }
+    }
+
+    /// Give a view a memory limit and an eviction priority.
+    ///
+    /// The limit applies to each shard of the view's reader. The priority applies to the reader,
+    /// and to the nodes that were added for the view in this migration. Like a base's storage,
+    /// these can only be chosen when the view is added.
+    pub(in crate::controller) fn set_view_eviction(
+        &mut self,
+        leaf: NodeIndex,
+        memory_limit: Option<usize>,
+        priority: EvictionPriority,
+    ) -> Result<(), String> {
+        let name = self.mainline.ingredients[leaf].name().to_owned();
+        let reader = self.reader_for(leaf)?;
+
+        if !self.added.contains(&reader) {
+            let r = &self.mainline.ingredients[reader];
+            if r.memory_limit == memory_limit && r.eviction_priority == priority {
+                return Ok(());
+            }
+            return Err(format!(
+                "cannot change memory limit or priority of existing view {}",
+                name
+            ));
+        }
+
+        self.mainline.ingredients[reader].memory_limit = memory_limit;
+        self.mainline.ingredients[reader].eviction_priority = priority;
+
+        // walk up through the nodes added for this view. nodes shared with other views keep the
+        // highest priority any of them asked for.
+        let mut stack = vec![leaf];
+        let mut seen = HashSet::new();
+        while let Some(n) = stack.pop() {
+            if !self.added.contains(&n) || !seen.insert(n) {
+                continue;
+            }
+            let node = &mut self.mainline.ingredients[n];
+            if node.eviction_priority == EvictionPriority::default()
+                || node.eviction_priority < priority
+            {
+                node.eviction_priority = priority;
+            }
+            stack.extend(
+                self.mainline
+                    .ingredients
+                    .neighbors_directed(n, petgraph::EdgeDirection::Incoming),
+            );
+        }
+        Ok(())
+    }
+
+    /// Split a base node into the given number of shards, instead of the default sharding factor.
+    ///
+    /// An existing base keeps its shard count; use `ControllerInner::reshard` to move its table
+    /// onto a base with a different one.
+    pub(in crate::controller) fn set_base_sharding(
+        &mut self,
+        node: NodeIndex,
+        shards: usize,
+    ) -> Result<(), String> {
+        let base = &self.mainline.ingredients[node];
+        if !base.is_base() {
+            return Err(format!(
+                "cannot set shard count of non-base node {}",
+                base.name()
+            ));
+        }
+
+        if self.added.contains(&node) {
+            self.hint_sharding(node, shards)
+        } else if base.sharded_by().shards().unwrap_or(1) == shards {
+            Ok(())
+        } else {
+            Err(format!(
+                "cannot change shard count of existing table {}",
+                base.name()
+            ))
+        }
+    }
+
+    /// Split the reader of a view into the given number of shards, instead of the default
+    /// sharding factor.
+    ///
+    /// An existing view's reader keeps its shard count; use `ControllerInner::reshard` to change
+    /// it.
+    pub(in crate::controller) fn set_view_sharding(
+        &mut self,
+        leaf: NodeIndex,
+        shards: usize,
+    ) -> Result<(), String> {
+        let reader = self.reader_for(leaf)?;
+        if self.added.contains(&reader) {
+            self.hint_sharding(reader, shards)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Split a base node into ranges of `column`, with one shard below the first bound, one at or
+    /// above the last bound, and one between each pair of adjacent bounds.
+    ///
+    /// The bounds must be values of `ty`, the type of `column`. The base must either have no primary
+    /// key, or be keyed by `column` alone. Like its shard count, a base's partitioning can only be
+    /// chosen when the base is added.
+    pub(in crate::controller) fn set_base_partitioning(
+        &mut self,
+        node: NodeIndex,
+        column: &str,
+        ty: SqlType,
+        bounds: Vec<DataType>,
+    ) -> Result<(), String> {
+        let base = &self.mainline.ingredients[node];
+        if !base.is_base() {
+            return Err(format!("cannot partition non-base node {}", base.name()));
+        }
+        let column = match base.fields().iter().position(|f| f == column) {
+            Some(column) => column,
+            None => {
+                return Err(format!(
+                    "table {} has no column named {}",
+                    base.name(),
+                    column
+                ))
+            }
+        };
+
+        if bounds.is_empty() {
+            return Err("partitioning needs at least one bound".to_owned());
+        }
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("partition bounds must be strictly increasing".to_owned());
+        }
+
+        let sharding = Sharding::ByRange(column, ty, bounds);
+        if !self.added.contains(&node) {
+            if base.sharded_by() == sharding {
+                return Ok(());
+            }
+            return Err(format!(
+                "cannot change partitioning of existing table {}",
+                base.name()
+            ));
+        }
+
+        match base.get_base().unwrap().key() {
+            Some(key) if key != [column] => {
+                return Err(format!(
+                    "cannot partition {} by {}, since it is keyed by other columns",
+                    base.name(),
+                    base.fields()[column]
+                ));
+            }
+            _ => {}
+        }
+        if self.mainline.sharding.is_none() {
+            return Err(format!(
+                "cannot partition {}, since sharding is disabled",
+                base.name()
+            ));
+        }
+
+        self.sharding_hints.remove(&node);
+        self.partitions.insert(node, sharding);
+        Ok(())
+    }
+
+    fn hint_sharding(&mut self, node: NodeIndex, shards: usize) -> Result<(), String> {
+        if shards == 0 {
+            return Err("shard count must be at least 1".to_owned());
+        }
+        if self.mainline.sharding.is_none() && shards != 1 {
+            return Err(format!(
+                "cannot split {} into {} shards, since sharding is disabled",
+                self.mainline.ingredients[node].name(),
+                shards
+            ));
+        }
+        self.sharding_hints.insert(node, shards);
+        Ok(())
+    }
+
+    /// Find the reader for `leaf`, which may be behind a shard merger.
+    fn reader_for(&self, leaf: NodeIndex) -> Result<NodeIndex, String> {
+        if let Some(&reader) = self.readers.get(&leaf) {
+            return Ok(reader);
+        }
+
+        // the reader may be behind a shard merger, so look a little further
+        let graph = &self.mainline.ingredients;
+        let mut bfs = petgraph::visit::Bfs::new(graph, leaf);
+        while let Some(child) = bfs.next(graph) {
+            if graph[child]
+                .with_reader(|r| r.is_for() == leaf)
+                .unwrap_or(false)
+            {
+                return Ok(child);
+            }
+        }
+        Err(format!("view {} is not maintained", graph[leaf].name()))
+    }
+
+    /// The key that hashing column masks added by this migration should use.
+    pub(crate) fn mask_key(&self) -> &[u8] {
+        self.mainline.mask_key.as_bytes()
+    }
+
     #[cfg(test)]
     pub(crate) fn graph(&self) -> &Graph {
         self.mainline.graph()
@@ -277,25 +603,124 @@ impl<'a> Migration<'a> {
             .unwrap();
     }
 
+    /// Describe what committing this `Migration` would do, without committing it.
+    ///
+    /// `queries` are the names of the queries that the migration exposes, and `sizes` gives the
+    /// size (in bytes) of the state of existing nodes, which is used to estimate how much would be
+    /// replayed. The nodes this migration added are left in the graph, and it is up to the caller
+    /// to remove them again.
+    pub(super) fn explain(
+        self,
+        queries: &HashMap<String, NodeIndex>,
+        sizes: &HashMap<NodeIndex, u64>,
+    ) -> RecipeExplanation {
+        let graph = &self.mainline.ingredients;
+        let new = &self.added;
+        let planned = self.mainline.materializations.plan(graph, new);
+        let is_materialized = |ni: NodeIndex| {
+            planned.is_materialized(ni) || graph[ni].with_reader(|r| r.key().is_some()) == Ok(true)
+        };
+
+        let mut sorted_new: Vec<_> = new.iter().cloned().collect();
+        sorted_new.sort();
+
+        let mut new_nodes = Vec::with_capacity(sorted_new.len());
+        let mut estimated_replay_bytes = 0;
+        for &ni in &sorted_new {
+            let n = &graph[ni];
+            let description = if n.is_internal() {
+                n.description(true)
+            } else if n.is_base() {
+                "Base table".to_owned()
+            } else if n.is_reader() {
+                "Leaf view".to_owned()
+            } else {
+                continue;
+            };
+            let materialized = planned.get_status(ni, n);
+
+            // a new full materialization is filled by replaying from the nearest materialized
+            // nodes above it. new bases start out empty, and partial state is filled on demand.
+            if materialized == MaterializationStatus::Full && !n.is_base() {
+                let mut stack: Vec<_> = graph
+                    .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                    .collect();
+                let mut seen = HashSet::new();
+                while let Some(p) = stack.pop() {
+                    if !seen.insert(p) || graph[p].is_source() {
+                        continue;
+                    }
+                    if is_materialized(p) {
+                        if !new.contains(&p) {
+                            estimated_replay_bytes += sizes.get(&p).cloned().unwrap_or(0);
+                        }
+                    } else {
+                        stack
+                            .extend(graph.neighbors_directed(p, petgraph::EdgeDirection::Incoming));
+                    }
+                }
+            }
+
+            new_nodes.push(ExplainedNode {
+                name: n.name().to_owned(),
+                description,
+                materialized,
+            });
+        }
+
+        // existing nodes that new nodes hang off of, and queries that were entirely reused
+        let mut reused_nodes: Vec<_> = sorted_new
+            .iter()
+            .flat_map(|&ni| graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming))
+            .chain(queries.values().cloned())
+            .filter(|ni| !new.contains(ni) && !graph[*ni].is_source())
+            .map(|ni| graph[ni].name().to_owned())
+            .collect();
+        reused_nodes.sort();
+        reused_nodes.dedup();
+
+        let mut queries: Vec<_> = queries.keys().cloned().collect();
+        queries.sort();
+
+        RecipeExplanation {
+            queries,
+            new_nodes,
+            reused_nodes,
+            estimated_replay_bytes,
+        }
+    }
+
     /// Commit the changes introduced by this `Migration` to the master `Soup`.
     ///
     /// This will spin up an execution thread for each new thread domain, and hook those new
     /// domains into the larger Soup graph. The returned map contains entry points through which
     /// new updates should be sent to introduce them into the Soup.
+    ///
+    /// Fails if a domain cannot set up one of the new nodes, such as a base whose storage backend
+    /// its worker does not have.
     #[allow(clippy::cognitive_complexity)]
-    pub(super) fn commit(self) {
+    pub(super) fn commit(self) -> Result<(), String> {
         info!(self.log, "finalizing migration"; "#nodes" => self.added.len());
 
         let log = self.log;
         let start = self.start;
         let mut mainline = self.mainline;
         let mut new = self.added;
+        let sharding_hints = self.sharding_hints;
+        let partitions = self.partitions;
         let mut topo = mainline.topo_order(&new);
 
         // Shard the graph as desired
         let mut swapped0 = if let Some(shards) = mainline.sharding {
-            let (t, swapped) =
-                sharding::shard(&log, &mut mainline.ingredients, &mut new, &topo, shards);
+            let (t, swapped) = sharding::shard(
+                &log,
+                &mut mainline.ingredients,
+                &mut new,
+                &topo,
+                shards,
+                &sharding_hints,
+                &partitions,
+            );
             topo = t;
 
             swapped
@@ -533,8 +958,8 @@ impl<'a> Migration<'a> {
                     })
                     .collect()
             } else {
-                // ingress nodes don't need to know about deleted columns, because those are only
-                // relevant when new writes enter the graph.
+                // ingress nodes don't need to know about deleted or modified columns, because those
+                // are only relevant when new writes enter the graph.
                 Vec::new()
             };
             inform.push(ni);
@@ -551,6 +976,14 @@ impl<'a> Migration<'a> {
                         node: n.local_addr(),
                         column,
                     }),
+                    ColumnChange::Modify(column, default, ty) => {
+                        Box::new(Packet::ModifyBaseColumn {
+                            node: n.local_addr(),
+                            column,
+                            default,
+                            ty,
+                        })
+                    }
                 };
 
                 let domain = mainline.domains.get_mut(&n.domain()).unwrap();
@@ -573,14 +1006,17 @@ impl<'a> Migration<'a> {
 
         // And now, the last piece of the puzzle -- set up materializations
         info!(log, "initializing new materializations");
-        mainline.materializations.commit(
+        let r = mainline.materializations.commit(
             &mut mainline.ingredients,
             &new,
             &mut mainline.domains,
             &mainline.workers,
             &mut mainline.replies,
         );
+        mainline.graph_generation += 1;
+        r?;
 
         warn!(log, "migration completed"; "ms" => start.elapsed().as_millis());
+        Ok(())
     }
 }
@@ -6,13 +6,55 @@ use petgraph::graph::NodeIndex;
 use slog::Logger;
 use std::collections::{HashMap, HashSet};
 
+/// Pick the number of shards for `node`.
+///
+/// A node that was given its own shard count uses that. Otherwise, a node whose inputs are all
+/// sharded the same number of ways keeps that number, so that a table with a non-default shard
+/// count doesn't force a shuffle right below it. Everything else uses `default`.
+fn shards_for(
+    graph: &Graph,
+    hints: &HashMap<NodeIndex, usize>,
+    node: NodeIndex,
+    default: usize,
+) -> usize {
+    if let Some(&shards) = hints.get(&node) {
+        return shards;
+    }
+
+    let mut inputs = graph
+        .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
+        .filter(|&ni| !graph[ni].is_source())
+        .map(|ni| graph[ni].sharded_by().shards());
+    match inputs.next() {
+        Some(Some(shards)) if inputs.all(|s| s == Some(shards)) => shards,
+        _ => default,
+    }
+}
+
+/// The sharding that the sharder `n` produces: its key columns, split as many ways as its children
+/// are sharded.
+fn sharder_output(graph: &Graph, n: NodeIndex, default: usize) -> Sharding {
+    let shards = graph
+        .neighbors_directed(n, petgraph::EdgeDirection::Outgoing)
+        .filter_map(|c| graph[c].sharded_by().shards())
+        .next()
+        .unwrap_or(default);
+    graph[n].with_sharder(|s| s.sharding(shards)).unwrap()
+}
+
+/// Shard the new nodes in `topo_list`.
+///
+/// Nodes are split `default_shards` ways unless `hints` gives them a shard count of their own. A
+/// hint of 1 keeps the node unsharded. Base tables in `partitions` are sharded exactly as given.
 #[allow(clippy::cognitive_complexity)]
 pub fn shard(
     log: &Logger,
     graph: &mut Graph,
     new: &mut HashSet<NodeIndex>,
     topo_list: &[NodeIndex],
-    sharding_factor: usize,
+    default_shards: usize,
+    hints: &HashMap<NodeIndex, usize>,
+    partitions: &HashMap<NodeIndex, Sharding>,
 ) -> (Vec<NodeIndex>, HashMap<(NodeIndex, NodeIndex), NodeIndex>) {
     // we must keep track of changes we make to the parent of a node, since this remapping must be
     // communicated to the nodes so they know the true identifier of their parent in the graph.
@@ -21,6 +63,13 @@ pub fn shard(
     // we want to shard every node by its "input" index. if the index required from a parent
     // doesn't match the current sharding key, we need to do a shuffle (i.e., a Union + Sharder).
     'nodes: for &node in topo_list {
+        if let Some(s) = partitions.get(&node) {
+            info!(log, "partitioning base node"; "node" => ?node, "sharding" => ?s);
+            graph.node_weight_mut(node).unwrap().shard_by(s.clone());
+            continue;
+        }
+
+        let sharding_factor = shards_for(graph, hints, node, default_shards);
         let mut input_shardings: HashMap<_, _> = graph
             .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
             .map(|ni| (ni, graph[ni].sharded_by()))
@@ -40,15 +89,23 @@ pub fn shard(
             let s = graph[node]
                 .with_reader(|r| r.key())
                 .unwrap()
-                .and_then(|c| {
-                    if c.len() == 1 {
-                        if graph[node].fields()[c[0]] == "bogokey" {
-                            Some(Sharding::ForcedNone)
-                        } else {
-                            Some(Sharding::ByColumn(c[0], sharding_factor))
+                .map(|c| {
+                    if (c.len() == 1 && graph[node].fields()[c[0]] == "bogokey")
+                        || sharding_factor == 1
+                    {
+                        return Sharding::ForcedNone;
+                    }
+                    match input_shardings[&ni] {
+                        // a reader keyed by the column its input is range partitioned on can keep
+                        // those ranges, unless it was asked for a different number of shards
+                        Sharding::ByRange(rc, _, ref bounds)
+                            if c.len() == 1
+                                && c[0] == rc
+                                && bounds.len() + 1 == sharding_factor =>
+                        {
+                            input_shardings[&ni].clone()
                         }
-                    } else {
-                        None
+                        _ => Sharding::by_columns(c.to_vec(), sharding_factor),
                     }
                 })
                 .unwrap_or(Sharding::ForcedNone);
@@ -56,14 +113,13 @@ pub fn shard(
                 info!(log, "de-sharding prior to poorly keyed reader"; "node" => ?node);
             } else {
                 info!(log, "sharding reader"; "node" => ?node);
-                graph[node]
-                    .with_reader_mut(|r| r.shard(sharding_factor))
-                    .unwrap();
+                let shards = s.shards().unwrap();
+                graph[node].with_reader_mut(|r| r.shard(shards)).unwrap();
             }
 
             if s != input_shardings[&ni] {
                 // input is sharded by different key -- need shuffle
-                reshard(log, new, &mut swaps, graph, ni, node, s);
+                reshard(log, new, &mut swaps, graph, ni, node, s.clone());
             }
             graph.node_weight_mut(node).unwrap().shard_by(s);
             continue;
@@ -74,40 +130,30 @@ pub fn shard(
             HashMap::new()
         };
         if need_sharding.is_empty()
-            && (input_shardings.len() == 1 || input_shardings.iter().all(|(_, &s)| s.is_none()))
+            && (input_shardings.len() == 1 || input_shardings.values().all(Sharding::is_none))
         {
-            let mut s = if input_shardings
-                .iter()
-                .any(|(_, &s)| s == Sharding::ForcedNone)
-            {
+            let mut s = if input_shardings.values().any(|s| *s == Sharding::ForcedNone) {
                 Sharding::ForcedNone
             } else {
-                input_shardings.iter().map(|(_, &s)| s).next().unwrap()
+                input_shardings.values().next().cloned().unwrap()
             };
             info!(log, "preserving sharding of pass-through node";
                   "node" => ?node,
                   "sharding" => ?s);
 
             if graph[node].is_internal() || graph[node].is_base() {
-                if let Sharding::ByColumn(c, shards) = s {
-                    // remap c according to node's semantics
-                    let n = &graph[node];
-                    let src = (0..n.fields().len()).find(|&col| {
+                // remap the sharding columns according to node's semantics. if any of them is not
+                // emitted by this node, sharding is effectively random at this point.
+                let n = &graph[node];
+                s = s.remap(|c| {
+                    (0..n.fields().len()).find(|&col| {
                         if let Some(src) = n.parent_columns(col)[0].1 {
                             src == c
                         } else {
                             false
                         }
-                    });
-
-                    if let Some(src) = src {
-                        s = Sharding::ByColumn(src, shards);
-                    } else {
-                        // sharding column is not emitted by this node!
-                        // at this point, sharding is effectively random.
-                        s = Sharding::Random(shards);
-                    }
-                }
+                    })
+                });
             }
             graph.node_weight_mut(node).unwrap().shard_by(s);
             continue;
@@ -120,7 +166,21 @@ pub fn shard(
             }
         }
         if complex {
-            if !graph[node].is_base() {
+            if graph[node].is_base() {
+                // a base with a compound key is looked up by all of its key columns together, so
+                // it can be sharded by a hash of them
+                if let Some(key) = need_sharding.remove(&node) {
+                    let s = if sharding_factor == 1 {
+                        Sharding::ForcedNone
+                    } else {
+                        Sharding::ByColumns(key, sharding_factor)
+                    };
+                    info!(log, "sharding base node by compound key";
+                          "node" => ?node,
+                          "sharding" => ?s);
+                    graph.node_weight_mut(node).unwrap().shard_by(s);
+                }
+            } else {
                 // not supported yet -- force no sharding
                 // TODO: if we're sharding by a two-part key and need sharding by the *first* part
                 // of that key, we can probably re-use the existing sharding?
@@ -176,6 +236,15 @@ pub fn shard(
                     // ok to continue since standard shard_by is None
                     continue;
                 }
+                None if sharding_factor == 1 => {
+                    // base nodes that were asked to stay in one piece
+                    info!(log, "not sharding base node"; "node" => ?node);
+                    graph
+                        .node_weight_mut(node)
+                        .unwrap()
+                        .shard_by(Sharding::ForcedNone);
+                    continue;
+                }
                 None => {
                     // base nodes -- what do we shard them by?
                     warn!(log, "sharding base node"; "node" => ?node, "column" => want_sharding);
@@ -229,7 +298,15 @@ pub fn shard(
                             let need_sharding = Sharding::ByColumn(col, sharding_factor);
                             if input_shardings[&ni] != need_sharding {
                                 // input is sharded by different key -- need shuffle
-                                reshard(log, new, &mut swaps, graph, ni, node, need_sharding);
+                                reshard(
+                                    log,
+                                    new,
+                                    &mut swaps,
+                                    graph,
+                                    ni,
+                                    node,
+                                    need_sharding.clone(),
+                                );
                                 input_shardings.insert(ni, need_sharding);
                             }
                         }
@@ -348,7 +425,7 @@ pub fn shard(
                         if input_shardings[&ni] != need_sharding {
                             debug!(log, "resharding input with sharding {:?} to match desired sharding {:?}",
                                input_shardings[&ni], need_sharding; "node" => ?node, "input" => ?ni);
-                            reshard(log, new, &mut swaps, graph, ni, node, need_sharding);
+                            reshard(log, new, &mut swaps, graph, ni, node, need_sharding.clone());
                             input_shardings.insert(ni, need_sharding);
                         }
                     }
@@ -371,13 +448,12 @@ pub fn shard(
         }
 
         // force everything to be unsharded...
-        let sharding = Sharding::ForcedNone;
         warn!(log, "forcing de-sharding"; "node" => ?node);
         for (&ni, in_sharding) in &mut input_shardings {
             if !in_sharding.is_none() {
                 // ancestor must be forced to right sharding
-                reshard(log, new, &mut swaps, graph, ni, node, sharding);
-                *in_sharding = sharding;
+                reshard(log, new, &mut swaps, graph, ni, node, Sharding::ForcedNone);
+                *in_sharding = Sharding::ForcedNone;
             }
         }
     }
@@ -411,8 +487,7 @@ pub fn shard(
             assert!(!graph[p].is_source());
 
             // and that its children must be sharded somehow (otherwise what is the sharder doing?)
-            let col = graph[n].with_sharder(|s| s.sharded_by()).unwrap();
-            let by = Sharding::ByColumn(col, sharding_factor);
+            let by = sharder_output(graph, n, default_shards);
 
             // we can only push sharding above newly created nodes that are not already sharded.
             if !new.contains(&p) || graph[p].sharded_by() != Sharding::None {
@@ -424,6 +499,14 @@ pub fn shard(
             if graph[p].is_base() {
                 trace!(log, "well, its parent is a base");
 
+                // a base that was given its own shard count must keep it
+                if let Some(&shards) = hints.get(&p) {
+                    if Some(shards) != by.shards() {
+                        trace!(log, "no, parent is weird (has a different shard count)");
+                        continue;
+                    }
+                }
+
                 // we can't shard compound bases (yet)
                 if let Some(k) = graph[p].get_base().unwrap().key() {
                     if k.len() != 1 {
@@ -445,7 +528,7 @@ pub fn shard(
                 }
 
                 // shard the base
-                warn!(log, "eagerly sharding unsharded base"; "by" => col, "base" => ?p);
+                warn!(log, "eagerly sharding unsharded base"; "by" => ?by, "base" => ?p);
                 graph[p].shard_by(by);
                 // remove the sharder at n by rewiring its outgoing edges directly to the base.
                 let mut cs = graph
@@ -470,6 +553,11 @@ pub fn shard(
                 continue;
             }
 
+            // only sharders that hash a single column can be pushed further up
+            let col = match by {
+                Sharding::ByColumn(col, _) => col,
+                _ => continue,
+            };
             let src_cols = graph[p].parent_columns(col);
             if src_cols.len() != 1 {
                 // TODO: technically we could push the sharder to all parents here
@@ -502,13 +590,12 @@ pub fn shard(
             let mut remove = Vec::new();
             for c in graph.neighbors_directed(p, petgraph::EdgeDirection::Outgoing) {
                 // what does c shard by?
-                let col = graph[c].with_sharder(|s| s.sharded_by());
-                if col.is_none() {
+                if !graph[c].is_sharder() {
                     // lifting n would shard a node that isn't expecting to be sharded
                     // TODO: we *could* insert a de-shard here
                     continue 'sharders;
                 }
-                let csharding = Sharding::ByColumn(col.unwrap(), sharding_factor);
+                let csharding = sharder_output(graph, c, default_shards);
 
                 if csharding == by {
                     // sharding by the same key, which is now unnecessary.
@@ -618,7 +705,7 @@ pub fn shard(
         }
         topo_list.push(node);
     }
-    validate(log, graph, &topo_list, sharding_factor);
+    validate(log, graph, &topo_list, default_shards);
 
     (topo_list, swaps)
 }
@@ -653,8 +740,8 @@ fn reshard(
             n.shard_by(to);
             n
         }
-        Sharding::ByColumn(c, _) => {
-            let mut n = graph[src].mirror(node::special::Sharder::new(c));
+        Sharding::ByColumn(..) | Sharding::ByColumns(..) | Sharding::ByRange(..) => {
+            let mut n = graph[src].mirror(node::special::Sharder::for_sharding(&to));
             n.shard_by(graph[src].sharded_by());
             n
         }
@@ -686,7 +773,7 @@ fn reshard(
     );
 }
 
-pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_factor: usize) {
+pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], default_shards: usize) {
     // ensure that each node matches the sharding of each of its ancestors, unless the ancestor is
     // a sharder or a shard merger
     for &node in topo_list {
@@ -703,9 +790,9 @@ pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_f
 
         let remap = |nd: &Node, pni: NodeIndex, ps: Sharding| -> Sharding {
             if nd.is_internal() || nd.is_base() {
-                if let Sharding::ByColumn(c, shards) = ps {
-                    // remap c according to node's semantics
-                    let src = (0..nd.fields().len()).find(|&col| {
+                // remap each sharding column c according to node's semantics
+                return ps.remap(|c| {
+                    (0..nd.fields().len()).find(|&col| {
                         for pc in nd.parent_columns(col) {
                             if let (p, Some(src)) = pc {
                                 // found column c in parent pni
@@ -729,14 +816,8 @@ pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_f
                             }
                         }
                         false
-                    });
-
-                    if let Some(src) = src {
-                        return Sharding::ByColumn(src, shards);
-                    } else {
-                        return Sharding::Random(shards);
-                    }
-                }
+                    })
+                });
             }
             // in all other cases, the sharding matches the parent's
             ps
@@ -747,11 +828,8 @@ pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_f
             if in_node.is_sharder() {
                 // ancestor is a sharder, so its output sharding must match ours
                 in_node.with_sharder(|s| {
-                    let in_sharding = remap(
-                        n,
-                        in_ni,
-                        Sharding::ByColumn(s.sharded_by(), sharding_factor),
-                    );
+                    let shards = n.sharded_by().shards().unwrap_or(default_shards);
+                    let in_sharding = remap(n, in_ni, s.sharding(shards));
                     if in_sharding != n.sharded_by() {
                         crit!(
                             log,
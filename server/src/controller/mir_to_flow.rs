@@ -8,7 +8,7 @@ use common::DataType;
 use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::join::{Join, JoinType};
 use dataflow::ops::latest::Latest;
-use dataflow::ops::project::{Project, ProjectExpression, ProjectExpressionBase};
+use dataflow::ops::project::{ColumnMask, Project, ProjectExpression, ProjectExpressionBase};
 use dataflow::{node, ops};
 use mir::node::{GroupedNodeType, MirNode, MirNodeType};
 use mir::query::{MirQuery, QueryFlowParts};
@@ -113,6 +113,7 @@ fn mir_node_to_flow_parts(
                         column_specs.as_mut_slice(),
                         &bna.columns_added,
                         &bna.columns_removed,
+                        &bna.columns_modified,
                     ),
                 },
                 MirNodeType::Extremum {
@@ -330,6 +331,11 @@ fn mir_node_to_flow_parts(
                         mig,
                     )
                 }
+                MirNodeType::Mask { ref masks } => {
+                    assert_eq!(mir_node.ancestors.len(), 1);
+                    let parent = mir_node.ancestors[0].clone();
+                    make_mask_node(&name, parent, mir_node.columns.as_slice(), masks, mig)
+                }
             };
 
             // any new flow nodes have been instantiated by now, so we replace them with
@@ -351,6 +357,7 @@ fn adapt_base_node(
     column_specs: &mut [(ColumnSpecification, Option<usize>)],
     add: &[ColumnSpecification],
     remove: &[ColumnSpecification],
+    modify: &[(ColumnSpecification, ColumnSpecification)],
 ) -> FlowNode {
     let na = match over_node.borrow().flow_node {
         None => panic!("adapted base node must have a flow node already!"),
@@ -358,19 +365,7 @@ fn adapt_base_node(
     };
 
     for a in add.iter() {
-        let default_value = match a
-            .constraints
-            .iter()
-            .filter_map(|c| match *c {
-                ColumnConstraint::DefaultValue(ref dv) => Some(dv.into()),
-                _ => None,
-            })
-            .next()
-        {
-            None => DataType::None,
-            Some(dv) => dv,
-        };
-        let column_id = mig.add_column(na, &a.column.name, default_value);
+        let column_id = mig.add_column(na, &a.column.name, default_value(a));
 
         // store the new column ID in the column specs for this node
         for &mut (ref cs, ref mut cid) in column_specs.iter_mut() {
@@ -392,10 +387,37 @@ fn adapt_base_node(
             .expect("base column ID must be set to remove column");
         mig.drop_column(na, cid);
     }
+    for &(ref old, ref new) in modify.iter() {
+        let cid = over_node
+            .borrow()
+            .column_specifications()
+            .iter()
+            .find(|&&(ref ecs, _)| ecs == old)
+            .and_then(|&(_, cid)| cid)
+            .expect("base column ID must be set to modify column");
+        if old.column.name != new.column.name {
+            mig.rename_column(na, cid, &new.column.name);
+        }
+        if old.sql_type != new.sql_type || default_value(old) != default_value(new) {
+            mig.modify_column(na, cid, default_value(new), new.sql_type.clone());
+        }
+    }
 
     FlowNode::Existing(na)
 }
 
+/// Returns the value that a column takes on in writes that don't provide it.
+fn default_value(cs: &ColumnSpecification) -> DataType {
+    cs.constraints
+        .iter()
+        .filter_map(|c| match *c {
+            ColumnConstraint::DefaultValue(ref dv) => Some(dv.into()),
+            _ => None,
+        })
+        .next()
+        .unwrap_or(DataType::None)
+}
+
 fn column_names<'a>(cs: &'a [Column]) -> Vec<&'a str> {
     cs.iter().map(|c| c.name.as_str()).collect()
 }
@@ -825,6 +847,34 @@ fn make_project_node(
     FlowNode::New(n)
 }
 
+fn make_mask_node(
+    name: &str,
+    parent: MirNodeRef,
+    columns: &[Column],
+    masks: &[(Column, ColumnMask)],
+    mig: &mut Migration,
+) -> FlowNode {
+    let parent_na = parent.borrow().flow_node_addr().unwrap();
+    let column_names = column_names(columns);
+
+    let emit = columns
+        .iter()
+        .map(|c| parent.borrow().column_id_for_column(c, None))
+        .collect::<Vec<_>>();
+    let masks = masks
+        .iter()
+        .map(|&(ref c, ref m)| {
+            let i = columns.iter().position(|oc| oc == c).unwrap();
+            (i, m.clone())
+        })
+        .collect();
+
+    let project =
+        Project::new(parent_na, emit.as_slice(), None, None).with_masks(masks, mig.mask_key());
+    let n = mig.add_ingredient(String::from(name), column_names.as_slice(), project);
+    FlowNode::New(n)
+}
+
 fn make_distinct_node(
     name: &str,
     parent: MirNodeRef,
@@ -1,22 +1,24 @@
+use crate::access::{self, AccessConfig, PrincipalEntry};
 use crate::controller::inner::ControllerInner;
 use crate::controller::migrate::Migration;
+use crate::controller::rbac::StoredAuditLog;
 use crate::controller::recipe::Recipe;
 use crate::coordination::CoordinationMessage;
 use crate::coordination::CoordinationPayload;
+use crate::metrics::Metrics;
 use crate::startup::Event;
 use crate::Config;
 use async_bincode::AsyncBincodeReader;
 use dataflow::payload::ControlReplyPacket;
 use futures_util::{
-    future::FutureExt,
-    future::TryFutureExt,
     sink::SinkExt,
     stream::{StreamExt, TryStreamExt},
 };
 use hyper::{self, StatusCode};
 use noria::channel::TcpSender;
-use noria::consensus::{Authority, Epoch, STATE_KEY};
-use noria::ControllerDescriptor;
+use noria::consensus::{Authority, Epoch, AUDIT_LOG_KEY, STATE_KEY};
+use noria::{ControllerDescriptor, RecipeVersion, TlsConfig};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
@@ -24,11 +26,15 @@ use std::time;
 use stream_cancel::Valve;
 use tokio::sync::mpsc::UnboundedSender;
 
+mod cost;
+mod dashboard;
 mod domain_handle;
 mod inner;
 mod keys;
 pub(crate) mod migrate; // crate viz for tests
 mod mir_to_flow;
+mod rbac;
+mod rebalance;
 pub(crate) mod recipe; // crate viz for tests
 mod schema;
 mod security;
@@ -41,20 +47,35 @@ pub(crate) struct ControllerState {
 
     recipe_version: usize,
     recipes: Vec<String>,
+    /// The most recent versions the recipe has gone through, oldest first.
+    #[serde(default)]
+    recipe_history: Vec<RecipeVersion>,
+    /// The recipe texts that reproduce the version just before the oldest one in the history.
+    #[serde(default)]
+    recipe_history_base: Vec<String>,
+    /// The principals that may use the deployment, and their roles, by name.
+    #[serde(default)]
+    pub(crate) principals: BTreeMap<String, PrincipalEntry>,
+    /// The key that hashing column masks are made with. Made up by the first controller.
+    #[serde(default)]
+    mask_key: String,
 }
 
 struct Worker {
     healthy: bool,
     last_heartbeat: time::Instant,
     sender: TcpSender<CoordinationMessage>,
+    /// The storage backends that base tables on the worker can use.
+    storage_backends: Vec<String>,
 }
 
 impl Worker {
-    fn new(sender: TcpSender<CoordinationMessage>) -> Self {
+    fn new(sender: TcpSender<CoordinationMessage>, storage_backends: Vec<String>) -> Self {
         Worker {
             healthy: true,
             last_heartbeat: time::Instant::now(),
             sender,
+            storage_backends,
         }
     }
 }
@@ -65,12 +86,14 @@ pub(super) async fn main<A: Authority + 'static>(
     alive: tokio::sync::mpsc::Sender<()>,
     valve: Valve,
     config: Config,
+    access: AccessConfig,
     descriptor: ControllerDescriptor,
     mut ctrl_rx: tokio::sync::mpsc::UnboundedReceiver<Event>,
     cport: tokio::net::TcpListener,
     log: slog::Logger,
     authority: Arc<A>,
     tx: tokio::sync::mpsc::UnboundedSender<Event>,
+    metrics: Arc<Metrics>,
 ) {
     let (dtx, drx) = tokio::sync::mpsc::unbounded_channel();
 
@@ -80,6 +103,8 @@ pub(super) async fn main<A: Authority + 'static>(
         log.clone(),
         dtx,
         cport,
+        access.worker_secret.clone(),
+        access.tls.clone(),
     ));
 
     // note that we do not start up the data-flow until we find a controller!
@@ -102,6 +127,7 @@ pub(super) async fn main<A: Authority + 'static>(
                         tokio::task::block_in_place(|| ctrl.create_universe(universe).unwrap());
                     }
                 }
+                // only workers that know the worker secret get to send coordination messages
                 CoordinationPayload::Register { .. } => {
                     if let Some(ref mut ctrl) = controller {
                         tokio::task::block_in_place(|| {
@@ -118,11 +144,11 @@ pub(super) async fn main<A: Authority + 'static>(
                 }
                 _ => unreachable!(),
             },
-            Event::ExternalRequest(method, path, query, body, reply_tx) => {
+            Event::ExternalRequest(method, path, query, body, caller, reply_tx) => {
                 if let Some(ref mut ctrl) = controller {
                     let authority = &authority;
                     let reply = tokio::task::block_in_place(|| {
-                        ctrl.external_request(method, path, query, body, &authority)
+                        ctrl.external_request(method, path, query, body, caller, &authority)
                     });
 
                     if reply_tx.send(reply).is_err() {
@@ -159,13 +185,27 @@ pub(super) async fn main<A: Authority + 'static>(
                 let c = campaign.take().unwrap();
                 tokio::task::block_in_place(move || c.join().unwrap());
                 let drx = drx.take().unwrap();
-                controller = Some(ControllerInner::new(log.clone(), state, drx));
+                // an audit log that can't be read is no reason not to take over
+                let audit_log = tokio::task::block_in_place(|| authority.try_read(AUDIT_LOG_KEY))
+                    .ok()
+                    .and_then(|stored| stored)
+                    .and_then(|stored| serde_json::from_slice::<StoredAuditLog>(&stored).ok())
+                    .map(|stored| stored.entries)
+                    .unwrap_or_default();
+                controller = Some(ControllerInner::new(
+                    log.clone(),
+                    state,
+                    audit_log,
+                    drx,
+                    access.clone(),
+                ));
             }
             Event::CampaignError(e) => {
                 panic!("{:?}", e);
             }
             e => unreachable!("{:?} is not a controller event", e),
         }
+        metrics.set_controller(controller.as_ref().map(ControllerInner::gauges));
     }
 
     // shutting down
@@ -183,6 +223,8 @@ async fn listen_domain_replies(
     log: slog::Logger,
     reply_tx: UnboundedSender<ControlReplyPacket>,
     mut on: tokio::net::TcpListener,
+    secret: Option<String>,
+    tls: Option<TlsConfig>,
 ) {
     let mut incoming = valve.wrap(on.incoming());
     while let Some(sock) = incoming.next().await {
@@ -193,20 +235,35 @@ async fn listen_domain_replies(
             }
             Ok(sock) => {
                 let alive = alive.clone();
-                tokio::spawn(
+                let valve = valve.clone();
+                let reply_tx = reply_tx.clone();
+                let secret = secret.clone();
+                let tls = tls.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let _alive = alive;
+                    let mut sock = match noria::channel::tls::accept(tls.as_ref(), sock).await {
+                        Ok(sock) => sock,
+                        Err(e) => {
+                            warn!(log, "rejected domain reply connection: {}", e);
+                            return;
+                        }
+                    };
+                    let secret = secret.as_ref().map(String::as_str);
+                    if let Err(e) = noria::channel::auth::challenge(&mut sock, secret).await {
+                        warn!(log, "rejected domain reply connection: {}", e);
+                        return;
+                    }
                     valve
                         .wrap(AsyncBincodeReader::from(sock))
                         .map_err(failure::Error::from)
                         .forward(
-                            crate::ImplSinkForSender(reply_tx.clone())
+                            crate::ImplSinkForSender(reply_tx)
                                 .sink_map_err(|_| format_err!("main event loop went away")),
                         )
-                        .map_err(|e| panic!("{:?}", e))
-                        .map(move |_| {
-                            let _ = alive;
-                            ()
-                        }),
-                );
+                        .await
+                        .unwrap_or_else(|e| panic!("{:?}", e));
+                });
             }
         }
     }
@@ -262,6 +319,10 @@ fn instance_campaign<A: Authority + 'static>(
                         epoch,
                         recipe_version: 0,
                         recipes: vec![],
+                        recipe_history: vec![],
+                        recipe_history_base: vec![],
+                        principals: BTreeMap::new(),
+                        mask_key: access::new_token(),
                     }),
                     Some(ref state) if state.epoch > epoch => Err(()),
                     Some(mut state) => {
@@ -271,6 +332,10 @@ fn instance_campaign<A: Authority + 'static>(
                             state.config, config,
                             "Config in Zk does not match requested config!"
                         );
+                        // deployments from before column masks were keyed
+                        if state.mask_key.is_empty() {
+                            state.mask_key = access::new_token();
+                        }
                         Ok(state)
                     }
                 },
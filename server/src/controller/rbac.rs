@@ -0,0 +1,336 @@
+//! Deciding which principal may use which controller endpoint, and keeping track of the
+//! decisions.
+//!
+//! The audit log is kept in the authority under a key of its own, so that it survives the
+//! controller failing over. Requests from clients that didn't say who they are are only audited
+//! in memory, so that they can't make the controller write to the authority at will.
+
+use crate::access::{self, Caller, PrincipalEntry};
+use hyper::StatusCode;
+use noria::consensus::Epoch;
+use noria::{AuditEntry, DataType, Principal, Role};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::SystemTime;
+
+/// How many audit log entries to keep around.
+const AUDIT_LOG_LEN: usize = 1024;
+
+/// The audit log as it is stored in the authority.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct StoredAuditLog {
+    /// The epoch of the controller that last wrote the log.
+    pub(super) epoch: Epoch,
+    pub(super) entries: Vec<AuditEntry>,
+}
+
+pub(super) struct AccessControl {
+    anonymous_roles: Vec<Role>,
+    pub(super) principals: BTreeMap<String, PrincipalEntry>,
+    audit_log: VecDeque<AuditEntry>,
+    /// Whether the audit log has entries that should be, but aren't, in the authority yet.
+    audit_log_changed: bool,
+    log: slog::Logger,
+}
+
+impl AccessControl {
+    pub(super) fn new(
+        anonymous_roles: Vec<Role>,
+        principals: BTreeMap<String, PrincipalEntry>,
+        audit_log: Vec<AuditEntry>,
+        log: slog::Logger,
+    ) -> Self {
+        AccessControl {
+            anonymous_roles,
+            principals,
+            audit_log: audit_log.into(),
+            audit_log_changed: false,
+            log,
+        }
+    }
+
+    fn resolve(&self, caller: &Caller) -> (Principal, Vec<Role>) {
+        access::resolve(caller, &self.anonymous_roles, || &self.principals)
+    }
+
+    /// Check that `caller` may use the endpoint at `path`.
+    ///
+    /// Denials, and requests that need an administrative role, are written to the audit log.
+    /// Requests for endpoints that don't exist are denied.
+    pub(super) fn authorize(&mut self, caller: &Caller, path: &str) -> Result<(), StatusCode> {
+        let (principal, roles) = self.resolve(caller);
+        let required = match access::required_role(path) {
+            Some(role) => role,
+            None => {
+                warn!(self.log, "denied request for unknown endpoint";
+                      "principal" => ?principal, "endpoint" => path);
+                return self
+                    .record(principal, path.to_owned(), false)
+                    .map_err(|_| StatusCode::NOT_FOUND);
+            }
+        };
+        let allowed = roles.iter().any(|r| r.grants(required));
+
+        if !allowed {
+            warn!(self.log, "denied request";
+                  "principal" => ?principal, "endpoint" => path, "needs" => ?required);
+        } else if required == Role::SchemaAdmin || required == Role::SecurityAdmin {
+            info!(self.log, "allowed administrative request";
+                  "principal" => ?principal, "endpoint" => path);
+        } else {
+            return Ok(());
+        }
+
+        self.record(principal, path.to_owned(), allowed)
+    }
+
+    /// Check that `caller` may write on behalf of the user universe `universe`.
+    ///
+    /// Only the principal named after the universe may, besides security administrators.
+    pub(super) fn authorize_universe(
+        &mut self,
+        caller: &Caller,
+        universe: &DataType,
+    ) -> Result<(), StatusCode> {
+        let (principal, roles) = self.resolve(caller);
+        let allowed = roles.iter().any(|r| r.grants(Role::SecurityAdmin))
+            || match principal {
+                Principal::Named(ref name) => names_universe(name, universe),
+                _ => false,
+            };
+        if allowed {
+            return Ok(());
+        }
+
+        warn!(self.log, "denied request for another universe";
+              "principal" => ?principal, "universe" => universe.to_string());
+        self.record(
+            principal,
+            format!("/universe_table_builder {}", universe.to_string()),
+            false,
+        )
+    }
+
+    /// Write a decision to the audit log, and turn it into the response to the request.
+    fn record(
+        &mut self,
+        principal: Principal,
+        endpoint: String,
+        allowed: bool,
+    ) -> Result<(), StatusCode> {
+        let status = access::denied(&principal);
+        if self.audit_log.len() == AUDIT_LOG_LEN {
+            self.audit_log.pop_front();
+        }
+        self.audit_log.push_back(AuditEntry {
+            at: SystemTime::now(),
+            principal,
+            endpoint,
+            allowed,
+        });
+        if is_stored(self.audit_log.back().expect("just pushed")) {
+            self.audit_log_changed = true;
+        }
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(status)
+        }
+    }
+
+    pub(super) fn audit_log(&self) -> Vec<AuditEntry> {
+        self.audit_log.iter().cloned().collect()
+    }
+
+    /// The entries of the audit log that are stored in the authority, if any of them have changed
+    /// since they were last taken to be stored.
+    pub(super) fn take_audit_log_changes(&mut self) -> Option<Vec<AuditEntry>> {
+        if !std::mem::replace(&mut self.audit_log_changed, false) {
+            return None;
+        }
+        Some(
+            self.audit_log
+                .iter()
+                .filter(|e| is_stored(e))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Remember that the changes last taken couldn't be stored, so that they are tried again.
+    pub(super) fn audit_log_not_stored(&mut self) {
+        self.audit_log_changed = true;
+    }
+
+    pub(super) fn roles(&self) -> BTreeMap<String, Vec<Role>> {
+        self.principals
+            .iter()
+            .map(|(name, p)| (name.clone(), p.roles.clone()))
+            .collect()
+    }
+}
+
+/// Whether `entry` is kept in the authority, rather than only in memory.
+///
+/// Anyone can make the controller deny a request without presenting a valid token, so those
+/// denials aren't written out.
+fn is_stored(entry: &AuditEntry) -> bool {
+    match entry.principal {
+        Principal::Anonymous | Principal::Unknown => entry.allowed,
+        _ => true,
+    }
+}
+
+/// Whether the principal called `name` is the one that the user universe `universe` is for.
+fn names_universe(name: &str, universe: &DataType) -> bool {
+    match *universe {
+        DataType::Text(..) | DataType::TinyText(..) => <&str>::from(universe) == name,
+        _ => universe.to_string() == name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_control() -> AccessControl {
+        let mut principals = BTreeMap::new();
+        principals.insert(
+            "ops".to_owned(),
+            PrincipalEntry {
+                token_hash: access::hash_token("ops-token"),
+                roles: vec![Role::SchemaAdmin],
+            },
+        );
+        AccessControl::new(
+            vec![Role::Reader],
+            principals,
+            Vec::new(),
+            slog::Logger::root(slog::Discard, o!()),
+        )
+    }
+
+    #[test]
+    fn it_authorizes_by_role() {
+        let mut ac = access_control();
+        let ops = Caller::Token(access::hash_token("ops-token"));
+        let stranger = Caller::Token(access::hash_token("guess"));
+
+        assert_eq!(ac.authorize(&Caller::Anonymous, "/view_builder"), Ok(()));
+        assert_eq!(
+            ac.authorize(&Caller::Anonymous, "/table_builder"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(ac.authorize(&ops, "/extend_recipe"), Ok(()));
+        assert_eq!(ac.authorize(&ops, "/view_builder"), Ok(()));
+        assert_eq!(
+            ac.authorize(&ops, "/set_security_config"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ac.authorize(&stranger, "/view_builder"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(ac.authorize(&Caller::Root, "/set_principal"), Ok(()));
+        assert_eq!(
+            ac.authorize(&Caller::Root, "/no_such_endpoint"),
+            Err(StatusCode::NOT_FOUND)
+        );
+
+        let audited: Vec<_> = ac
+            .audit_log()
+            .into_iter()
+            .map(|e| (e.principal, e.endpoint, e.allowed))
+            .collect();
+        assert_eq!(
+            audited,
+            vec![
+                (Principal::Anonymous, "/table_builder".to_owned(), false),
+                (
+                    Principal::Named("ops".to_owned()),
+                    "/extend_recipe".to_owned(),
+                    true
+                ),
+                (
+                    Principal::Named("ops".to_owned()),
+                    "/set_security_config".to_owned(),
+                    false
+                ),
+                (Principal::Unknown, "/view_builder".to_owned(), false),
+                (Principal::Root, "/set_principal".to_owned(), true),
+                (Principal::Root, "/no_such_endpoint".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_audit_log_for_the_next_controller() {
+        let mut ac = access_control();
+        let ops = Caller::Token(access::hash_token("ops-token"));
+        assert_eq!(ac.take_audit_log_changes(), None);
+        assert_eq!(ac.authorize(&Caller::Anonymous, "/view_builder"), Ok(()));
+        assert_eq!(ac.take_audit_log_changes(), None);
+
+        // clients that don't say who they are are audited, but not written out
+        assert!(ac.authorize(&Caller::Anonymous, "/extend_recipe").is_err());
+        let stranger = Caller::Token(access::hash_token("guess"));
+        assert!(ac.authorize(&stranger, "/view_builder").is_err());
+        assert_eq!(ac.audit_log().len(), 2);
+        assert_eq!(ac.take_audit_log_changes(), None);
+
+        assert!(ac.authorize(&ops, "/set_security_config").is_err());
+        let stored = ac.take_audit_log_changes().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(ac.take_audit_log_changes(), None);
+
+        // changes that couldn't be written out are tried again
+        ac.audit_log_not_stored();
+        assert_eq!(ac.take_audit_log_changes(), Some(stored.clone()));
+
+        // a controller that takes over picks up where the last one left off
+        let mut next = AccessControl::new(
+            vec![Role::Reader],
+            ac.principals.clone(),
+            stored,
+            slog::Logger::root(slog::Discard, o!()),
+        );
+        assert!(next.authorize(&ops, "/set_principal").is_err());
+        let endpoints: Vec<_> = next
+            .take_audit_log_changes()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.endpoint)
+            .collect();
+        assert_eq!(endpoints, vec!["/set_security_config", "/set_principal"]);
+    }
+
+    #[test]
+    fn it_ties_universes_to_principals() {
+        let mut ac = access_control();
+        ac.principals.insert(
+            "alice".to_owned(),
+            PrincipalEntry {
+                token_hash: access::hash_token("alice-token"),
+                roles: vec![Role::Writer],
+            },
+        );
+        let alice = Caller::Token(access::hash_token("alice-token"));
+        let ops = Caller::Token(access::hash_token("ops-token"));
+
+        assert_eq!(ac.authorize_universe(&alice, &"alice".into()), Ok(()));
+        assert_eq!(
+            ac.authorize_universe(&alice, &"bob".into()),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ac.authorize_universe(&ops, &"alice".into()),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ac.authorize_universe(&Caller::Anonymous, &"alice".into()),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(ac.authorize_universe(&Caller::Root, &1.into()), Ok(()));
+        assert_eq!(ac.audit_log().len(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+//! Deciding which domain shards to move between workers to even out their load.
+
+use crate::controller::WorkerIdentifier;
+use dataflow::prelude::DomainIndex;
+use noria::debug::stats::DomainStats;
+use std::cmp;
+use std::collections::HashMap;
+
+/// How long a domain shard has spent processing updates and replays since it was started.
+pub(super) fn busy_time(stats: &DomainStats) -> u64 {
+    stats.total_forward_time + stats.total_replay_time
+}
+
+/// How much load a domain shard has put on its worker since the last rebalancing round, given how
+/// long it has been busy now and as of that round.
+///
+/// A shard that has been restarted since, for example because it was moved, has only been busy
+/// for `now`. Every shard counts for something, so that idle domains are also spread out across
+/// workers.
+pub(super) fn load(now: u64, then: Option<u64>) -> u64 {
+    let since = match then {
+        Some(then) if then <= now => now - then,
+        _ => now,
+    };
+    cmp::max(1, since)
+}
+
+/// Plan moves of domain shards from the busiest worker to the least busy one for as long as that
+/// narrows the gap between them.
+///
+/// `loads` holds the load of every shard on each worker, including workers that have no shards.
+pub(super) fn plan(
+    mut loads: HashMap<WorkerIdentifier, Vec<((DomainIndex, usize), u64)>>,
+) -> Vec<(DomainIndex, usize, WorkerIdentifier)> {
+    let mut moves = Vec::new();
+    if loads.len() < 2 {
+        return moves;
+    }
+
+    loop {
+        let totals: Vec<_> = loads
+            .iter()
+            .map(|(&w, shards)| (shards.iter().map(|&(_, l)| l).sum::<u64>(), w))
+            .collect();
+        let (busiest_load, busiest) = *totals.iter().max().unwrap();
+        let (idlest_load, idlest) = *totals.iter().min().unwrap();
+
+        // moving a shard with load `l` leaves the two workers with `busiest_load - l` and
+        // `idlest_load + l`, which is only an improvement if `l` is below the gap between them.
+        let gap = busiest_load - idlest_load;
+        let candidate = loads[&busiest]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(_, l))| l < gap)
+            .max_by_key(|&(_, &(_, l))| l)
+            .map(|(i, _)| i);
+
+        match candidate {
+            Some(i) => {
+                let (shard, l) = loads.get_mut(&busiest).unwrap().swap_remove(i);
+                loads.get_mut(&idlest).unwrap().push((shard, l));
+                moves.push((shard.0, shard.1, idlest));
+            }
+            None => break,
+        }
+    }
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(port: u16) -> WorkerIdentifier {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    fn shard(domain: usize, load: u64) -> ((DomainIndex, usize), u64) {
+        ((domain.into(), 0), load)
+    }
+
+    #[test]
+    fn it_only_counts_recent_load() {
+        assert_eq!(load(500, None), 500);
+        assert_eq!(load(500, Some(400)), 100);
+        assert_eq!(load(500, Some(500)), 1);
+        // restarted since the last round
+        assert_eq!(load(50, Some(400)), 50);
+    }
+
+    #[test]
+    fn it_spreads_idle_domains() {
+        let mut loads = HashMap::new();
+        loads.insert(worker(1), (0..4).map(|d| shard(d, 1)).collect());
+        loads.insert(worker(2), Vec::new());
+
+        let moves = plan(loads);
+        assert_eq!(moves.len(), 2);
+        assert!(moves.iter().all(|&(_, _, to)| to == worker(2)));
+    }
+
+    #[test]
+    fn it_moves_the_hottest_domain_that_helps() {
+        let mut loads = HashMap::new();
+        loads.insert(worker(1), vec![shard(0, 50), shard(1, 40), shard(2, 10)]);
+        loads.insert(worker(2), vec![shard(3, 10)]);
+
+        assert_eq!(plan(loads), vec![(0.into(), 0, worker(2))]);
+    }
+
+    #[test]
+    fn it_leaves_a_single_hot_domain_alone() {
+        let mut loads = HashMap::new();
+        loads.insert(worker(1), vec![shard(0, 100)]);
+        loads.insert(worker(2), Vec::new());
+
+        assert!(plan(loads).is_empty());
+    }
+}
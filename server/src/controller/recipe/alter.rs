@@ -0,0 +1,511 @@
+//! `ALTER TABLE` statements that change the type of, or rename, a base table column.
+//!
+//! nom-sql doesn't parse `ALTER TABLE`, so these statements are recognized before the recipe text
+//! is handed to the SQL parser. An alteration is applied to the table's `CREATE TABLE` statement,
+//! and the changed base is then adapted like for any other schema change. Queries that read a
+//! renamed column are rewritten to use its new name.
+
+use nom::IResult;
+use nom_sql::parser as sql_parser;
+use nom_sql::{
+    ArithmeticBase, ArithmeticExpression, Column, ColumnOrLiteral, ColumnSpecification,
+    ConditionBase, ConditionExpression, CreateTableStatement, FieldDefinitionExpression,
+    FieldValueExpression, FunctionArguments, FunctionExpression, JoinConstraint, JoinRightSide,
+    SelectSpecification, SelectStatement, SqlQuery, TableKey,
+};
+use std::collections::HashMap;
+
+/// A change to an existing column of a base table.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Alteration {
+    /// `ALTER TABLE t MODIFY [COLUMN] <column definition>`
+    Modify {
+        table: String,
+        column: ColumnSpecification,
+    },
+    /// `ALTER TABLE t RENAME COLUMN <from> TO <to>`
+    Rename {
+        table: String,
+        from: String,
+        to: String,
+    },
+}
+
+impl Alteration {
+    /// The table whose column is changed.
+    pub(super) fn table(&self) -> &str {
+        match *self {
+            Alteration::Modify { ref table, .. } | Alteration::Rename { ref table, .. } => table,
+        }
+    }
+
+    /// The column renamed by this alteration, if any.
+    pub(super) fn rename(&self) -> Option<super::ColumnRename> {
+        match *self {
+            Alteration::Rename {
+                ref table,
+                ref from,
+                ref to,
+            } => Some((table.clone(), from.clone(), to.clone())),
+            Alteration::Modify { .. } => None,
+        }
+    }
+
+    /// Apply the alteration to the table definition in `ctq`.
+    pub(super) fn apply(&self, ctq: &CreateTableStatement) -> Result<CreateTableStatement, String> {
+        let mut ctq = ctq.clone();
+        match *self {
+            Alteration::Modify { ref column, .. } => {
+                let field = find_field(&mut ctq, &column.column.name)?;
+                *field = column.clone();
+            }
+            Alteration::Rename {
+                ref from, ref to, ..
+            } => {
+                if ctq.fields.iter().any(|f| f.column.name == *to) {
+                    return Err(format!(
+                        "table {} already has a column named {}",
+                        ctq.table.name, to
+                    ));
+                }
+                find_field(&mut ctq, from)?.column.name = to.clone();
+                if let Some(ref mut keys) = ctq.keys {
+                    for key in keys {
+                        let columns = match *key {
+                            TableKey::PrimaryKey(ref mut columns)
+                            | TableKey::UniqueKey(_, ref mut columns)
+                            | TableKey::FulltextKey(_, ref mut columns)
+                            | TableKey::Key(_, ref mut columns) => columns,
+                        };
+                        for c in columns.iter_mut().filter(|c| c.name == *from) {
+                            c.name = to.clone();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(ctq)
+    }
+}
+
+fn find_field<'a>(
+    ctq: &'a mut CreateTableStatement,
+    name: &str,
+) -> Result<&'a mut ColumnSpecification, String> {
+    let table = &ctq.table.name;
+    ctq.fields
+        .iter_mut()
+        .find(|f| f.column.name == name)
+        .ok_or_else(|| format!("table {} has no column named {}", table, name))
+}
+
+/// Rewrites the queries that read a renamed column.
+struct Renamer<'a> {
+    table: &'a str,
+    from: &'a str,
+    to: &'a str,
+    /// The columns of each table, to tell which table an unqualified column belongs to.
+    schemas: &'a HashMap<String, Vec<String>>,
+}
+
+/// How a `SELECT` refers to the table whose column is renamed.
+struct Scope {
+    /// The table's name, and any aliases the `SELECT` gives it.
+    qualifiers: Vec<String>,
+    /// Whether a column without a table refers to the table.
+    unqualified: bool,
+}
+
+impl<'a> Renamer<'a> {
+    fn scope(&self, sq: &SelectStatement) -> Scope {
+        let mut tables: Vec<_> = sq.tables.iter().collect();
+        for jc in &sq.join {
+            match jc.right {
+                JoinRightSide::Table(ref t) => tables.push(t),
+                JoinRightSide::Tables(ref ts) => tables.extend(ts),
+                _ => (),
+            }
+        }
+
+        let mut qualifiers = Vec::new();
+        for t in tables.iter().filter(|t| t.name == self.table) {
+            qualifiers.push(t.name.clone());
+            qualifiers.extend(t.alias.clone());
+        }
+        let has_column = |table: &str| {
+            self.schemas
+                .get(table)
+                .map_or(false, |columns| columns.iter().any(|c| c == self.from))
+        };
+        let unqualified = !qualifiers.is_empty()
+            && tables
+                .iter()
+                .all(|t| t.name == self.table || !has_column(&t.name));
+        Scope {
+            qualifiers,
+            unqualified,
+        }
+    }
+
+    fn column(&self, c: &mut Column, scope: &Scope) {
+        if let Some(ref mut f) = c.function {
+            self.function(f, scope);
+            return;
+        }
+        let ours = match c.table {
+            Some(ref t) => scope.qualifiers.contains(t),
+            None => scope.unqualified,
+        };
+        if ours && c.name == self.from {
+            c.name = self.to.to_owned();
+        }
+    }
+
+    fn function(&self, f: &mut FunctionExpression, scope: &Scope) {
+        use nom_sql::FunctionExpression::*;
+
+        let args = match *f {
+            Avg(ref mut args, _)
+            | Count(ref mut args, _)
+            | Sum(ref mut args, _)
+            | Max(ref mut args)
+            | Min(ref mut args)
+            | GroupConcat(ref mut args, _) => args,
+            _ => return,
+        };
+        match *args {
+            FunctionArguments::Column(ref mut c) => self.column(c, scope),
+            FunctionArguments::Conditional(ref mut cw) => {
+                self.condition(&mut cw.condition, scope);
+                if let ColumnOrLiteral::Column(ref mut c) = cw.then_expr {
+                    self.column(c, scope);
+                }
+                if let Some(ColumnOrLiteral::Column(ref mut c)) = cw.else_expr {
+                    self.column(c, scope);
+                }
+            }
+        }
+    }
+
+    fn arithmetic(&self, ae: &mut ArithmeticExpression, scope: &Scope) {
+        if let ArithmeticBase::Column(ref mut c) = ae.left {
+            self.column(c, scope);
+        }
+        if let ArithmeticBase::Column(ref mut c) = ae.right {
+            self.column(c, scope);
+        }
+    }
+
+    fn condition(&self, ce: &mut ConditionExpression, scope: &Scope) {
+        match *ce {
+            ConditionExpression::LogicalOp(ref mut ct)
+            | ConditionExpression::ComparisonOp(ref mut ct) => {
+                self.condition(&mut ct.left, scope);
+                self.condition(&mut ct.right, scope);
+            }
+            ConditionExpression::NegationOp(ref mut inner)
+            | ConditionExpression::Bracketed(ref mut inner) => self.condition(inner, scope),
+            ConditionExpression::Arithmetic(ref mut ae) => self.arithmetic(ae, scope),
+            ConditionExpression::Base(ConditionBase::Field(ref mut c)) => self.column(c, scope),
+            // a nested query has a scope of its own
+            ConditionExpression::Base(ConditionBase::NestedSelect(ref mut sq)) => self.select(sq),
+            ConditionExpression::Base(_) => (),
+        }
+    }
+
+    fn select(&self, sq: &mut SelectStatement) {
+        let scope = self.scope(sq);
+
+        // the columns the query outputs keep their old names, so that the views reading from it
+        // don't change
+        for field in &mut sq.fields {
+            match *field {
+                FieldDefinitionExpression::Col(ref mut c) => {
+                    let name = c.name.clone();
+                    self.column(c, &scope);
+                    if c.name != name && c.alias.is_none() {
+                        c.alias = Some(name);
+                    }
+                }
+                FieldDefinitionExpression::Value(FieldValueExpression::Arithmetic(ref mut ae)) => {
+                    let name = ae.to_string();
+                    self.arithmetic(ae, &scope);
+                    if ae.alias.is_none() && ae.to_string() != name {
+                        ae.alias = Some(name);
+                    }
+                }
+                _ => (),
+            }
+        }
+        for jc in &mut sq.join {
+            if let JoinRightSide::NestedSelect(ref mut nested, _) = jc.right {
+                self.select(nested);
+            }
+            // `USING` names a column of both sides, and so can't follow a rename of one of them
+            if let JoinConstraint::On(ref mut ce) = jc.constraint {
+                self.condition(ce, &scope);
+            }
+        }
+        if let Some(ref mut ce) = sq.where_clause {
+            self.condition(ce, &scope);
+        }
+        if let Some(ref mut gb) = sq.group_by {
+            for c in &mut gb.columns {
+                self.column(c, &scope);
+            }
+            if let Some(ref mut ce) = gb.having {
+                self.condition(ce, &scope);
+            }
+        }
+        if let Some(ref mut oc) = sq.order {
+            for &mut (ref mut c, _) in &mut oc.columns {
+                self.column(c, &scope);
+            }
+        }
+    }
+
+    fn query(&self, q: &mut SqlQuery) {
+        match *q {
+            SqlQuery::Select(ref mut sq) => self.select(sq),
+            SqlQuery::CompoundSelect(ref mut csq) => {
+                for &mut (_, ref mut sq) in &mut csq.selects {
+                    self.select(sq);
+                }
+            }
+            SqlQuery::CreateView(ref mut cvq) => match *cvq.definition {
+                SelectSpecification::Simple(ref mut sq) => self.select(sq),
+                SelectSpecification::Compound(ref mut csq) => {
+                    for &mut (_, ref mut sq) in &mut csq.selects {
+                        self.select(sq);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Rewrite `q` to refer to a renamed column by its new name, as given by `rename`. `schemas` lists
+/// the columns of each table.
+///
+/// Returns `None` if `q` doesn't read the renamed column.
+pub(super) fn rename_in_query(
+    q: &SqlQuery,
+    rename: &super::ColumnRename,
+    schemas: &HashMap<String, Vec<String>>,
+) -> Option<SqlQuery> {
+    let (ref table, ref from, ref to) = *rename;
+    let renamer = Renamer {
+        table,
+        from,
+        to,
+        schemas,
+    };
+    let mut renamed = q.clone();
+    renamer.query(&mut renamed);
+    if renamed != *q {
+        Some(renamed)
+    } else {
+        None
+    }
+}
+
+/// The columns of each table defined by `queries`.
+pub(super) fn table_columns<'a, I>(queries: I) -> HashMap<String, Vec<String>>
+where
+    I: IntoIterator<Item = &'a SqlQuery>,
+{
+    queries
+        .into_iter()
+        .filter_map(|q| match *q {
+            SqlQuery::CreateTable(ref ctq) => Some((
+                ctq.table.name.clone(),
+                ctq.fields.iter().map(|f| f.column.name.clone()).collect(),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+fn alter_prefix(input: &str) -> IResult<&str, &str> {
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::multispace1;
+
+    let (input, _) = tag_no_case("alter")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = tag_no_case("table")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, table) = super::ident(input)?;
+    let (input, _) = multispace1(input)?;
+    Ok((input, table))
+}
+
+fn modify_prefix(input: &str) -> IResult<&str, ()> {
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::{multispace0, multispace1};
+    use nom::combinator::opt;
+    use nom::sequence::pair;
+
+    let (input, _) = tag_no_case("modify")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = opt(pair(tag_no_case("column"), multispace1))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, ()))
+}
+
+fn rename_clause(input: &str) -> IResult<&str, (&str, &str)> {
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::{char, multispace0, multispace1};
+    use nom::combinator::opt;
+
+    let (input, _) = tag_no_case("rename")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = tag_no_case("column")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, from) = super::ident(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = tag_no_case("to")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, to) = super::ident(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = opt(char(';'))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, (from, to)))
+}
+
+/// Parse `stmt` as an `ALTER TABLE` statement.
+///
+/// Returns `None` if `stmt` isn't an `ALTER TABLE` statement at all, so that it can be handed on
+/// to the SQL parser.
+pub(super) fn parse_alter(stmt: &str) -> Option<Result<Alteration, String>> {
+    let (rest, table) = match alter_prefix(stmt.trim()) {
+        Ok(r) => r,
+        Err(_) => return None,
+    };
+
+    if let Ok((definition, _)) = modify_prefix(rest) {
+        // let nom-sql parse the column definition as if it were part of a table definition
+        let definition = definition.trim_end().trim_end_matches(';');
+        let q = format!("CREATE TABLE {} ({});", table, definition);
+        return Some(match sql_parser::parse_query(&q) {
+            Ok(SqlQuery::CreateTable(mut ctq)) if ctq.fields.len() == 1 => Ok(Alteration::Modify {
+                table: table.to_owned(),
+                column: ctq.fields.remove(0),
+            }),
+            _ => Err(format!("invalid column definition: {}", definition)),
+        });
+    }
+
+    Some(match rename_clause(rest) {
+        Ok(("", (from, to))) => Ok(Alteration::Rename {
+            table: table.to_owned(),
+            from: from.to_owned(),
+            to: to.to_owned(),
+        }),
+        _ => Err(format!(
+            "unsupported ALTER TABLE statement \"{}\"; only MODIFY COLUMN and RENAME COLUMN are \
+             supported",
+            stmt
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom_sql::SqlType;
+
+    fn table(q: &str) -> CreateTableStatement {
+        match sql_parser::parse_query(q).unwrap() {
+            SqlQuery::CreateTable(ctq) => ctq,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_parses_alterations() {
+        assert_eq!(parse_alter("SELECT * FROM t;"), None);
+
+        match parse_alter("ALTER TABLE t MODIFY COLUMN a bigint;") {
+            Some(Ok(Alteration::Modify { table, column })) => {
+                assert_eq!(table, "t");
+                assert_eq!(column.column.name, "a");
+                assert!(matches!(column.sql_type, SqlType::Bigint(_)));
+            }
+            a => panic!("unexpected alteration {:?}", a),
+        }
+
+        assert_eq!(
+            parse_alter("alter table t rename column a to b"),
+            Some(Ok(Alteration::Rename {
+                table: "t".to_owned(),
+                from: "a".to_owned(),
+                to: "b".to_owned(),
+            }))
+        );
+
+        assert!(parse_alter("ALTER TABLE t DROP COLUMN a;")
+            .unwrap()
+            .is_err());
+        assert!(parse_alter("ALTER TABLE t MODIFY COLUMN a;")
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn it_renames_columns_in_queries() {
+        let rename = ("t".to_owned(), "a".to_owned(), "b".to_owned());
+        let mut schemas = HashMap::new();
+        schemas.insert("t".to_owned(), vec!["id".to_owned(), "a".to_owned()]);
+        schemas.insert("u".to_owned(), vec!["id".to_owned(), "a".to_owned()]);
+        let rename_in = |q: &str| {
+            let q = sql_parser::parse_query(q).unwrap();
+            rename_in_query(&q, &rename, &schemas).map(|q| q.to_string())
+        };
+        let parse = |q: &str| Some(sql_parser::parse_query(q).unwrap().to_string());
+
+        // the output keeps its old name
+        assert_eq!(
+            rename_in("SELECT a, id FROM t WHERE a = ?;"),
+            parse("SELECT b AS a, id FROM t WHERE b = ?;")
+        );
+        assert_eq!(
+            rename_in("SELECT x.a AS c FROM t AS x;"),
+            parse("SELECT x.b AS c FROM t AS x;")
+        );
+        // an unqualified column could be either table's
+        assert_eq!(
+            rename_in("SELECT t.a, u.a FROM t JOIN u ON (t.id = u.id) WHERE a = 1;"),
+            parse("SELECT t.b AS a, u.a FROM t JOIN u ON (t.id = u.id) WHERE a = 1;")
+        );
+        assert_eq!(
+            rename_in("SELECT id FROM u WHERE u.id IN (SELECT id FROM t WHERE a = 1);"),
+            parse("SELECT id FROM u WHERE u.id IN (SELECT id FROM t WHERE b = 1);")
+        );
+        assert_eq!(rename_in("SELECT a FROM u;"), None);
+        assert_eq!(rename_in("SELECT id FROM t;"), None);
+    }
+
+    #[test]
+    fn it_applies_alterations() {
+        let ctq = table("CREATE TABLE t (id int, a int, PRIMARY KEY(id));");
+
+        let rename = parse_alter("ALTER TABLE t RENAME COLUMN id TO tid;")
+            .unwrap()
+            .unwrap();
+        let renamed = rename.apply(&ctq).unwrap();
+        assert_eq!(renamed.fields[0].column.name, "tid");
+        match renamed.keys.as_ref().unwrap()[0] {
+            TableKey::PrimaryKey(ref columns) => assert_eq!(columns[0].name, "tid"),
+            ref k => panic!("unexpected key {:?}", k),
+        }
+        assert!(rename.apply(&renamed).is_err());
+
+        let modify = parse_alter("ALTER TABLE t MODIFY a text;")
+            .unwrap()
+            .unwrap();
+        let modified = modify.apply(&ctq).unwrap();
+        assert_eq!(modified.fields[1].sql_type, SqlType::Text);
+        assert_eq!(modified.fields[0], ctq.fields[0]);
+    }
+}
@@ -0,0 +1,195 @@
+//! `DROP TABLE` and `DROP VIEW` statements that remove tables and named queries from a recipe.
+//!
+//! Like `ALTER TABLE`, these are recognized before the recipe text is handed to the SQL parser.
+//! Dropping an expression removes it from the recipe, and activating the recipe then removes the
+//! nodes that only it needed from the graph.
+
+use nom::IResult;
+use nom_sql::{
+    ConditionBase, ConditionExpression, JoinRightSide, SelectSpecification, SelectStatement,
+    SqlQuery,
+};
+
+/// A table or named query to remove from the recipe.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum DropTarget {
+    /// `DROP TABLE t`
+    Table(String),
+    /// `DROP VIEW q`
+    View(String),
+}
+
+impl DropTarget {
+    /// The name of the table or query that is dropped.
+    pub(super) fn name(&self) -> &str {
+        match *self {
+            DropTarget::Table(ref name) | DropTarget::View(ref name) => name,
+        }
+    }
+
+    /// What kind of expression is dropped, for use in errors.
+    pub(super) fn kind(&self) -> &'static str {
+        match *self {
+            DropTarget::Table(_) => "table",
+            DropTarget::View(_) => "view",
+        }
+    }
+}
+
+fn drop_prefix(input: &str) -> IResult<&str, bool> {
+    use nom::branch::alt;
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::multispace1;
+    use nom::combinator::map;
+
+    let (input, _) = tag_no_case("drop")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, table) = alt((
+        map(tag_no_case("table"), |_| true),
+        map(tag_no_case("view"), |_| false),
+    ))(input)?;
+    let (input, _) = multispace1(input)?;
+    Ok((input, table))
+}
+
+fn name_list(input: &str) -> IResult<&str, Vec<&str>> {
+    use nom::character::complete::{char, multispace0};
+    use nom::combinator::opt;
+    use nom::multi::separated_nonempty_list;
+    use nom::sequence::delimited;
+
+    let (input, names) = separated_nonempty_list(
+        delimited(multispace0, char(','), multispace0),
+        super::ident,
+    )(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = opt(char(';'))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, names))
+}
+
+/// Parse `stmt` as a `DROP TABLE` or `DROP VIEW` statement.
+///
+/// Returns `None` if `stmt` isn't a `DROP` statement at all, so that it can be handed on to the
+/// SQL parser.
+pub(super) fn parse_drop(stmt: &str) -> Option<Result<Vec<DropTarget>, String>> {
+    let (rest, table) = match drop_prefix(stmt.trim()) {
+        Ok(r) => r,
+        Err(_) => return None,
+    };
+
+    Some(match name_list(rest) {
+        Ok(("", names)) => Ok(names
+            .into_iter()
+            .map(|n| {
+                if table {
+                    DropTarget::Table(n.to_owned())
+                } else {
+                    DropTarget::View(n.to_owned())
+                }
+            })
+            .collect()),
+        _ => Err(format!(
+            "unsupported DROP statement \"{}\"; expected DROP TABLE or DROP VIEW followed by a \
+             list of names",
+            stmt
+        )),
+    })
+}
+
+fn condition_dependencies<'a>(ce: &'a ConditionExpression, deps: &mut Vec<&'a str>) {
+    match *ce {
+        ConditionExpression::LogicalOp(ref ct) | ConditionExpression::ComparisonOp(ref ct) => {
+            condition_dependencies(&ct.left, deps);
+            condition_dependencies(&ct.right, deps);
+        }
+        ConditionExpression::NegationOp(ref inner) | ConditionExpression::Bracketed(ref inner) => {
+            condition_dependencies(inner, deps)
+        }
+        ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => {
+            select_dependencies(sq, deps)
+        }
+        ConditionExpression::Base(_) | ConditionExpression::Arithmetic(_) => (),
+    }
+}
+
+fn select_dependencies<'a>(sq: &'a SelectStatement, deps: &mut Vec<&'a str>) {
+    deps.extend(sq.tables.iter().map(|t| &*t.name));
+    for jc in &sq.join {
+        match jc.right {
+            JoinRightSide::Table(ref t) => deps.push(&t.name),
+            JoinRightSide::Tables(ref ts) => deps.extend(ts.iter().map(|t| &*t.name)),
+            JoinRightSide::NestedSelect(ref sq, _) => select_dependencies(sq, deps),
+            JoinRightSide::NestedJoin(ref jc) => {
+                if let JoinRightSide::Table(ref t) = jc.right {
+                    deps.push(&t.name);
+                }
+            }
+        }
+    }
+    if let Some(ref ce) = sq.where_clause {
+        condition_dependencies(ce, deps);
+    }
+}
+
+/// The names of the tables and views that `q` reads from.
+pub(super) fn dependencies(q: &SqlQuery) -> Vec<&str> {
+    let mut deps = Vec::new();
+    match *q {
+        SqlQuery::Select(ref sq) => select_dependencies(sq, &mut deps),
+        SqlQuery::CompoundSelect(ref csq) => {
+            for (_, sq) in &csq.selects {
+                select_dependencies(sq, &mut deps);
+            }
+        }
+        SqlQuery::CreateView(ref cvq) => match *cvq.definition {
+            SelectSpecification::Simple(ref sq) => select_dependencies(sq, &mut deps),
+            SelectSpecification::Compound(ref csq) => {
+                for (_, sq) in &csq.selects {
+                    select_dependencies(sq, &mut deps);
+                }
+            }
+        },
+        _ => (),
+    }
+    deps.sort();
+    deps.dedup();
+    deps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom_sql::parser as sql_parser;
+
+    #[test]
+    fn it_parses_drops() {
+        assert_eq!(parse_drop("SELECT * FROM t;"), None);
+        assert_eq!(
+            parse_drop("DROP TABLE t;"),
+            Some(Ok(vec![DropTarget::Table("t".to_owned())]))
+        );
+        assert_eq!(
+            parse_drop("drop view a, b"),
+            Some(Ok(vec![
+                DropTarget::View("a".to_owned()),
+                DropTarget::View("b".to_owned())
+            ]))
+        );
+        assert!(parse_drop("DROP INDEX i;").is_none());
+        assert!(parse_drop("DROP TABLE t CASCADE;").unwrap().is_err());
+    }
+
+    #[test]
+    fn it_finds_dependencies() {
+        let q = sql_parser::parse_query(
+            "SELECT a.x FROM a JOIN b ON (a.y = b.y) \
+             WHERE a.z IN (SELECT c.z FROM c WHERE c.w = ?);",
+        )
+        .unwrap();
+        assert_eq!(dependencies(&q), vec!["a", "b", "c"]);
+
+        let q = sql_parser::parse_query("CREATE TABLE a (x int);").unwrap();
+        assert!(dependencies(&q).is_empty());
+    }
+}
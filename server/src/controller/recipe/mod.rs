@@ -1,22 +1,49 @@
+use crate::controller::security::policy::Policy;
 use crate::controller::security::SecurityConfig;
-use crate::controller::sql::SqlIncorporator;
+use crate::controller::sql::security::write_conditions;
+use crate::controller::sql::{SqlError, SqlIncorporator};
 use crate::controller::Migration;
 use crate::ReuseConfigType;
+use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::trigger::Trigger;
 use dataflow::ops::trigger::TriggerEvent;
 use dataflow::prelude::DataType;
+use mir::query::QueryFlowParts;
 use nom_sql::parser as sql_parser;
-use nom_sql::SqlQuery;
-use noria::ActivationResult;
+use nom_sql::{SelectStatement, SqlQuery, SqlType};
+use noria::error::RecipeError;
+use noria::{ActivationResult, RecipeChange, RecipeVersion, TableStatistics};
 use petgraph::graph::NodeIndex;
 
+use self::alter::Alteration;
+use self::drop::DropTarget;
+use self::options::{TableOptions, ViewOptions};
 use nom_sql::CreateTableStatement;
 use slog;
+use std::any::Any;
 use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::str;
 use std::vec::Vec;
 
+mod alter;
+mod drop;
+mod options;
+
 type QueryID = u64;
+/// Parsed recipe expressions as (name, query, public).
+type ParsedQueries = Vec<(Option<String>, SqlQuery, bool)>;
+/// A renamed base table column, as (table, from, to).
+type ColumnRename = (String, String, String);
+/// A parsed recipe as (expressions, table options, view options, alterations, dropped
+/// expressions). Each alteration comes with the number of expressions that precede it.
+type ParsedRecipe = (
+    ParsedQueries,
+    HashMap<String, TableOptions>,
+    HashMap<String, ViewOptions>,
+    Vec<(usize, Alteration)>,
+    Vec<DropTarget>,
+);
 
 /// Represents a Soup recipe.
 #[derive(Clone, Debug)]
@@ -28,6 +55,18 @@ pub(crate) struct Recipe {
     expression_order: Vec<QueryID>,
     /// Named read/write expression aliases, mapping to queries in `expressions`.
     aliases: HashMap<String, QueryID>,
+    /// Options given to base tables in `CREATE TABLE ... WITH (...)`, keyed by table name.
+    table_options: HashMap<String, TableOptions>,
+    /// Options given to named queries in `QUERY name WITH (...): ...`, keyed by query name.
+    view_options: HashMap<String, ViewOptions>,
+    /// `ALTER TABLE` statements for tables that this recipe doesn't define. These are applied to
+    /// the tables of the recipe that this one extends.
+    alterations: Vec<Alteration>,
+    /// `DROP` statements for expressions that this recipe doesn't define. These are applied to
+    /// the recipe that this one extends.
+    drops: Vec<DropTarget>,
+    /// Base table columns renamed in this revision of the recipe.
+    column_renames: Vec<ColumnRename>,
     /// Security configuration
     security_config: Option<SecurityConfig>,
 
@@ -50,6 +89,8 @@ impl PartialEq for Recipe {
         self.expressions == other.expressions
             && self.expression_order == other.expression_order
             && self.aliases == other.aliases
+            && self.table_options == other.table_options
+            && self.view_options == other.view_options
             && self.version == other.version
             && self.prior == other.prior
     }
@@ -123,6 +164,130 @@ fn query_exprs(input: &str) -> nom::IResult<&str, Vec<(bool, Option<&str>, SqlQu
     nom::multi::many1(query_expr)(input)
 }
 
+/// The type of the column that `options` asks to range partition by, and the partition bounds as
+/// values of that type.
+fn partition_bounds(
+    ctq: &CreateTableStatement,
+    options: &TableOptions,
+) -> Result<Option<(SqlType, Vec<DataType>)>, String> {
+    let (column, bounds) = match options.partition_by {
+        Some((ref column, ref bounds)) => (column, bounds),
+        None => return Ok(None),
+    };
+    let spec = ctq
+        .fields
+        .iter()
+        .find(|f| f.column.name == *column)
+        .ok_or_else(|| format!("table {} has no column named {}", ctq.table.name, column))?;
+    let bounds = bounds
+        .iter()
+        .map(|b| {
+            DataType::from(&**b)
+                .coerce_to(&spec.sql_type)
+                .ok_or_else(|| format!("invalid partition bound \"{}\" for {}", b, column))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Some((spec.sql_type.clone(), bounds)))
+}
+
+/// How to refer to the recipe expression `q`, named `n`, in errors.
+fn expression_name(n: &Option<String>, q: &SqlQuery) -> String {
+    match (n, q) {
+        (Some(ref n), _) => n.clone(),
+        (None, SqlQuery::CreateTable(ref ctq)) => ctq.table.name.clone(),
+        (None, SqlQuery::Select(ref sq)) => sq.to_string(),
+        (None, _) => "<unnamed query>".to_owned(),
+    }
+}
+
+/// The message that a panic was started with.
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|e| (*e).to_owned())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_owned())
+}
+
+/// How many versions of the recipe are kept around to be rolled back to.
+pub(super) const HISTORY_LEN: usize = 64;
+
+/// How many bytes of recipe text the kept versions, and the texts they build on, may add up to, so
+/// that the controller's state stays well clear of the size limit of what the authority can store
+/// under one key.
+pub(super) const HISTORY_BYTES: usize = 256 * 1024;
+
+/// The recipe texts that, applied in order to a blank recipe, reproduce recipe `version`.
+///
+/// `history` lists recipe versions in the order they were applied, and `base` holds the texts that
+/// reproduce the version just before the first of them. Version 0 is the blank recipe.
+pub(super) fn replay_texts(
+    base: &[String],
+    history: &[RecipeVersion],
+    version: usize,
+) -> Result<Vec<String>, String> {
+    if version == 0 {
+        return Ok(vec![]);
+    }
+
+    let i = history
+        .iter()
+        .position(|v| v.version == version)
+        .ok_or_else(|| format!("no stored recipe has version {}", version))?;
+    match history[i].change {
+        RecipeChange::Install => Ok(vec![history[i].text.clone()]),
+        RecipeChange::Extend => {
+            // extensions build on whichever version was applied just before them
+            let mut texts = match i.checked_sub(1) {
+                Some(prev) => replay_texts(base, history, history[prev].version)?,
+                None => base.to_vec(),
+            };
+            texts.push(history[i].text.clone());
+            Ok(texts)
+        }
+        RecipeChange::Rollback(to) if to == 0 || history.iter().any(|v| v.version == to) => {
+            replay_texts(base, history, to)
+        }
+        // the version that was rolled back to has since been forgotten, but a rollback also
+        // stores the full text it restored
+        RecipeChange::Rollback(_) => Ok(vec![history[i].text.clone()]),
+    }
+}
+
+/// Forget the oldest versions in `history` until at most `max_len` are left and their texts, along
+/// with those in `base`, add up to at most `max_bytes`. The latest version is always kept.
+///
+/// `base` is moved forward along with the oldest version that is left, so that all the versions
+/// that are left can still be replayed with `replay_texts`, and is emptied once none of them need
+/// it.
+pub(super) fn prune_history(
+    base: &mut Vec<String>,
+    history: &mut Vec<RecipeVersion>,
+    max_len: usize,
+    max_bytes: usize,
+) {
+    loop {
+        // only a chain of extensions that starts at the oldest version builds on the base
+        match history.first() {
+            Some(&RecipeVersion {
+                change: RecipeChange::Extend,
+                ..
+            }) => {}
+            _ => base.clear(),
+        }
+
+        let bytes = history.iter().map(|v| v.text.len()).sum::<usize>()
+            + base.iter().map(String::len).sum::<usize>();
+        if history.len() <= 1 || (history.len() <= max_len && bytes <= max_bytes) {
+            return;
+        }
+
+        *base = replay_texts(base, history, history[0].version)
+            .expect("every stored version can be replayed");
+        history.remove(0);
+    }
+}
+
 #[allow(unused)]
 impl Recipe {
     /// Return security groups in the recipe
@@ -133,6 +298,59 @@ impl Recipe {
         }
     }
 
+    /// Tables that the security configuration restricts the writes of user universes to.
+    pub(in crate::controller) fn write_policy_tables(&self) -> Vec<String> {
+        let mut tables: Vec<_> = match self.security_config {
+            Some(ref config) => config
+                .policies()
+                .iter()
+                .filter_map(|p| match *p {
+                    Policy::AllowWrite(ref p) => Some(p.table.clone()),
+                    _ => None,
+                })
+                .collect(),
+            None => vec![],
+        };
+        tables.sort();
+        tables.dedup();
+        tables
+    }
+
+    /// Compile the write policies on `table` into the conditions that its base, which has the
+    /// given `fields`, checks the rows written by the user universe with `context` against.
+    pub(in crate::controller) fn write_conditions(
+        &self,
+        table: &str,
+        fields: &[String],
+        context: &HashMap<String, DataType>,
+    ) -> Result<Vec<Vec<(usize, FilterCondition)>>, String> {
+        let policies: &[Policy] = match self.security_config {
+            Some(ref config) => config.policies(),
+            None => &[],
+        };
+        let mut alternatives = Vec::new();
+        for policy in policies {
+            let p = match *policy {
+                Policy::AllowWrite(ref p) if p.table == table => p,
+                _ => continue,
+            };
+            let conditions = match p.predicate {
+                SqlQuery::Select(SelectStatement {
+                    where_clause: Some(ref ce),
+                    ..
+                }) => write_conditions(ce, table, fields, context),
+                // a write policy without a predicate admits every row
+                _ => Ok(vec![vec![]]),
+            }
+            .map_err(|e| match p.name.as_str() {
+                "" => format!("write policy on {}: {}", table, e),
+                name => format!("write policy \"{}\" on {}: {}", name, table, e),
+            })?;
+            alternatives.extend(conditions);
+        }
+        Ok(alternatives)
+    }
+
     /// Return active aliases for expressions
     fn aliases(&self) -> Vec<&str> {
         self.aliases.keys().map(String::as_str).collect()
@@ -146,6 +364,11 @@ impl Recipe {
             expressions: HashMap::default(),
             expression_order: Vec::default(),
             aliases: HashMap::default(),
+            table_options: HashMap::default(),
+            view_options: HashMap::default(),
+            alterations: Vec::default(),
+            drops: Vec::default(),
+            column_renames: Vec::default(),
             version: 0,
             prior: None,
             inc: match log {
@@ -229,9 +452,13 @@ impl Recipe {
     }
 
     /// Set recipe's security configuration
-    pub(in crate::controller) fn set_security_config(&mut self, config_text: &str) {
-        let mut config = SecurityConfig::parse(config_text);
+    pub(in crate::controller) fn set_security_config(
+        &mut self,
+        config_text: &str,
+    ) -> Result<(), String> {
+        let config = SecurityConfig::parse(config_text)?;
         self.security_config = Some(config);
+        Ok(())
     }
 
     /// Creates a recipe from a set of SQL queries in a string (e.g., read from a file).
@@ -249,9 +476,48 @@ impl Recipe {
         let cleaned_recipe_text = lines.join("\n");
 
         // parse and compute differences to current recipe
-        let parsed_queries = Recipe::parse(&cleaned_recipe_text)?;
+        let (mut parsed_queries, table_options, view_options, alterations, drops) =
+            Recipe::parse(&cleaned_recipe_text)?;
+
+        // apply alterations to the tables defined in the recipe text itself, and hold on to the
+        // rest until the recipe is used to extend another one
+        let mut pending = Vec::new();
+        let mut column_renames = Vec::new();
+        for (position, alteration) in alterations {
+            // the expressions that precede the alteration still use the column's old name
+            if let Some(rename) = alteration.rename() {
+                let schemas = alter::table_columns(parsed_queries.iter().map(|(_, q, _)| q));
+                for (_, q, _) in &mut parsed_queries[..position] {
+                    if let Some(renamed) = alter::rename_in_query(q, &rename, &schemas) {
+                        *q = renamed;
+                    }
+                }
+            }
+
+            let table = parsed_queries
+                .iter_mut()
+                .rev()
+                .find_map(|(_, q, _)| match q {
+                    SqlQuery::CreateTable(ctq) if ctq.table.name == alteration.table() => Some(ctq),
+                    _ => None,
+                });
+            match table {
+                Some(ctq) => {
+                    *ctq = alteration.apply(ctq)?;
+                    column_renames.extend(alteration.rename());
+                }
+                None => pending.push(alteration),
+            }
+        }
 
-        Ok(Recipe::from_queries(parsed_queries, log))
+        let mut recipe = Recipe::from_queries(parsed_queries, log);
+        recipe.table_options = table_options;
+        recipe.view_options = view_options;
+        recipe.alterations = pending;
+        recipe.drops = drops;
+        recipe.column_renames = column_renames;
+        recipe.rename_option_columns();
+        Ok(recipe)
     }
 
     /// Creates a recipe from a set of pre-parsed `SqlQuery` structures.
@@ -304,6 +570,11 @@ impl Recipe {
             expressions,
             expression_order,
             aliases,
+            table_options: HashMap::default(),
+            view_options: HashMap::default(),
+            alterations: Vec::default(),
+            drops: Vec::default(),
+            column_renames: Vec::default(),
             security_config: None,
             version: 0,
             prior: None,
@@ -380,11 +651,38 @@ impl Recipe {
         Ok(result)
     }
 
+    /// Removes the security universe of user (or group) `id`, along with every query and policy
+    /// that was added for it.
+    ///
+    /// The universe's nodes are left in the graph; the caller must remove the returned leaves.
+    pub(in crate::controller) fn destroy_universe(
+        &mut self,
+        id: DataType,
+        group: Option<DataType>,
+    ) -> Result<ActivationResult, String> {
+        let removed_leaves = self.inc.as_mut().unwrap().remove_universe(&(id, group))?;
+
+        Ok(ActivationResult {
+            new_nodes: HashMap::default(),
+            expressions_added: 0,
+            expressions_removed: removed_leaves.len(),
+            removed_leaves,
+        })
+    }
+
+    /// The leaf nodes of the queries in each user universe, by user id.
+    pub(in crate::controller) fn user_universes(&self) -> HashMap<DataType, Vec<NodeIndex>> {
+        self.sql_inc().user_universe_leaves()
+    }
+
     /// Activate the recipe by migrating the Soup data-flow graph wrapped in `mig` to the recipe.
     /// This causes all necessary changes to said graph to be applied; however, it is the caller's
     /// responsibility to call `mig.commit()` afterwards.
     // crate viz for tests
-    pub(crate) fn activate(&mut self, mig: &mut Migration) -> Result<ActivationResult, String> {
+    pub(crate) fn activate(
+        &mut self,
+        mig: &mut Migration,
+    ) -> Result<ActivationResult, RecipeError> {
         debug!(self.log, "{} queries, {} of which are named",
                                  self.expressions.len(),
                                  self.aliases.len(); "version" => self.version);
@@ -424,12 +722,7 @@ impl Recipe {
                     "Creating membership view for group {}",
                     group.name()
                 );
-                let qfp = self.inc.as_mut().unwrap().add_parsed_query(
-                    group.membership(),
-                    Some(group.name()),
-                    true,
-                    mig,
-                )?;
+                let qfp = self.add_query(group.membership(), Some(group.name()), true, mig)?;
 
                 /// Add trigger node below group membership views
                 let group_creation = TriggerEvent::GroupCreation {
@@ -448,18 +741,101 @@ impl Recipe {
             self.security_config = Some(config);
         }
 
+        // a query that is replaced by a new version under the same name (e.g., because it reads a
+        // renamed column) has to be removed before the new version is added, since the
+        // incorporator knows queries by their name
+        let added_names: Vec<&String> = added
+            .iter()
+            .filter_map(|qid| self.expressions[qid].0.as_ref())
+            .collect();
+        let mut replaced = Vec::new();
+        let removed: Vec<_> = removed
+            .into_iter()
+            .filter(|qid| match self.prior.as_ref().unwrap().expressions[qid] {
+                (Some(ref n), ref q, _)
+                    if !matches!(*q, SqlQuery::CreateTable(_)) && added_names.contains(&n) =>
+                {
+                    replaced.push(n.clone());
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        for n in replaced {
+            result
+                .removed_leaves
+                .extend(self.inc.as_mut().unwrap().remove_query(&n, mig));
+        }
+
         // add new queries to the Soup graph carried by `mig`, and reflect state in the
         // incorporator in `inc`. `NodeIndex`es for new nodes are collected in `new_nodes` to be
         // returned to the caller (who may use them to obtain mutators and getters)
+        let mut added_tables = Vec::new();
         for qid in added {
             let (n, q, is_leaf) = self.expressions[&qid].clone();
+            let name = expression_name(&n, &q);
+            let invalid = |reason| RecipeError::Invalid {
+                query: name.clone(),
+                reason,
+            };
+
+            let options = match q {
+                SqlQuery::CreateTable(ref ctq) => {
+                    // tell the incorporator about renamed columns before it adapts the base, so
+                    // that it doesn't mistake them for a dropped column and a new one
+                    let inc = self.inc.as_mut().unwrap();
+                    for &(ref table, ref from, ref to) in &self.column_renames {
+                        if *table == ctq.table.name {
+                            inc.rename_base_column(table, from, to);
+                        }
+                    }
+                    added_tables.push(ctq.table.name.clone());
+                    match self.table_options.get(&ctq.table.name) {
+                        Some(options) => Some((
+                            options.clone(),
+                            partition_bounds(ctq, options).map_err(invalid)?,
+                        )),
+                        None => None,
+                    }
+                }
+                _ => None,
+            };
 
             // add the query
-            let qfp = self
-                .inc
-                .as_mut()
-                .unwrap()
-                .add_parsed_query(q, n.clone(), is_leaf, mig)?;
+            let qfp = self.add_query(q, n.clone(), is_leaf, mig)?;
+
+            if let Some((options, partition)) = options {
+                if let Some(ref storage) = options.storage {
+                    mig.set_base_storage(qfp.query_leaf, storage)
+                        .map_err(invalid)?;
+                }
+                if let Some((ref column, ttl)) = options.ttl {
+                    mig.set_base_retention(qfp.query_leaf, column, ttl)
+                        .map_err(invalid)?;
+                }
+                if let Some(shards) = options.shards {
+                    mig.set_base_sharding(qfp.query_leaf, shards)
+                        .map_err(invalid)?;
+                }
+                if let (Some((ref column, _)), Some((ty, bounds))) =
+                    (&options.partition_by, partition)
+                {
+                    mig.set_base_partitioning(qfp.query_leaf, column, ty, bounds)
+                        .map_err(invalid)?;
+                }
+            }
+            if let Some(options) = n.as_ref().and_then(|n| self.view_options.get(n)) {
+                mig.set_view_eviction(
+                    qfp.query_leaf,
+                    options.memory_limit,
+                    options.priority.unwrap_or_default(),
+                )
+                .map_err(invalid)?;
+                if let Some(shards) = options.shards {
+                    mig.set_view_sharding(qfp.query_leaf, shards)
+                        .map_err(invalid)?;
+                }
+            }
 
             // If the user provided us with a query name, use that.
             // If not, use the name internally used by the QFP.
@@ -471,11 +847,16 @@ impl Recipe {
             result.new_nodes.insert(query_name, qfp.query_leaf);
         }
 
-        result.removed_leaves = removed
+        let removed_leaves: Vec<_> = removed
             .iter()
             .filter_map(|qid| {
                 let (ref n, ref q, _) = self.prior.as_ref().unwrap().expressions[qid];
                 match q {
+                    SqlQuery::CreateTable(ref ctq) if added_tables.contains(&ctq.table.name) => {
+                        // the table was redefined (e.g., by `ALTER TABLE`), and its base adapted
+                        // rather than replaced, so there is nothing to remove
+                        None
+                    }
                     SqlQuery::CreateTable(ref ctq) => {
                         // a base may have many dependent queries, including ones that also lost
                         // nodes; the code handling `removed_leaves` therefore needs to take care
@@ -494,18 +875,49 @@ impl Recipe {
                             }
                         }
                     }
-                    _ => self
-                        .inc
-                        .as_mut()
-                        .unwrap()
-                        .remove_query(n.as_ref().unwrap(), mig),
+                    _ => match *n {
+                        Some(ref n) => self.inc.as_mut().unwrap().remove_query(n, mig),
+                        None => {
+                            // the incorporator only knows unnamed queries by a name it made up,
+                            // so their nodes stay in the graph
+                            warn!(self.log, "cannot remove unnamed query {}", q);
+                            None
+                        }
+                    },
                 }
             })
             .collect();
+        result.removed_leaves.extend(removed_leaves);
 
         Ok(result)
     }
 
+    /// Adds the expression `q`, named `n`, to the graph using `mig`.
+    ///
+    /// If the incorporator panics on a query it can't handle, the panic is reported as the query
+    /// being unsupported; the caller must then discard the migration and the incorporator state,
+    /// since the incorporator may have been left half-way through adding the query.
+    fn add_query(
+        &mut self,
+        q: SqlQuery,
+        n: Option<String>,
+        is_leaf: bool,
+        mig: &mut Migration,
+    ) -> Result<QueryFlowParts, RecipeError> {
+        let name = expression_name(&n, &q);
+        let inc = self.inc.as_mut().unwrap();
+        match panic::catch_unwind(AssertUnwindSafe(|| {
+            inc.add_parsed_query(q, n, is_leaf, mig)
+        })) {
+            Ok(r) => r.map_err(|e| e.for_query(&name)),
+            Err(payload) => {
+                let e = panic_message(&*payload);
+                crit!(self.log, "failed to plan query {}: {}", name, e);
+                Err(SqlError::Unsupported(e).for_query(&name))
+            }
+        }
+    }
+
     /// Work out the delta between two recipes.
     /// Returns two sets of `QueryID` -> `SqlQuery` mappings:
     /// (1) those queries present in `self`, but not in `other`; and
@@ -548,6 +960,14 @@ impl Recipe {
             Err(e) => return Err((self, e)),
         };
         let (added, _) = add_rp.compute_delta(&self);
+        let altered = match self.alter_tables(&add_rp.alterations) {
+            Ok(altered) => altered,
+            Err(e) => return Err((self, e)),
+        };
+        let dropped = match self.resolve_drops(&add_rp) {
+            Ok(dropped) => dropped,
+            Err(e) => return Err((self, e)),
+        };
 
         // move the incorporator state from the old recipe to the new one
         let prior_inc = self.inc.take();
@@ -557,6 +977,11 @@ impl Recipe {
             expressions: self.expressions.clone(),
             expression_order: self.expression_order.clone(),
             aliases: self.aliases.clone(),
+            table_options: self.table_options.clone(),
+            view_options: self.view_options.clone(),
+            alterations: Vec::default(),
+            drops: Vec::default(),
+            column_renames: add_rp.column_renames.clone(),
             version: self.version + 1,
             inc: prior_inc,
             log: self.log.clone(),
@@ -566,6 +991,23 @@ impl Recipe {
         };
 
         // apply changes
+        for (qid, ctq) in altered {
+            new.replace_expression(qid, SqlQuery::CreateTable(ctq));
+        }
+        for rename in add_rp.alterations.iter().filter_map(Alteration::rename) {
+            new.rename_in_queries(&rename);
+            new.column_renames.push(rename);
+        }
+        for qid in &dropped {
+            new.expressions.remove(qid);
+        }
+        new.expression_order.retain(|qid| !dropped.contains(qid));
+        for d in &add_rp.drops {
+            new.aliases.remove(d.name());
+            new.table_options.remove(d.name());
+            new.view_options.remove(d.name());
+        }
+        new.aliases.retain(|_, qid| !dropped.contains(qid));
         for qid in added {
             let q = add_rp.expressions[&qid].clone();
             new.expressions.insert(qid, q);
@@ -580,11 +1022,209 @@ impl Recipe {
             );
         }
         new.aliases.extend(add_rp.aliases);
+        new.table_options.extend(add_rp.table_options);
+        new.view_options.extend(add_rp.view_options);
+        new.rename_option_columns();
 
         // return new recipe as replacement for self
         Ok(new)
     }
 
+    /// Work out which expressions of this recipe the `DROP` statements in `additions` remove.
+    ///
+    /// Fails if a dropped table or view doesn't exist, if `additions` defines it again, or if an
+    /// expression that is kept, or one in `additions`, still reads from it. A view that other names
+    /// still refer to only loses the dropped name.
+    fn resolve_drops(&self, additions: &Recipe) -> Result<Vec<QueryID>, String> {
+        let names: Vec<&str> = additions.drops.iter().map(DropTarget::name).collect();
+        let mut dropped = Vec::new();
+        for d in &additions.drops {
+            let qid = match *d {
+                DropTarget::Table(ref name) => {
+                    self.expression_order.iter().rev().cloned().find(|qid| {
+                        matches!(self.expressions[qid].1,
+                                 SqlQuery::CreateTable(ref ctq) if ctq.table.name == *name)
+                    })
+                }
+                DropTarget::View(ref name) => self
+                    .aliases
+                    .get(name)
+                    .cloned()
+                    .filter(|qid| !matches!(self.expressions[qid].1, SqlQuery::CreateTable(_))),
+            };
+            let qid = qid.ok_or_else(|| {
+                format!(
+                    "cannot drop {} {}, which does not exist",
+                    d.kind(),
+                    d.name()
+                )
+            })?;
+
+            let redefined = additions.aliases.contains_key(d.name())
+                || additions.expressions.values().any(|(_, q, _)| {
+                    matches!(*q, SqlQuery::CreateTable(ref ctq) if ctq.table.name == d.name())
+                });
+            if redefined {
+                return Err(format!(
+                    "cannot drop {} {} and define it again in the same recipe change",
+                    d.kind(),
+                    d.name()
+                ));
+            }
+
+            if !self
+                .aliases
+                .iter()
+                .any(|(n, q)| *q == qid && !names.contains(&n.as_str()))
+            {
+                dropped.push(qid);
+            }
+        }
+
+        let kept = self
+            .expressions
+            .iter()
+            .filter(|(qid, _)| !dropped.contains(qid))
+            .chain(additions.expressions.iter());
+        for (_, (n, q, _)) in kept {
+            if let Some(dep) = drop::dependencies(q)
+                .into_iter()
+                .find(|dep| names.contains(dep))
+            {
+                let d = additions.drops.iter().find(|d| d.name() == dep).unwrap();
+                return Err(format!(
+                    "cannot drop {} {}, as {} still depends on it",
+                    d.kind(),
+                    dep,
+                    expression_name(n, q)
+                ));
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    /// Rebuild a recipe by applying `texts` in order to a blank recipe, as produced by
+    /// `replay_texts`.
+    pub(super) fn replay(texts: &[String], log: Option<slog::Logger>) -> Result<Recipe, String> {
+        let mut r = Recipe::blank(log);
+        for t in texts {
+            r = r.extend(t).map_err(|(_, e)| e)?;
+        }
+        // the replayed recipe describes what the tables look like, not how they got that way
+        r.column_renames.clear();
+        Ok(r)
+    }
+
+    /// Apply `alterations` to the tables of this recipe.
+    ///
+    /// Returns the `QueryID` of each altered table's current definition, along with its new
+    /// definition.
+    fn alter_tables(
+        &self,
+        alterations: &[Alteration],
+    ) -> Result<Vec<(QueryID, CreateTableStatement)>, String> {
+        let mut altered: Vec<(QueryID, CreateTableStatement)> = Vec::new();
+        for alteration in alterations {
+            let i = match altered
+                .iter()
+                .position(|&(_, ref ctq)| ctq.table.name == alteration.table())
+            {
+                Some(i) => i,
+                None => {
+                    let table = self.expression_order.iter().rev().find_map(|qid| {
+                        match self.expressions[qid].1 {
+                            SqlQuery::CreateTable(ref ctq)
+                                if ctq.table.name == alteration.table() =>
+                            {
+                                Some((*qid, ctq.clone()))
+                            }
+                            _ => None,
+                        }
+                    });
+                    match table {
+                        Some(table) => altered.push(table),
+                        None => {
+                            return Err(format!(
+                                "cannot alter table {}, which does not exist",
+                                alteration.table()
+                            ))
+                        }
+                    }
+                    altered.len() - 1
+                }
+            };
+            altered[i].1 = alteration.apply(&altered[i].1)?;
+        }
+        Ok(altered)
+    }
+
+    /// Replace the expression `old` with `q`, keeping its name and position.
+    fn replace_expression(&mut self, old: QueryID, q: SqlQuery) {
+        let (n, _, is_leaf) = self.expressions.remove(&old).unwrap();
+        let qid = hash_query(&q);
+        for e in self.expression_order.iter_mut().filter(|e| **e == old) {
+            *e = qid;
+        }
+        for alias in self.aliases.values_mut().filter(|a| **a == old) {
+            *alias = qid;
+        }
+        self.expressions.insert(qid, (n, q, is_leaf));
+    }
+
+    /// Make the queries of this recipe refer to a renamed column by its new name. Each query that
+    /// is rewritten becomes a new expression, so that activating the recipe plans it again.
+    fn rename_in_queries(&mut self, rename: &ColumnRename) {
+        let schemas = alter::table_columns(self.expressions.values().map(|(_, q, _)| q));
+        let renamed: Vec<_> = self
+            .expressions
+            .iter()
+            .filter_map(|(qid, (_, q, _))| {
+                alter::rename_in_query(q, rename, &schemas).map(|q| (*qid, q))
+            })
+            .collect();
+        for (qid, q) in renamed {
+            self.replace_expression(qid, q);
+        }
+    }
+
+    /// Make table options refer to the new names of columns renamed in this revision.
+    fn rename_option_columns(&mut self) {
+        for &(ref table, ref from, ref to) in &self.column_renames {
+            if let Some(options) = self.table_options.get_mut(table) {
+                options.rename_column(from, to);
+            }
+        }
+    }
+
+    /// Checks that every `ALTER TABLE` in the recipe refers to a table that the recipe defines,
+    /// and that the recipe doesn't `DROP` anything, since it has nothing to drop from.
+    pub(super) fn check_alterations(&self) -> Result<(), String> {
+        if let Some(d) = self.drops.first() {
+            return Err(format!(
+                "cannot drop {} {}, as DROP can only be used to extend a recipe",
+                d.kind(),
+                d.name()
+            ));
+        }
+        match self.alterations.first() {
+            None => Ok(()),
+            Some(alteration) => Err(format!(
+                "cannot alter table {}, which does not exist",
+                alteration.table()
+            )),
+        }
+    }
+
+    /// Record that the view `name` now has `shards` shards, so that the recipe stays in line with
+    /// the graph after the view is resharded.
+    pub(super) fn set_view_shards(&mut self, name: &str, shards: usize) {
+        self.view_options
+            .entry(name.to_owned())
+            .or_insert_with(ViewOptions::default)
+            .shards = Some(shards);
+    }
+
     /// Helper method to reparent a recipe. This is needed for the recovery logic to build
     /// recovery and original recipe (see `make_recovery`).
     pub(in crate::controller) fn set_prior(&mut self, new_prior: Recipe) {
@@ -601,7 +1241,14 @@ impl Recipe {
         self.inc = Some(new_inc);
     }
 
-    fn parse(recipe_text: &str) -> Result<Vec<(Option<String>, SqlQuery, bool)>, String> {
+    /// Order the joins of the queries this recipe adds using the given base table statistics.
+    pub(super) fn set_table_statistics(&mut self, tables: HashMap<String, TableStatistics>) {
+        if let Some(ref mut inc) = self.inc {
+            inc.set_table_statistics(tables);
+        }
+    }
+
+    fn parse(recipe_text: &str) -> Result<ParsedRecipe, String> {
         let lines: Vec<&str> = recipe_text
             .lines()
             .filter(|l| !l.is_empty() && !l.starts_with('#'))
@@ -632,10 +1279,36 @@ impl Recipe {
             i += 1;
         }
 
+        let mut table_options = HashMap::new();
+        let mut view_options = HashMap::new();
+        let mut alterations = Vec::new();
+        let mut drops = Vec::new();
         let parsed_queries = query_strings.iter().fold(
             Vec::new(),
-            |mut acc: Vec<Result<(bool, Option<&str>, SqlQuery), String>>, q| {
-                match query_exprs(q) {
+            |mut acc: Vec<Result<(bool, Option<String>, SqlQuery), String>>, q| {
+                // nor can it parse `ALTER TABLE`
+                if let Some(alteration) = alter::parse_alter(q) {
+                    match alteration {
+                        Ok(alteration) => alterations.push((acc.len(), alteration)),
+                        Err(e) => acc.push(Err(format!("Query \"{}\": {}", q, e))),
+                    }
+                    return acc;
+                }
+                // we handle `DROP` ourselves too, since nom-sql only knows `DROP TABLE`
+                if let Some(dropped) = drop::parse_drop(q) {
+                    match dropped {
+                        Ok(dropped) => drops.extend(dropped),
+                        Err(e) => acc.push(Err(format!("Query \"{}\": {}", q, e))),
+                    }
+                    return acc;
+                }
+
+                // nom-sql can't parse `WITH (...)` options, so we handle those ourselves
+                let (q, options) = match options::split_query_options(q) {
+                    (q, None) => options::split_options(&q),
+                    split => split,
+                };
+                match query_exprs(&q) {
                     Result::Err(e) => {
                         // we got a parse error
                         acc.push(Err(format!("Query \"{}\", parse error: {}", q, e)));
@@ -649,20 +1322,48 @@ impl Recipe {
                                 remainder
                             )
                         );
-                        acc.extend(parsed.into_iter().map(|p| Ok(p)).collect::<Vec<_>>());
+                        if let Some(options) = options {
+                            match parsed.last() {
+                                Some((_, _, SqlQuery::CreateTable(ref ctq))) => {
+                                    match TableOptions::from_pairs(options) {
+                                        Ok(options) => {
+                                            table_options.insert(ctq.table.name.clone(), options);
+                                        }
+                                        Err(e) => acc.push(Err(format!("Query \"{}\": {}", q, e))),
+                                    }
+                                }
+                                Some((_, Some(name), _)) => match ViewOptions::from_pairs(options) {
+                                    Ok(options) => {
+                                        view_options.insert((*name).to_owned(), options);
+                                    }
+                                    Err(e) => acc.push(Err(format!("Query \"{}\": {}", q, e))),
+                                },
+                                _ => acc.push(Err(format!(
+                                    "Query \"{}\": WITH options are only supported on CREATE TABLE and named queries",
+                                    q
+                                ))),
+                            }
+                        }
+                        acc.extend(parsed.into_iter().map(|(public, name, expr)| {
+                            Ok((public, name.map(String::from), expr))
+                        }));
                     }
                 }
                 acc
             },
         );
 
-        Ok(parsed_queries
+        let parsed_queries = parsed_queries
             .into_iter()
-            .map(|pr| {
-                let pr = pr.unwrap();
-                (pr.1.map(String::from), pr.2, pr.0)
-            })
-            .collect::<Vec<_>>())
+            .map(|pr| pr.map(|(public, name, expr)| (name, expr, public)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((
+            parsed_queries,
+            table_options,
+            view_options,
+            alterations,
+            drops,
+        ))
     }
 
     /// Returns the predecessor from which this `Recipe` was migrated to.
@@ -742,6 +1443,24 @@ impl Recipe {
             .collect()
     }
 
+    /// The SQL of the recipe expressions with the given names, in recipe order.
+    pub(super) fn sql_for_queries(&self, names: &[String]) -> Vec<String> {
+        self.expression_order
+            .iter()
+            .filter_map(|qid| self.expressions.get(qid))
+            .filter(|(n, q, _)| names.contains(&expression_name(n, q)))
+            .map(|(_, q, _)| q.to_string())
+            .collect()
+    }
+
+    /// Describe the MIR nodes that each data-flow node was made from.
+    pub(super) fn mir_by_flow_node(&self) -> HashMap<NodeIndex, Vec<String>> {
+        self.inc
+            .as_ref()
+            .expect("need SQL incorporator")
+            .mir_by_flow_node()
+    }
+
     pub(super) fn make_recovery(&self, mut affected_queries: Vec<String>) -> (Recipe, Recipe) {
         affected_queries.sort();
         affected_queries.dedup();
@@ -764,6 +1483,78 @@ impl Recipe {
 
         (recovery, original)
     }
+
+    /// Build the recipes that move the table `table` onto a new base with `shards` shards.
+    ///
+    /// The first leaves out the table and the queries in `dependents`, which read from it; the
+    /// second adds the table back with the new shard count, and the third adds back the queries.
+    /// Each is a successor of the one before.
+    ///
+    /// Fails if one of the queries isn't part of the recipe, and so can't be planned again.
+    pub(super) fn make_reshard(
+        &self,
+        table: &str,
+        shards: usize,
+        mut dependents: Vec<String>,
+    ) -> Result<(Recipe, Recipe, Recipe), String> {
+        dependents.sort();
+        dependents.dedup();
+        if let Some(q) = dependents.iter().find(|&q| {
+            !self
+                .expressions
+                .values()
+                .any(|(n, e, _)| expression_name(n, e) == *q)
+        }) {
+            return Err(format!(
+                "cannot reshard table {}, as {} reads from it but isn't part of the recipe",
+                table, q
+            ));
+        }
+
+        // the new base starts out with the table's current columns
+        let mut resharded = self.clone();
+        resharded.column_renames.clear();
+        resharded
+            .table_options
+            .entry(table.to_owned())
+            .or_insert_with(TableOptions::default)
+            .shards = Some(shards);
+
+        let mut with_table = resharded.clone();
+        with_table.remove_expressions(&dependents);
+        with_table.next();
+        with_table.next();
+
+        let mut without = self.clone();
+        without.prior = Some(Box::new(self.clone()));
+        dependents.push(table.to_owned());
+        without.remove_expressions(&dependents);
+        without.next();
+
+        resharded.next();
+        resharded.next();
+        resharded.next();
+
+        Ok((without, with_table, resharded))
+    }
+
+    /// Remove the expressions with the given names, along with every name they go by.
+    fn remove_expressions(&mut self, names: &[String]) {
+        let removed: Vec<QueryID> = self
+            .expression_order
+            .iter()
+            .cloned()
+            .filter(|qid| {
+                let (ref n, ref q, _) = self.expressions[qid];
+                names.contains(&expression_name(n, q))
+            })
+            .collect();
+        for qid in &removed {
+            self.expressions.remove(qid);
+        }
+        self.expression_order.retain(|qid| !removed.contains(qid));
+        self.aliases.retain(|_, qid| !removed.contains(qid));
+    }
 }
 
 #[cfg(test)]
@@ -896,4 +1687,211 @@ mod tests {
         let r1 = r0.replace(r1_t).unwrap();
         assert_eq!(r1.expressions.len(), 2);
     }
+
+    #[test]
+    fn it_alters_tables() {
+        let r0 = Recipe::blank(None);
+
+        let r1_txt = "CREATE TABLE b (a int, x int);\nSELECT a FROM b;";
+        let r1_t = Recipe::from_str(r1_txt, None).unwrap();
+        let r1 = r0.replace(r1_t).unwrap();
+        let order = r1.expression_order.clone();
+
+        let r2 = r1
+            .extend("ALTER TABLE b MODIFY COLUMN x text;\nALTER TABLE b RENAME COLUMN a TO c;")
+            .unwrap();
+        assert_eq!(r2.expressions.len(), 2);
+        assert_ne!(r2.expression_order[0], order[0]);
+        assert_eq!(
+            r2.column_renames,
+            vec![("b".to_owned(), "a".to_owned(), "c".to_owned())]
+        );
+        match r2.expressions[&r2.expression_order[0]].1 {
+            SqlQuery::CreateTable(ref ctq) => {
+                assert_eq!(ctq.fields[0].column.name, "c");
+                assert_eq!(ctq.fields[1].sql_type, nom_sql::SqlType::Text);
+            }
+            ref q => panic!("unexpected query {:?}", q),
+        }
+
+        // the query that reads the renamed column is rewritten, and so replaced
+        assert_ne!(r2.expression_order[1], order[1]);
+        let q = sql_parser::parse_query("SELECT c AS a FROM b;").unwrap();
+        assert_eq!(r2.expressions[&r2.expression_order[1]].1, q);
+        let (added, removed) = r2.compute_delta(r2.prior().unwrap());
+        assert_eq!((added.len(), removed.len()), (2, 2));
+
+        // as are queries that come before the rename in the same recipe text
+        let texts = vec![
+            r1_txt.to_owned(),
+            "ALTER TABLE b MODIFY COLUMN x text;\nALTER TABLE b RENAME COLUMN a TO c;".to_owned(),
+        ];
+        let joined = Recipe::from_str(&texts.join("\n"), None).unwrap();
+        assert_eq!(joined.expression_order, r2.expression_order);
+        assert_eq!(
+            Recipe::replay(&texts, None).unwrap().expression_order,
+            r2.expression_order
+        );
+
+        let (_, e) = r2
+            .extend("ALTER TABLE y MODIFY COLUMN x text;")
+            .unwrap_err();
+        assert!(e.contains("does not exist"));
+    }
+
+    #[test]
+    fn it_drops_expressions() {
+        let r0 = Recipe::blank(None);
+        let r1_txt = "CREATE TABLE a (x int, y int);\n\
+                      CREATE TABLE b (y int);\n\
+                      QUERY ax: SELECT x FROM a WHERE x = ?;\n\
+                      QUERY ab: SELECT a.x FROM a JOIN b ON (a.y = b.y) WHERE a.x = ?;";
+        let r1 = r0.replace(Recipe::from_str(r1_txt, None).unwrap()).unwrap();
+
+        // a table can't be dropped while queries read from it
+        let (r1, e) = r1.extend("DROP TABLE b;").unwrap_err();
+        assert_eq!(e, "cannot drop table b, as ab still depends on it");
+        let (r1, e) = r1.extend("DROP VIEW nope;").unwrap_err();
+        assert!(e.contains("does not exist"));
+        let (r1, e) = r1.extend("DROP TABLE ax;").unwrap_err();
+        assert!(e.contains("does not exist"));
+
+        let r2 = r1.extend("DROP VIEW ab;\nDROP TABLE b;").unwrap();
+        assert_eq!(r2.expressions.len(), 2);
+        assert!(r2.resolve_alias("ab").is_none());
+        assert!(r2.resolve_alias("ax").is_some());
+        let (added, removed) = r2.compute_delta(r2.prior().unwrap());
+        assert!(added.is_empty());
+        assert_eq!(removed.len(), 2);
+
+        // recipes that are installed, rather than extending another, have nothing to drop
+        let r = Recipe::from_str("DROP TABLE a;", None).unwrap();
+        assert!(r.check_alterations().is_err());
+    }
+
+    #[test]
+    fn it_parses_view_options() {
+        use dataflow::EvictionPriority;
+
+        let r_txt = "CREATE TABLE b (a int, x int);\n\
+                     QUERY feed WITH (memory_limit = '2MB', priority = high): SELECT a FROM b;\n\
+                     QUERY digest: SELECT x FROM b WITH (priority = low);\n\
+                     QUERY plain: SELECT a, x FROM b;";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        assert_eq!(r.expressions.len(), 4);
+        assert_eq!(r.view_options.len(), 2);
+        assert_eq!(r.view_options["feed"].memory_limit, Some(2 << 20));
+        assert_eq!(
+            r.view_options["feed"].priority,
+            Some(EvictionPriority::High)
+        );
+        assert_eq!(r.view_options["digest"].memory_limit, None);
+        assert_eq!(
+            r.view_options["digest"].priority,
+            Some(EvictionPriority::Low)
+        );
+    }
+
+    #[test]
+    fn it_replays_history() {
+        use std::time::SystemTime;
+
+        let v = |version, change, text: &str| RecipeVersion {
+            version,
+            change,
+            text: text.to_owned(),
+            applied_at: SystemTime::now(),
+        };
+        let history = vec![
+            v(1, RecipeChange::Extend, "a"),
+            v(2, RecipeChange::Extend, "b"),
+            // a universe was created in between, which also bumps the version
+            v(4, RecipeChange::Install, "c"),
+            v(5, RecipeChange::Extend, "d"),
+            v(6, RecipeChange::Rollback(2), "a\nb"),
+            v(7, RecipeChange::Extend, "e"),
+        ];
+
+        assert_eq!(replay_texts(&[], &history, 0), Ok(vec![]));
+        assert_eq!(
+            replay_texts(&[], &history, 2),
+            Ok(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(
+            replay_texts(&[], &history, 5),
+            Ok(vec!["c".into(), "d".into()])
+        );
+        assert_eq!(
+            replay_texts(&[], &history, 6),
+            Ok(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(
+            replay_texts(&[], &history, 7),
+            Ok(vec!["a".into(), "b".into(), "e".into()])
+        );
+        assert!(replay_texts(&[], &history, 3).is_err());
+    }
+
+    #[test]
+    fn it_prunes_history() {
+        use std::time::SystemTime;
+
+        let v = |version, change, text: &str| RecipeVersion {
+            version,
+            change,
+            text: text.to_owned(),
+            applied_at: SystemTime::now(),
+        };
+        let mut base = Vec::new();
+        let mut history = vec![
+            v(1, RecipeChange::Extend, "a"),
+            v(2, RecipeChange::Extend, "b"),
+            v(3, RecipeChange::Extend, "c"),
+            v(4, RecipeChange::Rollback(1), "a"),
+            v(5, RecipeChange::Extend, "d"),
+        ];
+
+        // short enough already
+        prune_history(&mut base, &mut history, 5, 100);
+        assert_eq!(history.len(), 5);
+
+        // too many versions
+        prune_history(&mut base, &mut history, 3, 100);
+        assert_eq!(history.len(), 3);
+        assert_eq!(base, vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(
+            replay_texts(&base, &history, 3),
+            Ok(vec!["a".into(), "b".into(), "c".into()])
+        );
+        assert_eq!(
+            replay_texts(&base, &history, 5),
+            Ok(vec!["a".into(), "d".into()])
+        );
+        assert!(replay_texts(&base, &history, 2).is_err());
+
+        // too much text, but the latest version stays, and an install doesn't need the base
+        history.push(v(6, RecipeChange::Install, "0123456789"));
+        prune_history(&mut base, &mut history, 10, 5);
+        assert_eq!(history.len(), 1);
+        assert!(base.is_empty());
+        assert_eq!(
+            replay_texts(&base, &history, 6),
+            Ok(vec!["0123456789".into()])
+        );
+
+        // the base counts towards the text that is kept
+        let mut base = Vec::new();
+        let mut history = vec![
+            v(1, RecipeChange::Extend, "aaaa"),
+            v(2, RecipeChange::Extend, "b"),
+            v(3, RecipeChange::Extend, "c"),
+        ];
+        prune_history(&mut base, &mut history, 10, 3);
+        assert_eq!(history.len(), 1);
+        assert_eq!(base, vec!["aaaa".to_owned(), "b".to_owned()]);
+        assert_eq!(
+            replay_texts(&base, &history, 3),
+            Ok(vec!["aaaa".into(), "b".into(), "c".into()])
+        );
+    }
 }
@@ -0,0 +1,459 @@
+//! Options given to recipe statements in a `WITH (key = value, ...)` clause.
+//!
+//! Tables take their options at the end of the statement, while named queries can take them either
+//! at the end or after the query name (`QUERY feed WITH (...): SELECT ...`). nom-sql doesn't know
+//! about these clauses, so they are split off the statement text before it is handed to the SQL
+//! parser.
+
+use dataflow::EvictionPriority;
+use nom::IResult;
+use std::time::Duration;
+
+/// Options for a base table, given as `CREATE TABLE ... WITH (...)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TableOptions {
+    /// The storage backend that holds the table's rows (`storage = 'rocksdb'`).
+    pub(crate) storage: Option<String>,
+    /// Rows are deleted once the time in this column is more than `ttl` in the past
+    /// (`ttl_column = created_at, ttl = '30d'`).
+    pub(crate) ttl: Option<(String, Duration)>,
+    /// How many shards to split the table into, instead of the default sharding factor
+    /// (`shards = 16`).
+    pub(crate) shards: Option<usize>,
+    /// Split the table into ranges of this column, at the given bounds
+    /// (`partition_by = ts, partition_bounds = '2019-01-01, 2020-01-01'`).
+    pub(crate) partition_by: Option<(String, Vec<String>)>,
+}
+
+impl TableOptions {
+    pub(super) fn from_pairs(pairs: Vec<(String, String)>) -> Result<TableOptions, String> {
+        let mut options = TableOptions::default();
+        let mut ttl_column = None;
+        let mut ttl = None;
+        let mut partition_by = None;
+        let mut partition_bounds = None;
+        for (key, value) in pairs {
+            match &*key.to_lowercase() {
+                "storage" => options.storage = Some(value),
+                "ttl_column" => ttl_column = Some(value),
+                "ttl" => ttl = Some(parse_duration(&value)?),
+                "shards" => options.shards = Some(parse_shards(&value)?),
+                "partition_by" => partition_by = Some(value),
+                "partition_bounds" => partition_bounds = Some(parse_bounds(&value)?),
+                _ => return Err(format!("unknown table option \"{}\"", key)),
+            }
+        }
+        options.ttl = match (ttl_column, ttl) {
+            (Some(column), Some(ttl)) => Some((column, ttl)),
+            (None, None) => None,
+            _ => return Err("ttl and ttl_column must be given together".to_owned()),
+        };
+        options.partition_by = match (partition_by, partition_bounds) {
+            (Some(column), Some(bounds)) => {
+                if options
+                    .shards
+                    .map(|s| s != bounds.len() + 1)
+                    .unwrap_or(false)
+                {
+                    return Err(format!(
+                        "{} partition bounds make {} shards, not {}",
+                        bounds.len(),
+                        bounds.len() + 1,
+                        options.shards.unwrap()
+                    ));
+                }
+                Some((column, bounds))
+            }
+            (None, None) => None,
+            _ => return Err("partition_by and partition_bounds must be given together".to_owned()),
+        };
+        Ok(options)
+    }
+
+    /// Follow a rename of one of the table's columns.
+    pub(super) fn rename_column(&mut self, from: &str, to: &str) {
+        if let Some((ref mut column, _)) = self.ttl {
+            if column == from {
+                *column = to.to_owned();
+            }
+        }
+        if let Some((ref mut column, _)) = self.partition_by {
+            if column == from {
+                *column = to.to_owned();
+            }
+        }
+    }
+}
+
+/// Options for a view, given as `QUERY name WITH (...): ...`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ViewOptions {
+    /// The most memory each shard of the view's reader may use (`memory_limit = '2GB'`).
+    pub(crate) memory_limit: Option<usize>,
+    /// How eagerly the view's state is evicted (`priority = high`).
+    pub(crate) priority: Option<EvictionPriority>,
+    /// How many shards to split the view's reader into, instead of the default sharding factor
+    /// (`shards = 4`).
+    pub(crate) shards: Option<usize>,
+}
+
+impl ViewOptions {
+    pub(super) fn from_pairs(pairs: Vec<(String, String)>) -> Result<ViewOptions, String> {
+        let mut options = ViewOptions::default();
+        for (key, value) in pairs {
+            match &*key.to_lowercase() {
+                "memory_limit" => options.memory_limit = Some(parse_size(&value)?),
+                "priority" => {
+                    options.priority = Some(match &*value.to_lowercase() {
+                        "low" => EvictionPriority::Low,
+                        "normal" => EvictionPriority::Normal,
+                        "high" => EvictionPriority::High,
+                        _ => return Err(format!("invalid priority \"{}\"", value)),
+                    })
+                }
+                "shards" => options.shards = Some(parse_shards(&value)?),
+                _ => return Err(format!("unknown query option \"{}\"", key)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Parse a shard count, which must be at least 1.
+fn parse_shards(value: &str) -> Result<usize, String> {
+    match value.trim().parse() {
+        Ok(shards) if shards > 0 => Ok(shards),
+        _ => Err(format!("invalid shard count \"{}\"", value)),
+    }
+}
+
+/// Parse a comma-separated list of partition bounds, such as `100, 200, 300`.
+fn parse_bounds(value: &str) -> Result<Vec<String>, String> {
+    let bounds: Vec<_> = value.split(',').map(|b| b.trim().to_owned()).collect();
+    if bounds.iter().any(String::is_empty) {
+        return Err(format!("invalid partition bounds \"{}\"", value));
+    }
+    Ok(bounds)
+}
+
+/// Parse a size such as `512KB`, `100MB` or `2GB`. A bare number is in bytes.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| value.len());
+    let (n, unit) = value.split_at(split);
+    let n: usize = n
+        .parse()
+        .map_err(|_| format!("invalid size \"{}\"", value))?;
+    let unit = match &*unit.trim().to_uppercase() {
+        "" | "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        "TB" => 1 << 40,
+        _ => return Err(format!("invalid size \"{}\"", value)),
+    };
+    n.checked_mul(unit)
+        .ok_or_else(|| format!("invalid size \"{}\"", value))
+}
+
+/// Parse a duration such as `90s`, `15m`, `12h`, `30d`, or `2w`. A bare number is in seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| value.len());
+    let (n, unit) = value.split_at(split);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("invalid duration \"{}\"", value))?;
+    let unit = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration \"{}\"", value)),
+    };
+    n.checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration \"{}\"", value))
+}
+
+fn option_value(input: &str) -> IResult<&str, String> {
+    use nom::branch::alt;
+    use nom::bytes::complete::{is_not, take_while1};
+    use nom::character::complete::char;
+    use nom::combinator::{map, opt};
+    use nom::sequence::delimited;
+
+    alt((
+        map(
+            delimited(char('\''), opt(is_not("'")), char('\'')),
+            |v: Option<&str>| v.unwrap_or("").to_owned(),
+        ),
+        map(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+            String::from,
+        ),
+    ))(input)
+}
+
+fn option_pair(input: &str) -> IResult<&str, (String, String)> {
+    use nom::character::complete::{char, multispace0};
+    use nom::sequence::{delimited, separated_pair};
+
+    let (input, (key, value)) = separated_pair(
+        super::ident,
+        delimited(multispace0, char('='), multispace0),
+        option_value,
+    )(input)?;
+    Ok((input, (key.to_owned(), value)))
+}
+
+/// Parses a `WITH (...)` clause.
+fn with_clause(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::{char, multispace0};
+    use nom::multi::separated_list;
+    use nom::sequence::delimited;
+
+    let (input, _) = tag_no_case("with")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char('(')(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, pairs) =
+        separated_list(delimited(multispace0, char(','), multispace0), option_pair)(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, pairs))
+}
+
+/// Parses a complete `WITH (...)` clause, up to the end of the statement.
+fn options_clause(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    use nom::character::complete::{char, multispace0};
+    use nom::combinator::opt;
+
+    let (input, pairs) = with_clause(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = opt(char(';'))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, pairs))
+}
+
+/// Split a trailing `WITH (...)` clause off `stmt`.
+///
+/// Returns the statement without the clause (but with its terminating semicolon), along with the
+/// options given in the clause, if there was one.
+pub(super) fn split_options(stmt: &str) -> (String, Option<Vec<(String, String)>>) {
+    let lower = stmt.to_ascii_lowercase();
+    let mut end = lower.len();
+    while let Some(pos) = lower[..end].rfind("with") {
+        // the keyword has to stand on its own, and the clause has to run to the end of `stmt`
+        let standalone = lower[..pos]
+            .chars()
+            .last()
+            .map(|c| c.is_whitespace() || c == ')')
+            .unwrap_or(false);
+        if standalone {
+            if let Ok(("", pairs)) = options_clause(&stmt[pos..]) {
+                let mut rest = stmt[..pos].trim_end().to_owned();
+                if stmt.trim_end().ends_with(';') {
+                    rest.push(';');
+                }
+                return (rest, Some(pairs));
+            }
+        }
+        end = pos;
+    }
+    (stmt.to_owned(), None)
+}
+
+/// Parses the `QUERY name ` that may precede a `WITH (...)` clause in a query prefix.
+fn query_name(input: &str) -> IResult<&str, &str> {
+    use nom::branch::alt;
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::{multispace0, space1};
+    use nom::combinator::opt;
+    use nom::sequence::pair;
+
+    let (input, _) = multispace0(input)?;
+    let (input, _) = opt(pair(
+        alt((tag_no_case("query"), tag_no_case("view"))),
+        space1,
+    ))(input)?;
+    let (input, name) = super::ident(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, name))
+}
+
+/// Split a `WITH (...)` clause off the prefix of a named query, as in
+/// `QUERY feed WITH (priority = high): SELECT ...`.
+///
+/// Returns the statement without the clause, along with the options given in the clause, if there
+/// was one.
+pub(super) fn split_query_options(stmt: &str) -> (String, Option<Vec<(String, String)>>) {
+    use nom::character::complete::{char, multispace0};
+
+    let parsed = query_name(stmt).and_then(|(rest, name)| {
+        let clause = rest;
+        let (rest, pairs) = with_clause(rest)?;
+        let (rest, _) = multispace0(rest)?;
+        let _ = char(':')(rest)?;
+        Ok((name, clause, rest, pairs))
+    });
+    match parsed {
+        Ok((name, _, _, _)) if name.is_empty() => (stmt.to_owned(), None),
+        Ok((_, clause, rest, pairs)) => {
+            let prefix = &stmt[..stmt.len() - clause.len()];
+            (format!("{}{}", prefix.trim_end(), rest), Some(pairs))
+        }
+        Err(_) => (stmt.to_owned(), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_splits_table_options() {
+        let (stmt, options) = split_options(
+            "CREATE TABLE audit (id int, msg text) WITH (storage = 'log', other=x_1);",
+        );
+        assert_eq!(stmt, "CREATE TABLE audit (id int, msg text);");
+        assert_eq!(
+            options,
+            Some(vec![
+                ("storage".to_owned(), "log".to_owned()),
+                ("other".to_owned(), "x_1".to_owned()),
+            ])
+        );
+
+        // no clause, or something that merely looks like one
+        let q = "CREATE TABLE with_stuff (withdrawn int);";
+        assert_eq!(split_options(q), (q.to_owned(), None));
+        let q = "SELECT a FROM b WHERE c = 'with (x = y)' AND d = 1;";
+        assert_eq!(split_options(q), (q.to_owned(), None));
+    }
+
+    #[test]
+    fn it_rejects_unknown_table_options() {
+        let options =
+            TableOptions::from_pairs(vec![("STORAGE".to_owned(), "rocksdb".to_owned())]).unwrap();
+        assert_eq!(options.storage, Some("rocksdb".to_owned()));
+        assert!(TableOptions::from_pairs(vec![("colour".to_owned(), "red".to_owned())]).is_err());
+    }
+
+    #[test]
+    fn it_parses_ttl_options() {
+        let options = TableOptions::from_pairs(vec![
+            ("ttl_column".to_owned(), "created_at".to_owned()),
+            ("ttl".to_owned(), "30d".to_owned()),
+        ])
+        .unwrap();
+        assert_eq!(
+            options.ttl,
+            Some((
+                "created_at".to_owned(),
+                Duration::from_secs(30 * 24 * 60 * 60)
+            ))
+        );
+
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("18446744073709551615w").is_err());
+
+        // both or neither
+        assert!(TableOptions::from_pairs(vec![("ttl".to_owned(), "1h".to_owned())]).is_err());
+    }
+
+    #[test]
+    fn it_splits_query_options() {
+        let (stmt, options) = split_query_options(
+            "QUERY feed WITH (memory_limit = '2GB', priority = high): SELECT a FROM b;",
+        );
+        assert_eq!(stmt, "QUERY feed: SELECT a FROM b;");
+        assert_eq!(
+            options,
+            Some(vec![
+                ("memory_limit".to_owned(), "2GB".to_owned()),
+                ("priority".to_owned(), "high".to_owned()),
+            ])
+        );
+
+        let (stmt, options) = split_query_options("feed WITH (priority = low) : SELECT a FROM b;");
+        assert_eq!(stmt, "feed : SELECT a FROM b;");
+        assert!(options.is_some());
+
+        // no clause, or one that isn't in the prefix
+        let q = "QUERY feed: SELECT a FROM b;";
+        assert_eq!(split_query_options(q), (q.to_owned(), None));
+        let q = "SELECT a FROM b WHERE c = 'with (x = y)';";
+        assert_eq!(split_query_options(q), (q.to_owned(), None));
+    }
+
+    #[test]
+    fn it_parses_view_options() {
+        let options = ViewOptions::from_pairs(vec![
+            ("memory_limit".to_owned(), "2GB".to_owned()),
+            ("PRIORITY".to_owned(), "Low".to_owned()),
+        ])
+        .unwrap();
+        assert_eq!(options.memory_limit, Some(2 << 30));
+        assert_eq!(options.priority, Some(EvictionPriority::Low));
+
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64kb"), Ok(64 << 10));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("3PB").is_err());
+        assert!(
+            ViewOptions::from_pairs(vec![("priority".to_owned(), "urgent".to_owned())]).is_err()
+        );
+        assert!(ViewOptions::from_pairs(vec![("storage".to_owned(), "log".to_owned())]).is_err());
+    }
+
+    #[test]
+    fn it_parses_shard_options() {
+        let options =
+            TableOptions::from_pairs(vec![("shards".to_owned(), "16".to_owned())]).unwrap();
+        assert_eq!(options.shards, Some(16));
+        let options = ViewOptions::from_pairs(vec![("SHARDS".to_owned(), "1".to_owned())]).unwrap();
+        assert_eq!(options.shards, Some(1));
+
+        assert!(TableOptions::from_pairs(vec![("shards".to_owned(), "0".to_owned())]).is_err());
+        assert!(ViewOptions::from_pairs(vec![("shards".to_owned(), "many".to_owned())]).is_err());
+    }
+
+    #[test]
+    fn it_parses_partition_options() {
+        let options = TableOptions::from_pairs(vec![
+            ("partition_by".to_owned(), "ts".to_owned()),
+            ("partition_bounds".to_owned(), "100, 200,300".to_owned()),
+            ("shards".to_owned(), "4".to_owned()),
+        ])
+        .unwrap();
+        assert_eq!(
+            options.partition_by,
+            Some((
+                "ts".to_owned(),
+                vec!["100".to_owned(), "200".to_owned(), "300".to_owned()]
+            ))
+        );
+
+        // both or neither, and the shard count has to agree
+        assert!(
+            TableOptions::from_pairs(vec![("partition_by".to_owned(), "ts".to_owned())]).is_err()
+        );
+        assert!(TableOptions::from_pairs(vec![
+            ("partition_by".to_owned(), "ts".to_owned()),
+            ("partition_bounds".to_owned(), "100".to_owned()),
+            ("shards".to_owned(), "4".to_owned()),
+        ])
+        .is_err());
+        assert!(parse_bounds("100,,200").is_err());
+    }
+}
@@ -40,6 +40,10 @@ fn type_for_internal_column(
     match *(*node) {
         ops::NodeOperator::Project(ref o) => {
             let emits = o.emits();
+            if o.masks().iter().any(|&(i, _)| i == column_index) {
+                // masked values are NULL or text
+                return Some(SqlType::Text);
+            }
             assert!(column_index >= emits.0.len());
             if column_index < emits.0.len() + emits.2.len() {
                 // computed expression
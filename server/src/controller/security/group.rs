@@ -12,24 +12,35 @@ pub struct Group {
 }
 
 impl Group {
-    pub fn parse(grou_txt: &str) -> Vec<Group> {
-        let groups: Vec<Value> = match serde_json::from_str(grou_txt) {
-            Ok(v) => v,
-            Err(e) => panic!(e.to_string()),
-        };
+    pub fn parse(grou_txt: &str) -> Result<Vec<Group>, String> {
+        let groups: Vec<Value> =
+            serde_json::from_str(grou_txt).map_err(|e| format!("failed to parse groups: {}", e))?;
 
         groups
             .iter()
             .map(|g| {
-                let name = g["name"].as_str().unwrap();
-                let membership = g["membership"].as_str().unwrap();
-                let policies = format!("{}", g["policies"]);
+                let field = |f: &str| {
+                    g.get(f)
+                        .and_then(Value::as_str)
+                        .ok_or_else(|| format!("group {} is missing the \"{}\" field", g, f))
+                };
+                let name = field("name")?;
+                let membership = field("membership")?;
+                let policies = match g.get("policies") {
+                    Some(p) => Policy::parse(&format!("{}", p))?,
+                    None => Vec::new(),
+                };
 
-                Group {
+                Ok(Group {
                     name: name.to_string(),
-                    membership: sql_parser::parse_query(membership).unwrap(),
-                    policies: Policy::parse(&policies),
-                }
+                    membership: sql_parser::parse_query(membership).map_err(|_| {
+                        format!(
+                            "failed to parse membership of group {}: {}",
+                            name, membership
+                        )
+                    })?,
+                    policies,
+                })
             })
             .collect()
     }
@@ -64,7 +75,7 @@ mod tests {
                 }
             ]"#;
 
-        let groups = Group::parse(group_text);
+        let groups = Group::parse(group_text).unwrap();
 
         assert_eq!(groups.len(), 1);
         assert_eq!(groups[0].name, "ta");
@@ -15,25 +15,26 @@ pub struct SecurityConfig {
 }
 
 impl SecurityConfig {
-    pub fn parse(policy_text: &str) -> SecurityConfig {
-        let config: serde_json::Map<String, Value> = match serde_json::from_str(policy_text) {
-            Ok(v) => v,
-            Err(e) => panic!(e.to_string()),
-        };
+    pub fn parse(policy_text: &str) -> Result<SecurityConfig, String> {
+        let config: serde_json::Map<String, Value> = serde_json::from_str(policy_text)
+            .map_err(|e| format!("failed to parse security config: {}", e))?;
 
         let groups = match config.get("groups") {
-            Some(groups) => Group::parse(&format!("{}", groups)),
+            Some(groups) => Group::parse(&format!("{}", groups))?,
             None => Vec::new(),
         };
 
         let groups_map = groups.iter().map(|g| (g.name(), g.clone())).collect();
 
-        let policies = Policy::parse(&format!("{}", config["policies"]));
+        let policies = match config.get("policies") {
+            Some(policies) => Policy::parse(&format!("{}", policies))?,
+            None => Vec::new(),
+        };
 
-        SecurityConfig {
+        Ok(SecurityConfig {
             groups: groups_map,
             policies,
-        }
+        })
     }
 
     pub fn policies(&self) -> &[Policy] {
@@ -67,9 +68,41 @@ mod tests {
                         ]
         }"#;
 
-        let config = SecurityConfig::parse(config_txt);
+        let config = SecurityConfig::parse(config_txt).unwrap();
 
         assert_eq!(config.policies.len(), 2);
         assert_eq!(config.groups.len(), 1);
     }
+
+    #[test]
+    fn it_parses_securecrp_configs() {
+        use super::*;
+
+        let hotcrp =
+            std::fs::read_to_string("../applications/securecrp/hotcrp_policies.json").unwrap();
+        let config = SecurityConfig::parse(&hotcrp).unwrap();
+        assert_eq!(config.policies.len(), 2);
+        assert!(config.policies.iter().all(|p| p.is_row_policy()));
+        assert_eq!(config.policies[1].name(), "unconflicted-papers");
+
+        let jeeves =
+            std::fs::read_to_string("../applications/securecrp/jeeves_policies.json").unwrap();
+        let config = SecurityConfig::parse(&jeeves).unwrap();
+        assert_eq!(config.policies.len(), 1);
+        assert_eq!(config.groups.len(), 3);
+        assert_eq!(config.get_group_policies("reviewers".to_owned()).len(), 3);
+    }
+
+    #[test]
+    fn it_rejects_malformed_configs() {
+        use super::*;
+
+        assert!(SecurityConfig::parse("{").is_err());
+        assert!(SecurityConfig::parse(r#"{ "groups": [{ "name": "ta" }] }"#).is_err());
+        assert!(SecurityConfig::parse(
+            r#"{ "policies": [{ "table": "post", "predicate": "WHERE post.type =" }] }"#
+        )
+        .is_err());
+        assert!(SecurityConfig::parse(r#"{ "policies": [] }"#).is_ok());
+    }
 }
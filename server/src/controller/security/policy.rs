@@ -6,6 +6,7 @@ use serde_json::Value;
 enum Action {
     Allow,
     Deny,
+    AllowWrite,
     #[allow(dead_code)]
     Rewrite,
 }
@@ -15,6 +16,10 @@ pub enum Policy {
     Rewrite(RewritePolicy),
     Allow(RowPolicy),
     Deny(RowPolicy),
+    Mask(MaskPolicy),
+    /// Restricts the rows a universe may write to a table to those satisfying the predicate.
+    /// Writes to the table that aren't made on behalf of a universe are refused.
+    AllowWrite(RowPolicy),
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Serialize, Deserialize)]
@@ -34,12 +39,37 @@ pub struct RewritePolicy {
     pub rewrite_view: SqlQuery,
 }
 
+/// How a masking policy obscures a column.
+#[derive(Clone, Debug, Hash, PartialEq, Serialize, Deserialize)]
+pub enum Mask {
+    /// Remove the column from query results altogether.
+    Hide,
+    /// Replace the column's values with `NULL`.
+    Null,
+    /// Replace the column's values with a digest of them, keyed with a secret of the deployment.
+    Hash,
+    /// Keep only `length` characters of the column's values, counted from the end if `from_end`.
+    Truncate { length: usize, from_end: bool },
+}
+
+/// Masks a column of a table for every universe whose context satisfies the predicate.
+#[derive(Clone, Debug, Hash, PartialEq, Serialize, Deserialize)]
+pub struct MaskPolicy {
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    pub mask: Mask,
+    pub predicate: SqlQuery,
+}
+
 impl Policy {
     pub fn name(&self) -> String {
         match *self {
             Policy::Rewrite(ref p) => p.name.clone(),
             Policy::Allow(ref p) => p.name.clone(),
             Policy::Deny(ref p) => p.name.clone(),
+            Policy::Mask(ref p) => p.name.clone(),
+            Policy::AllowWrite(ref p) => p.name.clone(),
         }
     }
 
@@ -48,6 +78,8 @@ impl Policy {
             Policy::Rewrite(ref p) => p.table.clone(),
             Policy::Allow(ref p) => p.table.clone(),
             Policy::Deny(ref p) => p.table.clone(),
+            Policy::Mask(ref p) => p.table.clone(),
+            Policy::AllowWrite(ref p) => p.table.clone(),
         }
     }
 
@@ -56,6 +88,8 @@ impl Policy {
             Policy::Rewrite(_) => false,
             Policy::Allow(_) => true,
             Policy::Deny(_) => true,
+            Policy::Mask(_) => false,
+            Policy::AllowWrite(_) => false,
         }
     }
 
@@ -64,6 +98,8 @@ impl Policy {
             Policy::Rewrite(ref p) => p.rewrite_view.clone(),
             Policy::Allow(ref p) => p.predicate.clone(),
             Policy::Deny(ref p) => p.predicate.clone(),
+            Policy::Mask(ref p) => p.predicate.clone(),
+            Policy::AllowWrite(ref p) => p.predicate.clone(),
         }
     }
 
@@ -72,6 +108,8 @@ impl Policy {
             Policy::Rewrite(ref p) => p.value.clone(),
             Policy::Allow(_) => panic!("Row policy doesn't have value field"),
             Policy::Deny(_) => panic!("Row policy doesn't have value field"),
+            Policy::Mask(_) => panic!("Mask policy doesn't have value field"),
+            Policy::AllowWrite(_) => panic!("Row policy doesn't have value field"),
         }
     }
 
@@ -80,6 +118,8 @@ impl Policy {
             Policy::Rewrite(ref p) => p.column.clone(),
             Policy::Allow(_) => panic!("Row policy doesn't have column field"),
             Policy::Deny(_) => panic!("Row policy doesn't have column field"),
+            Policy::Mask(ref p) => p.column.clone(),
+            Policy::AllowWrite(_) => panic!("Row policy doesn't have column field"),
         }
     }
 
@@ -88,14 +128,14 @@ impl Policy {
             Policy::Rewrite(ref p) => p.key.clone(),
             Policy::Allow(_) => panic!("Row policy doesn't have key field"),
             Policy::Deny(_) => panic!("Row policy doesn't have key field"),
+            Policy::Mask(_) => panic!("Mask policy doesn't have key field"),
+            Policy::AllowWrite(_) => panic!("Row policy doesn't have key field"),
         }
     }
 
-    pub fn parse(policy_text: &str) -> Vec<Policy> {
-        let config: Vec<Value> = match serde_json::from_str(policy_text) {
-            Ok(v) => v,
-            Err(e) => panic!(e.to_string()),
-        };
+    pub fn parse(policy_text: &str) -> Result<Vec<Policy>, String> {
+        let config: Vec<Value> = serde_json::from_str(policy_text)
+            .map_err(|e| format!("failed to parse policies: {}", e))?;
 
         config
             .iter()
@@ -104,22 +144,25 @@ impl Policy {
                     Some("rewrite") => Policy::parse_rewrite_policy(p),
                     Some("allow") => Policy::parse_row_policy(p, Action::Allow),
                     Some("deny") => Policy::parse_row_policy(p, Action::Deny),
-                    _ => panic!("Unsupported policy action {}", action),
+                    Some("mask") => Policy::parse_mask_policy(p),
+                    Some("allow_write") => Policy::parse_row_policy(p, Action::AllowWrite),
+                    _ => Err(format!("unsupported policy action {}", action)),
                 },
                 None => Policy::parse_row_policy(p, Action::Allow),
             })
             .collect()
     }
 
-    fn parse_row_policy(p: &Value, action: Action) -> Policy {
+    fn parse_row_policy(p: &Value, action: Action) -> Result<Policy, String> {
         let name = match p.get("name") {
-            Some(n) => n.as_str().unwrap(),
+            Some(n) => n.as_str().unwrap_or(""),
             None => "",
         };
-        let table = p["table"].as_str().unwrap();
-        let pred = p["predicate"].as_str().unwrap();
+        let table = field(p, "table")?;
+        let pred = field(p, "predicate")?;
 
-        let sq = sql_parser::parse_query(&format!("select * from {} {};", table, pred)).unwrap();
+        let sq = sql_parser::parse_query(&format!("select * from {} {};", table, pred))
+            .map_err(|_| format!("failed to parse predicate of policy on {}: {}", table, pred))?;
 
         let rp = RowPolicy {
             name: name.to_string(),
@@ -128,37 +171,98 @@ impl Policy {
         };
 
         match action {
-            Action::Allow => Policy::Allow(rp),
-            Action::Deny => Policy::Deny(rp),
+            Action::Allow => Ok(Policy::Allow(rp)),
+            Action::Deny => Ok(Policy::Deny(rp)),
+            Action::AllowWrite => Ok(Policy::AllowWrite(rp)),
             Action::Rewrite => unreachable!(),
         }
     }
 
-    fn parse_rewrite_policy(p: &Value) -> Policy {
+    fn parse_rewrite_policy(p: &Value) -> Result<Policy, String> {
         let name = match p.get("name") {
-            Some(n) => n.as_str().unwrap(),
+            Some(n) => n.as_str().unwrap_or(""),
             None => "",
         };
 
-        let table = p["table"].as_str().unwrap();
-        let rewrite = p["rewrite"].as_str().unwrap();
-        let value = p["value"].as_str().unwrap();
-        let column = p["column"].as_str().unwrap();
-        let key = p["key"].as_str().unwrap();
+        let table = field(p, "table")?;
+        let rewrite = field(p, "rewrite")?;
+        let value = field(p, "value")?;
+        let column = field(p, "column")?;
+        let key = field(p, "key")?;
 
-        let sq = sql_parser::parse_query(rewrite).unwrap();
+        let sq = sql_parser::parse_query(rewrite).map_err(|_| {
+            format!(
+                "failed to parse rewrite view of policy on {}: {}",
+                table, rewrite
+            )
+        })?;
 
-        Policy::Rewrite(RewritePolicy {
+        Ok(Policy::Rewrite(RewritePolicy {
             name: name.to_string(),
             table: table.to_string(),
             value: value.to_string(),
             column: column.to_string(),
             key: key.to_string(),
             rewrite_view: sq,
-        })
+        }))
+    }
+
+    fn parse_mask_policy(p: &Value) -> Result<Policy, String> {
+        let name = match p.get("name") {
+            Some(n) => n.as_str().unwrap_or(""),
+            None => "",
+        };
+
+        let table = field(p, "table")?;
+        let column = field(p, "column")?;
+        let mask = match field(p, "mask")? {
+            "hide" => Mask::Hide,
+            "null" => Mask::Null,
+            "hash" => Mask::Hash,
+            "truncate" => {
+                let length = p.get("length").and_then(Value::as_u64).ok_or_else(|| {
+                    format!("truncating policy {} needs a numeric \"length\" field", p)
+                })?;
+                let from_end = match p.get("from").and_then(Value::as_str) {
+                    None | Some("start") => false,
+                    Some("end") => true,
+                    Some(from) => return Err(format!("cannot truncate from {}", from)),
+                };
+                Mask::Truncate {
+                    length: length as usize,
+                    from_end,
+                }
+            }
+            mask => return Err(format!("unsupported column mask {}", mask)),
+        };
+
+        // without a predicate, the column is masked for everyone
+        let pred = match p.get("predicate") {
+            Some(pred) => pred
+                .as_str()
+                .ok_or_else(|| format!("predicate of policy {} is not a string", p))?,
+            None => "",
+        };
+        let sq = sql_parser::parse_query(&format!("select * from UserContext {};", pred))
+            .map_err(|_| format!("failed to parse predicate of policy on {}: {}", table, pred))?;
+
+        Ok(Policy::Mask(MaskPolicy {
+            name: name.to_string(),
+            table: table.to_string(),
+            column: column.to_string(),
+            mask,
+            predicate: sq,
+        }))
     }
 }
 
+/// Returns the string value of a required field of a policy.
+fn field<'a>(p: &'a Value, name: &str) -> Result<&'a str, String> {
+    p.get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("policy {} is missing the \"{}\" field", p, name))
+}
+
 mod tests {
     #[test]
     fn it_parses_row_policies() {
@@ -169,7 +273,7 @@ mod tests {
         let policy_text = r#"[{ "table": "post", "predicate": "WHERE post.type = ?" },
                               { "table": "post", "predicate": "WHERE post.author = ?" }]"#;
 
-        let policies = Policy::parse(policy_text);
+        let policies = Policy::parse(policy_text).unwrap();
 
         assert_eq!(policies.len(), 2);
         assert_eq!(
@@ -181,4 +285,65 @@ mod tests {
             sql_parser::parse_query(p1).unwrap()
         );
     }
+
+    #[test]
+    fn it_parses_mask_policies() {
+        use super::*;
+
+        let policy_text = r#"[{ "action": "mask", "table": "employee", "column": "ssn",
+                                "mask": "truncate", "length": 4, "from": "end",
+                                "predicate": "WHERE UserContext.role != 'hr'" },
+                              { "action": "mask", "table": "employee", "column": "salary",
+                                "mask": "hide" }]"#;
+
+        let policies = Policy::parse(policy_text).unwrap();
+
+        assert_eq!(policies.len(), 2);
+        assert!(!policies[0].is_row_policy());
+        assert_eq!(policies[0].column(), "ssn");
+        match policies[0] {
+            Policy::Mask(ref p) => assert_eq!(
+                p.mask,
+                Mask::Truncate {
+                    length: 4,
+                    from_end: true
+                }
+            ),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            policies[1].predicate(),
+            sql_parser::parse_query("select * from UserContext;").unwrap()
+        );
+
+        let bad = r#"[{ "action": "mask", "table": "employee", "column": "ssn",
+                        "mask": "truncate" }]"#;
+        assert!(Policy::parse(bad).is_err());
+        let bad = r#"[{ "action": "mask", "table": "employee", "column": "ssn",
+                        "mask": "scramble" }]"#;
+        assert!(Policy::parse(bad).is_err());
+    }
+
+    #[test]
+    fn it_parses_write_policies() {
+        use super::*;
+
+        let policy_text = r#"[{ "action": "allow_write", "name": "own-posts", "table": "post",
+                                "predicate": "WHERE post.author = UserContext.id" },
+                              { "action": "allow", "table": "post",
+                                "predicate": "WHERE post.author = UserContext.id" }]"#;
+
+        let policies = Policy::parse(policy_text).unwrap();
+
+        assert_eq!(policies.len(), 2);
+        assert_eq!(policies[0].name(), "own-posts");
+        assert!(!policies[0].is_row_policy());
+        match policies[0] {
+            Policy::AllowWrite(ref p) => assert_eq!(p.predicate, policies[1].predicate()),
+            _ => unreachable!(),
+        }
+
+        let bad = r#"[{ "action": "allow_write", "table": "post" }]"#;
+        assert!(Policy::parse(bad).is_err());
+    }
 }
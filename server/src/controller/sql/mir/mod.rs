@@ -23,7 +23,7 @@ use std::ops::Deref;
 use std::vec::Vec;
 
 use crate::controller::sql::security::Universe;
-use crate::controller::sql::UniverseId;
+use crate::controller::sql::{SqlError, UniverseId};
 
 mod grouped;
 mod join;
@@ -102,6 +102,8 @@ pub(super) struct SqlToMirConverter {
     log: slog::Logger,
     nodes: HashMap<(String, usize), MirNodeRef>,
     schema_version: usize,
+    /// Columns to be renamed the next time each base is adapted, as `(from, to)` pairs.
+    column_renames: HashMap<String, Vec<(String, String)>>,
 
     /// Universe in which the conversion is happening
     universe: Universe,
@@ -115,6 +117,7 @@ impl Default for SqlToMirConverter {
             log: slog::Logger::root(slog::Discard, o!()),
             nodes: HashMap::default(),
             schema_version: 0,
+            column_renames: HashMap::default(),
             universe: Universe::default(),
         }
     }
@@ -154,6 +157,22 @@ impl SqlToMirConverter {
             })
     }
 
+    /// All MIR nodes the converter knows about, along with any nodes connected to them.
+    pub(super) fn all_nodes(&self) -> Vec<MirNodeRef> {
+        let mut seen = HashSet::new();
+        let mut queue: Vec<MirNodeRef> = self.nodes.values().cloned().collect();
+        let mut nodes = Vec::new();
+        while let Some(n) = queue.pop() {
+            if !seen.insert(n.as_ptr()) {
+                continue;
+            }
+            queue.extend(n.borrow().ancestors.iter().cloned());
+            queue.extend(n.borrow().children.iter().cloned());
+            nodes.push(n);
+        }
+        nodes
+    }
+
     pub fn add_nodes(&mut self, nodes: Vec<MirNodeRef>) {
         for node in nodes {
             let node_id = (String::from(node.borrow().name()), self.schema_version);
@@ -168,31 +187,44 @@ impl SqlToMirConverter {
         ct: &ConditionTree,
         columns: &mut Vec<Column>,
         n: &MirNodeRef,
-    ) -> Vec<(usize, FilterCondition)> {
+    ) -> Result<Vec<(usize, FilterCondition)>, SqlError> {
         match ct.operator {
             Operator::And => {
                 let mut left_filter = match ct.left.as_ref() {
                     ConditionExpression::LogicalOp(ref ct2) => {
-                        self.logical_op_to_conditions(ct2, columns, n)
+                        self.logical_op_to_conditions(ct2, columns, n)?
                     }
                     ConditionExpression::ComparisonOp(ref ct2) => {
-                        self.to_conditions(ct2, columns, n)
+                        self.to_conditions(ct2, columns, n)?
+                    }
+                    ce => {
+                        return Err(SqlError::Unsupported(format!(
+                            "`{}` in a filter condition",
+                            ce
+                        )))
                     }
-                    _ => unimplemented!(),
                 };
                 let mut right_filter = match ct.right.as_ref() {
                     ConditionExpression::LogicalOp(ref ct2) => {
-                        self.logical_op_to_conditions(ct2, columns, n)
+                        self.logical_op_to_conditions(ct2, columns, n)?
                     }
                     ConditionExpression::ComparisonOp(ref ct2) => {
-                        self.to_conditions(ct2, columns, n)
+                        self.to_conditions(ct2, columns, n)?
+                    }
+                    ce => {
+                        return Err(SqlError::Unsupported(format!(
+                            "`{}` in a filter condition",
+                            ce
+                        )))
                     }
-                    _ => unimplemented!(),
                 };
                 left_filter.append(&mut right_filter);
-                left_filter
+                Ok(left_filter)
             }
-            _ => unimplemented!(),
+            _ => Err(SqlError::Unsupported(format!(
+                "`{}` in a filter condition (only AND is supported here)",
+                ct
+            ))),
         }
     }
 
@@ -203,13 +235,18 @@ impl SqlToMirConverter {
         ct: &ConditionTree,
         columns: &mut Vec<Column>,
         n: &MirNodeRef,
-    ) -> Vec<(usize, FilterCondition)> {
+    ) -> Result<Vec<(usize, FilterCondition)>, SqlError> {
         use std::cmp::max;
 
         // TODO(malte): we only support one level of condition nesting at this point :(
         let l = match *ct.left.as_ref() {
             ConditionExpression::Base(ConditionBase::Field(ref f)) => f.clone(),
-            _ => unimplemented!(),
+            ref ce => {
+                return Err(SqlError::Unsupported(format!(
+                    "`{}` on the left-hand side of `{}`",
+                    ce, ct
+                )))
+            }
         };
         use dataflow::ops::filter;
         let f = match *ct.right.as_ref() {
@@ -235,13 +272,21 @@ impl SqlToMirConverter {
                 FilterCondition::In(ll.iter().map(|l| DataType::from(l.clone())).collect())
             }
             ConditionExpression::Base(ConditionBase::Field(ref f)) => {
-                // NOTE(jon): the uwnrap here is almost certainly wrong given the business
-                // that goes on further down where it appens a column in magical circumstances.
-                // also, what if two columns share a name, but differ in .table?
-                let fi = columns.iter().rposition(|c| *c.name == f.name).unwrap();
+                // NOTE(jon): what if two columns share a name, but differ in .table?
+                let fi = columns
+                    .iter()
+                    .rposition(|c| *c.name == f.name)
+                    .ok_or_else(|| {
+                        SqlError::Invalid(format!("no column {} to compare against in `{}`", f, ct))
+                    })?;
                 FilterCondition::Comparison(ct.operator.clone(), filter::Value::Column(fi))
             }
-            _ => unimplemented!(),
+            ref ce => {
+                return Err(SqlError::Unsupported(format!(
+                    "`{}` on the right-hand side of `{}`",
+                    ce, ct
+                )))
+            }
         };
 
         let absolute_column_ids: Vec<usize> = columns
@@ -274,7 +319,7 @@ impl SqlToMirConverter {
             }
         }
 
-        filters
+        Ok(filters)
     }
 
     pub(super) fn add_leaf_below(
@@ -361,7 +406,7 @@ impl SqlToMirConverter {
         order: &Option<OrderClause>,
         limit: &Option<LimitClause>,
         has_leaf: bool,
-    ) -> MirQuery {
+    ) -> Result<MirQuery, SqlError> {
         let union_name = if !has_leaf && limit.is_none() {
             String::from(name)
         } else {
@@ -371,8 +416,8 @@ impl SqlToMirConverter {
             CompoundSelectOperator::Union => self.make_union_node(
                 &union_name,
                 &sqs.iter().map(|mq| mq.leaf.clone()).collect::<Vec<_>>()[..],
-            ),
-            _ => unimplemented!(),
+            )?,
+            ref op => return Err(SqlError::Unsupported(format!("compound `{}` query", op))),
         };
         let node_id = (union_name, self.schema_version);
         self.nodes
@@ -435,14 +480,14 @@ impl SqlToMirConverter {
             .entry(node_id)
             .or_insert_with(|| leaf_node.clone());
 
-        MirQuery {
+        Ok(MirQuery {
             name: String::from(name),
             roots: sqs.iter().fold(Vec::new(), |mut acc, mq| {
                 acc.extend(mq.roots.iter().cloned());
                 acc
             }),
             leaf: leaf_node,
-        }
+        })
     }
 
     // pub(super) viz for tests
@@ -456,6 +501,21 @@ impl SqlToMirConverter {
         }
     }
 
+    /// Describe the MIR nodes that each data-flow node was made from.
+    pub(super) fn descriptions_by_flow_node(&self) -> HashMap<NodeIndex, Vec<String>> {
+        let mut descriptions: HashMap<_, Vec<_>> = HashMap::new();
+        for n in self.all_nodes() {
+            let n = n.borrow();
+            if let Some(ref flow_node) = n.flow_node {
+                descriptions
+                    .entry(flow_node.address())
+                    .or_default()
+                    .push(format!("{}: {}", n.versioned_name(), n));
+            }
+        }
+        descriptions
+    }
+
     pub(super) fn get_leaf(&self, name: &str) -> Option<NodeIndex> {
         match self.current.get(name) {
             None => None,
@@ -480,6 +540,11 @@ impl SqlToMirConverter {
         }
     }
 
+    /// Whether a query with the given name is currently registered.
+    pub(super) fn has_query(&self, name: &str) -> bool {
+        self.current.contains_key(name)
+    }
+
     pub(super) fn remove_query(&mut self, name: &str, mq: &MirQuery) {
         use std::collections::VecDeque;
 
@@ -536,7 +601,7 @@ impl SqlToMirConverter {
             Option<HashMap<(String, Option<String>), String>>,
             String,
         ),
-        String,
+        SqlError,
     > {
         let (sec, nodes, table_mapping, base_name) =
             self.make_nodes_for_selection(&name, sq, qg, has_leaf, universe)?;
@@ -585,6 +650,15 @@ impl SqlToMirConverter {
         self.schema_version = new_version;
     }
 
+    /// Make the next schema change of base `table` treat column `from` as renamed to `to`, rather
+    /// than as dropped and replaced by a new column.
+    pub(super) fn rename_base_column(&mut self, table: &str, from: &str, to: &str) {
+        self.column_renames
+            .entry(table.to_owned())
+            .or_default()
+            .push((from.to_owned(), to.to_owned()));
+    }
+
     fn make_base_node(
         &mut self,
         name: &str,
@@ -626,12 +700,35 @@ impl SqlToMirConverter {
                         existing_sv
                     );
 
-                    // Find out if this is a simple case of adding or removing a column
+                    // Find out if this is a simple case of adding, removing, or modifying a
+                    // column. A column is modified if it changes type or default, but keeps its
+                    // name, or if it was explicitly renamed.
+                    let renames = self.column_renames.remove(name).unwrap_or_default();
+                    let mut columns_modified = Vec::new();
+                    for c in cols {
+                        if schema.contains(c) {
+                            continue;
+                        }
+                        let old_name = renames
+                            .iter()
+                            .find(|&&(_, ref to)| *to == c.column.name)
+                            .map(|&(ref from, _)| from.as_str())
+                            .unwrap_or_else(|| c.column.name.as_str());
+                        if let Some(old) = schema
+                            .iter()
+                            .find(|o| o.column.name == old_name && !cols.contains(o))
+                        {
+                            columns_modified.push((old, c));
+                        }
+                    }
+
                     let mut columns_added = Vec::new();
                     let mut columns_removed = Vec::new();
                     let mut columns_unchanged = Vec::new();
                     for c in cols {
-                        if !schema.contains(c) {
+                        if columns_modified.iter().any(|&(_, new)| new == c) {
+                            // modified column
+                        } else if !schema.contains(c) {
                             // new column
                             columns_added.push(c);
                         } else {
@@ -639,21 +736,27 @@ impl SqlToMirConverter {
                         }
                     }
                     for c in schema {
-                        if !cols.contains(c) {
+                        if columns_modified.iter().any(|&(old, _)| old == c) {
+                            // modified column
+                        } else if !cols.contains(c) {
                             // dropped column
                             columns_removed.push(c);
                         }
                     }
 
-                    if !columns_unchanged.is_empty()
-                        && (!columns_added.is_empty() || !columns_removed.is_empty())
+                    if (!columns_unchanged.is_empty() || !columns_modified.is_empty())
+                        && (!columns_added.is_empty()
+                            || !columns_removed.is_empty()
+                            || !columns_modified.is_empty())
                     {
                         error!(
                             self.log,
-                            "base {}: add columns {:?}, remove columns {:?} over v{}",
+                            "base {}: add columns {:?}, remove columns {:?}, modify columns {:?} \
+                             over v{}",
                             name,
                             columns_added,
                             columns_removed,
+                            columns_modified,
                             existing_sv
                         );
                         let existing_node = self.nodes[&(String::from(name), existing_sv)].clone();
@@ -681,6 +784,10 @@ impl SqlToMirConverter {
                                     });
                             columns.remove(pos);
                         }
+                        for &(old, new) in &columns_modified {
+                            let pos = columns.iter().position(|cc| cc == old).unwrap();
+                            columns[pos] = new.clone();
+                        }
                         assert_eq!(
                             columns.len(),
                             existing_node.borrow().columns().len() + columns_added.len()
@@ -691,7 +798,12 @@ impl SqlToMirConverter {
                         let base_schemas = self.base_schemas.entry(String::from(name)).or_default();
                         base_schemas.push((self.schema_version, columns.clone()));
 
-                        return MirNode::adapt_base(existing_node, columns_added, columns_removed);
+                        return MirNode::adapt_base(
+                            existing_node,
+                            columns_added,
+                            columns_removed,
+                            columns_modified,
+                        );
                     } else {
                         info!(self.log, "base table has complex schema change");
                         break;
@@ -770,7 +882,11 @@ impl SqlToMirConverter {
         }
     }
 
-    fn make_union_node(&self, name: &str, ancestors: &[MirNodeRef]) -> MirNodeRef {
+    fn make_union_node(
+        &self,
+        name: &str,
+        ancestors: &[MirNodeRef],
+    ) -> Result<MirNodeRef, SqlError> {
         let mut emit: Vec<Vec<Column>> = Vec::new();
         assert!(ancestors.len() > 1, "union must have more than 1 ancestors");
 
@@ -790,18 +906,19 @@ impl SqlToMirConverter {
             {
                 selected_cols.insert(c.name.clone());
             } else {
-                panic!(
-                    "column with name '{}' not found all union ancestors: all ancestors' \
+                return Err(SqlError::Invalid(format!(
+                    "column with name '{}' not found in all union ancestors: all ancestors' \
                      output columns must have the same names",
                     c.name
-                );
+                )));
             }
         }
-        assert_eq!(
-            num_ucols,
-            selected_cols.len(),
-            "union drops ancestor columns"
-        );
+        if num_ucols != selected_cols.len() {
+            // two output columns share a name, and we match columns by name
+            return Err(SqlError::Invalid(String::from(
+                "union ancestors have several output columns with the same name",
+            )));
+        }
 
         for ancestor in ancestors.iter() {
             let mut acols: Vec<Column> = Vec::new();
@@ -815,21 +932,21 @@ impl SqlToMirConverter {
             emit.push(acols.clone());
         }
 
-        assert!(
-            emit.iter().all(|e| e.len() == selected_cols.len()),
-            "all ancestors columns must have the same size, but got emit: {:?}, selected: {:?}",
-            emit,
-            selected_cols
-        );
+        if emit.iter().any(|e| e.len() != selected_cols.len()) {
+            return Err(SqlError::Invalid(format!(
+                "all union ancestors must have the same number of columns, but got {:?}",
+                emit.iter().map(Vec::len).collect::<Vec<_>>()
+            )));
+        }
 
-        MirNode::new(
+        Ok(MirNode::new(
             name,
             self.schema_version,
             emit.first().unwrap().clone(),
             MirNodeType::Union { emit },
             ancestors.to_vec(),
             vec![],
-        )
+        ))
     }
 
     // Creates union node for universe creation - returns the resulting node ref and a universe table mapping
@@ -961,24 +1078,29 @@ impl SqlToMirConverter {
         )
     }
 
-    fn make_filter_node(&self, name: &str, parent: MirNodeRef, cond: &ConditionTree) -> MirNodeRef {
+    fn make_filter_node(
+        &self,
+        name: &str,
+        parent: MirNodeRef,
+        cond: &ConditionTree,
+    ) -> Result<MirNodeRef, SqlError> {
         let mut fields = parent.borrow().columns().to_vec();
 
-        let filter = self.to_conditions(cond, &mut fields, &parent);
+        let filter = self.to_conditions(cond, &mut fields, &parent)?;
         trace!(
             self.log,
             "Added filter node {} with condition {:?}",
             name,
             filter
         );
-        MirNode::new(
+        Ok(MirNode::new(
             name,
             self.schema_version,
             fields,
             MirNodeType::Filter { conditions: filter },
             vec![parent.clone()],
             vec![],
-        )
+        ))
     }
 
     fn make_function_node(
@@ -987,7 +1109,7 @@ impl SqlToMirConverter {
         func_col: &Column,
         group_cols: Vec<&Column>,
         parent: MirNodeRef,
-    ) -> Vec<MirNodeRef> {
+    ) -> Result<Vec<MirNodeRef>, SqlError> {
         use dataflow::ops::grouped::aggregate::Aggregation;
         use dataflow::ops::grouped::extremum::Extremum;
         use dataflow::ops::grouped::filteraggregate::FilterAggregation;
@@ -1015,8 +1137,8 @@ impl SqlToMirConverter {
                     group_cols,
                     t,
                     cond,
-                ));
-                out_nodes
+                )?);
+                Ok(out_nodes)
             } else {
                 out_nodes.push(self.make_grouped_node(
                     name,
@@ -1025,8 +1147,8 @@ impl SqlToMirConverter {
                     group_cols,
                     t,
                     cond,
-                ));
-                out_nodes
+                )?);
+                Ok(out_nodes)
             }
         };
 
@@ -1081,7 +1203,10 @@ impl SqlToMirConverter {
                 // faithful to COUNT(*) semantics, because COUNT(*) is supposed to count all
                 // rows including those with NULL values, and we don't have a mechanism to do that
                 // (but we also don't have a NULL value, so maybe we're okay).
-                panic!("COUNT(*) should have been rewritten earlier!")
+                Err(SqlError::Unsupported(format!(
+                    "`{}` that could not be rewritten",
+                    func
+                )))
             }
             Count(
                 FunctionArguments::Conditional(CaseWhenExpression {
@@ -1132,7 +1257,10 @@ impl SqlToMirConverter {
                 false,
                 None,
             ),
-            _ => unimplemented!(),
+            _ => Err(SqlError::Unsupported(format!(
+                "aggregate function `{}`",
+                func
+            ))),
         }
     }
 
@@ -1144,7 +1272,7 @@ impl SqlToMirConverter {
         group_by: Vec<&Column>,
         node_type: GroupedNodeType,
         condition: Option<&ConditionExpression>,
-    ) -> MirNodeRef {
+    ) -> Result<MirNodeRef, SqlError> {
         let parent_node = over.0;
 
         // Resolve column IDs in parent
@@ -1160,7 +1288,7 @@ impl SqlToMirConverter {
         combined_columns.push(computed_col.clone());
 
         // make the new operator
-        match node_type {
+        Ok(match node_type {
             GroupedNodeType::Aggregation(agg) => MirNode::new(
                 name,
                 self.schema_version,
@@ -1188,17 +1316,21 @@ impl SqlToMirConverter {
             GroupedNodeType::FilterAggregation(filter_agg) => {
                 use nom_sql::ConditionExpression::*;
 
-                let cond = condition.expect("Filter aggregation must have condition!");
+                let cond = condition.ok_or_else(|| {
+                    SqlError::Invalid(format!("no condition for {}", computed_col.name))
+                })?;
                 let mut fields = parent_node.borrow().columns().to_vec();
                 let filter = match *cond {
                     LogicalOp(ref ct) => {
-                        self.logical_op_to_conditions(ct, &mut fields, &parent_node)
+                        self.logical_op_to_conditions(ct, &mut fields, &parent_node)?
+                    }
+                    ComparisonOp(ref ct) => self.to_conditions(ct, &mut fields, &parent_node)?,
+                    Bracketed(_) | NegationOp(_) | Base(_) | Arithmetic(_) => {
+                        return Err(SqlError::Unsupported(format!(
+                            "`{}` as the condition of {}",
+                            cond, computed_col.name
+                        )))
                     }
-                    ComparisonOp(ref ct) => self.to_conditions(ct, &mut fields, &parent_node),
-                    Bracketed(_) => unimplemented!(),
-                    NegationOp(_) => unreachable!("negation should have been removed earlier"),
-                    Base(_) => unreachable!("dangling base predicate"),
-                    Arithmetic(_) => unimplemented!(),
                 };
                 MirNode::new(
                     name,
@@ -1226,7 +1358,7 @@ impl SqlToMirConverter {
                 vec![parent_node.clone()],
                 vec![],
             ),
-        }
+        })
     }
 
     fn make_join_node(
@@ -1236,7 +1368,7 @@ impl SqlToMirConverter {
         left_node: MirNodeRef,
         right_node: MirNodeRef,
         kind: JoinType,
-    ) -> MirNodeRef {
+    ) -> Result<MirNodeRef, SqlError> {
         // TODO(malte): this is where we overproject join columns in order to increase reuse
         // opportunities. Technically, we need to only project those columns here that the query
         // actually needs; at a minimum, we could start with just the join colums, relying on the
@@ -1254,14 +1386,22 @@ impl SqlToMirConverter {
         let mut right_join_columns = Vec::new();
 
         // equi-join only
-        assert!(jp.operator == Operator::Equal || jp.operator == Operator::In);
+        let unsupported = || {
+            SqlError::Unsupported(format!(
+                "join condition `{}` (only equality between two columns is supported)",
+                jp
+            ))
+        };
+        if jp.operator != Operator::Equal && jp.operator != Operator::In {
+            return Err(unsupported());
+        }
         let mut l_col = match *jp.left {
             ConditionExpression::Base(ConditionBase::Field(ref f)) => Column::from(f),
-            _ => unimplemented!(),
+            _ => return Err(unsupported()),
         };
         let r_col = match *jp.right {
             ConditionExpression::Base(ConditionBase::Field(ref f)) => Column::from(f),
-            _ => unimplemented!(),
+            _ => return Err(unsupported()),
         };
 
         // don't duplicate the join column in the output, but instead add aliases to the columns
@@ -1306,14 +1446,14 @@ impl SqlToMirConverter {
             },
         };
         trace!(self.log, "Added join node {:?}", inner);
-        MirNode::new(
+        Ok(MirNode::new(
             name,
             self.schema_version,
             fields,
             inner,
             vec![left_node.clone(), right_node.clone()],
             vec![],
-        )
+        ))
     }
 
     fn make_projection_helper(
@@ -1450,7 +1590,7 @@ impl SqlToMirConverter {
         parent: MirNodeRef,
         ce: &ConditionExpression,
         nc: usize,
-    ) -> Vec<MirNodeRef> {
+    ) -> Result<Vec<MirNodeRef>, SqlError> {
         use nom_sql::ConditionExpression::*;
 
         let mut pred_nodes: Vec<MirNodeRef> = Vec::new();
@@ -1460,27 +1600,27 @@ impl SqlToMirConverter {
                 let (left, right);
                 match ct.operator {
                     Operator::And => {
-                        left = self.make_predicate_nodes(name, parent.clone(), &*ct.left, nc);
+                        left = self.make_predicate_nodes(name, parent.clone(), &*ct.left, nc)?;
 
                         right = self.make_predicate_nodes(
                             name,
                             left.last().unwrap().clone(),
                             &*ct.right,
                             nc + left.len(),
-                        );
+                        )?;
 
                         pred_nodes.extend(left.clone());
                         pred_nodes.extend(right.clone());
                     }
                     Operator::Or => {
-                        left = self.make_predicate_nodes(name, parent.clone(), &*ct.left, nc);
+                        left = self.make_predicate_nodes(name, parent.clone(), &*ct.left, nc)?;
 
                         right = self.make_predicate_nodes(
                             name,
                             parent.clone(),
                             &*ct.right,
                             nc + left.len(),
-                        );
+                        )?;
 
                         debug!(self.log, "Creating union node for `or` predicate");
 
@@ -1496,25 +1636,33 @@ impl SqlToMirConverter {
                         pred_nodes.extend(right.clone());
                         pred_nodes.push(union);
                     }
-                    _ => unreachable!("LogicalOp operator is {:?}", ct.operator),
+                    _ => {
+                        return Err(SqlError::Unsupported(format!(
+                            "`{}` in a condition",
+                            ct.operator
+                        )))
+                    }
                 }
             }
             ComparisonOp(ref ct) => {
                 // currently, we only support filter-like
                 // comparison operations, no nested-selections
-                let f = self.make_filter_node(&format!("{}_f{}", name, nc), parent, ct);
+                let f = self.make_filter_node(&format!("{}_f{}", name, nc), parent, ct)?;
 
                 pred_nodes.push(f);
             }
             Bracketed(ref inner) => {
-                pred_nodes.extend(self.make_predicate_nodes(name, parent, &*inner, nc));
+                pred_nodes.extend(self.make_predicate_nodes(name, parent, &*inner, nc)?);
+            }
+            NegationOp(_) | Base(_) | Arithmetic(_) => {
+                return Err(SqlError::Unsupported(format!(
+                    "condition `{}` that is not a comparison",
+                    ce
+                )))
             }
-            NegationOp(_) => unreachable!("negation should have been removed earlier"),
-            Base(_) => unreachable!("dangling base predicate"),
-            Arithmetic(_) => unimplemented!(),
         }
 
-        pred_nodes
+        Ok(pred_nodes)
     }
 
     fn predicates_above_group_by<'a>(
@@ -1524,7 +1672,7 @@ impl SqlToMirConverter {
         over_col: Column,
         parent: MirNodeRef,
         created_predicates: &mut Vec<&'a ConditionExpression>,
-    ) -> Vec<MirNodeRef> {
+    ) -> Result<Vec<MirNodeRef>, SqlError> {
         let mut predicates_above_group_by_nodes = Vec::new();
         let mut prev_node = parent.clone();
 
@@ -1536,7 +1684,7 @@ impl SqlToMirConverter {
                     prev_node.clone(),
                     ce,
                     0,
-                );
+                )?;
                 assert!(!mpns.is_empty());
                 prev_node = mpns.last().unwrap().clone();
                 predicates_above_group_by_nodes.extend(mpns);
@@ -1544,7 +1692,7 @@ impl SqlToMirConverter {
             }
         }
 
-        predicates_above_group_by_nodes
+        Ok(predicates_above_group_by_nodes)
     }
 
     fn make_value_project_node(
@@ -1618,7 +1766,7 @@ impl SqlToMirConverter {
             Option<HashMap<(String, Option<String>), String>>,
             String,
         ),
-        String,
+        SqlError,
     > {
         // TODO: make this take &self!
         use crate::controller::sql::mir::grouped::make_grouped;
@@ -1666,7 +1814,7 @@ impl SqlToMirConverter {
                 qg,
                 &node_for_rel,
                 new_node_count,
-            );
+            )?;
 
             new_node_count += join_nodes.len();
 
@@ -1719,7 +1867,7 @@ impl SqlToMirConverter {
                     new_node_count,
                     &column_to_predicates,
                     &mut prev_node,
-                );
+                )?;
 
             new_node_count += predicates_above_group_by_nodes.len();
 
@@ -1784,7 +1932,7 @@ impl SqlToMirConverter {
                     new_node_count,
                     &mut prev_node,
                     false,
-                );
+                )?;
 
                 new_node_count += func_nodes.len();
 
@@ -1827,7 +1975,7 @@ impl SqlToMirConverter {
                                 parent,
                                 p,
                                 0,
-                            );
+                            )?;
 
                             assert!(!fns.is_empty());
                             new_node_count += fns.len();
@@ -1856,7 +2004,12 @@ impl SqlToMirConverter {
                     }
 
                     let parent = match prev_node {
-                        None => unimplemented!(),
+                        None => {
+                            return Err(SqlError::Unsupported(format!(
+                                "condition `{}` on a query without any tables",
+                                p
+                            )))
+                        }
                         Some(pn) => pn,
                     };
 
@@ -1871,7 +2024,7 @@ impl SqlToMirConverter {
                         parent,
                         p,
                         0,
-                    );
+                    )?;
 
                     assert!(!fns.is_empty());
                     new_node_count += fns.len();
@@ -1948,7 +2101,7 @@ impl SqlToMirConverter {
                     &ancestors,
                     new_node_count,
                     sec_round,
-                );
+                )?;
 
                 if sec_round {
                     table_mapping = tables;
@@ -1969,6 +2122,7 @@ impl SqlToMirConverter {
                     .iter()
                     .filter_map(|oc| match *oc {
                         OutputColumn::Arithmetic(_) => None,
+                        OutputColumn::Data(ref c) if self.universe.hides(c) => None,
                         OutputColumn::Data(ref c) => Some(Column::from(c)),
                         OutputColumn::Literal(_) => None,
                     })
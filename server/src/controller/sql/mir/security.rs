@@ -1,8 +1,11 @@
+use crate::controller::security::policy::Mask;
 use crate::controller::sql::mir::rewrite::make_rewrite_nodes;
 use crate::controller::sql::mir::SqlToMirConverter;
 use crate::controller::sql::query_graph::QueryGraph;
 use crate::controller::sql::query_signature::Signature;
-use crate::controller::sql::UniverseId;
+use crate::controller::sql::{SqlError, UniverseId};
+use dataflow::ops::project::ColumnMask;
+use mir::node::{MirNode, MirNodeType};
 use mir::MirNodeRef;
 use std::collections::HashMap;
 
@@ -14,18 +17,21 @@ pub trait SecurityBoundary {
         ancestors: &[MirNodeRef],
         node_count: usize,
         sec: bool,
-    ) -> (
-        Vec<MirNodeRef>,
-        Option<HashMap<(String, Option<String>), String>>,
-        String,
-    );
+    ) -> Result<
+        (
+            Vec<MirNodeRef>,
+            Option<HashMap<(String, Option<String>), String>>,
+            String,
+        ),
+        SqlError,
+    >;
 
     fn make_security_boundary(
         &self,
         universe: UniverseId,
         node_for_rel: &mut HashMap<&str, MirNodeRef>,
         prev_node: Option<MirNodeRef>,
-    ) -> Result<(Vec<MirNodeRef>, Vec<MirNodeRef>), String>;
+    ) -> Result<(Vec<MirNodeRef>, Vec<MirNodeRef>), SqlError>;
 }
 
 impl SecurityBoundary for SqlToMirConverter {
@@ -36,11 +42,14 @@ impl SecurityBoundary for SqlToMirConverter {
         ancestors: &[MirNodeRef],
         node_count: usize,
         sec: bool,
-    ) -> (
-        Vec<MirNodeRef>,
-        Option<HashMap<(String, Option<String>), String>>,
-        String,
-    ) {
+    ) -> Result<
+        (
+            Vec<MirNodeRef>,
+            Option<HashMap<(String, Option<String>), String>>,
+            String,
+        ),
+        SqlError,
+    > {
         use crate::controller::sql::mir::grouped::make_grouped;
 
         let mut nodes_added = Vec::new();
@@ -54,7 +63,7 @@ impl SecurityBoundary for SqlToMirConverter {
         // First, union the results from all ancestors
         let (union, mapping) = if !sec {
             (
-                Some(self.make_union_node(&format!("{}_n{}", name, node_count), &ancestors)),
+                Some(self.make_union_node(&format!("{}_n{}", name, node_count), &ancestors)?),
                 None,
             )
         } else {
@@ -79,10 +88,10 @@ impl SecurityBoundary for SqlToMirConverter {
                     node_count,
                     &mut Some(node.clone()),
                     true,
-                );
+                )?;
 
                 nodes_added.extend(grouped);
-                (nodes_added, mapping, n)
+                Ok((nodes_added, mapping, n))
             }
             None => {
                 panic!("union not computed correctly");
@@ -96,7 +105,7 @@ impl SecurityBoundary for SqlToMirConverter {
         universe: UniverseId,
         node_for_rel: &mut HashMap<&str, MirNodeRef>,
         prev_node: Option<MirNodeRef>,
-    ) -> Result<(Vec<MirNodeRef>, Vec<MirNodeRef>), String> {
+    ) -> Result<(Vec<MirNodeRef>, Vec<MirNodeRef>), SqlError> {
         let mut security_nodes: Vec<MirNodeRef> = Vec::new();
         let mut last_security_nodes: Vec<MirNodeRef> = Vec::new();
         let mut prev_node = prev_node.unwrap().clone();
@@ -132,16 +141,82 @@ impl SecurityBoundary for SqlToMirConverter {
             last_security_nodes.push(prev_node.clone());
         }
 
+        // masks apply to whatever rows the row policies let through
+        if !self.universe.column_masks.is_empty() {
+            let suffix = match universe.1 {
+                Some(ref group) => format!("_m{}{}", group, universe.0),
+                None => format!("_m{}", universe.0),
+            };
+            for n in last_security_nodes.iter_mut() {
+                if let Some(mask) = make_mask_node(self, &suffix, n) {
+                    security_nodes.push(mask.clone());
+                    *n = mask;
+                }
+            }
+        }
+
         Ok((last_security_nodes, security_nodes))
     }
 }
 
+/// Masks the columns of `parent` that the universe's masking policies cover, if there are any.
+fn make_mask_node(
+    mir_converter: &SqlToMirConverter,
+    suffix: &str,
+    parent: &MirNodeRef,
+) -> Option<MirNodeRef> {
+    let columns = parent.borrow().columns().to_vec();
+    let masks: Vec<_> = columns
+        .iter()
+        .filter_map(|c| {
+            let table = c.table.as_ref()?;
+            mir_converter
+                .universe
+                .column_masks
+                .get(table)?
+                .iter()
+                .find(|&&(ref column, _)| *column == c.name)
+                .map(|&(_, ref mask)| (c.clone(), column_mask(mask)))
+        })
+        .collect();
+
+    if masks.is_empty() {
+        return None;
+    }
+
+    let name = format!("{}{}", parent.borrow().name(), suffix);
+    Some(MirNode::new(
+        &name,
+        mir_converter.schema_version,
+        columns,
+        MirNodeType::Mask { masks },
+        vec![parent.clone()],
+        vec![],
+    ))
+}
+
+fn column_mask(mask: &Mask) -> ColumnMask {
+    match *mask {
+        // hidden columns are also left out of the universe's views
+        Mask::Hide | Mask::Null => ColumnMask::Null,
+        Mask::Hash => ColumnMask::Hash,
+        Mask::Truncate {
+            length,
+            from_end: false,
+        } => ColumnMask::KeepFirst(length),
+        Mask::Truncate {
+            length,
+            from_end: true,
+        } => ColumnMask::KeepLast(length),
+    }
+}
+
 fn make_security_nodes(
     mir_converter: &SqlToMirConverter,
     table: &str,
     prev_node: &MirNodeRef,
     node_for_rel: HashMap<&str, MirNodeRef>,
-) -> Result<(Vec<MirNodeRef>, Vec<MirNodeRef>), String> {
+) -> Result<(Vec<MirNodeRef>, Vec<MirNodeRef>), SqlError> {
     let policies = match mir_converter
         .universe
         .row_policies
@@ -196,28 +271,16 @@ fn make_security_nodes(
             base_nodes.push(view_for_rel.clone());
         }
 
-        use crate::controller::sql::mir::join::make_joins;
-
-        // handles predicate nodes
-        for rel in &sorted_rels {
-            let qgn = qg
-                .relations
-                .get(*rel)
-                .expect("relation should have a query graph node.");
-            assert!(*rel != "computed_collumns");
-
-            // Skip empty predicates
-            if qgn.predicates.is_empty() {
-                continue;
-            }
-
+        // handles predicate nodes on the policy's table, which can be applied before any joins
+        if let Some(qgn) = qg.relations.get(table) {
             for pred in &qgn.predicates {
                 let new_nodes = mir_converter.make_predicate_nodes(
                     &format!("sp_{:x}_n{:x}", qg.signature().hash, node_count),
                     prev_node.expect("empty previous node"),
                     pred,
                     0,
-                );
+                )?;
+                node_count += new_nodes.len();
 
                 prev_node = Some(
                     new_nodes
@@ -230,24 +293,68 @@ fn make_security_nodes(
             }
 
             // update local node relations so joins know which views to join
-            local_node_for_rel.insert(*rel, prev_node.clone().unwrap());
+            local_node_for_rel.insert(table, prev_node.clone().unwrap());
         }
 
+        use crate::controller::sql::mir::join::make_joins;
+
         let join_nodes = make_joins(
             mir_converter,
             &format!("sp_{:x}", qg.signature().hash),
             qg,
             &local_node_for_rel,
             node_count,
-        );
+        )?;
 
         node_count += join_nodes.len();
 
-        let prev_node = match join_nodes.last() {
+        let mut prev_node = match join_nodes.last() {
             Some(n) => n.clone(),
             None => local_node_for_rel[table].clone(),
         };
 
+        // predicates on the tables and views joined with the policy's table, and those that
+        // compare columns of several of them, need the joins' output
+        let mut joined_filter_nodes = Vec::new();
+        let joined_predicates = sorted_rels
+            .iter()
+            .filter(|rel| **rel != table && **rel != "computed_columns")
+            .flat_map(|rel| qg.relations[*rel].predicates.iter())
+            .chain(qg.global_predicates.iter());
+        for pred in joined_predicates {
+            let new_nodes = mir_converter.make_predicate_nodes(
+                &format!("sp_{:x}_n{:x}", qg.signature().hash, node_count),
+                prev_node,
+                pred,
+                0,
+            )?;
+            node_count += new_nodes.len();
+
+            prev_node = new_nodes
+                .iter()
+                .last()
+                .expect("no new nodes were created")
+                .clone();
+            joined_filter_nodes.extend(new_nodes);
+        }
+
+        // the joined columns are only needed to decide which rows the policy lets through, and
+        // all of a table's policies must produce the same columns so they can be unioned
+        if !join_nodes.is_empty() {
+            let columns = local_node_for_rel[table].borrow().columns().to_vec();
+            let project = mir_converter.make_project_node(
+                &format!("sp_{:x}_n{:x}", qg.signature().hash, node_count),
+                prev_node,
+                columns.iter().collect(),
+                vec![],
+                vec![],
+                false,
+            );
+            node_count += 1;
+            prev_node = project.clone();
+            joined_filter_nodes.push(project);
+        }
+
         let rewrite_nodes = make_rewrite_nodes(
             mir_converter,
             &format!("sp_{:x}", qg.signature().hash),
@@ -262,6 +369,7 @@ fn make_security_nodes(
             .into_iter()
             .chain(filter_nodes.into_iter())
             .chain(join_nodes.into_iter())
+            .chain(joined_filter_nodes.into_iter())
             .chain(rewrite_nodes.into_iter())
             .collect();
 
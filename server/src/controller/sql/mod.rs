@@ -7,9 +7,10 @@ mod reuse;
 pub(super) mod security;
 
 use self::mir::SqlToMirConverter;
+use self::passes::unsupported::unsupported_statement;
 use self::query_graph::{to_query_graph, QueryGraph};
 use self::query_signature::Signature;
-use self::reuse::ReuseConfig;
+use self::reuse::{order_joins_by_cardinality, ReuseConfig};
 use super::mir_to_flow::mir_query_to_flow_parts;
 use crate::controller::Migration;
 use crate::ReuseConfigType;
@@ -21,10 +22,13 @@ use dataflow::prelude::DataType;
 use nom_sql::parser as sql_parser;
 use nom_sql::{ArithmeticBase, CreateTableStatement, SqlQuery};
 use nom_sql::{CompoundSelectOperator, CompoundSelectStatement, SelectStatement};
+use noria::error::RecipeError;
+use noria::TableStatistics;
 use petgraph::graph::NodeIndex;
 
 use slog;
 use std::collections::HashMap;
+use std::fmt;
 use std::str;
 use std::vec::Vec;
 
@@ -39,6 +43,47 @@ enum QueryGraphReuse {
     None,
 }
 
+/// Why a query could not be added to the graph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SqlError {
+    /// The query uses a SQL construct that we cannot turn into dataflow.
+    Unsupported(String),
+    /// The query is well-formed, but can't be added as written.
+    Invalid(String),
+}
+
+impl SqlError {
+    /// Attribute this error to the query named `query`.
+    pub(super) fn for_query(self, query: &str) -> RecipeError {
+        let query = query.to_owned();
+        match self {
+            SqlError::Unsupported(construct) => RecipeError::Unsupported { query, construct },
+            SqlError::Invalid(reason) => RecipeError::Invalid { query, reason },
+        }
+    }
+}
+
+impl From<String> for SqlError {
+    fn from(reason: String) -> Self {
+        SqlError::Invalid(reason)
+    }
+}
+
+impl From<SqlError> for String {
+    fn from(e: SqlError) -> Self {
+        e.to_string()
+    }
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SqlError::Unsupported(ref construct) => write!(f, "unsupported: {}", construct),
+            SqlError::Invalid(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
 /// Long-lived struct that holds information about the SQL queries that have been incorporated into
 /// the Soup graph `grap`.
 /// The incorporator shares the lifetime of the flow graph it is associated with.
@@ -65,6 +110,36 @@ pub(crate) struct SqlIncorporator {
     /// Active universes mapped to the group they belong to.
     /// If an user universe, mapped to None.
     universes: HashMap<Option<DataType>, Vec<UniverseId>>,
+    /// The names of the queries and bases added for each universe, in the order they were added.
+    universe_queries: HashMap<UniverseId, Vec<String>>,
+
+    /// How big the base tables were when last checked, for ordering joins.
+    table_statistics: HashMap<String, TableStatistics>,
+}
+
+/// The state of an incorporator from before a migration, which the incorporator can be rolled back
+/// to if the migration is abandoned.
+///
+/// Clones of an incorporator share their MIR nodes, and adding a query hangs new nodes off existing
+/// ones, so a clone alone does not remember what the incorporator looked like.
+pub(crate) struct Checkpoint {
+    inc: SqlIncorporator,
+    /// The connections and columns of every existing MIR node.
+    nodes: Vec<(MirNodeRef, Vec<MirNodeRef>, Vec<MirNodeRef>, Vec<Column>)>,
+}
+
+impl Checkpoint {
+    /// Undo any changes made to MIR nodes since the checkpoint was taken, and return the
+    /// incorporator as it was then.
+    pub(super) fn restore(self) -> SqlIncorporator {
+        for (n, ancestors, children, columns) in self.nodes {
+            let mut n = n.borrow_mut();
+            n.ancestors = ancestors;
+            n.children = children;
+            n.columns = columns;
+        }
+        self.inc
+    }
 }
 
 impl Default for SqlIncorporator {
@@ -87,6 +162,9 @@ impl Default for SqlIncorporator {
 
             reuse_type: ReuseConfigType::Finkelstein,
             universes: HashMap::default(),
+            universe_queries: HashMap::default(),
+
+            table_statistics: HashMap::default(),
         }
     }
 }
@@ -114,6 +192,11 @@ impl SqlIncorporator {
         self.reuse_type = reuse_type;
     }
 
+    /// Use the given base table statistics to order the joins of queries added from now on.
+    pub(super) fn set_table_statistics(&mut self, tables: HashMap<String, TableStatistics>) {
+        self.table_statistics = tables;
+    }
+
     /// Incorporates a single query into via the flow graph migration in `mig`. The `query`
     /// argument is a string that holds a parameterized SQL query, and the `name` argument supplies
     /// an optional name for the query. If no `name` is specified, the table name is used in the
@@ -128,7 +211,7 @@ impl SqlIncorporator {
         query: &str,
         name: Option<String>,
         mut mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         query.to_flow_parts(self, name, &mut mig)
     }
 
@@ -145,13 +228,34 @@ impl SqlIncorporator {
         name: Option<String>,
         is_leaf: bool,
         mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         match name {
             None => self.nodes_for_query(query, is_leaf, mig),
             Some(n) => self.nodes_for_named_query(query, n, is_leaf, mig),
         }
     }
 
+    /// Remember the current state of the incorporator, so that it can be restored if a migration
+    /// fails part-way through.
+    pub(super) fn checkpoint(&self) -> Checkpoint {
+        let nodes = self
+            .mir_converter
+            .all_nodes()
+            .into_iter()
+            .map(|n| {
+                let (ancestors, children, columns) = {
+                    let n = n.borrow();
+                    (n.ancestors.clone(), n.children.clone(), n.columns.clone())
+                };
+                (n, ancestors, children, columns)
+            })
+            .collect();
+        Checkpoint {
+            inc: self.clone(),
+            nodes,
+        }
+    }
+
     pub(super) fn get_base_schema(&self, name: &str) -> Option<CreateTableStatement> {
         self.base_schemas.get(name).cloned()
     }
@@ -174,6 +278,11 @@ impl SqlIncorporator {
         }
     }
 
+    /// Describe the MIR nodes that each data-flow node was made from.
+    pub(super) fn mir_by_flow_node(&self) -> HashMap<NodeIndex, Vec<String>> {
+        self.mir_converter.descriptions_by_flow_node()
+    }
+
     pub(super) fn is_leaf_address(&self, ni: NodeIndex) -> bool {
         self.leaf_addresses.values().any(|nn| *nn == ni)
     }
@@ -190,20 +299,18 @@ impl SqlIncorporator {
         query_name: &str,
         universe: UniverseId,
         st: &SelectStatement,
-    ) -> (QueryGraph, QueryGraphReuse) {
+    ) -> Result<(QueryGraph, QueryGraphReuse), SqlError> {
         debug!(self.log, "Making QG for \"{}\"", query_name);
         trace!(self.log, "Query \"{}\": {:#?}", query_name, st);
 
-        let mut qg = match to_query_graph(st) {
-            Ok(qg) => qg,
-            Err(e) => panic!(e),
-        };
+        let mut qg = to_query_graph(st)?;
 
         trace!(self.log, "QG for \"{}\": {:#?}", query_name, qg);
 
         // if reuse is disabled, we're done
         if self.reuse_type == ReuseConfigType::NoReuse {
-            return (qg, QueryGraphReuse::None);
+            order_joins_by_cardinality(&mut qg, &self.table_statistics);
+            return Ok((qg, QueryGraphReuse::None));
         }
 
         // Do we already have this exact query or a subset of it in the same universe?
@@ -238,7 +345,7 @@ impl SqlIncorporator {
                         existing_qg,
                     );
 
-                    return (qg, QueryGraphReuse::ExactMatch(mir_query.leaf.clone()));
+                    return Ok((qg, QueryGraphReuse::ExactMatch(mir_query.leaf.clone())));
                 } else if existing_qg.signature() == qg.signature()
                     && existing_qg.parameters() != qg.parameters()
                 {
@@ -331,16 +438,20 @@ impl SqlIncorporator {
                                     Some(project_columns)
                                 }
                             };
-                            return (
+                            return Ok((
                                 qg,
                                 QueryGraphReuse::ReaderOntoExisting(mn, project_columns, params),
-                            );
+                            ));
                         }
                     }
                 }
             }
         }
 
+        // the joins are ordered by how many rows they're expected to produce, and then reordered
+        // to line up with any reusable join chains
+        order_joins_by_cardinality(&mut qg, &self.table_statistics);
+
         let reuse_config = ReuseConfig::new(self.reuse_type.clone());
 
         // Find a promising set of query graphs
@@ -372,12 +483,12 @@ impl SqlIncorporator {
                 mir_queries.extend(mqs);
             }
 
-            return (qg, QueryGraphReuse::ExtendExisting(mir_queries));
+            return Ok((qg, QueryGraphReuse::ExtendExisting(mir_queries)));
         } else {
             info!(self.log, "No reuse opportunity, adding fresh query");
         }
 
-        (qg, QueryGraphReuse::None)
+        Ok((qg, QueryGraphReuse::None))
     }
 
     fn add_leaf_to_existing_query(
@@ -445,8 +556,8 @@ impl SqlIncorporator {
         query: &CompoundSelectStatement,
         is_leaf: bool,
         mut mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
-        let subqueries: Result<Vec<_>, String> = query
+    ) -> Result<QueryFlowParts, SqlError> {
+        let subqueries: Result<Vec<_>, SqlError> = query
             .selects
             .iter()
             .enumerate()
@@ -465,7 +576,7 @@ impl SqlIncorporator {
             &query.order,
             &query.limit,
             is_leaf,
-        );
+        )?;
 
         let qfp = mir_query_to_flow_parts(&mut combined_mir_query, &mut mig, None);
 
@@ -482,8 +593,8 @@ impl SqlIncorporator {
         sq: &SelectStatement,
         is_leaf: bool,
         mig: &mut Migration,
-    ) -> Result<(QueryFlowParts, Option<MirQuery>), String> {
-        let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq);
+    ) -> Result<(QueryFlowParts, Option<MirQuery>), SqlError> {
+        let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq)?;
         Ok(match reuse {
             QueryGraphReuse::ExactMatch(mn) => {
                 let flow_node = mn.borrow().flow_node.as_ref().unwrap().address();
@@ -518,7 +629,7 @@ impl SqlIncorporator {
         qg: QueryGraph,
         is_leaf: bool,
         mut mig: &mut Migration,
-    ) -> Result<(QueryFlowParts, MirQuery), String> {
+    ) -> Result<(QueryFlowParts, MirQuery), SqlError> {
         use ::mir::visualize::GraphViz;
         let universe = mig.universe();
         // no QG-level reuse possible, so we'll build a new query.
@@ -595,7 +706,7 @@ impl SqlIncorporator {
 
             // clean up local state
             self.mir_queries.remove(&(qg_hash, mig.universe())).unwrap();
-            self.query_graphs.remove(&qg_hash).unwrap();
+            self.forget_query_graph(qg_hash);
             self.view_schemas.remove(query_name).unwrap();
 
             // trigger reader node removal
@@ -609,13 +720,125 @@ impl SqlIncorporator {
 
             // clean up state for this query
             self.mir_queries.remove(&(qg_hash, mig.universe())).unwrap();
-            self.query_graphs.remove(&qg_hash).unwrap();
+            self.forget_query_graph(qg_hash);
             self.view_schemas.remove(query_name).unwrap();
 
             None
         }
     }
 
+    /// Drop the query graph with the given hash, unless a query in some universe still uses it.
+    fn forget_query_graph(&mut self, qg_hash: u64) {
+        if !self.mir_queries.keys().any(|&(h, _)| h == qg_hash) {
+            self.query_graphs.remove(&qg_hash);
+        }
+    }
+
+    /// Forget about every query and base that was added for `universe`.
+    ///
+    /// Returns the leaf nodes of the universe's queries and bases that no other query uses, which
+    /// the caller must remove from the graph.
+    pub(super) fn remove_universe(
+        &mut self,
+        universe: &UniverseId,
+    ) -> Result<Vec<NodeIndex>, String> {
+        let registered = match self.universes.get_mut(&universe.1) {
+            Some(universes) => match universes.iter().position(|u| u == universe) {
+                Some(i) => {
+                    universes.remove(i);
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        };
+        if self.universes.get(&universe.1).map(Vec::is_empty) == Some(true) {
+            self.universes.remove(&universe.1);
+        }
+
+        let names = match self.universe_queries.remove(universe) {
+            Some(names) => names,
+            None if registered => Vec::new(),
+            None => {
+                return Err(format!(
+                    "universe \"{}\" does not exist",
+                    universe.0.to_string()
+                ))
+            }
+        };
+
+        info!(
+            self.log,
+            "Removing universe {} from SqlIncorporator",
+            universe.0.to_string();
+            "queries" => names.len(),
+        );
+
+        // queries may build on ones added to the universe before them, so go newest first
+        let mut leaves = Vec::new();
+        for name in names.iter().rev() {
+            self.view_schemas.remove(name);
+
+            if let Some(qg_hash) = self.named_queries.remove(name) {
+                if let Some(mir) = self.mir_queries.remove(&(qg_hash, universe.clone())) {
+                    if self.mir_converter.has_query(name) {
+                        self.mir_converter.remove_query(name, &mir);
+                    }
+                }
+                self.forget_query_graph(qg_hash);
+            } else if let Some(mir) = self.base_mir_queries.remove(name) {
+                let is_base = self.base_schemas.remove(name).is_some();
+                if !self.mir_converter.has_query(name) {
+                    // the query reused an existing leaf, so the converter never knew about it
+                } else if is_base {
+                    self.mir_converter.remove_base(name, &mir);
+                } else {
+                    self.mir_converter.remove_query(name, &mir);
+                }
+            }
+
+            if let Some(ni) = self.leaf_addresses.remove(name) {
+                if !leaves.contains(&ni) && !self.leaf_addresses.values().any(|&l| l == ni) {
+                    leaves.push(ni);
+                }
+            }
+        }
+
+        Ok(leaves)
+    }
+
+    /// The leaf nodes of the queries in each user universe, by user id.
+    pub(super) fn user_universe_leaves(&self) -> HashMap<DataType, Vec<NodeIndex>> {
+        self.universe_queries
+            .iter()
+            .filter(|((_, group), _)| group.is_none())
+            .map(|((id, _), names)| {
+                let leaves = names
+                    .iter()
+                    .filter_map(|name| self.leaf_addresses.get(name).cloned())
+                    .collect();
+                (id.clone(), leaves)
+            })
+            .collect()
+    }
+
+    /// Remember that `query_name` was added for `universe`, so it can be removed along with it.
+    ///
+    /// Only names that are new are remembered; universes also add queries that exist globally,
+    /// such as the recipe's tables, and those must outlive the universe.
+    fn remember_universe_query(&mut self, query_name: &str, universe: UniverseId) {
+        if universe.0 == "global".into() {
+            return;
+        }
+        let names = self
+            .universe_queries
+            .entry(universe)
+            .or_insert_with(Vec::new);
+        if !names.iter().any(|n| n == query_name) {
+            names.push(query_name.to_owned());
+        }
+    }
+
     pub(super) fn remove_base(&mut self, name: &str) {
         info!(self.log, "Removing base {} from SqlIncorporator", name);
         if self.base_schemas.remove(name).is_none() {
@@ -651,7 +874,13 @@ impl SqlIncorporator {
 
         // TODO(malte): get rid of duplication and figure out where to track this state
         debug!(self.log, "registering query \"{}\"", query_name);
-        self.view_schemas.insert(String::from(query_name), fields);
+        if self
+            .view_schemas
+            .insert(String::from(query_name), fields)
+            .is_none()
+        {
+            self.remember_universe_query(query_name, universe.clone());
+        }
 
         // We made a new query, so store the query graph and the corresponding leaf MIR node.
         // TODO(malte): we currently store nothing if there is no QG (e.g., for compound queries).
@@ -678,7 +907,7 @@ impl SqlIncorporator {
         reuse_mirs: Vec<(u64, UniverseId)>,
         is_leaf: bool,
         mut mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         use ::mir::reuse::merge_mir_for_queries;
         use ::mir::visualize::GraphViz;
         let universe = mig.universe();
@@ -760,18 +989,18 @@ impl SqlIncorporator {
         q: SqlQuery,
         is_leaf: bool,
         mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         let name = match q {
             SqlQuery::CreateTable(ref ctq) => ctq.table.name.clone(),
             SqlQuery::CreateView(ref cvq) => cvq.name.clone(),
             SqlQuery::Select(_) | SqlQuery::CompoundSelect(_) => format!("q_{}", self.num_queries),
-            _ => panic!("only CREATE TABLE and SELECT queries can be added to the graph!"),
+            ref q => return Err(SqlError::Unsupported(unsupported_statement(q))),
         };
         self.nodes_for_named_query(q, name, is_leaf, mig)
     }
 
     /// Runs some standard rewrite passes on the query.
-    fn rewrite_query(&mut self, q: SqlQuery, mig: &mut Migration) -> Result<SqlQuery, String> {
+    fn rewrite_query(&mut self, q: SqlQuery, mig: &mut Migration) -> Result<SqlQuery, SqlError> {
         // TODO: make this not take &mut self
 
         use passes::alias_removal::AliasRemoval;
@@ -781,8 +1010,12 @@ impl SqlIncorporator {
         use passes::negation_removal::NegationRemoval;
         use passes::star_expansion::StarExpansion;
         use passes::subqueries::SubQueries;
+        use passes::unsupported::CheckSupported;
         use query_utils::ReferredTables;
 
+        // reject anything we can't build dataflow for before we start adding nodes for it
+        q.check_supported().map_err(SqlError::Unsupported)?;
+
         // need to increment here so that each subquery has a unique name.
         // (subqueries call recursively into `nodes_for_named_query` via `add_parsed_query` below,
         // so we will end up incrementing this for every subquery.
@@ -800,22 +1033,18 @@ impl SqlIncorporator {
                 Subquery::InComparison(cond_base) => {
                     let (sq, column) = query_from_condition_base(&cond_base);
 
-                    let qfp = self
-                        .add_parsed_query(sq, None, false, mig)
-                        .expect("failed to add subquery");
+                    let qfp = self.add_parsed_query(sq, None, false, mig)?;
                     *cond_base = field_with_table_name(qfp.name.clone(), column);
                 }
                 Subquery::InJoin(join_right_side) => {
                     *join_right_side = match *join_right_side {
                         JoinRightSide::NestedSelect(ref ns, ref alias) => {
-                            let qfp = self
-                                .add_parsed_query(
-                                    SqlQuery::Select((**ns).clone()),
-                                    alias.clone(),
-                                    false,
-                                    mig,
-                                )
-                                .expect("failed to add subquery in join");
+                            let qfp = self.add_parsed_query(
+                                SqlQuery::Select((**ns).clone()),
+                                alias.clone(),
+                                false,
+                                mig,
+                            )?;
                             JoinRightSide::Table(Table {
                                 name: qfp.name.clone(),
                                 alias: None,
@@ -845,7 +1074,10 @@ impl SqlIncorporator {
             | ref q @ SqlQuery::Insert(_) => {
                 for t in &q.referred_tables() {
                     if !self.view_schemas.contains_key(&t.name) {
-                        return Err(format!("query refers to unknown table \"{}\"", t.name));
+                        return Err(SqlError::Invalid(format!(
+                            "query refers to unknown table \"{}\"",
+                            t.name
+                        )));
                     }
                 }
             }
@@ -868,7 +1100,7 @@ impl SqlIncorporator {
         query_name: String,
         is_leaf: bool,
         mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         // short-circuit if we're dealing with a CreateView query; this avoids having to deal with
         // CreateView in all of our rewrite passes.
         if let SqlQuery::CreateView(cvq) = q {
@@ -899,21 +1131,30 @@ impl SqlIncorporator {
                 // NOTE(malte): We can't currently reuse complete compound select queries, since
                 // our reuse logic operates on `SqlQuery` structures. Their subqueries do get
                 // reused, however.
-                self.add_compound_query(&query_name, &csq, is_leaf, mig)
-                    .unwrap()
+                self.add_compound_query(&query_name, &csq, is_leaf, mig)?
             }
             SqlQuery::Select(sq) => self.add_select_query(&query_name, &sq, is_leaf, mig)?.0,
             ref q @ SqlQuery::CreateTable { .. } => self.add_base_via_mir(&query_name, &q, mig),
-            q => panic!("unhandled query type in recipe: {:?}", q),
+            ref q => return Err(SqlError::Unsupported(unsupported_statement(q))),
         };
 
         // record info about query
-        self.leaf_addresses
-            .insert(String::from(query_name.as_str()), qfp.query_leaf);
+        if self
+            .leaf_addresses
+            .insert(String::from(query_name.as_str()), qfp.query_leaf)
+            .is_none()
+        {
+            self.remember_universe_query(&query_name, mig.universe());
+        }
 
         Ok(qfp)
     }
 
+    /// Treat column `from` of base `table` as renamed to `to` when the table's schema next changes.
+    pub(super) fn rename_base_column(&mut self, table: &str, from: &str, to: &str) {
+        self.mir_converter.rename_base_column(table, from, to);
+    }
+
     /// Upgrades the schema version that any nodes created for queries will be tagged with.
     /// `new_version` must be strictly greater than the current version in `self.schema_version`.
     pub(super) fn upgrade_schema(&mut self, new_version: usize) {
@@ -937,7 +1178,7 @@ trait ToFlowParts {
         inc: &mut SqlIncorporator,
         name: Option<String>,
         mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String>;
+    ) -> Result<QueryFlowParts, SqlError>;
 }
 
 impl<'a> ToFlowParts for &'a String {
@@ -946,7 +1187,7 @@ impl<'a> ToFlowParts for &'a String {
         inc: &mut SqlIncorporator,
         name: Option<String>,
         mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         self.as_str().to_flow_parts(inc, name, mig)
     }
 }
@@ -957,21 +1198,21 @@ impl<'a> ToFlowParts for &'a str {
         inc: &mut SqlIncorporator,
         name: Option<String>,
         mig: &mut Migration,
-    ) -> Result<QueryFlowParts, String> {
+    ) -> Result<QueryFlowParts, SqlError> {
         // try parsing the incoming SQL
         let parsed_query = sql_parser::parse_query(self);
 
         // if ok, manufacture a node for the query structure we got
         match parsed_query {
             Ok(q) => inc.add_parsed_query(q, name, true, mig),
-            Err(e) => Err(String::from(e)),
+            Err(e) => Err(SqlError::Invalid(String::from(e))),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{SqlIncorporator, ToFlowParts};
+    use super::{SqlError, SqlIncorporator, ToFlowParts};
     use crate::controller::Migration;
     use crate::integration;
     use dataflow::prelude::*;
@@ -2332,4 +2573,61 @@ mod tests {
         })
         .await;
     }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn it_rejects_unsupported_queries() {
+        let mut g = integration::start_simple("it_rejects_unsupported_queries").await;
+        g.migrate(|mig| {
+            let mut inc = SqlIncorporator::default();
+            assert!(inc
+                .add_query("CREATE TABLE users (id int, name varchar(40));", None, mig)
+                .is_ok());
+            assert!(inc
+                .add_query("CREATE TABLE articles (id int, author int);", None, mig)
+                .is_ok());
+            let ncount = mig.graph().node_count();
+
+            for q in &[
+                "SELECT users.name FROM users, articles WHERE users.id > articles.author;",
+                "SELECT users.name FROM users JOIN articles \
+                 ON (users.id = articles.author AND articles.id = 1);",
+                "SELECT users.name FROM users CROSS JOIN articles ON (users.id = articles.author);",
+                "SELECT users.name FROM users WHERE users.id + 1 = 2;",
+                "SELECT users.id FROM users INTERSECT SELECT articles.id FROM articles;",
+                "SELECT AVG(users.id) AS a FROM users;",
+                "SELECT users.name FROM users WHERE users.id = (SELECT articles.author FROM articles);",
+                // these get past the syntactic checks and are only caught while building MIR
+                "SELECT SUM(CASE WHEN articles.id = ? THEN articles.author ELSE 0 END) AS s \
+                 FROM articles;",
+            ] {
+                match inc.add_query(q, None, mig) {
+                    Err(SqlError::Unsupported(_)) => (),
+                    r => panic!("expected {} to be unsupported, got {:?}", q, r.map(|_| ())),
+                }
+            }
+            for q in &[
+                "SELECT nonexistent.x FROM nonexistent;",
+                "SELECT users.name FROM users WHERE users.name = users.nonexistent;",
+            ] {
+                match inc.add_query(q, None, mig) {
+                    Err(SqlError::Invalid(_)) => (),
+                    r => panic!("expected {} to be invalid, got {:?}", q, r.map(|_| ())),
+                }
+            }
+
+            // none of the rejected queries should have added nodes
+            assert_eq!(mig.graph().node_count(), ncount);
+
+            // the union's inputs are added before we find that their columns don't line up
+            match inc.add_query(
+                "SELECT users.id FROM users UNION SELECT articles.author FROM articles;",
+                None,
+                mig,
+            ) {
+                Err(SqlError::Invalid(_)) => (),
+                r => panic!("expected a mismatched union to be invalid, got {:?}", r.map(|_| ())),
+            }
+        })
+        .await;
+    }
 }
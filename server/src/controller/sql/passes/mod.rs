@@ -5,3 +5,4 @@ pub mod key_def_coalescing;
 pub mod negation_removal;
 pub mod star_expansion;
 pub mod subqueries;
+pub mod unsupported;
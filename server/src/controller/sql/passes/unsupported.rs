@@ -0,0 +1,324 @@
+use nom_sql::{
+    ArithmeticBase, CaseWhenExpression, Column, ColumnOrLiteral, CompoundSelectOperator,
+    CompoundSelectStatement, ConditionBase, ConditionExpression, ConditionTree,
+    FieldDefinitionExpression, FieldValueExpression, FunctionArguments, FunctionExpression,
+    JoinConstraint, JoinOperator, JoinRightSide, Literal, Operator, SelectSpecification,
+    SelectStatement, SqlQuery,
+};
+
+/// Rejects queries that use SQL constructs we cannot build dataflow for.
+///
+/// Query planning assumes that queries only use the constructs it knows how to handle, so this
+/// check runs before any other pass. On failure, it returns a description of the offending
+/// construct.
+pub trait CheckSupported {
+    fn check_supported(&self) -> Result<(), String>;
+}
+
+/// Describes a kind of statement that cannot be part of a recipe.
+pub fn unsupported_statement(q: &SqlQuery) -> String {
+    let kind = match *q {
+        SqlQuery::CreateTable(_) => "CREATE TABLE",
+        SqlQuery::CreateView(_) => "CREATE VIEW",
+        SqlQuery::Select(_) | SqlQuery::CompoundSelect(_) => "SELECT",
+        SqlQuery::Insert(_) => "INSERT",
+        SqlQuery::Update(_) => "UPDATE",
+        SqlQuery::Delete(_) => "DELETE",
+        SqlQuery::DropTable(_) => "DROP TABLE",
+        SqlQuery::Set(_) => "SET",
+    };
+    format!("{} statements in a recipe", kind)
+}
+
+fn check_function(f: &FunctionExpression) -> Result<(), String> {
+    use nom_sql::FunctionExpression::*;
+
+    match *f {
+        CountStar
+        | Sum(FunctionArguments::Column(_), _)
+        | Count(FunctionArguments::Column(_), _)
+        | Max(FunctionArguments::Column(_))
+        | Min(FunctionArguments::Column(_))
+        | GroupConcat(FunctionArguments::Column(_), _) => Ok(()),
+        Sum(FunctionArguments::Conditional(ref cw), false)
+        | Count(FunctionArguments::Conditional(ref cw), false) => check_case_when(cw),
+        _ => Err(format!("aggregation `{}`", f)),
+    }
+}
+
+fn check_case_when(cw: &CaseWhenExpression) -> Result<(), String> {
+    match (&cw.then_expr, &cw.else_expr) {
+        (ColumnOrLiteral::Column(_), None)
+        | (ColumnOrLiteral::Column(_), Some(ColumnOrLiteral::Literal(_))) => (),
+        _ => {
+            return Err(format!(
+                "CASE WHEN `{}` (aggregations must be over a column, with a literal ELSE value)",
+                cw
+            ))
+        }
+    }
+
+    // filter aggregations only understand conjunctions of simple comparisons
+    fn check_conjunction(ce: &ConditionExpression) -> Result<(), String> {
+        match *ce {
+            ConditionExpression::LogicalOp(ConditionTree {
+                operator: Operator::And,
+                ref left,
+                ref right,
+            }) => {
+                check_conjunction(left)?;
+                check_conjunction(right)
+            }
+            ConditionExpression::ComparisonOp(ref ct) => check_comparison(ct),
+            _ => Err(format!("CASE WHEN condition `{}`", ce)),
+        }
+    }
+    check_conjunction(&cw.condition)
+}
+
+fn check_column(c: &Column) -> Result<(), String> {
+    match c.function {
+        Some(ref f) => check_function(f),
+        None => Ok(()),
+    }
+}
+
+fn check_literal(l: &Literal) -> Result<(), String> {
+    match *l {
+        Literal::Null | Literal::Integer(_) | Literal::String(_) | Literal::Placeholder => Ok(()),
+        _ => Err(format!("comparisons with literal `{}`", l.to_string())),
+    }
+}
+
+fn check_comparison(ct: &ConditionTree) -> Result<(), String> {
+    match *ct.left {
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => check_column(c)?,
+        _ => {
+            return Err(format!(
+                "comparison `{} {} {}` whose left-hand side is not a column",
+                ct.left, ct.operator, ct.right
+            ))
+        }
+    }
+
+    match *ct.right {
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => check_column(c),
+        ConditionExpression::Base(ConditionBase::Literal(ref l)) => check_literal(l),
+        ConditionExpression::Base(ConditionBase::LiteralList(ref ll)) => {
+            ll.iter().map(check_literal).collect()
+        }
+        ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => {
+            if ct.operator != Operator::In {
+                return Err(format!(
+                    "nested SELECT compared with `{}` (only IN is supported)",
+                    ct.operator
+                ));
+            }
+            if sq.fields.len() != 1 {
+                return Err("nested SELECTs in IN that don't return exactly one column".into());
+            }
+            match sq.fields[0] {
+                FieldDefinitionExpression::Col(_) => sq.check_supported(),
+                ref f => Err(format!("nested SELECT returning `{}`", f)),
+            }
+        }
+        _ => Err(format!(
+            "comparison `{} {} {}` whose right-hand side is an expression",
+            ct.left, ct.operator, ct.right
+        )),
+    }
+}
+
+fn check_condition(ce: &ConditionExpression) -> Result<(), String> {
+    match *ce {
+        ConditionExpression::LogicalOp(ref ct) => {
+            check_condition(&ct.left)?;
+            check_condition(&ct.right)
+        }
+        ConditionExpression::ComparisonOp(ref ct) => check_comparison(ct),
+        ConditionExpression::NegationOp(ref inner) | ConditionExpression::Bracketed(ref inner) => {
+            check_condition(inner)
+        }
+        ConditionExpression::Arithmetic(ref ae) => {
+            Err(format!("arithmetic expression `{}` in a condition", ae))
+        }
+        ConditionExpression::Base(ref b) => {
+            Err(format!("condition `{}` that is not a comparison", b))
+        }
+    }
+}
+
+fn check_join_condition(ce: &ConditionExpression) -> Result<(), String> {
+    match *ce {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            ref left,
+            ref right,
+        }) => match (&**left, &**right) {
+            (
+                ConditionExpression::Base(ConditionBase::Field(_)),
+                ConditionExpression::Base(ConditionBase::Field(_)),
+            ) => Ok(()),
+            _ => Err(format!(
+                "join condition `{}` that doesn't compare two columns",
+                ce
+            )),
+        },
+        _ => Err(format!(
+            "join condition `{}` (only a single equality between columns is supported)",
+            ce
+        )),
+    }
+}
+
+impl CheckSupported for SelectStatement {
+    fn check_supported(&self) -> Result<(), String> {
+        if self.tables.is_empty() {
+            return Err("SELECT without a FROM clause".into());
+        }
+        if self.distinct {
+            return Err("SELECT DISTINCT".into());
+        }
+
+        for field in &self.fields {
+            match *field {
+                FieldDefinitionExpression::Col(ref c) => check_column(c)?,
+                FieldDefinitionExpression::Value(FieldValueExpression::Arithmetic(ref ae)) => {
+                    for base in &[&ae.left, &ae.right] {
+                        if let ArithmeticBase::Column(ref c) = **base {
+                            check_column(c)?;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for jc in &self.join {
+            match jc.operator {
+                JoinOperator::Join | JoinOperator::InnerJoin | JoinOperator::LeftJoin => (),
+                ref op => return Err(op.to_string()),
+            }
+            match jc.right {
+                JoinRightSide::Table(_) => (),
+                JoinRightSide::NestedSelect(ref sq, _) => sq.check_supported()?,
+                JoinRightSide::Tables(_) => {
+                    return Err("joins against a comma-separated list of tables".into())
+                }
+                JoinRightSide::NestedJoin(_) => return Err("nested joins".into()),
+            }
+            match jc.constraint {
+                JoinConstraint::On(ref ce) => check_join_condition(ce)?,
+                JoinConstraint::Using(ref cols) if cols.len() != 1 => {
+                    return Err("USING with more than one column".into())
+                }
+                JoinConstraint::Using(_) => (),
+            }
+        }
+
+        if let Some(ref ce) = self.where_clause {
+            check_condition(ce)?;
+        }
+
+        if let Some(ref gb) = self.group_by {
+            if gb.having.is_some() {
+                return Err("HAVING clauses".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CheckSupported for CompoundSelectStatement {
+    fn check_supported(&self) -> Result<(), String> {
+        for &(ref op, ref sq) in &self.selects {
+            match *op {
+                None
+                | Some(CompoundSelectOperator::Union)
+                | Some(CompoundSelectOperator::DistinctUnion) => (),
+                Some(ref op) => return Err(op.to_string()),
+            }
+            sq.check_supported()?;
+        }
+        Ok(())
+    }
+}
+
+impl CheckSupported for SqlQuery {
+    fn check_supported(&self) -> Result<(), String> {
+        match *self {
+            SqlQuery::CreateTable(_) => Ok(()),
+            SqlQuery::CreateView(ref cvq) => match *cvq.definition {
+                SelectSpecification::Simple(ref sq) => sq.check_supported(),
+                SelectSpecification::Compound(ref csq) => csq.check_supported(),
+            },
+            SqlQuery::Select(ref sq) => sq.check_supported(),
+            SqlQuery::CompoundSelect(ref csq) => csq.check_supported(),
+            ref q => Err(unsupported_statement(q)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CheckSupported;
+    use nom_sql::parse_query;
+
+    fn check(q: &str) -> Result<(), String> {
+        parse_query(q).unwrap().check_supported()
+    }
+
+    #[test]
+    fn it_accepts_supported_queries() {
+        assert_eq!(check("SELECT a.x FROM a WHERE a.y = ?;"), Ok(()));
+        assert_eq!(
+            check("SELECT a.x, b.z FROM a JOIN b ON (a.y = b.y) WHERE a.x IN (1, 2);"),
+            Ok(())
+        );
+        assert_eq!(
+            check("SELECT a.x, COUNT(a.y) FROM a WHERE a.x > 10 OR a.x < 2 GROUP BY a.x;"),
+            Ok(())
+        );
+        assert_eq!(
+            check("SELECT a.x FROM a WHERE a.y IN (SELECT b.y FROM b);"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn it_names_unsupported_constructs() {
+        assert!(check("SELECT a.x FROM a WHERE a.x + 1 = 2;")
+            .unwrap_err()
+            .ends_with("whose left-hand side is not a column"));
+        assert_eq!(
+            check("SELECT DISTINCT a.x FROM a;"),
+            Err("SELECT DISTINCT".into())
+        );
+        assert_eq!(
+            check("SELECT a.x FROM a CROSS JOIN b ON (a.y = b.y);"),
+            Err("CROSS JOIN".into())
+        );
+        assert_eq!(
+            check("SELECT a.x FROM a WHERE a.x = 1 INTERSECT SELECT b.x FROM b;"),
+            Err("INTERSECT".into())
+        );
+        assert_eq!(
+            check("INSERT INTO a (x) VALUES (1);"),
+            Err("INSERT statements in a recipe".into())
+        );
+        assert!(check("SELECT AVG(a.x) AS ax FROM a;").is_err());
+        assert!(check("SELECT a.x FROM a JOIN b ON (a.y < b.y);")
+            .unwrap_err()
+            .starts_with("join condition"));
+        assert_eq!(
+            check("SELECT a.x, COUNT(a.y) AS c FROM a GROUP BY a.x HAVING c > 1;"),
+            Err("HAVING clauses".into())
+        );
+        assert!(
+            check("SELECT SUM(CASE WHEN a.x = 1 OR a.y = 2 THEN a.z ELSE 0 END) AS s FROM a;")
+                .unwrap_err()
+                .starts_with("CASE WHEN condition")
+        );
+    }
+}
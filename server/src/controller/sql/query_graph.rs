@@ -5,6 +5,7 @@ use nom_sql::{
     JoinRightSide, Literal, Operator, Table,
 };
 
+use super::SqlError;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
@@ -256,7 +257,7 @@ fn classify_conditionals(
     join: &mut Vec<ConditionTree>,
     global: &mut Vec<ConditionExpression>,
     params: &mut Vec<Column>,
-) {
+) -> Result<(), SqlError> {
     // Handling OR and AND expressions requires some care as there are some corner cases.
     //    a) we don't support OR expressions with predicates with placeholder parameters,
     //       because these expressions are meaningless in the Soup context.
@@ -287,7 +288,7 @@ fn classify_conditionals(
                 &mut new_join,
                 &mut new_global,
                 &mut new_params,
-            );
+            )?;
             classify_conditionals(
                 ct.right.as_ref(),
                 tables,
@@ -295,7 +296,7 @@ fn classify_conditionals(
                 &mut new_join,
                 &mut new_global,
                 &mut new_params,
-            );
+            )?;
 
             match ct.operator {
                 Operator::And => {
@@ -326,14 +327,18 @@ fn classify_conditionals(
                     global.extend(new_global);
                 }
                 Operator::Or => {
-                    assert!(
-                        new_join.is_empty(),
-                        "can't handle OR expressions between join predicates"
-                    );
-                    assert!(
-                        new_params.is_empty(),
-                        "can't handle OR expressions between query parameter predicates"
-                    );
+                    if !new_join.is_empty() {
+                        return Err(SqlError::Unsupported(format!(
+                            "OR between join predicates in `{}`",
+                            ce
+                        )));
+                    }
+                    if !new_params.is_empty() {
+                        return Err(SqlError::Unsupported(format!(
+                            "OR between query parameters in `{}`",
+                            ce
+                        )));
+                    }
                     if new_local.keys().len() == 1 && new_global.is_empty() {
                         // OR over a single table => local predicate
                         let (t, ces) = new_local.into_iter().next().unwrap();
@@ -387,8 +392,10 @@ fn classify_conditionals(
                                         }
                                         join.push(join_ct);
                                     } else {
-                                        // non-equi-join?
-                                        unimplemented!();
+                                        return Err(SqlError::Unsupported(format!(
+                                            "non-equality join condition `{}`",
+                                            ce
+                                        )));
                                     }
                                 } else {
                                     // not a comma join, just an ordinary comparison with a
@@ -398,7 +405,10 @@ fn classify_conditionals(
                                     global.push(ce.clone());
                                 }
                             } else {
-                                panic!("left hand side of comparison must be field");
+                                return Err(SqlError::Unsupported(format!(
+                                    "comparison `{}` whose left-hand side is not a column",
+                                    ce
+                                )));
                             }
                         }
                         // right-hand side is a placeholder, so this must be a query parameter
@@ -423,7 +433,9 @@ fn classify_conditionals(
                             }
                         }
                         ConditionBase::LiteralList(_) => (),
-                        ConditionBase::NestedSelect(_) => unimplemented!(),
+                        ConditionBase::NestedSelect(_) => {
+                            return Err(SqlError::Unsupported(format!("nested SELECT in `{}`", ce)))
+                        }
                     }
                 };
             };
@@ -439,68 +451,90 @@ fn classify_conditionals(
                 &mut new_join,
                 global,
                 &mut new_params,
-            );
+            )?;
+            for (t, ces) in new_local {
+                local.entry(t).or_default().extend(ces);
+            }
             join.extend(new_join);
             params.extend(new_params);
         }
         ConditionExpression::Base(_) => {
-            // don't expect to see a base here: we ought to exit when classifying its
-            // parent selection predicate
-            panic!("encountered unexpected standalone base of condition expression");
+            // we ought to exit when classifying a base's parent selection predicate, so a base on
+            // its own is a condition that isn't a comparison
+            return Err(SqlError::Unsupported(format!(
+                "condition `{}` that is not a comparison",
+                ce
+            )));
         }
         ConditionExpression::NegationOp(_) => {
-            panic!("negation should have been removed earlier");
+            return Err(SqlError::Unsupported(format!(
+                "negated condition `{}` that could not be rewritten",
+                ce
+            )));
+        }
+        ConditionExpression::Arithmetic(_) => {
+            return Err(SqlError::Unsupported(format!(
+                "arithmetic expression `{}` in a condition",
+                ce
+            )));
         }
-        ConditionExpression::Arithmetic(_) => unimplemented!(),
     }
+
+    Ok(())
 }
 
 #[allow(clippy::cognitive_complexity)]
-pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
+pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, SqlError> {
     let mut qg = QueryGraph::new();
 
     // a handy closure for making new relation nodes
-    let new_node =
-        |rel: String, preds: Vec<ConditionExpression>, st: &SelectStatement| -> QueryGraphNode {
-            QueryGraphNode {
-                rel_name: rel.clone(),
-                predicates: preds,
-                columns: st
-                    .fields
-                    .iter()
-                    .filter_map(|field| match *field {
-                        // unreachable because SQL rewrite passes will have expanded these already
-                        FieldDefinitionExpression::All => unreachable!(),
-                        FieldDefinitionExpression::AllInTable(_) => unreachable!(),
-                        // No need to do anything for literals and arithmetic expressions here, as they
-                        // aren't associated with a relation (and thus have no QGN)
-                        FieldDefinitionExpression::Value(_) => None,
-                        FieldDefinitionExpression::Col(ref c) => {
-                            match c.table.as_ref() {
-                                None => {
-                                    match c.function {
-                                        // XXX(malte): don't drop aggregation columns
-                                        Some(_) => None,
-                                        None => panic!(
-                                            "No table name set for column {} on {}",
-                                            c.name, rel
-                                        ),
-                                    }
+    let new_node = |rel: String,
+                    preds: Vec<ConditionExpression>,
+                    st: &SelectStatement|
+     -> Result<QueryGraphNode, SqlError> {
+        Ok(QueryGraphNode {
+            rel_name: rel.clone(),
+            predicates: preds,
+            columns: st
+                .fields
+                .iter()
+                .filter_map(|field| match *field {
+                    // SQL rewrite passes should have expanded these already
+                    FieldDefinitionExpression::All | FieldDefinitionExpression::AllInTable(_) => {
+                        Some(Err(SqlError::Unsupported(format!(
+                            "`{}` that could not be expanded",
+                            field
+                        ))))
+                    }
+                    // No need to do anything for literals and arithmetic expressions here, as they
+                    // aren't associated with a relation (and thus have no QGN)
+                    FieldDefinitionExpression::Value(_) => None,
+                    FieldDefinitionExpression::Col(ref c) => {
+                        match c.table.as_ref() {
+                            None => {
+                                match c.function {
+                                    // XXX(malte): don't drop aggregation columns
+                                    Some(_) => None,
+                                    None => Some(Err(SqlError::Invalid(format!(
+                                        "no table name set for column {} on {}",
+                                        c.name, rel
+                                    )))),
                                 }
-                                Some(t) => {
-                                    if *t == rel {
-                                        Some(c.clone())
-                                    } else {
-                                        None
-                                    }
+                            }
+                            Some(t) => {
+                                if *t == rel {
+                                    Some(Ok(c.clone()))
+                                } else {
+                                    None
                                 }
                             }
                         }
-                    })
-                    .collect(),
-                parameters: Vec::new(),
-            }
-        };
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            parameters: Vec::new(),
+        })
+    };
 
     // 1. Add any relations mentioned in the query to the query graph.
     // This is needed so that we don't end up with an empty query graph when there are no
@@ -508,7 +542,7 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
     for table in &st.tables {
         qg.relations.insert(
             table.name.clone(),
-            new_node(table.name.clone(), Vec::new(), st),
+            new_node(table.name.clone(), Vec::new(), st)?,
         );
     }
     for jc in &st.join {
@@ -517,11 +551,16 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                 if !qg.relations.contains_key(&table.name) {
                     qg.relations.insert(
                         table.name.clone(),
-                        new_node(table.name.clone(), Vec::new(), st),
+                        new_node(table.name.clone(), Vec::new(), st)?,
                     );
                 }
             }
-            _ => unimplemented!(),
+            ref rhs => {
+                return Err(SqlError::Unsupported(format!(
+                    "joining with `{}` (only tables can be joined)",
+                    rhs
+                )))
+            }
         }
     }
 
@@ -560,7 +599,13 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                                     if tables_mentioned[1] != table.name {
                                         // tables are in the wrong order in join predicate, swap
                                         tables_mentioned.swap(0, 1);
-                                        assert_eq!(tables_mentioned[1], table.name);
+                                        if tables_mentioned[1] != table.name {
+                                            return Err(SqlError::Invalid(format!(
+                                                "join condition `{}` does not mention joined \
+                                                 table \"{}\"",
+                                                cond, table.name
+                                            )));
+                                        }
                                     }
                                     left_table = tables_mentioned.remove(0);
                                     right_table = tables_mentioned.remove(0);
@@ -569,20 +614,28 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                                     left_table = tables_mentioned.remove(0);
                                     right_table = left_table.clone();
                                 } else {
-                                    unreachable!("more than 2 tables mentioned in join condition!");
+                                    return Err(SqlError::Unsupported(format!(
+                                        "join condition `{}` that doesn't mention exactly two \
+                                         tables",
+                                        cond
+                                    )));
                                 };
 
                                 // the condition tree might specify tables in opposite order to
                                 // their join order in the query; if so, flip them
                                 // TODO(malte): this only deals with simple, flat join
                                 // conditions for now.
-                                let l = match *ct.left.as_ref() {
-                                    ConditionExpression::Base(ConditionBase::Field(ref f)) => f,
-                                    _ => unimplemented!(),
-                                };
-                                let r = match *ct.right.as_ref() {
-                                    ConditionExpression::Base(ConditionBase::Field(ref f)) => f,
-                                    _ => unimplemented!(),
+                                let (l, r) = match (ct.left.as_ref(), ct.right.as_ref()) {
+                                    (
+                                        ConditionExpression::Base(ConditionBase::Field(ref l)),
+                                        ConditionExpression::Base(ConditionBase::Field(ref r)),
+                                    ) => (l, r),
+                                    _ => {
+                                        return Err(SqlError::Unsupported(format!(
+                                            "join condition `{}` that doesn't compare two columns",
+                                            cond
+                                        )))
+                                    }
                                 };
                                 if *l.table.as_ref().unwrap() == right_table
                                     && *r.table.as_ref().unwrap() == left_table
@@ -596,11 +649,20 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                                     ct.clone()
                                 }
                             }
-                            _ => panic!("join condition is not a comparison!"),
+                            _ => {
+                                return Err(SqlError::Unsupported(format!(
+                                    "join condition `{}` that is not a comparison",
+                                    cond
+                                )))
+                            }
                         }
                     }
                     JoinConstraint::Using(ref cols) => {
-                        assert_eq!(cols.len(), 1);
+                        if cols.len() != 1 {
+                            return Err(SqlError::Unsupported(
+                                "USING with more than one column".to_owned(),
+                            ));
+                        }
                         let col = cols.iter().next().unwrap();
 
                         left_table = prev_table.as_ref().unwrap().clone();
@@ -614,19 +676,21 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                     }
                 };
 
+                let edge = match jc.operator {
+                    JoinOperator::LeftJoin => QueryGraphEdge::LeftJoin(vec![join_pred]),
+                    JoinOperator::Join | JoinOperator::InnerJoin => {
+                        QueryGraphEdge::Join(vec![join_pred])
+                    }
+                    ref op => return Err(SqlError::Unsupported(op.to_string())),
+                };
+
                 // add edge for join
-                let mut _e = qg
-                    .edges
+                qg.edges
                     .entry((left_table.clone(), right_table.clone()))
-                    .or_insert_with(|| match jc.operator {
-                        JoinOperator::LeftJoin => QueryGraphEdge::LeftJoin(vec![join_pred]),
-                        JoinOperator::Join | JoinOperator::InnerJoin => {
-                            QueryGraphEdge::Join(vec![join_pred])
-                        }
-                        _ => unimplemented!(),
-                    });
+                    .or_insert(edge);
             }
-            _ => unimplemented!(),
+            // we've already bailed out above if this isn't a table
+            _ => unreachable!(),
         }
     }
 
@@ -642,7 +706,7 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
             &mut join_predicates,
             &mut global_predicates,
             &mut query_parameters,
-        );
+        )?;
 
         for (_, ces) in local_predicates.iter_mut() {
             *ces = split_conjunctions(ces.clone());
@@ -653,10 +717,10 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
             if !qg.relations.contains_key(&rel) {
                 // can't have predicates on tables that do not appear in the FROM part of the
                 // statement
-                panic!(
-                    "predicate(s) {:?} on relation {} that is not in query graph",
-                    preds, rel
-                );
+                return Err(SqlError::Invalid(format!(
+                    "predicate on table \"{}\", which the query does not select from",
+                    rel
+                )));
             } else {
                 qg.relations.get_mut(&rel).unwrap().predicates.extend(preds);
             }
@@ -668,13 +732,12 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
             if let ConditionExpression::Base(ConditionBase::Field(ref l)) = *jp.left.as_ref() {
                 if let ConditionExpression::Base(ConditionBase::Field(ref r)) = *jp.right.as_ref() {
                     // If tables aren't already in the relations, add them.
-                    qg.relations
-                        .entry(l.table.clone().unwrap())
-                        .or_insert_with(|| new_node(l.table.clone().unwrap(), Vec::new(), st));
-
-                    qg.relations
-                        .entry(r.table.clone().unwrap())
-                        .or_insert_with(|| new_node(r.table.clone().unwrap(), Vec::new(), st));
+                    for t in &[l.table.as_ref().unwrap(), r.table.as_ref().unwrap()] {
+                        if !qg.relations.contains_key(*t) {
+                            let n = new_node((*t).clone(), Vec::new(), st)?;
+                            qg.relations.insert((*t).clone(), n);
+                        }
+                    }
 
                     let e = qg
                         .edges
@@ -682,7 +745,13 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                         .or_insert_with(|| QueryGraphEdge::Join(vec![]));
                     match *e {
                         QueryGraphEdge::Join(ref mut preds) => preds.push(jp.clone()),
-                        _ => panic!("Expected join edge for join condition {:#?}", jp),
+                        _ => {
+                            return Err(SqlError::Unsupported(format!(
+                                "comma join condition `{}` between tables that are also \
+                                 LEFT JOINed",
+                                jp
+                            )))
+                        }
                     };
                 }
             }
@@ -694,9 +763,19 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
         //    parameters might be evaluated sooner).
         for column in query_parameters.into_iter() {
             match column.table {
-                None => panic!("each parameter's column must have an associated table!"),
+                None => {
+                    return Err(SqlError::Invalid(format!(
+                        "parameter column \"{}\" does not belong to any table",
+                        column.name
+                    )))
+                }
                 Some(ref table) => {
-                    let rel = qg.relations.get_mut(table).unwrap();
+                    let rel = qg.relations.get_mut(table).ok_or_else(|| {
+                        SqlError::Invalid(format!(
+                            "parameter on table \"{}\", which the query does not select from",
+                            table
+                        ))
+                    })?;
                     if !rel.columns.contains(&column) {
                         rel.columns.push(column.clone());
                     }
@@ -719,14 +798,20 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
             Some(_) => {
                 // add a special node representing the computed columns; if it already
                 // exists, add another computed column to it
-                let n = query_graph
+                let rel = String::from("computed_columns");
+                if !query_graph.relations.contains_key(&rel) {
+                    let n = new_node(rel.clone(), vec![], st)?;
+                    query_graph.relations.insert(rel.clone(), n);
+                }
+                query_graph
                     .relations
-                    .entry(String::from("computed_columns"))
-                    .or_insert_with(|| new_node(String::from("computed_columns"), vec![], st));
-
-                n.columns.push(column.clone());
+                    .get_mut(&rel)
+                    .unwrap()
+                    .columns
+                    .push(column.clone());
             }
         }
+        Ok::<_, SqlError>(())
     };
 
     // 4. Add query graph nodes for any computed columns, which won't be represented in the
@@ -734,7 +819,10 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
     for field in st.fields.iter() {
         match *field {
             FieldDefinitionExpression::All | FieldDefinitionExpression::AllInTable(_) => {
-                panic!("Stars should have been expanded by now!")
+                return Err(SqlError::Unsupported(format!(
+                    "`{}` that could not be expanded",
+                    field
+                )))
             }
             FieldDefinitionExpression::Value(FieldValueExpression::Literal(ref l)) => {
                 qg.columns.push(OutputColumn::Literal(LiteralColumn {
@@ -748,11 +836,11 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
             }
             FieldDefinitionExpression::Value(FieldValueExpression::Arithmetic(ref a)) => {
                 if let ArithmeticBase::Column(ref c) = a.left {
-                    add_computed_column(&mut qg, c);
+                    add_computed_column(&mut qg, c)?;
                 }
 
                 if let ArithmeticBase::Column(ref c) = a.right {
-                    add_computed_column(&mut qg, c);
+                    add_computed_column(&mut qg, c)?;
                 }
 
                 qg.columns.push(OutputColumn::Arithmetic(ArithmeticColumn {
@@ -762,7 +850,7 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                 }));
             }
             FieldDefinitionExpression::Col(ref c) => {
-                add_computed_column(&mut qg, c);
+                add_computed_column(&mut qg, c)?;
                 qg.columns.push(OutputColumn::Data(c.clone()));
             }
         }
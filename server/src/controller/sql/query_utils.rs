@@ -1,4 +1,4 @@
-use nom_sql::{ConditionBase, ConditionExpression, SqlQuery, Table};
+use nom_sql::{ArithmeticBase, Column, ConditionBase, ConditionExpression, SqlQuery, Table};
 
 pub trait ReferredTables {
     fn referred_tables(&self) -> Vec<Table>;
@@ -47,7 +47,25 @@ impl ReferredTables for ConditionExpression {
                     }
                 }
             }
-            _ => unimplemented!(),
+            ConditionExpression::Bracketed(ref inner)
+            | ConditionExpression::NegationOp(ref inner) => {
+                tables = inner.referred_tables();
+            }
+            ConditionExpression::Arithmetic(ref ae) => {
+                for base in &[&ae.left, &ae.right] {
+                    if let ArithmeticBase::Column(Column {
+                        table: Some(ref t), ..
+                    }) = **base
+                    {
+                        let t = Table::from(t.as_ref());
+                        if !tables.contains(&t) {
+                            tables.push(t);
+                        }
+                    }
+                }
+            }
+            // literals and nested selections don't refer to any of the query's tables
+            ConditionExpression::Base(_) => {}
         }
         tables
     }
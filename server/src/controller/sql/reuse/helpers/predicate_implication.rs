@@ -7,37 +7,37 @@ fn direct_elimination(op1: &Operator, op2: &Operator) -> Option<Operator> {
             Operator::Equal => Some(Operator::Equal),
             Operator::Less => Some(Operator::Less),
             Operator::Greater => Some(Operator::Greater),
-            _ => unimplemented!(),
+            _ => None,
         },
         Operator::NotEqual => match *op2 {
             Operator::Equal => Some(Operator::NotEqual),
             Operator::Less => None,
             Operator::Greater => None,
-            _ => unimplemented!(),
+            _ => None,
         },
         Operator::Less => match *op2 {
             Operator::Equal => Some(Operator::Less),
             Operator::Less => Some(Operator::Less),
             Operator::Greater => None,
-            _ => unimplemented!(),
+            _ => None,
         },
         Operator::LessOrEqual => match *op2 {
             Operator::Equal => Some(Operator::LessOrEqual),
             Operator::Less => Some(Operator::LessOrEqual),
             Operator::Greater => None,
-            _ => unimplemented!(),
+            _ => None,
         },
         Operator::Greater => match *op2 {
             Operator::Equal => Some(Operator::Greater),
             Operator::Less => None,
             Operator::Greater => Some(Operator::Greater),
-            _ => unimplemented!(),
+            _ => None,
         },
         Operator::GreaterOrEqual => match *op2 {
             Operator::Equal => Some(Operator::GreaterOrEqual),
             Operator::Less => None,
             Operator::Greater => Some(Operator::Greater),
-            _ => unimplemented!(),
+            _ => None,
         },
         _ => None,
     }
@@ -69,29 +69,31 @@ where
     }
 }
 
+/// Do two join predicates join on the same pair of columns?
+///
+/// Predicates that aren't between two columns are never considered equivalent.
 pub fn predicate_is_equivalent(np: &ConditionTree, ep: &ConditionTree) -> bool {
-    let nl_col = match *np.left {
-        ConditionExpression::Base(ConditionBase::Field(ref f)) => f.clone(),
-        _ => unimplemented!(),
-    };
-    let nr_col = match *np.right {
-        ConditionExpression::Base(ConditionBase::Field(ref f)) => f.clone(),
-        _ => unimplemented!(),
-    };
-
-    let el_col = match *ep.left {
-        ConditionExpression::Base(ConditionBase::Field(ref f)) => f.clone(),
-        _ => unimplemented!(),
-    };
-    let er_col = match *ep.right {
-        ConditionExpression::Base(ConditionBase::Field(ref f)) => f.clone(),
-        _ => unimplemented!(),
+    let column = |ce: &ConditionExpression| match *ce {
+        ConditionExpression::Base(ConditionBase::Field(ref f)) => Some(f.clone()),
+        _ => None,
     };
-
-    (nl_col == el_col && nr_col == er_col) || (nl_col == er_col && nr_col == el_col)
+    match (
+        column(&np.left),
+        column(&np.right),
+        column(&ep.left),
+        column(&ep.right),
+    ) {
+        (Some(nl_col), Some(nr_col), Some(el_col), Some(er_col)) => {
+            (nl_col == el_col && nr_col == er_col) || (nl_col == er_col && nr_col == el_col)
+        }
+        _ => false,
+    }
 }
 
 /// Direct elimination for complex predicates with nested `and` and `or` expressions
+///
+/// Predicates of a shape we can't reason about are conservatively assumed not to imply each
+/// other, which just means that the existing query won't be reused.
 pub fn complex_predicate_implies(np: &ConditionExpression, ep: &ConditionExpression) -> bool {
     match *ep {
         LogicalOp(ref ect) => {
@@ -113,7 +115,7 @@ pub fn complex_predicate_implies(np: &ConditionExpression, ep: &ConditionExpress
                     complex_predicate_implies(np, &*ect.left)
                         || complex_predicate_implies(np, &*ect.right)
                 }
-                _ => unreachable!(),
+                _ => false,
             }
         }
         ComparisonOp(ref ect) => match *np {
@@ -126,12 +128,12 @@ pub fn complex_predicate_implies(np: &ConditionExpression, ep: &ConditionExpress
                     complex_predicate_implies(&*nct.left, ep)
                         && complex_predicate_implies(&*nct.right, ep)
                 }
-                _ => unreachable!(),
+                _ => false,
             },
             ComparisonOp(ref nct) => nct.left == ect.left && predicate_implies(nct, ect),
-            _ => unreachable!(),
+            _ => false,
         },
-        _ => unreachable!(),
+        _ => false,
     }
 }
 
@@ -145,7 +147,8 @@ fn predicate_implies(np: &ConditionTree, ep: &ConditionTree) -> bool {
                     check_op_elimination(nv, ev, &np.operator, &ep.operator)
                 }
                 ConditionExpression::Base(ConditionBase::Literal(_)) => false,
-                _ => panic!("right-hand side of predicate must currently be literal"),
+                // right-hand side of predicate must currently be literal
+                _ => false,
             }
         }
         ConditionExpression::Base(ConditionBase::Literal(Literal::Integer(ref nv))) => {
@@ -154,15 +157,16 @@ fn predicate_implies(np: &ConditionTree, ep: &ConditionTree) -> bool {
                     check_op_elimination(nv, ev, &np.operator, &ep.operator)
                 }
                 ConditionExpression::Base(ConditionBase::Literal(_)) => false,
-                _ => panic!("right-hand side of predicate must currently be literal"),
+                // right-hand side of predicate must currently be literal
+                _ => false,
             }
         }
         ConditionExpression::Base(ConditionBase::Literal(Literal::Null)) => match *ep.right {
             ConditionExpression::Base(ConditionBase::Literal(Literal::Null)) => true,
             ConditionExpression::Base(ConditionBase::Literal(_)) => false,
-            _ => panic!("right-hand side of predicate must currently be literal"),
+            _ => false,
         },
-        _ => panic!("right-hand side of predicate must currently be literal"),
+        _ => false,
     }
 }
 
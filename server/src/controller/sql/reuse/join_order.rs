@@ -1,8 +1,9 @@
 use super::super::query_graph::{JoinRef, QueryGraph, QueryGraphEdge};
 use super::helpers::predicate_implication::predicate_is_equivalent;
 use super::ReuseType;
-use nom_sql::ConditionTree;
-use std::collections::HashSet;
+use nom_sql::{ConditionBase, ConditionExpression, ConditionTree};
+use noria::TableStatistics;
+use std::collections::{HashMap, HashSet};
 use std::vec::Vec;
 
 #[derive(Debug, Clone)]
@@ -176,3 +177,184 @@ pub(super) fn reorder_joins(
 
     chains_to_order(join_chains, &mut qg.join_order);
 }
+
+/// Estimate how many rows joining the relation groups on either side of `jref` would produce.
+///
+/// Joining `R` and `S` on `R.a = S.b` is estimated to produce `|R| * |S| / max(V(a), V(b))` rows,
+/// where `V` is the number of distinct values in a column, or the number of rows in its table if
+/// that isn't known.
+fn estimate_join(
+    jref: &JoinRef,
+    qg: &QueryGraph,
+    tables: &HashMap<String, TableStatistics>,
+    groups: &HashMap<String, usize>,
+    sizes: &[f64],
+) -> f64 {
+    let (left, right) = (groups[&jref.src], groups[&jref.dst]);
+    if left == right {
+        // both sides have been joined already, so this can only filter
+        return sizes[left];
+    }
+
+    let distinct = |ce: &ConditionExpression, group: usize| {
+        let v = match *ce {
+            ConditionExpression::Base(ConditionBase::Field(ref c)) => c
+                .table
+                .as_ref()
+                .and_then(|t| tables.get(t))
+                .map(|t| {
+                    t.columns
+                        .get(&c.name)
+                        .map(|cs| cs.distinct_values)
+                        .unwrap_or(t.rows) as f64
+                })
+                .unwrap_or(sizes[group]),
+            _ => sizes[group],
+        };
+        // a join can't have more distinct values in a column than it has rows
+        v.min(sizes[group])
+    };
+
+    let jp = from_join_ref(jref, qg);
+    let v = distinct(&*jp.left, left)
+        .max(distinct(&*jp.right, right))
+        .max(1.0);
+    sizes[left] * sizes[right] / v
+}
+
+/// Order the joins in `qg` greedily, so that the join expected to produce the fewest rows is
+/// always done next, using cardinality statistics for the `tables` the query reads from.
+///
+/// The order is left alone if the query has outer joins, since those can't be freely reordered,
+/// or if it reads from a relation that there are no statistics for.
+pub(in crate::controller) fn order_joins_by_cardinality(
+    qg: &mut QueryGraph,
+    tables: &HashMap<String, TableStatistics>,
+) {
+    if qg.join_order.len() < 2 {
+        return;
+    }
+
+    let estimable = qg.join_order.iter().all(|jref| {
+        let edge = &qg.edges[&(jref.src.clone(), jref.dst.clone())];
+        matches!(edge, QueryGraphEdge::Join(_))
+            && tables.contains_key(&jref.src)
+            && tables.contains_key(&jref.dst)
+    });
+    if !estimable {
+        return;
+    }
+
+    // every relation starts out in a group of its own, and each join merges two groups
+    let mut groups = HashMap::new();
+    let mut sizes = Vec::new();
+    for jref in &qg.join_order {
+        for rel in &[&jref.src, &jref.dst] {
+            if !groups.contains_key(*rel) {
+                groups.insert((*rel).clone(), sizes.len());
+                sizes.push(tables[*rel].rows as f64);
+            }
+        }
+    }
+
+    let mut remaining = qg.join_order.clone();
+    let mut order = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        // ties go to the join that came first in the original order
+        let (next, size) = remaining
+            .iter()
+            .map(|jref| estimate_join(jref, qg, tables, &groups, &sizes))
+            .enumerate()
+            .fold(None, |best, (i, size)| match best {
+                Some((_, best_size)) if best_size <= size => best,
+                _ => Some((i, size)),
+            })
+            .unwrap();
+
+        let jref = remaining.remove(next);
+        let (left, right) = (groups[&jref.src], groups[&jref.dst]);
+        for group in groups.values_mut() {
+            if *group == right {
+                *group = left;
+            }
+        }
+        sizes[left] = size;
+        order.push(jref);
+    }
+
+    qg.join_order = order;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::sql::query_graph::to_query_graph;
+    use nom_sql::{parser, SqlQuery};
+    use noria::ColumnStatistics;
+
+    fn table(rows: u64, columns: &[(&str, u64)]) -> TableStatistics {
+        TableStatistics {
+            rows,
+            columns: columns
+                .iter()
+                .map(|&(name, distinct_values)| {
+                    (
+                        name.to_owned(),
+                        ColumnStatistics {
+                            distinct_values,
+                            max_rows_per_value: 1,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn query_graph(sql: &str) -> QueryGraph {
+        match parser::parse_query(sql).unwrap() {
+            SqlQuery::Select(ref st) => to_query_graph(st).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn order(qg: &QueryGraph) -> Vec<(&str, &str)> {
+        qg.join_order
+            .iter()
+            .map(|jref| (&*jref.src, &*jref.dst))
+            .collect()
+    }
+
+    #[test]
+    fn it_orders_joins_by_cardinality() {
+        let sql = "SELECT a.z, c.z FROM a JOIN b ON (a.x = b.x) JOIN c ON (b.y = c.y);";
+        let mut tables = HashMap::new();
+        tables.insert("a".to_owned(), table(10, &[]));
+        tables.insert("b".to_owned(), table(1000, &[("x", 1000), ("y", 10)]));
+        tables.insert("c".to_owned(), table(1000, &[("y", 10)]));
+
+        // b ⋈ c is estimated at 1000 * 1000 / 10 rows, while a ⋈ b is only 10 * 1000 / 1000
+        let mut qg = query_graph(sql);
+        assert_eq!(order(&qg), vec![("b", "c"), ("a", "b")]);
+        order_joins_by_cardinality(&mut qg, &tables);
+        assert_eq!(order(&qg), vec![("a", "b"), ("b", "c")]);
+
+        // without statistics for every table, the order is left alone
+        tables.remove("c");
+        let mut qg = query_graph(sql);
+        order_joins_by_cardinality(&mut qg, &tables);
+        assert_eq!(order(&qg), vec![("b", "c"), ("a", "b")]);
+    }
+
+    #[test]
+    fn it_does_not_reorder_outer_joins() {
+        let mut tables = HashMap::new();
+        tables.insert("a".to_owned(), table(1, &[]));
+        tables.insert("b".to_owned(), table(1000, &[]));
+        tables.insert("c".to_owned(), table(1000, &[]));
+
+        let mut qg =
+            query_graph("SELECT a.z FROM a LEFT JOIN b ON (a.x = b.x) JOIN c ON (b.y = c.y);");
+        order_joins_by_cardinality(&mut qg, &tables);
+        assert_eq!(order(&qg), vec![("b", "c"), ("a", "b")]);
+    }
+}
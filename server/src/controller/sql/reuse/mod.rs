@@ -13,6 +13,8 @@ mod helpers;
 mod join_order;
 mod relaxed;
 
+pub(in crate::controller) use self::join_order::order_joins_by_cardinality;
+
 #[derive(Clone, Debug)]
 pub(in crate::controller) enum ReuseType {
     DirectExtension,
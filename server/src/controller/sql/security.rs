@@ -1,10 +1,18 @@
+use crate::controller::security::policy::{Mask, Policy};
 use crate::controller::security::SecurityConfig;
+use crate::controller::sql::passes::subqueries::field_with_table_name;
 use crate::controller::sql::query_graph::{to_query_graph, QueryGraph};
-use crate::controller::sql::{QueryFlowParts, SqlIncorporator};
+use crate::controller::sql::query_utils::ReferredTables;
+use crate::controller::sql::{QueryFlowParts, SqlError, SqlIncorporator};
 use crate::controller::Migration;
+use dataflow::ops::filter::{FilterCondition, Value as FilterValue};
 use dataflow::prelude::DataType;
 use nom_sql::parser as sql_parser;
-use nom_sql::SqlQuery;
+use nom_sql::{
+    Column, ConditionBase, ConditionExpression, ConditionTree, FieldDefinitionExpression,
+    FunctionExpression, GroupByClause, JoinClause, JoinConstraint, JoinOperator, JoinRightSide,
+    Literal, Operator, SelectStatement, SqlQuery, Table,
+};
 use std::collections::HashMap;
 
 #[derive(Clone, Debug)]
@@ -14,6 +22,7 @@ pub(super) struct Universe {
     pub(super) member_of: HashMap<String, Vec<DataType>>,
     pub(super) row_policies: HashMap<String, Vec<QueryGraph>>,
     pub(super) rewrite_policies: HashMap<String, Vec<RewritePolicy>>,
+    pub(super) column_masks: HashMap<String, Vec<(String, Mask)>>,
 }
 
 impl Default for Universe {
@@ -24,10 +33,24 @@ impl Default for Universe {
             member_of: HashMap::default(),
             row_policies: HashMap::default(),
             rewrite_policies: HashMap::default(),
+            column_masks: HashMap::default(),
         }
     }
 }
 
+impl Universe {
+    /// Whether a masking policy hides the given column from this universe's views.
+    pub(super) fn hides(&self, column: &Column) -> bool {
+        let masks = match column.table.as_ref().and_then(|t| self.column_masks.get(t)) {
+            Some(masks) => masks,
+            None => return false,
+        };
+        masks
+            .iter()
+            .any(|&(ref c, ref mask)| *c == column.name && *mask == Mask::Hide)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(super) struct RewritePolicy {
     pub(super) value: String,
@@ -73,6 +96,7 @@ impl Multiverse for SqlIncorporator {
             member_of: universe_groups,
             row_policies: HashMap::new(),
             rewrite_policies: HashMap::new(),
+            column_masks: HashMap::new(),
         };
 
         // Create the UserContext base node.
@@ -95,6 +119,16 @@ impl Multiverse for SqlIncorporator {
             (uc_name, config.get_group_policies(group_name.to_string()))
         };
 
+        // policies refer to the context table by the name it has in every universe
+        let context_table = Table {
+            name: uc_name.clone(),
+            alias: Some(if group.is_none() {
+                "UserContext".to_owned()
+            } else {
+                "GroupContext".to_owned()
+            }),
+        };
+
         let base = self.add_base(uc_name.clone(), &mut fields, mig);
         qfps.push(base);
 
@@ -105,10 +139,45 @@ impl Multiverse for SqlIncorporator {
         // e.g. if they reference UserContext.
         let mut row_policies_qg: HashMap<String, Vec<QueryGraph>> = HashMap::new();
         for policy in universe_policies {
+            if let Policy::Mask(ref mp) = *policy {
+                // whether a mask applies only depends on the universe's context, so it is
+                // decided here rather than in the dataflow
+                let applies = match mp.predicate {
+                    SqlQuery::Select(SelectStatement {
+                        where_clause: Some(ref ce),
+                        ..
+                    }) => context_satisfies(ce, mig.context()),
+                    _ => Ok(true),
+                }
+                .map_err(|e| match mp.name.as_str() {
+                    "" => format!("mask policy on {}: {}", mp.table, e),
+                    name => format!("mask policy \"{}\" on {}: {}", name, mp.table, e),
+                })?;
+
+                if applies {
+                    trace!(
+                        self.log,
+                        "Masking {}.{} with {:?}",
+                        mp.table,
+                        mp.column,
+                        mp.mask
+                    );
+                    universe
+                        .column_masks
+                        .entry(mp.table.clone())
+                        .or_insert_with(Vec::new)
+                        .push((mp.column.clone(), mp.mask.clone()));
+                }
+                continue;
+            }
+
+            if let Policy::AllowWrite(_) = *policy {
+                // write policies are enforced by the bases, not by the universe's views
+                continue;
+            }
+
             if !policy.is_row_policy() {
-                let qfp = self
-                    .add_parsed_query(policy.predicate(), None, false, mig)
-                    .unwrap();
+                let qfp = self.add_parsed_query(policy.predicate(), None, false, mig)?;
                 let rewrite_view = qfp.name.clone();
                 let rw_pol = RewritePolicy {
                     value: policy.value(),
@@ -127,25 +196,17 @@ impl Multiverse for SqlIncorporator {
             }
 
             trace!(self.log, "Adding row policy {:?}", policy.name());
-            let predicate = self.rewrite_query(policy.predicate(), mig)?;
-            let st = match predicate {
-                SqlQuery::Select(ref st) => st,
-                _ => unreachable!(),
-            };
-
-            // TODO(larat): currently we only support policies with a single predicate. These can be
-            // represented as a query graph. This will change for more complex policies eg. column
-            // replacement and aggregation permission.
-
-            let qg = match to_query_graph(st) {
-                Ok(qg) => qg,
-                Err(e) => panic!(e),
-            };
+            let qgs =
+                self.plan_row_policy(policy, &context_table, mig)
+                    .map_err(|e| match policy.name().as_str() {
+                        "" => format!("row policy on {}: {}", policy.table(), e),
+                        name => format!("row policy \"{}\" on {}: {}", name, policy.table(), e),
+                    })?;
 
             let e = row_policies_qg
                 .entry(policy.table().clone())
                 .or_insert_with(Vec::new);
-            e.push(qg);
+            e.extend(qgs);
         }
 
         universe.row_policies = row_policies_qg;
@@ -185,3 +246,607 @@ impl Multiverse for SqlIncorporator {
             .unwrap()
     }
 }
+
+impl SqlIncorporator {
+    /// Turn a row policy's predicate into the query graphs its security nodes are built from.
+    ///
+    /// A predicate with disjunctions that need different joins is split into branches (see
+    /// `policy_branches`), each with its own query graph; their security paths are unioned.
+    fn plan_row_policy(
+        &mut self,
+        policy: &Policy,
+        context_table: &Table,
+        mig: &mut Migration,
+    ) -> Result<Vec<QueryGraph>, SqlError> {
+        let predicate = policy.predicate();
+        let branches = match predicate {
+            SqlQuery::Select(SelectStatement {
+                where_clause: Some(ref ce),
+                ..
+            }) => policy_branches(ce, &policy.table())?,
+            _ => vec![],
+        };
+        if branches.len() <= 1 {
+            return Ok(vec![self.plan_row_policy_branch(
+                predicate,
+                context_table,
+                mig,
+            )?]);
+        }
+
+        let st = match predicate {
+            SqlQuery::Select(st) => st,
+            _ => unreachable!(),
+        };
+        branches
+            .into_iter()
+            .map(|ce| {
+                let branch = SqlQuery::Select(SelectStatement {
+                    where_clause: Some(ce),
+                    ..st.clone()
+                });
+                self.plan_row_policy_branch(branch, context_table, mig)
+            })
+            .collect()
+    }
+
+    /// Turn a row policy predicate without disjunctions that need different joins into a query
+    /// graph.
+    ///
+    /// Tables the predicate refers to without selecting from them (like the context table) are
+    /// joined with the policy's table. `IN` and `NOT IN` subqueries become views that are joined
+    /// and anti-joined with it, respectively.
+    fn plan_row_policy_branch(
+        &mut self,
+        mut predicate: SqlQuery,
+        context_table: &Table,
+        mig: &mut Migration,
+    ) -> Result<QueryGraph, SqlError> {
+        if let SqlQuery::Select(ref mut st) = predicate {
+            if let Some(ce) = st.where_clause.take() {
+                let mut conditions = conjuncts(ce);
+                for ce in conditions.iter_mut() {
+                    let (column, sq, negated) = match in_subquery(ce) {
+                        Some(subquery) => subquery,
+                        None if has_subquery(ce) => {
+                            return Err(SqlError::Unsupported(format!(
+                                "nested SELECT in `{}` (only IN and NOT IN subqueries that every \
+                                 row must satisfy are supported)",
+                                ce
+                            )))
+                        }
+                        None => continue,
+                    };
+
+                    let (view, key) = self.add_subquery_view(sq, context_table, mig)?;
+                    let on = ConditionExpression::ComparisonOp(ConditionTree {
+                        operator: Operator::Equal,
+                        left: Box::new(ConditionExpression::Base(ConditionBase::Field(column))),
+                        right: Box::new(ConditionExpression::Base(field_with_table_name(
+                            view.clone(),
+                            key,
+                        ))),
+                    });
+
+                    if negated {
+                        // a left join with the view leaves the rows whose value the subquery
+                        // doesn't return without a match count
+                        st.join.push(JoinClause {
+                            operator: JoinOperator::LeftJoin,
+                            right: JoinRightSide::Table(Table::from(view.as_str())),
+                            constraint: JoinConstraint::On(on),
+                        });
+                        *ce = ConditionExpression::ComparisonOp(ConditionTree {
+                            operator: Operator::Equal,
+                            left: Box::new(ConditionExpression::Base(field_with_table_name(
+                                view,
+                                Column::from(SUBQUERY_MATCHES),
+                            ))),
+                            right: Box::new(ConditionExpression::Base(ConditionBase::Literal(
+                                Literal::Null,
+                            ))),
+                        });
+                    } else {
+                        st.tables.push(Table::from(view.as_str()));
+                        *ce = on;
+                    }
+                }
+                st.where_clause = conjoin(conditions);
+            }
+
+            join_referred_tables(st, context_table);
+        }
+
+        match self.rewrite_query(predicate, mig)? {
+            SqlQuery::Select(ref st) => to_query_graph(st),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Add a view for a policy's `IN` or `NOT IN` subquery, which has one row for each distinct
+    /// value the subquery returns, along with the number of times it returns it.
+    ///
+    /// Returns the name of the view and of the column that holds the subquery's values.
+    fn add_subquery_view(
+        &mut self,
+        mut sq: SelectStatement,
+        context_table: &Table,
+        mig: &mut Migration,
+    ) -> Result<(String, Column), SqlError> {
+        let key = match sq.fields.as_slice() {
+            [FieldDefinitionExpression::Col(ref c)] if c.function.is_none() => c.clone(),
+            _ => {
+                return Err(SqlError::Unsupported(format!(
+                    "subquery `{}` that doesn't select exactly one column",
+                    sq
+                )))
+            }
+        };
+        if sq.group_by.is_some() || sq.limit.is_some() {
+            return Err(SqlError::Unsupported(format!(
+                "subquery `{}` with GROUP BY or LIMIT",
+                sq
+            )));
+        }
+
+        sq.fields.push(FieldDefinitionExpression::Col(Column {
+            name: SUBQUERY_MATCHES.to_owned(),
+            alias: Some(SUBQUERY_MATCHES.to_owned()),
+            table: None,
+            function: Some(Box::new(FunctionExpression::CountStar)),
+        }));
+        sq.group_by = Some(GroupByClause {
+            columns: vec![key.clone()],
+            having: None,
+        });
+        join_referred_tables(&mut sq, context_table);
+
+        let qfp = self.add_parsed_query(SqlQuery::Select(sq), None, false, mig)?;
+        Ok((qfp.name, key))
+    }
+}
+
+/// The column of a policy subquery's view that counts how often the subquery returns a value.
+const SUBQUERY_MATCHES: &str = "matches";
+
+/// Splits a condition into the conditions that it is a conjunction of.
+fn conjuncts(ce: ConditionExpression) -> Vec<ConditionExpression> {
+    match ce {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            left,
+            right,
+        }) => {
+            let mut ces = conjuncts(*left);
+            ces.extend(conjuncts(*right));
+            ces
+        }
+        ConditionExpression::Bracketed(inner) => match *inner {
+            ce @ ConditionExpression::LogicalOp(ConditionTree {
+                operator: Operator::And,
+                ..
+            }) => conjuncts(ce),
+            ce => vec![ConditionExpression::Bracketed(Box::new(ce))],
+        },
+        ce => vec![ce],
+    }
+}
+
+/// The inverse of `conjuncts`.
+fn conjoin(ces: Vec<ConditionExpression>) -> Option<ConditionExpression> {
+    ces.into_iter().fold(None, |acc, ce| match acc {
+        None => Some(ce),
+        Some(acc) => Some(ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            left: Box::new(acc),
+            right: Box::new(ce),
+        })),
+    })
+}
+
+/// Splits a row policy predicate into the branches whose security paths are unioned to find the
+/// rows the policy admits.
+///
+/// Disjunctions that only compare columns of the policy's `table` stay in the filter that
+/// evaluates them; the others (those that refer to other tables or have subqueries) are split,
+/// since each side needs different joins. The union of the branches must not produce a row more
+/// than once, so at most one branch may need joins, and it only admits the rows that the other
+/// branch, which collects the local disjuncts, doesn't.
+///
+/// Returns a single branch if the predicate needn't be split.
+fn policy_branches(
+    ce: &ConditionExpression,
+    table: &str,
+) -> Result<Vec<ConditionExpression>, SqlError> {
+    let (local, mut joined): (Vec<_>, Vec<_>) = disjuncts(ce.clone(), table)
+        .into_iter()
+        .partition(|conditions| conditions.iter().all(|ce| is_local(ce, table)));
+    if joined.len() > 1 {
+        return Err(SqlError::Unsupported(format!(
+            "OR between several conditions that need joins or subqueries in `{}`",
+            ce
+        )));
+    }
+
+    let local = local
+        .into_iter()
+        .filter_map(conjoin)
+        .map(|ce| ConditionExpression::Bracketed(Box::new(ce)))
+        .fold(None, |acc, ce| match acc {
+            None => Some(ce),
+            Some(acc) => Some(ConditionExpression::LogicalOp(ConditionTree {
+                operator: Operator::Or,
+                left: Box::new(acc),
+                right: Box::new(ce),
+            })),
+        });
+    match (local, joined.pop()) {
+        (Some(local), Some(mut conditions)) => {
+            conditions.push(ConditionExpression::Bracketed(Box::new(negate(&local)?)));
+            Ok(vec![local, conjoin(conditions).unwrap()])
+        }
+        _ => Ok(vec![ce.clone()]),
+    }
+}
+
+/// Splits a condition into alternatives, each a conjunction of conditions, leaving disjunctions
+/// that are local to `table` in place.
+fn disjuncts(ce: ConditionExpression, table: &str) -> Vec<Vec<ConditionExpression>> {
+    if is_local(&ce, table) {
+        return vec![vec![ce]];
+    }
+
+    match ce {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            left,
+            right,
+        }) => {
+            let right = disjuncts(*right, table);
+            disjuncts(*left, table)
+                .into_iter()
+                .flat_map(|l| {
+                    right
+                        .iter()
+                        .map(move |r| l.iter().chain(r).cloned().collect())
+                })
+                .collect()
+        }
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::Or,
+            left,
+            right,
+        }) => {
+            let mut alternatives = disjuncts(*left, table);
+            alternatives.extend(disjuncts(*right, table));
+            alternatives
+        }
+        ConditionExpression::Bracketed(inner) => match *inner {
+            ce @ ConditionExpression::LogicalOp(_) => disjuncts(ce, table),
+            ce => vec![vec![ConditionExpression::Bracketed(Box::new(ce))]],
+        },
+        ce => vec![vec![ce]],
+    }
+}
+
+/// Whether `ce` only refers to columns of `table`, and so can be evaluated by a filter on it.
+fn is_local(ce: &ConditionExpression, table: &str) -> bool {
+    !has_subquery(ce) && ce.referred_tables().iter().all(|t| t.name == table)
+}
+
+/// The negation of a policy condition, without `NOT`.
+fn negate(ce: &ConditionExpression) -> Result<ConditionExpression, SqlError> {
+    match *ce {
+        ConditionExpression::LogicalOp(ref ct) => {
+            Ok(ConditionExpression::LogicalOp(ConditionTree {
+                operator: match ct.operator {
+                    Operator::And => Operator::Or,
+                    _ => Operator::And,
+                },
+                left: Box::new(negate(&ct.left)?),
+                right: Box::new(negate(&ct.right)?),
+            }))
+        }
+        ConditionExpression::ComparisonOp(ref ct) => {
+            let operator = match ct.operator {
+                Operator::Equal => Operator::NotEqual,
+                Operator::NotEqual => Operator::Equal,
+                Operator::Less => Operator::GreaterOrEqual,
+                Operator::LessOrEqual => Operator::Greater,
+                Operator::Greater => Operator::LessOrEqual,
+                Operator::GreaterOrEqual => Operator::Less,
+                _ => {
+                    return Err(SqlError::Unsupported(format!(
+                        "`{}` in a policy OR that needs joins (its negation can't be filtered)",
+                        ce
+                    )))
+                }
+            };
+            Ok(ConditionExpression::ComparisonOp(ConditionTree {
+                operator,
+                ..ct.clone()
+            }))
+        }
+        ConditionExpression::Bracketed(ref inner) => {
+            Ok(ConditionExpression::Bracketed(Box::new(negate(inner)?)))
+        }
+        ConditionExpression::NegationOp(ref inner) => Ok((**inner).clone()),
+        _ => Err(SqlError::Unsupported(format!(
+            "`{}` in a policy OR that needs joins (its negation can't be filtered)",
+            ce
+        ))),
+    }
+}
+
+/// If `ce` is `column [NOT] IN (subquery)`, returns the column, the subquery, and whether it is
+/// negated.
+fn in_subquery(ce: &ConditionExpression) -> Option<(Column, SelectStatement, bool)> {
+    let ct = match *ce {
+        ConditionExpression::ComparisonOp(ref ct) if ct.operator == Operator::In => ct,
+        _ => return None,
+    };
+    let column = match *ct.left {
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => c.clone(),
+        _ => return None,
+    };
+    match *ct.right {
+        ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => {
+            Some((column, (**sq).clone(), false))
+        }
+        ConditionExpression::NegationOp(ref inner) => match **inner {
+            ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => {
+                Some((column, (**sq).clone(), true))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn has_subquery(ce: &ConditionExpression) -> bool {
+    match *ce {
+        ConditionExpression::LogicalOp(ref ct) | ConditionExpression::ComparisonOp(ref ct) => {
+            has_subquery(&ct.left) || has_subquery(&ct.right)
+        }
+        ConditionExpression::NegationOp(ref inner) | ConditionExpression::Bracketed(ref inner) => {
+            has_subquery(inner)
+        }
+        ConditionExpression::Base(ConditionBase::NestedSelect(_)) => true,
+        ConditionExpression::Base(_) | ConditionExpression::Arithmetic(_) => false,
+    }
+}
+
+/// The value of a literal, or of a context column, in a policy predicate.
+fn context_value(
+    ce: &ConditionExpression,
+    context: &HashMap<String, DataType>,
+) -> Result<DataType, SqlError> {
+    match *ce {
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => {
+            match c.table.as_ref().map(String::as_str) {
+                None | Some("UserContext") | Some("GroupContext") => {}
+                Some(_) => {
+                    return Err(SqlError::Unsupported(format!(
+                        "column {} in a policy predicate (only the context may be referred to)",
+                        c
+                    )))
+                }
+            }
+            context
+                .get(&c.name)
+                .cloned()
+                .ok_or_else(|| SqlError::Invalid(format!("context has no field {}", c.name)))
+        }
+        ConditionExpression::Base(ConditionBase::Literal(ref l)) => literal_value(l),
+        _ => Err(SqlError::Unsupported(format!(
+            "`{}` in a policy predicate",
+            ce
+        ))),
+    }
+}
+
+/// Evaluates a policy predicate that may only refer to the context against a universe's
+/// context.
+fn context_satisfies(
+    ce: &ConditionExpression,
+    context: &HashMap<String, DataType>,
+) -> Result<bool, SqlError> {
+    match *ce {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            ref left,
+            ref right,
+        }) => Ok(context_satisfies(left, context)? && context_satisfies(right, context)?),
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::Or,
+            ref left,
+            ref right,
+        }) => Ok(context_satisfies(left, context)? || context_satisfies(right, context)?),
+        ConditionExpression::NegationOp(ref inner) => Ok(!context_satisfies(inner, context)?),
+        ConditionExpression::Bracketed(ref inner) => context_satisfies(inner, context),
+        ConditionExpression::ComparisonOp(ref ct) => {
+            let left = context_value(&ct.left, context)?;
+            if let ConditionExpression::Base(ConditionBase::LiteralList(ref ll)) = *ct.right {
+                return match ct.operator {
+                    Operator::In => ll
+                        .iter()
+                        .map(literal_value)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map(|vs| vs.contains(&left)),
+                    _ => Err(SqlError::Unsupported(format!(
+                        "`{}` in a policy predicate",
+                        ce
+                    ))),
+                };
+            }
+
+            let right = context_value(&ct.right, context)?;
+            match ct.operator {
+                Operator::Equal | Operator::Is => Ok(left == right),
+                Operator::NotEqual => Ok(left != right),
+                Operator::Less => Ok(left < right),
+                Operator::LessOrEqual => Ok(left <= right),
+                Operator::Greater => Ok(left > right),
+                Operator::GreaterOrEqual => Ok(left >= right),
+                _ => Err(SqlError::Unsupported(format!(
+                    "`{}` in a policy predicate",
+                    ce
+                ))),
+            }
+        }
+        _ => Err(SqlError::Unsupported(format!(
+            "`{}` in a policy predicate",
+            ce
+        ))),
+    }
+}
+
+/// Compiles the predicate of a write policy on `table` into the conditions the table's base
+/// checks the rows a universe writes against: alternatives, of which a row must satisfy at least
+/// one, that each are a conjunction of conditions on the base's `fields`. Comparisons that only
+/// involve the context are decided here, once.
+pub(in crate::controller) fn write_conditions(
+    ce: &ConditionExpression,
+    table: &str,
+    fields: &[String],
+    context: &HashMap<String, DataType>,
+) -> Result<Vec<Vec<(usize, FilterCondition)>>, SqlError> {
+    // the table column a side of a comparison refers to, if any
+    let column = |ce: &ConditionExpression| match *ce {
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => {
+            match c.table.as_ref().map(String::as_str) {
+                Some("UserContext") | Some("GroupContext") => Ok(None),
+                Some(t) if t != table => Err(SqlError::Unsupported(format!(
+                    "column {} in a write policy on {}",
+                    c, table
+                ))),
+                _ => fields
+                    .iter()
+                    .position(|f| *f == c.name)
+                    .map(Some)
+                    .ok_or_else(|| {
+                        SqlError::Invalid(format!("table {} has no column {}", table, c.name))
+                    }),
+            }
+        }
+        _ => Ok(None),
+    };
+    let unsupported = || SqlError::Unsupported(format!("`{}` in a write policy", ce));
+
+    match *ce {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            ref left,
+            ref right,
+        }) => {
+            let left = write_conditions(left, table, fields, context)?;
+            let right = write_conditions(right, table, fields, context)?;
+            Ok(left
+                .iter()
+                .flat_map(|l| {
+                    right
+                        .iter()
+                        .map(move |r| l.iter().chain(r).cloned().collect())
+                })
+                .collect())
+        }
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::Or,
+            ref left,
+            ref right,
+        }) => {
+            let mut alternatives = write_conditions(left, table, fields, context)?;
+            alternatives.extend(write_conditions(right, table, fields, context)?);
+            Ok(alternatives)
+        }
+        ConditionExpression::Bracketed(ref inner) => {
+            write_conditions(inner, table, fields, context)
+        }
+        ConditionExpression::ComparisonOp(ref ct) => {
+            // conditions compare a table column with the other side of the comparison
+            let (col, flipped, other) = match (column(&ct.left)?, column(&ct.right)?) {
+                (None, None) => {
+                    return Ok(if context_satisfies(ce, context)? {
+                        vec![vec![]]
+                    } else {
+                        vec![]
+                    });
+                }
+                (Some(c), _) => (c, false, &ct.right),
+                (None, Some(c)) => (c, true, &ct.left),
+            };
+
+            if let ConditionExpression::Base(ConditionBase::LiteralList(ref ll)) = **other {
+                if ct.operator != Operator::In || flipped {
+                    return Err(unsupported());
+                }
+                let values = ll.iter().map(literal_value).collect::<Result<_, _>>()?;
+                return Ok(vec![vec![(col, FilterCondition::In(values))]]);
+            }
+
+            let operator = match (&ct.operator, flipped) {
+                (&Operator::Equal, _) | (&Operator::Is, _) => Operator::Equal,
+                (&Operator::NotEqual, _) => Operator::NotEqual,
+                (&Operator::Less, false) | (&Operator::Greater, true) => Operator::Less,
+                (&Operator::LessOrEqual, false) | (&Operator::GreaterOrEqual, true) => {
+                    Operator::LessOrEqual
+                }
+                (&Operator::Greater, false) | (&Operator::Less, true) => Operator::Greater,
+                (&Operator::GreaterOrEqual, false) | (&Operator::LessOrEqual, true) => {
+                    Operator::GreaterOrEqual
+                }
+                _ => return Err(unsupported()),
+            };
+            let value = match column(other)? {
+                Some(c) => FilterValue::Column(c),
+                None => FilterValue::Constant(context_value(other, context)?),
+            };
+            Ok(vec![vec![(
+                col,
+                FilterCondition::Comparison(operator, value),
+            )]])
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+fn literal_value(l: &Literal) -> Result<DataType, SqlError> {
+    match *l {
+        Literal::Null | Literal::Integer(_) | Literal::String(_) | Literal::FixedPoint(_) => {
+            Ok(DataType::from(l))
+        }
+        _ => Err(SqlError::Unsupported(format!(
+            "literal {} in a policy predicate",
+            l.to_string()
+        ))),
+    }
+}
+
+/// Adds the tables that `st`'s WHERE clause refers to, but that it doesn't select from, to its
+/// FROM clause, so that comparisons with their columns become joins.
+fn join_referred_tables(st: &mut SelectStatement, context_table: &Table) {
+    let referred = match st.where_clause {
+        Some(ref ce) => ce.referred_tables(),
+        None => return,
+    };
+
+    for t in referred {
+        let selected = st
+            .tables
+            .iter()
+            .chain(st.join.iter().filter_map(|jc| match jc.right {
+                JoinRightSide::Table(ref t) => Some(t),
+                _ => None,
+            }))
+            .any(|s| s.name == t.name || s.alias.as_ref() == Some(&t.name));
+        if selected {
+            continue;
+        }
+
+        if context_table.alias.as_ref() == Some(&t.name) {
+            st.tables.push(context_table.clone());
+        } else {
+            st.tables.push(t);
+        }
+    }
+}
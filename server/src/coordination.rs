@@ -26,6 +26,8 @@ pub enum CoordinationPayload {
         read_listen_addr: SocketAddr,
         /// Which log files are stored locally on the worker.
         log_files: Vec<String>,
+        /// The names of the storage backends that base tables on the worker can use.
+        storage_backends: Vec<String>,
     },
     /// Worker going offline.
     Deregister,
@@ -3,6 +3,7 @@ use crate::startup::Event;
 use dataflow::prelude::*;
 use noria::consensus::Authority;
 use noria::prelude::*;
+use noria::ConnectOptions;
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
@@ -34,8 +35,9 @@ impl<A: Authority + 'static> Handle<A> {
         authority: Arc<A>,
         event_tx: tokio::sync::mpsc::UnboundedSender<Event>,
         kill: Trigger,
+        options: ConnectOptions,
     ) -> Result<Self, failure::Error> {
-        let c = ControllerHandle::make(authority).await?;
+        let c = ControllerHandle::make_with(authority, options).await?;
         Ok(Handle {
             c: Some(c),
             event_tx: Some(event_tx),
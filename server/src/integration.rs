@@ -622,6 +622,1822 @@ async fn it_works_w_partial_mat_below_empty() {
     assert_eq!(cq.len().await.unwrap(), 1);
 }
 
+#[tokio::test(threaded_scheduler)]
+async fn it_works_w_persistent_partial_mat() {
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_works_w_persistent_partial_mat"));
+    builder.set_partial_storage_strategy(crate::PartialStorageStrategy::AllPersistent);
+    let mut g = builder.start_local().await.unwrap().0;
+    g.install_recipe(
+        "
+        CREATE TABLE Vote (aid int, uid int);
+        QUERY VoteCount: SELECT aid, COUNT(uid) AS votes FROM Vote WHERE aid = ? GROUP BY aid;
+    ",
+    )
+    .await
+    .unwrap();
+
+    let mut vote = g.table("Vote").await.unwrap();
+    let mut vc = g.view("VoteCount").await.unwrap();
+    for uid in 0..3 {
+        vote.insert(vec![1.into(), uid.into()]).await.unwrap();
+    }
+    vote.insert(vec![2.into(), 1.into()]).await.unwrap();
+    sleep().await;
+
+    // the aggregation's state is on disk, and filled by replay on the first read
+    let res = vc.lookup(&[1.into()], true).await.unwrap();
+    assert_eq!(res, vec![vec![1.into(), 3.into()]]);
+
+    // later writes should update the persisted state
+    vote.insert(vec![1.into(), 3.into()]).await.unwrap();
+    sleep().await;
+
+    let res = vc.lookup(&[1.into()], true).await.unwrap();
+    assert_eq!(res, vec![vec![1.into(), 4.into()]]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_works_w_custom_storage_backend() {
+    use dataflow::LogBackend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // the log backend keeps an append-only log of all writes, as one might for audit tables
+    let opened = Arc::new(AtomicUsize::new(0));
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_works_w_custom_storage_backend"));
+    builder.add_storage_backend("log", LogBackend(opened.clone()));
+    let mut g = builder.start_local().await.unwrap().0;
+    g.install_recipe(
+        "
+        CREATE TABLE Audit (id int, msg varchar(255), PRIMARY KEY(id)) WITH (storage = 'log');
+        CREATE TABLE Scratch (id int, msg varchar(255), PRIMARY KEY(id)) WITH (storage = 'memory');
+        QUERY AuditById: SELECT id, msg FROM Audit WHERE id = ?;
+        QUERY ScratchById: SELECT id, msg FROM Scratch WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+    let mut audit = g.table("Audit").await.unwrap();
+    let mut scratch = g.table("Scratch").await.unwrap();
+    let mut audit_by_id = g.view("AuditById").await.unwrap();
+    let mut scratch_by_id = g.view("ScratchById").await.unwrap();
+
+    audit
+        .insert(vec![1.into(), "created".into()])
+        .await
+        .unwrap();
+    audit
+        .insert(vec![2.into(), "deleted".into()])
+        .await
+        .unwrap();
+    audit.delete(vec![2.into()]).await.unwrap();
+    scratch.insert(vec![1.into(), "temp".into()]).await.unwrap();
+    sleep().await;
+
+    // the readers are filled by replays out of the base storage
+    assert_eq!(
+        audit_by_id.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "created".into()]]
+    );
+    assert!(audit_by_id
+        .lookup(&[2.into()], true)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        scratch_by_id.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "temp".into()]]
+    );
+
+    // tables can't ask for storage that the workers don't have
+    assert!(g
+        .extend_recipe("CREATE TABLE Other (id int, PRIMARY KEY(id)) WITH (storage = 'sled');")
+        .await
+        .is_err());
+    assert!(!g.inputs().await.unwrap().contains_key("Other"));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_expires_rows_past_ttl() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_expires_rows_past_ttl"));
+    builder.set_retention_interval(Duration::from_millis(50));
+    let mut g = builder.start_local().await.unwrap().0;
+    g.install_recipe(
+        "
+        CREATE TABLE Event (id int, kind int, created_at bigint, PRIMARY KEY(id))
+            WITH (ttl_column = created_at, ttl = '1h');
+        QUERY EventById: SELECT id, kind FROM Event WHERE id = ?;
+        QUERY EventCount: SELECT kind, COUNT(id) AS n FROM Event WHERE kind = ? GROUP BY kind;
+    ",
+    )
+    .await
+    .unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let mut event = g.table("Event").await.unwrap();
+    let mut by_id = g.view("EventById").await.unwrap();
+    let mut count = g.view("EventCount").await.unwrap();
+    event
+        .insert(vec![1.into(), 1.into(), (now - 2 * 60 * 60).into()])
+        .await
+        .unwrap();
+    event
+        .insert(vec![2.into(), 1.into(), now.into()])
+        .await
+        .unwrap();
+    sleep().await;
+
+    // the old event is deleted, and the deletion reaches the aggregate too
+    assert!(by_id.lookup(&[1.into()], true).await.unwrap().is_empty());
+    assert_eq!(
+        by_id.lookup(&[2.into()], true).await.unwrap(),
+        vec![vec![2.into(), 1.into()]]
+    );
+    assert_eq!(
+        count.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_reports_cache_statistics() {
+    use noria::debug::stats::CacheStats;
+
+    let mut g = start_simple_unsharded("it_reports_cache_statistics").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    let mut article = g.table("Article").await.unwrap();
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    article
+        .insert(vec![1.into(), "Hello".into()])
+        .await
+        .unwrap();
+    sleep().await;
+
+    // the first lookup misses and triggers a replay, the second one hits
+    for _ in 0..2 {
+        assert_eq!(
+            by_id.lookup(&[1.into()], true).await.unwrap(),
+            vec![vec![1.into(), "Hello".into()]]
+        );
+    }
+
+    let stats = g.statistics().await.unwrap();
+    let cache = stats
+        .values()
+        .flat_map(|(_, nodes)| nodes.values())
+        .map(|n| n.cache)
+        .fold(CacheStats::default(), |a, b| a + b);
+    assert!(cache.misses >= 1);
+    assert!(cache.hits >= 2);
+    assert!(cache.hit_rate().unwrap() > 0.0);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_exports_metrics() {
+    use noria::consensus::{Authority, CONTROLLER_KEY};
+    use noria::ControllerDescriptor;
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_exports_metrics"));
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    g.backend_ready().await;
+
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    article
+        .insert(vec![1.into(), "Hello".into()])
+        .await
+        .unwrap();
+    sleep().await;
+    for _ in 0..2 {
+        assert_eq!(
+            by_id.lookup(&[1.into()], true).await.unwrap(),
+            vec![vec![1.into(), "Hello".into()]]
+        );
+    }
+    let descriptor: ControllerDescriptor =
+        serde_json::from_slice(&authority.try_read(CONTROLLER_KEY).unwrap().unwrap()).unwrap();
+    let url: hyper::Uri = format!("http://{}/metrics", descriptor.external_addr)
+        .parse()
+        .unwrap();
+
+    // the sum of all samples in `metrics` whose name and labels start with `prefix`
+    let total = |metrics: &str, prefix: &str| -> f64 {
+        metrics
+            .lines()
+            .filter(|l| l.starts_with(prefix))
+            .map(|l| l.rsplit(' ').next().unwrap().parse::<f64>().unwrap())
+            .sum()
+    };
+
+    // domains only report on their nodes every so often
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    let metrics = loop {
+        let res = hyper::Client::new().get(url.clone()).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        let reported = total(&metrics, "noria_node_state_bytes{") > 0.0
+            && total(&metrics, "noria_reader_lookups_total{") >= 2.0;
+        if reported || std::time::Instant::now() > deadline {
+            break metrics;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    };
+    let total = |prefix: &str| total(&metrics, prefix);
+    assert_eq!(total("noria_controller_leader "), 1.0);
+    assert_eq!(total("noria_controller_workers{state=\"healthy\"}"), 1.0);
+    assert_eq!(total("noria_table_write_duration_seconds_count"), 1.0);
+    assert!(total("noria_view_read_duration_seconds_count") >= 2.0);
+    assert!(
+        total("noria_controller_request_duration_seconds_count{endpoint=\"/view_builder\"}") >= 1.0
+    );
+    assert!(total("noria_domain_partial_replays_total") >= 1.0);
+    assert!(total("noria_domain_partial_replay_latency_seconds_count") >= 1.0);
+    assert!(total("noria_reader_lookups_total{") >= 2.0);
+    assert!(total("noria_node_state_bytes{") > 0.0);
+
+    drop(by_id);
+    drop(article);
+    drop(g);
+    done.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_traces_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("spans.json");
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_traces_writes"));
+    builder.set_tracing(1.0, noria_server::TraceExport::File(path.clone()));
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    g.backend_ready().await;
+
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    assert!(by_id.lookup(&[1.into()], true).await.unwrap().is_empty());
+    article
+        .insert(vec![1.into(), "Hello".into()])
+        .await
+        .unwrap();
+    sleep().await;
+    assert_eq!(
+        by_id.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "Hello".into()]]
+    );
+
+    // shutting down exports whatever spans are left
+    drop(by_id);
+    drop(article);
+    drop(g);
+    done.await;
+
+    let spans: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .flat_map(|batch| {
+            let batch: serde_json::Value = serde_json::from_str(batch).unwrap();
+            batch["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                .as_array()
+                .unwrap()
+                .clone()
+        })
+        .collect();
+
+    // the one write was traced, from the base table's domain to the view's
+    let trace_id = &spans[0]["traceId"];
+    assert!(spans.iter().all(|s| &s["traceId"] == trace_id));
+    let named = |prefix: &str| {
+        spans
+            .iter()
+            .filter(|s| s["name"].as_str().unwrap().starts_with(prefix))
+            .count()
+    };
+    assert!(named("domain ") >= 2);
+    assert_eq!(named("base Article"), 1);
+    assert!(named("egress ") >= 1);
+    assert!(named("ingress ") >= 1);
+    assert_eq!(named("reader "), 1);
+
+    // and every span but the first domain's hangs off another span of the trace
+    let ids: Vec<_> = spans.iter().map(|s| &s["spanId"]).collect();
+    let roots: Vec<_> = spans
+        .iter()
+        .filter(|s| s.get("parentSpanId").is_none())
+        .collect();
+    assert_eq!(roots.len(), 1);
+    assert!(roots[0]["name"].as_str().unwrap().starts_with("domain "));
+    assert!(spans
+        .iter()
+        .filter_map(|s| s.get("parentSpanId"))
+        .all(|p| ids.contains(&p)));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_reports_materialization_advice() {
+    use noria::{MaterializationStatus, Recommendation};
+
+    let mut g = start_simple_unsharded("it_reports_materialization_advice").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, author int, PRIMARY KEY(id));
+        CREATE TABLE Author (id int, name varchar(255), PRIMARY KEY(id));
+        CREATE TABLE Vote (article_id int, user int);
+        QUERY ArticleById: SELECT id, author FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    let mut article = g.table("Article").await.unwrap();
+    let mut author = g.table("Author").await.unwrap();
+    let mut vote = g.table("Vote").await.unwrap();
+    article
+        .perform_all((0..1000).map(|i| vec![i.into(), (i % 10).into()]))
+        .await
+        .unwrap();
+    author
+        .perform_all((0..10).map(|i| vec![i.into(), format!("a{}", i).into()]))
+        .await
+        .unwrap();
+    vote.perform_all(vec![
+        vec![3.into(), 7.into()],
+        vec![13.into(), 8.into()],
+        vec![4.into(), 9.into()],
+    ])
+    .await
+    .unwrap();
+    sleep().await;
+
+    let report = g.materialization_report().await.unwrap();
+    assert_eq!(report.tables["Article"].rows, 1000);
+    assert_eq!(report.tables["Article"].columns["id"].distinct_values, 1000);
+    assert_eq!(report.tables["Author"].rows, 10);
+    assert!(report.advice.is_empty());
+
+    // every one of these lookups misses
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    by_id
+        .multi_lookup((0..1000).map(|i| vec![i.into()]).collect(), false)
+        .await
+        .unwrap();
+    sleep().await;
+
+    let report = g.materialization_report().await.unwrap();
+    let advice: Vec<_> = report
+        .advice
+        .iter()
+        .filter(|a| a.recommended == Recommendation::Full)
+        .collect();
+    assert_eq!(advice.len(), 1);
+    match advice[0].current {
+        MaterializationStatus::Partial { .. } => {}
+        ref s => unreachable!("advised to fully materialize {:?} state", s),
+    }
+    assert!(advice[0].cache.misses >= 1000);
+
+    let applied = g.apply_materialization_advice().await.unwrap();
+    assert_eq!(applied, vec![advice[0].name.clone()]);
+
+    // queries added once the tables have data have their joins ordered by size
+    g.extend_recipe(
+        "QUERY VotesByAuthor: SELECT Author.name, Vote.user FROM Article \
+         JOIN Author ON (Article.author = Author.id) \
+         JOIN Vote ON (Vote.article_id = Article.id) WHERE Author.name = ?;",
+    )
+    .await
+    .unwrap();
+    let mut votes = g.view("VotesByAuthor").await.unwrap();
+    let mut rows: Vec<Vec<_>> = votes.lookup(&["a3".into()], true).await.unwrap().into();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![vec!["a3".into(), 7.into()], vec!["a3".into(), 8.into()]]
+    );
+}
+
+const POST_POLICIES: &str = r#"
+{
+    "policies": [
+        { "table": "Post", "predicate": "WHERE Post.p_private = 0" },
+        { "table": "Post", "predicate": "WHERE Post.p_private = 1 AND UserContext.id = Post.p_author" }
+    ]
+}"#;
+
+const POST_RECIPE: &str = "
+    CREATE TABLE Post (p_id int, p_cid int, p_author int, p_private tinyint(1), PRIMARY KEY(p_id));
+    QUERY posts: SELECT * FROM Post WHERE p_cid = ?;
+";
+
+fn user(id: i32) -> HashMap<String, DataType> {
+    let mut context = HashMap::new();
+    context.insert("id".to_owned(), id.into());
+    context
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_destroys_universes() {
+    let mut g = start_simple_unsharded("it_destroys_universes").await;
+    g.set_security_config(POST_POLICIES.to_owned())
+        .await
+        .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+    let domains = g.statistics().await.unwrap().domains.len();
+
+    let mut post = g.table("Post").await.unwrap();
+    post.perform_all(vec![
+        vec![1.into(), 1.into(), 1.into(), 0.into()],
+        vec![2.into(), 1.into(), 1.into(), 1.into()],
+        vec![3.into(), 1.into(), 2.into(), 1.into()],
+    ])
+    .await
+    .unwrap();
+    sleep().await;
+
+    g.create_universe(user(1)).await.unwrap();
+    sleep().await;
+    assert!(g.outputs().await.unwrap().contains_key("posts_u1"));
+    assert!(g.statistics().await.unwrap().domains.len() > domains);
+    let mut posts = g.view("posts_u1").await.unwrap();
+    assert_eq!(posts.lookup(&[1.into()], true).await.unwrap().len(), 2);
+
+    g.destroy_universe(user(1)).await.unwrap();
+    assert!(!g.outputs().await.unwrap().contains_key("posts_u1"));
+    assert!(g.view("posts_u1").await.is_err());
+    // the domains that only held the universe's nodes are gone
+    assert_eq!(g.statistics().await.unwrap().domains.len(), domains);
+    // and there is nothing left to destroy
+    assert!(g.destroy_universe(user(1)).await.is_err());
+
+    // the global query is unaffected
+    post.insert(vec![4.into(), 1.into(), 2.into(), 0.into()])
+        .await
+        .unwrap();
+    sleep().await;
+    let mut posts = g.view("posts").await.unwrap();
+    assert_eq!(posts.lookup(&[1.into()], true).await.unwrap().len(), 4);
+
+    // and the user can log in again
+    g.create_universe(user(1)).await.unwrap();
+    sleep().await;
+    let mut posts = g.view("posts_u1").await.unwrap();
+    assert_eq!(posts.lookup(&[1.into()], true).await.unwrap().len(), 3);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_collects_idle_universes() {
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_collects_idle_universes"));
+    builder.set_universe_idle_timeout(Duration::from_millis(500));
+    let mut g = builder.start_local().await.unwrap().0;
+    g.set_security_config(POST_POLICIES.to_owned())
+        .await
+        .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+
+    g.create_universe(user(1)).await.unwrap();
+    g.create_universe(user(2)).await.unwrap();
+    sleep().await;
+
+    // only the second user keeps reading
+    let mut posts = g.view("posts_u2").await.unwrap();
+    for _ in 0..30 {
+        posts.lookup(&[1.into()], true).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+
+    let outputs = g.outputs().await.unwrap();
+    assert!(!outputs.contains_key("posts_u1"));
+    assert!(outputs.contains_key("posts_u2"));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_applies_compound_row_policies() {
+    let mut g = start_simple_unsharded("it_applies_compound_row_policies").await;
+    g.set_security_config(
+        r#"
+        {
+            "policies": [
+                {
+                    "table": "Post",
+                    "predicate": "WHERE Post.p_private = 0 AND (Post.p_cid = 1 OR Post.p_cid = 2)"
+                },
+                {
+                    "table": "Post",
+                    "predicate": "WHERE Post.p_private = 1 AND Post.p_author = UserContext.id"
+                }
+            ]
+        }"#
+        .to_owned(),
+    )
+    .await
+    .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+
+    let mut post = g.table("Post").await.unwrap();
+    post.perform_all(vec![
+        vec![1.into(), 1.into(), 2.into(), 0.into()],
+        vec![2.into(), 3.into(), 2.into(), 0.into()],
+        vec![3.into(), 1.into(), 1.into(), 1.into()],
+        vec![4.into(), 1.into(), 2.into(), 1.into()],
+        vec![5.into(), 3.into(), 1.into(), 1.into()],
+    ])
+    .await
+    .unwrap();
+    sleep().await;
+
+    g.create_universe(user(1)).await.unwrap();
+    sleep().await;
+
+    let mut posts = g.view("posts_u1").await.unwrap();
+    let mut visible: Vec<i32> = posts
+        .lookup(&[1.into()], true)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| i32::from(&r[0]))
+        .collect();
+    visible.sort();
+    // public posts in the right classes, and the user's own private posts
+    assert_eq!(visible, vec![1, 3]);
+    let visible: Vec<i32> = posts
+        .lookup(&[3.into()], true)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| i32::from(&r[0]))
+        .collect();
+    assert_eq!(visible, vec![5]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_applies_hotcrp_policies() {
+    let mut g = start_simple_unsharded("it_applies_hotcrp_policies").await;
+    let policies =
+        std::fs::read_to_string("../applications/securecrp/hotcrp_policies.json").unwrap();
+    g.set_security_config(policies).await.unwrap();
+    g.install_recipe(
+        "CREATE TABLE Paper (paperId int, title varchar(256), leadContactId int, \
+                             PRIMARY KEY(paperId));
+         CREATE TABLE PaperConflict (paperId int, contactId int, conflictType int);
+         QUERY papers: SELECT Paper.paperId, Paper.title FROM Paper WHERE Paper.paperId = ?;",
+    )
+    .await
+    .unwrap();
+
+    let mut paper = g.table("Paper").await.unwrap();
+    paper
+        .perform_all(vec![
+            vec![1.into(), "mine".into(), 1.into()],
+            vec![2.into(), "unconflicted".into(), 2.into()],
+            vec![3.into(), "conflicted".into(), 2.into()],
+        ])
+        .await
+        .unwrap();
+    let mut conflict = g.table("PaperConflict").await.unwrap();
+    conflict
+        .perform_all(vec![
+            // authors are conflicted with their own papers
+            vec![1.into(), 1.into(), 1.into()],
+            vec![3.into(), 1.into(), 1.into()],
+            // other users' conflicts don't matter
+            vec![2.into(), 2.into(), 1.into()],
+        ])
+        .await
+        .unwrap();
+    sleep().await;
+
+    g.create_universe(user(1)).await.unwrap();
+    sleep().await;
+
+    let mut papers = g.view("papers_u1").await.unwrap();
+    assert_eq!(
+        papers.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![DataType::from(1), "mine".into()]]
+    );
+    assert_eq!(
+        papers.lookup(&[2.into()], true).await.unwrap(),
+        vec![vec![DataType::from(2), "unconflicted".into()]]
+    );
+    assert!(papers.lookup(&[3.into()], true).await.unwrap().is_empty());
+
+    // a new conflict hides the paper
+    conflict
+        .insert(vec![2.into(), 1.into(), 1.into()])
+        .await
+        .unwrap();
+    sleep().await;
+    assert!(papers.lookup(&[2.into()], true).await.unwrap().is_empty());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_rejects_unsupported_policies() {
+    let mut g = start_simple_unsharded("it_rejects_unsupported_policies").await;
+    assert!(g.set_security_config("{ ".to_owned()).await.is_err());
+    assert!(g
+        .set_security_config(r#"{ "policies": [{ "table": "Post" }] }"#.to_owned())
+        .await
+        .is_err());
+
+    // OR between two joins with the context, which may both match a row
+    g.set_security_config(
+        r#"
+        {
+            "policies": [
+                {
+                    "table": "Post",
+                    "predicate": "WHERE Post.p_author = UserContext.id OR Post.p_cid = UserContext.id"
+                }
+            ]
+        }"#
+        .to_owned(),
+    )
+    .await
+    .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+
+    assert!(g.create_universe(user(1)).await.is_err());
+    assert!(!g.outputs().await.unwrap().contains_key("posts_u1"));
+
+    // the controller carries on as before
+    let mut post = g.table("Post").await.unwrap();
+    post.insert(vec![1.into(), 1.into(), 1.into(), 0.into()])
+        .await
+        .unwrap();
+    sleep().await;
+    let mut posts = g.view("posts").await.unwrap();
+    assert_eq!(posts.lookup(&[1.into()], true).await.unwrap().len(), 1);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_unions_policy_disjunctions() {
+    use noria::Modification;
+
+    let mut g = start_simple_unsharded("it_unions_policy_disjunctions").await;
+    g.set_security_config(
+        r#"
+        {
+            "policies": [
+                {
+                    "table": "Post",
+                    "predicate": "WHERE (Post.p_private = 0 OR Post.p_author = UserContext.id) AND Post.p_cid != 3"
+                }
+            ]
+        }"#
+        .to_owned(),
+    )
+    .await
+    .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+
+    let mut post = g.table("Post").await.unwrap();
+    post.perform_all(vec![
+        vec![1.into(), 1.into(), 1.into(), 0.into()],
+        vec![2.into(), 1.into(), 1.into(), 1.into()],
+        vec![3.into(), 1.into(), 2.into(), 0.into()],
+        vec![4.into(), 1.into(), 2.into(), 1.into()],
+        vec![5.into(), 3.into(), 1.into(), 0.into()],
+    ])
+    .await
+    .unwrap();
+    sleep().await;
+
+    g.create_universe(user(1)).await.unwrap();
+    sleep().await;
+
+    // the public posts and the user's own private post, each once
+    let mut posts = g.view("posts_u1").await.unwrap();
+    let mut ids: Vec<i32> = posts
+        .lookup(&[1.into()], true)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| i32::from(&r[0]))
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert!(posts.lookup(&[3.into()], true).await.unwrap().is_empty());
+
+    // rows move between the branches as they change
+    post.update(vec![2.into()], vec![(3, Modification::Set(0.into()))])
+        .await
+        .unwrap();
+    post.update(vec![3.into()], vec![(3, Modification::Set(1.into()))])
+        .await
+        .unwrap();
+    sleep().await;
+    let mut ids: Vec<i32> = posts
+        .lookup(&[1.into()], true)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| i32::from(&r[0]))
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_masks_columns() {
+    let mut g = start_simple_unsharded("it_masks_columns").await;
+    g.set_security_config(
+        r#"
+        {
+            "policies": [
+                {
+                    "action": "mask",
+                    "table": "Employee",
+                    "column": "ssn",
+                    "mask": "truncate",
+                    "length": 4,
+                    "from": "end",
+                    "predicate": "WHERE UserContext.role != 'hr'"
+                },
+                {
+                    "action": "mask",
+                    "table": "Employee",
+                    "column": "salary",
+                    "mask": "hide",
+                    "predicate": "WHERE UserContext.role != 'hr'"
+                },
+                {
+                    "action": "mask",
+                    "table": "Employee",
+                    "column": "manager",
+                    "mask": "null",
+                    "predicate": "WHERE UserContext.role IN ('staff', 'intern')"
+                }
+            ]
+        }"#
+        .to_owned(),
+    )
+    .await
+    .unwrap();
+    g.install_recipe(
+        "CREATE TABLE Employee (e_id int, ssn varchar(11), salary int, manager int, \
+                                PRIMARY KEY(e_id));
+         QUERY employees: SELECT * FROM Employee WHERE e_id = ?;",
+    )
+    .await
+    .unwrap();
+
+    let mut employee = g.table("Employee").await.unwrap();
+    employee
+        .insert(vec![1.into(), "123-45-6789".into(), 100.into(), 2.into()])
+        .await
+        .unwrap();
+    sleep().await;
+
+    let mut hr = user(1);
+    hr.insert("role".to_owned(), "hr".into());
+    g.create_universe(hr).await.unwrap();
+    let mut staff = user(2);
+    staff.insert("role".to_owned(), "staff".into());
+    g.create_universe(staff).await.unwrap();
+    sleep().await;
+
+    let mut employees = g.view("employees_u1").await.unwrap();
+    assert_eq!(
+        employees.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "123-45-6789".into(), 100.into(), 2.into()]]
+    );
+
+    let mut employees = g.view("employees_u2").await.unwrap();
+    assert_eq!(
+        employees.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "6789".into(), DataType::None]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_rejects_mask_policies_on_other_tables() {
+    let mut g = start_simple_unsharded("it_rejects_mask_policies_on_other_tables").await;
+    g.set_security_config(
+        r#"
+        {
+            "policies": [
+                {
+                    "action": "mask",
+                    "table": "Post",
+                    "column": "p_author",
+                    "mask": "hash",
+                    "predicate": "WHERE Post.p_private = 1"
+                }
+            ]
+        }"#
+        .to_owned(),
+    )
+    .await
+    .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+
+    // mask predicates can only depend on the context
+    assert!(g.create_universe(user(1)).await.is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_enforces_write_policies() {
+    use noria::error::TableError;
+
+    let mut g = start_simple_unsharded("it_enforces_write_policies").await;
+    g.set_security_config(
+        r#"
+        {
+            "policies": [
+                {
+                    "action": "allow_write",
+                    "table": "Post",
+                    "predicate": "WHERE Post.p_author = UserContext.id AND Post.p_cid IN (1, 2)"
+                }
+            ]
+        }"#
+        .to_owned(),
+    )
+    .await
+    .unwrap();
+    g.install_recipe(POST_RECIPE).await.unwrap();
+
+    // writes that aren't made on behalf of a universe are refused
+    let mut post = g.table("Post").await.unwrap();
+    match post
+        .insert(vec![1.into(), 1.into(), 2.into(), 0.into()])
+        .await
+    {
+        Err(TableError::PolicyViolation(_)) => {}
+        r => panic!("expected a policy violation, got {:?}", r),
+    }
+
+    // there is no universe to write on behalf of yet
+    assert!(g.universe_table("Post", 1.into()).await.is_err());
+    g.create_universe(user(1)).await.unwrap();
+    g.create_universe(user(2)).await.unwrap();
+    let mut mine = g.universe_table("Post", 1.into()).await.unwrap();
+    let mut theirs = g.universe_table("Post", 2.into()).await.unwrap();
+    theirs
+        .insert(vec![1.into(), 1.into(), 2.into(), 0.into()])
+        .await
+        .unwrap();
+
+    mine.insert(vec![2.into(), 1.into(), 1.into(), 0.into()])
+        .await
+        .unwrap();
+    match mine
+        .insert(vec![3.into(), 1.into(), 2.into(), 0.into()])
+        .await
+    {
+        Err(TableError::PolicyViolation(_)) => {}
+        r => panic!("expected a policy violation, got {:?}", r),
+    }
+    match mine
+        .insert(vec![3.into(), 3.into(), 1.into(), 0.into()])
+        .await
+    {
+        Err(TableError::PolicyViolation(_)) => {}
+        r => panic!("expected a policy violation, got {:?}", r),
+    }
+    // removing another author's post is writing it too
+    match mine.delete(vec![1.into()]).await {
+        Err(TableError::PolicyViolation(_)) => {}
+        r => panic!("expected a policy violation, got {:?}", r),
+    }
+    sleep().await;
+
+    let mut posts = g.view("posts").await.unwrap();
+    assert_eq!(
+        posts.lookup(&[1.into()], true).await.unwrap(),
+        vec![
+            vec![1.into(), 1.into(), 2.into(), 0.into()],
+            vec![2.into(), 1.into(), 1.into(), 0.into()],
+        ]
+    );
+
+    // handles outlive their universe, but lose the right to write
+    g.destroy_universe(user(1)).await.unwrap();
+    assert!(mine
+        .insert(vec![4.into(), 1.into(), 1.into(), 0.into()])
+        .await
+        .is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_requires_token_for_mutating_endpoints() {
+    use noria::{ConnectOptions, ControllerHandle};
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params(
+        "it_requires_token_for_mutating_endpoints",
+    ));
+    builder.set_auth_token("s3cret");
+    builder.set_worker_secret("hunter2");
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    g.backend_ready().await;
+
+    // the handle returned by the builder knows the token
+    g.install_recipe("CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    // other clients can still look around, but not write to tables
+    let mut anon = ControllerHandle::make(authority.clone()).await.unwrap();
+    anon.ready().await.unwrap();
+    assert!(anon.inputs().await.unwrap().contains_key("Article"));
+    assert!(anon.table("Article").await.is_err());
+    let mut article = g.table("Article").await.unwrap();
+    article.insert(vec![1.into(), "a".into()]).await.unwrap();
+
+    // but can't change the deployment without the right token
+    assert!(anon
+        .extend_recipe("QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;")
+        .await
+        .is_err());
+    let mut wrong = ControllerHandle::make_with(
+        authority.clone(),
+        ConnectOptions::default().with_auth_token("s3cre"),
+    )
+    .await
+    .unwrap();
+    wrong.ready().await.unwrap();
+    assert!(wrong
+        .extend_recipe("QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;")
+        .await
+        .is_err());
+    assert!(!anon.outputs().await.unwrap().contains_key("ArticleById"));
+
+    let mut right = ControllerHandle::make_with(
+        authority.clone(),
+        ConnectOptions::default().with_auth_token("s3cret"),
+    )
+    .await
+    .unwrap();
+    right.ready().await.unwrap();
+    right
+        .extend_recipe("QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;")
+        .await
+        .unwrap();
+    sleep().await;
+
+    let mut by_id = anon.view("ArticleById").await.unwrap();
+    assert_eq!(
+        by_id.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+
+    // views check the token themselves, so knowing where they are isn't enough to read them
+    let vb: Option<noria::builders::ViewBuilder> = right
+        .rpc(
+            "view_builder",
+            "ArticleById",
+            "failed to fetch view builder",
+        )
+        .await
+        .unwrap();
+    let mut sneaky = vb
+        .unwrap()
+        .build(Default::default(), None, Some("s3cre"))
+        .unwrap();
+    assert!(sneaky.lookup(&[1.into()], true).await.is_err());
+
+    drop(anon);
+    drop(wrong);
+    drop(right);
+    drop(g);
+    done.await;
+}
+
+// Sets up a new certificate authority in `dir`, issues a certificate for `noria.test` from it,
+// and returns TLS settings that present that certificate and require the same of peers.
+fn issue_tls(dir: &std::path::Path) -> noria::TlsConfig {
+    std::fs::create_dir_all(dir).unwrap();
+    let mut params = rcgen::CertificateParams::new(Vec::<String>::new());
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "Noria test authority");
+    let ca = rcgen::Certificate::from_params(params).unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["noria.test".to_owned()]).unwrap();
+
+    let (ca_pem, cert_pem, key_pem) = (
+        dir.join("ca.pem"),
+        dir.join("cert.pem"),
+        dir.join("key.pem"),
+    );
+    std::fs::write(&ca_pem, ca.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&cert_pem, cert.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+    std::fs::write(&key_pem, cert.serialize_private_key_pem()).unwrap();
+    noria::TlsConfig::new(&ca_pem, "noria.test")
+        .and_then(|tls| tls.with_identity(&cert_pem, &key_pem))
+        .and_then(|tls| tls.require_client_certs())
+        .unwrap()
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_encrypts_internal_connections() {
+    use noria::consensus::Authority;
+    use noria::{ControllerDescriptor, ControllerHandle};
+
+    let dir = tempfile::tempdir().unwrap();
+    let tls = issue_tls(&dir.path().join("deployment"));
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(Some(2));
+    builder.set_persistence(get_persistence_params("it_encrypts_internal_connections"));
+    builder.set_tls(tls.clone());
+    builder.set_worker_secret("hunter2");
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    // a second worker, so that domains also talk to each other across workers
+    let (g2, done2) = builder.start(authority.clone()).await.unwrap();
+    g.backend_ready().await;
+
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleByTitle: SELECT id, title FROM Article WHERE title = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    for i in 0..10 {
+        article
+            .insert(vec![i.into(), format!("a{}", i % 2).into()])
+            .await
+            .unwrap();
+    }
+    sleep().await;
+
+    // writes got from the tables through the shard mergers to the view
+    let mut by_title = g.view("ArticleByTitle").await.unwrap();
+    let mut ids: Vec<i32> = by_title
+        .lookup(&["a1".into()], true)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| i32::from(&row[0]))
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![1, 3, 5, 7, 9]);
+
+    // the controller's internal listeners do a real TLS handshake, and still want the worker
+    // secret once it is done
+    let (_, desc) = authority.get_leader().unwrap();
+    let desc: ControllerDescriptor = serde_json::from_slice(&desc).unwrap();
+    let sock = tokio::net::TcpStream::connect(desc.worker_addr)
+        .await
+        .unwrap();
+    let mut conn = tls.connect(sock).await.unwrap();
+    noria::channel::auth::prove_async(&mut conn, Some("hunter2"))
+        .await
+        .unwrap();
+    tokio::io::AsyncWriteExt::shutdown(&mut conn).await.unwrap();
+
+    // and turn away peers that don't trust the deployment's authority, or that it doesn't trust
+    let stranger = issue_tls(&dir.path().join("stranger"));
+    for &addr in &[desc.worker_addr, desc.domain_addr] {
+        let sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(stranger.connect(sock).await.is_err());
+    }
+
+    // clients that don't speak TLS don't get anywhere either
+    let mut plain = ControllerHandle::make(authority.clone()).await.unwrap();
+    assert!(plain.inputs().await.is_err());
+
+    drop(plain);
+    drop(by_title);
+    drop(article);
+    drop(g);
+    drop(g2);
+    done.await;
+    done2.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_enforces_roles() {
+    use noria::{ConnectOptions, ControllerHandle, Principal, Role};
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_enforces_roles"));
+    builder.set_auth_token("s3cret");
+    builder.set_anonymous_roles(vec![Role::Reader]);
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    g.backend_ready().await;
+    g.install_recipe("CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    let ops_token = g
+        .set_principal("ops", vec![Role::SchemaAdmin])
+        .await
+        .unwrap()
+        .expect("new principals get a token");
+    let sec_token = g
+        .set_principal("sec", vec![Role::SecurityAdmin, Role::Writer])
+        .await
+        .unwrap()
+        .unwrap();
+    // changing roles keeps the token
+    assert_eq!(
+        g.set_principal("sec", vec![Role::SecurityAdmin])
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        g.principals()
+            .await
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec![
+            ("ops".to_owned(), vec![Role::SchemaAdmin]),
+            ("sec".to_owned(), vec![Role::SecurityAdmin]),
+        ]
+    );
+
+    let connect = |token: String| {
+        let authority = authority.clone();
+        async move {
+            let mut c = ControllerHandle::make_with(
+                authority,
+                ConnectOptions::default().with_auth_token(token),
+            )
+            .await
+            .unwrap();
+            c.ready().await.unwrap();
+            c
+        }
+    };
+    let mut ops = connect(ops_token).await;
+    let mut sec = connect(sec_token).await;
+    let mut anon = ControllerHandle::make(authority.clone()).await.unwrap();
+    anon.ready().await.unwrap();
+
+    // anonymous clients may only read
+    assert!(anon.inputs().await.is_ok());
+    assert!(anon.table("Article").await.is_err());
+
+    // schema admins may change the recipe, but not security settings or principals
+    ops.extend_recipe("QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;")
+        .await
+        .unwrap();
+    assert!(ops.view("ArticleById").await.is_ok());
+    assert!(ops.table("Article").await.is_err());
+    assert!(ops
+        .rpc::<_, ()>("set_security_config", "", "failed to set security config")
+        .await
+        .is_err());
+    assert!(ops
+        .set_principal("ops", vec![Role::SecurityAdmin])
+        .await
+        .is_err());
+
+    // security admins can't change the recipe, but can see what was denied
+    assert!(sec
+        .extend_recipe("QUERY AllArticles: SELECT id, title FROM Article;")
+        .await
+        .is_err());
+    let denied: Vec<_> = sec
+        .audit_log()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|e| !e.allowed)
+        .map(|e| (e.principal, e.endpoint))
+        .collect();
+    assert_eq!(
+        denied,
+        vec![
+            (Principal::Anonymous, "/table_builder".to_owned()),
+            (
+                Principal::Named("ops".to_owned()),
+                "/table_builder".to_owned()
+            ),
+            (
+                Principal::Named("ops".to_owned()),
+                "/set_security_config".to_owned()
+            ),
+            (
+                Principal::Named("ops".to_owned()),
+                "/set_principal".to_owned()
+            ),
+            (
+                Principal::Named("sec".to_owned()),
+                "/extend_recipe".to_owned()
+            ),
+        ]
+    );
+
+    // removed principals lose access entirely
+    sec.remove_principal("ops").await.unwrap();
+    assert!(ops.view("ArticleById").await.is_err());
+
+    drop(ops);
+    drop(sec);
+    drop(anon);
+    drop(g);
+    done.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_evicts_views_over_their_memory_limit() {
+    use noria::debug::stats::CacheStats;
+
+    let mut g = start_simple_unsharded("it_evicts_views_over_their_memory_limit").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById WITH (memory_limit = '1KB', priority = low):
+            SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    let mut article = g.table("Article").await.unwrap();
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    let title = "x".repeat(200);
+    for i in 0..32 {
+        article
+            .insert(vec![i.into(), title.clone().into()])
+            .await
+            .unwrap();
+    }
+    sleep().await;
+
+    // fill the view well beyond its limit
+    for i in 0..32 {
+        assert_eq!(by_id.lookup(&[i.into()], true).await.unwrap().len(), 1);
+    }
+
+    // give the worker a chance to notice, even though there is no global memory limit
+    tokio::time::delay_for(Duration::from_secs(3)).await;
+
+    let stats = g.statistics().await.unwrap();
+    let cache = stats
+        .values()
+        .flat_map(|(_, nodes)| nodes.values())
+        .map(|n| n.cache)
+        .fold(CacheStats::default(), |a, b| a + b);
+    assert!(cache.evictions > 0);
+
+    // evicted keys are simply replayed again
+    assert_eq!(
+        by_id.lookup(&[0.into()], true).await.unwrap(),
+        vec![vec![0.into(), title.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_rebalances_domains_onto_new_workers() {
+    let authority = Arc::new(LocalAuthority::new());
+
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_rebalances_domains"));
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    let mut article = g.table("Article").await.unwrap();
+    for i in 0..10 {
+        article
+            .insert(vec![i.into(), format!("a{}", i).into()])
+            .await
+            .unwrap();
+    }
+    sleep().await;
+
+    // a second worker joins, but gets nothing to do until we rebalance
+    let (g2, done2) = builder.start(authority.clone()).await.unwrap();
+    let mut moves = Vec::new();
+    for _ in 0..50 {
+        moves = g.rebalance().await.unwrap();
+        if !moves.is_empty() {
+            break;
+        }
+        sleep().await;
+    }
+    assert!(!moves.is_empty());
+
+    // the old table handle still works, and so does a new one
+    article.insert(vec![10.into(), "a10".into()]).await.unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    article.insert(vec![11.into(), "a11".into()]).await.unwrap();
+    sleep().await;
+
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    for i in &[0, 9, 10, 11] {
+        assert_eq!(
+            by_id.lookup(&[(*i).into()], true).await.unwrap(),
+            vec![vec![(*i).into(), format!("a{}", i).into()]]
+        );
+    }
+
+    drop(g);
+    drop(g2);
+    done.await;
+    done2.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_reshards_views() {
+    use noria::builders::{TableBuilder, ViewBuilder};
+
+    let mut g = start_simple("it_reshards_views").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Big (id int, title varchar(255), PRIMARY KEY(id)) WITH (shards = 4);
+        CREATE TABLE Small (id int, title varchar(255), PRIMARY KEY(id)) WITH (shards = 1);
+        QUERY BigById: SELECT id, title FROM Big WHERE id = ?;
+        QUERY SmallById WITH (shards = 1): SELECT id, title FROM Small WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    // tables and views are split as many ways as the recipe asks, and views follow their tables
+    let big: Option<TableBuilder> = g.rpc("table_builder", "Big", "no table").await.unwrap();
+    assert_eq!(big.unwrap().txs.len(), 4);
+    let small: Option<TableBuilder> = g.rpc("table_builder", "Small", "no table").await.unwrap();
+    assert_eq!(small.unwrap().txs.len(), 1);
+    let by_id: Option<ViewBuilder> = g.rpc("view_builder", "BigById", "no view").await.unwrap();
+    assert_eq!(by_id.unwrap().shards.len(), 4);
+    let by_id: Option<ViewBuilder> = g.rpc("view_builder", "SmallById", "no view").await.unwrap();
+    assert_eq!(by_id.unwrap().shards.len(), 1);
+
+    let mut big = g.table("Big").await.unwrap();
+    for i in 0..10 {
+        big.insert(vec![i.into(), format!("b{}", i).into()])
+            .await
+            .unwrap();
+    }
+    sleep().await;
+
+    g.reshard("BigById", 3).await.unwrap();
+    let by_id: Option<ViewBuilder> = g.rpc("view_builder", "BigById", "no view").await.unwrap();
+    assert_eq!(by_id.unwrap().shards.len(), 3);
+
+    // the new reader has all the data, and keeps up with new writes
+    big.insert(vec![10.into(), "b10".into()]).await.unwrap();
+    sleep().await;
+    let mut by_id = g.view("BigById").await.unwrap();
+    for i in &[0, 9, 10] {
+        assert_eq!(
+            by_id.lookup(&[(*i).into()], true).await.unwrap(),
+            vec![vec![(*i).into(), format!("b{}", i).into()]]
+        );
+    }
+
+    // a table's rows move to a base with the new shard count while writes keep going through,
+    // and the views on top of it are rebuilt with the options they had
+    g.reshard("Big", 4).await.unwrap();
+    let mut writer = big.clone();
+    let writes = tokio::spawn(async move {
+        for i in 100..200 {
+            writer
+                .insert(vec![i.into(), format!("b{}", i).into()])
+                .await
+                .unwrap();
+        }
+    });
+    g.reshard("Big", 8).await.unwrap();
+    writes.await.unwrap();
+    let builder: Option<TableBuilder> = g.rpc("table_builder", "Big", "no table").await.unwrap();
+    assert_eq!(builder.unwrap().txs.len(), 8);
+    let by_id: Option<ViewBuilder> = g.rpc("view_builder", "BigById", "no view").await.unwrap();
+    assert_eq!(by_id.unwrap().shards.len(), 3);
+
+    // handles fetched before the move keep working, and so do new ones
+    big.insert(vec![11.into(), "b11".into()]).await.unwrap();
+    let mut big = g.table("Big").await.unwrap();
+    big.insert(vec![12.into(), "b12".into()]).await.unwrap();
+    sleep().await;
+    let mut by_id = g.view("BigById").await.unwrap();
+    for i in &[0, 9, 10, 11, 12, 100, 150, 199] {
+        assert_eq!(
+            by_id.lookup(&[(*i).into()], true).await.unwrap(),
+            vec![vec![(*i).into(), format!("b{}", i).into()]]
+        );
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_explains_recipes() {
+    use noria::internal::MaterializationStatus;
+
+    let mut g = start_simple("it_explains_recipes").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    article.insert(vec![1.into(), "a".into()]).await.unwrap();
+    sleep().await;
+
+    let addition =
+        "QUERY CountByTitle: SELECT title, COUNT(id) AS n FROM Article WHERE title = ? GROUP BY title;";
+    let explanation = g.explain_recipe(addition).await.unwrap();
+    assert!(explanation.queries.contains(&"CountByTitle".to_owned()));
+    assert!(explanation.reused_nodes.contains(&"Article".to_owned()));
+    assert!(!explanation.new_nodes.is_empty());
+    assert!(explanation
+        .new_nodes
+        .iter()
+        .any(|n| n.materialized != MaterializationStatus::Not));
+
+    // nothing actually changed
+    let outputs = g.outputs().await.unwrap();
+    assert!(outputs.contains_key("ArticleById"));
+    assert!(!outputs.contains_key("CountByTitle"));
+
+    // queries that can't be planned are reported, and leave the graph intact
+    assert!(g
+        .explain_recipe("QUERY Bad: SELECT id FROM Missing WHERE id = ?;")
+        .await
+        .is_err());
+    g.extend_recipe(addition).await.unwrap();
+    article.insert(vec![2.into(), "a".into()]).await.unwrap();
+    sleep().await;
+    let mut count = g.view("CountByTitle").await.unwrap();
+    assert_eq!(
+        count.lookup(&["a".into()], true).await.unwrap(),
+        vec![vec!["a".into(), 2.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_rolls_back_recipes() {
+    use noria::RecipeChange;
+
+    let mut g = start_simple("it_rolls_back_recipes").await;
+    g.install_recipe("CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+    g.extend_recipe("QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;")
+        .await
+        .unwrap();
+    g.extend_recipe("QUERY ArticleByTitle: SELECT id, title FROM Article WHERE title = ?;")
+        .await
+        .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    article.insert(vec![1.into(), "a".into()]).await.unwrap();
+    sleep().await;
+
+    let history = g.recipe_history().await.unwrap();
+    assert_eq!(
+        history.iter().map(|v| v.change).collect::<Vec<_>>(),
+        vec![
+            RecipeChange::Install,
+            RecipeChange::Extend,
+            RecipeChange::Extend
+        ]
+    );
+    assert!(history[2].text.contains("ArticleByTitle"));
+    assert!(history.windows(2).all(|w| w[0].version < w[1].version));
+
+    // go back to before ArticleByTitle was added
+    let ra = g.rollback_to(history[1].version).await.unwrap();
+    assert_eq!(ra.expressions_removed, 1);
+    let outputs = g.outputs().await.unwrap();
+    assert!(outputs.contains_key("ArticleById"));
+    assert!(!outputs.contains_key("ArticleByTitle"));
+
+    // the rollback is itself a version, and can be undone
+    let history = g.recipe_history().await.unwrap();
+    assert_eq!(history.len(), 4);
+    assert_eq!(
+        history[3].change,
+        RecipeChange::Rollback(history[1].version)
+    );
+    g.rollback_to(history[2].version).await.unwrap();
+    let mut by_title = g.view("ArticleByTitle").await.unwrap();
+    assert_eq!(
+        by_title.lookup(&["a".into()], true).await.unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+
+    // versions that don't exist yet can't be rolled back to
+    assert!(g.rollback_to(history[3].version + 10).await.is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_drops_tables_and_views() {
+    let mut g = start_simple("it_drops_tables_and_views").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        CREATE TABLE Vote (article_id int, user int);
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+        QUERY VoteCount: SELECT article_id, COUNT(user) AS votes FROM Vote WHERE article_id = ? GROUP BY article_id;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut vote = g.table("Vote").await.unwrap();
+    vote.insert(vec![1.into(), 1.into()]).await.unwrap();
+    sleep().await;
+
+    // tables that queries still read from can't be dropped
+    assert!(g.extend_recipe("DROP TABLE Vote;").await.is_err());
+    assert!(g.outputs().await.unwrap().contains_key("VoteCount"));
+
+    let ra = g
+        .extend_recipe("DROP VIEW VoteCount;\nDROP TABLE Vote;")
+        .await
+        .unwrap();
+    assert_eq!(ra.expressions_removed, 2);
+    assert!(!g.outputs().await.unwrap().contains_key("VoteCount"));
+    assert!(!g.inputs().await.unwrap().contains_key("Vote"));
+    assert!(g.inputs().await.unwrap().contains_key("Article"));
+
+    // the rest of the graph keeps working
+    let mut article = g.table("Article").await.unwrap();
+    article.insert(vec![1.into(), "a".into()]).await.unwrap();
+    sleep().await;
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    assert_eq!(
+        by_id.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+
+    // a table created again under the same name starts out empty
+    g.extend_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Voters: SELECT user FROM Vote WHERE article_id = ?;",
+    )
+    .await
+    .unwrap();
+    let mut voters = g.view("Voters").await.unwrap();
+    assert!(voters.lookup(&[1.into()], true).await.unwrap().is_empty());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_rejects_unsupported_recipes() {
+    use noria::error::RecipeError;
+
+    let mut g = start_simple("it_rejects_unsupported_recipes").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    // unsupported queries are reported by name, and don't take down the controller
+    let err = g
+        .extend_recipe("QUERY Plus: SELECT id FROM Article WHERE id + 1 = ?;")
+        .await
+        .unwrap_err();
+    match err.downcast::<RecipeError>() {
+        Ok(RecipeError::Unsupported { ref query, .. }) => assert_eq!(query, "Plus"),
+        e => panic!("unexpected error: {:?}", e),
+    }
+    let err = g
+        .extend_recipe("QUERY Missing: SELECT id FROM Missing WHERE id = ?;")
+        .await
+        .unwrap_err();
+    match err.downcast::<RecipeError>() {
+        Ok(RecipeError::Invalid { ref query, .. }) => assert_eq!(query, "Missing"),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    // the existing recipe is left as it was
+    let outputs = g.outputs().await.unwrap();
+    assert!(outputs.contains_key("ArticleById"));
+    assert!(!outputs.contains_key("Plus"));
+    assert!(!outputs.contains_key("Missing"));
+
+    g.extend_recipe("QUERY ArticleByTitle: SELECT id, title FROM Article WHERE title = ?;")
+        .await
+        .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    article.insert(vec![1.into(), "a".into()]).await.unwrap();
+    sleep().await;
+    let mut by_title = g.view("ArticleByTitle").await.unwrap();
+    assert_eq!(
+        by_title.lookup(&["a".into()], true).await.unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_partitions_tables() {
+    use noria::builders::{TableBuilder, ViewBuilder};
+    use noria::KeyPartitioning;
+
+    let mut g = start_simple("it_partitions_tables").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Reading (ts int, val int) WITH (partition_by = ts, partition_bounds = '100, 200');
+        CREATE TABLE Edge (src int, dst int, weight int, PRIMARY KEY(src, dst));
+        QUERY ReadingsAt: SELECT ts, val FROM Reading WHERE ts = ?;
+        QUERY EdgeWeight: SELECT src, dst, weight FROM Edge WHERE src = ? AND dst = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    // a range partitioned table has one more shard than it has bounds, and so do its views
+    let reading: Option<TableBuilder> =
+        g.rpc("table_builder", "Reading", "no table").await.unwrap();
+    assert_eq!(reading.unwrap().txs.len(), 3);
+    let at: Option<ViewBuilder> = g
+        .rpc("view_builder", "ReadingsAt", "no view")
+        .await
+        .unwrap();
+    let at = at.unwrap();
+    assert_eq!(at.shards.len(), 3);
+    assert!(matches!(at.partitioning, KeyPartitioning::Range(..)));
+
+    // a table with a compound key is hashed on all of its key columns
+    let edge: Option<TableBuilder> = g.rpc("table_builder", "Edge", "no table").await.unwrap();
+    assert_eq!(edge.unwrap().txs.len(), DEFAULT_SHARDING);
+    let weight: Option<ViewBuilder> = g
+        .rpc("view_builder", "EdgeWeight", "no view")
+        .await
+        .unwrap();
+    assert_eq!(weight.unwrap().shards.len(), DEFAULT_SHARDING);
+
+    let mut reading = g.table("Reading").await.unwrap();
+    for &ts in &[50, 100, 150, 250] {
+        reading
+            .insert(vec![ts.into(), (ts * 2).into()])
+            .await
+            .unwrap();
+    }
+    let mut edge = g.table("Edge").await.unwrap();
+    for src in 0..4 {
+        for dst in 0..4 {
+            edge.insert(vec![src.into(), dst.into(), (src * 10 + dst).into()])
+                .await
+                .unwrap();
+        }
+    }
+    sleep().await;
+
+    let mut at = g.view("ReadingsAt").await.unwrap();
+    for &ts in &[50, 100, 150, 250] {
+        assert_eq!(
+            at.lookup(&[ts.into()], true).await.unwrap(),
+            vec![vec![ts.into(), (ts * 2).into()]]
+        );
+    }
+    assert!(at.lookup(&[199.into()], true).await.unwrap().is_empty());
+
+    let mut weight = g.view("EdgeWeight").await.unwrap();
+    for &(src, dst) in &[(0, 0), (1, 3), (3, 2)] {
+        assert_eq!(
+            weight
+                .lookup(&[src.into(), dst.into()], true)
+                .await
+                .unwrap(),
+            vec![vec![src.into(), dst.into(), (src * 10 + dst).into()]]
+        );
+    }
+
+    // and updates by key find the right shard
+    edge.update(
+        vec![1.into(), 3.into()],
+        vec![(2, noria::Modification::Set(0.into()))],
+    )
+    .await
+    .unwrap();
+    sleep().await;
+    assert_eq!(
+        weight.lookup(&[1.into(), 3.into()], true).await.unwrap(),
+        vec![vec![1.into(), 3.into(), 0.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_shards_by_any_column_type() {
+    let mut g = start_simple("it_shards_by_any_column_type").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Event (ts datetime, id int) \
+            WITH (partition_by = ts, partition_bounds = '2019-01-01, 2020-01-01');
+        CREATE TABLE Price (item int, at double, price int, PRIMARY KEY(item, at));
+        QUERY EventsAt: SELECT ts, id FROM Event WHERE ts = ?;
+        QUERY PriceAt: SELECT item, at, price FROM Price WHERE item = ? AND at = ?;
+    ",
+    )
+    .await
+    .unwrap();
+
+    // range partitioned values are routed by what they represent in the column's type, so a
+    // timestamp given as text ends up in the same shard as the equivalent timestamp, and NULL
+    // has a shard of its own
+    let mut event = g.table("Event").await.unwrap();
+    for (i, &ts) in [
+        "2018-06-01 00:00:00",
+        "2019-06-01 00:00:00",
+        "2020-06-01 00:00:00",
+    ]
+    .iter()
+    .enumerate()
+    {
+        event
+            .insert(vec![ts.into(), (i as i32).into()])
+            .await
+            .unwrap();
+    }
+    event.insert(vec![DataType::None, 3.into()]).await.unwrap();
+
+    // a compound key can include columns that aren't integers or text
+    let mut price = g.table("Price").await.unwrap();
+    price
+        .insert(vec![1.into(), 2.5.into(), 10.into()])
+        .await
+        .unwrap();
+    sleep().await;
+
+    let mut at = g.view("EventsAt").await.unwrap();
+    for (i, &ts) in [
+        "2018-06-01 00:00:00",
+        "2019-06-01 00:00:00",
+        "2020-06-01 00:00:00",
+    ]
+    .iter()
+    .enumerate()
+    {
+        assert_eq!(
+            at.lookup(&[ts.into()], true).await.unwrap(),
+            vec![vec![ts.into(), (i as i32).into()]]
+        );
+    }
+    assert_eq!(
+        at.lookup(&[DataType::None], true).await.unwrap(),
+        vec![vec![DataType::None, 3.into()]]
+    );
+
+    let mut price_at = g.view("PriceAt").await.unwrap();
+    assert_eq!(
+        price_at
+            .lookup(&[1.into(), 2.5.into()], true)
+            .await
+            .unwrap(),
+        vec![vec![1.into(), 2.5.into(), 10.into()]]
+    );
+}
+
 #[tokio::test(threaded_scheduler)]
 async fn it_works_deletion() {
     // set up graph
@@ -2573,6 +4389,66 @@ async fn recipe_activates_and_migrates_with_join() {
     assert_eq!(g.outputs().await.unwrap().len(), 1);
 }
 
+#[tokio::test(threaded_scheduler)]
+async fn recipe_alters_tables() {
+    let r_txt = "CREATE TABLE t (id int, n int, PRIMARY KEY(id));\n
+                 QUERY ById: SELECT id, n FROM t WHERE id = ?;";
+
+    let mut g = start_simple("recipe_alters_tables").await;
+    g.install_recipe(r_txt).await.unwrap();
+
+    let mut mutator = g.table("t").await.unwrap();
+    mutator.insert(vec![1.into(), 42.into()]).await.unwrap();
+    sleep().await;
+
+    // existing rows are converted, and the change is reflected downstream
+    g.extend_recipe("ALTER TABLE t MODIFY COLUMN n text;")
+        .await
+        .unwrap();
+    assert_eq!(g.inputs().await.unwrap().len(), 1);
+    sleep().await;
+    let mut getter = g.view("ById").await.unwrap();
+    assert_eq!(
+        getter.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "42".into()]]
+    );
+
+    // renaming a column keeps the data
+    g.extend_recipe(
+        "ALTER TABLE t RENAME COLUMN n TO name;\n
+         QUERY NameById: SELECT id, name FROM t WHERE id = ?;",
+    )
+    .await
+    .unwrap();
+    let mut mutator = g.table("t").await.unwrap();
+    assert_eq!(mutator.columns(), &["id", "name"]);
+    mutator.insert(vec![2.into(), "bob".into()]).await.unwrap();
+    sleep().await;
+    let mut getter = g.view("NameById").await.unwrap();
+    assert_eq!(
+        getter.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "42".into()]]
+    );
+    assert_eq!(
+        getter.lookup(&[2.into()], true).await.unwrap(),
+        vec![vec![2.into(), "bob".into()]]
+    );
+
+    // queries that read the renamed column are planned again, and keep their output columns
+    let mut getter = g.view("ById").await.unwrap();
+    assert_eq!(getter.columns(), &["id", "n"]);
+    assert_eq!(
+        getter.lookup(&[2.into()], true).await.unwrap(),
+        vec![vec![2.into(), "bob".into()]]
+    );
+
+    // tables that don't exist can't be altered
+    assert!(g
+        .extend_recipe("ALTER TABLE nope RENAME COLUMN a TO b;")
+        .await
+        .is_err());
+}
+
 async fn test_queries(test: &str, file: &'static str, shard: bool, reuse: bool, log: bool) {
     use crate::logger_pls;
     use std::fs::File;
@@ -2959,3 +4835,126 @@ async fn correct_nested_view_schema() {
     ];
     assert_eq!(q.schema(), Some(&expected_schema[..]));
 }
+
+#[tokio::test(threaded_scheduler)]
+async fn it_logs_partial_replays() {
+    let mut g = start_simple_unsharded("it_logs_partial_replays").await;
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    let mut by_id = g.view("ArticleById").await.unwrap();
+    article
+        .insert(vec![1.into(), "Hello".into()])
+        .await
+        .unwrap();
+    sleep().await;
+    assert!(g.replay_log().await.unwrap().is_empty());
+
+    assert_eq!(
+        by_id.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "Hello".into()]]
+    );
+
+    // the lookup missed in the reader, and the replay that filled it brought in the one row
+    let log = g.replay_log().await.unwrap();
+    assert_eq!(log.len(), 1);
+    let replay = &log[0];
+    assert_eq!(replay.keys, 1);
+    assert_eq!(replay.rows, 1);
+    assert_eq!(replay.path.last().unwrap().node, replay.node);
+    assert!(replay.duration > Duration::from_secs(0));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_serves_dashboard() {
+    use noria::consensus::{Authority, CONTROLLER_KEY};
+    use noria::ControllerDescriptor;
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("it_serves_dashboard"));
+    let (mut g, done) = builder.start(authority.clone()).await.unwrap();
+    g.backend_ready().await;
+
+    g.install_recipe(
+        "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+    ",
+    )
+    .await
+    .unwrap();
+    let mut article = g.table("Article").await.unwrap();
+    article
+        .insert(vec![1.into(), "Hello".into()])
+        .await
+        .unwrap();
+    sleep().await;
+
+    let descriptor: ControllerDescriptor =
+        serde_json::from_slice(&authority.try_read(CONTROLLER_KEY).unwrap().unwrap()).unwrap();
+    let get = |path: &str| {
+        let uri = format!("http://{}/{}", descriptor.external_addr, path)
+            .parse()
+            .unwrap();
+        async move {
+            let res = hyper::Client::new().get(uri).await.unwrap();
+            assert_eq!(res.status(), hyper::StatusCode::OK);
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+
+    assert!(get("dashboard.html").await.contains("dashboard_totals"));
+
+    let nodes: Vec<serde_json::Value> =
+        serde_json::from_str(&get("dashboard_nodes").await).unwrap();
+    // ingresses, egresses and readers are named after the node they come after
+    let named = |name: &str| nodes.iter().filter(move |n| n["name"] == name);
+
+    // the base knows which table it came from
+    let base = named("Article")
+        .find(|n| n["parents"].as_array().unwrap().is_empty())
+        .unwrap();
+    assert_eq!(base["materialized"], "Full");
+    assert_eq!(base["workers"].as_array().unwrap().len(), 1);
+    let queries = base["queries"].as_array().unwrap();
+    assert!(queries
+        .iter()
+        .any(|q| q.as_str().unwrap().starts_with("CREATE TABLE")));
+    assert!(!base["mir"].as_array().unwrap().is_empty());
+
+    // the query's nodes hang off the base, and know which query they belong to
+    assert!(named("ArticleById").count() >= 2);
+    for n in named("ArticleById") {
+        assert!(!n["parents"].as_array().unwrap().is_empty());
+        let queries = n["queries"].as_array().unwrap();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].as_str().unwrap().starts_with("SELECT"));
+    }
+
+    // the base processed the one write
+    let totals: serde_json::Value = serde_json::from_str(&get("dashboard_totals").await).unwrap();
+    let id = base["node"].to_string();
+    assert_eq!(totals["nodes"][&id]["records"], 1);
+    let generation = totals["generation"].clone();
+    // totals that were just gathered are served again, until the graph changes
+    let again: serde_json::Value = serde_json::from_str(&get("dashboard_totals").await).unwrap();
+    assert_eq!(again["at"], totals["at"]);
+    g.extend_recipe("QUERY ArticleByTitle: SELECT id FROM Article WHERE title = ?;")
+        .await
+        .unwrap();
+    let again: serde_json::Value = serde_json::from_str(&get("dashboard_totals").await).unwrap();
+    assert_ne!(again["generation"], generation);
+
+    drop(article);
+    drop(g);
+    done.await;
+}
@@ -357,11 +357,14 @@ extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 
+mod access;
 mod builder;
 mod controller;
 mod coordination;
 mod handle;
+mod metrics;
 mod startup;
+mod tracing;
 mod worker;
 
 #[cfg(test)]
@@ -378,12 +381,22 @@ pub enum ReuseConfigType {
 
 pub use crate::builder::Builder;
 pub use crate::handle::Handle;
-pub use controller::migrate::materialization::FrontierStrategy;
-pub use dataflow::{DurabilityMode, PersistenceParameters};
+pub use crate::tracing::TraceExport;
+pub use controller::migrate::materialization::{FrontierStrategy, PartialStorageStrategy};
+pub use dataflow::{DurabilityMode, PersistenceParameters, PersistentTableFormat};
+pub use dataflow::{EvictionPolicy, RandomEviction, SampledLru};
 pub use noria::consensus::LocalAuthority;
 pub use noria::*;
 pub use petgraph::graph::NodeIndex;
 
+/// Types needed to implement custom storage backends for base tables.
+pub mod storage {
+    pub use dataflow::prelude::{KeyType, Record, Records, SizeOf};
+    pub use dataflow::{
+        BaseStorage, KeyRange, StorageBackend, StorageBackends, MEMORY_BACKEND, ROCKSDB_BACKEND,
+    };
+}
+
 #[doc(hidden)]
 pub mod manual {
     pub use crate::controller::migrate::Migration;
@@ -399,10 +412,13 @@ pub(crate) struct Config {
     pub(crate) sharding: Option<usize>,
     pub(crate) partial_enabled: bool,
     pub(crate) frontier_strategy: FrontierStrategy,
+    pub(crate) partial_storage: PartialStorageStrategy,
     pub(crate) domain_config: DomainConfig,
     pub(crate) persistence: PersistenceParameters,
     pub(crate) heartbeat_every: time::Duration,
     pub(crate) healthcheck_every: time::Duration,
+    pub(crate) rebalance_every: Option<time::Duration>,
+    pub(crate) universe_idle_timeout: Option<time::Duration>,
     pub(crate) quorum: usize,
     pub(crate) reuse: ReuseConfigType,
     pub(crate) threads: Option<usize>,
@@ -416,13 +432,18 @@ impl Default for Config {
             sharding: None,
             partial_enabled: true,
             frontier_strategy: Default::default(),
+            partial_storage: Default::default(),
             domain_config: DomainConfig {
                 concurrent_replays: 512,
                 replay_batch_timeout: time::Duration::new(0, 100_000),
+                retention_interval: time::Duration::from_secs(60),
+                slow_replay_threshold: time::Duration::from_secs(1),
             },
             persistence: Default::default(),
             heartbeat_every: time::Duration::from_secs(1),
             healthcheck_every: time::Duration::from_secs(10),
+            rebalance_every: None,
+            universe_idle_timeout: None,
             quorum: 1,
             reuse: ReuseConfigType::Finkelstein,
             #[cfg(any(debug_assertions, test))]
@@ -1,5 +1,5 @@
 use clap::value_t_or_exit;
-use noria_server::{Builder, ReuseConfigType, ZookeeperAuthority};
+use noria_server::{Builder, ReuseConfigType, TlsConfig, TraceExport, ZookeeperAuthority};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
@@ -38,6 +38,14 @@ fn main() {
                 .default_value("1")
                 .help("Number of background threads used by RocksDB."),
         )
+        .arg(
+            Arg::with_name("table-format")
+                .long("table-format")
+                .takes_value(true)
+                .possible_values(&["plain", "block"])
+                .default_value("plain")
+                .help("RocksDB table format for base tables [block = supports range scans]."),
+        )
         .arg(
             Arg::with_name("flush-timeout")
                 .long("flush-timeout")
@@ -100,6 +108,72 @@ fn main() {
                 .default_value("0")
                 .help("Shard the graph this many ways (0 = disable sharding)."),
         )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .takes_value(true)
+                .requires_all(&["tls-cert", "tls-key"])
+                .help("PEM file with the certificate authority for TLS [enables TLS]."),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls-ca")
+                .help("PEM file with this server's TLS certificate chain."),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-ca")
+                .help("PEM file with this server's TLS private key."),
+        )
+        .arg(
+            Arg::with_name("tls-name")
+                .long("tls-name")
+                .takes_value(true)
+                .default_value("noria")
+                .help("Name that server certificates are issued for."),
+        )
+        .arg(
+            Arg::with_name("tls-client-auth")
+                .long("tls-client-auth")
+                .requires("tls-ca")
+                .help("Require clients to present a certificate signed by the TLS authority."),
+        )
+        .arg(
+            Arg::with_name("auth-token")
+                .long("auth-token")
+                .takes_value(true)
+                .help("Token that holds every role [enables access control]."),
+        )
+        .arg(
+            Arg::with_name("worker-secret")
+                .long("worker-secret")
+                .takes_value(true)
+                .help("Secret workers must share to join the deployment."),
+        )
+        .arg(
+            Arg::with_name("trace-to")
+                .long("trace-to")
+                .takes_value(true)
+                .help("File or http:// collector URL to export write traces to [enables tracing]."),
+        )
+        .arg(
+            Arg::with_name("trace-sample-rate")
+                .long("trace-sample-rate")
+                .takes_value(true)
+                .default_value("0.01")
+                .help("Fraction of writes to trace."),
+        )
+        .arg(
+            Arg::with_name("slow-replay-ms")
+                .long("slow-replay-ms")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Log partial replays that take longer than this many milliseconds."),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -142,6 +216,40 @@ fn main() {
         builder.set_reuse(ReuseConfigType::NoReuse);
     }
 
+    if let Some(ca) = matches.value_of("tls-ca") {
+        let mut tls = TlsConfig::new(ca, matches.value_of("tls-name").unwrap())
+            .and_then(|tls| {
+                tls.with_identity(
+                    matches.value_of("tls-cert").unwrap(),
+                    matches.value_of("tls-key").unwrap(),
+                )
+            })
+            .unwrap();
+        if matches.is_present("tls-client-auth") {
+            tls = tls.require_client_certs().unwrap();
+        }
+        builder.set_tls(tls);
+    }
+    if let Some(token) = matches.value_of("auth-token") {
+        builder.set_auth_token(token);
+    }
+    if let Some(secret) = matches.value_of("worker-secret") {
+        builder.set_worker_secret(secret);
+    }
+    if let Some(to) = matches.value_of("trace-to") {
+        let export = if to.starts_with("http://") {
+            TraceExport::Collector(to.to_owned())
+        } else {
+            TraceExport::File(PathBuf::from(to))
+        };
+        builder.set_tracing(value_t_or_exit!(matches, "trace-sample-rate", f64), export);
+    }
+    builder.set_slow_replay_threshold(Duration::from_millis(value_t_or_exit!(
+        matches,
+        "slow-replay-ms",
+        u64
+    )));
+
     let mut persistence_params = noria_server::PersistenceParameters::new(
         match durability {
             "persistent" => noria_server::DurabilityMode::Permanent,
@@ -156,6 +264,11 @@ fn main() {
     persistence_params.log_dir = matches
         .value_of("log-dir")
         .and_then(|p| Some(PathBuf::from(p)));
+    persistence_params.table_format = match matches.value_of("table-format").unwrap() {
+        "plain" => noria_server::PersistentTableFormat::Plain,
+        "block" => noria_server::PersistentTableFormat::BlockBased,
+        _ => unreachable!(),
+    };
     builder.set_persistence(persistence_params);
 
     if verbose {
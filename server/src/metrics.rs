@@ -0,0 +1,232 @@
+//! What an instance reports at `/metrics`.
+//!
+//! Every instance reports on the domains its worker runs and on the client requests it has
+//! served. The instance that is currently the controller also reports on the deployment as a
+//! whole.
+
+use dataflow::metrics::{self, DomainMetrics, Exposition, Histogram};
+use noria::internal::DomainIndex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The deployment as the controller last saw it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ControllerGauges {
+    pub(crate) healthy_workers: usize,
+    pub(crate) unhealthy_workers: usize,
+    pub(crate) domains: usize,
+    pub(crate) nodes: usize,
+}
+
+pub(crate) struct Metrics {
+    domains: Mutex<HashMap<(DomainIndex, usize), Arc<DomainMetrics>>>,
+    /// Latencies of requests to the controller, by endpoint.
+    rpcs: Mutex<BTreeMap<String, Arc<Histogram>>>,
+    view_reads: Histogram,
+    table_writes: Histogram,
+    controller: Mutex<Option<ControllerGauges>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            domains: Default::default(),
+            rpcs: Default::default(),
+            view_reads: Histogram::new(metrics::LATENCY_BUCKETS),
+            table_writes: Histogram::new(metrics::LATENCY_BUCKETS),
+            controller: Default::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Start reporting on a domain shard booted by this instance's worker.
+    pub(crate) fn add_domain(&self, domain: (DomainIndex, usize)) -> Arc<DomainMetrics> {
+        let m = Arc::new(DomainMetrics::default());
+        self.domains.lock().unwrap().insert(domain, m.clone());
+        m
+    }
+
+    /// Stop reporting on a domain shard whose replica has exited, unless the shard has since been
+    /// booted here again and reports through other metrics.
+    pub(crate) fn remove_domain(&self, domain: (DomainIndex, usize), m: &Arc<DomainMetrics>) {
+        let mut domains = self.domains.lock().unwrap();
+        if domains.get(&domain).map_or(false, |d| Arc::ptr_eq(d, m)) {
+            domains.remove(&domain);
+        }
+    }
+
+    /// A request to the controller endpoint at `path` took `took`.
+    ///
+    /// Only endpoints the controller knows about should be recorded, since every endpoint gets
+    /// its own time series.
+    pub(crate) fn served_rpc(&self, path: &str, took: Duration) {
+        let h = self
+            .rpcs
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_insert_with(|| Arc::new(Histogram::new(metrics::LATENCY_BUCKETS)))
+            .clone();
+        h.observe_duration(took);
+    }
+
+    pub(crate) fn served_read(&self, took: Duration) {
+        self.view_reads.observe_duration(took);
+    }
+
+    pub(crate) fn acked_write(&self, took: Duration) {
+        self.table_writes.observe_duration(took);
+    }
+
+    /// Report on the deployment, or stop doing so if this instance is no longer the controller.
+    pub(crate) fn set_controller(&self, gauges: Option<ControllerGauges>) {
+        *self.controller.lock().unwrap() = gauges;
+    }
+
+    /// Everything, in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = Exposition::default();
+
+        let mut domains: Vec<_> = self
+            .domains
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&d, m)| (d, m.clone()))
+            .collect();
+        domains.sort_by_key(|&((di, shard), _)| (di.index(), shard));
+        metrics::render_domains(&domains, &mut out);
+
+        let name = "noria_controller_request_duration_seconds";
+        out.family(
+            name,
+            "histogram",
+            "Time taken to serve requests to the controller.",
+        );
+        let rpcs: Vec<_> = self
+            .rpcs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, h)| (path.clone(), h.clone()))
+            .collect();
+        for (path, h) in rpcs {
+            out.histogram(name, &[("endpoint", &path)], &h);
+        }
+
+        let name = "noria_view_read_duration_seconds";
+        out.family(
+            name,
+            "histogram",
+            "Time taken to serve reads from views, including any replays they waited for.",
+        );
+        out.histogram(name, &[], &self.view_reads);
+        let name = "noria_table_write_duration_seconds";
+        out.family(
+            name,
+            "histogram",
+            "Time from receiving a write to a base table until it was acknowledged.",
+        );
+        out.histogram(name, &[], &self.table_writes);
+
+        let controller = self.controller.lock().unwrap().clone();
+        out.family(
+            "noria_controller_leader",
+            "gauge",
+            "Whether this instance is the controller.",
+        );
+        out.sample(
+            "noria_controller_leader",
+            &[],
+            if controller.is_some() { 1.0 } else { 0.0 },
+        );
+        if let Some(c) = controller {
+            out.family(
+                "noria_controller_workers",
+                "gauge",
+                "Workers in the deployment, by whether they are healthy.",
+            );
+            out.sample(
+                "noria_controller_workers",
+                &[("state", "healthy")],
+                c.healthy_workers as f64,
+            );
+            out.sample(
+                "noria_controller_workers",
+                &[("state", "unhealthy")],
+                c.unhealthy_workers as f64,
+            );
+            out.family(
+                "noria_controller_domains",
+                "gauge",
+                "Domains in the data-flow graph.",
+            );
+            out.sample("noria_controller_domains", &[], c.domains as f64);
+            out.family(
+                "noria_controller_nodes",
+                "gauge",
+                "Nodes in the data-flow graph.",
+            );
+            out.sample("noria_controller_nodes", &[], c.nodes as f64);
+        }
+
+        out.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_instance_metrics() {
+        let m = Metrics::default();
+        m.add_domain((DomainIndex::from(0), 0));
+        m.served_rpc("/inputs", Duration::from_millis(2));
+        m.served_read(Duration::from_micros(50));
+
+        let out = m.render();
+        let has = |line: &str| out.lines().any(|l| l == line);
+        assert!(has(
+            "noria_domain_evicted_bytes_total{domain=\"0\",shard=\"0\"} 0"
+        ));
+        assert!(has(
+            "noria_controller_request_duration_seconds_count{endpoint=\"/inputs\"} 1"
+        ));
+        assert!(has(
+            "noria_view_read_duration_seconds_bucket{le=\"0.0001\"} 1"
+        ));
+        assert!(has("noria_controller_leader 0"));
+        assert!(!out.contains("noria_controller_workers"));
+
+        m.set_controller(Some(ControllerGauges {
+            healthy_workers: 2,
+            ..Default::default()
+        }));
+        let out = m.render();
+        assert!(out
+            .lines()
+            .any(|l| l == "noria_controller_workers{state=\"healthy\"} 2"));
+        assert!(out.lines().any(|l| l == "noria_controller_leader 1"));
+    }
+
+    #[test]
+    fn it_forgets_exited_domains() {
+        let m = Metrics::default();
+        let old = m.add_domain((DomainIndex::from(0), 0));
+        let new = m.add_domain((DomainIndex::from(0), 0));
+        let gone = m.add_domain((DomainIndex::from(1), 0));
+
+        // the shard was booted again before its old replica exited
+        m.remove_domain((DomainIndex::from(0), 0), &old);
+        m.remove_domain((DomainIndex::from(1), 0), &gone);
+
+        let out = m.render();
+        assert!(out.contains("domain=\"0\""));
+        assert!(!out.contains("domain=\"1\""));
+        m.remove_domain((DomainIndex::from(0), 0), &new);
+        assert!(!m.render().contains("domain=\"0\""));
+    }
+}
@@ -1,5 +1,8 @@
+use crate::access::{AccessConfig, Caller, PrincipalSource};
 use crate::controller::ControllerState;
 use crate::coordination::{CoordinationMessage, CoordinationPayload};
+use crate::metrics::Metrics;
+use crate::tracing::TraceExport;
 use async_bincode::AsyncBincodeReader;
 use futures_util::{
     future::FutureExt,
@@ -8,8 +11,9 @@ use futures_util::{
     stream::{StreamExt, TryStreamExt},
 };
 use hyper::{self, header::CONTENT_TYPE, Method, StatusCode};
-use noria::consensus::Authority;
-use noria::ControllerDescriptor;
+use noria::channel::tls::{self, MaybeTlsStream};
+use noria::consensus::{Authority, STATE_KEY};
+use noria::{ConnectOptions, ControllerDescriptor, Role, TlsConfig};
 use std::io;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
@@ -24,6 +28,8 @@ use tokio::sync::mpsc::UnboundedSender;
 
 use crate::handle::Handle;
 use crate::Config;
+use dataflow::tracing::Tracer;
+use dataflow::{EvictionPolicy, StorageBackends};
 
 #[allow(clippy::large_enum_variant)]
 pub(crate) enum Event {
@@ -33,6 +39,7 @@ pub(crate) enum Event {
         String,
         Option<String>,
         hyper::body::Bytes,
+        Caller,
         tokio::sync::oneshot::Sender<Result<Result<String, String>, StatusCode>>,
     ),
     LeaderChange(ControllerState, ControllerDescriptor),
@@ -70,6 +77,10 @@ pub(super) async fn start_instance<A: Authority + 'static>(
     config: Config,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    storage_backends: StorageBackends,
+    eviction_policy: Arc<dyn EvictionPolicy>,
+    tracing: Option<(f64, TraceExport)>,
+    access: AccessConfig,
     log: slog::Logger,
 ) -> Result<(Handle<A>, impl Future<Output = ()> + Unpin + Send), failure::Error> {
     let (trigger, valve) = Valve::new();
@@ -92,6 +103,38 @@ pub(super) async fn start_instance<A: Authority + 'static>(
     let cport = tokio::net::TcpListener::bind(SocketAddr::new(listen_addr, 0)).await?;
     let caddr = cport.local_addr()?;
 
+    // both "parts" of us report what they are up to at the external port's /metrics
+    let metrics = Arc::new(Metrics::default());
+    // and the worker "part" exports the spans of traced writes
+    let tracer = match tracing {
+        Some((sample_rate, export)) => {
+            let tracer = Arc::new(Tracer::new(sample_rate));
+            tokio::spawn(crate::tracing::export(
+                alive.clone(),
+                valve.clone(),
+                tracer.clone(),
+                export,
+                log.clone(),
+            ));
+            tracer
+        }
+        None => Arc::new(Tracer::default()),
+    };
+    // instances that aren't the controller can't ask it who a client is, so they look at the
+    // principals in the authority instead
+    let principals = {
+        let authority = authority.clone();
+        PrincipalSource::new(move || {
+            authority
+                .try_read(STATE_KEY)
+                .ok()
+                .and_then(|state| state)
+                .and_then(|state| serde_json::from_slice::<ControllerState>(&state).ok())
+                .map(|state| state.principals)
+                .unwrap_or_default()
+        })
+    };
+
     // set up different loops for the controller "part" and the worker "part" of us. this is
     // necessary because sometimes the two need to communicate (e.g., for migrations), and if they
     // were in a single loop, that could deadlock.
@@ -99,12 +142,21 @@ pub(super) async fn start_instance<A: Authority + 'static>(
     let (worker_tx, worker_rx) = tokio::sync::mpsc::unbounded_channel();
 
     // spawn all of those
+    if access.tls.is_some() && access.worker_secret.is_none() {
+        warn!(
+            log,
+            "TLS is enabled without a worker secret, so connections between workers are not \
+             authenticated"
+        );
+    }
     tokio::spawn(listen_internal(
         alive.clone(),
         valve.clone(),
         log.clone(),
         tx.clone(),
         wport,
+        access.worker_secret.clone(),
+        access.tls.clone(),
     ));
     let ext_log = log.clone();
     tokio::spawn(
@@ -114,6 +166,10 @@ pub(super) async fn start_instance<A: Authority + 'static>(
             tx.clone(),
             xport,
             authority.clone(),
+            access.clone(),
+            principals.clone(),
+            metrics.clone(),
+            log.clone(),
         )
         .map_err(move |e| {
             warn!(ext_log, "external request failed: {:?}", e);
@@ -161,12 +217,14 @@ pub(super) async fn start_instance<A: Authority + 'static>(
         alive.clone(),
         valve,
         config,
+        access.clone(),
         descriptor,
         ctrl_rx,
         cport,
         log.clone(),
         authority.clone(),
         tx.clone(),
+        metrics.clone(),
     ));
     tokio::spawn(crate::worker::main(
         alive.clone(),
@@ -175,10 +233,23 @@ pub(super) async fn start_instance<A: Authority + 'static>(
         waddr,
         memory_limit,
         memory_check_frequency,
+        storage_backends,
+        eviction_policy,
+        access.clone(),
+        principals,
+        metrics,
+        tracer,
         log.clone(),
     ));
 
-    let h = Handle::new(authority, tx, trigger).await?;
+    let mut options = ConnectOptions::default();
+    if let Some(tls) = access.tls {
+        options = options.with_tls(tls);
+    }
+    if let Some(token) = access.auth_token {
+        options = options.with_auth_token(token);
+    }
+    let h = Handle::new(authority, tx, trigger, options).await?;
     Ok((h, done.into_future().map(|_| {})))
 }
 
@@ -188,6 +259,8 @@ async fn listen_internal(
     log: slog::Logger,
     event_tx: UnboundedSender<Event>,
     mut on: tokio::net::TcpListener,
+    secret: Option<String>,
+    tls: Option<TlsConfig>,
 ) {
     let mut rx = valve.wrap(on.incoming());
     while let Some(r) = rx.next().await {
@@ -198,21 +271,38 @@ async fn listen_internal(
             }
             Ok(sock) => {
                 let alive = alive.clone();
-                tokio::spawn(
+                let valve = valve.clone();
+                let event_tx = event_tx.clone();
+                let secret = secret.clone();
+                let tls = tls.clone();
+                let log = log.clone();
+                // every coordination message comes in on a connection whose peer proved that it
+                // knows the worker secret
+                tokio::spawn(async move {
+                    let _alive = alive;
+                    let mut sock = match tls::accept(tls.as_ref(), sock).await {
+                        Ok(sock) => sock,
+                        Err(e) => {
+                            warn!(log, "rejected internal connection: {}", e);
+                            return;
+                        }
+                    };
+                    let secret = secret.as_ref().map(String::as_str);
+                    if let Err(e) = noria::channel::auth::challenge(&mut sock, secret).await {
+                        warn!(log, "rejected internal connection: {}", e);
+                        return;
+                    }
                     valve
                         .wrap(AsyncBincodeReader::from(sock))
                         .map_ok(Event::InternalMessage)
                         .map_err(failure::Error::from)
                         .forward(
-                            crate::ImplSinkForSender(event_tx.clone())
+                            crate::ImplSinkForSender(event_tx)
                                 .sink_map_err(|_| format_err!("main event loop went away")),
                         )
-                        .map_err(|e| panic!("{:?}", e))
-                        .map(move |_| {
-                            let _ = alive;
-                            ()
-                        }),
-                );
+                        .await
+                        .unwrap_or_else(|e| panic!("{:?}", e));
+                });
             }
         }
     }
@@ -222,6 +312,9 @@ struct ExternalServer<A: Authority>(
     tokio::sync::mpsc::Sender<()>,
     UnboundedSender<Event>,
     Arc<A>,
+    Arc<AccessConfig>,
+    Arc<Metrics>,
+    PrincipalSource,
 );
 
 async fn listen_external<A: Authority + 'static>(
@@ -230,14 +323,52 @@ async fn listen_external<A: Authority + 'static>(
     event_tx: UnboundedSender<Event>,
     mut on: tokio::net::TcpListener,
     authority: Arc<A>,
+    access: AccessConfig,
+    principals: PrincipalSource,
+    metrics: Arc<Metrics>,
+    log: slog::Logger,
 ) -> Result<(), hyper::Error> {
-    let on = valve.wrap(on.incoming());
+    // finish TLS handshakes off the accept loop, so that a slow or misbehaving client can't hold
+    // up everyone else.
+    let (conn_tx, conn_rx) = tokio::sync::mpsc::unbounded_channel::<MaybeTlsStream>();
+    let tls = access.tls.clone();
+    tokio::spawn(async move {
+        let mut incoming = valve.wrap(on.incoming());
+        while let Some(sock) = incoming.next().await {
+            let sock = match sock {
+                Ok(sock) => sock,
+                Err(e) => {
+                    warn!(log, "external connection failed: {:?}", e);
+                    break;
+                }
+            };
+            let tls = tls.clone();
+            let conn_tx = conn_tx.clone();
+            let log = log.clone();
+            tokio::spawn(async move {
+                match tls::accept(tls.as_ref(), sock).await {
+                    Ok(conn) => {
+                        let _ = conn_tx.send(conn);
+                    }
+                    Err(e) => warn!(log, "rejected external connection: {}", e),
+                }
+            });
+        }
+    });
+    let on = conn_rx.map(io::Result::Ok);
     use hyper::{service::make_service_fn, Body, Request, Response};
     use tower::Service;
     impl<A: Authority> Clone for ExternalServer<A> {
         // Needed due to #26925
         fn clone(&self) -> Self {
-            ExternalServer(self.0.clone(), self.1.clone(), self.2.clone())
+            ExternalServer(
+                self.0.clone(),
+                self.1.clone(),
+                self.2.clone(),
+                self.3.clone(),
+                self.4.clone(),
+                self.5.clone(),
+            )
         }
     }
 
@@ -254,6 +385,8 @@ async fn listen_external<A: Authority + 'static>(
             let res = Response::builder();
             // disable CORS to allow use as API server
             let res = res.header(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+            // the controller decides what the caller may do
+            let caller = self.3.identify(req.headers());
             if let Method::GET = *req.method() {
                 match req.uri().path() {
                     "/graph.html" => {
@@ -262,7 +395,37 @@ async fn listen_external<A: Authority + 'static>(
                             .body(hyper::Body::from(include_str!("graph.html")));
                         return Box::pin(async move { Ok(res.unwrap()) });
                     }
+                    "/dashboard.html" => {
+                        let res = res
+                            .header(CONTENT_TYPE, "text/html")
+                            .body(hyper::Body::from(include_str!("dashboard.html")));
+                        return Box::pin(async move { Ok(res.unwrap()) });
+                    }
+                    "/metrics" => {
+                        let access = self.3.clone();
+                        let metrics = self.4.clone();
+                        let principals = self.5.clone();
+                        return Box::pin(async move {
+                            let res = match access
+                                .authorize_role(&caller, Role::Reader, &principals)
+                                .await
+                            {
+                                Ok(()) => res
+                                    .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                                    .body(hyper::Body::from(metrics.render())),
+                                Err(status) => res.status(status).body(hyper::Body::empty()),
+                            };
+                            Ok(res.unwrap())
+                        });
+                    }
                     path if path.starts_with("/zookeeper/") => {
+                        // the authority holds the principals, so only root gets to look at it
+                        if caller != Caller::Root {
+                            let res = res
+                                .status(StatusCode::UNAUTHORIZED)
+                                .body(hyper::Body::empty());
+                            return Box::pin(async move { Ok(res.unwrap()) });
+                        }
                         let res = match self.2.try_read(&format!("/{}", &path[11..])) {
                             Ok(Some(data)) => res
                                 .header(CONTENT_TYPE, "application/json")
@@ -279,13 +442,17 @@ async fn listen_external<A: Authority + 'static>(
             let path = req.uri().path().to_string();
             let query = req.uri().query().map(ToOwned::to_owned);
             let event_tx = self.1.clone();
+            let metrics = self.4.clone();
 
             Box::pin(async move {
+                let start = time::Instant::now();
+                let endpoint = path.clone();
                 let body = hyper::body::to_bytes(req.into_body()).await?;
                 let (tx, rx) = tokio::sync::oneshot::channel();
 
-                if let Err(_) = event_tx.send(Event::ExternalRequest(method, path, query, body, tx))
-                {
+                if let Err(_) = event_tx.send(Event::ExternalRequest(
+                    method, path, query, body, caller, tx,
+                )) {
                     let res = res
                         .status(StatusCode::SERVICE_UNAVAILABLE)
                         .header("Content-Type", "text/plain; charset=utf-8");
@@ -294,6 +461,11 @@ async fn listen_external<A: Authority + 'static>(
 
                 match rx.await {
                     Ok(reply) => {
+                        // only endpoints the controller actually served get a time series, so
+                        // that requests for made-up paths can't create any number of them
+                        if reply.is_ok() {
+                            metrics.served_rpc(&endpoint, start.elapsed());
+                        }
                         let res = match reply {
                             Ok(Ok(reply)) => res
                                 .header("Content-Type", "application/json; charset=utf-8")
@@ -315,7 +487,14 @@ async fn listen_external<A: Authority + 'static>(
         }
     }
 
-    let service = ExternalServer(alive, event_tx, authority);
+    let service = ExternalServer(
+        alive,
+        event_tx,
+        authority,
+        Arc::new(access),
+        metrics,
+        principals,
+    );
     hyper::server::Server::builder(hyper::server::accept::from_stream(on))
         .serve(make_service_fn(move |_| {
             let s = service.clone();
@@ -0,0 +1,83 @@
+//! Exporting the spans of traced writes.
+//!
+//! Each instance exports the spans that its own domains recorded, so a trace that crosses
+//! workers is put back together by whatever the spans are exported to.
+
+use dataflow::tracing::{self, Tracer};
+use futures_util::stream::StreamExt;
+use hyper::header::CONTENT_TYPE;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use stream_cancel::Valve;
+use tokio::io::AsyncWriteExt;
+
+/// How often recorded spans are exported.
+const EXPORT_EVERY: Duration = Duration::from_secs(1);
+
+/// Where the spans of traced writes are exported to, in the OTLP/JSON format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceExport {
+    /// Append each batch of spans to the given file, as one OTLP/JSON request per line.
+    File(PathBuf),
+    /// Send each batch of spans to an OTLP/HTTP collector at the given `http://` URL, such as
+    /// `http://localhost:4318/v1/traces` for a local OpenTelemetry collector or Jaeger.
+    Collector(String),
+}
+
+type Client = hyper::Client<hyper::client::HttpConnector>;
+
+async fn export_batch(
+    client: &Client,
+    to: &TraceExport,
+    batch: Vec<u8>,
+) -> Result<(), failure::Error> {
+    match *to {
+        TraceExport::File(ref path) => {
+            let mut f = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            f.write_all(&batch).await?;
+            f.write_all(b"\n").await?;
+        }
+        TraceExport::Collector(ref url) => {
+            let req = hyper::Request::post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(hyper::Body::from(batch))?;
+            let res = client.request(req).await?;
+            if !res.status().is_success() {
+                bail!("collector responded with {}", res.status());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Export the spans `tracer` collects to `to` until `valve` is closed.
+pub(crate) async fn export(
+    alive: tokio::sync::mpsc::Sender<()>,
+    valve: Valve,
+    tracer: Arc<Tracer>,
+    to: TraceExport,
+    log: slog::Logger,
+) {
+    let _alive = alive;
+    let client = Client::new();
+    let mut ticks = valve.wrap(tokio::time::interval(EXPORT_EVERY));
+    let mut closed = false;
+    while !closed {
+        // once we're shutting down, export what was recorded in the meantime one last time
+        closed = ticks.next().await.is_none();
+
+        let spans = tracer.take_spans();
+        if spans.is_empty() {
+            continue;
+        }
+        let batch = serde_json::to_vec(&tracing::to_otlp_json("noria", &spans)).unwrap();
+        if let Err(e) = export_batch(&client, &to, batch).await {
+            warn!(log, "failed to export trace spans"; "spans" => spans.len(), "error" => %e);
+        }
+    }
+}
@@ -1,8 +1,11 @@
+use crate::access::{AccessConfig, PrincipalSource};
 use crate::controller::ControllerState;
 use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::metrics::Metrics;
 use crate::startup::Event;
 use async_bincode::AsyncBincodeWriter;
-use dataflow::{DomainBuilder, Packet};
+use dataflow::tracing::Tracer;
+use dataflow::{DomainBuilder, EvictionPolicy, Packet, StateSizes, StorageBackends};
 use futures_util::{future::FutureExt, future::TryFutureExt, sink::SinkExt, stream::StreamExt};
 use noria::channel;
 use noria::consensus::Epoch;
@@ -14,10 +17,7 @@ use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
-};
+use std::sync::{atomic::Ordering, Arc, Mutex};
 use std::time::{self, Duration};
 use stream_cancel::{Trigger, Valve};
 use tokio;
@@ -28,6 +28,9 @@ mod replica;
 
 type ChannelCoordinator = channel::ChannelCoordinator<ReplicaAddr, Box<Packet>>;
 
+/// How often to check state sizes against per-view memory limits if no memory limit is set.
+const DEFAULT_EVICT_EVERY: Duration = Duration::from_secs(1);
+
 enum InstanceState {
     Pining,
     Active {
@@ -49,10 +52,19 @@ pub(super) async fn main(
     waddr: SocketAddr,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    storage_backends: StorageBackends,
+    eviction_policy: Arc<dyn EvictionPolicy>,
+    access: AccessConfig,
+    principals: PrincipalSource,
+    metrics: Arc<Metrics>,
+    tracer: Arc<Tracer>,
     log: slog::Logger,
 ) {
     // shared df state
-    let coord = Arc::new(ChannelCoordinator::new());
+    let coord = Arc::new(ChannelCoordinator::with_access(
+        access.worker_secret.clone(),
+        access.tls.clone(),
+    ));
 
     let mut worker_state = InstanceState::Pining;
     let log = log.clone();
@@ -91,7 +103,28 @@ pub(super) async fn main(
                                 shard,
                                 addr
                             );
-                            coord.insert_remote((domain, shard), addr);
+
+                            // if we are running the domain, but it has booted somewhere else, it
+                            // has been moved away from us. our instance has to pass on whatever it
+                            // still receives, but only once we send to the new address ourselves.
+                            let key = (domain, shard);
+                            let moved = coord.is_local(&key).is_some()
+                                && coord.get_addr(&key) != Some(addr);
+                            let local = if moved {
+                                coord.remove_local(&key)
+                            } else {
+                                None
+                            };
+                            coord.insert_remote(key, addr);
+                            if let Some(local) = local {
+                                info!(
+                                    log,
+                                    "domain {}.{} moved away; handing off",
+                                    domain.index(),
+                                    shard
+                                );
+                                let _ = local.send(Box::new(Packet::Handoff));
+                            }
                         }
                     }
                 }
@@ -137,6 +170,12 @@ pub(super) async fn main(
                     valve,
                     log.clone(),
                     (memory_limit, memory_check_frequency),
+                    storage_backends.clone(),
+                    eviction_policy.clone(),
+                    &access,
+                    &principals,
+                    metrics.clone(),
+                    tracer.clone(),
                     &state,
                     &descriptor,
                     waddr,
@@ -176,6 +215,12 @@ async fn listen_df<'a>(
     valve: Valve,
     log: slog::Logger,
     (memory_limit, evict_every): (Option<usize>, Option<Duration>),
+    storage_backends: StorageBackends,
+    eviction_policy: Arc<dyn EvictionPolicy>,
+    access: &'a AccessConfig,
+    principals: &'a PrincipalSource,
+    metrics: Arc<Metrics>,
+    tracer: Arc<Tracer>,
     state: &'a ControllerState,
     desc: &'a ControllerDescriptor,
     waddr: SocketAddr,
@@ -186,6 +231,8 @@ async fn listen_df<'a>(
     // first, try to connect to controller
     let ctrl = tokio::net::TcpStream::connect(&desc.worker_addr).await?;
     let ctrl_addr = ctrl.local_addr()?;
+    let mut ctrl = channel::tls::connect(coord.tls(), ctrl).await?;
+    channel::auth::prove_async(&mut ctrl, coord.secret()).await?;
     info!(log, "connected to controller"; "src" => ?ctrl_addr);
 
     let log_prefix = state.config.persistence.log_prefix.clone();
@@ -204,6 +251,9 @@ async fn listen_df<'a>(
 
     let (ctrl_tx, mut ctrl_rx) = tokio::sync::mpsc::unbounded_channel();
 
+    // clients' tokens are checked against the same settings by readers and domains
+    let access = Arc::new(access.clone());
+
     // reader setup
     let readers = Arc::new(Mutex::new(HashMap::new()));
     let rport = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0)).await?;
@@ -237,9 +287,17 @@ async fn listen_df<'a>(
         valve.clone(),
         rport,
         readers.clone(),
+        access.clone(),
+        principals.clone(),
+        metrics.clone(),
     ));
 
     // and tell the controller about us
+    let backend_names = storage_backends
+        .names()
+        .into_iter()
+        .map(String::from)
+        .collect();
     let mut timer = valve.wrap(tokio::time::interval_at(
         tokio::time::Instant::now() + heartbeat_every,
         heartbeat_every,
@@ -252,6 +310,7 @@ async fn listen_df<'a>(
             addr: waddr,
             read_listen_addr: raddr,
             log_files,
+            storage_backends: backend_names,
         });
 
         // start sending heartbeats
@@ -264,7 +323,10 @@ async fn listen_df<'a>(
     });
 
     let state_sizes = Arc::new(Mutex::new(HashMap::new()));
-    if let Some(evict_every) = evict_every {
+    {
+        // views may have memory limits of their own, so we check sizes even if the worker as a
+        // whole has no limit
+        let evict_every = evict_every.unwrap_or(DEFAULT_EVICT_EVERY);
         let log = log.clone();
         let coord = coord.clone();
         let mut domain_senders = HashMap::new();
@@ -291,6 +353,7 @@ async fn listen_df<'a>(
 
     // Now we're ready to accept new domains.
     let dcaddr = desc.domain_addr;
+    let principals = principals.clone();
     tokio::spawn(
         async move {
             let alive = alive;
@@ -301,7 +364,8 @@ async fn listen_df<'a>(
                 let on = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0)).await?;
                 let addr = on.local_addr()?;
 
-                let state_size = Arc::new(AtomicUsize::new(0));
+                let state_size = Arc::new(StateSizes::default());
+                let domain_metrics = metrics.add_domain((idx, shard));
                 let d = tokio::task::block_in_place(|| {
                     d.build(
                         log.clone(),
@@ -310,6 +374,10 @@ async fn listen_df<'a>(
                         dcaddr,
                         &valve,
                         state_size.clone(),
+                        domain_metrics.clone(),
+                        tracer.clone(),
+                        storage_backends.clone(),
+                        eviction_policy.clone(),
                     )
                 });
 
@@ -318,12 +386,29 @@ async fn listen_df<'a>(
                 // need to register the domain with the local channel coordinator.
                 // local first to ensure that we don't unnecessarily give away remote for a
                 // local thing if there's a race
-                coord.insert_local((idx, shard), tx);
+                let previous = tokio::task::block_in_place(|| {
+                    // an earlier replica of this domain may still be draining here after being
+                    // relocated, and it only forgets about itself while holding this lock
+                    let mut state_sizes = state_sizes.lock().unwrap();
+                    let previous = coord.remove_local(&(idx, shard));
+                    coord.insert_local((idx, shard), tx);
+                    state_sizes.insert((idx, shard), state_size.clone());
+                    previous
+                });
                 coord.insert_remote((idx, shard), addr);
 
-                tokio::task::block_in_place(|| {
-                    state_sizes.lock().unwrap().insert((idx, shard), state_size)
-                });
+                // a domain that couldn't be moved elsewhere is started here again, and takes
+                // over from the instance that shipped off its state just like a replacement on
+                // another worker would
+                if let Some(previous) = previous {
+                    info!(
+                        log,
+                        "domain {}.{} restarted; handing off",
+                        idx.index(),
+                        shard
+                    );
+                    let _ = previous.send(Box::new(Packet::Handoff));
+                }
 
                 let replica = replica::Replica::new(
                     &valve,
@@ -333,14 +418,33 @@ async fn listen_df<'a>(
                     ctrl_tx.clone(),
                     log.clone(),
                     coord.clone(),
+                    access.clone(),
+                    principals.clone(),
+                    metrics.clone(),
                 );
                 let a = alive.clone();
+                let state_sizes = state_sizes.clone();
+                let coord = coord.clone();
+                let metrics = metrics.clone();
                 tokio::spawn(async move {
                     let _alive = a;
                     let log = replica.log.clone();
                     if let Err(e) = replica.await {
                         crit!(log, "replica failure: {:?}", e);
                     }
+
+                    // the domain has quit or been drained, so stop sizing it and sending to it,
+                    // unless it has been booted here again in the meantime
+                    tokio::task::block_in_place(|| {
+                        let mut state_sizes = state_sizes.lock().unwrap();
+                        if let Some(s) = state_sizes.get(&(idx, shard)) {
+                            if Arc::ptr_eq(s, &state_size) {
+                                state_sizes.remove(&(idx, shard));
+                                coord.remove_local(&(idx, shard));
+                            }
+                        }
+                    });
+                    metrics.remove_domain((idx, shard), &domain_metrics);
                 });
 
                 info!(
@@ -379,46 +483,81 @@ async fn do_eviction(
         Box<dyn futures_sink::Sink<Box<Packet>, Error = Box<bincode::ErrorKind>> + Send + Unpin>,
     >,
     coord: &ChannelCoordinator,
-    state_sizes: &Arc<Mutex<HashMap<(DomainIndex, usize), Arc<AtomicUsize>>>>,
+    state_sizes: &Arc<Mutex<HashMap<(DomainIndex, usize), Arc<StateSizes>>>>,
 ) {
     use std::cmp;
 
     // 2. add current state sizes (could be out of date, as packet sent below is not
     //    necessarily received immediately)
-    let mut sizes: Vec<((DomainIndex, usize), usize)> = tokio::task::block_in_place(|| {
-        let state_sizes = state_sizes.lock().unwrap();
-        state_sizes
-            .iter()
-            .map(|(ds, sa)| {
-                let size = sa.load(Ordering::Acquire);
-                trace!(
-                    log,
-                    "domain {}.{} state size is {} bytes",
-                    ds.0.index(),
-                    ds.1,
-                    size
-                );
-                (*ds, size)
-            })
-            .collect()
-    });
+    let mut sizes: Vec<((DomainIndex, usize), usize, usize, usize)> =
+        tokio::task::block_in_place(|| {
+            let state_sizes = state_sizes.lock().unwrap();
+            state_sizes
+                .iter()
+                .map(|(ds, sa)| {
+                    let size = sa.total.load(Ordering::Acquire);
+                    let low_priority = sa.low_priority.load(Ordering::Acquire);
+                    let over_budget = sa.over_budget.load(Ordering::Acquire);
+                    trace!(
+                        log,
+                        "domain {}.{} state size is {} bytes",
+                        ds.0.index(),
+                        ds.1,
+                        size;
+                        "low_priority" => low_priority,
+                        "over_budget" => over_budget
+                    );
+                    (*ds, size, low_priority, over_budget)
+                })
+                .collect()
+        });
+
+    // domains that have gone away since the last round don't need a sender any more
+    domain_senders.retain(|ds, _| sizes.iter().any(|&(s, _, _, _)| s == *ds));
+
+    // views with a memory limit of their own are held to it no matter what
+    let mut evictions: HashMap<(DomainIndex, usize), usize> = sizes
+        .iter()
+        .filter(|&&(_, _, _, over_budget)| over_budget > 0)
+        .map(|&(ds, _, _, over_budget)| (ds, over_budget))
+        .collect();
 
     // 3. are we above the limit?
-    let total: usize = sizes.iter().map(|&(_, s)| s).sum();
+    let total: usize = sizes.iter().map(|&(_, s, _, _)| s).sum();
     match memory_limit {
         None => (),
         Some(limit) => {
             if total >= limit {
-                let mut over = total - limit;
+                let mut over = (total - limit).saturating_sub(evictions.values().sum());
 
                 // we are! time to evict.
                 // here's how we're going to proceed.
-                // we don't want to _empty_ any views if we can avoid it.
-                // and we also need to be aware that evicting something from one place may cause a
-                // number of downstream evictions.
+                // we don't want to _empty_ any views if we can avoid it, unless they have asked
+                // to be evicted first. and we also need to be aware that evicting something from
+                // one place may cause a number of downstream evictions.
+
+                // low-priority state goes first, starting with the domain that holds the most
+                sizes.sort_unstable_by_key(|&(_, _, low, _)| -1 * (low as i64));
+                for &(target, _, low, _) in &sizes {
+                    let evict = cmp::min(low, over);
+                    if evict == 0 {
+                        break;
+                    }
+                    *evictions.entry(target).or_insert(0) += evict;
+                    over -= evict;
+                }
+
+                // we want to spread the rest of the eviction impact across multiple nodes where
+                // possible, so we distribute how much we're over the limit across the 3 largest
+                // nodes.
+                let mut sizes: Vec<_> = sizes
+                    .iter()
+                    .map(|&(ds, s, _, _)| (ds, s.saturating_sub(*evictions.get(&ds).unwrap_or(&0))))
+                    .collect();
+                if over == 0 {
+                    sizes.clear();
+                }
 
-                // we want to spread the eviction impact across multiple nodes where possible,
-                // so we distribute how much we're over the limit across the 3 largest nodes.
                 // -1* so we sort in descending order
                 // TODO: be smarter than 3 here
                 sizes.sort_unstable_by_key(|&(_, s)| -1 * (s as i64));
@@ -453,27 +592,30 @@ async fn do_eviction(
                             limit,
                             target.0.index(),
                         );
-
-                    let tx = domain_senders.entry(target).or_insert_with(|| {
-                        tokio::task::block_in_place(|| {
-                            coord.builder_for(&target).unwrap().build_async().unwrap()
-                        })
-                    });
-                    let r = tx
-                        .send(Box::new(Packet::Evict {
-                            node: None,
-                            num_bytes: evict,
-                        }))
-                        .await;
-
-                    if let Err(e) = r {
-                        // probably exiting?
-                        warn!(log, "failed to evict from {}: {}", target.0.index(), e);
-                        // remove sender so we don't try to use it again
-                        domain_senders.remove(&target);
-                    }
+                    *evictions.entry(target).or_insert(0) += evict;
                 }
             }
         }
     }
+
+    for (target, evict) in evictions {
+        let tx = domain_senders.entry(target).or_insert_with(|| {
+            tokio::task::block_in_place(|| {
+                coord.builder_for(&target).unwrap().build_async().unwrap()
+            })
+        });
+        let r = tx
+            .send(Box::new(Packet::Evict {
+                node: None,
+                num_bytes: evict,
+            }))
+            .await;
+
+        if let Err(e) = r {
+            // probably exiting?
+            warn!(log, "failed to evict from {}: {}", target.0.index(), e);
+            // remove sender so we don't try to use it again
+            domain_senders.remove(&target);
+        }
+    }
 }
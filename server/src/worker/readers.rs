@@ -1,3 +1,5 @@
+use crate::access::{AccessConfig, PrincipalSource};
+use crate::metrics::Metrics;
 use async_bincode::AsyncBincodeStream;
 use dataflow::prelude::DataType;
 use dataflow::prelude::*;
@@ -9,11 +11,13 @@ use futures_util::{
     future::{FutureExt, TryFutureExt},
     stream::{StreamExt, TryStreamExt},
 };
-use noria::{ReadQuery, ReadReply, Tagged};
+use noria::channel::tls;
+use noria::{ReadQuery, ReadReply, Role, Tagged};
 use pin_project::pin_project;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::mem;
+use std::sync::Arc;
 use std::time;
 use std::{future::Future, task::Poll};
 use stream_cancel::Valve;
@@ -60,6 +64,9 @@ pub(super) async fn listen(
     valve: Valve,
     mut on: tokio::net::TcpListener,
     readers: Readers,
+    access: Arc<AccessConfig>,
+    principals: PrincipalSource,
+    metrics: Arc<Metrics>,
 ) {
     let mut stream = valve.wrap(on.incoming()).into_stream();
     while let Some(stream) = stream.next().await {
@@ -115,13 +122,35 @@ pub(super) async fn listen(
         });
         tokio::spawn(retries);
 
-        let server = READERS.scope(
-            Default::default(),
+        let access = access.clone();
+        let principals = principals.clone();
+        let metrics = metrics.clone();
+        let server = READERS.scope(Default::default(), async move {
+            let mut stream = match tls::accept(access.tls.as_ref(), stream).await {
+                Ok(stream) => stream,
+                // a client that fails the handshake doesn't get to read anything
+                Err(_) => return Ok(()),
+            };
+            // and neither does one whose token doesn't let it read
+            if access
+                .admit(&mut stream, Role::Reader, &principals)
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
             server::Server::new(
                 AsyncBincodeStream::from(stream).for_async(),
-                service_fn(move |req| handle_message(req, &readers, &mut tx)),
-            ),
-        );
+                service_fn(move |req| {
+                    let metrics = metrics.clone();
+                    let start = time::Instant::now();
+                    handle_message(req, &readers, &mut tx).inspect(move |_| {
+                        metrics.served_read(start.elapsed());
+                    })
+                }),
+            )
+            .await
+        });
         tokio::spawn(
             server
                 .map_err(|e| {
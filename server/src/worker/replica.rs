@@ -2,7 +2,9 @@
 const FORCE_INPUT_YIELD_EVERY: usize = 32;
 
 use super::ChannelCoordinator;
+use crate::access::{AccessConfig, PrincipalSource};
 use crate::coordination::CoordinationPayload;
+use crate::metrics::Metrics;
 use ahash::{AHashMap, AHashSet};
 use async_bincode::AsyncDestination;
 use async_timer::Oneshot;
@@ -17,14 +19,18 @@ use futures_util::{
     sink::Sink,
     stream::{futures_unordered::FuturesUnordered, Stream},
 };
-use noria::channel::{DualTcpStream, CONNECTION_FROM_BASE};
+use noria::channel::tls::{self, MaybeTlsStream};
+use noria::channel::{
+    auth, AckedConnection, DualTcpStream, CONNECTION_FROM_BASE, CONNECTION_FROM_DOMAIN,
+};
 use noria::internal::DomainIndex;
 use noria::internal::LocalOrNot;
-use noria::{Input, Tagged};
+use noria::{Input, Role, Tagged, WriteAck};
 use pin_project::pin_project;
 use slog;
 use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time;
 use std::{
@@ -40,14 +46,43 @@ use tokio::io::{AsyncReadExt, BufReader, BufStream, BufWriter};
 pub(super) type ReplicaAddr = (DomainIndex, usize);
 
 // https://github.com/rust-lang/rust/issues/64445
-type FirstByte = impl Future<Output = Result<(tokio::net::TcpStream, u8), tokio::io::Error>> + Send;
-
-/// Read the first byte of a stream.
-fn read_first_byte(mut stream: tokio::net::TcpStream) -> FirstByte {
+type FirstByte = impl Future<Output = Result<(MaybeTlsStream, u8), tokio::io::Error>> + Send;
+
+/// Read the first byte of a stream, and complete the TLS handshake that follows it.
+///
+/// Connections from other domains and from the controller then have to prove that they know the
+/// deployment's worker secret. Clients have to present a token that lets them write. Anything
+/// else is turned away.
+fn read_first_byte(
+    mut stream: tokio::net::TcpStream,
+    access: Arc<AccessConfig>,
+    principals: PrincipalSource,
+    secret: Option<String>,
+) -> FirstByte {
     async move {
         let mut byte = [0; 1];
         let n = stream.read_exact(&mut byte[..]).await?;
         assert_eq!(n, 1);
+        if byte[0] != CONNECTION_FROM_BASE && byte[0] != CONNECTION_FROM_DOMAIN {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("unknown connection type {}", byte[0]),
+            ));
+        }
+
+        let mut stream = tls::accept(access.tls.as_ref(), stream)
+            .await
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("TLS handshake failed: {}", e),
+                )
+            })?;
+        if byte[0] == CONNECTION_FROM_DOMAIN {
+            auth::challenge(&mut stream, secret.as_ref().map(String::as_str)).await?;
+        } else {
+            access.admit(&mut stream, Role::Writer, &principals).await?;
+        }
         Ok((stream, byte[0]))
     }
 }
@@ -58,6 +93,8 @@ pub(super) struct Replica {
     pub(super) log: slog::Logger,
 
     coord: Arc<ChannelCoordinator>,
+    access: Arc<AccessConfig>,
+    principals: PrincipalSource,
 
     retry: Option<Box<Packet>>,
 
@@ -78,7 +115,7 @@ pub(super) struct Replica {
     #[pin]
     inputs: StreamUnordered<
         DualTcpStream<
-            BufStream<tokio::net::TcpStream>,
+            BufStream<MaybeTlsStream>,
             Box<Packet>,
             Tagged<LocalOrNot<Input>>,
             AsyncDestination,
@@ -90,9 +127,24 @@ pub(super) struct Replica {
         (
             Box<dyn Sink<Box<Packet>, Error = bincode::Error> + Send + Unpin>,
             bool,
+            SocketAddr,
         ),
     >,
 
+    // connections to where relocated domains used to be, which are closed once they have been
+    // told so
+    retiring: Vec<(
+        Box<dyn Sink<Box<Packet>, Error = bincode::Error> + Send + Unpin>,
+        Option<Box<Packet>>,
+    )>,
+    moves_seen: usize,
+    rerouted: Rerouted,
+
+    // once this domain has been relocated: the connection to its replacement, and whether it has
+    // sends pending
+    handoff: Option<(AckedConnection<Box<Packet>>, bool)>,
+    locals_closed: bool,
+
     #[pin]
     timeout: Strawpoll<async_timer::oneshot::Timer>,
     timed_out: bool,
@@ -109,12 +161,18 @@ impl Replica {
         ctrl_tx: tokio::sync::mpsc::UnboundedSender<CoordinationPayload>,
         log: slog::Logger,
         cc: Arc<ChannelCoordinator>,
+        access: Arc<AccessConfig>,
+        principals: PrincipalSource,
+        metrics: Arc<Metrics>,
     ) -> Self {
         let id = domain.id();
         let id = format!("{}.{}", id.0.index(), id.1);
         domain.booted(on.local_addr().unwrap());
+        let moves_seen = cc.moves();
         Replica {
             coord: cc,
+            access,
+            principals,
             domain,
             retry: None,
             valve: valve.clone(),
@@ -124,7 +182,12 @@ impl Replica {
             log: log.new(o! {"id" => id}),
             inputs: Default::default(),
             outputs: Default::default(),
-            out: Outboxes::new(ctrl_tx),
+            retiring: Vec::new(),
+            moves_seen,
+            rerouted: Default::default(),
+            handoff: None,
+            locals_closed: false,
+            out: Outboxes::new(ctrl_tx, metrics),
             timeout: Strawpoll::from(async_timer::oneshot::Timer::new(time::Duration::from_secs(
                 3600,
             ))),
@@ -147,7 +210,7 @@ impl Replica {
             let mut stream = Pin::new(&mut inputs[streami]);
             let mut sent = 0;
 
-            for &tag in &conn.tag_acks {
+            for &(tag, ref ack) in &conn.tag_acks {
                 match stream.as_mut().poll_ready(cx) {
                     Poll::Ready(Ok(())) => {}
                     Poll::Pending => break,
@@ -157,7 +220,10 @@ impl Replica {
                     }
                 }
 
-                if let Err(e) = stream.as_mut().start_send(Tagged { tag, v: () }) {
+                if let Err(e) = stream.as_mut().start_send(Tagged {
+                    tag,
+                    v: ack.clone(),
+                }) {
                     // start_send shouldn't generally error
                     err.push(e.into());
                     break;
@@ -223,6 +289,7 @@ impl Replica {
                     conn.pending_flush = false;
                     conn.unacked = 0;
                     conn.tag_acks.clear();
+                    conn.received.clear();
                     if inputs.is_finished(streami).unwrap() {
                         close.push(streami);
                     } else {
@@ -262,6 +329,42 @@ impl Replica {
         let cc = this.coord;
         let outputs = this.outputs;
 
+        // a connection made before a domain was relocated goes to the old instance, which passes
+        // everything on to the replacement. we end such connections by telling the old instance
+        // so, and send to the replacement directly from then on.
+        let moves = cc.moves();
+        if moves != *this.moves_seen {
+            *this.moves_seen = moves;
+            let from = this.domain.id();
+            let moved: Vec<_> = outputs
+                .iter()
+                .filter(|&(ri, &(_, _, addr))| cc.get_addr(ri) != Some(addr))
+                .map(|(&ri, _)| ri)
+                .collect();
+            for ri in moved {
+                let (tx, _, _) = outputs.remove(&ri).unwrap();
+                this.retiring
+                    .push((tx, Some(Box::new(Packet::Rerouting { from }))));
+
+                // the replacement holds back what we send it until everything we sent the old
+                // instance has caught up, which it can only do for a connection of our own
+                let nowhere =
+                    || failure::format_err!("domain {}.{} moved to nowhere", ri.0.index(), ri.1);
+                let addr = cc.get_addr(&ri).ok_or_else(nowhere)?;
+                let tx = cc
+                    .builder_for(&ri)
+                    .ok_or_else(nowhere)?
+                    .build_acked()
+                    .context("connect to moved domain")?;
+                this.out
+                    .domains
+                    .entry(ri)
+                    .or_default()
+                    .push_front(Box::new(Packet::Rerouted { from }));
+                outputs.insert(ri, (Box::new(tx), true, addr));
+            }
+        }
+
         // just like in try_acks:
         // first, queue up any additional writes we have to do
         let mut err = Vec::new();
@@ -270,10 +373,10 @@ impl Replica {
                 continue;
             }
 
-            let &mut (ref mut tx, ref mut pending) = outputs.entry(ri).or_insert_with(|| {
+            let &mut (ref mut tx, ref mut pending, _) = outputs.entry(ri).or_insert_with(|| {
                 while !cc.has(&ri) {}
                 let tx = cc.builder_for(&ri).unwrap().build_async().unwrap();
-                (tx, true)
+                (tx, true, cc.get_addr(&ri).unwrap())
             });
 
             let mut tx = Pin::new(tx);
@@ -307,7 +410,7 @@ impl Replica {
         }
 
         // then, try to do any sends that are still pending
-        for &mut (ref mut tx, ref mut pending) in outputs.values_mut() {
+        for &mut (ref mut tx, ref mut pending, _) in outputs.values_mut() {
             if !*pending {
                 continue;
             }
@@ -321,6 +424,84 @@ impl Replica {
             }
         }
 
+        // send the last packet on connections to where relocated domains used to be, and close
+        // them once it is out
+        let retiring = this.retiring;
+        let mut i = 0;
+        while i < retiring.len() {
+            let (ref mut tx, ref mut last) = retiring[i];
+            let mut tx = Pin::new(tx);
+            if last.is_some() {
+                match tx.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        if let Err(e) = tx.as_mut().start_send(last.take().unwrap()) {
+                            err.push(e);
+                        }
+                    }
+                    Poll::Pending => {
+                        i += 1;
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => {
+                        err.push(e);
+                        break;
+                    }
+                }
+            }
+
+            match tx.poll_close(cx) {
+                Poll::Ready(Ok(())) => {
+                    retiring.swap_remove(i);
+                }
+                Poll::Pending => i += 1,
+                Poll::Ready(Err(e)) => {
+                    err.push(e);
+                    break;
+                }
+            }
+        }
+
+        // if this domain has been relocated, pass what still arrives here on to its replacement
+        if !this.out.forwarded.is_empty() && this.handoff.is_none() {
+            let id = this.domain.id();
+            let tx = cc
+                .builder_for(&id)
+                .ok_or_else(|| failure::err_msg("relocated domain has no replacement"))?
+                .build_acked()
+                .context("connect to replacement")?;
+            *this.handoff = Some((tx, false));
+        }
+        if let Some((ref mut tx, ref mut pending)) = *this.handoff {
+            let mut tx = Pin::new(tx);
+            while !this.out.forwarded.is_empty() {
+                match tx.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Pending => break,
+                    Poll::Ready(Err(e)) => {
+                        err.push(e);
+                        break;
+                    }
+                }
+
+                let m = this.out.forwarded.pop_front().expect("!is_empty");
+                match tx.as_mut().start_send(m) {
+                    Ok(()) => *pending = true,
+                    Err(e) => {
+                        err.push(e);
+                        break;
+                    }
+                }
+            }
+
+            if *pending {
+                match tx.poll_flush(cx) {
+                    Poll::Ready(Ok(())) => *pending = false,
+                    Poll::Pending => {}
+                    Poll::Ready(Err(e)) => err.push(e),
+                }
+            }
+        }
+
         if !err.is_empty() {
             return Err(err.swap_remove(0).into());
         }
@@ -328,6 +509,50 @@ impl Replica {
         Ok(())
     }
 
+    /// Pass the replacement's acknowledgements of the writes this relocated domain forwarded to
+    /// it on to the clients that made them.
+    fn try_handoff_acks(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Result<(), failure::Error> {
+        let this = self.project();
+        let out = this.out;
+        if let Some((ref mut rx, _)) = *this.handoff {
+            loop {
+                match Pin::new(&mut *rx).poll_next(cx) {
+                    Poll::Ready(Some(Ok(Tagged { tag, v }))) => {
+                        for id in out.held.remove(&tag).unwrap_or_default() {
+                            out.respond(id, v.clone());
+                        }
+                    }
+                    Poll::Ready(Some(Err(e))) => return Err(e.into()),
+                    Poll::Ready(None) if out.held.is_empty() => break,
+                    Poll::Ready(None) => {
+                        return Err(failure::err_msg(
+                            "replacement went away before acknowledging forwarded writes",
+                        ));
+                    }
+                    Poll::Pending => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this domain has been relocated, and there is nothing left for it to do: nobody
+    /// is connected to it anymore, and everything it passed on has been dealt with.
+    fn is_drained(&self) -> bool {
+        self.domain.has_handed_off()
+            && self.locals_closed
+            && self.inputs.is_empty()
+            && self.first_byte.is_empty()
+            && self.retiring.is_empty()
+            && self.out.is_empty()
+            && self.outputs.values().all(|&(_, pending, _)| !pending)
+            && self
+                .handoff
+                .as_ref()
+                .map(|&(_, pending)| !pending)
+                .unwrap_or(true)
+    }
+
     fn try_new(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Result<bool, failure::Error> {
         let mut this = self.project();
 
@@ -343,7 +568,12 @@ impl Replica {
                 // we know that any new connection to a domain will first send a one-byte
                 // token to indicate whether the connection is from a base or not.
                 debug!(this.log, "accepted new connection"; "from" => ?stream.peer_addr().unwrap());
-                this.first_byte.push(read_first_byte(stream));
+                this.first_byte.push(read_first_byte(
+                    stream,
+                    this.access.clone(),
+                    this.principals.clone(),
+                    this.coord.secret().map(String::from),
+                ));
             }
         }
 
@@ -351,6 +581,10 @@ impl Replica {
             let (stream, tag) = match r {
                 Ok((s, t)) => (s, t),
                 Err(e) => {
+                    if let io::ErrorKind::PermissionDenied = e.kind() {
+                        warn!(this.log, "rejected new connection: {}", e);
+                        continue;
+                    }
                     if let io::ErrorKind::BrokenPipe
                     | io::ErrorKind::NotConnected
                     | io::ErrorKind::UnexpectedEof
@@ -377,16 +611,17 @@ impl Replica {
                 let t = this.out.connections.insert(ConnState {
                     unacked: 0,
                     tag_acks: Vec::new(),
+                    received: Default::default(),
                     epoch,
                     pending_flush: false,
                 });
                 assert_eq!(t, token);
                 epoch
             };
-            if let Err(e) = stream.set_nodelay(true) {
+            if let Err(e) = stream.get_ref().set_nodelay(true) {
                 warn!(this.log,
                       "failed to set TCP_NODELAY for new connection: {:?}", e;
-                      "from" => ?stream.peer_addr().unwrap());
+                      "from" => ?stream.get_ref().peer_addr().unwrap());
             }
             let tcp = if is_base {
                 DualTcpStream::upgrade(
@@ -396,6 +631,7 @@ impl Replica {
                             inner: input,
                             src: Some(SourceChannelIdentifier { token, tag, epoch }),
                             senders: Vec::new(),
+                            trace: None,
                         })
                     },
                 )
@@ -432,12 +668,82 @@ impl Replica {
     }
 }
 
+/// Connections to this domain that replace connections to where it used to be.
+///
+/// A domain that learns that a domain it sends to has been relocated ends its connection to the
+/// old instance with `Packet::Rerouting`, and starts one to the new instance with
+/// `Packet::Rerouted`. What it sends on the new connection must not overtake what it sent on the
+/// old one, so that is held back until the `Rerouting` has been passed on to us.
+#[derive(Default)]
+struct Rerouted {
+    // packets held back on new connections, and which domain they are from
+    waiting: AHashMap<usize, (ReplicaAddr, VecDeque<Box<Packet>>)>,
+
+    // domains whose old connection has caught up before their new one was opened
+    caught_up: AHashSet<ReplicaAddr>,
+
+    // packets that are no longer held back
+    ready: VecDeque<Box<Packet>>,
+}
+
+impl Rerouted {
+    /// Decide whether a packet that arrived on connection `streami`, or on the local channel, can
+    /// be processed by `d` now.
+    fn admit(
+        &mut self,
+        streami: Option<usize>,
+        packet: Box<Packet>,
+        d: &Domain,
+    ) -> Option<Box<Packet>> {
+        match *packet {
+            Packet::Rerouted { from } => {
+                if !self.caught_up.remove(&from) {
+                    let streami = streami.expect("rerouted connections are never local");
+                    self.waiting.insert(streami, (from, VecDeque::new()));
+                }
+                None
+            }
+            Packet::Rerouting { from } => {
+                let caught_up: Vec<_> = self
+                    .waiting
+                    .iter()
+                    .filter(|&(_, &(f, _))| f == from)
+                    .map(|(&streami, _)| streami)
+                    .collect();
+                if !caught_up.is_empty() {
+                    for streami in caught_up {
+                        let (_, held) = self.waiting.remove(&streami).unwrap();
+                        self.ready.extend(held);
+                    }
+                    None
+                } else if d.is_relocated() {
+                    // the new connection went to an instance further along
+                    Some(packet)
+                } else {
+                    self.caught_up.insert(from);
+                    None
+                }
+            }
+            _ => match streami.and_then(|streami| self.waiting.get_mut(&streami)) {
+                Some(&mut (_, ref mut held)) => {
+                    held.push_back(packet);
+                    None
+                }
+                None => Some(packet),
+            },
+        }
+    }
+}
+
 struct ConnState {
     // number of unacked inputs
     unacked: usize,
 
-    // unsent acks (value is the tag)
-    tag_acks: Vec<u32>,
+    // unsent acks (the tag, and whether the write was accepted)
+    tag_acks: Vec<(u32, WriteAck)>,
+
+    // when each unacked input was received, by tag
+    received: AHashMap<u32, time::Instant>,
 
     // epoch counter for each stream index (since they're re-used)
     epoch: usize,
@@ -461,16 +767,30 @@ struct Outboxes {
 
     // for sending messages to the controller
     ctrl_tx: tokio::sync::mpsc::UnboundedSender<CoordinationPayload>,
+
+    // packets for the replacement of this domain, once it has been relocated
+    forwarded: VecDeque<Box<Packet>>,
+
+    // where the writes that were forwarded to the replacement came from, by the tag they were
+    // forwarded with
+    held: AHashMap<u32, Vec<SourceChannelIdentifier>>,
+    next_held: u32,
+
+    metrics: Arc<Metrics>,
 }
 
 impl Outboxes {
-    fn new(ctrl_tx: tokio::sync::mpsc::UnboundedSender<CoordinationPayload>) -> Self {
+    fn new(
+        ctrl_tx: tokio::sync::mpsc::UnboundedSender<CoordinationPayload>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let mut connections = slab::Slab::new();
 
         // index 0 is reserved
         connections.insert(ConnState {
             unacked: 0,
             tag_acks: Vec::new(),
+            received: Default::default(),
             epoch: 0,
             pending_flush: false,
         });
@@ -480,14 +800,27 @@ impl Outboxes {
             connections,
             pending: Default::default(),
             ctrl_tx,
+            forwarded: VecDeque::new(),
+            held: Default::default(),
+            next_held: 0,
+            metrics,
             dirty: false,
         }
     }
 
-    fn saw_input(&mut self, token: usize, epoch: usize) {
+    /// Whether there is nothing left to send, and no forwarded writes waiting to be acknowledged.
+    fn is_empty(&self) -> bool {
+        self.domains.values().all(VecDeque::is_empty)
+            && self.pending.is_empty()
+            && self.forwarded.is_empty()
+            && self.held.is_empty()
+    }
+
+    fn saw_input(&mut self, token: usize, tag: u32, epoch: usize) {
         let mut c = &mut self.connections[token];
         if c.epoch == epoch {
             c.unacked += 1;
+            c.received.entry(tag).or_insert_with(time::Instant::now);
         }
     }
 
@@ -507,16 +840,17 @@ impl Outboxes {
             false
         }
     }
-}
 
-impl Executor for Outboxes {
-    fn ack(&mut self, id: SourceChannelIdentifier) {
+    fn respond(&mut self, id: SourceChannelIdentifier, ack: WriteAck) {
         self.dirty = true;
         let mut c = &mut self.connections[id.token];
         if id.epoch == c.epoch {
             // if the epoch doesn't match, the stream was closed and a new one has been established
             // note that this only matters for connections that do not wait for all acks!
-            c.tag_acks.push(id.tag);
+            c.tag_acks.push((id.tag, ack));
+            if let Some(received) = c.received.remove(&id.tag) {
+                self.metrics.acked_write(received.elapsed());
+            }
 
             // NOTE: it's a little sad we can't crash on underflow here.
             // it is because if a send fails, we set c.unacked = 0, and should the domain _then_
@@ -527,6 +861,16 @@ impl Executor for Outboxes {
             self.pending.insert(id.token);
         }
     }
+}
+
+impl Executor for Outboxes {
+    fn ack(&mut self, id: SourceChannelIdentifier) {
+        self.respond(id, Ok(()));
+    }
+
+    fn reject(&mut self, id: SourceChannelIdentifier, reason: String) {
+        self.respond(id, Err(reason));
+    }
 
     fn create_universe(&mut self, universe: HashMap<String, DataType>) {
         self.ctrl_tx
@@ -538,6 +882,42 @@ impl Executor for Outboxes {
         self.dirty = true;
         self.domains.entry(dest).or_default().push_back(m);
     }
+
+    fn forward(&mut self, m: Box<Packet>) {
+        self.dirty = true;
+        let m = match *m {
+            Packet::Input {
+                inner,
+                src,
+                mut senders,
+                trace,
+            } => {
+                // the replacement acknowledges the write on our connection to it, under a tag of
+                // our choosing, and we pass that on to everyone who is waiting for it
+                senders.extend(src);
+                let src = if senders.is_empty() {
+                    None
+                } else {
+                    let tag = self.next_held;
+                    self.next_held = self.next_held.wrapping_add(1);
+                    self.held.insert(tag, senders);
+                    Some(SourceChannelIdentifier {
+                        token: 0,
+                        epoch: 0,
+                        tag,
+                    })
+                };
+                Box::new(Packet::Input {
+                    inner: LocalOrNot::new(unsafe { inner.take() }),
+                    src,
+                    senders: Vec::new(),
+                    trace,
+                })
+            }
+            m => Box::new(m),
+        };
+        self.forwarded.push_back(m);
+    }
 }
 
 impl Future for Replica {
@@ -594,11 +974,11 @@ impl Future for Replica {
                     if let ProcessResult::StopPolling = {
                         let packet = retry.take().unwrap();
                         if let Packet::Input {
-                            src: Some(SourceChannelIdentifier { token, epoch, .. }),
+                            src: Some(SourceChannelIdentifier { token, tag, epoch }),
                             ..
                         } = *packet
                         {
-                            $outbox.saw_input(token, epoch);
+                            $outbox.saw_input(token, tag, epoch);
                         }
                         $pp(packet)
                     } {
@@ -619,8 +999,16 @@ impl Future for Replica {
                 if !local_done && (check_local || remote_done) {
                     match this.locals.poll_recv(cx) {
                         Poll::Ready(Some(packet)) => {
-                            process!(*this.retry, out, packet, |p| d
-                                .on_event(out, PollEvent::Process(p),));
+                            if let Some(packet) = this.rerouted.admit(None, packet, d) {
+                                process!(*this.retry, out, packet, |p| d
+                                    .on_event(out, PollEvent::Process(p),));
+                            }
+                        }
+                        Poll::Ready(None) if d.is_relocated() => {
+                            // a domain that has moved away is no longer registered locally, but
+                            // keeps forwarding what arrives from remote connections made earlier
+                            *this.locals_closed = true;
+                            local_done = true;
                         }
                         Poll::Ready(None) => {
                             // local input stream finished
@@ -636,9 +1024,21 @@ impl Future for Replica {
 
                 if !remote_done && (!check_local || local_done) {
                     match this.inputs.as_mut().poll_next(cx) {
-                        Poll::Ready(Some((StreamYield::Item(Ok(packet)), _))) => {
-                            process!(*this.retry, out, packet, |p| d
-                                .on_event(out, PollEvent::Process(p),));
+                        Poll::Ready(Some((StreamYield::Item(Ok(mut packet)), streami))) => {
+                            if let Packet::Input {
+                                src: Some(ref mut src),
+                                ..
+                            } = *packet
+                            {
+                                // writes are acknowledged on the connection they came in on. this
+                                // also goes for writes that a relocated domain passes on to us.
+                                src.token = streami;
+                                src.epoch = out.connections[streami].epoch;
+                            }
+                            if let Some(packet) = this.rerouted.admit(Some(streami), packet, d) {
+                                process!(*this.retry, out, packet, |p| d
+                                    .on_event(out, PollEvent::Process(p),));
+                            }
                         }
                         Poll::Ready(Some((StreamYield::Finished(f), streami))) => {
                             if out.try_retire(streami) {
@@ -661,16 +1061,24 @@ impl Future for Replica {
                             error!(this.log, "input stream failed: {:?}", e);
                             // we want to _forcibly_ retire streami
                             this.inputs.as_mut().remove(streami);
+                            this.rerouted.waiting.remove(&streami);
                             let c = &mut out.connections[streami];
                             c.epoch += 1;
                             c.unacked = 0;
                             c.tag_acks.clear();
+                            c.received.clear();
                             c.pending_flush = false;
                             out.pending.remove(&streami);
                         }
                     }
                 }
 
+                // packets held back on a new connection may have been let through
+                while let Some(packet) = this.rerouted.ready.pop_front() {
+                    process!(*this.retry, out, packet, |p| d
+                        .on_event(out, PollEvent::Process(p),));
+                }
+
                 if local_done && remote_done {
                     break;
                 }
@@ -685,9 +1093,15 @@ impl Future for Replica {
                 .try_flush(cx)
                 .context("downstream flush (after)")?;
 
-            // send acks
+            // send acks, including those for writes our replacement applied for us
+            self.as_mut().try_handoff_acks(cx)?;
             self.as_mut().try_acks(cx)?;
 
+            if self.is_drained() {
+                info!(self.log, "relocated domain has been drained, shutting down");
+                return Poll::Ready(Ok(()));
+            }
+
             if !local_done || !remote_done {
                 // we're yielding voluntarily to not block the executor and must ensure we wake
                 // up again